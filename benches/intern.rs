@@ -0,0 +1,80 @@
+//! Heap taken up by paths with interned keys, against the same paths with a string per key.
+//!
+//! Run with `cargo bench --bench intern`. Paths are those of users and their devices, the way
+//! deployments hold millions of them: `users.<id>.devices.<id>.status`, ids repeated across users.
+
+extern crate serde;
+
+#[path = "../src/intern.rs"]
+#[allow(dead_code)]
+mod intern;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+use intern::Key;
+
+/// Paths made
+const PATHS: usize = 1_000_000;
+
+/// Distinct ids of users, and of devices
+const IDS: usize = 10_000;
+
+/// The system allocator, counting bytes allocated and not freed.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn keys(i: usize) -> Vec<String> {
+    vec![
+        "users".to_string(),
+        format!("{:08x}-user", i % IDS),
+        "devices".to_string(),
+        format!("{:08x}-device", (i / IDS) % IDS),
+        "status".to_string()
+    ]
+}
+
+/// Heap bytes taken up by what `make` returns, and seconds taken to make it.
+fn measure<T, F>(make: F) -> (T, usize, f64) where F: FnOnce() -> T {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let made = make();
+    let elapsed = start.elapsed();
+
+    (made, ALLOCATED.load(Ordering::Relaxed) - before, elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9)
+}
+
+fn main() {
+    let (strings, string_bytes, string_secs) = measure(|| (0..PATHS).map(keys).collect::<Vec<_>>());
+    let (interned, interned_bytes, interned_secs) = measure(|| {
+        strings.iter().map(|keys| keys.iter().map(Key::from).collect::<Vec<_>>()).collect::<Vec<_>>()
+    });
+
+    let stats = intern::stats();
+
+    println!("{} paths of {} keys", PATHS, strings[0].len());
+    println!("  strings:  {:>12} bytes, {:.3}s", string_bytes, string_secs);
+    println!("  interned: {:>12} bytes, {:.3}s", interned_bytes, interned_secs);
+    println!("  saved:    {:>12} bytes ({:.1}%)", string_bytes.saturating_sub(interned_bytes), 100.0 * (1.0 - interned_bytes as f64 / string_bytes as f64));
+    println!("  pooled:   {:>12} keys, {} bytes, {} bytes saved by sharing", stats.keys, stats.bytes, stats.saved);
+
+    assert!(interned_bytes < string_bytes);
+    drop(interned);
+}
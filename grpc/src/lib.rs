@@ -0,0 +1,208 @@
+//! gRPC service of qumulus, see `proto/qumulus.proto`.
+//!
+//! The service is generated by tonic and runs on a tokio runtime of its own, apart from the mioco
+//! coroutines of the rest of the node. It knows nothing of zones: a node serves it with a
+//! `Backend`, whose blocking calls are made on tokio's blocking threads, and whose subscriptions
+//! are each followed on a thread of their own, waiting on the backend for each change, until the
+//! client goes away and the subscription is ended with its `Unsubscribe`.
+
+use std::error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+pub mod pb {
+    tonic::include_proto!("qumulus");
+}
+
+use pb::qumulus_server::{Qumulus, QumulusServer};
+use pb::{Change, DeleteReply, DeleteRequest, ReadReply, ReadRequest, SubscribeRequest, WriteReply, WriteRequest};
+
+/// Changes buffered for a subscriber before waiting on it
+const CHANGES_BUFFERED: usize = 64;
+
+/// Why a call failed, replied as a status.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    BadPath(String),
+    BadValue(String),
+    NotFound,         // No data at the path read
+    Frozen,           // A frozen path would change
+    Invalid(String),  // What a schema found wrong with the value, as JSON
+    Failed(String)
+}
+
+/// How a subscription binds its path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bind {
+    pub filter: Vec<String>,
+    pub regex: Option<String>,
+    pub depth: Option<u32>
+}
+
+/// Changes to a path subscribed to, by the path of the zone each is from, as JSON, each blocking
+/// until it comes, and ending once unsubscribed.
+pub type Changes = Box<dyn Iterator<Item = (Vec<String>, String)> + Send>;
+
+/// Ends the `Changes` of a subscription, waking them if waiting, once its client is gone.
+pub type Unsubscribe = Box<dyn FnOnce() + Send>;
+
+/// What the service calls, blocking, for each request.
+pub trait Backend: Send + Sync + 'static {
+    /// The data at `path` as plain JSON.
+    fn read(&self, path: Vec<String>) -> Result<String, Error>;
+
+    /// Writes `json` at `path`.
+    fn write(&self, path: Vec<String>, json: String) -> Result<(), Error>;
+
+    /// Kills the data at `path`.
+    fn delete(&self, path: Vec<String>) -> Result<(), Error>;
+
+    /// Binds `path`, returning the data there and then changes to it, until dropped, and how to
+    /// end them.
+    fn subscribe(&self, path: Vec<String>, bind: Bind) -> Result<(Changes, Unsubscribe), Error>;
+}
+
+struct Service<B> {
+    backend: Arc<B>
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::BadPath(ref error) => write!(f, "Bad path: {}", error),
+            Error::BadValue(ref error) => write!(f, "Bad value: {}", error),
+            Error::NotFound => write!(f, "No data"),
+            Error::Frozen => write!(f, "Frozen"),
+            Error::Invalid(ref invalid) => write!(f, "Invalid: {}", invalid),
+            Error::Failed(ref error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Status {
+        let message = error.to_string();
+
+        match error {
+            Error::BadPath(_) | Error::BadValue(_) | Error::Invalid(_) => Status::invalid_argument(message),
+            Error::NotFound => Status::not_found(message),
+            Error::Frozen => Status::permission_denied(message),
+            Error::Failed(_) => Status::internal(message)
+        }
+    }
+}
+
+impl<B: Backend> Service<B> {
+    /// Runs `f` with the backend on a blocking thread.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Status> where T: Send + 'static, F: FnOnce(&B) -> Result<T, Error> + Send + 'static {
+        let backend = self.backend.clone();
+
+        match task::spawn_blocking(move || f(&*backend)).await {
+            Ok(result) => result.map_err(Status::from),
+            Err(e) => Err(Status::internal(e.to_string()))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<B: Backend> Qumulus for Service<B> {
+    type SubscribeStream = ReceiverStream<Result<Change, Status>>;
+
+    async fn read(&self, request: Request<ReadRequest>) -> Result<Response<ReadReply>, Status> {
+        let path = keys(request.into_inner().path);
+        let json = self.blocking(move |backend| backend.read(path)).await?;
+
+        Ok(Response::new(ReadReply { json }))
+    }
+
+    async fn write(&self, request: Request<WriteRequest>) -> Result<Response<WriteReply>, Status> {
+        let request = request.into_inner();
+        let path = keys(request.path);
+        let json = request.json;
+
+        self.blocking(move |backend| backend.write(path, json)).await?;
+
+        Ok(Response::new(WriteReply {}))
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteReply>, Status> {
+        let path = keys(request.into_inner().path);
+
+        self.blocking(move |backend| backend.delete(path)).await?;
+
+        Ok(Response::new(DeleteReply {}))
+    }
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let path = keys(request.path);
+
+        let bind = Bind {
+            filter: request.filter,
+            regex: Some(request.regex).filter(|regex| ! regex.is_empty()),
+            depth: request.depth
+        };
+
+        // Bound before replying, so bad subscriptions fail the call
+        let (changes, unsubscribe) = self.blocking(move |backend| backend.subscribe(path, bind)).await?;
+        let (tx, rx) = mpsc::channel(CHANGES_BUFFERED);
+        let (followed, done) = oneshot::channel::<()>();
+        let closed = tx.clone();
+
+        thread::spawn(move || {
+            let _followed = followed; // until the changes end
+
+            // Client gone, dropping the changes unbinds
+            for (path, json) in changes {
+                let change = Change { path: Some(pb::Path { keys: path }), json };
+
+                if tx.blocking_send(Ok(change)).is_err() {
+                    return;
+                }
+            }
+        });
+
+        // Client gone while waiting on changes, ended so the thread is too
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = closed.closed() => unsubscribe(),
+                _ = done => ()
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// Serves `backend` at `addr`, blocking until the server fails.
+pub fn serve<B: Backend>(backend: B, addr: SocketAddr) -> Result<(), Box<dyn error::Error>> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let service = QumulusServer::new(Service { backend: Arc::new(backend) });
+
+    runtime.block_on(tonic::transport::Server::builder().add_service(service).serve(addr))?;
+
+    Ok(())
+}
+
+fn keys(path: Option<pb::Path>) -> Vec<String> {
+    path.map(|path| path.keys).unwrap_or_default()
+}
+
+#[test]
+fn test_status() {
+    use tonic::Code;
+
+    assert_eq!(Status::from(Error::NotFound).code(), Code::NotFound);
+    assert_eq!(Status::from(Error::Frozen).code(), Code::PermissionDenied);
+    assert_eq!(Status::from(Error::BadPath("Bad key".to_string())).message(), "Bad path: Bad key");
+    assert_eq!(keys(None), Vec::<String>::new());
+}
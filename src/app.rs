@@ -7,9 +7,16 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 
 use command::Call;
 use cluster::{ClusterHandle, ClusterChannel};
+use cold;
+use freeze::Frozen;
+use hooks::Hooks;
 use manager::{ManagerHandle, ManagerChannel};
+use path::Limits;
 use replica::Replica;
+use schema::Schema;
 use store::{StoreHandle, StoreChannel};
+use strategy::Strategies;
+use tombstone;
 
 pub struct App {
     pub id: Replica,
@@ -20,16 +27,32 @@ pub struct App {
 
     pub channels: Channels,
 
+    pub schema: Arc<Schema>,
+    pub strategies: Arc<Strategies>,
+    pub tombstones: tombstone::Retention,
+    pub cold: Option<cold::Policy>,
+    pub frozen: Arc<Frozen>,
+    pub limits: Limits,
+    pub hooks: Arc<Hooks>,
     pub stats: Arc<Stats>
 }
 
 /// The shareable reference to the App
 #[derive(Clone)]
 pub struct AppHandle {
+    pub id: Replica,
+
     pub cluster: ClusterHandle,
     pub manager: ManagerHandle,
     pub store: StoreHandle,
 
+    pub schema: Arc<Schema>,
+    pub strategies: Arc<Strategies>,
+    pub tombstones: tombstone::Retention,
+    pub cold: Option<cold::Policy>,
+    pub frozen: Arc<Frozen>,
+    pub limits: Limits,
+    pub hooks: Arc<Hooks>,
     pub stats: Arc<Stats>
 }
 
@@ -75,6 +98,7 @@ pub struct StoreStats {
     pub reads: Stat,
     pub reads_pending: Stat,
     pub reads_errors: Stat,
+    pub reads_corrupt: Stat,
     pub writes: Stat,
     pub writes_pending: Stat,
     pub writes_errors: Stat
@@ -89,8 +113,20 @@ pub struct ZoneStats {
 #[derive(Default, Serialize)]
 pub struct CommandStats {
     pub bind: Stat,
+    pub cas: Stat,
+    pub copy: Stat,
+    pub crdt: Stat,
+    pub diff: Stat,
+    pub expire: Stat,
+    pub incr: Stat,
     pub kill: Stat,
+    pub list: Stat,
+    pub lock: Stat,
+    #[serde(rename = "move")]
+    pub move_: Stat,
     pub read: Stat,
+    pub snapshot: Stat,
+    pub txn: Stat,
     pub write: Stat
 }
 
@@ -119,16 +155,32 @@ impl App {
                 store: Some(store)
             },
 
+            schema: Default::default(),
+            strategies: Default::default(),
+            tombstones: Default::default(),
+            cold: None,
+            frozen: Default::default(),
+            limits: Default::default(),
+            hooks: Default::default(),
             stats: Default::default()
         }
     }
 
     pub fn handle(&self) -> AppHandle {
         AppHandle {
+            id: self.id.clone(),
+
             cluster: self.cluster.clone(),
             manager: self.manager.clone(),
             store: self.store.clone(),
 
+            schema: self.schema.clone(),
+            strategies: self.strategies.clone(),
+            tombstones: self.tombstones,
+            cold: self.cold,
+            frozen: self.frozen.clone(),
+            limits: self.limits,
+            hooks: self.hooks.clone(),
             stats: self.stats.clone()
         }
     }
@@ -150,8 +202,19 @@ impl CommandStats {
     pub fn increment(&self, call: &Call) {
         match call {
             &Call::Bind => self.bind.increment(),
+            &Call::Cas => self.cas.increment(),
+            &Call::Copy => self.copy.increment(),
+            &Call::Crdt => self.crdt.increment(),
+            &Call::Diff => self.diff.increment(),
+            &Call::Expire => self.expire.increment(),
+            &Call::Incr => self.incr.increment(),
             &Call::Kill => self.kill.increment(),
+            &Call::List => self.list.increment(),
+            &Call::Lock => self.lock.increment(),
+            &Call::Move => self.move_.increment(),
             &Call::Read => self.read.increment(),
+            &Call::Snapshot => self.snapshot.increment(),
+            &Call::Txn => self.txn.increment(),
             &Call::Write => self.write.increment()
         };
     }
@@ -0,0 +1,410 @@
+//! Binary framing, negotiated by clients sending commands faster than JSON is parsed.
+//!
+//! Clients connecting over TCP can send `{ "binary": [1] }`, the versions of the framing they
+//! speak, and wait for the reply: `{ "binary": 1 }` with the version chosen, after which commands
+//! and replies are frames both ways, or `{ "binary": null }` if none of them is spoken, after which
+//! lines go on as before. Options such as `{ "deltas": true }` are sent before switching.
+//!
+//! A frame is `<length: u32 BE> <payload>`, of at most `MAX_FRAME_BYTES`. Commands are encoded, in
+//! version 1, as
+//!
+//! ```text
+//! command: <id: u64> <call: string> <keys: u32> <key: string>.. <params: value>
+//! value:   0 null | 1 false | 2 true | 3 <i64> | 4 <u64> | 5 <f64> | 6 <string>
+//!          | 7 <len: u32> <value>.. | 8 <len: u32> (<key: string> <value>)..
+//! string:  <len: u32> <UTF-8 bytes>
+//! ```
+//!
+//! with numbers little-endian and the tags of values a byte each, nested at most `MAX_DEPTH` deep,
+//! and checked like commands sent as JSON. Replies are framed as the JSON other clients get, since
+//! they're built as JSON wherever they're from (see `Listener::update`).
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json;
+use serde_json::Value;
+
+/// Versions of the framing spoken
+pub const VERSIONS: &'static [u64] = &[1];
+
+/// Biggest frame read, in bytes
+pub const MAX_FRAME_BYTES: usize = 64 << 20;
+
+/// Deepest values nest in commands
+pub const MAX_DEPTH: usize = 128;
+
+/// Start of the reply accepting a version
+const ACCEPTED: &'static str = "{ \"binary\": ";
+
+/// What a client sent.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),                   // A line, or a WebSocket message
+    Command(Result<Value, String>)  // A frame, as `[ id, call, path, params ]`, or why it isn't one
+}
+
+/// Messages read from a client, lines until switched to frames with `binary`.
+pub struct Messages<R: BufRead> {
+    reader: R,
+    binary: Arc<AtomicBool>,
+    done: bool // Once frames can't be told apart anymore
+}
+
+/// Reads the values of a command frame.
+struct Decoder<'a> {
+    bytes: &'a [u8]
+}
+
+impl<R: BufRead> Messages<R> {
+    pub fn new(reader: R, binary: Arc<AtomicBool>) -> Messages<R> {
+        Messages {
+            reader: reader,
+            binary: binary,
+            done: false
+        }
+    }
+
+    fn read_line(&mut self) -> Option<io::Result<Message>> {
+        let mut line = String::new();
+
+        match self.reader.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => {
+                if line.ends_with('\n') {
+                    line.pop();
+
+                    if line.ends_with('\r') {
+                        line.pop();
+                    }
+                }
+
+                Some(Ok(Message::Text(line)))
+            },
+            Err(e) => Some(Err(e))
+        }
+    }
+
+    fn read_frame(&mut self) -> Option<io::Result<Message>> {
+        let mut length = [0; 4];
+
+        // Closed between frames
+        match self.reader.read(&mut length[..1]) {
+            Ok(0) => return None,
+            Ok(_) => {},
+            Err(e) => return Some(Err(e))
+        }
+
+        if let Err(e) = self.reader.read_exact(&mut length[1..]) {
+            return Some(Err(e));
+        }
+
+        let length = u32::from_be_bytes(length) as usize;
+
+        if length > MAX_FRAME_BYTES {
+            return Some(Err(io::Error::new(io::ErrorKind::InvalidData, "Frame too big")));
+        }
+
+        let mut payload = vec![0; length];
+
+        if let Err(e) = self.reader.read_exact(&mut payload) {
+            return Some(Err(e));
+        }
+
+        Some(Ok(Message::Command(decode(&payload))))
+    }
+}
+
+impl<R: BufRead> Iterator for Messages<R> {
+    type Item = io::Result<Message>;
+
+    fn next(&mut self) -> Option<io::Result<Message>> {
+        if self.done {
+            return None;
+        }
+
+        if ! self.binary.load(Ordering::SeqCst) {
+            return self.read_line();
+        }
+
+        let message = self.read_frame();
+
+        if let Some(Err(_)) = message {
+            self.done = true;
+        }
+
+        message
+    }
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() < n {
+            return Err("Frame cut short".to_string());
+        }
+
+        let (taken, rest) = self.bytes.split_at(n);
+
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut bytes = [0; 4];
+
+        bytes.copy_from_slice(try!(self.take(4)));
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+
+        bytes.copy_from_slice(try!(self.take(8)));
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Count of items following, sized by the bytes left so a bad count can't take up memory.
+    fn len(&mut self) -> Result<usize, String> {
+        let len = try!(self.u32()) as usize;
+
+        if len > self.bytes.len() {
+            return Err("Frame cut short".to_string());
+        }
+
+        Ok(len)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = try!(self.u32()) as usize;
+        let bytes = try!(self.take(len));
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| "Bad UTF-8".to_string())
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, String> {
+        if depth > MAX_DEPTH {
+            return Err("Value nested too deep".to_string());
+        }
+
+        match try!(self.u8()) {
+            0 => Ok(Value::Null),
+            1 => Ok(Value::Bool(false)),
+            2 => Ok(Value::Bool(true)),
+            3 => self.u64().map(|n| Value::from(n as i64)),
+            4 => self.u64().map(Value::from),
+            5 => self.u64().map(|n| Value::from(f64::from_bits(n))),
+            6 => self.string().map(Value::String),
+            7 => {
+                let len = try!(self.len());
+                let mut array = Vec::with_capacity(len);
+
+                for _ in 0..len {
+                    array.push(try!(self.value(depth + 1)));
+                }
+
+                Ok(Value::Array(array))
+            },
+            8 => {
+                let len = try!(self.len());
+                let mut object = serde_json::Map::new();
+
+                for _ in 0..len {
+                    let key = try!(self.string());
+
+                    object.insert(key, try!(self.value(depth + 1)));
+                }
+
+                Ok(Value::Object(object))
+            },
+            tag => Err(format!("Bad value tag {}", tag))
+        }
+    }
+}
+
+/// Command of a frame, as `[ id, call, path, params ]` like commands sent as JSON.
+pub fn decode(payload: &[u8]) -> Result<Value, String> {
+    let mut decoder = Decoder { bytes: payload };
+
+    let id = try!(decoder.u64());
+    let call = try!(decoder.string());
+    let len = try!(decoder.len());
+    let mut keys = Vec::with_capacity(len);
+
+    for _ in 0..len {
+        keys.push(Value::String(try!(decoder.string())));
+    }
+
+    let params = try!(decoder.value(0));
+
+    if ! decoder.bytes.is_empty() {
+        return Err("Trailing bytes in frame".to_string());
+    }
+
+    Ok(Value::Array(vec![id.into(), Value::String(call), Value::Array(keys), params]))
+}
+
+/// Writes `payload` as a frame.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(4 + payload.len());
+
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    // At once, so frames are written whole
+    writer.write_all(&frame)
+}
+
+/// Versions offered by `{ "binary": [ versions.. ] }`, or `None` for other options.
+pub fn parse_offer(line: &str) -> Option<Vec<u64>> {
+    let options: Value = match serde_json::from_str(line) {
+        Ok(options) => options,
+        Err(_) => return None
+    };
+
+    options.get("binary").map(|versions| versions.as_array().iter().flat_map(|versions| versions.iter()).filter_map(|version| version.as_u64()).collect())
+}
+
+/// Latest version spoken of those `offered`.
+pub fn choose(offered: &[u64]) -> Option<u64> {
+    offered.iter().filter(|version| VERSIONS.contains(version)).max().cloned()
+}
+
+/// Reply to an offer, accepting `version` or none.
+pub fn reply(version: Option<u64>) -> String {
+    match version {
+        Some(version) => format!("{}{} }}", ACCEPTED, version),
+        None => "{ \"binary\": null }".to_string()
+    }
+}
+
+/// Whether `message` is a reply accepting a version, after which replies are framed.
+pub fn is_accepted(message: &str) -> bool {
+    message.starts_with(ACCEPTED) && message[ACCEPTED.len()..].starts_with(|c: char| c.is_digit(10))
+}
+
+#[cfg(test)]
+fn encode(id: u64, call: &str, keys: &[&str], params: &Value) -> Vec<u8> {
+    fn write_string(bytes: &mut Vec<u8>, s: &str) {
+        bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(s.as_bytes());
+    }
+
+    fn write_value(bytes: &mut Vec<u8>, value: &Value) {
+        match *value {
+            Value::Null => bytes.push(0),
+            Value::Bool(b) => bytes.push(if b { 2 } else { 1 }),
+            Value::Number(ref n) if n.is_u64() => {
+                bytes.push(4);
+                bytes.extend_from_slice(&n.as_u64().unwrap().to_le_bytes());
+            },
+            Value::Number(ref n) if n.is_i64() => {
+                bytes.push(3);
+                bytes.extend_from_slice(&n.as_i64().unwrap().to_le_bytes());
+            },
+            Value::Number(ref n) => {
+                bytes.push(5);
+                bytes.extend_from_slice(&n.as_f64().unwrap().to_bits().to_le_bytes());
+            },
+            Value::String(ref s) => {
+                bytes.push(6);
+                write_string(bytes, s);
+            },
+            Value::Array(ref array) => {
+                bytes.push(7);
+                bytes.extend_from_slice(&(array.len() as u32).to_le_bytes());
+
+                for v in array {
+                    write_value(bytes, v);
+                }
+            },
+            Value::Object(ref object) => {
+                bytes.push(8);
+                bytes.extend_from_slice(&(object.len() as u32).to_le_bytes());
+
+                for (k, v) in object {
+                    write_string(bytes, k);
+                    write_value(bytes, v);
+                }
+            }
+        }
+    }
+
+    let mut bytes = id.to_le_bytes().to_vec();
+
+    write_string(&mut bytes, call);
+    bytes.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+
+    for key in keys {
+        write_string(&mut bytes, key);
+    }
+
+    write_value(&mut bytes, params);
+    bytes
+}
+
+#[test]
+fn test_decode() {
+    let params: Value = vec![Value::from(1), Value::from(-2), Value::from(0.5), Value::from("moo"), Value::Null, Value::Bool(true)].into();
+    let payload = encode(7, "write", &["users", "moo"], &params);
+
+    assert_eq!(decode(&payload), Ok(vec![Value::from(7), Value::from("write"), vec![Value::from("users"), Value::from("moo")].into(), params].into()));
+
+    assert!(decode(&payload[..payload.len() - 1]).is_err());
+    assert!(decode(&[payload.clone(), vec![0]].concat()).is_err());
+
+    // Nested too deep
+    let mut deep = Value::Null;
+
+    for _ in 0..MAX_DEPTH + 1 {
+        deep = vec![deep].into();
+    }
+
+    assert!(decode(&encode(1, "write", &[], &deep)).is_err());
+
+    // Counts more than the bytes left
+    let mut payload = encode(1, "write", &[], &Value::Null);
+
+    payload.pop();
+    payload.extend_from_slice(&[7, 0xff, 0xff, 0xff, 0xff]);
+    assert!(decode(&payload).is_err());
+}
+
+#[test]
+fn test_messages() {
+    use std::io::Cursor;
+
+    let binary = Arc::new(AtomicBool::new(false));
+    let mut stream = b"{ \"binary\": [1] }\r\n".to_vec();
+    let payload = encode(1, "read", &["moo"], &Value::Null);
+
+    write_frame(&mut stream, &payload).unwrap();
+    stream.extend_from_slice(&[0, 0]);
+
+    let mut messages = Messages::new(Cursor::new(stream), binary.clone());
+
+    assert_eq!(messages.next().unwrap().unwrap(), Message::Text("{ \"binary\": [1] }".to_string()));
+
+    binary.store(true, Ordering::SeqCst);
+
+    assert_eq!(messages.next().unwrap().unwrap(), Message::Command(decode(&payload)));
+
+    // Cut short, then done
+    assert!(messages.next().unwrap().is_err());
+    assert!(messages.next().is_none());
+}
+
+#[test]
+fn test_negotiate() {
+    assert_eq!(choose(&[1, 2]), Some(1));
+    assert_eq!(choose(&[2]), None);
+    assert_eq!(reply(Some(1)), "{ \"binary\": 1 }");
+    assert!(is_accepted(&reply(Some(1))));
+    assert!(! is_accepted(&reply(None)));
+    assert!(! is_accepted("{ \"hello!\": 1 }"));
+}
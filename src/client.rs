@@ -1,42 +1,100 @@
 //! Represents a connected API client. Spins off 2 threads per client.
-
-use std::collections::VecDeque;
+//!
+//! Clients connect over TCP, sending commands and read replies a line each, or a frame each once
+//! negotiated (see `binary`), or over WebSocket (see `websocket`), a text message each. Either way,
+//! commands go through `process` the same.
+//!
+//! Clients can send `{ "deltas": true }` instead of a command to have the paths of later replies
+//! written as deltas (see `Path::to_delta_json`), each after the one before it among the replies
+//! to the same command, the first after the root: `[ n, keys.. ]` with the count of leading keys
+//! shared with that path and the keys after them. So do the paths listed by `diff` replies, one
+//! after another. `{ "deltas": false }` goes back to full paths.
+
+use std::cmp;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
 use std::io::prelude::*;
 use std::io::BufReader;
 use std::mem;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
 use mioco::sync::mpsc::{channel, Receiver, Sender};
 use mioco::sync::Mutex;
-use mioco::tcp::TcpStream;
 use mioco;
 use serde_json;
 use serde_json::Value;
 
 use app::AppHandle;
-use command::Command;
-use node::{DelegatedMatch, Update};
+use binary::{self, Message};
+use clock;
+use command::{Call, Command};
+use listener;
+use node::{DelegatedMatch, Node, NodeTree, Update, Vis};
 use path::Path;
+use tls::Stream;
+use websocket;
+use zone::ZoneHandle;
 
 pub struct Client {
     app: AppHandle,
-    stream: TcpStream,
+    stream: Stream,
+    writer: Writer,
+    framing: Framing,
     tx: Sender<String>
 }
 
+/// How messages are told apart on a client's stream.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Framing {
+    Lines,     // One a line
+    WebSocket, // One a WebSocket message, once upgraded
+    Binary     // One a frame, once negotiated, see `binary`
+}
+
+/// Why a write made with `write` wasn't.
+#[derive(Debug, PartialEq)]
+pub enum Refused {
+    Frozen,         // See `freeze`
+    Invalid(Value), // What a schema found wrong with the value, see `schema`
+    Failed(String)
+}
+
+/// The stream of a client, written a whole buffer at a time, so frames answering WebSocket pings
+/// don't interleave with replies.
+#[derive(Clone)]
+struct Writer(Arc<Mutex<Stream>>);
+
 impl Client {
-    /// Creates a new `Client` from a `TcpStream`
-    pub fn new(app: AppHandle, stream: TcpStream) {
+    /// Creates a new `Client` from a `Stream`, over TLS or not
+    pub fn new(app: AppHandle, stream: Stream) {
+        Client::spawn(app, stream, Framing::Lines);
+    }
+
+    /// Creates a new `Client` from a `Stream` upgrading to WebSocket.
+    pub fn websocket(app: AppHandle, stream: Stream) {
+        Client::spawn(app, stream, Framing::WebSocket);
+    }
+
+    fn spawn(app: AppHandle, stream: Stream, framing: Framing) {
         let (tx, rx) = channel();
 
-        let client = Client {
+        let mut client = Client {
             app: app,
+            writer: Writer(Arc::new(Mutex::new(stream.try_clone().unwrap()))),
             stream: stream,
+            framing: framing,
             tx: tx
         };
 
         mioco::spawn(move|| {
+            if client.framing == Framing::WebSocket {
+                if let Err(e) = websocket::handshake(&mut client.stream) {
+                    return println!("WebSocket handshake error: {}", e);
+                }
+            }
+
             client.app.stats.clients.connects.increment();
 
             // Asynchronously write data to client
@@ -57,16 +115,24 @@ impl Client {
         pinger(self.tx.clone());
 
         let reader = BufReader::new(self.stream.try_clone().unwrap());
+        let binary = Arc::new(AtomicBool::new(false)); // Read frames rather than lines
+
+        let messages: Box<Iterator<Item = io::Result<Message>>> = match self.framing {
+            Framing::WebSocket => Box::new(websocket::Messages::new(reader, self.writer.clone()).map(|message| message.map(Message::Text))),
+            _ => Box::new(binary::Messages::new(reader, binary.clone()))
+        };
 
         let (commands_tx, commands_rx) = mioco::sync::mpsc::channel::<Command>();
 
         let commands_rx = Arc::new(Mutex::new(commands_rx));
+        let deltas = Arc::new(AtomicBool::new(false)); // Reply paths as deltas
 
         // Pipeline up to 1000 commands at a time
         for _ in 0..1000 {
             let commands_rx = commands_rx.clone();
             let app = self.app.clone();
             let tx = self.tx.clone();
+            let deltas = deltas.clone();
 
             mioco::spawn(move|| {
                 loop {
@@ -79,16 +145,35 @@ impl Client {
                         Err(_) => return
                     };
 
-                    process(&app, &tx, command);
+                    process(&app, &tx, command, deltas.load(Ordering::SeqCst));
                 }
             });
         }
 
         // Read loop, push decoded commands into queue
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    match Command::from_json(&line) {
+        for message in messages {
+            match message {
+                Ok(Message::Text(ref line)) if line.trim_start().starts_with('{') => {
+                    match binary::parse_offer(line) {
+                        Some(offered) => self.negotiate(&offered, &binary),
+                        None => match parse_deltas(line) {
+                            Ok(on) => deltas.store(on, Ordering::SeqCst),
+                            Err(e) => self.tx.send("[0,\"error\",\"".to_string() + &e + "\"]").unwrap()
+                        }
+                    }
+                },
+                Ok(Message::Text(line)) => {
+                    match Command::from_json_with(&line, &self.app.limits) {
+                        Ok(command) => {
+                            commands_tx.send(command).unwrap();
+                        },
+                        Err(e) => {
+                            self.tx.send("[0,\"error\",\"".to_string() + &e + "\"]").unwrap();
+                        }
+                    }
+                },
+                Ok(Message::Command(command)) => {
+                    match command.and_then(|command| Command::from_value_with(command, &self.app.limits)) {
                         Ok(command) => {
                             commands_tx.send(command).unwrap();
                         },
@@ -107,8 +192,25 @@ impl Client {
         // command_tx is dropped here, threads using command_rx will panic
     }
 
+    /// Replies to an offer to switch to frames, reading them from then on if one of the versions
+    /// `offered` is spoken. Replies are written as frames once the reply is, see
+    /// `create_writer_thread`.
+    fn negotiate(&self, offered: &[u64], binary: &AtomicBool) {
+        let version = match self.framing {
+            Framing::Lines => binary::choose(offered),
+            _ => None // Framed already
+        };
+
+        self.tx.send(binary::reply(version)).unwrap();
+
+        if version.is_some() {
+            binary.store(true, Ordering::SeqCst);
+        }
+    }
+
     fn create_writer_thread(&self, channel: Receiver<String>) {
-        let mut writer = self.stream.try_clone().unwrap();
+        let mut writer = self.writer.clone();
+        let mut framing = self.framing;
 
         mioco::spawn(move|| {
             loop {
@@ -117,6 +219,21 @@ impl Client {
                     Err(_) => return
                 };
 
+                if framing == Framing::WebSocket {
+                    if let Err(_) = websocket::write_text(&mut writer, &message) {
+                        return;
+                    }
+
+                    continue;
+                }
+
+                if framing == Framing::Binary {
+                    if let Err(_) = binary::write_frame(&mut writer, message.as_bytes()) {
+                        return;
+                    }
+
+                    continue;
+                }
 
                 // TODO: test socket for writability
                 if let Err(_) = writer.write_all(message.as_bytes()) {
@@ -126,19 +243,65 @@ impl Client {
                 if let Err(_) = writer.write(b"\n") {
                     return;
                 }
+
+                // Accepted as a line, framed after
+                if binary::is_accepted(&message) {
+                    framing = Framing::Binary;
+                }
             }
         });
     }
 }
 
-/// Process a single command from client. Recursively dispatch for delegated zones.
-fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut stream = self.0.lock().unwrap();
+
+        try!(stream.write_all(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+/// Whether `{ "deltas": bool }` asks for paths as deltas.
+fn parse_deltas(line: &str) -> Result<bool, String> {
+    let options: Value = try!(serde_json::from_str(line).or(Err("Bad JSON")));
+
+    options.get("deltas").and_then(|deltas| deltas.as_bool()).ok_or("Bad options".to_string())
+}
+
+/// Process a single command from client. Recursively dispatch for delegated zones. With `deltas`,
+/// paths replied are written as deltas, see `Path::to_delta_json`.
+pub fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command, deltas: bool) {
+    // Path replied last, if replying with deltas
+    let mut last = if deltas { Some(Path::empty()) } else { None };
+
+    // Span zones on both sides, so copied here rather than by a zone
+    if command.call == Call::Copy || command.call == Call::Move {
+        app.stats.clients.commands.increment(&command.call);
+
+        let (left, path, data) = copy_path(app, tx, &command);
+
+        return reply(app, tx, command.id, left, &path, data, &mut last);
+    }
+
     let resolved_path = command.path.resolved();
     let (prefix, zone) = app.manager.find_nearest(&resolved_path);
 
+    // Calls on delegated data need their params again
+    let params = if command.recursive() {
+        command.params.clone()
+    }
+    else {
+        mem::replace(&mut command.params, Value::Null)
+    };
+
     let c = Command {
         path: command.path.slice(prefix.len()),
-        params: mem::replace(&mut command.params, Value::Null),
+        params: params,
         ..command
     };
 
@@ -157,7 +320,41 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
         queue.push_back(d);
     }
 
-    reply(app, tx, command.id, queue.len() as u64, &prefix, result.update);
+    // Replied to once it gets its lease, see `lease`
+    if result.queued {
+        return;
+    }
+
+    if result.conflict {
+        // Replied to with the current value instead of the count of replies left
+        let data = match result.data {
+            Some(data) => data,
+            None => to_json(result.update)
+        };
+
+        reply(app, tx, command.id, "conflict".into(), &prefix, data, &mut last);
+        return;
+    }
+
+    if result.frozen {
+        // Replied to with the path written, see `freeze`
+        reply(app, tx, command.id, "frozen".into(), &prefix, command.path.to_json(), &mut last);
+        return;
+    }
+
+    if let Some(invalid) = result.invalid {
+        // Replied to with what was wrong with the value written
+        reply(app, tx, command.id, "invalid".into(), &prefix, invalid.to_json(), &mut last);
+        return;
+    }
+
+    let data = match result.data {
+        Some(data) if deltas && command.call == Call::Diff => diff_deltas(data),
+        Some(data) => data,
+        None => to_json(result.update)
+    };
+
+    reply(app, tx, command.id, queue.len().into(), &prefix, data, &mut last);
 
     if ! command.recursive() {
         return;
@@ -166,9 +363,20 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
     while let Some(delegated) = queue.pop_front() {
         let zone = app.manager.load(&delegated.path);
 
+        // Binds with a depth go less deep on data delegated below recursive wildcards, and only
+        // read data deeper than that
+        let (call, params) = match command.call {
+            Call::Bind => match listener::delegated_bind(&command.params, &command.path, &delegated.path) {
+                Some(params) => (Call::Bind, params),
+                None => (Call::Read, Value::Null)
+            },
+            call => (call, command.params.clone())
+        };
+
         let c = Command {
+            call: call,
             path: delegated.match_spec,
-            params: Value::Null,
+            params: params,
             ..command
         };
 
@@ -182,15 +390,31 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
             queue.push_back(d);
         }
 
-        reply(app, tx, command.id, queue.len() as u64, &delegated.path, result.update);
+        let data = match result.data {
+            Some(data) if deltas && command.call == Call::Diff => diff_deltas(data),
+            Some(data) => data,
+            None => to_json(result.update)
+        };
+
+        reply(app, tx, command.id, queue.len().into(), &delegated.path, data, &mut last);
     }
 
-    fn reply(app: &AppHandle, tx: &Sender<String>, id: u64, left: u64, path: &Path, update: Option<Update>) {
+    fn reply(app: &AppHandle, tx: &Sender<String>, id: u64, left: Value, path: &Path, data: Value, last: &mut Option<Path>) {
+        let path = match *last {
+            Some(ref mut last) => {
+                let delta = path.to_delta_json(last);
+
+                *last = path.clone();
+                delta
+            },
+            None => path.to_json()
+        };
+
         let response = vec![
             id.into(),
-            left.into(),
-            path.to_json(),
-            update.map_or(Value::Null, |u| u.to_json())
+            left,
+            path,
+            data
         ];
 
         app.stats.clients.replies.increment();
@@ -198,6 +422,323 @@ fn process(app: &AppHandle, tx: &Sender<String>, mut command: Command) {
         // TODO stop processing if unable to reply, otherwise we're just wasting cycles
         tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
     }
+
+    fn to_json(update: Option<Update>) -> Value {
+        update.map_or(Value::Null, |u| u.to_json())
+    }
+
+    /// `diff` changes, `[ difference, path ]` each, with paths as deltas one after another.
+    fn diff_deltas(data: Value) -> Value {
+        let mut last = Path::empty();
+
+        match data {
+            Value::Array(changes) => Value::Array(changes.into_iter().map(|change| match change {
+                Value::Array(mut change) => {
+                    let path = Path::new(change[1].as_array().unwrap().iter().map(|key| key.as_str().unwrap().to_string()).collect());
+
+                    change[1] = path.to_delta_json(&last);
+                    last = path;
+                    Value::Array(change)
+                },
+                change => change
+            }).collect()),
+            data => data
+        }
+    }
+}
+
+/// Copies the data at the path of a `copy` or `move` command to its destination. What was there is
+/// killed, and the data read is written there with the same values. Moves then kill it where it
+/// was, so listeners on either side see deletes and writes. Within one zone, that is all one merge
+/// (see `Zone::copy`), and otherwise one change held across every zone it touches (see
+/// `copy_across`). Returns what to reply with once all of it is written: the count of replies
+/// left, a path and its data.
+fn copy_path(app: &AppHandle, tx: &Sender<String>, command: &Command) -> (Value, Path, Value) {
+    let from = &command.path;
+    let to = command.destination().unwrap(); // checked when parsed
+
+    // Copies leave the source as it is
+    if app.frozen.is_frozen(&to) {
+        return ("frozen".into(), to.clone(), to.to_json());
+    }
+
+    if command.call == Call::Move && app.frozen.is_frozen(from) {
+        return ("frozen".into(), from.clone(), from.to_json());
+    }
+
+    let (prefix, zone) = app.manager.find_nearest(from);
+
+    if app.manager.find_nearest(&to).0 == prefix {
+        let c = Command {
+            path: from.slice(prefix.len()),
+            params: to.slice(prefix.len()).to_json(),
+            ..command.clone()
+        };
+
+        let result = zone.dispatch(c, tx);
+
+        if let Some(invalid) = result.invalid {
+            return ("invalid".into(), to, invalid.to_json());
+        }
+
+        if ! result.conflict {
+            return (0.into(), to, Value::Null);
+        }
+    }
+
+    copy_across(app, tx, command, &to)
+}
+
+/// Copies or moves the data at the path of `command` to `to` across zones. Every zone with data at
+/// either path, or owning where some of it goes, is held (see `ZoneHandle::hold`) and read again,
+/// so nothing changes either side in the meantime. Each zone is then sent its part of the change,
+/// the destination killed as of the command and the data written and, for moves, killed where it
+/// was at one later time. Replied to once every zone merged its part: readers of either path see
+/// the data where it was or where it went, though a read spanning zones may have read some of them
+/// before the change and others after. Conflicts if there is no data, or zones split or folded
+/// before they were all held, and is invalid if the schema rejects any of the data where it goes.
+fn copy_across(app: &AppHandle, tx: &Sender<String>, command: &Command, to: &Path) -> (Value, Path, Value) {
+    let from = &command.path;
+    let conflict = ("conflict".into(), from.clone(), Value::Null);
+
+    // Where the data of the zone at `zone_path` goes, from its path or `from` down
+    let moved = |zone_path: &Path| {
+        let mut moved = to.clone();
+
+        moved.append(&mut zone_path.slice(cmp::min(zone_path.len(), from.len())));
+        moved
+    };
+
+    let empty = || NodeTree { node: Default::default(), vis: Vis::permanent() };
+
+    // Found before any are held, as the manager may wait on zones held
+    let pieces = read_each(app, tx, command, from);
+
+    if pieces.iter().all(|&(_, _, ref update)| update.is_none()) {
+        return conflict;
+    }
+
+    let mut zones = BTreeMap::new();
+
+    for &(ref zone_path, ref zone, ref update) in &pieces {
+        if update.is_some() {
+            let (prefix, zone) = app.manager.find_nearest(&moved(zone_path));
+
+            zones.insert(prefix, zone);
+        }
+
+        zones.insert(zone_path.clone(), zone.clone());
+    }
+
+    for (zone_path, zone, _) in read_each(app, tx, command, to) {
+        zones.insert(zone_path, zone);
+    }
+
+    // In path order, see `ZoneHandle::hold`
+    let mut holds = BTreeMap::new();
+
+    for (zone_path, zone) in zones {
+        let paths = vec![within(from, &zone_path), within(to, &zone_path)];
+
+        let (hold, reads) = match zone.hold(paths.iter().filter_map(|path| path.clone()).collect()) {
+            Some(held) => held,
+            None => return conflict // dropping holds releases them
+        };
+
+        // Read of either path, if the zone has any of it
+        let mut reads = reads.into_iter();
+        let reads: Vec<_> = paths.iter().map(|path| path.as_ref().and_then(|_| reads.next())).collect();
+
+        holds.insert(zone_path, (hold, reads));
+    }
+
+    // Unless zones split since, those held own all of the data on either side
+    let all_held = holds.iter().all(|(zone_path, &(_, ref reads))| reads.iter().flat_map(|read| read.iter()).all(|&(_, ref delegated)| delegated.iter().all(|d| {
+        let mut path = zone_path.clone();
+
+        path.append(&mut d.path.clone());
+        holds.contains_key(&path)
+    })));
+
+    if ! all_held {
+        return conflict;
+    }
+
+    let ts = clock::now();
+    let held: Vec<Path> = holds.keys().cloned().collect();
+    let mut diffs: BTreeMap<Path, NodeTree> = BTreeMap::new();
+    let mut moves = vec![];
+    let mut commits = vec![];
+
+    for (zone_path, (hold, reads)) in holds {
+        let mut reads = reads.into_iter().map(|read| read.and_then(|(update, _)| update));
+        let (source, replaced) = (reads.next().unwrap(), reads.next().unwrap());
+        let at = |path: &Path| within_zone(path, &zone_path);
+
+        if replaced.and_then(|replaced| replaced.at(&at(to))).is_some() {
+            diffs.entry(zone_path.clone()).or_insert_with(&empty).merge(&mut Node::delete(command.timestamp).prepend_path(&at(to).path).noop_vis());
+        }
+
+        if let Some(update) = source.and_then(|source| source.at(&at(from))) {
+            moves.push((moved(&zone_path), update));
+
+            if command.call == Call::Move {
+                diffs.entry(zone_path.clone()).or_insert_with(&empty).merge(&mut Node::delete(ts).prepend_path(&at(from).path).noop_vis());
+            }
+        }
+
+        commits.push((zone_path, hold));
+    }
+
+    if moves.is_empty() {
+        return conflict;
+    }
+
+    for (destination, update) in moves {
+        let node = update.to_node(ts);
+
+        // Checked before any zone is sent its part, which releases them all
+        if let Err(invalid) = app.schema.check(&destination, &NodeTree { node: node.clone(), vis: Vis::permanent() }.to_plain_json()) {
+            return ("invalid".into(), to.clone(), invalid.to_json());
+        }
+
+        // The held zone nearest it, the others delegating it there
+        let owner = held.iter().filter(|zone_path| destination.starts_with(zone_path)).max_by_key(|zone_path| zone_path.len()).unwrap();
+        let node = node.prepend_path(&destination.slice(owner.len()).path);
+
+        diffs.entry(owner.clone()).or_insert_with(&empty).merge(&mut node.noop_vis());
+    }
+
+    // Zones with nothing to change are released as they are dropped
+    for (zone_path, hold) in commits {
+        if let Some(diff) = diffs.remove(&zone_path) {
+            hold.commit(diff.node.noop_vis());
+        }
+    }
+
+    (0.into(), to.clone(), Value::Null)
+}
+
+/// What to read of `path` in the zone at `zone_path`: all of it from `path` or the zone's path
+/// down, whichever is below the other, or `None` if neither is.
+fn within(path: &Path, zone_path: &Path) -> Option<Path> {
+    if ! path.starts_with(zone_path) && ! zone_path.starts_with(path) {
+        return None;
+    }
+
+    let mut read = within_zone(path, zone_path);
+
+    read.push(&"*#".to_string());
+    Some(read)
+}
+
+/// `path` relative to the zone at `zone_path`, the zone's own path if below `path`.
+fn within_zone(path: &Path, zone_path: &Path) -> Path {
+    path.slice(cmp::min(zone_path.len(), path.len()))
+}
+
+/// Reads all of the data at the path of `command`, without wildcards, as plain JSON, without
+/// versions, from every zone it is in. `Null` if there is none.
+pub fn read_plain(app: &AppHandle, command: &Command) -> Value {
+    let (tx, _rx) = channel();
+    let mut json = Value::Null;
+
+    for (zone_path, update) in read_all(app, &tx, command, &command.path) {
+        let tree = NodeTree { node: update.to_node(1), vis: Vis::permanent() };
+        let below = zone_path.slice(cmp::min(zone_path.len(), command.path.len()));
+
+        insert(&mut json, &below, tree.to_plain_json());
+    }
+
+    json
+}
+
+/// Writes or kills as `command` says, like commands of other clients, for gateways replying their
+/// own way.
+pub fn write(app: &AppHandle, command: Command) -> Result<(), Refused> {
+    let (tx, rx) = channel();
+
+    process(app, &tx, command, false);
+
+    // `[ id, replies left or why it failed, path, data ]`, sent before `process` returned
+    let reply: Value = match rx.try_recv().ok().and_then(|reply| serde_json::from_str(&reply).ok()) {
+        Some(reply) => reply,
+        None => return Err(Refused::Failed("No reply".to_string()))
+    };
+
+    match reply[1].as_str() {
+        Some("frozen") => Err(Refused::Frozen),
+        Some("invalid") => Err(Refused::Invalid(reply[3].clone())),
+        Some(error) => Err(Refused::Failed(error.to_string())),
+        None => Ok(())
+    }
+}
+
+/// Reads all of the data at `path`, without wildcards, as of `command` from every zone it is in.
+/// Returns the path of each zone with some of it, and the data read there, from `path` down or,
+/// for zones below it, from the zone's path down.
+pub fn read_all(app: &AppHandle, tx: &Sender<String>, command: &Command, path: &Path) -> Vec<(Path, Update)> {
+    read_each(app, tx, command, path).into_iter().filter_map(|(zone_path, _, update)| update.map(|update| (zone_path, update))).collect()
+}
+
+/// Like `read_all`, returning every zone reached, with or without data, and its handle.
+fn read_each(app: &AppHandle, tx: &Sender<String>, command: &Command, path: &Path) -> Vec<(Path, ZoneHandle, Option<Update>)> {
+    let (prefix, _) = app.manager.find_nearest(path);
+    let mut read = path.slice(prefix.len());
+
+    read.push(&"*#".to_string());
+
+    let mut queue = VecDeque::new();
+    let mut pieces = vec![];
+
+    queue.push_back(DelegatedMatch { path: prefix, match_spec: read });
+
+    while let Some(delegated) = queue.pop_front() {
+        let zone = app.manager.load(&delegated.path);
+
+        let c = Command {
+            call: Call::Read,
+            path: delegated.match_spec,
+            params: Value::Null,
+            ..command.clone()
+        };
+
+        let result = zone.dispatch(c, tx);
+
+        for mut d in result.delegated {
+            let mut path = delegated.path.clone();
+
+            path.append(&mut d.path);
+            d.path = path;
+            queue.push_back(d);
+        }
+
+        // Zones delegated to are read whole, the first one from the path down
+        let at = path.slice(cmp::min(delegated.path.len(), path.len()));
+
+        pieces.push((delegated.path, zone, result.update.and_then(|update| update.at(&at))));
+    }
+
+    pieces
+}
+
+/// Sets the value at `path` below `json` to `value`, unless it's `Null`, making objects along it.
+fn insert(json: &mut Value, path: &Path, value: Value) {
+    if value.is_null() {
+        return;
+    }
+
+    let mut json = json;
+
+    for key in path.iter() {
+        if ! json.is_object() {
+            *json = Value::Object(serde_json::Map::new());
+        }
+
+        json = json.as_object_mut().unwrap().entry(key.to_string()).or_insert(Value::Null);
+    }
+
+    *json = value;
 }
 
 fn pinger(tx: Sender<String>) {
@@ -212,3 +753,122 @@ fn pinger(tx: Sender<String>) {
         }
     });
 }
+
+#[test]
+fn test_insert() {
+    let path = |path: &str| path.parse::<Path>().unwrap();
+
+    let mut json = Value::Null;
+
+    insert(&mut json, &path("users.moo"), Value::from(1));
+    insert(&mut json, &path("users.cow.name"), Value::from(2));
+    insert(&mut json, &path("users.pig"), Value::Null);
+
+    let mut users = serde_json::Map::new();
+    let mut cow = serde_json::Map::new();
+
+    cow.insert("name".to_string(), Value::from(2));
+    users.insert("moo".to_string(), Value::from(1));
+    users.insert("cow".to_string(), Value::Object(cow));
+
+    let mut expected = serde_json::Map::new();
+
+    expected.insert("users".to_string(), Value::Object(users));
+    assert_eq!(json, Value::Object(expected));
+}
+
+#[test]
+fn test_move() {
+    use std::thread;
+
+    use app::App;
+    use manager::Manager;
+    use schema::Schema;
+    use store;
+
+    let path = |path: &str| path.parse::<Path>().unwrap();
+    let command = |call: Call, path: Path, params: Value| Command { id: 1, call: call, path: path, params: params, timestamp: clock::now() };
+    let object = |key: &str, value: Value| Value::Object(vec![(key.to_string(), value)].into_iter().collect());
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    app.schema = Arc::new(Schema::from_json(r#"{ "users.*": { "type": "object" } }"#).unwrap());
+    store::spawn_custom(&mut app, Box::new(store::null::Null), &Default::default());
+    Manager::spawn(&mut app);
+
+    let handle = app.handle();
+
+    handle.manager.load(&Path::empty());
+
+    let mut data = object("drafts", object("moo", Value::from(1)));
+
+    data.as_object_mut().unwrap().insert("posts".to_string(), object("cow", Value::from(2)));
+    assert_eq!(write(&handle, command(Call::Write, Path::empty(), data)), Ok(()));
+
+    // Read while moved from one post to the next, always in exactly one place
+    let reader = {
+        let handle = handle.clone();
+
+        thread::spawn(move || {
+            for _ in 0..100 {
+                let json = read_plain(&handle, &command(Call::Read, Path::empty(), Value::Null));
+                let found = [&json["drafts"], &json["posts"]].iter()
+                    .filter_map(|parent| parent.as_object())
+                    .flat_map(|parent| parent.iter())
+                    .filter(|&(_, value)| *value == Value::from(1))
+                    .count();
+
+                assert_eq!(found, 1, "{}", json);
+            }
+        })
+    };
+
+    let mut from = path("drafts.moo");
+
+    for i in 0..100 {
+        let to = path(&format!("posts.moo{}", i));
+
+        assert_eq!(write(&handle, command(Call::Move, from, to.to_json())), Ok(()));
+        from = to;
+    }
+
+    reader.join().unwrap();
+
+    let json = read_plain(&handle, &command(Call::Read, Path::empty(), Value::Null));
+
+    assert!(json["drafts"]["moo"].is_null());
+    assert_eq!(json["posts"]["moo99"], Value::from(1));
+    assert_eq!(json["posts"]["cow"], Value::from(2));
+
+    // Nothing to move conflicts
+    assert_eq!(write(&handle, command(Call::Move, path("drafts.moo"), path("drafts.cow").to_json())), Err(Refused::Failed("conflict".to_string())));
+
+    // Across zones, gone from one once in the other
+    assert_eq!(write(&handle, command(Call::Write, path("archive"), object("cow", Value::from(3)))), Ok(()));
+
+    handle.manager.load(&Path::empty()).merge(Node::delegate(clock::now()).prepend_path(&["archive"]).noop_vis(), true);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(handle.manager.find_nearest(&path("archive.moo")).0, path("archive"));
+
+    assert_eq!(write(&handle, command(Call::Move, path("posts.moo99"), path("archive.moo").to_json())), Ok(()));
+    assert!(read_plain(&handle, &command(Call::Read, path("posts.moo99"), Value::Null)).is_null());
+    assert_eq!(read_plain(&handle, &command(Call::Read, path("archive.moo"), Value::Null)), Value::from(1));
+
+    // Rejected by the schema at the destination, within a zone or across them, and not written
+    let invalid = || {
+        let mut invalid = object("path", path("users.cow").to_json());
+
+        invalid.as_object_mut().unwrap().insert("error".to_string(), "Expected object".into());
+        Err(Refused::Invalid(invalid))
+    };
+
+    assert_eq!(write(&handle, command(Call::Copy, path("posts.cow"), path("users.cow").to_json())), invalid());
+    assert_eq!(write(&handle, command(Call::Move, path("archive.cow"), path("users.cow").to_json())), invalid());
+    assert!(read_plain(&handle, &command(Call::Read, path("users"), Value::Null)).is_null());
+    assert_eq!(read_plain(&handle, &command(Call::Read, path("archive.cow"), Value::Null)), Value::from(3));
+
+    // And back, from the zone to its parent
+    assert_eq!(write(&handle, command(Call::Move, path("archive"), path("drafts.archive").to_json())), Ok(()));
+    assert!(read_plain(&handle, &command(Call::Read, path("archive"), Value::Null)).is_null());
+    assert_eq!(read_plain(&handle, &command(Call::Read, path("drafts.archive.moo"), Value::Null)), Value::from(1));
+}
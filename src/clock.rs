@@ -0,0 +1,61 @@
+//! Hybrid logical clock for write timestamps.
+//!
+//! Every value in a zone carries the timestamp of the write that set it, which is its version:
+//! reads, binds and notifications return it next to the value, and of two writes to a path the one
+//! with the later timestamp wins (see `node`). Timestamps are nanoseconds since the epoch, but never
+//! go backwards, and are never handed out twice, even when the wall clock steps back. Merges from
+//! replicas move the clock past their timestamps too, so a write made after seeing a replica's
+//! value wins over it even if that replica's clock is ahead.
+
+use std::cmp;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Last timestamp handed out or seen
+static LAST: AtomicU64 = AtomicU64::new(0);
+
+/// Timestamp for a write, later than any handed out or seen so far.
+pub fn now() -> u64 {
+    let mut last = LAST.load(Ordering::SeqCst);
+
+    loop {
+        let next = cmp::max(wall(), last + 1);
+
+        match LAST.compare_exchange(last, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return next,
+            Err(current) => last = current
+        }
+    }
+}
+
+/// Moves the clock past `timestamp`, of a write made elsewhere.
+pub fn observe(timestamp: u64) {
+    LAST.fetch_max(timestamp, Ordering::SeqCst);
+}
+
+/// Wall clock time in nanoseconds since the epoch.
+fn wall() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64,
+        Err(_) => 0
+    }
+}
+
+#[test]
+fn test_now() {
+    let first = now();
+
+    assert!(now() > first);
+
+    // Past a timestamp from a clock that's ahead
+    let ahead = wall() + 60 * 1000000000;
+
+    observe(ahead);
+    assert!(now() > ahead);
+
+    // Seeing older ones changes nothing
+    let last = now();
+
+    observe(first);
+    assert!(now() > last);
+}
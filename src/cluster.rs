@@ -9,6 +9,7 @@ use std::thread::Builder;
 use bincode;
 
 use app::{App, AppHandle};
+use clock;
 use node::NodeTree;
 use path::Path;
 use replica::Replica;
@@ -172,6 +173,7 @@ impl Cluster {
                 // TODO thread pool
                 let zone = self.app.manager.load(&path);
 
+                clock::observe(data.node.latest());
                 zone.merge(data, false);
             },
             ClusterMessage::Sync => self.sync()
@@ -212,12 +214,17 @@ impl Cluster {
 
     /// Synchronize each Zone to all Peers.
     pub fn sync(&self) {
-        self.app.store.each_zone(|path| {
+        let listed = self.app.store.each_zone(|path| {
             match self.app.store.load_data(path.clone()) {
-                None => println!("Could not sync {:?}", path),
-                Some(data) => self.replicate(path, data.tree)
+                Err(err) => println!("Could not sync {:?}: {}", path, err),
+                Ok(None) => println!("Could not sync {:?}", path),
+                Ok(Some(data)) => self.replicate(path, data.tree)
             }
-        })
+        });
+
+        if let Err(err) = listed {
+            println!("Could not sync: {}", err);
+        }
     }
 
     /// Request all peers to synchronize local data.
@@ -228,10 +235,16 @@ impl Cluster {
 
     /// Synchronize Zone to all Peers.
     pub fn sync_zone(&self, path: Path) {
-        // TODO: does not check for non-existent Zones
+        match self.app.store.stat(&path) {
+            Err(err) => return println!("Could not sync {:?}: {}", path, err),
+            Ok(None) => return println!("Nothing stored to sync for {:?}", path),
+            Ok(Some(_)) => ()
+        }
+
         match self.app.store.load_data(path.clone()) {
-            None => println!("Could not sync {:?}", path),
-            Some(data) => self.replicate(path, data.tree)
+            Err(err) => println!("Could not sync {:?}: {}", path, err),
+            Ok(None) => println!("Could not sync {:?}", path),
+            Ok(Some(data)) => self.replicate(path, data.tree)
         }
     }
 
@@ -0,0 +1,230 @@
+//! In-memory compression of cold zone data.
+//!
+//! With `ZONE_COLD=<idle seconds>[:<min bytes>[:<compression>]]`, loaded zones keep the children
+//! of their root that haven't been used for that long, and take up at least `<min bytes>` (64KB by
+//! default), serialized and compressed (`lz4` by default, see `store::codec`) rather than as nodes.
+//! A child is inflated again as soon as a command or merge reaches it, and all of them are before
+//! the zone writes all of its data, splits, folds or hands it out. Zones trade the CPU to pack and
+//! inflate data for less memory taken by large parts hardly ever read.
+//!
+//! Only zones with nothing left to write pack data, so deltas (see `region`) never need it, and
+//! children with data delegated to other zones stay as they are.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use bincode;
+
+use node::Node;
+use path::Path;
+use store::codec::{self, Compression};
+
+/// Smallest child packed by default, in estimated bytes
+pub const DEFAULT_MIN_BYTES: usize = 64 * 1024;
+
+/// When zones pack their children.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    pub idle: u64,         // Seconds unused
+    pub min_bytes: usize,  // Estimated size, see `Node::total_byte_size`
+    pub compression: Compression
+}
+
+/// Children of a zone's root packed, and when each was last used.
+#[derive(Debug, Default)]
+pub struct Cold {
+    packed: BTreeMap<String, Packed>,
+    used: BTreeMap<String, usize> // Milliseconds on a monotonic clock
+}
+
+/// A serialized and compressed child.
+#[derive(Debug)]
+struct Packed {
+    bytes: Vec<u8>,
+    size: usize // Estimated size inflated
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    /// Parses `<idle seconds>[:<min bytes>[:<compression>]]`.
+    fn from_str(s: &str) -> Result<Policy, String> {
+        let mut parts = s.splitn(3, ':');
+
+        let idle = match parts.next().map(|idle| idle.parse()) {
+            Some(Ok(idle)) => idle,
+            _ => return Err(format!("Bad idle seconds: {}", s))
+        };
+
+        let min_bytes = match parts.next().map(|min_bytes| min_bytes.parse()) {
+            Some(Ok(min_bytes)) => min_bytes,
+            Some(Err(_)) => return Err(format!("Bad min bytes: {}", s)),
+            None => DEFAULT_MIN_BYTES
+        };
+
+        let compression = match parts.next() {
+            Some(compression) => try!(compression.parse()),
+            None => Compression::Lz4
+        };
+
+        if compression == Compression::None {
+            return Err("Packing without compression saves nothing".to_string());
+        }
+
+        if ! compression.is_available() {
+            return Err(format!("Compression {} not compiled in", compression));
+        }
+
+        Ok(Policy { idle: idle, min_bytes: min_bytes, compression: compression })
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "after {} seconds, from {} bytes, with {}", self.idle, self.min_bytes, self.compression)
+    }
+}
+
+impl Cold {
+    pub fn is_empty(&self) -> bool {
+        self.packed.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.packed.len()
+    }
+
+    /// Forgets everything packed, for a zone whose data is gone or replaced.
+    pub fn clear(&mut self) {
+        self.packed.clear();
+        self.used.clear();
+    }
+
+    /// Estimated bytes saved by packing.
+    pub fn saved(&self) -> usize {
+        self.packed.values().map(|packed| packed.size.saturating_sub(packed.bytes.len())).sum()
+    }
+
+    /// Inflates the children of `root` that `path`, relative to it, reaches back into it, all of
+    /// them if it starts with a wildcard, and marks them used at `now`.
+    pub fn thaw(&mut self, root: &mut Node, path: &Path, now: usize) {
+        match path.path.first() {
+            Some(key) if ! key.starts_with('*') => {
+                self.used.insert(key.to_string(), now);
+                self.inflate(root, key);
+            },
+            _ => {
+                for used in self.used.values_mut() {
+                    *used = now;
+                }
+
+                self.thaw_all(root);
+            }
+        }
+    }
+
+    /// Inflates every child packed back into `root`.
+    pub fn thaw_all(&mut self, root: &mut Node) {
+        let keys: Vec<String> = self.packed.keys().cloned().collect();
+
+        for key in keys {
+            self.inflate(root, &key);
+        }
+    }
+
+    /// Packs the children of `root` unused since `policy.idle` before `now`, and big enough.
+    /// Children seen for the first time count as used now. Returns the number packed.
+    pub fn pack(&mut self, root: &mut Node, policy: &Policy, now: usize) -> usize {
+        let idle = (policy.idle * 1000) as usize;
+        let mut cold = vec![];
+
+        root.each_child(|key, child| {
+            let used = *self.used.entry(key.clone()).or_insert(now);
+
+            if now.saturating_sub(used) >= idle && child.total_byte_size() >= policy.min_bytes && child.delegations().is_empty() {
+                cold.push(key.clone());
+            }
+        });
+
+        let mut count = 0;
+
+        for key in cold {
+            let child = root.remove_child(&key).unwrap();
+            let size = key.len() + child.total_byte_size();
+
+            let bytes = bincode::serialize(&child, bincode::Infinite).map_err(|err| err.to_string())
+                .and_then(|serialized| codec::compress(serialized, policy.compression).map_err(|err| err.to_string()));
+
+            match bytes {
+                Ok(bytes) => {
+                    self.packed.insert(key, Packed { bytes: bytes, size: size });
+                    count += 1;
+                },
+                Err(err) => {
+                    println!("Error packing {}: {}", key, err);
+                    root.add_child(key, child);
+                }
+            }
+        }
+
+        count
+    }
+
+    fn inflate(&mut self, root: &mut Node, key: &str) {
+        let packed = match self.packed.remove(key) {
+            Some(packed) => packed,
+            None => return
+        };
+
+        // Packed by this node from its own data, so this only fails on a bug
+        let child: Node = codec::decompress(packed.bytes).map_err(|err| err.to_string())
+            .and_then(|serialized| bincode::deserialize(&serialized).map_err(|err| err.to_string()))
+            .unwrap_or_else(|err| panic!("Could not inflate {}: {}", key, err));
+
+        root.add_child(key.to_string(), child);
+    }
+}
+
+#[test]
+fn test_pack() {
+    use serde_json::{Map, Value};
+
+    let policy = Policy { idle: 1, min_bytes: 4, compression: Compression::None };
+    let mut cold = Cold::default();
+
+    let mut data = Map::new();
+
+    data.insert("moo".to_string(), Value::from("moo moo moo"));
+    data.insert("cow".to_string(), Value::from("m"));
+
+    let mut root = Node::expand(Value::Object(data), 1000);
+    let original = root.clone();
+
+    // Seen for the first time, then unused for a second
+    assert_eq!(cold.pack(&mut root, &policy, 0), 0);
+    assert_eq!(cold.pack(&mut root, &policy, 1000), 1);
+    assert_eq!(cold.len(), 1);
+    assert_eq!(root.len(), 1);
+
+    // Used again
+    cold.thaw(&mut root, &Path::new(vec!["moo".to_string()]), 1000);
+    assert!(cold.is_empty());
+    assert_eq!(root, original);
+    assert_eq!(cold.pack(&mut root, &policy, 1500), 0);
+
+    cold.pack(&mut root, &policy, 2000);
+    cold.thaw(&mut root, &Path::new(vec!["cow".to_string()]), 2000);
+    assert_eq!(cold.len(), 1);
+
+    cold.thaw(&mut root, &Path::new(vec!["**".to_string()]), 2000);
+    assert_eq!(root, original);
+
+    match "600".parse::<Policy>() {
+        Ok(policy) => assert_eq!(policy, Policy { idle: 600, min_bytes: DEFAULT_MIN_BYTES, compression: Compression::Lz4 }),
+        Err(_) => assert!(! Compression::Lz4.is_available())
+    }
+
+    assert!("600:moo".parse::<Policy>().is_err());
+    assert!("600:10:none".parse::<Policy>().is_err());
+}
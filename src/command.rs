@@ -2,9 +2,13 @@
 
 use serde_json;
 use serde_json::Value;
-use time;
 
-use path::Path;
+use clock;
+use crdt;
+use lease;
+use list;
+use listener;
+use path::{Limits, Path, PathError};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Command {
@@ -18,58 +22,222 @@ pub struct Command {
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Call {
     Bind,
+    Cas,
+    Copy,
+    Crdt,
+    Diff,
+    Expire,
+    Incr,
     Kill,
+    List,
+    Lock,
+    Move,
     Read,
+    Snapshot,
+    Txn,
     Write
 }
 
 impl Command {
     pub fn from_json(json: &str) -> Result<Command, String> {
+        Command::from_json_with(json, &Limits::default())
+    }
+
+    /// Same as `from_json`, with paths, those of `txn` ops and destinations included, checked
+    /// within `limits`.
+    pub fn from_json_with(json: &str, limits: &Limits) -> Result<Command, String> {
         let data: Value = try!(serde_json::from_str(json).or(Err("Bad JSON")));
-        let data = try!(data.as_array().ok_or("Not array"));
+
+        Command::from_value_with(data, limits)
+    }
+
+    /// Same as `from_json_with`, for commands decoded already, see `binary`.
+    pub fn from_value_with(data: Value, limits: &Limits) -> Result<Command, String> {
+        let mut data = match data {
+            Value::Array(data) => data,
+            _ => return Err("Not array".to_string())
+        };
 
         if data.len() != 4 {
             return Err("Wrong number of elements".to_string());
         }
 
         let id   = try!(data[0].as_u64().ok_or("Bad ID"));
-        let call = try!(data[1].as_str().ok_or("Bad call"));
-        let path = try!(data[2].as_array().ok_or("Bad path"));
+        let call = try!(parse_call(&data[1]));
+        let path = try!(parse_path(&data[2], limits));
+
+        let command = Command {
+            id: id,
+            call: call,
+            path: path,
+            params: data.pop().unwrap(),
+            timestamp: clock::now()
+        };
+
+        try!(command.check_params(limits));
+
+        Ok(command)
+    }
+
+    /// Returns the calls of a `txn` command, `[ call, path, params ]` each, with paths relative to
+    /// the command's. Only `write`, `kill` and `cas` can be part of one.
+    pub fn ops(&self) -> Result<Vec<Command>, String> {
+        self.parse_ops(&Limits::none()) // checked when parsed
+    }
+
+    /// Returns the path a `copy` or `move` command copies or moves its path to, its params. Neither
+    /// can have wildcards or be below the other.
+    pub fn destination(&self) -> Result<Path, String> {
+        self.parse_destination(&Limits::none())
+    }
+
+    fn parse_ops(&self, limits: &Limits) -> Result<Vec<Command>, String> {
+        let ops = try!(self.params.as_array().ok_or("Bad txn ops"));
+        let mut commands = Vec::with_capacity(ops.len());
+
+        for op in ops {
+            let op = match op.as_array() {
+                Some(op) if op.len() == 3 => op,
+                _ => return Err("Bad txn op".to_string())
+            };
+
+            let call = try!(parse_call(&op[0]));
+
+            match call {
+                Call::Cas | Call::Kill | Call::Write => (),
+                _ => return Err("Bad txn call".to_string())
+            }
+
+            let mut path = self.path.clone();
+
+            path.append(&mut try!(parse_path(&op[1], limits)));
+
+            if path.len() > limits.depth {
+                return Err(PathError::TooDeep(path.len(), limits.depth).to_string());
+            }
 
-        let mut path_string: Vec<String> = vec![];
+            let command = Command {
+                call: call,
+                path: path,
+                params: op[2].clone(),
+                ..*self
+            };
 
-        for p in path.iter() {
-            path_string.push(try!(p.as_str().ok_or("Bad path")).to_string());
+            try!(command.check_params(limits));
+            commands.push(command);
         }
 
-        let params = data[3].clone();
+        Ok(commands)
+    }
 
-        let call = match call {
-            "bind" => Call::Bind,
-            "kill" => Call::Kill,
-            "read" => Call::Read,
-            "write" => Call::Write,
-            _ => return Err("Bad call".to_string())
-        };
+    fn parse_destination(&self, limits: &Limits) -> Result<Path, String> {
+        let destination = try!(parse_path(&self.params, limits));
+        let (from, to) = (&self.path.path, &destination.path);
 
-        Ok(Command {
-            id: id,
-            call: call,
-            path: Path { path: path_string },
-            params: params,
-            timestamp: time::precise_time_ns()
-        })
+        if from.iter().chain(to).any(|key| key.starts_with('*')) {
+            return Err("Bad destination, wildcards".to_string());
+        }
+
+        if from.iter().zip(to).all(|(a, b)| a == b) {
+            return Err("Bad destination, paths overlap".to_string());
+        }
+
+        Ok(destination)
     }
 
     /// Returns true if delegated data requires separate calls.
     ///
-    /// Right now, only `Call::Bind` and Call::Read` fall into this category
+    /// Right now, only `Call::Bind`, `Call::Diff`, `Call::Read` and `Call::Snapshot` fall into this
+    /// category
     pub fn recursive(&self) -> bool {
         match self.call {
-            Call::Bind | Call::Read => true,
+            Call::Bind | Call::Diff | Call::Read | Call::Snapshot => true,
             _ => false
         }
     }
+
+    /// Whether the command changes data, see `freeze`.
+    pub fn writes(&self) -> bool {
+        match self.call {
+            Call::Bind | Call::Diff | Call::Lock | Call::Read | Call::Snapshot => false,
+            _ => true
+        }
+    }
+
+    fn check_params(&self, limits: &Limits) -> Result<(), String> {
+        match self.call {
+            Call::Bind => listener::bind_filter(&self.params).map(|_| ()),
+            // `[ expected version or null, value ]`
+            Call::Cas => match self.params.as_array() {
+                Some(params) if params.len() == 2 && (params[0].is_null() || params[0].is_u64()) => Ok(()),
+                _ => Err("Bad cas params".to_string())
+            },
+            // `name` to take, or `{ "drop": name }`
+            Call::Snapshot if self.params.is_string() => Ok(()),
+            Call::Snapshot => match self.params.get("drop") {
+                Some(name) if name.is_string() => Ok(()),
+                _ => Err("Bad snapshot params".to_string())
+            },
+            Call::Crdt => crdt::Op::from_params(&self.params).map(|_| ()),
+            // Name of the snapshot to diff against
+            Call::Diff if self.params.is_string() => Ok(()),
+            Call::Diff => Err("Bad diff params".to_string()),
+            // Number to add
+            Call::Incr if self.params.is_number() => Ok(()),
+            Call::Incr => Err("Bad incr params".to_string()),
+            Call::List => list::Op::from_params(&self.params).map(|_| ()),
+            Call::Lock => lease::Request::from_params(&self.params).map(|_| ()),
+            Call::Copy | Call::Move => self.parse_destination(limits).map(|_| ()),
+            Call::Txn => self.parse_ops(limits).map(|_| ()),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Splits `cas` params into the expected version, `None` for no value, and the value to write.
+pub fn cas_params(params: Value) -> (Option<u64>, Value) {
+    match params {
+        Value::Array(mut params) => {
+            let value = params.pop().unwrap_or_default();
+
+            (params.pop().and_then(|expected| expected.as_u64()), value)
+        },
+        _ => (None, params)
+    }
+}
+
+fn parse_call(call: &Value) -> Result<Call, String> {
+    match try!(call.as_str().ok_or("Bad call")) {
+        "bind" => Ok(Call::Bind),
+        "cas" => Ok(Call::Cas),
+        "copy" => Ok(Call::Copy),
+        "crdt" => Ok(Call::Crdt),
+        "diff" => Ok(Call::Diff),
+        "expire" => Ok(Call::Expire),
+        "incr" => Ok(Call::Incr),
+        "kill" => Ok(Call::Kill),
+        "list" => Ok(Call::List),
+        "lock" => Ok(Call::Lock),
+        "move" => Ok(Call::Move),
+        "read" => Ok(Call::Read),
+        "snapshot" => Ok(Call::Snapshot),
+        "txn" => Ok(Call::Txn),
+        "write" => Ok(Call::Write),
+        _ => Err("Bad call".to_string())
+    }
+}
+
+/// Parses a path sent by a client, refusing keys that aren't legal (see `Path::try_new_with`).
+fn parse_path(path: &Value, limits: &Limits) -> Result<Path, String> {
+    let path = try!(path.as_array().ok_or("Bad path"));
+
+    let mut path_string: Vec<String> = vec![];
+
+    for p in path.iter() {
+        path_string.push(try!(p.as_str().ok_or("Bad path")).to_string());
+    }
+
+    Path::try_new_with(path_string, limits).map_err(|err| err.to_string())
 }
 
 #[test]
@@ -80,6 +248,92 @@ fn test_from_json() {
     let result = Command::from_json(r#"[ 1, "write", [], 42 ]"#).unwrap();
     assert_eq!(result.call, Call::Write);
 
+    let result = Command::from_json(r#"[ 1, "write", [ "moo", "" ], 42 ]"#);
+    assert_eq!(result, Err("Bad path, empty key at 1".to_string()));
+
+    let result = Command::from_json(r#"[ 1, "expire", [ "moo" ], 60 ]"#).unwrap();
+    assert_eq!(result.call, Call::Expire);
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], [ 1000, 42 ] ]"#).unwrap();
+    assert_eq!(result.call, Call::Cas);
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], [ null, 42 ] ]"#).unwrap();
+    assert_eq!(result.call, Call::Cas);
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], 42 ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "cas", [ "moo" ], [ "moo", 42 ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "txn", [ "moo" ], [ [ "write", [ "cow" ], 42 ], [ "kill", [], null ] ] ]"#).unwrap();
+    assert_eq!(result.call, Call::Txn);
+
+    let ops = result.ops().unwrap();
+    assert_eq!(ops.len(), 2);
+    assert_eq!(ops[0].call, Call::Write);
+    assert_eq!(ops[0].path, Path::new(vec!["moo".to_string(), "cow".to_string()]));
+    assert_eq!(ops[1].call, Call::Kill);
+    assert_eq!(ops[1].path, Path::new(vec!["moo".to_string()]));
+
+    let result = Command::from_json(r#"[ 1, "txn", [], [ [ "read", [], null ] ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "txn", [], [ [ "cas", [], 42 ] ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "snapshot", [ "moo" ], "before" ]"#).unwrap();
+    assert_eq!(result.call, Call::Snapshot);
+    assert!(result.recursive());
+
+    let result = Command::from_json(r#"[ 1, "snapshot", [ "moo" ], { "drop": "before" } ]"#).unwrap();
+    assert_eq!(result.call, Call::Snapshot);
+
+    let result = Command::from_json(r#"[ 1, "snapshot", [ "moo" ], 42 ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "crdt", [ "moo" ], [ "pncounter", "incr", -1 ] ]"#).unwrap();
+    assert_eq!(result.call, Call::Crdt);
+
+    let result = Command::from_json(r#"[ 1, "crdt", [ "moo" ], [ "gcounter", "add", 1 ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "diff", [ "moo", "**" ], "before" ]"#).unwrap();
+    assert_eq!(result.call, Call::Diff);
+
+    let result = Command::from_json(r#"[ 1, "diff", [ "moo" ], null ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "incr", [ "moo" ], -1.5 ]"#).unwrap();
+    assert_eq!(result.call, Call::Incr);
+
+    let result = Command::from_json(r#"[ 1, "incr", [ "moo" ], "1" ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "list", [ "moo" ], [ "insert", 0, "cow" ] ]"#).unwrap();
+    assert_eq!(result.call, Call::List);
+
+    let result = Command::from_json(r#"[ 1, "list", [ "moo" ], [ "pop", 0 ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "lock", [ "moo" ], { "owner": "cow", "ttl": 30 } ]"#).unwrap();
+    assert_eq!(result.call, Call::Lock);
+
+    let result = Command::from_json(r#"[ 1, "lock", [ "moo" ], { "owner": "cow" } ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "move", [ "moo", "cow" ], [ "moo", "pig" ] ]"#).unwrap();
+    assert_eq!(result.destination(), Ok(Path::new(vec!["moo".to_string(), "pig".to_string()])));
+
+    let result = Command::from_json(r#"[ 1, "move", [ "moo" ], [ "moo", "pig" ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "move", [ "moo" ], [ "*" ] ]"#);
+    assert!(result.is_err());
+
+    let result = Command::from_json(r#"[ 1, "copy", [ "moo" ], [ "pig" ] ]"#).unwrap();
+    assert_eq!(result.call, Call::Copy);
+
     let result = Command::from_json(r#"[ 1, "moo", [], 42 ]"#);
     assert!(result.is_err());
 
@@ -92,4 +346,14 @@ fn test_from_json() {
 
     let result = Command::from_json(r#"[ 1, "bind", [ "moo", 42 ], 42 ]"#);
     assert!(result.is_err());
+    let limits = Limits { depth: 2, key_bytes: 3 };
+
+    let result = Command::from_json_with(r#"[ 1, "write", [ "moo", "cow", "pig" ], 42 ]"#, &limits);
+    assert_eq!(result, Err("Bad path, 3 keys deep, at most 2".to_string()));
+
+    let result = Command::from_json_with(r#"[ 1, "txn", [ "moo" ], [ [ "write", [ "cow", "pig" ], 42 ] ] ]"#, &limits);
+    assert!(result.is_err());
+
+    let result = Command::from_json_with(r#"[ 1, "copy", [ "moo" ], [ "cows" ] ]"#, &limits);
+    assert_eq!(result, Err("Bad path, key at 0 longer than 3 bytes".to_string()));
 }
@@ -0,0 +1,308 @@
+//! Convergent replicated data types, stored as values.
+//!
+//! Plain values written to the same path resolve to one of them (see `strategy`), so concurrent
+//! writers lose each other's changes. CRDT values merge instead: a `crdt` command applies an
+//! operation to the value at its path, as `[ type, op, arg ]` params, and every copy of the value
+//! merged, on replicas or from logs, joins with the rest into the same result whatever the order.
+//!
+//! * `gcounter` counts up with `[ "gcounter", "incr", n ]`, n at least 0.
+//! * `pncounter` counts up or down with `[ "pncounter", "incr", n ]`.
+//! * `orset` is a set of JSON values, with `[ "orset", "add", value ]` and `remove`. A value removed
+//!   stays in the set if another writer added it again without seeing the removal.
+//! * `register` holds a single JSON value, set with `[ "register", "set", value ]`, the later set
+//!   winning.
+//!
+//! Values read as what they count or hold: counters as numbers, sets as arrays. Counters count by
+//! node, so each node's changes are kept apart. Kills drop a CRDT value like any other, and an
+//! operation on a path without one, or with a plain value, starts a new one.
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde_json;
+use serde_json::Value as JSON;
+
+/// A CRDT value.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Crdt {
+    GCounter(Counts),
+    PNCounter(Counts, Counts), // Increments, decrements
+    OrSet(OrSet),
+    Register(Register)
+}
+
+/// Count by node.
+pub type Counts = BTreeMap<String, u64>;
+
+/// Observed-remove set of JSON values, kept as JSON text.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct OrSet {
+    added: BTreeMap<String, BTreeSet<String>>, // Tags of each add, by value
+    removed: BTreeSet<String>                  // Tags of adds since removed
+}
+
+/// JSON value and the timestamp it was set at.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Register {
+    value: String,
+    timestamp: u64
+}
+
+/// An operation on a CRDT value, from `crdt` command params.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Incr(i64),
+    Add(JSON),
+    Remove(JSON),
+    Set(JSON)
+}
+
+impl Crdt {
+    /// Empty value of `kind`, one of `gcounter`, `pncounter`, `orset` or `register`.
+    pub fn new(kind: &str) -> Option<Crdt> {
+        match kind {
+            "gcounter" => Some(Crdt::GCounter(Default::default())),
+            "pncounter" => Some(Crdt::PNCounter(Default::default(), Default::default())),
+            "orset" => Some(Crdt::OrSet(Default::default())),
+            "register" => Some(Crdt::Register(Default::default())),
+            _ => None
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            Crdt::GCounter(_) => "gcounter",
+            Crdt::PNCounter(..) => "pncounter",
+            Crdt::OrSet(_) => "orset",
+            Crdt::Register(_) => "register"
+        }
+    }
+
+    /// Applies `op` as the node `replica`, at `timestamp`.
+    pub fn apply(&mut self, op: &Op, replica: &str, timestamp: u64) -> Result<(), String> {
+        match (self, op) {
+            (&mut Crdt::GCounter(ref mut counts), &Op::Incr(n)) if n >= 0 => {
+                *counts.entry(replica.to_string()).or_insert(0) += n as u64;
+            },
+            (&mut Crdt::PNCounter(ref mut up, _), &Op::Incr(n)) if n >= 0 => {
+                *up.entry(replica.to_string()).or_insert(0) += n as u64;
+            },
+            (&mut Crdt::PNCounter(_, ref mut down), &Op::Incr(n)) => {
+                *down.entry(replica.to_string()).or_insert(0) += (-n) as u64;
+            },
+            (&mut Crdt::OrSet(ref mut set), &Op::Add(ref value)) => {
+                let tag = format!("{}@{}", timestamp, replica);
+
+                set.added.entry(value.to_string()).or_insert_with(BTreeSet::new).insert(tag);
+            },
+            (&mut Crdt::OrSet(ref mut set), &Op::Remove(ref value)) => {
+                if let Some(tags) = set.added.get(&value.to_string()) {
+                    set.removed.extend(tags.iter().cloned());
+                }
+            },
+            (&mut Crdt::Register(ref mut register), &Op::Set(ref value)) => {
+                *register = Register { value: value.to_string(), timestamp: timestamp };
+            },
+            (crdt, op) => return Err(format!("Bad {} op: {:?}", crdt.kind(), op))
+        }
+
+        Ok(())
+    }
+
+    /// Merges `other` into this value. Returns false if it's another kind, which can't be.
+    pub fn join(&mut self, other: &Crdt) -> bool {
+        match (self, other) {
+            (&mut Crdt::GCounter(ref mut counts), &Crdt::GCounter(ref other)) => {
+                join_counts(counts, other);
+            },
+            (&mut Crdt::PNCounter(ref mut up, ref mut down), &Crdt::PNCounter(ref other_up, ref other_down)) => {
+                join_counts(up, other_up);
+                join_counts(down, other_down);
+            },
+            (&mut Crdt::OrSet(ref mut set), &Crdt::OrSet(ref other)) => {
+                for (value, tags) in &other.added {
+                    set.added.entry(value.clone()).or_insert_with(BTreeSet::new).extend(tags.iter().cloned());
+                }
+
+                set.removed.extend(other.removed.iter().cloned());
+            },
+            (&mut Crdt::Register(ref mut register), &Crdt::Register(ref other)) => {
+                if (other.timestamp, &other.value) > (register.timestamp, &register.value) {
+                    *register = other.clone();
+                }
+            },
+            _ => return false
+        }
+
+        true
+    }
+
+    /// What this value counts or holds, as read by clients.
+    pub fn to_json(&self) -> JSON {
+        match *self {
+            Crdt::GCounter(ref counts) => total(counts).into(),
+            Crdt::PNCounter(ref up, ref down) => (total(up) as i64 - total(down) as i64).into(),
+            Crdt::OrSet(ref set) => JSON::Array(set.values().iter().map(|value| parse(value)).collect()),
+            Crdt::Register(ref register) => parse(&register.value)
+        }
+    }
+
+    /// Estimated size once stored.
+    pub fn byte_size(&self) -> usize {
+        let size = |counts: &Counts| counts.keys().map(|replica| replica.len() + 8).sum::<usize>();
+
+        match *self {
+            Crdt::GCounter(ref counts) => size(counts),
+            Crdt::PNCounter(ref up, ref down) => size(up) + size(down),
+            Crdt::OrSet(ref set) => {
+                set.added.iter().map(|(value, tags)| value.len() + tags.iter().map(|tag| tag.len()).sum::<usize>()).sum::<usize>() +
+                set.removed.iter().map(|tag| tag.len()).sum::<usize>()
+            },
+            Crdt::Register(ref register) => register.value.len() + 8
+        }
+    }
+}
+
+impl OrSet {
+    /// JSON text of the values in the set.
+    pub fn values(&self) -> Vec<&String> {
+        self.added.iter()
+            .filter(|&(_, tags)| tags.iter().any(|tag| ! self.removed.contains(tag)))
+            .map(|(value, _)| value)
+            .collect()
+    }
+}
+
+impl Op {
+    /// Parses `crdt` command params, `[ type, op, arg ]`, into the type and the operation.
+    pub fn from_params(params: &JSON) -> Result<(&str, Op), String> {
+        let params = match params.as_array() {
+            Some(params) if params.len() == 3 => params,
+            _ => return Err("Bad crdt params".to_string())
+        };
+
+        let kind = try!(params[0].as_str().ok_or("Bad crdt type"));
+
+        let op = match (kind, params[1].as_str()) {
+            ("gcounter", Some("incr")) | ("pncounter", Some("incr")) => {
+                Op::Incr(try!(params[2].as_i64().ok_or("Bad crdt incr")))
+            },
+            ("orset", Some("add")) => Op::Add(params[2].clone()),
+            ("orset", Some("remove")) => Op::Remove(params[2].clone()),
+            ("register", Some("set")) => Op::Set(params[2].clone()),
+            _ => return Err(format!("Bad crdt op: {}", params[1]))
+        };
+
+        if let (&Op::Incr(n), "gcounter") = (&op, kind) {
+            if n < 0 {
+                return Err("Bad crdt incr, gcounter can't count down".to_string());
+            }
+        }
+
+        Ok((kind, op))
+    }
+}
+
+fn join_counts(counts: &mut Counts, other: &Counts) {
+    for (replica, &count) in other {
+        let entry = counts.entry(replica.clone()).or_insert(0);
+
+        *entry = cmp::max(*entry, count);
+    }
+}
+
+fn total(counts: &Counts) -> u64 {
+    counts.values().sum()
+}
+
+fn parse(json: &str) -> JSON {
+    serde_json::from_str(json).unwrap_or(JSON::Null)
+}
+
+#[test]
+fn test_counters() {
+    let mut a = Crdt::new("pncounter").unwrap();
+    let mut b = a.clone();
+
+    a.apply(&Op::Incr(5), "a", 1000).unwrap();
+    b.apply(&Op::Incr(3), "b", 1000).unwrap();
+    b.apply(&Op::Incr(-1), "b", 2000).unwrap();
+
+    // Joins converge, and joining again changes nothing
+    let mut ab = a.clone();
+    let mut ba = b.clone();
+
+    assert!(ab.join(&b));
+    assert!(ba.join(&a));
+    assert_eq!(ab, ba);
+    assert_eq!(ab.to_json(), JSON::from(7));
+
+    ab.join(&b);
+    assert_eq!(ab.to_json(), JSON::from(7));
+
+    let mut g = Crdt::new("gcounter").unwrap();
+
+    assert!(g.apply(&Op::Incr(-1), "a", 1000).is_err());
+    assert!(g.apply(&Op::Add(JSON::Null), "a", 1000).is_err());
+    assert!(! g.join(&ab));
+}
+
+#[test]
+fn test_orset() {
+    let moo = JSON::from("moo");
+    let cow = JSON::from("cow");
+
+    let mut a = Crdt::new("orset").unwrap();
+
+    a.apply(&Op::Add(moo.clone()), "a", 1000).unwrap();
+    a.apply(&Op::Add(cow.clone()), "a", 1001).unwrap();
+
+    let mut b = a.clone();
+
+    // Removed on one, added again on the other without seeing it
+    a.apply(&Op::Remove(moo.clone()), "a", 2000).unwrap();
+    a.apply(&Op::Remove(cow.clone()), "a", 2001).unwrap();
+    b.apply(&Op::Add(moo.clone()), "b", 2000).unwrap();
+
+    let mut ab = a.clone();
+    let mut ba = b.clone();
+
+    ab.join(&b);
+    ba.join(&a);
+
+    assert_eq!(ab, ba);
+
+    match ab {
+        Crdt::OrSet(ref set) => assert_eq!(set.values(), vec![&moo.to_string()]),
+        _ => unreachable!()
+    }
+}
+
+#[test]
+fn test_register() {
+    let mut a = Crdt::new("register").unwrap();
+    let mut b = a.clone();
+
+    a.apply(&Op::Set(JSON::from(1)), "a", 2000).unwrap();
+    b.apply(&Op::Set(JSON::from(2)), "b", 1000).unwrap();
+
+    let mut ba = b.clone();
+
+    ba.join(&a);
+    a.join(&b);
+
+    assert_eq!(a, ba);
+    assert_eq!(a, Crdt::Register(Register { value: JSON::from(1).to_string(), timestamp: 2000 }));
+}
+
+#[test]
+fn test_from_params() {
+    let params = |kind: &str, op: &str, arg: JSON| JSON::Array(vec![JSON::from(kind), JSON::from(op), arg]);
+
+    assert_eq!(Op::from_params(&params("pncounter", "incr", JSON::from(-2))), Ok(("pncounter", Op::Incr(-2))));
+    assert_eq!(Op::from_params(&params("orset", "add", JSON::Null)), Ok(("orset", Op::Add(JSON::Null))));
+    assert!(Op::from_params(&params("gcounter", "incr", JSON::from(-2))).is_err());
+    assert!(Op::from_params(&params("orset", "incr", JSON::from(1))).is_err());
+    assert!(Op::from_params(&params("moo", "set", JSON::Null)).is_err());
+    assert!(Op::from_params(&JSON::from(1)).is_err());
+}
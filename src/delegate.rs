@@ -1,11 +1,119 @@
 //! Contains functions to help measure size / population statistics of Nodes and help decide the
 //! appropriate points in the tree to partition as Zones.
 
-use std::collections::BinaryHeap;
-
-use time;
+use std::collections::{BTreeMap, BinaryHeap};
 
+use clock;
 use node::Node;
+use path::Path;
+
+/// Zones bigger than this, in estimated bytes, split off their largest children
+pub const MAX_BYTES: usize = 65535;
+
+/// Zones with more nodes than this split off their largest children
+pub const MAX_ENTRIES: usize = 10000;
+
+/// Children smaller than this, in bytes and in nodes, stay in their zone
+const MIN_CHILD_BYTES: usize = 1024;
+const MIN_CHILD_ENTRIES: usize = 100;
+
+/// Estimated size of data: bytes of keys and values, and number of nodes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Size {
+    pub bytes: usize,
+    pub entries: usize
+}
+
+impl Size {
+    /// Measures `node` and its children.
+    pub fn of(node: &Node) -> Size {
+        let mut size = Size { bytes: node.byte_size(), entries: 1 };
+
+        node.each_child(|k, child_node| {
+            let child_size = Size::of(child_node);
+
+            size.bytes += k.len() + child_size.bytes;
+            size.entries += child_size.entries;
+        });
+
+        size
+    }
+
+    pub fn add(&mut self, other: Size) {
+        self.bytes += other.bytes;
+        self.entries += other.entries;
+    }
+
+    /// Returns true if a Zone this big should split.
+    pub fn exceeds(&self) -> bool {
+        self.bytes > MAX_BYTES || self.entries > MAX_ENTRIES
+    }
+
+    /// Returns true if a Zone this small would not have been split off, and should fold back into
+    /// its parent.
+    pub fn is_small(&self) -> bool {
+        self.bytes <= MIN_CHILD_BYTES && self.entries <= MIN_CHILD_ENTRIES
+    }
+}
+
+/// Size of a zone's data kept up to date as it changes (see `NodeTree::merge_sized`), by child of
+/// its root, so zones know how big they are, and their heaviest children, without measuring it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sizes {
+    total: Size,
+    root: Size,                      // The root node on its own
+    children: BTreeMap<String, Size> // Each child of the root, with its key
+}
+
+impl Size {
+    fn grow(&mut self, bytes: isize, entries: isize) {
+        self.bytes = (self.bytes as isize + bytes).max(0) as usize;
+        self.entries = (self.entries as isize + entries).max(0) as usize;
+    }
+}
+
+impl Sizes {
+    /// Measures `node` and each of its children.
+    pub fn of(node: &Node) -> Sizes {
+        let root = Size { bytes: node.byte_size(), entries: 1 };
+        let mut sizes = Sizes { total: root, root: root, children: BTreeMap::new() };
+
+        node.each_child(|k, child_node| {
+            let mut child_size = Size::of(child_node);
+
+            child_size.bytes += k.len();
+            sizes.total.add(child_size);
+            sizes.children.insert(k.clone(), child_size);
+        });
+
+        sizes
+    }
+
+    /// Size of all of the data, as `Size::of` would measure it.
+    pub fn total(&self) -> Size {
+        self.total
+    }
+
+    /// Children of the root with the most bytes, at most `count` of them, heaviest first.
+    pub fn heaviest(&self, count: usize) -> Vec<(String, Size)> {
+        let mut heaviest: Vec<(String, Size)> = self.children.iter().map(|(k, size)| (k.clone(), *size)).collect();
+
+        heaviest.sort_by(|a, b| b.1.bytes.cmp(&a.1.bytes).then(a.0.cmp(&b.0)));
+        heaviest.truncate(count);
+        heaviest
+    }
+
+    /// Accounts for data at `path`, relative to the root, growing by `bytes` and `entries`, or
+    /// shrinking for negative ones.
+    pub fn grow(&mut self, path: &Path, bytes: isize, entries: isize) {
+        self.total.grow(bytes, entries);
+
+        match path.path.first() {
+            Some(key) => self.children.entry(key.to_string()).or_insert_with(Default::default).grow(bytes, entries),
+            None => self.root.grow(bytes, entries)
+        }
+    }
+}
 
 /// Possibly delegate
 pub fn delegate(node: &Node) -> Option<Node> {
@@ -15,14 +123,14 @@ pub fn delegate(node: &Node) -> Option<Node> {
     delegate_node
 }
 
-fn check_node(node: &Node) -> (usize, Option<Node>) {
+fn check_node(node: &Node) -> (Size, Option<Node>) {
     let mut delegate_node: Node = Default::default();
-    let mut total_size = node.byte_size();
+    let mut total_size = Size { bytes: node.byte_size(), entries: 1 };
 
-    if total_size > 32768 {
+    if total_size.bytes > 32768 {
         // TODO: delegate this Node if value stored here is e.g. > 32k
     }
-    // TODO: handle if Node has many children, e.g. > 10000
+    // TODO: handle if Node has many small children, e.g. > 10000
     else {
         // recursively check if children need to be delegated
 
@@ -35,18 +143,19 @@ fn check_node(node: &Node) -> (usize, Option<Node>) {
                 delegate_node.add_child(k.clone(), child_delegations);
             }
 
-            child_size += k.len();
-            total_size += child_size;
+            child_size.bytes += k.len();
+            total_size.add(child_size);
 
-            if child_size > 1024 {
-                largest_children.push( (child_size, k.clone()) );
+            if child_size.bytes > MIN_CHILD_BYTES || child_size.entries > MIN_CHILD_ENTRIES {
+                largest_children.push( (child_size.bytes, child_size.entries, k.clone()) );
             }
         });
 
-        while total_size > 65535 {
-            if let Some( (child_size, k) ) = largest_children.pop() {
-                delegate_node.add_child(k.clone(), Node::delegate(time::precise_time_ns()));
-                total_size -= child_size;
+        while total_size.exceeds() {
+            if let Some( (child_bytes, child_entries, k) ) = largest_children.pop() {
+                delegate_node.add_child(k.clone(), Node::delegate(clock::now()));
+                total_size.bytes -= child_bytes;
+                total_size.entries -= child_entries;
             }
             else {
                 break;
@@ -58,3 +167,82 @@ fn check_node(node: &Node) -> (usize, Option<Node>) {
 
     (total_size, delegate_node)
 }
+
+#[test]
+fn test_delegate() {
+    use serde_json::Value as JSON;
+
+    let mut node = Node::default();
+    let mut big = Node::default();
+    let mut many = Node::default();
+
+    // Small enough to stay
+    assert_eq!(delegate(&Node::expand(JSON::from(42), 1)), None);
+
+    for i in 0..8 {
+        big.add_child(i.to_string(), Node::expand(JSON::from("moo".repeat(4000)), 1));
+    }
+
+    for i in 0..MAX_ENTRIES {
+        many.add_child(i.to_string(), Node::expand(JSON::from(i as u64), 1));
+    }
+
+    node.add_child("big".to_string(), big);
+    node.add_child("many".to_string(), many);
+    node.add_child("small".to_string(), Node::expand(JSON::from(42), 1));
+
+    let size = Size::of(&node);
+
+    assert_eq!(size.entries, 1 + 9 + MAX_ENTRIES + 2);
+    assert!(size.exceeds());
+
+    // Too big, and too many nodes
+    let delegated = delegate(&node).unwrap();
+    let mut keys = vec![];
+
+    delegated.each_child(|k, _| keys.push(k.clone()));
+
+    assert_eq!(keys, vec!["big".to_string(), "many".to_string()]);
+}
+
+#[test]
+fn test_sizes() {
+    use node::{NodeTree, Vis};
+    use serde_json::Value as JSON;
+    use strategy::Strategies;
+
+    let (strategies, root) = (Strategies::default(), Path::empty());
+    let mut tree = NodeTree { node: Default::default(), vis: Vis::permanent() };
+    let mut sizes = Sizes::of(&tree.node);
+
+    let write = |path: &[&str], value: JSON, ts: u64| {
+        let path: Vec<String> = path.iter().map(|key| key.to_string()).collect();
+
+        Node::expand_from(&path, value, ts).noop_vis()
+    };
+
+    let diffs = vec![
+        write(&["moo", "cow"], JSON::from("moo"), 1000),
+        write(&["pig"], JSON::from("oink".repeat(10)), 1000),
+        write(&["moo", "cow"], JSON::from("moo".repeat(20)), 2000),
+        write(&["moo", "hen"], JSON::from(true), 2000),
+        write(&["pig"], JSON::from(1), 3000),
+        Node::delete(3000).prepend_path(&["moo".to_string(), "hen".to_string()]).noop_vis()
+    ];
+
+    // Kept up to date as data is written over and killed
+    for mut diff in diffs {
+        tree.merge_sized(&mut diff, strategies.at(&root), &mut sizes);
+        assert_eq!(sizes, Sizes::of(&tree.node));
+    }
+
+    assert_eq!(sizes.total(), Size::of(&tree.node));
+    assert_eq!(sizes.heaviest(1), vec![("moo".to_string(), Size { bytes: 3 + 1 + 3 + 60 + 3 + 1, entries: 3 })]);
+
+    // Data delegated to another zone leaves only the node in its place
+    let mut delegate = Node::delegate(4000).prepend_path(&["moo".to_string()]).noop_vis();
+
+    tree.merge_sized(&mut delegate, strategies.at(&root), &mut sizes);
+    assert_eq!(sizes, Sizes::of(&tree.node));
+    assert_eq!(sizes.heaviest(2), vec![("pig".to_string(), Size { bytes: 3 + 8, entries: 1 }), ("moo".to_string(), Size { bytes: 3 + 1, entries: 1 })]);
+}
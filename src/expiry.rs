@@ -0,0 +1,128 @@
+//! Expiry of zone data.
+//!
+//! An `expire` command gives the value at a path, and everything below it, a time to live: its
+//! params are the seconds left, or `null` to keep the value after all. Expiring the path of a zone
+//! expires the whole zone. A `Zone` with TTLs sweeps them every `SWEEP_MS` while loaded, and right
+//! away when it loads. Each expired path is killed as of the `expire` command, so values written
+//! to it since are kept. The kill merges like any other: listeners are notified, and it is logged,
+//! persisted and replicated.
+//!
+//! TTLs are persisted with the zone data, on the node that got the `expire` command; its replicas
+//! only get the kills. Setting a TTL is not logged like merges are, so one set since the last
+//! write is lost on a crash.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use path::Path;
+
+/// Milliseconds between sweeps of a zone with TTLs
+pub const SWEEP_MS: u64 = 1000;
+
+/// TTLs set in a zone, by path.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Expiry {
+    ttls: BTreeMap<Path, Ttl>
+}
+
+/// When a path expires.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Ttl {
+    pub set: u64,    // Timestamp of the `expire` command, values written after it are kept
+    pub expires: u64 // Milliseconds since the epoch
+}
+
+impl Expiry {
+    pub fn is_empty(&self) -> bool {
+        self.ttls.is_empty()
+    }
+
+    /// Sets when `path` expires, in place of any TTL it had.
+    pub fn set(&mut self, path: Path, ttl: Ttl) {
+        self.ttls.insert(path, ttl);
+    }
+
+    /// Keeps `path` after all. Returns false if it had no TTL.
+    pub fn clear(&mut self, path: &Path) -> bool {
+        self.ttls.remove(path).is_some()
+    }
+
+    /// Takes the TTLs of a zone folding back into this one, at `prefix`.
+    pub fn adopt(&mut self, prefix: &Path, other: Expiry) {
+        for (mut path, ttl) in other.ttls {
+            let mut adopted = prefix.clone();
+
+            adopted.append(&mut path);
+            self.ttls.insert(adopted, ttl);
+        }
+    }
+
+    /// Takes the paths expired at `now`, in milliseconds since the epoch.
+    pub fn due(&mut self, now: u64) -> Vec<(Path, Ttl)> {
+        let due: Vec<Path> = self.ttls.iter()
+            .filter(|&(_, ttl)| ttl.expires <= now)
+            .map(|(path, _)| path.clone())
+            .collect();
+
+        due.into_iter().map(|path| {
+            let ttl = self.ttls.remove(&path).unwrap();
+
+            (path, ttl)
+        }).collect()
+    }
+}
+
+impl Ttl {
+    /// TTL of `secs` from now, for an `expire` command at timestamp `set`.
+    pub fn new(set: u64, secs: f64) -> Ttl {
+        Ttl {
+            set: set,
+            expires: now() + (secs.max(0.0) * 1000.0) as u64
+        }
+    }
+}
+
+/// Milliseconds since the epoch.
+pub fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000,
+        Err(_) => 0
+    }
+}
+
+#[test]
+fn test_due() {
+    let path = |path: &str| Path::new(path.split('.').map(|key| key.to_string()).collect());
+
+    let mut expiry = Expiry::default();
+
+    assert!(expiry.is_empty());
+
+    expiry.set(path("moo"), Ttl { set: 1, expires: 100 });
+    expiry.set(path("moo.cow"), Ttl { set: 2, expires: 200 });
+    expiry.set(path("cow"), Ttl { set: 3, expires: 300 });
+
+    // Replacing a TTL
+    expiry.set(path("cow"), Ttl { set: 4, expires: 150 });
+
+    assert_eq!(expiry.due(50), vec![]);
+    assert_eq!(expiry.due(150), vec![
+        (path("cow"), Ttl { set: 4, expires: 150 }),
+        (path("moo"), Ttl { set: 1, expires: 100 })
+    ]);
+
+    // Taken ones are gone
+    assert_eq!(expiry.due(150), vec![]);
+
+    assert!(expiry.clear(&path("moo.cow")));
+    assert!(! expiry.clear(&path("moo.cow")));
+    assert!(expiry.is_empty());
+
+    // TTLs of a zone folding back in
+    let mut folded = Expiry::default();
+
+    folded.set(path("cow"), Ttl { set: 5, expires: 500 });
+    expiry.adopt(&path("moo"), folded);
+
+    assert_eq!(expiry.due(500), vec![(path("moo.cow"), Ttl { set: 5, expires: 500 })]);
+}
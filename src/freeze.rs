@@ -0,0 +1,124 @@
+//! Read-only parts of the tree.
+//!
+//! Paths frozen, with `ZONE_FROZEN` as comma separated dotted paths where `*` matches any key, or
+//! with `zone.freeze` in the shell, reject commands changing data at or below them with a
+//! `"frozen"` reply, `[ id, "frozen", prefix, path ]` with the path written. Writes above a frozen
+//! path are rejected too, since they would change it. Reads, binds and snapshots go on as usual,
+//! and so do leases (see `lease`), which don't change data.
+//!
+//! Only commands sent to this node are refused: changes merged from replicas and other zones are
+//! still applied, so replicas stay in sync whichever of them were frozen. Freezing is meant for
+//! reference data and maintenance such as migrations, on every node at once.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use path::Path;
+
+/// Patterns of paths frozen, shared by all zones.
+#[derive(Debug, Default)]
+pub struct Frozen {
+    patterns: RwLock<Vec<Path>>
+}
+
+impl Frozen {
+    pub fn is_empty(&self) -> bool {
+        self.patterns.read().unwrap().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.patterns.read().unwrap().len()
+    }
+
+    /// Patterns frozen, in the order they were.
+    pub fn patterns(&self) -> Vec<Path> {
+        self.patterns.read().unwrap().clone()
+    }
+
+    /// Freezes the paths matching `pattern` and below. Returns false if it was already.
+    pub fn freeze(&self, pattern: Path) -> bool {
+        let mut patterns = self.patterns.write().unwrap();
+
+        if patterns.contains(&pattern) {
+            return false;
+        }
+
+        patterns.push(pattern);
+        true
+    }
+
+    /// Unfreezes `pattern`, frozen as is. Returns false if it wasn't.
+    pub fn unfreeze(&self, pattern: &Path) -> bool {
+        let mut patterns = self.patterns.write().unwrap();
+        let len = patterns.len();
+
+        patterns.retain(|frozen| frozen != pattern);
+        patterns.len() < len
+    }
+
+    /// Whether a write to `path`, absolute and with any wildcards, could change a frozen path.
+    pub fn is_frozen(&self, path: &Path) -> bool {
+        self.patterns.read().unwrap().iter().any(|pattern| overlaps(pattern, path))
+    }
+}
+
+impl FromStr for Frozen {
+    type Err = String;
+
+    /// Parses comma separated dotted paths, an empty one for the root.
+    fn from_str(s: &str) -> Result<Frozen, String> {
+        let frozen = Frozen::default();
+
+        for pattern in s.split(',') {
+            let pattern = Path::new(pattern.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+            if ! frozen.freeze(pattern) {
+                return Err(format!("Frozen twice: {}", s));
+            }
+        }
+
+        Ok(frozen)
+    }
+}
+
+impl fmt::Display for Frozen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let patterns: Vec<String> = self.patterns().iter().map(|pattern| pattern.to_string()).collect();
+
+        write!(f, "{}", patterns.join(","))
+    }
+}
+
+/// Whether `pattern` and `path` agree on the keys both have, so one is at or below the other,
+/// with `*` keys, or wildcards in `path`, matching any key.
+fn overlaps(pattern: &Path, path: &Path) -> bool {
+    pattern.path.iter().zip(&path.path).all(|(p, k)| p == "*" || k.starts_with('*') || p == k)
+}
+
+#[test]
+fn test_frozen() {
+    let path = |path: &str| Path::new(path.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+    let frozen: Frozen = "config,users.*.plan".parse().unwrap();
+
+    assert_eq!(frozen.len(), 2);
+    assert!(frozen.is_frozen(&path("config")));
+    assert!(frozen.is_frozen(&path("config.limits")));
+    assert!(frozen.is_frozen(&path("users.alice.plan.name")));
+    assert!(! frozen.is_frozen(&path("users.alice.name")));
+    assert!(! frozen.is_frozen(&path("counters")));
+
+    // Writes above and across frozen paths
+    assert!(frozen.is_frozen(&path("users.alice")));
+    assert!(frozen.is_frozen(&path("*.alice.plan")));
+    assert!(frozen.is_frozen(&Path::empty()));
+
+    assert!(! frozen.freeze(path("config")));
+    assert!(frozen.unfreeze(&path("users.*.plan")));
+    assert!(! frozen.unfreeze(&path("users")));
+    assert!(! frozen.is_frozen(&path("users.alice")));
+    assert_eq!(frozen.to_string(), "config");
+
+    assert!("config,config".parse::<Frozen>().is_err());
+}
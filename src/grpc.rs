@@ -0,0 +1,196 @@
+//! gRPC service, for clients generated from `grpc/proto/qumulus.proto`.
+//!
+//! Served by the `qumulus-grpc` crate, built with the `grpc` feature. Reads, writes and deletes
+//! are those of the HTTP gateway (see `client::read_plain` and `client::write`), on paths without
+//! wildcards, and subscriptions are binds, with wildcards, filters and depths as for other clients,
+//! streaming the data bound and then each change to it, as the native protocol replies it.
+
+use std::net::SocketAddr;
+use std::thread;
+
+use mioco::sync::mpsc::{channel, Receiver};
+use qumulus_grpc;
+use qumulus_grpc::{Backend, Bind, Changes, Error, Unsubscribe};
+use serde_json;
+use serde_json::Value;
+
+use app::{App, AppHandle};
+use client::{self, Refused};
+use clock;
+use command::{Call, Command};
+use listener;
+use path::Path;
+
+/// This node, served to gRPC clients.
+struct Node {
+    app: AppHandle
+}
+
+/// Replies to a bind, as changes, each waited for, until an empty one says it was unbound.
+struct Replies {
+    rx: Receiver<String>
+}
+
+/// Serves gRPC clients at `addr`.
+pub fn spawn(app: &App, addr: SocketAddr) {
+    let node = Node { app: app.handle() };
+
+    thread::spawn(move|| {
+        if let Err(e) = qumulus_grpc::serve(node, addr) {
+            println!("gRPC server error: {}", e);
+        }
+    });
+}
+
+impl Node {
+    /// Path of `keys`, checked like those of other clients.
+    fn path(&self, keys: Vec<String>, patterns: bool) -> Result<Path, Error> {
+        let path = try!(Path::try_new_with(keys, &self.app.limits).map_err(|err| Error::BadPath(err.to_string())));
+
+        if ! patterns && path.is_pattern() {
+            return Err(Error::BadPath("Wildcards only for subscriptions".to_string()));
+        }
+
+        Ok(path)
+    }
+
+    fn command(&self, call: Call, path: Path, params: Value) -> Command {
+        Command {
+            id: 1,
+            call: call,
+            path: path,
+            params: params,
+            timestamp: clock::now()
+        }
+    }
+
+    fn write_command(&self, command: Command) -> Result<(), Error> {
+        client::write(&self.app, command).map_err(|refused| match refused {
+            Refused::Frozen => Error::Frozen,
+            Refused::Invalid(invalid) => Error::Invalid(invalid.to_string()),
+            Refused::Failed(error) => Error::Failed(error)
+        })
+    }
+}
+
+impl Backend for Node {
+    fn read(&self, keys: Vec<String>) -> Result<String, Error> {
+        let path = try!(self.path(keys, false));
+
+        match client::read_plain(&self.app, &self.command(Call::Read, path, Value::Null)) {
+            Value::Null => Err(Error::NotFound),
+            json => Ok(json.to_string())
+        }
+    }
+
+    fn write(&self, keys: Vec<String>, json: String) -> Result<(), Error> {
+        let path = try!(self.path(keys, false));
+        let value: Value = try!(serde_json::from_str(&json).map_err(|err| Error::BadValue(err.to_string())));
+
+        self.write_command(self.command(Call::Write, path, value))
+    }
+
+    fn delete(&self, keys: Vec<String>) -> Result<(), Error> {
+        let path = try!(self.path(keys, false));
+
+        self.write_command(self.command(Call::Kill, path, Value::Null))
+    }
+
+    fn subscribe(&self, keys: Vec<String>, bind: Bind) -> Result<(Changes, Unsubscribe), Error> {
+        let path = try!(self.path(keys, true));
+        let params = bind_params(bind);
+
+        try!(listener::bind_filter(&params).map_err(Error::BadValue));
+
+        let (tx, rx) = channel();
+
+        // Replied to and later notified on `tx`, unbound once `rx` is dropped
+        client::process(&self.app, &tx, self.command(Call::Bind, path, params), false);
+
+        // Wakes the replies waited for, to end them
+        let unsubscribe = move || tx.send(String::new()).unwrap_or_default();
+
+        Ok((Box::new(Replies { rx: rx }), Box::new(unsubscribe)))
+    }
+}
+
+impl Iterator for Replies {
+    type Item = (Vec<String>, String);
+
+    fn next(&mut self) -> Option<(Vec<String>, String)> {
+        // `[ id, replies left, path, data ]`, for the data bound then each change
+        loop {
+            let reply = match self.rx.recv() {
+                Ok(ref reply) if reply.is_empty() => return None, // unsubscribed
+                Ok(reply) => reply,
+                Err(_) => return None
+            };
+
+            let reply: Value = match serde_json::from_str(&reply) {
+                Ok(reply) => reply,
+                Err(_) => continue
+            };
+
+            if reply[3].is_null() {
+                continue;
+            }
+
+            let path = reply[2].as_array().iter().flat_map(|keys| keys.iter()).filter_map(|key| key.as_str()).map(|key| key.to_string()).collect();
+
+            return Some((path, reply[3].to_string()));
+        }
+    }
+}
+
+/// Params of a bind, as sent by other clients.
+fn bind_params(bind: Bind) -> Value {
+    let mut params = serde_json::Map::new();
+
+    if ! bind.filter.is_empty() {
+        params.insert("filter".to_string(), Value::Array(bind.filter.into_iter().map(Value::String).collect()));
+    }
+
+    if let Some(regex) = bind.regex {
+        params.insert("regex".to_string(), Value::String(regex));
+    }
+
+    if let Some(depth) = bind.depth {
+        params.insert("depth".to_string(), depth.into());
+    }
+
+    Value::Object(params)
+}
+
+#[test]
+fn test_bind_params() {
+    let bind = Bind { filter: vec!["status".to_string()], regex: None, depth: Some(2) };
+    let params = bind_params(bind);
+
+    assert_eq!(listener::bind_filter(&params).unwrap().0.to_string(), "status");
+    assert_eq!(listener::bind_depth(&params), Some(2));
+    assert_eq!(bind_params(Bind::default()), Value::Object(serde_json::Map::new()));
+}
+
+#[test]
+fn test_replies() {
+    let (tx, rx) = channel();
+    let mut replies = Replies { rx: rx };
+
+    tx.send(r#"[1,0,["moo"],{"cow":1}]"#.to_string()).unwrap();
+    tx.send(r#"[1,0,["moo"],null]"#.to_string()).unwrap();
+    tx.send(r#"[1,0,["moo"],{"cow":2}]"#.to_string()).unwrap();
+
+    assert_eq!(replies.next(), Some((vec!["moo".to_string()], r#"{"cow":1}"#.to_string())));
+    assert_eq!(replies.next(), Some((vec!["moo".to_string()], r#"{"cow":2}"#.to_string())));
+
+    // Woken to end once unsubscribed, or unbound
+    let waiting = thread::spawn(move || replies.next());
+
+    tx.send(String::new()).unwrap();
+    assert_eq!(waiting.join().unwrap(), None);
+
+    let (tx, rx) = channel::<String>();
+
+    drop(tx);
+    assert_eq!(Replies { rx: rx }.next(), None);
+}
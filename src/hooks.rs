@@ -0,0 +1,119 @@
+//! Hooks into the lifecycle of zones.
+//!
+//! `ZoneHooks` are told when a `Zone` has loaded its data, is about to write it to the store, has
+//! been evicted, or has split children off into zones of their own, to warm caches, index data
+//! elsewhere or keep metrics without changing `Zone` itself. Like strategies, they are added to
+//! `App::hooks` before the app starts, and are called in turn in the order they were added.
+//!
+//! Hooks run in the zone's coroutine, in the middle of what it's doing, so they should return
+//! quickly and leave blocking work, and calls back into the app, to a coroutine of their own.
+
+use std::sync::Arc;
+
+use path::Path;
+use zone::ZoneData;
+
+/// Callbacks on the lifecycle of zones, each doing nothing unless implemented. Paths are absolute.
+pub trait ZoneHooks: Send + Sync {
+    /// Zone at `path` loaded `data` from the store.
+    fn on_loaded(&self, _path: &Path, _data: &ZoneData) {}
+
+    /// Zone at `path` is about to write `data` to the store. Deltas only write part of it, and
+    /// children packed in memory are left out (see `cold`).
+    fn on_before_persist(&self, _path: &Path, _data: &ZoneData) {}
+
+    /// Zone at `path` dropped its data, to load it again from the store when next used.
+    fn on_evicted(&self, _path: &Path) {}
+
+    /// Zone at `path` split off `children` into zones of their own (see `delegate`).
+    fn on_split(&self, _path: &Path, _children: &[Path]) {}
+}
+
+/// Hooks called for every zone.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<ZoneHooks>>
+}
+
+impl Hooks {
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    /// Adds `hooks`, called after those added before.
+    pub fn add(&mut self, hooks: Arc<ZoneHooks>) {
+        self.hooks.push(hooks);
+    }
+
+    pub fn loaded(&self, path: &Path, data: &ZoneData) {
+        for hooks in &self.hooks {
+            hooks.on_loaded(path, data);
+        }
+    }
+
+    pub fn before_persist(&self, path: &Path, data: &ZoneData) {
+        for hooks in &self.hooks {
+            hooks.on_before_persist(path, data);
+        }
+    }
+
+    pub fn evicted(&self, path: &Path) {
+        for hooks in &self.hooks {
+            hooks.on_evicted(path);
+        }
+    }
+
+    pub fn split(&self, path: &Path, children: &[Path]) {
+        for hooks in &self.hooks {
+            hooks.on_split(path, children);
+        }
+    }
+}
+
+#[test]
+fn test_hooks() {
+    use std::sync::Mutex;
+
+    use node::NodeTree;
+
+    #[derive(Default)]
+    struct Log {
+        calls: Mutex<Vec<String>>
+    }
+
+    impl ZoneHooks for Log {
+        fn on_loaded(&self, path: &Path, _: &ZoneData) {
+            self.calls.lock().unwrap().push(format!("loaded {}", path.len()));
+        }
+
+        fn on_split(&self, _: &Path, children: &[Path]) {
+            self.calls.lock().unwrap().push(format!("split {}", children.len()));
+        }
+    }
+
+    let first = Arc::new(Log::default());
+    let second = Arc::new(Log::default());
+    let mut hooks = Hooks::default();
+
+    assert!(hooks.is_empty());
+
+    hooks.add(first.clone());
+    hooks.add(second.clone());
+
+    let path = Path::new(vec!["moo".to_string()]);
+    let data = ZoneData::new(path.clone(), NodeTree::default());
+
+    // Unimplemented hooks do nothing
+    hooks.loaded(&path, &data);
+    hooks.before_persist(&path, &data);
+    hooks.evicted(&path);
+    hooks.split(&path, &[path.clone(), path.clone()]);
+
+    assert_eq!(hooks.len(), 2);
+    assert_eq!(*first.calls.lock().unwrap(), vec!["loaded 1", "split 2"]);
+    assert_eq!(*second.calls.lock().unwrap(), vec!["loaded 1", "split 2"]);
+}
@@ -0,0 +1,345 @@
+//! HTTP gateway, for services reading and writing now and then without the native protocol.
+//!
+//! `GET /data/<path>` reads the data at a path as plain JSON, without versions, from every zone it
+//! is in, `PUT /data/<path>` writes the JSON body there and `DELETE /data/<path>` kills it. Paths
+//! are written as URLs (see `Path::to_url`), `/data` alone for the root, and checked like those of
+//! other clients, without wildcards. Writes and kills are dispatched like commands of other clients
+//! (see `client::write`), so frozen paths and schemas apply the same.
+//!
+//! Replies are `200 OK` with the data read, `204 No Content` once written, `404 Not Found` for
+//! paths without data and URLs outside `/data`, `400 Bad Request` for bad paths and bodies,
+//! `403 Forbidden` for writes to frozen paths and `422 Unprocessable Entity` for values a schema
+//! rejects, the body `{ "error": ... }` saying why. Each connection is closed after one request.
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+
+use mioco;
+use serde_json;
+use serde_json::Value;
+
+use app::AppHandle;
+use client::{self, Refused};
+use clock;
+use command::{Call, Command};
+use path::Path;
+use tls::Stream;
+
+/// Longest request line or header read, in bytes
+pub const MAX_LINE_BYTES: usize = 8 << 10;
+
+/// Biggest body read, in bytes
+pub const MAX_BODY_BYTES: usize = 64 << 20;
+
+/// Prefix of the URLs of data
+const DATA: &'static str = "/data";
+
+/// A request read, its method, URL and body.
+#[derive(Debug, PartialEq)]
+struct Request {
+    method: String,
+    url: String,
+    body: Vec<u8>
+}
+
+/// A reply, its status and JSON body, `Null` for none.
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u16,
+    body: Value
+}
+
+impl Response {
+    fn ok(body: Value) -> Response {
+        Response { status: 200, body: body }
+    }
+
+    fn no_content() -> Response {
+        Response { status: 204, body: Value::Null }
+    }
+
+    fn error(status: u16, error: &str) -> Response {
+        let mut body = serde_json::Map::new();
+
+        body.insert("error".to_string(), error.to_string().into());
+
+        Response { status: status, body: Value::Object(body) }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            413 => "Payload Too Large",
+            422 => "Unprocessable Entity",
+            _ => "Internal Server Error"
+        }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(write!(writer, "HTTP/1.1 {} {}\r\n", self.status, self.reason()));
+
+        if self.status == 405 {
+            try!(write!(writer, "Allow: GET, PUT, DELETE\r\n"));
+        }
+
+        // No body at all, so no headers of one either
+        if self.status == 204 {
+            return write!(writer, "Connection: close\r\n\r\n");
+        }
+
+        let body = match self.body {
+            Value::Null => String::new(),
+            ref body => serde_json::to_string(body).unwrap()
+        };
+
+        try!(write!(writer, "Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()));
+        writer.write_all(body.as_bytes())
+    }
+}
+
+/// Serves one request of a client connecting, then closes the connection.
+pub fn serve(app: AppHandle, stream: Stream) {
+    mioco::spawn(move|| {
+        let mut writer = stream.try_clone().unwrap();
+
+        let response = match read_request(&mut BufReader::new(stream)) {
+            Ok(request) => handle(&app, request),
+            Err(response) => response
+        };
+
+        if let Err(e) = response.write_to(&mut writer) {
+            println!("HTTP connection error: {}", e);
+        }
+    });
+}
+
+/// Reads a request, or the reply to one that can't be.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Request, Response> {
+    let line = try!(read_line(reader));
+    let mut line = line.split(' ');
+
+    let (method, url) = match (line.next(), line.next(), line.next()) {
+        (Some(method), Some(url), Some(version)) if version.starts_with("HTTP/1.") => (method.to_string(), url.to_string()),
+        _ => return Err(Response::error(400, "Bad request line"))
+    };
+
+    let mut length = 0;
+
+    loop {
+        let header = try!(read_line(reader));
+
+        if header.is_empty() {
+            break;
+        }
+
+        let mut header = header.splitn(2, ':');
+
+        if let (Some(name), Some(value)) = (header.next(), header.next()) {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = try!(value.trim().parse().map_err(|_| Response::error(400, "Bad Content-Length")));
+            }
+        }
+    }
+
+    if length > MAX_BODY_BYTES {
+        return Err(Response::error(413, "Body too big"));
+    }
+
+    let mut body = vec![0; length];
+
+    try!(reader.read_exact(&mut body).map_err(|_| Response::error(400, "Body cut short")));
+
+    Ok(Request { method: method, url: url, body: body })
+}
+
+/// Reads a line ending with `\r\n`, without it.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, Response> {
+    let mut line = vec![];
+
+    try!(reader.by_ref().take(MAX_LINE_BYTES as u64 + 1).read_until(b'\n', &mut line).map_err(|_| Response::error(400, "Bad request")));
+
+    if ! line.ends_with(b"\r\n") {
+        return Err(Response::error(400, "Bad request"));
+    }
+
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| Response::error(400, "Bad request"))
+}
+
+/// Reads or writes as `request` says.
+fn handle(app: &AppHandle, request: Request) -> Response {
+    let path = match parse_url(app, &request.url) {
+        Ok(path) => path,
+        Err(response) => return response
+    };
+
+    let (call, params) = match request.method.as_str() {
+        "GET" => (Call::Read, Value::Null),
+        "PUT" => match serde_json::from_slice(&request.body) {
+            Ok(value) => (Call::Write, value),
+            Err(_) => return Response::error(400, "Bad JSON")
+        },
+        "DELETE" => (Call::Kill, Value::Null),
+        _ => return Response::error(405, "Bad method")
+    };
+
+    let command = Command {
+        id: 1,
+        call: call,
+        path: path,
+        params: params,
+        timestamp: clock::now()
+    };
+
+    match call {
+        Call::Read => read(app, &command),
+        _ => write(app, command)
+    }
+}
+
+/// Path of a URL below `/data`, checked like those of other clients.
+fn parse_url(app: &AppHandle, url: &str) -> Result<Path, Response> {
+    let url = url.splitn(2, '?').next().unwrap_or_default();
+
+    if ! url.starts_with(DATA) || url.len() > DATA.len() && ! url[DATA.len()..].starts_with('/') {
+        return Err(Response::error(404, "Not found"));
+    }
+
+    let path = match Path::from_url(&url[DATA.len()..]) {
+        Ok(path) => path,
+        Err(err) => return Err(Response::error(400, &err.to_string()))
+    };
+
+    if path.is_pattern() {
+        return Err(Response::error(400, "Bad path, wildcards only for the native protocol"));
+    }
+
+    Path::try_new_with(path.iter().map(|key| key.to_string()).collect(), &app.limits).map_err(|err| Response::error(400, &err.to_string()))
+}
+
+/// Reads the data at the path of `command` as plain JSON.
+fn read(app: &AppHandle, command: &Command) -> Response {
+    match client::read_plain(app, command) {
+        Value::Null => Response::error(404, "No data"),
+        json => Response::ok(json)
+    }
+}
+
+/// Writes or kills as `command` says.
+fn write(app: &AppHandle, command: Command) -> Response {
+    match client::write(app, command) {
+        Ok(()) => Response::no_content(),
+        Err(Refused::Frozen) => Response::error(403, "Frozen"),
+        Err(Refused::Invalid(invalid)) => Response { status: 422, body: invalid },
+        Err(Refused::Failed(error)) => Response::error(500, &error)
+    }
+}
+
+#[test]
+fn test_read_request() {
+    use std::io::Cursor;
+
+    let mut request = Cursor::new(b"PUT /data/users/moo HTTP/1.1\r\nHost: moo\r\ncontent-length: 4\r\n\r\n\"hi\"".to_vec());
+
+    assert_eq!(read_request(&mut request), Ok(Request { method: "PUT".to_string(), url: "/data/users/moo".to_string(), body: b"\"hi\"".to_vec() }));
+
+    let mut request = Cursor::new(b"GET /data HTTP/1.1\r\n\r\n".to_vec());
+
+    assert_eq!(read_request(&mut request).map(|request| request.body), Ok(vec![]));
+
+    let mut request = Cursor::new(b"GET /data\r\n\r\n".to_vec());
+
+    assert_eq!(read_request(&mut request).unwrap_err().status, 400);
+
+    let mut request = Cursor::new(b"PUT /data HTTP/1.1\r\nContent-Length: 1000000000000\r\n\r\n".to_vec());
+
+    assert_eq!(read_request(&mut request).unwrap_err().status, 413);
+
+    let mut request = Cursor::new(b"PUT /data HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}".to_vec());
+
+    assert_eq!(read_request(&mut request).unwrap_err().status, 400);
+
+    let mut request = Cursor::new(format!("GET /{} HTTP/1.1\r\n\r\n", "m".repeat(MAX_LINE_BYTES)).into_bytes());
+
+    assert_eq!(read_request(&mut request).unwrap_err().status, 400);
+}
+
+#[test]
+fn test_response() {
+    let mut written = vec![];
+
+    Response::error(405, "Bad method").write_to(&mut written).unwrap();
+
+    let written = String::from_utf8(written).unwrap();
+
+    assert!(written.starts_with("HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, PUT, DELETE\r\n"));
+
+    let mut written = vec![];
+
+    Response::no_content().write_to(&mut written).unwrap();
+    assert_eq!(String::from_utf8(written).unwrap(), "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n");
+
+    let mut written = vec![];
+
+    Response::ok(Value::from(42)).write_to(&mut written).unwrap();
+    assert!(String::from_utf8(written).unwrap().ends_with("Content-Type: application/json\r\nContent-Length: 2\r\nConnection: close\r\n\r\n42"));
+}
+
+#[test]
+fn test_handle() {
+    use std::sync::Arc;
+
+    use app::App;
+    use manager::Manager;
+    use schema::Schema;
+    use store;
+
+    let mut app = App::new("127.0.0.1:1000".parse().unwrap());
+
+    app.schema = Arc::new(Schema::from_json(r#"{ "users.*": { "type": "object" } }"#).unwrap());
+    store::spawn(&mut app, &store::Config { backend: store::Backend::Memory, ..Default::default() });
+    Manager::spawn(&mut app);
+
+    let app = app.handle();
+
+    app.manager.load(&Path::empty());
+
+    let status = |method: &str, url: &str, body: &str| handle(&app, Request {
+        method: method.to_string(),
+        url: url.to_string(),
+        body: body.as_bytes().to_vec()
+    }).status;
+
+    assert_eq!(status("PUT", "/data", r#"{ "users": {} }"#), 204);
+    assert_eq!(status("PUT", "/data/users/moo", r#"{ "name": "moo" }"#), 204);
+    assert_eq!(status("GET", "/data/users/moo?pretty", ""), 200);
+    assert_eq!(status("DELETE", "/data/users/moo", ""), 204);
+    assert_eq!(status("GET", "/data/users/moo", ""), 404);
+
+    // Outside `/data`
+    assert_eq!(status("GET", "/", ""), 404);
+    assert_eq!(status("GET", "/database", ""), 404);
+    assert_eq!(status("GET", "/moo/data", ""), 404);
+
+    // Bad paths and bodies
+    assert_eq!(status("GET", "/data/users/*", ""), 400);
+    assert_eq!(status("GET", "/data/users/%zz", ""), 400);
+    assert_eq!(status("PUT", "/data/users/moo", "{ moo"), 400);
+    assert_eq!(status("PUT", "/data/users/moo", ""), 400);
+
+    assert_eq!(status("POST", "/data/users/moo", "{}"), 405);
+
+    app.frozen.freeze("config".parse().unwrap());
+    assert_eq!(status("PUT", "/data/config/moo", "42"), 403);
+    assert_eq!(status("DELETE", "/data/config", ""), 403);
+
+    // Rejected by the schema, and not written
+    assert_eq!(status("PUT", "/data/users/moo", "42"), 422);
+    assert_eq!(status("GET", "/data/users/moo", ""), 404);
+}
@@ -0,0 +1,245 @@
+//! Interned path keys.
+//!
+//! Paths repeat the same keys over and over, `users`, `status` and the like, once for every path
+//! held by zones, listeners, stores and commands in flight. The keys of a `Path` are `Key`s, shared
+//! strings taken from one pool for the whole node, so equal keys take up memory once however many
+//! paths hold them, and cloning a path only bumps reference counts.
+//!
+//! Keys written as decimal numbers, `0` or with no leading zeros, are indices, those of arrays
+//! (stored as objects keyed by index) and lists. They sort numerically, before any other key, so
+//! `items.2` sorts before `items.10`, and `Key::index` tells them from other keys.
+//!
+//! Keys already pooled are looked up under a read lock, so paths are made from them in parallel,
+//! and only new keys wait on each other. Keys no path holds anymore are dropped from the pool once
+//! it has grown to twice its size since they last were. `stats` tells how many keys are pooled and
+//! how many bytes sharing them saves, printed by `path.interned` in the shell.
+
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Smallest pool pruned
+const MIN_PRUNE: usize = 1024;
+
+/// The pool, created with the first key
+static POOL: RwLock<Option<Pool>> = RwLock::new(None);
+
+/// A path key, sharing its string with equal keys.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct Key(Arc<str>);
+
+/// Keys pooled, and the size to prune at next.
+struct Pool {
+    keys: HashSet<Arc<str>>,
+    prune_at: usize
+}
+
+/// What the pool holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub keys: usize,
+    pub bytes: usize, // Of the keys pooled
+    pub refs: usize,  // Keys held by paths, shared or not
+    pub saved: usize  // Bytes keys held would take up unshared, less those pooled
+}
+
+impl Key {
+    /// Pooled key equal to `key`.
+    pub fn new(key: &str) -> Key {
+        if let Some(pooled) = POOL.read().unwrap().as_ref().and_then(|pool| pool.keys.get(key)) {
+            return Key(pooled.clone());
+        }
+
+        let mut pool = POOL.write().unwrap();
+        let pool = pool.get_or_insert_with(|| Pool { keys: HashSet::new(), prune_at: MIN_PRUNE });
+
+        // Pooled by another thread meanwhile
+        if let Some(pooled) = pool.keys.get(key) {
+            return Key(pooled.clone());
+        }
+
+        if pool.keys.len() >= pool.prune_at {
+            pool.keys.retain(|pooled| Arc::strong_count(pooled) > 1);
+            pool.prune_at = (pool.keys.len() * 2).max(MIN_PRUNE);
+        }
+
+        let pooled: Arc<str> = Arc::from(key);
+
+        pool.keys.insert(pooled.clone());
+        Key(pooled)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Index this key is written as, if it is one.
+    pub fn index(&self) -> Option<u64> {
+        match self.0.as_bytes() {
+            b"0" => Some(0),
+            bytes if bytes.first().map_or(false, |&b| b >= b'1' && b <= b'9') && bytes.iter().all(|&b| b >= b'0' && b <= b'9') => self.0.parse().ok(),
+            _ => None
+        }
+    }
+
+    /// The least key after this one.
+    pub fn next(&self) -> Key {
+        match self.index() {
+            Some(index) if index < u64::MAX => Key::from((index + 1).to_string()),
+            Some(_) => Key::new(""),
+            None => Key::from(format!("{}\0", self.0))
+        }
+    }
+}
+
+/// Indices first, by number, then other keys by their bytes.
+impl Ord for Key {
+    fn cmp(&self, other: &Key) -> Ordering {
+        match (self.index(), other.index()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => self.0.cmp(&other.0)
+        }
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Key) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for Key {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Key {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for Key {
+    fn from(key: &'a str) -> Key {
+        Key::new(key)
+    }
+}
+
+impl From<String> for Key {
+    fn from(key: String) -> Key {
+        Key::new(&key)
+    }
+}
+
+impl<'a> From<&'a String> for Key {
+    fn from(key: &'a String) -> Key {
+        Key::new(key)
+    }
+}
+
+impl PartialEq<str> for Key {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Key {
+    fn eq(&self, other: &&'a str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Key {
+    fn eq(&self, other: &String) -> bool {
+        &*self.0 == other.as_str()
+    }
+}
+
+impl PartialEq<Key> for String {
+    fn eq(&self, other: &Key) -> bool {
+        self.as_str() == &*other.0
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&*self.0, f)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+/// Written as strings, so stored paths read the same as before keys were interned.
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        String::deserialize(deserializer).map(Key::from)
+    }
+}
+
+/// What the pool holds now.
+pub fn stats() -> Stats {
+    let pool = POOL.read().unwrap();
+    let mut stats = Stats::default();
+
+    for pooled in pool.iter().flat_map(|pool| pool.keys.iter()) {
+        let held = Arc::strong_count(pooled) - 1;
+
+        stats.keys += 1;
+        stats.bytes += pooled.len();
+        stats.refs += held;
+        stats.saved += held.saturating_sub(1) * pooled.len();
+    }
+
+    stats
+}
+
+#[test]
+fn test_key() {
+    let moo = Key::new("moo-interned");
+    let again = Key::from("moo-interned".to_string());
+
+    assert_eq!(moo, again);
+    assert!(Arc::ptr_eq(&moo.0, &again.0));
+    assert!(moo == "moo-interned");
+    assert_eq!(format!("{:?} {}", moo, moo), "\"moo-interned\" moo-interned");
+    assert!(Key::new("cow-interned") < moo);
+
+    // Indices
+    assert_eq!(Key::new("0").index(), Some(0));
+    assert_eq!(Key::new("42").index(), Some(42));
+    assert_eq!(Key::new("042").index(), None);
+    assert_eq!(Key::new("-1").index(), None);
+    assert_eq!(Key::new("99999999999999999999").index(), None);
+    assert!(Key::new("2") < Key::new("10"));
+    assert!(Key::new("10") < Key::new(""));
+    assert!(Key::new("042") < Key::new("1a"));
+    assert_eq!(Key::new("9").next(), Key::new("10"));
+    assert_eq!(Key::new("moo").next(), Key::new("moo\0"));
+    assert_eq!(Key::new(&u64::MAX.to_string()).next(), Key::new(""));
+
+    // Shared by every path holding them
+    let keys: Vec<Key> = (0..1000).map(|_| Key::new("status-interned")).collect();
+    let stats = stats();
+
+    assert!(stats.refs >= keys.len());
+    assert!(stats.saved >= (keys.len() - 1) * "status-interned".len());
+}
@@ -0,0 +1,245 @@
+//! Advisory leases on paths.
+//!
+//! A `lock` command with `{ "owner": name, "ttl": seconds }` as params leases its path to `owner`
+//! for `ttl` seconds, or renews the lease if `owner` has it already. The reply is the lease,
+//! `{ "owner": name, "expires": ms }` with milliseconds since the epoch. If another owner has it,
+//! the reply is a conflict with their lease, unless `"wait": seconds` is given: the lock then waits
+//! for the lease in turn, and is replied to once it gets it, or with the conflict once it has
+//! waited that long. `{ "owner": name, "release": true }` gives a lease up before it expires.
+//!
+//! Leases are on paths, not data: they don't stop anyone writing to them, and a lease on a path
+//! doesn't cover those below it. They are held by the `Zone` owning the path, in memory, on the node
+//! the command was sent to; zones holding leases stay loaded, and don't split or fold, until they
+//! are released or expire. Leases are swept along with TTLs, as they expire (see `expiry`).
+
+use std::collections::{BTreeMap, VecDeque};
+
+use mioco::sync::mpsc::Sender;
+use serde_json;
+use serde_json::Value as JSON;
+
+use path::Path;
+
+/// A path's lease.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lease {
+    pub owner: String,
+    pub expires: u64 // Milliseconds since the epoch
+}
+
+/// `lock` command params.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    pub owner: String,
+    pub ttl: f64,           // Seconds
+    pub wait: Option<f64>,  // Seconds to wait for the lease, not at all for none
+    pub release: bool
+}
+
+/// A lock waiting for a lease, replied to once it gets it or gives up.
+pub struct Waiter {
+    pub owner: String,
+    pub ttl: f64,
+    pub until: u64,       // Milliseconds since the epoch
+    pub id: u64,          // Of the command, to reply to
+    pub prefix: Path,     // Zone path replied with
+    pub tx: Sender<String>
+}
+
+/// Leases held in a zone, and locks waiting for them, by path.
+#[derive(Default)]
+pub struct Leases {
+    held: BTreeMap<Path, Lease>,
+    waiting: BTreeMap<Path, VecDeque<Waiter>>
+}
+
+impl Lease {
+    pub fn new(owner: &str, ttl: f64, now: u64) -> Lease {
+        Lease {
+            owner: owner.to_string(),
+            expires: now + (ttl.max(0.0) * 1000.0) as u64
+        }
+    }
+
+    pub fn to_json(&self) -> JSON {
+        let mut json = serde_json::Map::new();
+
+        json.insert("owner".to_string(), JSON::from(self.owner.clone()));
+        json.insert("expires".to_string(), JSON::from(self.expires));
+
+        JSON::Object(json)
+    }
+}
+
+impl Request {
+    /// Parses `{ "owner": name, "ttl": seconds, "wait": seconds }` or
+    /// `{ "owner": name, "release": true }`.
+    pub fn from_params(params: &JSON) -> Result<Request, String> {
+        let owner = try!(params.get("owner").and_then(|owner| owner.as_str()).ok_or("Bad lock owner"));
+        let release = params.get("release").and_then(|release| release.as_bool()).unwrap_or(false);
+
+        let ttl = match params.get("ttl").and_then(|ttl| ttl.as_f64()) {
+            Some(ttl) if ttl > 0.0 => ttl,
+            _ if release => 0.0,
+            _ => return Err("Bad lock ttl".to_string())
+        };
+
+        let wait = match params.get("wait").and_then(|wait| wait.as_f64()) {
+            Some(wait) if wait > 0.0 => Some(wait),
+            _ => None
+        };
+
+        Ok(Request {
+            owner: owner.to_string(),
+            ttl: ttl,
+            wait: wait,
+            release: release
+        })
+    }
+}
+
+impl Waiter {
+    /// Replies like a client does, with `left` in place of the count of replies left.
+    fn reply(&self, left: JSON, lease: &Lease) {
+        let response = vec![self.id.into(), left, self.prefix.to_json(), lease.to_json()];
+
+        self.tx.send(serde_json::to_string(&response).unwrap()).unwrap_or_default();
+    }
+}
+
+impl Leases {
+    pub fn is_empty(&self) -> bool {
+        self.held.is_empty() && self.waiting.is_empty()
+    }
+
+    /// Leases `path` to `owner` for `ttl` seconds from `now`, in milliseconds since the epoch, or
+    /// renews their lease. Returns the lease of the owner holding it otherwise.
+    pub fn acquire(&mut self, path: &Path, owner: &str, ttl: f64, now: u64) -> Result<Lease, Lease> {
+        if let Some(lease) = self.held.get(path) {
+            if lease.owner != owner && lease.expires > now {
+                return Err(lease.clone());
+            }
+        }
+
+        let lease = Lease::new(owner, ttl, now);
+
+        self.held.insert(path.clone(), lease.clone());
+
+        Ok(lease)
+    }
+
+    /// Gives up `owner`'s lease on `path`, to the next lock waiting for it if any. Returns the
+    /// lease of the owner holding it otherwise, if there is one.
+    pub fn release(&mut self, path: &Path, owner: &str, now: u64) -> Result<(), Option<Lease>> {
+        match self.held.get(path) {
+            Some(lease) if lease.owner == owner => (),
+            Some(lease) if lease.expires > now => return Err(Some(lease.clone())),
+            _ => return Err(None)
+        }
+
+        self.held.remove(path);
+        self.grant(path, now);
+
+        Ok(())
+    }
+
+    /// Queues `waiter` for the lease on `path`.
+    pub fn wait(&mut self, path: &Path, waiter: Waiter) {
+        self.waiting.entry(path.clone()).or_insert_with(VecDeque::new).push_back(waiter);
+    }
+
+    /// Drops leases expired at `now`, handing them to locks waiting for them, and replies to locks
+    /// that gave up waiting.
+    pub fn sweep(&mut self, now: u64) {
+        self.held.retain(|_, lease| lease.expires > now);
+
+        let paths: Vec<Path> = self.waiting.keys().cloned().collect();
+
+        for path in paths {
+            self.grant(&path, now);
+
+            let held = self.held.get(&path).cloned();
+
+            let empty = match self.waiting.get_mut(&path) {
+                Some(queue) => {
+                    queue.retain(|waiter| {
+                        if waiter.until > now {
+                            return true;
+                        }
+
+                        if let Some(ref lease) = held {
+                            waiter.reply("conflict".into(), lease);
+                        }
+
+                        false
+                    });
+
+                    queue.is_empty()
+                },
+                None => false
+            };
+
+            if empty {
+                self.waiting.remove(&path);
+            }
+        }
+    }
+
+    /// Hands the lease on `path`, if free, to the first lock waiting for it.
+    fn grant(&mut self, path: &Path, now: u64) {
+        if self.held.contains_key(path) {
+            return;
+        }
+
+        let waiter = match self.waiting.get_mut(path).and_then(|queue| queue.pop_front()) {
+            Some(waiter) => waiter,
+            None => return
+        };
+
+        if self.waiting.get(path).map_or(false, |queue| queue.is_empty()) {
+            self.waiting.remove(path);
+        }
+
+        let lease = Lease::new(&waiter.owner, waiter.ttl, now);
+
+        waiter.reply(0.into(), &lease);
+        self.held.insert(path.clone(), lease);
+    }
+}
+
+#[test]
+fn test_acquire() {
+    use mioco::sync::mpsc::channel;
+
+    let moo = Path::new(vec!["moo".to_string()]);
+    let mut leases = Leases::default();
+
+    assert_eq!(leases.acquire(&moo, "a", 1.0, 1000).unwrap().expires, 2000);
+
+    // Renewed by its owner, but not taken by others until it expires
+    assert_eq!(leases.acquire(&moo, "a", 2.0, 1500).unwrap().expires, 3500);
+    assert_eq!(leases.acquire(&moo, "b", 1.0, 2000).unwrap_err().owner, "a");
+    assert_eq!(leases.release(&moo, "b", 2000), Err(Some(Lease::new("a", 2.0, 1500))));
+    assert_eq!(leases.acquire(&moo, "b", 1.0, 3500).unwrap().owner, "b");
+
+    let (tx, rx) = channel();
+    let waiter = |owner: &str, until: u64| Waiter { owner: owner.to_string(), ttl: 1.0, until: until, id: 1, prefix: Path::empty(), tx: tx.clone() };
+
+    // Handed to waiters in turn
+    leases.wait(&moo, waiter("c", 10000));
+    leases.wait(&moo, waiter("d", 4800));
+    assert!(leases.release(&moo, "b", 4000).is_ok());
+    assert!(rx.try_recv().is_ok());
+    assert_eq!(leases.held.get(&moo).unwrap().owner, "c");
+
+    leases.sweep(4500);
+    assert!(rx.try_recv().is_err());
+
+    // Given up on
+    leases.sweep(4800);
+    assert!(rx.try_recv().is_ok());
+    assert!(leases.waiting.is_empty());
+
+    leases.sweep(5000);
+    assert!(leases.is_empty());
+}
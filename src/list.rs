@@ -0,0 +1,159 @@
+//! Ordered lists of JSON values, stored as values.
+//!
+//! Keys of a node are ordered by name, which doesn't keep the order items were added in. A `list`
+//! command changes the list at its path on the zone, so clients don't read and write all of it:
+//!
+//! * `[ "push", value ]` adds a value at the end, `[ "pop" ]` removes the last one.
+//! * `[ "insert", index, value ]` adds a value before the one at `index`, or at the end for the
+//!   length of the list.
+//! * `[ "remove", index ]` removes the value at `index`.
+//!
+//! Lists read as arrays. Along with its items, a list keeps the change that made it what it is,
+//! `[ "insert" or "remove", index, value ]`, notified to listeners along with it so they can apply
+//! it to the list they have instead of replacing it. Changes are applied by the zone, one after
+//! another; like other values, lists written on replicas at the same time resolve by version.
+
+use serde_json;
+use serde_json::Value as JSON;
+
+/// List of JSON values, kept as JSON text.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct List {
+    items: Vec<String>,
+    change: Option<Change> // Last change made
+}
+
+/// Change at a position, with the value added or removed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Change {
+    Insert(usize, String),
+    Remove(usize, String)
+}
+
+/// An operation on a list, from `list` command params.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Op {
+    Push(JSON),
+    Pop,
+    Insert(usize, JSON),
+    Remove(usize)
+}
+
+impl List {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// Applies `op`, keeping the change it made. Fails if there is no value at its index.
+    pub fn apply(&mut self, op: &Op) -> Result<(), String> {
+        let len = self.items.len();
+
+        let change = match *op {
+            Op::Push(ref value) => Change::Insert(len, value.to_string()),
+            Op::Insert(index, ref value) if index <= len => Change::Insert(index, value.to_string()),
+            Op::Pop if len > 0 => Change::Remove(len - 1, self.items[len - 1].clone()),
+            Op::Remove(index) if index < len => Change::Remove(index, self.items[index].clone()),
+            Op::Pop => return Err("Empty list".to_string()),
+            Op::Insert(index, _) | Op::Remove(index) => return Err(format!("Bad list index: {}", index))
+        };
+
+        match change {
+            Change::Insert(index, ref item) => self.items.insert(index, item.clone()),
+            Change::Remove(index, _) => { self.items.remove(index); }
+        }
+
+        self.change = Some(change);
+
+        Ok(())
+    }
+
+    /// The items, as read by clients.
+    pub fn to_json(&self) -> JSON {
+        JSON::Array(self.items.iter().map(|item| parse(item)).collect())
+    }
+
+    /// The last change, as notified to listeners, if any.
+    pub fn change_json(&self) -> Option<JSON> {
+        self.change.as_ref().map(|change| {
+            let (op, index, item) = match *change {
+                Change::Insert(index, ref item) => ("insert", index, item),
+                Change::Remove(index, ref item) => ("remove", index, item)
+            };
+
+            JSON::Array(vec![JSON::from(op), JSON::from(index as u64), parse(item)])
+        })
+    }
+
+    /// Estimated size once stored.
+    pub fn byte_size(&self) -> usize {
+        let change = match self.change {
+            Some(Change::Insert(_, ref item)) | Some(Change::Remove(_, ref item)) => item.len() + 8,
+            None => 0
+        };
+
+        self.items.iter().map(|item| item.len()).sum::<usize>() + change
+    }
+}
+
+impl Op {
+    /// Parses `list` command params, `[ op, index or value ]`.
+    pub fn from_params(params: &JSON) -> Result<Op, String> {
+        let params = try!(params.as_array().ok_or("Bad list params"));
+
+        let index = |i: usize| params.get(i).and_then(|index| index.as_u64()).map(|index| index as usize).ok_or("Bad list index");
+
+        let op = match (params.get(0).and_then(|op| op.as_str()), params.len()) {
+            (Some("push"), 2) => Op::Push(params[1].clone()),
+            (Some("pop"), 1) => Op::Pop,
+            (Some("insert"), 3) => Op::Insert(try!(index(1)), params[2].clone()),
+            (Some("remove"), 2) => Op::Remove(try!(index(1))),
+            _ => return Err("Bad list op".to_string())
+        };
+
+        Ok(op)
+    }
+}
+
+fn parse(json: &str) -> JSON {
+    serde_json::from_str(json).unwrap_or(JSON::Null)
+}
+
+#[test]
+fn test_apply() {
+    let text = |item: &str| JSON::from(item).to_string();
+    let mut list = List::default();
+
+    list.apply(&Op::Push(JSON::from("moo"))).unwrap();
+    list.apply(&Op::Push(JSON::from("pig"))).unwrap();
+    list.apply(&Op::Insert(1, JSON::from("cow"))).unwrap();
+
+    assert_eq!(list.items, vec![text("moo"), text("cow"), text("pig")]);
+    assert_eq!(list.change, Some(Change::Insert(1, text("cow"))));
+
+    list.apply(&Op::Remove(0)).unwrap();
+    list.apply(&Op::Pop).unwrap();
+
+    assert_eq!(list.items, vec![text("cow")]);
+    assert_eq!(list.change, Some(Change::Remove(1, text("pig"))));
+
+    // Nothing changes on bad indexes
+    assert!(list.apply(&Op::Insert(2, JSON::Null)).is_err());
+    assert!(list.apply(&Op::Remove(1)).is_err());
+    assert_eq!(list.len(), 1);
+
+    list.apply(&Op::Pop).unwrap();
+    assert!(list.apply(&Op::Pop).is_err());
+    assert!(list.is_empty());
+
+    let params = |params: Vec<JSON>| Op::from_params(&JSON::Array(params));
+
+    assert_eq!(params(vec![JSON::from("insert"), JSON::from(2), JSON::Null]), Ok(Op::Insert(2, JSON::Null)));
+    assert_eq!(params(vec![JSON::from("pop")]), Ok(Op::Pop));
+    assert!(params(vec![JSON::from("remove"), JSON::from(-1)]).is_err());
+    assert!(params(vec![JSON::from("push")]).is_err());
+    assert!(Op::from_params(&JSON::Null).is_err());
+}
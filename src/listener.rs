@@ -1,3 +1,6 @@
+//! Listeners of zones, notified of changes matching their path, and with a depth, only of those that
+//! many levels below recursive wildcards (`**` and `*#`).
+
 use std::sync::Arc;
 use std::sync::mpsc::SendError;
 
@@ -5,34 +8,42 @@ use mioco::sync::mpsc::Sender;
 use serde_json;
 use serde_json::value::Value;
 
+#[cfg(feature = "regex")] use matcher;
+use matcher::Regexes;
 use node::Update;
 use path::Path;
 
 pub struct Listener {
     pub root: Arc<Path>,
     pub path: Arc<Path>,
+    pub regexes: Arc<Regexes>, // Of the `*~` keys of `path`, compiled as it was bound
+    pub depth: Option<usize>, // Levels matched by recursive wildcards, all if none
     pub tx: Sender<String>
 }
 
 /// A Relative Listeer
 pub struct RListener {
     pub path: Path,
+    pub regexes: Arc<Regexes>,
+    pub depth: Option<usize>,
     pub tx: Sender<String>
 }
 
 impl Listener {
-    pub fn new(root: Arc<Path>, path: Arc<Path>, tx: Sender<String>) -> Listener {
+    pub fn new(root: Arc<Path>, path: Arc<Path>, regexes: Arc<Regexes>, depth: Option<usize>, tx: Sender<String>) -> Listener {
         Listener {
             root: root,
             path: path,
+            regexes: regexes,
+            depth: depth,
             tx: tx
         }
     }
 
     pub fn update(&self, update: &Update) -> Result<(), SendError<String>> {
         let req_id: Value = 0.into();
-        let root = Value::Array(self.root.path.iter().map(|s| { Value::String(s.clone()) }).collect());
-        let update = update.filter(&self.path.path[..]);
+        let root = Value::Array(self.root.path.iter().map(|s| { Value::String(s.to_string()) }).collect());
+        let update = update.filter_with(&self.path.path[..], self.depth, &self.regexes);
 
         if update == Value::Null {
             return Ok(());
@@ -46,22 +57,120 @@ impl Listener {
 
     /// Computes whether listener is retained and/or delegated
     pub fn delegate(&self, d_path: &Path) -> (bool, Option<RListener>) {
-        let (retain, path) = self.path.delegate(d_path);
-        let d_listener = path.map(|p| RListener::new(p, &self.tx.clone()));
+        let (retain, path) = self.path.delegate(d_path, &self.regexes);
+
+        let d_listener = match delegated_depth(&self.path, d_path, self.depth) {
+            Some(depth) => path.map(|p| RListener::new(p, self.regexes.clone(), depth, &self.tx.clone())),
+            None => None // delegated data is too deep down
+        };
 
         (retain, d_listener)
     }
 }
 
 impl RListener {
-    pub fn new(path: Path, tx: &Sender<String>) -> RListener {
+    pub fn new(path: Path, regexes: Arc<Regexes>, depth: Option<usize>, tx: &Sender<String>) -> RListener {
         RListener {
             path: path,
+            regexes: regexes,
+            depth: depth,
             tx: tx.clone()
         }
     }
 
+    /// Same listener, relative to a zone `prefix` above.
+    pub fn prefixed(self, prefix: &Path) -> RListener {
+        let mut path = prefix.clone();
+        let mut relative = self.path;
+
+        path.append(&mut relative);
+
+        RListener { path: path, regexes: self.regexes, depth: self.depth, tx: self.tx }
+    }
+
     pub fn to_absolute(self, path: Arc<Path>) -> Listener {
-        Listener::new(path, Arc::new(self.path), self.tx)
+        Listener::new(path, Arc::new(self.path), self.regexes, self.depth, self.tx)
+    }
+}
+
+/// Path below the bound one a bind with `params` listens at, its `filter`, or its `regex` with a
+/// regular expression for each key (see `matcher::Segments`), see `Zone::bind`, with the regular
+/// expressions compiled for the listener.
+pub fn bind_filter(params: &Value) -> Result<(Path, Regexes), String> {
+    match (params.get("filter"), params.get("regex")) {
+        (Some(_), Some(_)) => Err("Bind with both filter and regex".to_string()),
+        (_, Some(regex)) => match regex.as_str() {
+            Some(regex) => bind_regex(regex),
+            None => Err("Bad bind regex".to_string())
+        },
+        (Some(filter), None) => {
+            let filter = Path::new(filter.as_array().iter().flat_map(|filter| filter.iter()).filter_map(|p| p.as_str()).map(|p| p.to_string()).collect());
+            let regexes = try!(Regexes::new(&filter));
+
+            Ok((filter, regexes))
+        },
+        (None, None) => Ok((Path::empty(), Regexes::default()))
+    }
+}
+
+#[cfg(feature = "regex")]
+fn bind_regex(regex: &str) -> Result<(Path, Regexes), String> {
+    matcher::Segments::new(regex).map(|segments| (segments.path().clone(), segments.regexes().clone()))
+}
+
+#[cfg(not(feature = "regex"))]
+fn bind_regex(_: &str) -> Result<(Path, Regexes), String> {
+    Err("Bind regex needs the regex feature".to_string())
+}
+
+/// Depth of a bind with `params`, see `Zone::bind`.
+pub fn bind_depth(params: &Value) -> Option<usize> {
+    params.get("depth").and_then(|depth| depth.as_u64()).map(|depth| depth as usize)
+}
+
+/// Params of a bind on `path` for its data delegated at `d_path`, with the depth left there.
+/// Returns `None` if all of that data is deeper than the bind goes.
+pub fn delegated_bind(params: &Value, path: &Path, d_path: &Path) -> Option<Value> {
+    match delegated_depth(path, d_path, bind_depth(params)) {
+        Some(Some(depth)) => {
+            let mut params = params.clone();
+
+            if let Some(params) = params.as_object_mut() {
+                params.insert("depth".to_string(), depth.into());
+            }
+
+            Some(params)
+        },
+        Some(None) => Some(params.clone()),
+        None => None
     }
 }
+
+/// Depth left to a listener at `path` limited to `depth`, for data delegated at `d_path`. Returns
+/// `None` if all of the delegated data is deeper than the listener goes.
+fn delegated_depth(path: &Path, d_path: &Path, depth: Option<usize>) -> Option<Option<usize>> {
+    let recursive = path.path.iter().position(|p| p == "**" || p == "*#");
+
+    match (recursive, depth) {
+        (Some(pos), Some(depth)) if d_path.len() > pos => {
+            let below = d_path.len() - pos;
+
+            if below > depth { None } else { Some(Some(depth - below)) }
+        },
+        _ => Some(depth)
+    }
+}
+
+#[test]
+fn test_delegated_depth() {
+    let path = |path: &str| Path::new(path.split('.').map(|key| key.to_string()).collect());
+
+    assert_eq!(delegated_depth(&path("moo.**"), &path("moo.cow"), None), Some(None));
+    assert_eq!(delegated_depth(&path("moo.**"), &path("moo"), Some(2)), Some(Some(2)));
+    assert_eq!(delegated_depth(&path("moo.**"), &path("moo.cow"), Some(2)), Some(Some(1)));
+    assert_eq!(delegated_depth(&path("moo.**"), &path("moo.cow.pig"), Some(2)), Some(Some(0)));
+    assert_eq!(delegated_depth(&path("moo.**"), &path("moo.cow.pig.hen"), Some(2)), None);
+
+    // Without recursive wildcards, the depth doesn't matter
+    assert_eq!(delegated_depth(&path("moo.*.cow"), &path("moo.pig"), Some(0)), Some(Some(0)));
+}
@@ -6,28 +6,62 @@ extern crate bincode;
 extern crate env_logger;
 #[macro_use] extern crate log;
 extern crate mioco;
-extern crate rand;
+#[cfg(feature = "encryption")] extern crate chacha20poly1305;
+extern crate crc32fast;
+#[cfg(feature = "lz4")] extern crate lz4;
+#[cfg(feature = "mmap")] extern crate memmap;
+#[cfg(feature = "grpc")] extern crate qumulus_grpc;
+#[cfg(feature = "regex")] extern crate regex;
+#[cfg(feature = "watch")] extern crate notify;
+#[cfg(feature = "rocksdb")] extern crate rocksdb;
+#[cfg(feature = "s3")] extern crate s3;
+#[cfg(feature = "sled")] extern crate sled;
+#[cfg(feature = "tls")] extern crate rustls;
+#[cfg(feature = "tls")] extern crate rustls_pemfile;
+#[cfg(all(test, feature = "tls"))] extern crate rcgen;
+#[cfg(feature = "zstd")] extern crate zstd;
 extern crate serde;
 extern crate serde_json;
 #[macro_use] extern crate serde_derive;
-extern crate threadpool;
 extern crate time;
 
 pub mod app;
+pub mod binary;
 pub mod client;
+pub mod clock;
 pub mod cluster;
+pub mod cold;
 pub mod command;
+pub mod crdt;
 pub mod delegate;
+pub mod expiry;
+pub mod freeze;
+pub mod hooks;
+#[cfg(feature = "grpc")] pub mod grpc;
+pub mod http;
+pub mod intern;
+pub mod lease;
+pub mod list;
 pub mod listener;
 pub mod manager;
+pub mod matcher;
 pub mod monitor;
 pub mod node;
 #[macro_use] pub mod path;
+pub mod prefetch;
+pub mod region;
 pub mod replica;
+pub mod schema;
 pub mod shell;
+pub mod snapshot;
 pub mod server;
 pub mod store;
+pub mod strategy;
+pub mod subs;
+pub mod tls;
+pub mod tombstone;
 pub mod value;
+pub mod websocket;
 pub mod zone;
 
 fn main() {
@@ -37,10 +71,25 @@ fn main() {
 
     let args: Vec<_> = std::env::args().collect();
 
-    if args.len() != 2 {
-        println!("Usage: {} <ID>", &args[0]);
+    let mode = args.get(2).map(|arg| arg.as_str());
+
+    let usage = match (args.len(), mode) {
+        (2, _) | (3, Some("migrate")) | (4, Some("import")) | (3, Some("verify")) => false,
+        (4, Some("verify")) if args[3] == "quarantine" => false,
+        (4, Some("restore")) | (5, Some("restore")) => false,
+        _ => true
+    };
+
+    if usage {
+        println!("Usage: {} <ID> [migrate | import <archive> | restore <timestamp> [path] | verify [quarantine]]", &args[0]);
         println!("Missing ID. ID must be provided as an IP:port string.");
         println!("This is used as the listening address as well as the data directory.");
+        println!("With `migrate`, stored data is upgraded to the current format and the node exits.");
+        println!("With `import`, zones in a backup archive are stored and the node exits.");
+        println!("With `restore`, zones under path (or all) are restored to how they were at timestamp, in");
+        println!("milliseconds since the epoch, from the store's history and the node exits.");
+        println!("With `verify`, stored zones are checked for corrupt data, which is moved aside with");
+        println!("`quarantine`, and the node exits.");
 
         return;
     }
@@ -52,20 +101,173 @@ fn main() {
 
     let mut app = app::App::new(id.clone());
 
-    store::fs::FS::spawn(&mut app);
+    let store_config = store::Config::from_env();
+
+    println!("  Store: {:?} ({} compression, {} flush)", store_config.backend, store_config.codec.compression, store_config.flush);
+
+    if store_config.read_only {
+        println!("  Store is read-only");
+    }
+
+    if store_config.quota.is_limited() {
+        println!("  Quota: {}", store_config.quota);
+    }
+
+    if store_config.gc.interval > 0 && ! store_config.read_only {
+        println!("  GC: {}", store_config.gc);
+    }
+
+    if store_config.throttle.is_limited() {
+        println!("  Write throttle: {}", store_config.throttle);
+    }
+
+    if store_config.maintenance.is_limited() {
+        println!("  Maintenance: {}", store_config.maintenance);
+    }
+
+    if store_config.history != store::history::Retention::None {
+        println!("  History: {}", store_config.history);
+    }
+
+    if store_config.dedup {
+        println!("  Deduplicating zone data");
+    }
+
+    if let Some(ref keyring) = store_config.codec.keyring {
+        println!("  Encryption key: {}", keyring.current_id());
+    }
+
+    if let Ok(file) = std::env::var("ZONE_SCHEMA") {
+        let schema = schema::Schema::load(&file).unwrap_or_else(|err| panic!("ZONE_SCHEMA: {}", err));
+
+        println!("  Schema: {} rules", schema.len());
+        app.schema = std::sync::Arc::new(schema);
+    }
+
+    if let Ok(strategies) = std::env::var("ZONE_MERGE") {
+        let strategies: strategy::Strategies = strategies.parse().unwrap_or_else(|err| panic!("ZONE_MERGE: {}", err));
+
+        println!("  Merge strategies: {} paths", strategies.len());
+        app.strategies = std::sync::Arc::new(strategies);
+    }
+
+    if let Ok(frozen) = std::env::var("ZONE_FROZEN") {
+        let frozen: freeze::Frozen = frozen.parse().unwrap_or_else(|err| panic!("ZONE_FROZEN: {}", err));
+
+        println!("  Frozen: {}", frozen);
+        app.frozen = std::sync::Arc::new(frozen);
+    }
+
+    if let Ok(limits) = std::env::var("ZONE_PATH_LIMITS") {
+        app.limits = limits.parse().unwrap_or_else(|err| panic!("ZONE_PATH_LIMITS: {}", err));
+
+        println!("  Path limits: {}", app.limits);
+    }
+
+    if let Ok(retention) = std::env::var("ZONE_TOMBSTONES") {
+        app.tombstones = retention.parse().unwrap_or_else(|err| panic!("ZONE_TOMBSTONES: {}", err));
+
+        println!("  Tombstones kept: {}", app.tombstones);
+    }
+
+    if let Ok(policy) = std::env::var("ZONE_COLD") {
+        app.cold = Some(policy.parse().unwrap_or_else(|err| panic!("ZONE_COLD: {}", err)));
+
+        println!("  Packing cold data: {}", app.cold.unwrap());
+    }
+
+    if mode == Some("migrate") {
+        match store::migrate(&app, &store_config) {
+            Ok(migrated) => println!("Migrated {} zones", migrated),
+            Err(err) => println!("Migration failed: {}", err)
+        }
+
+        return;
+    }
+
+    store::spawn(&mut app, &store_config);
+
+    if mode == Some("restore") {
+        let timestamp: u64 = match args[3].parse() {
+            Ok(timestamp) => timestamp,
+            Err(_) => return println!("Bad timestamp: {}", &args[3])
+        };
+
+        let restored = match args.get(4).map(|path| path.parse::<path::Path>()) {
+            Some(Ok(path)) => app.store.restore(&path, timestamp).map(|restored| restored as usize),
+            Some(Err(err)) => return println!("{}", err),
+            None => app.store.restore_all(timestamp)
+        };
+
+        match restored {
+            Ok(restored) => println!("Restored {} zones", restored),
+            Err(err) => println!("Restore failed: {}", err)
+        }
+
+        return;
+    }
+
+    if mode == Some("verify") {
+        let quarantine = args.get(3).is_some();
+
+        match store::verify::verify(&app.store, &path::Path::empty(), quarantine) {
+            Ok(report) => println!("{}", report),
+            Err(err) => println!("Verification failed: {}", err)
+        }
+
+        return;
+    }
+
+    if mode == Some("import") {
+        let imported = std::fs::File::open(&args[3])
+            .map_err(|err| store::StoreError::Io(err))
+            .and_then(|file| app.store.import(std::io::BufReader::new(file)));
+
+        match imported {
+            Ok(imported) => println!("Imported {} zones", imported),
+            Err(err) => println!("Import failed: {}", err)
+        }
+
+        return;
+    }
+
     manager::Manager::spawn(&mut app);
     cluster::Cluster::spawn(&mut app);
 
     app.manager.load(&path::Path::empty());
 
+    if ! store_config.read_only {
+        store::gc::spawn(&app.handle(), store_config.gc, store_config.maintenance.clone());
+    }
+
+    let tls_config = tls::Config::from_env().unwrap_or_else(|err| panic!("{}", err));
+
     println!("Listening addresses:");
     println!("  API: {}", id.api_addr());
     println!("  Peer: {}", id.peer_addr());
     println!("  Monitor: {}", id.monitor_addr());
+    println!("  WebSocket: {}", id.websocket_addr());
+    println!("  HTTP: {}", id.http_addr());
+
+    if let Some(ref tls_config) = tls_config {
+        println!("  TLS: {}", tls_config);
+    }
 
-    let server = server::Server::new(&app, id.api_addr());
+    #[cfg(feature = "grpc")]
+    println!("  gRPC: {}", id.grpc_addr());
+
+    let server = server::Server::new(&app, id.api_addr()).with_tls(tls_config.clone());
     server.listen();
 
+    let websocket = server::Server::websocket(&app, id.websocket_addr()).with_tls(tls_config.clone());
+    websocket.listen();
+
+    let http = server::Server::http(&app, id.http_addr()).with_tls(tls_config);
+    http.listen();
+
+    #[cfg(feature = "grpc")]
+    grpc::spawn(&app, id.grpc_addr());
+
     let replicas: Vec<replica::Replica> = match std::env::var("CLUSTER") {
         Ok(r) => r.split(' ').map(|r| r.parse().unwrap()).collect(),
         Err(_) => vec![]
@@ -1,22 +1,41 @@
 //! Zone registry, dispatches commands and spawns Zones
+//!
+//! Loaded zones are evicted, written if they have changes and dropped from memory, least recently
+//! used first while more than `MAX_LOADED_SOFT` are loaded. With `ZONE_EVICTION`, they are also
+//! evicted while their data takes more than a memory budget, in estimated bytes (see
+//! `Zone::size`), and once idle for longer than a number of seconds. Evicted zones load again from
+//! the Store when next used. The root zone is never evicted.
 
 use std::any::Any;
 use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
+use std::fmt;
+use std::str::FromStr;
 use std::thread;
+use std::time::Duration;
 
 use mioco;
 use mioco::sync::mpsc::{channel, Receiver, Sender};
-use rand;
 
 use app::{App, AppHandle};
 use listener::RListener;
 use node::External;
 use path::Path;
-use zone::{Zone, ZoneHandle};
+use zone::{AccessStats, Zone, ZoneHandle};
 
 const MAX_LOADED_SOFT: usize = 600;
 const MAX_LOADED_HARD: usize = 800;
 
+/// Seconds between eviction passes, on top of those when zones load
+const EVICTION_INTERVAL: u64 = 1;
+
+/// Limits on loaded zones besides `MAX_LOADED_SOFT`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EvictionPolicy {
+    pub bytes: usize, // Memory budget of loaded zones' data, 0 for no limit
+    pub idle: u64     // Seconds zones stay loaded unused, 0 for no limit
+}
+
 /// A handle to the Manager process. This is the shareable public interface.
 #[derive(Clone)]
 pub struct ManagerHandle {
@@ -34,10 +53,13 @@ pub enum ManagerCall {
     Find(Path),
     List,
     Load(Path),
+    Preload(Vec<Path>),
     ZoneLoaded(Path),
+    Prefetch(Vec<Path>),
 
     // Called by Zones
     SignalDeferHibernation(ZoneHandle),
+    SignalFolded(Path),
     SignalHibernated(ZoneHandle),
     SignalRequestLoad(ZoneHandle),
 }
@@ -64,6 +86,18 @@ impl ManagerHandle {
         self.call(ManagerCall::Load(path.clone()))
     }
 
+    /// Loads many zones ahead of use, e.g. at startup, in one call to the Store. Returns the number
+    /// of zones loaded.
+    pub fn preload(&self, paths: Vec<Path>) -> usize {
+        self.call(ManagerCall::Preload(paths))
+    }
+
+    /// Called by Zone to load the zones at `paths` ahead of reads likely to reach them, see
+    /// `prefetch`. Doesn't wait for them.
+    pub fn prefetch(&self, paths: Vec<Path>) {
+        self.cast(ManagerCall::Prefetch(paths));
+    }
+
     /// Routes delegated data to the correct `Zone`
     pub fn send_external(&self, prefix: &Path, external: External, replicate: bool) {
         let mut path = prefix.clone();
@@ -121,6 +155,16 @@ impl ManagerHandle {
         self.call(ManagerCall::List)
     }
 
+    /// Gets access and mutation counts of every active zone, busiest first. Zones are asked one by
+    /// one from the caller's thread, not the Manager's, as they may be waiting on the Manager.
+    pub fn zone_stats(&self) -> Vec<(Path, AccessStats)> {
+        let mut stats: Vec<_> = self.list().into_iter().map(|zone| (zone.path(), zone.stats())).collect();
+
+        stats.sort_by(|a, b| b.1.ops().cmp(&a.1.ops()).then_with(|| a.0.cmp(&b.0)));
+
+        stats
+    }
+
     /// Called by Zone to defer hibernation.
     pub fn zone_defer_hibernation(&self, zone: ZoneHandle) {
         self.cast(ManagerCall::SignalDeferHibernation(zone));
@@ -131,6 +175,11 @@ impl ManagerHandle {
         self.cast(ManagerCall::SignalHibernated(zone));
     }
 
+    /// Called by Zone to notify that the child `Zone` at `path` folded back into it.
+    pub fn zone_folded(&self, path: &Path) {
+        self.cast(ManagerCall::SignalFolded(path.clone()));
+    }
+
     /// Called by Zone to request to load data.
     pub fn zone_request_load(&self, zone: ZoneHandle) {
         self.cast(ManagerCall::SignalRequestLoad(zone));
@@ -178,7 +227,12 @@ impl Manager {
     }
 
     pub fn new(app: &mut App) -> Manager {
-        let eviction = EvictionManager::spawn();
+        let policy = match env::var("ZONE_EVICTION") {
+            Ok(value) => value.parse().unwrap_or_else(|err| panic!("ZONE_EVICTION: {}", err)),
+            Err(_) => Default::default()
+        };
+
+        let eviction = EvictionManager::spawn(policy);
         let channel = app.channels.manager.take().expect("Receiver already taken");
 
         let manager = Manager {
@@ -202,8 +256,11 @@ impl Manager {
                 ManagerCall::FindNearest(path) => Box::new(self.find_nearest(&path)),
                 ManagerCall::List => Box::new(self.list()),
                 ManagerCall::Load(path) => Box::new(self.load(&path)),
+                ManagerCall::Preload(paths) => Box::new(self.preload(paths)),
                 ManagerCall::ZoneLoaded(path) => Box::new(self.zone_loaded(&path)),
+                ManagerCall::Prefetch(paths) => Box::new(self.prefetch(paths)),
                 ManagerCall::SignalDeferHibernation(zone) => Box::new(self.zone_defer_hibernation(zone)),
+                ManagerCall::SignalFolded(path) => Box::new(self.zone_folded(&path)),
                 ManagerCall::SignalHibernated(zone) => Box::new(self.zone_hibernated(zone)),
                 ManagerCall::SignalRequestLoad(zone) => Box::new(self.zone_request_load(zone)),
             };
@@ -228,6 +285,54 @@ impl Manager {
         zone
     }
 
+    /// Spawns and loads zones not already active, up to MAX_LOADED_SOFT loaded zones, asking the
+    /// Store for all of their data at once so it can read it in the order it reads fastest.
+    pub fn preload(&mut self, paths: Vec<Path>) -> usize {
+        let mut zones = vec![];
+
+        for path in paths {
+            if self.loaded + zones.len() >= MAX_LOADED_SOFT {
+                break;
+            }
+
+            if self.active.contains_key(&path) {
+                continue;
+            }
+
+            // No one else has the handle yet, so the zone is idle until told it is loading
+            let zone = self.load(&path);
+
+            zone.loading();
+            zones.push((zone, path));
+        }
+
+        let count = zones.len();
+
+        for &(ref zone, _) in &zones {
+            self.eviction.tx.send(EvictionCall::Loaded(zone.clone())).unwrap();
+            self.loaded += 1;
+            self.app.stats.zones.local_loaded.increment();
+        }
+
+        if let Err(err) = self.app.store.load_many(zones) {
+            println!("Error preloading zones: {}", err);
+        }
+
+        count
+    }
+
+    /// Loads the zones at `paths` ahead of use: those not active yet like `preload` does, and
+    /// those hibernating once told to prefetch.
+    pub fn prefetch(&mut self, paths: Vec<Path>) {
+        let (active, inactive): (Vec<Path>, Vec<Path>) = paths.into_iter().partition(|path| self.active.contains_key(path));
+
+        for path in active {
+            self.active[&path].prefetch();
+        }
+
+        self.preload(inactive);
+    }
+
     pub fn zone_loaded(&self, path: &Path) -> bool {
         self.active.contains_key(path)
     }
@@ -262,6 +367,16 @@ impl Manager {
         self.eviction.tx.send(EvictionCall::Deferred(zone)).unwrap();
     }
 
+    /// Called by Zone to notify that the child `Zone` at `path` folded back into it. Its paths are
+    /// routed to the parent from now on, and it is told to fold, in case the parent merged the
+    /// data from a replica of it.
+    pub fn zone_folded(&mut self, path: &Path) {
+        if let Some(zone) = self.active.remove(path) {
+            self.app.stats.zones.local_active.decrement();
+            zone.fold();
+        }
+    }
+
     /// Called by Zone to notify of hibernation.
     pub fn zone_hibernated(&mut self, zone: ZoneHandle) {
         self.eviction.tx.send(EvictionCall::Unloaded(zone)).unwrap();
@@ -309,8 +424,10 @@ struct EvictionHandle {
 }
 
 struct EvictionManager {
+    policy: EvictionPolicy,
     loaded: HashSet<ZoneHandle>,
-    pending: HashSet<ZoneHandle>,
+    pending: HashSet<ZoneHandle>,  // Asked to hibernate
+    deferred: HashSet<ZoneHandle>, // Deferred hibernation since the last tick
     rx: Receiver<EvictionCall>,
     tx: Sender<EvictionCall>
 }
@@ -318,27 +435,87 @@ struct EvictionManager {
 enum EvictionCall {
     Loaded(ZoneHandle),
     Unloaded(ZoneHandle),
-    Deferred(ZoneHandle)
+    Deferred(ZoneHandle),
+    Tick
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    /// Parses `none`, `<bytes>` or `<bytes>:<idle seconds>`. A limit of 0 leaves that one off.
+    fn from_str(s: &str) -> Result<EvictionPolicy, String> {
+        if s == "none" {
+            return Ok(Default::default());
+        }
+
+        let mut parts = s.splitn(2, ':');
+
+        let bytes = match parts.next().unwrap().parse() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(format!("Bad eviction memory budget: {}", s))
+        };
+
+        let idle = match parts.next().map(|idle| idle.parse()) {
+            None => 0,
+            Some(Ok(idle)) => idle,
+            Some(Err(_)) => return Err(format!("Bad eviction idle seconds: {}", s))
+        };
+
+        match (EvictionPolicy { bytes: bytes, idle: idle }) {
+            policy if policy.is_limited() => Ok(policy),
+            _ => Err(format!("Bad eviction, no limit: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for EvictionPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.bytes, self.idle) {
+            (0, 0) => write!(f, "none"),
+            (bytes, 0) => write!(f, "{} bytes", bytes),
+            (0, idle) => write!(f, "idle {}s", idle),
+            (bytes, idle) => write!(f, "{} bytes, idle {}s", bytes, idle)
+        }
+    }
+}
+
+impl EvictionPolicy {
+    pub fn is_limited(&self) -> bool {
+        self.bytes > 0 || self.idle > 0
+    }
 }
 
 impl EvictionManager {
-    pub fn spawn() -> EvictionHandle {
-        let manager = EvictionManager::new();
+    pub fn spawn(policy: EvictionPolicy) -> EvictionHandle {
+        let manager = EvictionManager::new(policy);
         let handle = manager.handle();
+        let ticks = handle.clone();
+
+        if policy.is_limited() {
+            info!("Evicting zones past {}", policy);
+        }
 
         thread::spawn(move|| {
             manager.message_loop();
         });
 
+        thread::spawn(move|| {
+            while ticks.tx.send(EvictionCall::Tick).is_ok() {
+                thread::sleep(Duration::from_secs(EVICTION_INTERVAL));
+            }
+        });
+
         handle
     }
 
-    pub fn new() -> EvictionManager {
+    pub fn new(policy: EvictionPolicy) -> EvictionManager {
         let (tx, rx) = channel();
 
         EvictionManager {
+            policy: policy,
             loaded: HashSet::new(),
             pending: HashSet::new(),
+            deferred: HashSet::new(),
             rx: rx,
             tx: tx
         }
@@ -365,7 +542,12 @@ impl EvictionManager {
                 },
                 EvictionCall::Deferred(zone) => {
                     self.pending.remove(&zone);
+                    self.deferred.insert(zone.clone());
                     self.loaded.insert(zone);
+                },
+                EvictionCall::Tick => {
+                    // Zones that had changes to write may have written them by now
+                    self.deferred.clear();
                 }
             }
 
@@ -374,35 +556,38 @@ impl EvictionManager {
         }
     }
 
+    /// Asks zones to hibernate, least recently used first, while too many are loaded or they take
+    /// up more than the memory budget, and any idle for too long. Zones already asked are on their
+    /// way out and don't count. Zones that deferred, having changes not yet written, are left
+    /// alone until the next tick.
     fn evict(&mut self) {
-        let loaded = self.loaded.len();
-        let pending = self.pending.len();
-        let total = loaded + pending;
+        let mut count = self.loaded.len();
+        let mut bytes: usize = self.loaded.iter().map(|zone| zone.bytes()).sum();
+        let idle_limit = Duration::from_secs(self.policy.idle);
 
-        if total <= MAX_LOADED_SOFT {
-            return;
-        }
+        let mut zones: Vec<_> = self.loaded.iter()
+            .filter(|zone| ! self.deferred.contains(zone))
+            .map(|zone| (zone.idle(), zone.clone()))
+            .collect();
 
-        if loaded == 0 {
-            println!("Nothing to evict");
-            return;
-        }
-
-        let overflow = total - MAX_LOADED_SOFT;
-        let r = rand::random::<usize>() % (MAX_LOADED_HARD - MAX_LOADED_SOFT) / 2;
+        zones.sort_by(|a, b| b.0.cmp(&a.0));
 
-        if r > overflow {
-            return;
-        }
+        for (idle, zone) in zones {
+            let over_count = count > MAX_LOADED_SOFT;
+            let over_bytes = self.policy.bytes > 0 && bytes > self.policy.bytes;
+            let too_idle = self.policy.idle > 0 && idle >= idle_limit;
 
-        let i = rand::random::<u64>() % loaded as u64;
-        let zone = self.loaded.iter().nth(i as usize).unwrap().clone();
+            if ! over_count && ! over_bytes && ! too_idle {
+                break; // the rest were used more recently
+            }
 
-        zone.hibernate();
-        self.loaded.remove(&zone);
-        self.pending.insert(zone);
+            count -= 1;
+            bytes -= zone.bytes();
 
-        // TODO improve this cache eviction algorithm
+            zone.hibernate();
+            self.loaded.remove(&zone);
+            self.pending.insert(zone);
+        }
     }
 }
 
@@ -452,3 +637,90 @@ fn test_load() {
 
     assert!(zone.state().is_idle());
 }
+
+#[test]
+fn test_zone_stats() {
+    use app;
+
+    let id = "127.0.0.1:1000".parse().unwrap();
+    let mut app = app::App::new(id);
+
+    Manager::spawn(&mut app);
+
+    let root = Path::new(vec![]);
+    let moo  = Path::new(vec!["moo".into()]);
+
+    app.manager.load(&moo);
+    app.manager.load(&root);
+
+    // Ties in path order
+    assert_eq!(app.manager.zone_stats(), vec![
+        (root, AccessStats::default()),
+        (moo, AccessStats::default())
+    ]);
+}
+
+#[test]
+fn test_parse_eviction() {
+    assert_eq!("none".parse(), Ok(EvictionPolicy::default()));
+    assert_eq!("1000000".parse(), Ok(EvictionPolicy { bytes: 1000000, idle: 0 }));
+    assert_eq!("1000000:600".parse(), Ok(EvictionPolicy { bytes: 1000000, idle: 600 }));
+    assert_eq!("0:600".parse(), Ok(EvictionPolicy { bytes: 0, idle: 600 }));
+
+    assert!("0".parse::<EvictionPolicy>().is_err());
+    assert!("moo".parse::<EvictionPolicy>().is_err());
+    assert!("1000:moo".parse::<EvictionPolicy>().is_err());
+}
+
+#[test]
+fn test_evict() {
+    use std::sync::Arc;
+
+    let zone = |key: &str, idle_ms: usize, bytes: usize| {
+        let zone = ZoneHandle::test_handle(Arc::new(Path::new(vec![key.into()])));
+
+        zone.set_usage(idle_ms, bytes);
+        zone
+    };
+
+    let keys = |zones: &HashSet<ZoneHandle>| {
+        let mut keys: Vec<String> = zones.iter().map(|zone| zone.path().to_string()).collect();
+
+        keys.sort();
+        keys
+    };
+
+    let evicting = |policy: EvictionPolicy, zones: Vec<ZoneHandle>, deferred: Vec<ZoneHandle>| {
+        let mut eviction = EvictionManager::new(policy);
+
+        eviction.loaded.extend(zones);
+        eviction.deferred.extend(deferred);
+        eviction.evict();
+        eviction
+    };
+
+    // Least recently used first, until within the byte budget
+    let eviction = evicting(EvictionPolicy { bytes: 100, idle: 0 }, vec![zone("moo", 3000, 50), zone("cow", 1000, 50), zone("pig", 2000, 50)], vec![]);
+
+    assert_eq!(keys(&eviction.pending), vec!["moo"]);
+    assert_eq!(keys(&eviction.loaded), vec!["cow", "pig"]);
+
+    // Deferred zones are left, and count towards the budget
+    let eviction = evicting(EvictionPolicy { bytes: 60, idle: 0 }, vec![zone("moo", 3000, 50), zone("cow", 1000, 50), zone("pig", 2000, 50)], vec![zone("moo", 0, 0)]);
+
+    assert_eq!(keys(&eviction.pending), vec!["cow", "pig"]);
+    assert_eq!(keys(&eviction.loaded), vec!["moo"]);
+
+    // Idle for too long
+    let eviction = evicting(EvictionPolicy { bytes: 0, idle: 2 }, vec![zone("moo", 3000, 50), zone("cow", 1000, 50), zone("pig", 2500, 50)], vec![]);
+
+    assert_eq!(keys(&eviction.pending), vec!["moo", "pig"]);
+    assert_eq!(keys(&eviction.loaded), vec!["cow"]);
+
+    // Too many loaded, without limits
+    let zones = (0..MAX_LOADED_SOFT + 1).map(|i| zone(&i.to_string(), i, 0)).collect();
+    let eviction = evicting(EvictionPolicy::default(), zones, vec![]);
+
+    assert_eq!(keys(&eviction.pending), vec![MAX_LOADED_SOFT.to_string()]);
+    assert_eq!(eviction.loaded.len(), MAX_LOADED_SOFT);
+}
@@ -0,0 +1,285 @@
+//! Predicates on paths.
+//!
+//! A `PathMatcher` tells whether a path is one of those it stands for. Rules set by path, merge
+//! strategies (see `strategy`) and schema rules (see `schema`), and stores listing the zones below
+//! a prefix all go through one, so other ways of matching paths only need a matcher of their own.
+//!
+//! Listeners don't go through one yet. They match trees of changes rather than paths, a key at a
+//! time: the trie of a zone's subscriptions (see `subs`) follows the keys of an update down its
+//! branches for keys, `*` and each `RegexKey`, and `Update::filter_with` keeps the keys a
+//! listener's path matches with `Regexes::key_matches`. Until they match through a `PathMatcher`,
+//! new ways for listeners to match keys have to be added to both as well.
+//!
+//! `Exact` matches one path, `Prefix` a path and everything below it, `Glob` the paths a pattern
+//! matches (see `Path::matches`) and, with the `regex` feature, `Regex` those whose dotted form
+//! (see `Path::to_string`) matches a regular expression, as a whole, and `Segments` those whose
+//! keys match regular expressions key by key.
+//!
+//! Patterns with regular expressions for keys are paths too, with `*~` keys followed by the regular
+//! expression, so binds with one (see `Zone::bind`) listen at such a path, delegated and folded
+//! with their zones like any other. Their regular expressions are compiled once, as they're bound,
+//! into `Regexes` kept with the listener, which tell which keys such a key matches.
+
+#[cfg(feature = "regex")] use regex;
+
+use path::Path;
+
+/// Start of keys matching the keys a regular expression, the rest of the key, matches.
+pub const REGEX_KEY: &'static str = "*~";
+
+/// Whether a path is one of a family of paths.
+pub trait PathMatcher: Send + Sync {
+    fn matches(&self, path: &Path) -> bool;
+}
+
+/// The path itself, keys taken as they are.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exact(pub Path);
+
+/// The path and those below it, with `*` keys matching any key.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prefix(pub Path);
+
+/// Paths matched by a pattern, with `*` and `**` keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Glob(pub Path);
+
+/// Paths written as dotted strings matching a regular expression.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub struct Regex(regex::Regex);
+
+/// Paths whose keys match regular expressions one by one, written `/` separated, with `*`, `**` and
+/// `*#` matching as in other patterns, e.g. `users/[0-9a-f]{8}/status`.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub struct Segments {
+    path: Path,
+    regexes: Regexes
+}
+
+/// The regular expressions of the `*~` keys of a pattern, compiled.
+#[derive(Clone, Debug, Default)]
+pub struct Regexes {
+    keys: Vec<RegexKey>
+}
+
+/// A `*~` key, and its regular expression compiled to match keys from start to end.
+#[derive(Clone, Debug)]
+pub struct RegexKey {
+    key: String,
+    #[cfg(feature = "regex")]
+    regex: regex::Regex
+}
+
+impl PathMatcher for Exact {
+    fn matches(&self, path: &Path) -> bool {
+        *path == self.0
+    }
+}
+
+impl PathMatcher for Prefix {
+    fn matches(&self, path: &Path) -> bool {
+        self.0.len() <= path.len() && self.0.iter().zip(path.iter()).all(|(p, k)| p == "*" || p == k)
+    }
+}
+
+impl PathMatcher for Glob {
+    fn matches(&self, path: &Path) -> bool {
+        self.0.matches(path)
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Regex {
+    /// Matches paths whose dotted form `pattern` matches from start to end.
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        regex::Regex::new(&format!("^(?:{})$", pattern)).map(Regex).map_err(|err| format!("Bad regex {}: {}", pattern, err))
+    }
+}
+
+#[cfg(feature = "regex")]
+impl PathMatcher for Regex {
+    fn matches(&self, path: &Path) -> bool {
+        self.0.is_match(&path.to_string())
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Segments {
+    /// Compiles the regular expressions of `pattern`, each matching keys from start to end.
+    pub fn new(pattern: &str) -> Result<Segments, String> {
+        let mut keys = vec![];
+
+        for segment in pattern.split('/') {
+            match segment {
+                "*" | "**" | "*#" => keys.push(segment.to_string()),
+                _ => keys.push(format!("{}{}", REGEX_KEY, segment))
+            }
+        }
+
+        let path = Path::new(keys);
+        let regexes = try!(Regexes::new(&path));
+
+        Ok(Segments { path: path, regexes: regexes })
+    }
+
+    /// The pattern as a path, with `*~` keys for the regular expressions.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn regexes(&self) -> &Regexes {
+        &self.regexes
+    }
+}
+
+#[cfg(feature = "regex")]
+impl PathMatcher for Segments {
+    fn matches(&self, path: &Path) -> bool {
+        self.path.matches_with(path, &self.regexes)
+    }
+}
+
+impl Regexes {
+    /// Compiles the `*~` keys of `pattern`, failing on any that isn't a regular expression.
+    pub fn new(pattern: &Path) -> Result<Regexes, String> {
+        let mut keys: Vec<RegexKey> = vec![];
+
+        for key in pattern.iter().filter(|key| is_regex_key(key)) {
+            if ! keys.iter().any(|regex| regex.key == **key) {
+                keys.push(try!(RegexKey::new(key)));
+            }
+        }
+
+        Ok(Regexes { keys: keys })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The compiled `*~` key `key`, if it's one of the pattern's.
+    pub fn get(&self, key: &str) -> Option<&RegexKey> {
+        self.keys.iter().find(|regex| regex.key == key)
+    }
+
+    /// Whether key `key` of the pattern, other than `**` and `*#`, matches key `k` of a path: `*`
+    /// any key, regular expression keys those the regular expression matches, and others
+    /// themselves.
+    pub fn key_matches(&self, key: &str, k: &str) -> bool {
+        match key {
+            "*" => true,
+            key if is_regex_key(key) => self.get(key).map_or(false, |regex| regex.matches(k)),
+            key => key == k
+        }
+    }
+}
+
+impl RegexKey {
+    #[cfg(feature = "regex")]
+    pub fn new(key: &str) -> Result<RegexKey, String> {
+        let pattern = &key[REGEX_KEY.len()..];
+
+        match regex::Regex::new(&format!("^(?:{})$", pattern)) {
+            Ok(regex) => Ok(RegexKey { key: key.to_string(), regex: regex }),
+            Err(err) => Err(format!("Bad regex {}: {}", pattern, err))
+        }
+    }
+
+    #[cfg(not(feature = "regex"))]
+    pub fn new(_: &str) -> Result<RegexKey, String> {
+        Err("Regular expressions need the regex feature".to_string())
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    #[cfg(feature = "regex")]
+    pub fn matches(&self, k: &str) -> bool {
+        self.regex.is_match(k)
+    }
+
+    /// Without regular expressions, no key is one to match.
+    #[cfg(not(feature = "regex"))]
+    pub fn matches(&self, _: &str) -> bool {
+        false
+    }
+}
+
+/// Whether `key` is one of those matching keys by a regular expression.
+pub fn is_regex_key(key: &str) -> bool {
+    key.starts_with(REGEX_KEY)
+}
+
+#[test]
+fn test_matches() {
+    let path = |path: &str| Path::new(path.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+    let users = path("users.moo");
+
+    assert!(Exact(path("users.moo")).matches(&users));
+    assert!(! Exact(path("users")).matches(&users));
+    assert!(! Exact(path("users.*")).matches(&users));
+
+    assert!(Prefix(path("users")).matches(&users));
+    assert!(Prefix(path("users.*")).matches(&users));
+    assert!(Prefix(Path::empty()).matches(&users));
+    assert!(! Prefix(path("users.moo.status")).matches(&users));
+    assert!(! Prefix(path("posts")).matches(&users));
+
+    assert!(Glob(path("users.*")).matches(&users));
+    assert!(Glob(path("**")).matches(&users));
+    assert!(! Glob(path("users")).matches(&users));
+
+    // Any of them, as trait objects
+    let matchers: Vec<Box<PathMatcher>> = vec![Box::new(Exact(path("posts"))), Box::new(Glob(path("*.moo")))];
+
+    assert!(matchers.iter().any(|matcher| matcher.matches(&users)));
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_regex() {
+    let path = |path: &str| Path::new(path.split('.').map(|key| key.to_string()).collect());
+
+    let matcher = Regex::new(r"users\.[a-z]+").unwrap();
+
+    assert!(matcher.matches(&path("users.moo")));
+    assert!(! matcher.matches(&path("users.moo.status")));
+    assert!(! matcher.matches(&path("posts.users.moo")));
+
+    assert!(Regex::new("(").is_err());
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn test_segments() {
+    let path = |path: &str| Path::new(path.split('.').map(|key| key.to_string()).collect());
+
+    let matcher = Segments::new("users/[0-9a-f]{8}/status").unwrap();
+
+    assert_eq!(matcher.path().len(), 3);
+    assert!(matcher.matches(&path("users.0badf00d.status")));
+    assert!(! matcher.matches(&path("users.0badf00d.name")));
+    assert!(! matcher.matches(&path("users.moo.status")));
+    assert!(! matcher.matches(&path("posts.0badf00d.status")));
+
+    let matcher = Segments::new("users|posts/**").unwrap();
+
+    assert!(matcher.matches(&path("posts.moo.title")));
+    assert!(! matcher.matches(&path("drafts.moo")));
+
+    // As keys of patterns, compiled once
+    let regexes = Regexes::new(&Path::new(vec!["*~[a-z]+".to_string(), "*".to_string(), "*~[a-z]+".to_string()])).unwrap();
+
+    assert!(regexes.key_matches("*~[a-z]+", "moo"));
+    assert!(! regexes.key_matches("*~[a-z]+", "moo1"));
+    assert!(! regexes.key_matches("*~[0-9]+", "1"));
+    assert!(regexes.key_matches("*", "moo1"));
+    assert_eq!(regexes.get("*~[a-z]+").map(|regex| regex.key()), Some("*~[a-z]+"));
+    assert!(! Regexes::default().key_matches("*~[a-z]+", "moo"));
+
+    assert!(Segments::new("users/(").is_err());
+}
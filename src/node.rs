@@ -6,16 +6,32 @@
 //! For each 'node' in the tree, two timestamps are tracked as meta information. These timestamps
 //! are used to for consistent conflict resolution.
 //!
-//! Deleted data leave meta information as tombstones which are occasionally cleared [TODO].
+//! The `updated` timestamp of a node is the version of its value: the timestamp of the write that
+//! set it (see `clock`). Reads and binds return it with the value, along with when the node was
+//! created: the timestamp of the earliest write since it was last killed (see `Node::created`).
+//! Creation is only metadata, merged and carried along with the node like the rest of it, but
+//! never deciding which value wins a merge, and left out when values are compared.
+//!
+//! Deleted data leave meta information as tombstones. Tombstones hidden by a deleted ancestor carry
+//! no information and are cleared by `prune` when zones are compacted. Others are kept unless a
+//! retention is set, past which `purge` drops them too (see `tombstone`).
 
+use std::cmp;
 use std::collections::BTreeMap;
 use std::collections::btree_map::Entry;
 use std::mem;
+use std::sync::Arc;
 
 use serde_json;
 use serde_json::Value as JSON;
 
+use crdt::Crdt;
+use list::List;
+use delegate::{Size, Sizes};
+use matcher;
+use matcher::Regexes;
 use path::Path;
+use strategy::{Resolver, Strategies};
 use value::Value;
 
 /// Tracks visibility of a node
@@ -29,8 +45,9 @@ pub struct Vis {
 pub struct Node {
     vis: Vis,
     value: Value,
-    keys: Option<BTreeMap<String, Node>>,
-    delegated: u64
+    keys: Option<Arc<BTreeMap<String, Node>>>, // Shared by copies until one of them changes
+    delegated: u64,
+    created: u64 // Earliest write since it was last killed, see `created()`
 }
 
 /// Node structure that includes ancestor visibility information
@@ -46,10 +63,20 @@ pub struct Update {
     changed: bool,
     old: Option<Value>,
     new: Option<Value>,
+    version: u64, // Version of `new`
+    created: u64, // When the node of `new` was created
     keys: Option<BTreeMap<String, Update>>,
     delegated: Option<bool>
 }
 
+/// How the value at a path differs between two reads, see `Update::diff`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Difference {
+    Added,
+    Changed, // Value or version
+    Removed
+}
+
 #[derive(Debug, Default)]
 pub struct External {
     /// Path to delegated data
@@ -140,10 +167,10 @@ impl Node {
         let vis = Vis::update(timestamp);
 
         match data {
-            JSON::Null => Node { vis: vis, value: Value::Null, ..Default::default() },
-            JSON::Bool(v) => Node { vis: vis, value: Value::Bool(v), ..Default::default() },
-            JSON::Number(v) => Node { vis: vis, value: Value::F64(v.as_f64().unwrap()), ..Default::default() },
-            JSON::String(s) => Node { vis: vis, value: Value::from(s), ..Default::default() },
+            JSON::Null => Node { vis: vis, value: Value::Null, created: timestamp, ..Default::default() },
+            JSON::Bool(v) => Node { vis: vis, value: Value::Bool(v), created: timestamp, ..Default::default() },
+            JSON::Number(v) => Node { vis: vis, value: Value::F64(v.as_f64().unwrap()), created: timestamp, ..Default::default() },
+            JSON::String(s) => Node { vis: vis, value: Value::from(s), created: timestamp, ..Default::default() },
             JSON::Object(obj) => {
                 let keys = obj.into_iter().map(|(k, v)|
                     (k, Node::expand(v, timestamp))
@@ -151,7 +178,8 @@ impl Node {
 
                 Node {
                     vis: vis,
-                    keys: Some(keys),
+                    keys: Some(Arc::new(keys)),
+                    created: timestamp,
                 ..Default::default()
                 }
             },
@@ -162,23 +190,24 @@ impl Node {
 
                 Node {
                     vis: vis,
-                    keys: Some(keys),
+                    keys: Some(Arc::new(keys)),
+                    created: timestamp,
                 ..Default::default()
                 }
             }
         }
     }
 
-    pub fn expand_from(path: &[String], data: JSON, timestamp: u64) -> Node {
+    pub fn expand_from<K: AsRef<str>>(path: &[K], data: JSON, timestamp: u64) -> Node {
         // TODO: make iterative
         match path.len() {
             0 => Node::expand(data, timestamp),
             _ => {
                 match path.split_first() {
                     Some((first, rest)) => Node {
-                        keys: Some(map! {
-                            first.clone() => Node::expand_from(rest, data, timestamp)
-                        }),
+                        keys: Some(Arc::new(map! {
+                            first.as_ref().to_string() => Node::expand_from(rest, data, timestamp)
+                        })),
                         ..Default::default()
                     },
                     None => Default::default()
@@ -187,6 +216,26 @@ impl Node {
         }
     }
 
+    /// Creates a `Node` holding CRDT value `crdt`, see `crdt`.
+    pub fn crdt(crdt: Crdt, timestamp: u64) -> Node {
+        Node {
+            vis: Vis::update(timestamp),
+            value: Value::Crdt(Box::new(crdt)),
+            created: timestamp,
+            ..Default::default()
+        }
+    }
+
+    /// Creates a `Node` holding list `list`, see `list`.
+    pub fn list(list: List, timestamp: u64) -> Node {
+        Node {
+            vis: Vis::update(timestamp),
+            value: Value::List(Box::new(list)),
+            created: timestamp,
+            ..Default::default()
+        }
+    }
+
     pub fn delegate(timestamp: u64) -> Node {
         Node {
             vis: Default::default(),
@@ -195,6 +244,13 @@ impl Node {
         }
     }
 
+    /// Marks this node as no longer delegated as of `timestamp`, for the data of a zone folding
+    /// back into its parent.
+    pub fn undelegated(mut self, timestamp: u64) -> Node {
+        self.delegated = timestamp & !1;
+        self
+    }
+
     pub fn undelegate(timestamp: u64) -> Node {
         Node {
             vis: Default::default(),
@@ -209,18 +265,19 @@ impl Node {
             vis: mem::replace(&mut self.vis, Default::default()),
             value: mem::replace(&mut self.value, Value::Null),
             keys: mem::replace(&mut self.keys, None),
-            delegated: self.delegated
+            delegated: self.delegated,
+            created: mem::replace(&mut self.created, 0)
         }
     }
 
-    pub fn prepend_path(self, path: &[String]) -> Node {
+    pub fn prepend_path<K: AsRef<str>>(self, path: &[K]) -> Node {
         let mut node = self;
 
         for p in path.iter().rev() {
             node = Node {
-                keys: Some(map! {
-                    p.clone() => node
-                }),
+                keys: Some(Arc::new(map! {
+                    p.as_ref().to_string() => node
+                })),
                 ..Default::default()
             }
         }
@@ -232,6 +289,12 @@ impl Node {
         *self == Default::default()
     }
 
+    /// Timestamp the node was created at: the earliest write since it was last killed, or its
+    /// last write when writes before a kill came in after it and the earlier ones are unknown.
+    pub fn created(&self) -> u64 {
+        if self.created > self.vis.deleted { self.created } else { self.vis.updated }
+    }
+
     /// Returns number of child nodes.
     pub fn len(&self) -> usize {
         match self.keys {
@@ -243,18 +306,32 @@ impl Node {
     /// Returns an iterator over the children.
     pub fn each_child<F>(&self, mut f: F) where F: FnMut(&String, &Node) {
         if let Some(ref keys) = self.keys {
-            for (k, node) in keys {
+            for (k, node) in keys.iter() {
                 f(k, node);
             }
         }
     }
 
+    /// Returns the latest timestamp of this node and its children, of updates, deletions or
+    /// delegations.
+    pub fn latest(&self) -> u64 {
+        let mut latest = *[self.vis.updated, self.vis.deleted, self.delegated].iter().max().unwrap();
+
+        self.each_child(|_, child_node| {
+            latest = latest.max(child_node.latest());
+        });
+
+        latest
+    }
+
     /// Returns the estimated byte size of storing this node's value.
     pub fn byte_size(&self) -> usize {
         match self.value {
             Value::Bool(_) => 1,
             Value::I64(_) | Value::U64(_) | Value::F64(_) => 8,
             Value::String(ref s) => s.len(),
+            Value::Crdt(ref crdt) => crdt.byte_size(),
+            Value::List(ref list) => list.byte_size(),
             Value::Null => 1
         }
     }
@@ -277,12 +354,202 @@ impl Node {
                 let mut keys = BTreeMap::new();
 
                 keys.insert(k, child);
-                self.keys = Some(keys);
+                self.keys = Some(Arc::new(keys));
             },
             Some(ref mut keys) => {
-                keys.insert(k, child);
+                Arc::make_mut(keys).insert(k, child);
+            }
+        };
+    }
+
+    /// Removes the child Node with given key, if there is one.
+    pub fn remove_child(&mut self, k: &str) -> Option<Node> {
+        match self.keys {
+            None => None,
+            Some(ref mut keys) => Arc::make_mut(keys).remove(k)
+        }
+    }
+
+    /// Drops descendants hidden by a deletion of one of their ancestors. `vis` is the effective
+    /// visibility of this node's ancestors.
+    ///
+    /// A dropped node is older than the deletion hiding it, so any update it could still affect
+    /// is hidden by that deletion as well. Delegated nodes are always kept.
+    ///
+    /// Returns the number of nodes dropped.
+    pub fn prune(&mut self, mut vis: Vis) -> usize {
+        vis.descend(&self.vis);
+
+        let mut pruned = 0;
+
+        let empty = match self.keys {
+            None => return 0,
+            Some(ref mut keys) => {
+                let keys = Arc::make_mut(keys);
+                let mut dead = vec![];
+
+                for (k, child) in keys.iter_mut() {
+                    pruned += child.prune(vis);
+
+                    if child.keys.is_none() && child.delegated == 0 &&
+                       child.vis.updated <= vis.deleted && child.vis.deleted <= vis.deleted {
+                        dead.push(k.clone());
+                    }
+                }
+
+                for k in dead {
+                    keys.remove(&k);
+                    pruned += 1;
+                }
+
+                keys.is_empty()
+            }
+        };
+
+        if empty {
+            self.keys = None;
+        }
+
+        pruned
+    }
+
+    /// Drops descendants deleted before `cutoff`, once nothing is left below them. Unlike those
+    /// `prune` drops, merges of updates older than their deletion make them visible again, see
+    /// `tombstone`. Delegated nodes are always kept.
+    ///
+    /// Returns the number of nodes dropped.
+    pub fn purge(&mut self, cutoff: u64) -> usize {
+        let mut purged = 0;
+
+        let empty = match self.keys {
+            None => return 0,
+            Some(ref mut keys) => {
+                let keys = Arc::make_mut(keys);
+                let mut dead = vec![];
+
+                for (k, child) in keys.iter_mut() {
+                    purged += child.purge(cutoff);
+
+                    if child.keys.is_none() && child.delegated == 0 && ! child.vis.is_visible() &&
+                       child.vis.deleted > 0 && child.vis.deleted < cutoff {
+                        dead.push(k.clone());
+                    }
+                }
+
+                for k in dead {
+                    keys.remove(&k);
+                    purged += 1;
+                }
+
+                keys.is_empty()
             }
         };
+
+        if empty {
+            self.keys = None;
+        }
+
+        purged
+    }
+
+    /// Returns paths, relative to this node, of descendants currently delegated to other zones.
+    /// Nodes below a delegated node belong to its zone and are not searched.
+    pub fn delegations(&self) -> Vec<Path> {
+        let mut found = vec![];
+        let mut prefix = Path::empty();
+
+        self.find_delegations(&mut prefix, &mut found);
+
+        found
+    }
+
+    fn find_delegations(&self, prefix: &mut Path, found: &mut Vec<Path>) {
+        self.each_child(|k, child| {
+            prefix.push(k);
+
+            if child.delegated & 1 == 1 {
+                found.push(prefix.clone());
+            }
+            else {
+                child.find_delegations(prefix, found);
+            }
+
+            prefix.pop();
+        });
+    }
+
+    /// Returns paths, relative to this node, of descendants marked as no longer delegated. Merged
+    /// diffs only keep the marks that changed something, so these are zones folded back in.
+    pub fn undelegations(&self) -> Vec<Path> {
+        let mut found = vec![];
+        let mut prefix = Path::empty();
+
+        self.find_undelegations(&mut prefix, &mut found);
+
+        found
+    }
+
+    fn find_undelegations(&self, prefix: &mut Path, found: &mut Vec<Path>) {
+        self.each_child(|k, child| {
+            prefix.push(k);
+
+            if child.delegated > 0 && child.delegated & 1 == 0 {
+                found.push(prefix.clone());
+            }
+
+            child.find_undelegations(prefix, found);
+            prefix.pop();
+        });
+    }
+
+    /// Returns paths, relative to this node, of the topmost nodes this diff changes. Merged diffs
+    /// only keep the changes, so these are the parts of a tree a merge changed.
+    pub fn changes(&self) -> Vec<Path> {
+        let mut found = vec![];
+        let mut prefix = Path::empty();
+
+        self.find_changes(&mut prefix, &mut found);
+
+        found
+    }
+
+    fn find_changes(&self, prefix: &mut Path, found: &mut Vec<Path>) {
+        if ! self.vis.is_noop() || self.delegated > 0 || self.value != Value::Null {
+            found.push(prefix.clone());
+            return;
+        }
+
+        self.each_child(|k, child| {
+            prefix.push(k);
+            child.find_changes(prefix, found);
+            prefix.pop();
+        });
+    }
+
+    /// Returns the node at `path`, relative to this one.
+    pub fn at(&self, path: &Path) -> Option<&Node> {
+        let mut node = self;
+
+        for k in &path.path {
+            node = match node.keys.as_ref().and_then(|keys| keys.get(k.as_str())) {
+                Some(node) => node,
+                None => return None
+            };
+        }
+
+        Some(node)
+    }
+
+    /// Puts `node` at `path`, relative to this one, in place of whatever was there.
+    pub fn put<K: AsRef<str>>(&mut self, path: &[K], node: Node) {
+        match path.split_first() {
+            None => *self = node,
+            Some((first, rest)) => {
+                let keys = Arc::make_mut(self.keys.get_or_insert_with(Default::default));
+
+                keys.entry(first.as_ref().to_string()).or_insert_with(Default::default).put(rest, node);
+            }
+        }
     }
 
     /// Unified merge function - merges `diff` into `self` and returns changes.
@@ -302,6 +569,7 @@ impl Node {
     /// * [in]
     ///   * `vis_old` - The previous `Vis` timestamps of ancestor nodes.
     ///   * `vis_new` - The next `Vis` timestamps of ancestor nodes.
+    ///   * `resolver` - Strategies for values written to the same path, see `strategy`.
     /// * [in/out]
     ///   * `diff` - Set of changes to be applied. Modified to retain only actual changes.
     /// * [out]
@@ -310,13 +578,15 @@ impl Node {
     pub fn merge(&mut self,
                  diff: &mut Node,
                  vis_old: Vis,
-                 vis_new: Vis
+                 vis_new: Vis,
+                 resolver: Resolver,
+                 sizes: &mut Sizes
                 ) -> (Option<Update>, Vec<External>) {
         let mut externals: Vec<External> = vec![];
 
         let mut stack = Path::empty();
 
-        let update = merge(&mut stack, self, diff, vis_old, vis_new, &mut externals);
+        let update = merge(&mut stack, self, diff, vis_old, vis_new, &mut externals, resolver, sizes);
 
         (update, externals)
     }
@@ -334,6 +604,29 @@ impl Node {
         (update, externals)
     }
 
+    /// Plain JSON of the data visible through `vis`, the visibility of this node's ancestors, or
+    /// `None` if there is none. Nodes with visible children are objects of them, and their own
+    /// values are left out. Delegated data is left out too.
+    fn to_plain_json(&self, mut vis: Vis) -> Option<JSON> {
+        vis.descend(&self.vis);
+
+        if let Some(ref keys) = self.keys {
+            let keys: serde_json::Map<String, JSON> = keys.iter()
+                .filter(|&(_, child)| child.delegated & 1 == 0)
+                .filter_map(|(k, child)| child.to_plain_json(vis).map(|child| (k.clone(), child)))
+                .collect();
+
+            if ! keys.is_empty() {
+                return Some(JSON::Object(keys));
+            }
+        }
+
+        match vis.is_visible() {
+            true => Some(self.value.to_json()),
+            false => None
+        }
+    }
+
     /// Converts Node to a NodeTree
     pub fn noop_vis(self) -> NodeTree {
         NodeTree {
@@ -346,55 +639,240 @@ impl Node {
 impl NodeTree {
     /// Merge two trees, including visibilitiy through ancestors.
     pub fn merge(&mut self, diff: &mut NodeTree) -> (Option<Update>, Vec<External>) {
+        let (strategies, prefix) = (Strategies::default(), Path::empty());
+
+        self.merge_with(diff, strategies.at(&prefix))
+    }
+
+    /// Merge two trees, resolving values written to the same path with `resolver`.
+    pub fn merge_with(&mut self, diff: &mut NodeTree, resolver: Resolver) -> (Option<Update>, Vec<External>) {
+        self.merge_sized(diff, resolver, &mut Sizes::default())
+    }
+
+    /// Same as `merge_with`, accounting for how the data grows or shrinks in `sizes`, those of
+    /// this tree.
+    pub fn merge_sized(&mut self, diff: &mut NodeTree, resolver: Resolver, sizes: &mut Sizes) -> (Option<Update>, Vec<External>) {
         let (update, externals) = {
             diff.vis.merge(&self.vis); // 'new' vis cannot contain older data than current vis
-            self.node.merge(&mut diff.node, self.vis, diff.vis)
+            self.node.merge(&mut diff.node, self.vis, diff.vis, resolver, sizes)
         };
 
         self.vis = diff.vis;
         (update, externals)
     }
 
+    /// Drops tombstones that can no longer affect merges. See `Node::prune`.
+    pub fn prune(&mut self) -> usize {
+        self.node.prune(self.vis)
+    }
+
+    /// Drops tombstones that can no longer affect merges, and those deleted before `cutoff`. See
+    /// `Node::purge`.
+    pub fn collect_tombstones(&mut self, cutoff: u64) -> usize {
+        self.prune() + self.node.purge(cutoff)
+    }
+
     /// Read data from node
     ///
     /// Returns user-visible data at `path`.
     pub fn read(&self, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
         self.node.read(self.vis, path)
     }
+
+    /// Returns user-visible data as plain JSON, without versions, `Null` if there is none. See
+    /// `Node::to_plain_json`.
+    pub fn to_plain_json(&self) -> JSON {
+        self.node.to_plain_json(self.vis).unwrap_or(JSON::Null)
+    }
+}
+
+impl Difference {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Difference::Added => "added",
+            Difference::Changed => "changed",
+            Difference::Removed => "removed"
+        }
+    }
 }
 
 impl Update {
+    /// Calls `f` with each child updated.
+    pub fn each_child<F>(&self, mut f: F) where F: FnMut(&String, &Update) {
+        if let Some(ref keys) = self.keys {
+            for (k, update) in keys.iter() {
+                f(k, update);
+            }
+        }
+    }
+
+    /// Paths whose values differ between reads `old` and `new`, in order. Like plain JSON, nodes
+    /// with children only count for their value if it isn't `null` (see `to_plain_json`), and data
+    /// delegated to other zones is left out.
+    pub fn diff(old: Option<&Update>, new: Option<&Update>) -> Vec<(Path, Difference)> {
+        let (mut before, mut after) = (BTreeMap::new(), BTreeMap::new());
+
+        if let Some(old) = old {
+            old.values(&mut Path::empty(), &mut before);
+        }
+
+        if let Some(new) = new {
+            new.values(&mut Path::empty(), &mut after);
+        }
+
+        let mut diff: Vec<(Path, Difference)> = before.iter().filter_map(|(path, value)| {
+            match after.get(path) {
+                None => Some((path.clone(), Difference::Removed)),
+                Some(changed) if changed != value => Some((path.clone(), Difference::Changed)),
+                Some(_) => None
+            }
+        }).collect();
+
+        diff.extend(after.keys().filter(|path| ! before.contains_key(path)).map(|path| (path.clone(), Difference::Added)));
+        diff.sort_by(|a, b| a.0.cmp(&b.0));
+        diff
+    }
+
+    /// Values read, with their versions, by path from `path`.
+    fn values<'a>(&'a self, path: &mut Path, values: &mut BTreeMap<Path, (&'a Value, u64)>) {
+        let leaf = self.keys.as_ref().map_or(true, |keys| keys.is_empty());
+
+        if let Some(ref value) = self.new {
+            if leaf || *value != Value::Null {
+                values.insert(path.clone(), (value, self.version));
+            }
+        }
+
+        if let Some(ref keys) = self.keys {
+            for (k, child) in keys.iter().filter(|&(_, child)| child.delegated != Some(true)) {
+                path.push(k);
+                child.values(path, values);
+                path.pop();
+            }
+        }
+    }
+
+    /// Returns the part of this update at `path`, relative to it.
+    pub fn at(mut self, path: &Path) -> Option<Update> {
+        for k in &path.path {
+            self = match self.keys.and_then(|mut keys| keys.remove(k.as_str())) {
+                Some(update) => update,
+                None => return None
+            };
+        }
+
+        Some(self)
+    }
+
+    /// Returns the version of the new value, if there is one.
+    pub fn version(&self) -> Option<u64> {
+        match self.new {
+            Some(_) => Some(self.version),
+            None => None
+        }
+    }
+
+    /// Returns the version of the new value at `path`, if there is one.
+    pub fn version_at(&self, path: &Path) -> Option<u64> {
+        let mut update = self;
+
+        for k in &path.path {
+            update = match update.keys.as_ref().and_then(|keys| keys.get(k.as_str())) {
+                Some(update) => update,
+                None => return None
+            };
+        }
+
+        update.version()
+    }
+
+    /// Returns the new value at `path`, if there is one.
+    pub fn value_at(&self, path: &Path) -> Option<&Value> {
+        let mut update = self;
+
+        for k in &path.path {
+            update = match update.keys.as_ref().and_then(|keys| keys.get(k.as_str())) {
+                Some(update) => update,
+                None => return None
+            };
+        }
+
+        update.new.as_ref()
+    }
+
+    /// Returns the new value at `path` if it's a CRDT.
+    pub fn crdt_at(&self, path: &Path) -> Option<&Crdt> {
+        match self.value_at(path) {
+            Some(&Value::Crdt(ref crdt)) => Some(crdt),
+            _ => None
+        }
+    }
+
     pub fn to_json(&self) -> JSON {
-        let changed = match self.changed {
-            false => JSON::Null,
-            true => JSON::Bool(self.new.is_some()),
+        self.to_json_within(None)
+    }
+
+    /// The data read, written again at `timestamp` with the same values. Data delegated to other
+    /// zones is left out.
+    pub fn to_node(&self, timestamp: u64) -> Node {
+        let keys: BTreeMap<String, Node> = self.keys.iter().flat_map(|keys| keys.iter())
+            .filter(|&(_, child)| child.delegated != Some(true))
+            .map(|(k, child)| (k.clone(), child.to_node(timestamp)))
+            .collect();
+
+        Node {
+            vis: Vis::update(timestamp),
+            value: self.new.clone().unwrap_or(Value::Null),
+            keys: if keys.is_empty() { None } else { Some(Arc::new(keys)) },
+            created: timestamp,
+            ..Default::default()
+        }
+    }
+
+    /// JSON representation of changes down to `depth` levels below this one, `Null` if there are
+    /// none. All of them for no `depth`.
+    fn to_json_within(&self, depth: Option<usize>) -> JSON {
+        let keys = match (&self.keys, depth) {
+            (&None, _) | (_, Some(0)) => JSON::Null,
+            (&Some(ref keys), _) => JSON::Object(keys.iter().filter_map(|(k, v)| {
+                if v.delegated == Some(true) {
+                    return None;
+                }
+
+                match v.to_json_within(depth.map(|depth| depth - 1)) {
+                    JSON::Null => None,
+                    v => Some((k.clone(), v))
+                }
+            }).collect())
         };
 
-        let value = match self.new {
-            Some(Value::Null) | None => JSON::Null,
-            Some(Value::Bool(v)) => JSON::Bool(v),
-            Some(Value::I64(v)) => v.into(),
-            Some(Value::U64(v)) => v.into(),
-            Some(Value::F64(v)) => v.into(),
-            Some(Value::String(ref s)) => JSON::String(String::from(&**s))
+        // Nothing within the depth
+        let keys = match keys {
+            JSON::Object(ref keys) if keys.is_empty() && depth.is_some() => JSON::Null,
+            keys => keys
         };
 
-        let keys = match self.keys {
-            None => JSON::Null,
-            Some(ref keys) => JSON::Object(keys.iter().filter_map(|(k, v)|
-                match v.delegated {
-                    Some(true) => None,
-                    _ => Some((k.clone(), v.to_json()))
-                }
-            ).collect())
+        if depth.is_some() && ! self.changed && keys.is_null() {
+            return JSON::Null;
+        }
+
+        let changed = match self.changed {
+            false => JSON::Null,
+            true => JSON::Bool(self.new.is_some()),
         };
 
-        JSON::Array(vec![keys, changed, value])
+        self.value_json(keys, changed)
     }
 
-    /// Given a path, return the JSON representation which matches data in Update.
-    /// Returns `Null` if nothing matches.
-    pub fn filter(&self, path: &[String]) -> JSON {
+    /// Given a path, return the JSON representation which matches data in Update, down to `depth`
+    /// levels below recursive wildcards if given. Returns `Null` if nothing matches.
+    pub fn filter<K: AsRef<str>>(&self, path: &[K], depth: Option<usize>) -> JSON {
+        self.filter_with(path, depth, &Regexes::default())
+    }
+
+    /// As `filter`, with regular expression keys (see `matcher`) matching the keys `regexes`,
+    /// compiled from them, match.
+    pub fn filter_with<K: AsRef<str>>(&self, path: &[K], depth: Option<usize>, regexes: &Regexes) -> JSON {
         if path.len() == 0 {
             // update matches path so return changes if any
             if ! self.changed {
@@ -403,30 +881,21 @@ impl Update {
 
             let changed = JSON::Bool(self.new.is_some());
 
-            let value = match self.new {
-                Some(Value::Null) | None => JSON::Null,
-                Some(Value::Bool(v)) => JSON::Bool(v),
-                Some(Value::I64(v)) => v.into(),
-                Some(Value::U64(v)) => v.into(),
-                Some(Value::F64(v)) => v.into(),
-                Some(Value::String(ref s)) => JSON::String(String::from(&**s))
-            };
-
-            return JSON::Array(vec![JSON::Null, changed, value])
+            return self.value_json(JSON::Null, changed)
         }
 
-        if path[0] == "**" || path[0] == "*#" {
-            return self.to_json();
+        if path[0].as_ref() == "**" || path[0].as_ref() == "*#" {
+            return self.to_json_within(depth);
         }
 
-        if path[0] == "*" {
+        if path[0].as_ref() == "*" || matcher::is_regex_key(path[0].as_ref()) {
             if let Some(ref keys) = self.keys {
                 let keys = keys.iter().filter_map(|(k, v) | {
-                    if v.delegated.unwrap_or_default() {
+                    if v.delegated.unwrap_or_default() || ! regexes.key_matches(path[0].as_ref(), k) {
                         return None;
                     }
 
-                    let v = v.filter(&path[1..]);
+                    let v = v.filter_with(&path[1..], depth, regexes);
 
                     if v == JSON::Null {
                         return None;
@@ -435,7 +904,7 @@ impl Update {
                     return Some((k.clone(), v));
                 }).collect();
 
-                return JSON::Array(vec![JSON::Object(keys), JSON::Null, JSON::Null]);
+                return JSON::Array(vec![JSON::Object(keys), JSON::Null, JSON::Null, JSON::Null, JSON::Null]);
             }
             else {
                 return JSON::Null;
@@ -443,11 +912,11 @@ impl Update {
         }
 
         if let Some(ref keys) = self.keys {
-            let ref part = path[0];
+            let part = path[0].as_ref();
 
             match keys.get(part) {
                 Some(child_update) => {
-                    let update = child_update.filter(&path[1..]);
+                    let update = child_update.filter_with(&path[1..], depth, regexes);
 
                     if update == JSON::Null {
                         return JSON::Null
@@ -455,9 +924,9 @@ impl Update {
 
                     let mut keys = serde_json::Map::new();
 
-                    keys.insert(part.clone(), update);
+                    keys.insert(part.to_string(), update);
 
-                    return JSON::Array(vec![JSON::Object(keys), JSON::Null, JSON::Null]);
+                    return JSON::Array(vec![JSON::Object(keys), JSON::Null, JSON::Null, JSON::Null, JSON::Null]);
                 },
                 None => {
                     return JSON::Null;
@@ -468,7 +937,34 @@ impl Update {
         return JSON::Null;
     }
 
-    fn add_child(&mut self, k: &String, child_update: Option<Update>) {
+    /// `[ keys, changed, value, version, created ]`, with the change that made a list changed to
+    /// what it is after them (see `list`).
+    fn value_json(&self, keys: JSON, changed: JSON) -> JSON {
+        let value = match self.new {
+            Some(ref value) => value.to_json(),
+            None => JSON::Null
+        };
+
+        let created = match self.new {
+            Some(_) => self.created.into(),
+            None => JSON::Null
+        };
+
+        let mut json = vec![keys, changed, value, self.version_json(), created];
+
+        if let (true, Some(&Value::List(ref list))) = (self.changed, self.new.as_ref()) {
+            json.extend(list.change_json());
+        }
+
+        JSON::Array(json)
+    }
+
+    /// Version of the new value, `Null` if there is none.
+    fn version_json(&self) -> JSON {
+        self.version().map_or(JSON::Null, |version| version.into())
+    }
+
+    fn add_child(&mut self, k: &str, child_update: Option<Update>) {
         if let Some(child_update) = child_update {
             if self.keys.is_none() {
                 self.keys = Some(BTreeMap::new())
@@ -476,7 +972,7 @@ impl Update {
 
             let keys = self.keys.as_mut().unwrap();
 
-            keys.insert(k.clone(), child_update);
+            keys.insert(k.to_string(), child_update);
         }
     }
 
@@ -502,12 +998,15 @@ fn merge(
     diff: &mut Node,
     mut vis_old: Vis, // Old visibility of parent node
     mut vis_new: Vis, // New visibility of parent node
-    externals: &mut Vec<External>)
+    externals: &mut Vec<External>,
+    resolver: Resolver,
+    sizes: &mut Sizes)
 -> Option<Update> {
     // "Previous" effective visibility of this node
     vis_old.descend(&node.vis);
 
     let mut update: Update = Default::default();
+    let bytes = node.byte_size(); // Of the value before, see `Sizes`
 
     if vis_old.is_visible() {
         update.old = Some(node.value.clone()); // TODO unnecessary copy if value / vis not changed
@@ -522,7 +1021,60 @@ fn merge(
 
     // Merge value at node
 
-    if diff.vis.updated > node.vis.updated {
+    // Values neither of which was killed join if they're CRDTs, or a strategy may pick between them
+    let live = diff.vis.updated > cmp::max(vis_old.deleted, diff.vis.deleted) &&
+               vis_old.is_visible() && diff.value != node.value;
+
+    let joined = match (&node.value, &diff.value) {
+        (&Value::Crdt(ref current), &Value::Crdt(ref incoming)) if live => {
+            let mut joined = current.clone();
+
+            match joined.join(incoming) {
+                true => Some(joined),
+                false => None
+            }
+        },
+        _ => None
+    };
+
+    let resolved = match live && joined.is_none() {
+        true => resolver.prefers_incoming(stack, (&node.value, node.vis.updated), (&diff.value, diff.vis.updated)),
+        false => None
+    };
+
+    if let Some(joined) = joined {
+        // Whichever was written first, so merging the diff again joins to the same
+        let joined = Value::Crdt(joined);
+
+        if joined != node.value {
+            node.value = joined;
+            value_changed = true;
+        }
+
+        if diff.vis.updated > node.vis.updated {
+            node.vis.updated = diff.vis.updated;
+            propagate = Some(Default::default());
+        }
+    }
+    else if let Some(incoming) = resolved.filter(|&incoming| incoming != (diff.vis.updated > node.vis.updated)) {
+        // The value that lost by timestamp, merged as a write after both, so merging the diff
+        // resolves it the same without strategies
+        let updated = cmp::max(node.vis.updated, diff.vis.updated) + 1;
+
+        if incoming {
+            node.value = diff.value.clone();
+            value_changed = true;
+        }
+        else {
+            diff.value = node.value.clone();
+        }
+
+        node.vis.updated = updated;
+        diff.vis.updated = updated;
+
+        propagate = Some(Default::default());
+    }
+    else if diff.vis.updated > node.vis.updated {
         // timestamp newer, use updated value
         if node.value != diff.value {
             node.value = diff.value.clone();
@@ -569,6 +1121,11 @@ fn merge(
         diff.vis.deleted = 0
     }
 
+    // Merge creation, the earliest write since the node was last killed whichever order they come in
+    if diff.created > node.vis.deleted && (node.created <= node.vis.deleted || diff.created < node.created) {
+        node.created = diff.created;
+    }
+
     // "New" effective visibility of this node
     vis_new.descend(&node.vis);
 
@@ -581,6 +1138,8 @@ fn merge(
         },
         (false, true)  => {
             update.new = Some(node.value.clone());
+            update.version = node.vis.updated;
+            update.created = node.created();
             update.changed = true;
         },
         (true, false) => {
@@ -589,6 +1148,8 @@ fn merge(
         (true, true)  => {
             if value_changed {
                 update.new = Some(node.value.clone());
+                update.version = node.vis.updated;
+                update.created = node.created();
                 update.changed = true;
             }
             else {
@@ -601,11 +1162,11 @@ fn merge(
     if let Some(mut p_node) = propagate {
         if let Some(ref mut node_keys) = node.keys {
             // Uncloak / delete children
-            for (k, node_child) in node_keys.iter_mut() {
+            for (k, node_child) in Arc::make_mut(node_keys).iter_mut() {
                 stack.push(k);
 
                 // TODO: p_node is mutable and will get corrupted by child nodes
-                let child_diff = merge(stack, node_child, &mut p_node, vis_old, vis_new, externals);
+                let child_diff = merge(stack, node_child, &mut p_node, vis_old, vis_new, externals, resolver, sizes);
 
                 stack.pop();
 
@@ -617,12 +1178,13 @@ fn merge(
     // Merge keys
     if let Some(ref mut diff_keys) = diff.keys {
         if node.keys.is_none() {
-            node.keys = Some(BTreeMap::new());
+            node.keys = Some(Default::default());
         }
 
-        let node_keys = node.keys.as_mut().unwrap();
+        // Copies keys still shared with a snapshot
+        let node_keys = Arc::make_mut(node.keys.as_mut().unwrap());
 
-        for (k, diff_child) in diff_keys.iter_mut() {
+        for (k, diff_child) in Arc::make_mut(diff_keys).iter_mut() {
             // TODO: unnecessary copy if key exists
             let entry = node_keys.entry(k.clone());
 
@@ -631,7 +1193,7 @@ fn merge(
             match entry {
                 Entry::Occupied(mut entry) => {
                     // Existing node exists, so recursively merge
-                    let child_update = merge(stack, entry.get_mut(), diff_child, vis_old, vis_new, externals);
+                    let child_update = merge(stack, entry.get_mut(), diff_child, vis_old, vis_new, externals, resolver, sizes);
                     update.add_child(k, child_update);
 
                     // TODO: remove from diff_keys if noop
@@ -640,11 +1202,13 @@ fn merge(
                     // No existing node, merge to empty node
                     let mut node_child: Node = Default::default();
 
-                    let child_update = merge(stack, &mut node_child, diff_child, vis_old, vis_new, externals);
+                    let child_update = merge(stack, &mut node_child, diff_child, vis_old, vis_new, externals, resolver, sizes);
 
                     if ! node_child.is_noop() {
-                        // If there are actual changes, keep node child
+                        // If there are actual changes, keep node child, whose value was accounted
+                        // for as changed from that of an empty node
                         entry.insert(node_child);
+                        sizes.grow(stack, (k.len() + Node::default().byte_size()) as isize, 1);
                     }
 
                     update.add_child(k, child_update);
@@ -657,6 +1221,10 @@ fn merge(
         // TODO: set diff.keys to None if empty
     }
 
+    if node.byte_size() != bytes {
+        sizes.grow(stack, node.byte_size() as isize - bytes as isize, 0);
+    }
+
     // True if this node is transitioning to a delegated state
     let mut initial_delegation = false;
 
@@ -688,6 +1256,11 @@ fn merge(
             initial: initial_delegation
         };
 
+        // Only the node is left, with a null value
+        let moved = Size::of(&external.tree.node);
+
+        sizes.grow(stack, node.byte_size() as isize - moved.bytes as isize, 1 - moved.entries as isize);
+
         externals.push(external);
 
         // TODO: at this point, delegated data has been moved, so we better not crash
@@ -698,6 +1271,8 @@ fn merge(
             update.changed = false;
             update.old = None;
             update.new = None;
+            update.version = 0;
+            update.created = 0;
             update.keys = None;
         }
     }
@@ -790,9 +1365,9 @@ fn read(stack: &mut Path,
             }
             else {
                 // Match one
-                match node_keys.get(part) {
+                match node_keys.get(part.as_str()) {
                     Some(node_child) => {
-                        stack.push(part);
+                        stack.path.push(part.clone());
 
                         let child_update = read(stack, node_child, vis, &path, pos + 1, externals);
 
@@ -819,6 +1394,8 @@ fn read(stack: &mut Path,
         if vis.is_visible() {
             update.changed = true;
             update.new = Some(node.value.clone());
+            update.version = node.vis.updated;
+            update.created = node.created();
         }
     }
 
@@ -841,15 +1418,17 @@ fn test_expand() {
     let expected = Node {
         vis: Vis::new(1000, 0),
         value:  Value::Null,
-        keys: Some(map! {
+        keys: Some(Arc::new(map! {
             "moo".to_string() => Node {
                 vis: Vis::new(1000, 0),
                 value: Value::F64(42.0),
                 keys: None,
-                delegated: 0
+                delegated: 0,
+                created: 1000
             }
-        }),
-        delegated: 0
+        })),
+        delegated: 0,
+        created: 1000
     };
 
     assert_eq!(node, expected);
@@ -861,39 +1440,45 @@ fn test_merge() {
         node: Node {
             vis: Vis { updated: 1201575709650540, deleted: 0 },
             value: Value::Null,
-            keys: Some(map! {
+            keys: Some(Arc::new(map! {
                 "#5".into() => Node {
                     vis: Vis { updated: 1201575625873458, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    created: 0
                 },
                 "#I".into() => Node {
                     vis: Vis { updated: 1201575640647792, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    created: 0
                 },
                 "#K".into() => Node {
                     vis: Vis { updated: 1201575709365982, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    created: 0
                 },
                 "#S".into() => Node {
                     vis: Vis { updated: 1201575313136481, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    created: 0
                 },
                 "#W".into() => Node {
                     vis: Vis { updated: 1201575709650540, deleted: 0 },
                     value: Value::String("test".into()),
                     keys: None,
-                    delegated: 0
+                    delegated: 0,
+                    created: 0
                 }
-            }),
-            delegated: 1201576002005307
+            })),
+            delegated: 1201576002005307,
+            created: 0
         },
         vis: Vis { updated: 1201575709650540, deleted: 0 }
     };
@@ -907,7 +1492,7 @@ fn test_merge() {
 #[test]
 fn test_merge_noop() {
     let mut tree = NodeTree {
-        node: Node { vis: Vis { updated: 1, deleted: 0 }, value: Value::Null, keys: None, delegated: 0 },
+        node: Node { vis: Vis { updated: 1, deleted: 0 }, value: Value::Null, keys: None, delegated: 0, created: 0 },
         vis: Vis { updated: 1, deleted: 0 }
     };
 
@@ -918,3 +1503,326 @@ fn test_merge_noop() {
     assert_eq!(update, None);
     assert_eq!(externals.len(), 0);
 }
+
+#[test]
+fn test_prune() {
+    let data: JSON = serde_json::from_str(r#"
+        {
+            "moo": { "cow": 42 }
+        }
+    "#).unwrap();
+
+    let mut tree = NodeTree {
+        node: Node::expand(data, 1000),
+        vis: Vis::permanent()
+    };
+
+    let mut delete = Node::delete(2000).prepend_path(&["moo".to_string()]).noop_vis();
+
+    tree.merge(&mut delete);
+
+    // "moo" is the tombstone of the deletion, "cow" is hidden by it
+    assert_eq!(tree.prune(), 1);
+    assert_eq!(tree.prune(), 0);
+
+    let moo = &tree.node.keys.as_ref().unwrap()["moo"];
+
+    assert_eq!(moo.vis, Vis::new(1000, 2000));
+    assert_eq!(moo.keys, None);
+
+    // Older updates stay hidden
+    let mut stale = Node::expand_from(&["moo".to_string(), "cow".to_string()], JSON::from(1), 1500).noop_vis();
+    let (update, _) = tree.merge(&mut stale);
+
+    assert_eq!(update, None);
+}
+
+#[test]
+fn test_delegations() {
+    let node = Node {
+        keys: Some(Arc::new(map! {
+            "moo".to_string() => Node {
+                keys: Some(Arc::new(map! {
+                    "cow".to_string() => Node::delegate(1000),
+                    "pig".to_string() => Node::undelegate(2000)
+                })),
+                ..Default::default()
+            },
+            "sheep".to_string() => Node {
+                keys: Some(Arc::new(map! {
+                    "lamb".to_string() => Node::delegate(1000)
+                })),
+                ..Node::delegate(1000)
+            }
+        })),
+        ..Default::default()
+    };
+
+    // Undelegated nodes and nodes within delegated ones don't count
+    assert_eq!(node.delegations(), [Path::new(vec!["moo".into(), "cow".into()]), Path::new(vec!["sheep".into()])]);
+    assert!(Node::default().delegations().is_empty());
+}
+
+#[test]
+fn test_undelegations() {
+    let moo = Path::new(vec!["moo".into()]);
+
+    let mut tree = NodeTree {
+        node: Node {
+            vis: Vis::update(1000),
+            keys: Some(Arc::new(map! {
+                "moo".to_string() => Node::delegate(1000)
+            })),
+            ..Default::default()
+        },
+        vis: Vis::permanent()
+    };
+
+    // Data of a zone folding back in stays here
+    let mut diff = Node::expand(JSON::from(42), 2000).undelegated(2000).prepend_path(&moo.path).noop_vis();
+    let (update, externals) = tree.merge(&mut diff);
+    let update = update.unwrap();
+
+    assert!(externals.is_empty());
+    assert!(update.at(&moo).unwrap().changed);
+    assert_eq!(diff.node.undelegations(), [moo.clone()]);
+    assert!(tree.node.delegations().is_empty());
+
+    // Marks already merged are dropped from the diff
+    let mut diff = Node::undelegate(2000).prepend_path(&moo.path).noop_vis();
+
+    tree.merge(&mut diff);
+
+    assert!(diff.node.undelegations().is_empty());
+}
+
+#[test]
+fn test_version() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": 42, "cow": 1 }"#).unwrap();
+
+    let mut tree = NodeTree {
+        node: Node::expand(data, 1000),
+        vis: Vis::permanent()
+    };
+
+    let moo = Path::new(vec!["moo".to_string()]);
+    let expected: JSON = serde_json::from_str(r#"[ { "moo": [ null, true, 42.0, 1000, 1000 ] }, null, null, null, null ]"#).unwrap();
+
+    assert_eq!(tree.read(&moo).0.unwrap().to_json(), expected);
+
+    // Bumped by writes, and notified with them, created when first written
+    let mut write = Node::expand_from(&moo.path, JSON::from(43), 2000).noop_vis();
+    let expected: JSON = serde_json::from_str(r#"[ { "moo": [ null, true, 43.0, 2000, 1000 ] }, null, null, null, null ]"#).unwrap();
+
+    assert_eq!(tree.merge(&mut write).0.unwrap().to_json(), expected);
+    assert_eq!(tree.read(&moo).0.unwrap().filter(&moo.path, None), expected);
+    assert_eq!(tree.read(&moo).0.unwrap().version_at(&moo), Some(2000));
+    assert_eq!(tree.read(&Path::new(vec!["pig".to_string()])).0, None);
+    assert_eq!(tree.node.latest(), 2000);
+}
+
+#[test]
+fn test_filter_depth() {
+    let data: JSON = serde_json::from_str(r#"{ "moo": { "cow": 1, "pig": { "hen": 2 } } }"#).unwrap();
+
+    let mut tree = NodeTree {
+        node: Default::default(),
+        vis: Vis::permanent()
+    };
+
+    let mut write = Node::expand(data, 1000).noop_vis();
+    let (update, _) = tree.merge(&mut write);
+    let update = update.unwrap();
+
+    let recursive = ["moo".to_string(), "**".to_string()];
+    let expected: JSON = serde_json::from_str(r#"
+        [ { "moo": [ { "cow": [ null, true, 1.0, 1000, 1000 ], "pig": [ null, true, null, 1000, 1000 ] }, true, null, 1000, 1000 ] }, null, null, null, null ]
+    "#).unwrap();
+
+    assert_eq!(update.filter(&recursive, Some(1)), expected);
+    assert!(update.filter(&recursive, None) != expected);
+
+    // Changes deeper down aren't notified
+    let hen = ["moo".to_string(), "pig".to_string(), "hen".to_string()];
+    let mut write = Node::expand_from(&hen, JSON::from(3), 2000).noop_vis();
+    let (update, _) = tree.merge(&mut write);
+    let update = update.unwrap();
+
+    assert_eq!(update.filter(&recursive, Some(1)), JSON::Null);
+    assert!(update.filter(&recursive, Some(2)) != JSON::Null);
+}
+
+#[test]
+fn test_plain_json() {
+    let mut cow = serde_json::Map::new();
+
+    cow.insert("cow".to_string(), JSON::from(42));
+    cow.insert("pig".to_string(), JSON::from("oink"));
+
+    let mut data = serde_json::Map::new();
+
+    data.insert("moo".to_string(), JSON::Object(cow));
+    data.insert("hen".to_string(), JSON::Bool(true));
+
+    let mut tree = NodeTree {
+        node: Node::expand(JSON::Object(data), 1000),
+        vis: Vis::permanent()
+    };
+
+    // Numbers are stored as floats
+    let json = tree.to_plain_json();
+
+    assert_eq!(json["moo"]["cow"], JSON::from(42.0));
+    assert_eq!(json["moo"]["pig"], JSON::from("oink"));
+    assert_eq!(json["hen"], JSON::Bool(true));
+
+    // Killed and delegated data is left out
+    let mut kill = Node::delete(2000).prepend_path(&["moo".to_string(), "pig".to_string()]).noop_vis();
+    let mut delegate = Node::delegate(2000).prepend_path(&["hen".to_string()]).noop_vis();
+
+    tree.merge(&mut kill);
+    tree.merge(&mut delegate);
+
+    let json = tree.to_plain_json();
+
+    assert_eq!(json["moo"]["cow"], JSON::from(42.0));
+    assert_eq!(json["moo"].get("pig"), None);
+    assert_eq!(json.get("hen"), None);
+
+    // Imported back, it reads the same
+    let imported = NodeTree {
+        node: Node::expand(json.clone(), 3000),
+        vis: Vis::permanent()
+    };
+
+    assert_eq!(imported.to_plain_json(), json);
+    assert_eq!(NodeTree::default().to_plain_json(), JSON::Null);
+}
+
+#[test]
+fn test_purge() {
+    let mut moo = serde_json::Map::new();
+
+    moo.insert("cow".to_string(), JSON::from(1));
+
+    let mut data = serde_json::Map::new();
+
+    data.insert("moo".to_string(), JSON::Object(moo));
+    data.insert("pig".to_string(), JSON::from(2));
+
+    let mut tree = NodeTree {
+        node: Node::expand(JSON::Object(data), 1000),
+        vis: Vis::permanent()
+    };
+
+    let cow = Path::new(vec!["moo".to_string(), "cow".to_string()]);
+    let pig = Path::new(vec!["pig".to_string()]);
+
+    tree.merge(&mut Node::delete(2000).prepend_path(&cow.path).noop_vis());
+    tree.merge(&mut Node::delete(3000).prepend_path(&pig.path).noop_vis());
+
+    // Only tombstones older than the cutoff
+    assert_eq!(tree.collect_tombstones(2500), 1);
+    assert_eq!(tree.node.at(&cow), None);
+    assert!(tree.node.at(&pig).is_some());
+    assert_eq!(tree.collect_tombstones(2500), 0);
+
+    // Older writes aren't hidden anymore
+    let mut stale = Node::expand_from(&cow.path, JSON::from(3), 1500).noop_vis();
+
+    assert!(tree.merge(&mut stale).0.is_some());
+    assert_eq!(tree.collect_tombstones(4000), 1);
+}
+
+#[test]
+fn test_merge_strategy() {
+    use std::sync::Arc;
+    use strategy::MaxValue;
+
+    let mut strategies = Strategies::default();
+
+    strategies.set(Path::empty(), Arc::new(MaxValue));
+
+    let root = Path::empty();
+    let moo = Path::new(vec!["moo".to_string()]);
+    let tree = || NodeTree { node: Node::expand(JSON::Object(serde_json::Map::new()), 1), vis: Vis::permanent() };
+
+    let big = || Node::expand_from(&moo.path, JSON::from(2), 1000).noop_vis();
+    let small = || Node::expand_from(&moo.path, JSON::from(1), 2000).noop_vis();
+
+    // Either way round, the greatest value wins
+    let mut first = tree();
+    let mut second = tree();
+    let mut diffs = vec![];
+
+    for mut diff in vec![big(), small()] {
+        first.merge_with(&mut diff, strategies.at(&root));
+        diffs.push(diff);
+    }
+
+    for mut diff in vec![small(), big()] {
+        second.merge_with(&mut diff, strategies.at(&root));
+    }
+
+    assert_eq!(first.to_plain_json()["moo"], JSON::from(2.0));
+    assert_eq!(first, second);
+
+    // Diffs kept replay to the same without strategies
+    let mut replayed = tree();
+
+    for mut diff in diffs {
+        replayed.merge(&mut diff);
+    }
+
+    assert_eq!(replayed, first);
+}
+
+#[test]
+fn test_merge_crdt() {
+    use crdt::Op;
+
+    let moo = Path::new(vec!["moo".to_string()]);
+    let mut tree = NodeTree { node: Node::expand(JSON::Object(serde_json::Map::new()), 1), vis: Vis::permanent() };
+
+    // Counted on two nodes without seeing each other's
+    let incr = |n: i64, replica: &str, ts: u64| {
+        let mut counter = Crdt::new("pncounter").unwrap();
+
+        counter.apply(&Op::Incr(n), replica, ts).unwrap();
+        Node::crdt(counter, ts).prepend_path(&moo.path).noop_vis()
+    };
+
+    tree.merge(&mut incr(5, "a", 2000));
+    tree.merge(&mut incr(-2, "b", 1000));
+
+    assert_eq!(tree.to_plain_json()["moo"], JSON::from(3));
+    assert_eq!(tree.read(&moo).0.unwrap().version_at(&moo), Some(2000));
+
+    // Kills still drop it, along with older counts merged later
+    tree.merge(&mut Node::delete(3000).prepend_path(&moo.path).noop_vis());
+    tree.merge(&mut incr(1, "b", 2500));
+
+    assert_eq!(tree.to_plain_json().get("moo"), None);
+}
+
+#[test]
+fn test_to_node() {
+    let data = |ts: u64| {
+        let mut cow = serde_json::Map::new();
+        let mut moo = serde_json::Map::new();
+
+        cow.insert("cow".to_string(), JSON::from(42));
+        moo.insert("moo".to_string(), JSON::Object(cow.clone()));
+
+        (Node::expand(JSON::Object(moo), ts), Node::expand(JSON::Object(cow), ts))
+    };
+
+    let (root, _) = data(1000);
+    let tree = NodeTree { node: root, vis: Vis::permanent() };
+    let moo = Path::new(vec!["moo".to_string()]);
+
+    // Read back, then written again later
+    let update = tree.read(&Path::new(vec!["moo".to_string(), "*#".to_string()])).0.unwrap();
+
+    assert_eq!(update.at(&moo).unwrap().to_node(2000), data(2000).1);
+}
@@ -1,17 +1,95 @@
 //! Represents a path to a subtree / node. Ordered so we can iterate through paths in a BTreeMap
+//!
+//! Paths read, bound or otherwise matched against data are patterns too: a `*` key matches any
+//! one key, and `**` any number of them, none included, so `users.*.status` stands for the status
+//! of every user. `Path::matches` tells whether a pattern covers a path.
+//!
+//! Paths sent by clients are checked with `Path::try_new` before they go anywhere: keys can't be
+//! empty, longer than `MAX_KEY_BYTES` or hold control characters, only `*` and `**` keys can start
+//! with `*`, and paths can't be deeper than `MAX_DEPTH`. Nodes can set limits of their own with
+//! `ZONE_PATH_LIMITS=<depth>[:<key bytes>]` (see `Limits`), checked by `Path::try_new_with`. Paths
+//! made by the node itself aren't checked. Converting keys or a dotted path with `Path::try_from`
+//! checks them the same way, while `path!` builds unchecked paths from literals, for tests and
+//! embedders.
+//!
+//! Paths are ordered key by key, indices first by number and other keys by their bytes (see
+//! `intern`), so a path sorts right before the paths below it, and those sort together, before any
+//! other path after it. `Path::range_under` is the range of a path and those below it, for
+//! `BTreeMap::range`, and `Path::sort_key` bytes that sort the same way, for stores ordering keys
+//! by bytes.
+//!
+//! Paths are written as dotted strings for logs and the shell, `users.moo.status`, empty for the
+//! root. Dots and backslashes in keys are escaped with a backslash, and empty keys are written as
+//! `\e`, so any path parses back to itself.
+//!
+//! In URLs, paths are written as `/users/moo/status`, empty for the root, with every byte of a key
+//! but letters, digits, `-`, `.`, `_`, `~` and `*` percent-encoded (see `Path::to_url`).
+//!
+//! As JSON Pointers (RFC 6901), for JSON Patch documents and other tools, paths are written
+//! `/users/moo/status` too, empty for the root, with `~` escaped as `~0` and `/` as `~1`, and
+//! nothing else (see `Path::to_pointer`).
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::ops::Bound;
+use std::slice;
+use std::str;
+use std::str::FromStr;
+use std::usize;
 
 use serde_json::Value;
 
+use intern::Key;
+use matcher;
+use matcher::Regexes;
+
+/// Longest key in a client's path by default, in bytes
+pub const MAX_KEY_BYTES: usize = 1024;
+
+/// Most keys in a client's path by default
+pub const MAX_DEPTH: usize = 128;
+
+/// How deep, and how long keys in, paths sent by clients can be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub depth: usize,    // Keys
+    pub key_bytes: usize
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize)]
 pub struct Path {
-    pub path: Vec<String>
+    pub path: Vec<Key>
+}
+
+/// What is wrong with a client's path, with the index of the key at fault.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathError {
+    TooDeep(usize, usize), // Number of keys instead, and the most allowed
+    EmptyKey(usize),
+    KeyTooLong(usize, usize), // With the most bytes allowed
+    ControlCharacter(usize),
+    BadWildcard(usize), // Starts with `*` but is neither `*` nor `**`
+    BadEscape(usize),   // In a dotted path or a JSON Pointer, see `Path::from_str`
+    BadEncoding(usize)  // In a URL path or a JSON Pointer, see `Path::from_url`
 }
 
+/// Path from its keys, as dotted identifiers, `path!(users.moo)` with `%` for `**` and `#` for
+/// `*#`, or as literals, `path!["users", "moo", 2]`. Keys aren't checked.
 macro_rules! path {
+    ( $($p:literal),+ $(,)* ) => {
+        {
+            Path::new(vec![
+                $(
+                    $p.to_string(),
+                )+
+            ])
+        }
+    };
     ( $($p:tt).* ) => {
         {
-            Path {
-                path: vec![
+            Path::new(vec![
                 $(
                     match stringify!($p) {
                         "%" => "**".to_string(),
@@ -19,8 +97,7 @@ macro_rules! path {
                         p => p.to_string()
                     },
                 )*
-                ]
-            }
+            ])
         }
     }
 }
@@ -31,7 +108,55 @@ impl Path {
     }
 
     pub fn new(path: Vec<String>) -> Path {
-        Path { path: path }
+        Path { path: path.into_iter().map(Key::from).collect() }
+    }
+
+    /// Checks keys from a client, see `PathError`. Runs of `**` keys, which match the same paths as
+    /// one, are normalized to one.
+    pub fn try_new(keys: Vec<String>) -> Result<Path, PathError> {
+        Path::try_new_with(keys, &Limits::default())
+    }
+
+    /// Same as `try_new`, within `limits` rather than the default ones.
+    pub fn try_new_with(keys: Vec<String>, limits: &Limits) -> Result<Path, PathError> {
+        if keys.len() > limits.depth {
+            return Err(PathError::TooDeep(keys.len(), limits.depth));
+        }
+
+        let mut path: Vec<Key> = Vec::with_capacity(keys.len());
+
+        for (i, key) in keys.into_iter().enumerate() {
+            if key.is_empty() {
+                return Err(PathError::EmptyKey(i));
+            }
+
+            if key.len() > limits.key_bytes {
+                return Err(PathError::KeyTooLong(i, limits.key_bytes));
+            }
+
+            if key.chars().any(|c| c.is_control()) {
+                return Err(PathError::ControlCharacter(i));
+            }
+
+            if key.starts_with('*') && key != "*" && key != "**" {
+                return Err(PathError::BadWildcard(i));
+            }
+
+            if key == "**" && path.last().map_or(false, |last| last == "**") {
+                continue;
+            }
+
+            path.push(Key::from(key));
+        }
+
+        Ok(Path { path: path })
+    }
+
+    /// Same as `try_new` for a dotted path, see `from_str`.
+    pub fn try_from_str(s: &str) -> Result<Path, PathError> {
+        let path: Path = try!(s.parse());
+
+        Path::try_new(path.path.iter().map(|key| key.to_string()).collect())
     }
 
     pub fn append(&mut self, path: &mut Path) {
@@ -39,11 +164,10 @@ impl Path {
     }
 
     pub fn push(&mut self, component: &String) {
-        // TODO: unncessary copy
-        self.path.push(component.clone());
+        self.path.push(Key::new(component));
     }
 
-    pub fn pop(&mut self) -> Option<String> {
+    pub fn pop(&mut self) -> Option<Key> {
         self.path.pop()
     }
 
@@ -59,10 +183,11 @@ impl Path {
     pub fn resolved(&self) -> Path {
         let prefix = self.path.iter().take_while(|p| p.starts_with("#"));
 
-        Path::new(prefix.cloned().collect())
+        Path { path: prefix.cloned().collect() }
     }
 
-    pub fn delegate(&self, d_path: &Path) -> (bool, Option<Path>) {
+    /// With `regexes` compiled from the `*~` keys of this path.
+    pub fn delegate(&self, d_path: &Path, regexes: &Regexes) -> (bool, Option<Path>) {
         let mut iter = self.path.iter();
         let mut retain = false;
 
@@ -78,7 +203,7 @@ impl Path {
                     // Part matches, so continue
                     continue;
                 },
-                Some(p) if &*p == "*" => {
+                Some(p) if &*p == "*" || matcher::is_regex_key(p) && regexes.key_matches(p, d) => {
                     // Wildcard matches, retain and continue
                     retain = true;
                     continue;
@@ -98,18 +223,456 @@ impl Path {
         let mut path = vec![];
 
         path.extend(iter.cloned());
-        (retain, Some(Path::new(path)))
+        (retain, Some(Path { path: path }))
+    }
+
+    /// Whether this path has wildcards, standing for a family of paths rather than one.
+    pub fn is_pattern(&self) -> bool {
+        self.path.iter().any(|p| p.starts_with('*'))
+    }
+
+    /// Whether this path, as a pattern, matches `path`, whose keys are taken as they are.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.matches_with(path, &Regexes::default())
+    }
+
+    /// Whether this path matches `path`, with `regexes` compiled from its `*~` keys.
+    pub fn matches_with(&self, path: &Path, regexes: &Regexes) -> bool {
+        glob(&self.path, &path.path, regexes)
     }
 
     pub fn slice(&self, n: usize) -> Path {
-        Path::new(self.path[n..].to_vec())
+        Path { path: self.path[n..].to_vec() }
+    }
+
+    /// Keys in order, from the root down.
+    pub fn iter(&self) -> slice::Iter<Key> {
+        self.path.iter()
+    }
+
+    /// Path one key up, `None` for the root.
+    pub fn parent(&self) -> Option<Path> {
+        match self.path.split_last() {
+            Some((_, parent)) => Some(Path { path: parent.to_vec() }),
+            None => None
+        }
+    }
+
+    /// New path with `key` below this one.
+    pub fn join(&self, key: &str) -> Path {
+        let mut path = self.clone();
+
+        path.path.push(Key::new(key));
+        path
+    }
+
+    /// Whether `prefix` is this path or above it, keys taken as they are.
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        self.path.starts_with(&prefix.path)
+    }
+
+    /// This path relative to `prefix`, `None` unless it starts with it.
+    pub fn relative_to(&self, prefix: &Path) -> Option<Path> {
+        match self.starts_with(prefix) {
+            true => Some(self.slice(prefix.len())),
+            false => None
+        }
+    }
+
+    /// Range of `prefix` and the paths below it, all of them for the root.
+    pub fn range_under(prefix: &Path) -> (Bound<Path>, Bound<Path>) {
+        let end = match prefix.path.split_last() {
+            Some((last, parent)) => {
+                let mut end = Path { path: parent.to_vec() };
+
+                end.path.push(last.next());
+                Bound::Excluded(end)
+            },
+            None => Bound::Unbounded
+        };
+
+        (Bound::Included(prefix.clone()), end)
+    }
+
+    /// Bytes ordered as paths are. Indices are written as `1` and 8 big endian bytes, and other
+    /// keys as `2`, their bytes, `0` ones written as `0 255`, and `0 1`.
+    pub fn sort_key(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.path.iter().map(|key| key.len() + 3).sum());
+
+        for key in &self.path {
+            if let Some(index) = key.index() {
+                bytes.push(1);
+                bytes.extend((0..8).rev().map(|i| (index >> (i * 8)) as u8));
+                continue;
+            }
+
+            bytes.push(2);
+
+            for &b in key.as_bytes() {
+                match b {
+                    0 => bytes.extend_from_slice(&[0, 255]),
+                    b => bytes.push(b)
+                }
+            }
+
+            bytes.extend_from_slice(&[0, 1]);
+        }
+
+        bytes
+    }
+
+    /// URL path of this one, each key after a `/`, percent-encoded.
+    pub fn to_url(&self) -> String {
+        let mut url = String::new();
+
+        for key in &self.path {
+            url.push('/');
+
+            for &b in key.as_bytes() {
+                match b {
+                    b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' | b'*' => url.push(b as char),
+                    b => url.push_str(&format!("%{:02X}", b))
+                }
+            }
+        }
+
+        url
+    }
+
+    /// Parses a path written by `to_url`, without checking keys like `try_new`. Any byte can be
+    /// percent-encoded, in upper or lower case.
+    pub fn from_url(url: &str) -> Result<Path, PathError> {
+        if url.is_empty() {
+            return Ok(Path::empty());
+        }
+
+        if ! url.starts_with('/') {
+            return Err(PathError::BadEncoding(0));
+        }
+
+        let mut path = vec![];
+
+        for (i, segment) in url[1..].split('/').enumerate() {
+            let mut bytes = Vec::with_capacity(segment.len());
+            let mut rest = segment.as_bytes();
+
+            while let Some((&b, after)) = rest.split_first() {
+                if b != b'%' {
+                    bytes.push(b);
+                    rest = after;
+                    continue;
+                }
+
+                let hex = after.get(..2).filter(|hex| hex.iter().all(|&h| (h as char).is_digit(16)));
+
+                match hex.and_then(|hex| str::from_utf8(hex).ok()).map(|hex| u8::from_str_radix(hex, 16)) {
+                    Some(Ok(b)) => bytes.push(b),
+                    _ => return Err(PathError::BadEncoding(i))
+                }
+
+                rest = &after[2..];
+            }
+
+            match String::from_utf8(bytes) {
+                Ok(key) => path.push(key),
+                Err(_) => return Err(PathError::BadEncoding(i))
+            }
+        }
+
+        Ok(Path::new(path))
+    }
+
+    /// JSON Pointer to the value at this path, each key after a `/`, with `~` and `/` escaped.
+    pub fn to_pointer(&self) -> String {
+        let mut pointer = String::new();
+
+        for key in &self.path {
+            pointer.push('/');
+            pointer.push_str(&key.replace('~', "~0").replace('/', "~1"));
+        }
+
+        pointer
+    }
+
+    /// Parses a JSON Pointer, without checking keys like `try_new`. Pointers other than the root's
+    /// start with `/`, and `~` only escapes `~0` or `~1`.
+    pub fn from_pointer(pointer: &str) -> Result<Path, PathError> {
+        if pointer.is_empty() {
+            return Ok(Path::empty());
+        }
+
+        if ! pointer.starts_with('/') {
+            return Err(PathError::BadEncoding(0));
+        }
+
+        let mut path = vec![];
+
+        for (i, segment) in pointer[1..].split('/').enumerate() {
+            let mut key = String::with_capacity(segment.len());
+            let mut chars = segment.chars();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '~' => match chars.next() {
+                        Some('0') => key.push('~'),
+                        Some('1') => key.push('/'),
+                        _ => return Err(PathError::BadEscape(i))
+                    },
+                    c => key.push(c)
+                }
+            }
+
+            path.push(key);
+        }
+
+        Ok(Path::new(path))
     }
 
     pub fn to_json(&self) -> Value {
-        Value::Array(self.path.iter().map(|p| Value::String(p.clone())).collect())
+        Value::Array(self.path.iter().map(|p| Value::String(p.to_string())).collect())
+    }
+
+    /// This path written after `prev`, as `[ n, keys.. ]`: the count of keys it shares with `prev`
+    /// from the root, followed by its keys after those.
+    pub fn to_delta_json(&self, prev: &Path) -> Value {
+        let shared = self.path.iter().zip(prev.path.iter()).take_while(|&(k, p)| k == p).count();
+        let mut json = vec![shared.into()];
+
+        json.extend(self.path[shared..].iter().map(|p| Value::String(p.to_string())));
+        Value::Array(json)
+    }
+
+    /// Path written after `prev` by `to_delta_json`.
+    pub fn from_delta_json(json: &Value, prev: &Path) -> Result<Path, String> {
+        let json = try!(json.as_array().ok_or("Bad delta"));
+        let shared = try!(json.first().and_then(|shared| shared.as_u64()).ok_or("Bad delta")) as usize;
+
+        if shared > prev.len() {
+            return Err("Bad delta".to_string());
+        }
+
+        let mut path = prev.path[..shared].to_vec();
+
+        for key in &json[1..] {
+            path.push(Key::new(try!(key.as_str().ok_or("Bad delta"))));
+        }
+
+        Ok(Path { path: path })
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let keys: Vec<String> = self.path.iter().map(|key| match key.as_str() {
+            "" => "\\e".to_string(),
+            key => key.replace('\\', "\\\\").replace('.', "\\.")
+        }).collect();
+
+        write!(f, "{}", keys.join("."))
+    }
+}
+
+impl FromStr for Path {
+    type Err = PathError;
+
+    /// Parses a dotted path as written by `to_string`, without checking keys like `try_from_str`.
+    fn from_str(s: &str) -> Result<Path, PathError> {
+        if s.is_empty() {
+            return Ok(Path::empty());
+        }
+
+        let mut path = vec![];
+        let mut key = String::new();
+        let mut empty = false; // Key written as `\e`
+        let mut chars = s.chars();
+
+        loop {
+            let next = chars.next();
+
+            let c = match next {
+                None | Some('.') => {
+                    if key.is_empty() && ! empty {
+                        return Err(PathError::EmptyKey(path.len()));
+                    }
+
+                    path.push(mem::replace(&mut key, String::new()));
+                    empty = false;
+
+                    if next.is_none() {
+                        return Ok(Path::new(path));
+                    }
+
+                    continue;
+                },
+                Some('\\') => match chars.next() {
+                    Some('e') if key.is_empty() && ! empty => {
+                        empty = true;
+                        continue;
+                    },
+                    Some(c) if c == '.' || c == '\\' => c,
+                    _ => return Err(PathError::BadEscape(path.len()))
+                },
+                Some(c) => c
+            };
+
+            if empty {
+                return Err(PathError::BadEscape(path.len()));
+            }
+
+            key.push(c);
+        }
+    }
+}
+
+/// A dotted path, checked like `try_new`.
+impl<'a> TryFrom<&'a str> for Path {
+    type Error = PathError;
+
+    fn try_from(s: &'a str) -> Result<Path, PathError> {
+        Path::try_from_str(s)
+    }
+}
+
+/// Keys checked like `try_new`.
+impl TryFrom<Vec<String>> for Path {
+    type Error = PathError;
+
+    fn try_from(keys: Vec<String>) -> Result<Path, PathError> {
+        Path::try_new(keys)
+    }
+}
+
+impl Limits {
+    /// No limits, for paths checked before.
+    pub fn none() -> Limits {
+        Limits { depth: usize::MAX, key_bytes: usize::MAX }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits { depth: MAX_DEPTH, key_bytes: MAX_KEY_BYTES }
+    }
+}
+
+impl FromStr for Limits {
+    type Err = String;
+
+    /// Parses `<depth>[:<key bytes>]`.
+    fn from_str(s: &str) -> Result<Limits, String> {
+        let mut parts = s.splitn(2, ':');
+
+        let depth = match parts.next().map(|depth| depth.parse()) {
+            Some(Ok(depth)) if depth > 0 => depth,
+            _ => return Err(format!("Bad depth: {}", s))
+        };
+
+        let key_bytes = match parts.next().map(|key_bytes| key_bytes.parse()) {
+            Some(Ok(key_bytes)) if key_bytes > 0 => key_bytes,
+            Some(_) => return Err(format!("Bad key bytes: {}", s)),
+            None => MAX_KEY_BYTES
+        };
+
+        Ok(Limits { depth: depth, key_bytes: key_bytes })
+    }
+}
+
+impl fmt::Display for Limits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} keys deep, {} bytes a key", self.depth, self.key_bytes)
+    }
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PathError::TooDeep(len, depth) => write!(f, "Bad path, {} keys deep, at most {}", len, depth),
+            PathError::EmptyKey(i) => write!(f, "Bad path, empty key at {}", i),
+            PathError::KeyTooLong(i, bytes) => write!(f, "Bad path, key at {} longer than {} bytes", i, bytes),
+            PathError::ControlCharacter(i) => write!(f, "Bad path, control character in key at {}", i),
+            PathError::BadWildcard(i) => write!(f, "Bad path, bad wildcard at {}", i),
+            PathError::BadEscape(i) => write!(f, "Bad path, bad escape at {}", i),
+            PathError::BadEncoding(i) => write!(f, "Bad path, bad encoding at {}", i)
+        }
+    }
+}
+
+impl Error for PathError {
+    fn description(&self) -> &str {
+        match *self {
+            PathError::TooDeep(..) => "Path too deep",
+            PathError::EmptyKey(_) => "Empty key",
+            PathError::KeyTooLong(..) => "Key too long",
+            PathError::ControlCharacter(_) => "Control character in key",
+            PathError::BadWildcard(_) => "Bad wildcard",
+            PathError::BadEscape(_) => "Bad escape",
+            PathError::BadEncoding(_) => "Bad encoding"
+        }
     }
 }
 
+/// Whether the keys of `pattern` match those of `path`, `**` (or `*#`, see `listener`) trying
+/// every number of keys it could stand for.
+fn glob(pattern: &[Key], path: &[Key], regexes: &Regexes) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((p, rest)) if p == "**" || p == "*#" => (0..path.len() + 1).any(|n| glob(rest, &path[n..], regexes)),
+        Some((p, rest)) => match path.split_first() {
+            Some((k, path)) => regexes.key_matches(p, k) && glob(rest, path, regexes),
+            None => false
+        }
+    }
+}
+
+#[test]
+fn test_to_pointer() {
+    let path = Path::new(vec!["users".to_string(), "a/b".to_string(), "m~o".to_string(), "".to_string()]);
+
+    assert_eq!(path.to_pointer(), "/users/a~1b/m~0o/");
+    assert_eq!(Path::from_pointer(&path.to_pointer()), Ok(path));
+    assert_eq!(Path::empty().to_pointer(), "");
+    assert_eq!(Path::from_pointer(""), Ok(Path::empty()));
+    assert_eq!(Path::from_pointer("/"), Ok(Path::new(vec!["".to_string()])));
+
+    // `~01` is `~1`, not `/`
+    assert_eq!(Path::from_pointer("/~01"), Ok(Path::new(vec!["~1".to_string()])));
+    assert_eq!(path!(users.moo).to_pointer(), "/users/moo");
+
+    assert_eq!(Path::from_pointer("users"), Err(PathError::BadEncoding(0)));
+    assert_eq!(Path::from_pointer("/users/~2"), Err(PathError::BadEscape(1)));
+    assert_eq!(Path::from_pointer("/users~"), Err(PathError::BadEscape(0)));
+}
+
+#[test]
+fn test_delta_json() {
+    let paths = vec![path!(users.moo.status), path!(users.moo.name), path!(users.cow), path!(posts), Path::empty()];
+    let mut prev = Path::empty();
+
+    for path in &paths {
+        let delta = path.to_delta_json(&prev);
+
+        assert_eq!(Path::from_delta_json(&delta, &prev), Ok(path.clone()));
+        prev = path.clone();
+    }
+
+    assert_eq!(path!(users.moo.name).to_delta_json(&path!(users.moo.status)), Value::Array(vec![2.into(), "name".to_string().into()]));
+    assert_eq!(path!(users).to_delta_json(&path!(users.moo)), Value::Array(vec![1.into()]));
+
+    assert!(Path::from_delta_json(&Value::Array(vec![3.into()]), &path!(users.moo)).is_err());
+    assert!(Path::from_delta_json(&path!(users).to_json(), &Path::empty()).is_err());
+}
+
+#[test]
+fn test_try_from() {
+    assert_eq!(Path::try_from("users.moo.status"), Ok(path!(users.moo.status)));
+    assert_eq!(Path::try_from(""), Ok(Path::empty()));
+    assert_eq!(Path::try_from("users..status"), Err(PathError::EmptyKey(1)));
+    assert_eq!(Path::try_from("users.*moo"), Err(PathError::BadWildcard(1)));
+
+    assert_eq!(Path::try_from(vec!["moo.cow".to_string()]), Ok(path!["moo.cow"]));
+    assert_eq!(Path::try_from(vec!["moo".to_string(), "".to_string()]), Err(PathError::EmptyKey(1)));
+}
+
 #[test]
 fn test_macro() {
     assert_eq!(path(vec!["root"]), path!(root));
@@ -119,9 +682,12 @@ fn test_macro() {
     assert_eq!(path(vec!["root", "*"]), path!(root.*));
     assert_eq!(path(vec!["root", "*", "moo"]), path!(root.*.moo));
     assert_eq!(path(vec!["root", "**"]), path!(root.%));
+    assert_eq!(path(vec!["users", "123", "status"]), path!["users", "123", "status"]);
+    assert_eq!(path(vec!["items", "2", "moo.cow"]), path!["items", 2, "moo.cow",]);
+    assert_eq!(path(vec!["0"]), path![0]);
 
     fn path(path: Vec<&str>) -> Path {
-        Path { path: path.iter().map(|p| p.to_string()).collect() }
+        Path { path: path.iter().map(|&p| Key::new(p)).collect() }
     }
 }
 
@@ -138,8 +704,182 @@ fn test_push() {
 fn test_pop() {
     let mut p = path!(root.moo.cow);
 
-    assert_eq!(p.pop(), Some("cow".to_string()));
-    assert_eq!(p.pop(), Some("moo".to_string()));
+    assert_eq!(p.pop(), Some(Key::new("cow")));
+    assert_eq!(p.pop(), Some(Key::new("moo")));
+}
+
+#[test]
+fn test_matches() {
+    assert!(path!(users.*.status).matches(&path!(users.moo.status)));
+    assert!(! path!(users.*.status).matches(&path!(users.moo)));
+    assert!(! path!(users.*.status).matches(&path!(users.moo.cow.status)));
+    assert!(! path!(users.*.status).matches(&path!(posts.moo.status)));
+
+    // Any depth, none included
+    assert!(path!(users.%).matches(&path!(users)));
+    assert!(path!(users.%).matches(&path!(users.moo.cow)));
+    assert!(path!(%.status).matches(&path!(users.moo.status)));
+    assert!(path!(users.%.status).matches(&path!(users.status)));
+    assert!(! path!(users.%.status).matches(&path!(users.moo.name)));
+    assert!(path!(%).matches(&Path::empty()));
+
+    assert!(path!(moo).matches(&path!(moo)));
+    assert!(! path!(moo).is_pattern());
+    assert!(path!(moo.*).is_pattern());
+}
+
+#[test]
+fn test_try_new() {
+    let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(Path::try_new(keys(&["users", "*", "status"])), Ok(path!(users.*.status)));
+    assert_eq!(Path::try_new(keys(&["moo", "**", "**"])), Ok(path!(moo.%)));
+    assert_eq!(Path::try_from_str("users.*.status"), Ok(path!(users.*.status)));
+    assert_eq!(Path::try_from_str(""), Ok(Path::empty()));
+
+    assert_eq!(Path::try_new(keys(&["moo", ""])), Err(PathError::EmptyKey(1)));
+    assert_eq!(Path::try_from_str("moo..cow"), Err(PathError::EmptyKey(1)));
+    assert_eq!(Path::try_new(keys(&["moo\n"])), Err(PathError::ControlCharacter(0)));
+    assert_eq!(Path::try_new(keys(&["moo", "*#"])), Err(PathError::BadWildcard(1)));
+    assert_eq!(Path::try_new(vec!["m".repeat(MAX_KEY_BYTES + 1)]), Err(PathError::KeyTooLong(0, MAX_KEY_BYTES)));
+    assert_eq!(Path::try_new(vec!["moo".to_string(); MAX_DEPTH + 1]), Err(PathError::TooDeep(MAX_DEPTH + 1, MAX_DEPTH)));
+
+    let limits: Limits = "2:3".parse().unwrap();
+
+    assert_eq!(Path::try_new_with(keys(&["moo", "cow"]), &limits), Ok(path!(moo.cow)));
+    assert_eq!(Path::try_new_with(keys(&["moo", "cow", "pig"]), &limits), Err(PathError::TooDeep(3, 2)));
+    assert_eq!(Path::try_new_with(keys(&["moo", "cows"]), &limits), Err(PathError::KeyTooLong(1, 3)));
+    assert_eq!(Path::try_new_with(vec!["moo".to_string(); MAX_DEPTH + 1], &Limits::none()).map(|path| path.len()), Ok(MAX_DEPTH + 1));
+    assert_eq!("64".parse(), Ok(Limits { depth: 64, key_bytes: MAX_KEY_BYTES }));
+    assert!("0".parse::<Limits>().is_err());
+    assert!("64:moo".parse::<Limits>().is_err());
+}
+
+#[test]
+fn test_relative() {
+    let path = path!(users.moo.status);
+
+    assert_eq!(path.parent(), Some(path!(users.moo)));
+    assert_eq!(Path::empty().parent(), None);
+    assert_eq!(path!(users.moo).join("status"), path);
+    assert_eq!(path.iter().last(), Some(&Key::new("status")));
+
+    assert!(path.starts_with(&path!(users)));
+    assert!(path.starts_with(&path));
+    assert!(path.starts_with(&Path::empty()));
+    assert!(! path.starts_with(&path!(users.cow)));
+    assert!(! path.starts_with(&path!(users.*)));
+
+    assert_eq!(path.relative_to(&path!(users)), Some(path!(moo.status)));
+    assert_eq!(path.relative_to(&path), Some(Path::empty()));
+    assert_eq!(path.relative_to(&path!(posts)), None);
+    assert_eq!(path!(users).relative_to(&path), None);
+}
+
+#[test]
+fn test_order() {
+    use std::collections::BTreeSet;
+
+    let keys = |keys: &[&str]| Path::new(keys.iter().map(|key| key.to_string()).collect());
+
+    let paths: BTreeSet<Path> = vec![
+        Path::empty(),
+        path!(moo),
+        path!(moo.cow),
+        path!(moo.cow.pig),
+        keys(&["moo", "cow\0"]),
+        keys(&["moo", "cow", "\0"]),
+        path!(moo.cows),
+        path!(moo0),
+        keys(&["moo\0"]),
+        path!(pig),
+        path!(items.2),
+        path!(items.10),
+        path!(items.10.moo),
+        path!(items.011),
+        path!(items.18446744073709551615),
+        keys(&["items", ""])
+    ].into_iter().collect();
+
+    let under: Vec<&Path> = paths.range(Path::range_under(&path!(moo.cow))).collect();
+
+    assert_eq!(under, vec![&path!(moo.cow), &keys(&["moo", "cow", "\0"]), &path!(moo.cow.pig)]);
+    assert_eq!(paths.range(Path::range_under(&path!(moo))).count(), 6);
+    assert_eq!(paths.range(Path::range_under(&Path::empty())).count(), paths.len());
+
+    // Indices by number, before other keys
+    let items: Vec<&Path> = paths.range(Path::range_under(&path!(items))).collect();
+
+    assert_eq!(items, vec![
+        &path!(items.2), &path!(items.10), &path!(items.10.moo), &path!(items.18446744073709551615),
+        &keys(&["items", ""]), &path!(items.011)
+    ]);
+    assert_eq!(paths.range(Path::range_under(&path!(items.10))).count(), 2);
+    assert_eq!(paths.range(Path::range_under(&path!(items.18446744073709551615))).count(), 1);
+
+    // Sort keys sort the same
+    let sorted: Vec<Vec<u8>> = paths.iter().map(|path| path.sort_key()).collect();
+    let mut resorted = sorted.clone();
+
+    resorted.sort();
+    assert_eq!(sorted, resorted);
+    assert_eq!(sorted.len(), resorted.iter().collect::<BTreeSet<_>>().len());
+}
+
+#[test]
+fn test_to_url() {
+    let keys = |keys: &[&str]| Path::new(keys.iter().map(|key| key.to_string()).collect());
+
+    let paths = vec![
+        Path::empty(),
+        path!(users.moo.status),
+        path!(users.*.%),
+        keys(&["a/b", "moo cow", "100%", "\u{e9}t\u{e9}"]),
+        keys(&[""]),
+        keys(&["moo", ""])
+    ];
+
+    for path in paths {
+        assert_eq!(Path::from_url(&path.to_url()), Ok(path));
+    }
+
+    assert_eq!(path!(users.*.status).to_url(), "/users/*/status");
+    assert_eq!(keys(&["a/b", "\u{e9}"]).to_url(), "/a%2Fb/%C3%A9");
+    assert_eq!(keys(&[""]).to_url(), "/");
+    assert_eq!(Path::from_url("/moo%2fcow"), Ok(keys(&["moo/cow"])));
+
+    assert_eq!(Path::from_url("moo"), Err(PathError::BadEncoding(0)));
+    assert_eq!(Path::from_url("/moo/cow%2"), Err(PathError::BadEncoding(1)));
+    assert_eq!(Path::from_url("/moo%zz"), Err(PathError::BadEncoding(0)));
+    assert_eq!(Path::from_url("/%+1"), Err(PathError::BadEncoding(0)));
+    assert_eq!(Path::from_url("/%FF"), Err(PathError::BadEncoding(0)));
+}
+
+#[test]
+fn test_to_string() {
+    let keys = |keys: &[&str]| Path::new(keys.iter().map(|key| key.to_string()).collect());
+
+    let paths = vec![
+        Path::empty(),
+        path!(users.moo.status),
+        path!(users.*.%),
+        keys(&["moo.cow", "a\\b", "\\e"]),
+        keys(&[""]),
+        keys(&["", "moo", ""])
+    ];
+
+    for path in paths {
+        assert_eq!(path.to_string().parse(), Ok(path));
+    }
+
+    assert_eq!(path!(users.moo).to_string(), "users.moo");
+    assert_eq!(keys(&["moo.cow", ""]).to_string(), "moo\\.cow.\\e");
+
+    assert_eq!("moo..cow".parse::<Path>(), Err(PathError::EmptyKey(1)));
+    assert_eq!("moo.".parse::<Path>(), Err(PathError::EmptyKey(1)));
+    assert_eq!("moo\\".parse::<Path>(), Err(PathError::BadEscape(0)));
+    assert_eq!("cow.\\emoo".parse::<Path>(), Err(PathError::BadEscape(1)));
+    assert_eq!(Path::try_from_str("moo.\\e"), Err(PathError::EmptyKey(1)));
 }
 
 #[test]
@@ -211,7 +951,7 @@ fn test_delegate_match() {
     assert_eq!(p.unwrap(), path!(cow.%));
 
     fn d(listener: Path, delegated: Path) -> (bool, Option<Path>) {
-        let (retain, d_listener) = listener.delegate(&delegated);
+        let (retain, d_listener) = listener.delegate(&delegated, &Regexes::default());
 
         (retain, d_listener)
     }
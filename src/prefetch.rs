@@ -0,0 +1,114 @@
+//! Prefetching of child zones by read patterns.
+//!
+//! Reads and binds reaching data delegated to child zones (see `delegate`) are replied to zone
+//! by zone, the client loading each child in turn once the zone before it replied. A `Zone` keeps
+//! count of the child zones its reads went on to, and when another read comes in above children
+//! read often enough lately, asks the `Manager` to load them all at once (see `Manager::preload`),
+//! so they're loaded, or on their way, by the time the client gets to them.
+//!
+//! Counts only last `WINDOW_MS`, so zones stop prefetching children no longer read, and each
+//! child is prefetched at most once per window.
+
+use std::collections::BTreeMap;
+
+use intern::Key;
+use path::Path;
+
+/// Milliseconds reads of a child zone count for
+pub const WINDOW_MS: usize = 60 * 1000;
+
+/// Reads of a child zone within the window before it is prefetched
+pub const MIN_READS: u32 = 2;
+
+/// Most child zones prefetched for a read, most read first
+pub const MAX_PREFETCH: usize = 16;
+
+/// Recent reads of a zone's child zones, by path relative to the zone.
+#[derive(Clone, Debug, Default)]
+pub struct Prefetch {
+    reads: BTreeMap<Path, Reads>
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Reads {
+    count: u32,
+    since: usize,          // Milliseconds on a monotonic clock the count started at
+    fetched: Option<usize> // Last prefetched at
+}
+
+impl Prefetch {
+    pub fn is_empty(&self) -> bool {
+        self.reads.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.reads.clear();
+    }
+
+    /// Counts reads of the child zones at `paths`, at `now`.
+    pub fn record<'a, I>(&mut self, paths: I, now: usize) where I: IntoIterator<Item=&'a Path> {
+        for path in paths {
+            let reads = self.reads.entry(path.clone()).or_insert(Reads { count: 0, since: now, fetched: None });
+
+            if now.saturating_sub(reads.since) > WINDOW_MS {
+                *reads = Reads { count: 0, since: now, fetched: reads.fetched };
+            }
+
+            reads.count += 1;
+        }
+    }
+
+    /// Child zones worth loading for a read of `path` at `now`: those below it, up to its first
+    /// wildcard, read at least `MIN_READS` times within the window and not prefetched since.
+    pub fn likely(&mut self, path: &Path, now: usize) -> Vec<Path> {
+        self.reads.retain(|_, reads| now.saturating_sub(reads.since) <= WINDOW_MS);
+
+        let prefix: Vec<&Key> = path.path.iter().take_while(|key| ! key.starts_with('*')).collect();
+
+        let mut likely: Vec<(&Path, &mut Reads)> = self.reads.iter_mut()
+            .filter(|&(child, ref reads)| {
+                reads.count >= MIN_READS &&
+                reads.fetched.map_or(true, |fetched| now.saturating_sub(fetched) > WINDOW_MS) &&
+                child.len() > prefix.len() &&
+                child.path.iter().zip(&prefix).all(|(k, p)| k == *p)
+            })
+            .collect();
+
+        likely.sort_by(|a, b| b.1.count.cmp(&a.1.count));
+        likely.truncate(MAX_PREFETCH);
+
+        likely.into_iter().map(|(child, reads)| {
+            reads.fetched = Some(now);
+            child.clone()
+        }).collect()
+    }
+}
+
+#[test]
+fn test_likely() {
+    let path = |path: &str| Path::new(path.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+    let users = path("users");
+    let alice = path("users.alice");
+    let bob = path("users.bob");
+    let now = 1000;
+
+    let mut prefetch = Prefetch::default();
+
+    prefetch.record(vec![&alice, &bob], now);
+    assert!(prefetch.likely(&users, now).is_empty());
+
+    // Read often enough, most read first
+    prefetch.record(vec![&bob], now + 1);
+    prefetch.record(vec![&alice, &bob], now + 2);
+    assert!(prefetch.likely(&path("posts"), now + 3).is_empty());
+    assert!(prefetch.likely(&alice, now + 3).is_empty());
+    assert_eq!(prefetch.likely(&path("users.*"), now + 3), vec![bob.clone(), alice.clone()]);
+
+    // Once per window
+    assert!(prefetch.likely(&users, now + 4).is_empty());
+
+    // Counts expire
+    assert!(prefetch.likely(&Path::empty(), now + WINDOW_MS * 2).is_empty());
+    assert!(prefetch.is_empty());
+}
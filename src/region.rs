@@ -0,0 +1,130 @@
+//! Dirty regions of a zone, the sub-paths changed since it was last saved.
+//!
+//! Zones large enough to save deltas (see `store::delta`) don't keep every diff merged into them
+//! until their next save. They mark the paths the diffs changed instead, and save their current
+//! data at those paths as a single diff. Paths changed over and over are saved once, and changes
+//! below a path already marked add nothing, so deltas only grow with the parts of the zone that
+//! changed rather than with the number of writes.
+
+use std::collections::BTreeSet;
+
+use node::{Node, NodeTree};
+use path::Path;
+
+/// Sub-paths of a zone changed since it was last saved, none below another.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Regions {
+    paths: BTreeSet<Path>
+}
+
+impl Regions {
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.paths.clear();
+    }
+
+    /// Marks the paths `diff`, a diff merged into the zone, changed. See `Node::changes`.
+    pub fn mark_changes(&mut self, diff: &Node) {
+        for path in diff.changes() {
+            self.mark(path);
+        }
+    }
+
+    /// Marks `path` changed, along with everything below it.
+    pub fn mark(&mut self, path: Path) {
+        let mut above = path.clone();
+
+        loop {
+            if self.paths.contains(&above) {
+                return; // already marked
+            }
+
+            if above.pop().is_none() {
+                break;
+            }
+        }
+
+        // Paths sort right after those above them
+        let below: Vec<Path> = self.paths.range(Path::range_under(&path)).cloned().collect();
+
+        for marked in below {
+            self.paths.remove(&marked);
+        }
+
+        self.paths.insert(path);
+    }
+
+    /// Returns the current data of `tree` at the marked paths, as a diff to merge over the data
+    /// last saved.
+    pub fn extract(&self, tree: &NodeTree) -> NodeTree {
+        let mut diff = NodeTree { node: Default::default(), vis: tree.vis };
+
+        for path in &self.paths {
+            // Paths without data were pruned, as a deletion above hid them
+            if let Some(node) = tree.node.at(path) {
+                diff.node.put(&path.path, node.clone());
+            }
+        }
+
+        diff
+    }
+}
+
+#[test]
+fn test_mark() {
+    let mut regions = Regions::default();
+
+    regions.mark(path![moo.cow]);
+    regions.mark(path![moo.cow.pig]);
+    regions.mark(path![moo.hen]);
+    regions.mark(path![moose]);
+
+    assert_eq!(regions.len(), 3);
+
+    // Covers those below
+    regions.mark(path![moo]);
+
+    assert_eq!(regions.paths.iter().cloned().collect::<Vec<_>>(), vec![path![moo], path![moose]]);
+
+    regions.clear();
+    assert!(regions.is_empty());
+}
+
+#[test]
+fn test_extract() {
+    use node::Vis;
+
+    let moo = Node::expand_from(&path![moo].path, 1.into(), 1000);
+    let cow = Node::expand_from(&path![cow.pig].path, 2.into(), 1000);
+
+    let mut tree = NodeTree { node: Default::default(), vis: Vis::permanent() };
+    let mut regions = Regions::default();
+
+    for diff in vec![moo, cow.clone()] {
+        let mut diff = diff.noop_vis();
+
+        tree.merge(&mut diff);
+        regions.mark_changes(&diff.node);
+    }
+
+    assert_eq!(regions.paths.iter().cloned().collect::<Vec<_>>(), vec![path![cow.pig], path![moo]]);
+
+    // Replayed over data saved before the changes, it brings it up to date
+    let mut saved = NodeTree { node: Default::default(), vis: Vis::permanent() };
+
+    saved.merge(&mut regions.extract(&tree));
+    assert_eq!(saved, tree);
+
+    // Or over data already up to date
+    let mut diff = regions.extract(&tree);
+
+    assert_eq!(tree.merge(&mut diff).0, None);
+    assert_eq!(regions.extract(&tree).node.at(&path![cow.pig]), cow.at(&path![cow.pig]));
+}
@@ -36,6 +36,37 @@ impl Replica {
 
         addr
     }
+
+    /// Where API clients connect over WebSocket, see `websocket`.
+    pub fn websocket_addr(&self) -> SocketAddr {
+        let mut addr = self.addr.clone();
+        let port = addr.port() + 300;
+
+        addr.set_port(port);
+
+        addr
+    }
+
+    /// Where the HTTP gateway listens, see `http`.
+    pub fn http_addr(&self) -> SocketAddr {
+        let mut addr = self.addr.clone();
+        let port = addr.port() + 400;
+
+        addr.set_port(port);
+
+        addr
+    }
+
+    /// Where gRPC clients connect, see `grpc`.
+    #[cfg(feature = "grpc")]
+    pub fn grpc_addr(&self) -> SocketAddr {
+        let mut addr = self.addr.clone();
+        let port = addr.port() + 500;
+
+        addr.set_port(port);
+
+        addr
+    }
 }
 
 impl fmt::Display for Replica {
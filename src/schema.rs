@@ -0,0 +1,258 @@
+//! Validation of client writes.
+//!
+//! With `ZONE_SCHEMA`, the file it names holds rules for the values written at paths, as a JSON
+//! object of dotted paths, where `*` matches any key and `**` any number of them (see `Path::matches`),
+//! to rules:
+//!
+//! ```json
+//! {
+//!     "users.*": { "type": "object", "required": ["name"], "max_bytes": 4096 },
+//!     "users.*.name": { "type": "string" }
+//! }
+//! ```
+//!
+//! `type` is one of `null`, `bool`, `number`, `string` or `object`, which arrays are stored as.
+//! `required` lists keys an object written at the path needs, and `max_bytes` caps the estimated size
+//! of a value written there (see `Node::total_byte_size`). Writes, `cas` and `txn` calls, copies and
+//! moves are checked against the rules of their path, or destination, and of every path within the
+//! value written, before anything is merged; a rejected call changes nothing and replies with what was wrong. Only what is written is
+//! checked, so a write of one field of an object doesn't need the object's required keys. Kills and
+//! merges from replicas aren't checked.
+
+use std::fs::File;
+use std::io::Read;
+
+use serde_json;
+use serde_json::Value;
+
+use matcher::{Glob, PathMatcher};
+use path::Path;
+
+/// Rules for values written, by path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Schema {
+    rules: Vec<(Glob, Rule)>
+}
+
+/// Constraints on values written at a path.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Rule {
+    pub kind: Option<Kind>,    // Type values have to be, any if none
+    pub required: Vec<String>, // Keys objects need
+    pub max_bytes: usize       // Largest estimated size, 0 for no limit
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Null,
+    Bool,
+    Number,
+    String,
+    Object
+}
+
+/// A rejected write.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invalid {
+    pub path: Path,    // Where in the value written the rule failed
+    pub error: String
+}
+
+impl Schema {
+    /// Reads the rules in `file`.
+    pub fn load(file: &str) -> Result<Schema, String> {
+        let mut json = String::new();
+
+        try!(File::open(file).and_then(|mut file| file.read_to_string(&mut json)).map_err(|err| err.to_string()));
+
+        Schema::from_json(&json)
+    }
+
+    pub fn from_json(json: &str) -> Result<Schema, String> {
+        let data: Value = try!(serde_json::from_str(json).or(Err("Bad JSON")));
+        let data = try!(data.as_object().ok_or("Not object"));
+        let mut rules = vec![];
+
+        for (path, rule) in data.iter() {
+            let path = Path::new(path.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+            rules.push((Glob(path), try!(Rule::from_json(rule))));
+        }
+
+        Ok(Schema { rules: rules })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Checks `value`, to be written at `path`, against the rules for it and for what it holds.
+    pub fn check(&self, path: &Path, value: &Value) -> Result<(), Invalid> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+
+        let mut path = path.clone();
+
+        self.check_at(&mut path, value)
+    }
+
+    fn check_at(&self, path: &mut Path, value: &Value) -> Result<(), Invalid> {
+        for &(ref pattern, ref rule) in &self.rules {
+            if pattern.matches(path) {
+                if let Err(error) = rule.check(value) {
+                    return Err(Invalid { path: path.clone(), error: error });
+                }
+            }
+        }
+
+        // Arrays are stored as objects keyed by index
+        let children: Vec<(String, &Value)> = match *value {
+            Value::Object(ref keys) => keys.iter().map(|(k, child)| (k.clone(), child)).collect(),
+            Value::Array(ref items) => items.iter().enumerate().map(|(k, child)| (k.to_string(), child)).collect(),
+            _ => return Ok(())
+        };
+
+        for (k, child) in children {
+            path.push(&k);
+
+            let checked = self.check_at(path, child);
+
+            path.pop();
+            try!(checked);
+        }
+
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn from_json(json: &Value) -> Result<Rule, String> {
+        let mut rule = Rule::default();
+
+        rule.kind = match json.get("type").map(|kind| kind.as_str()) {
+            None => None,
+            Some(Some("null")) => Some(Kind::Null),
+            Some(Some("bool")) => Some(Kind::Bool),
+            Some(Some("number")) => Some(Kind::Number),
+            Some(Some("string")) => Some(Kind::String),
+            Some(Some("object")) => Some(Kind::Object),
+            Some(_) => return Err(format!("Bad type: {}", json))
+        };
+
+        if let Some(required) = json.get("required") {
+            let keys = try!(required.as_array().ok_or(format!("Bad required: {}", json)));
+
+            for key in keys {
+                rule.required.push(try!(key.as_str().ok_or(format!("Bad required: {}", json))).to_string());
+            }
+        }
+
+        if let Some(max_bytes) = json.get("max_bytes") {
+            rule.max_bytes = try!(max_bytes.as_u64().ok_or(format!("Bad max_bytes: {}", json))) as usize;
+        }
+
+        Ok(rule)
+    }
+
+    fn check(&self, value: &Value) -> Result<(), String> {
+        if let Some(kind) = self.kind {
+            let ok = match kind {
+                Kind::Null => value.is_null(),
+                Kind::Bool => value.is_boolean(),
+                Kind::Number => value.is_number(),
+                Kind::String => value.is_string(),
+                Kind::Object => value.is_object() || value.is_array()
+            };
+
+            if ! ok {
+                return Err(format!("Expected {}", kind.name()));
+            }
+        }
+
+        for key in &self.required {
+            if value.get(key).is_none() {
+                return Err(format!("Missing {}", key));
+            }
+        }
+
+        if self.max_bytes > 0 && byte_size(value) > self.max_bytes {
+            return Err(format!("Larger than {} bytes", self.max_bytes));
+        }
+
+        Ok(())
+    }
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Null => "null",
+            Kind::Bool => "bool",
+            Kind::Number => "number",
+            Kind::String => "string",
+            Kind::Object => "object"
+        }
+    }
+}
+
+impl Invalid {
+    pub fn to_json(&self) -> Value {
+        let mut json = serde_json::Map::new();
+
+        json.insert("path".to_string(), self.path.to_json());
+        json.insert("error".to_string(), Value::String(self.error.clone()));
+
+        Value::Object(json)
+    }
+}
+
+/// Estimated size of `value` once stored, as `Node::total_byte_size` estimates it.
+fn byte_size(value: &Value) -> usize {
+    match *value {
+        Value::Bool(_) | Value::Null => 1,
+        Value::Number(_) => 8,
+        Value::String(ref s) => s.len(),
+        Value::Array(ref items) => 1 + items.iter().enumerate().map(|(k, child)| k.to_string().len() + byte_size(child)).sum::<usize>(),
+        Value::Object(ref keys) => 1 + keys.iter().map(|(k, child)| k.len() + byte_size(child)).sum::<usize>()
+    }
+}
+
+#[test]
+fn test_check() {
+    let schema = Schema::from_json(r#"{
+        "users.*": { "type": "object", "required": ["name"], "max_bytes": 32 },
+        "users.*.name": { "type": "string" }
+    }"#).unwrap();
+
+    let users = Path::new(vec!["users".to_string()]);
+    let user = Path::new(vec!["users".to_string(), "1".to_string()]);
+    let json = |json: &str| -> Value { serde_json::from_str(json).unwrap() };
+
+    assert_eq!(schema.len(), 2);
+    assert_eq!(schema.check(&user, &json(r#"{ "name": "moo" }"#)), Ok(()));
+    assert_eq!(schema.check(&users, &json(r#"{ "1": { "name": "moo" } }"#)), Ok(()));
+
+    // Writes of one field only need that to be right
+    assert_eq!(schema.check(&Path::new(vec!["users".to_string(), "1".to_string(), "age".to_string()]), &json("42")), Ok(()));
+
+    assert_eq!(schema.check(&user, &json("42")).unwrap_err().error, "Expected object");
+    assert_eq!(schema.check(&user, &json(r#"{ "age": 42 }"#)).unwrap_err().error, "Missing name");
+    assert_eq!(schema.check(&users, &json(r#"{ "1": { "name": 42 } }"#)), Err(Invalid {
+        path: Path::new(vec!["users".to_string(), "1".to_string(), "name".to_string()]),
+        error: "Expected string".to_string()
+    }));
+    assert_eq!(schema.check(&user, &json(r#"{ "name": "moo moo moo moo moo moo moo moo" }"#)).unwrap_err().error, "Larger than 32 bytes");
+
+    // Rules for any depth
+    let logs = Schema::from_json(r#"{ "logs.**": { "type": "string" } }"#).unwrap();
+
+    assert_eq!(logs.check(&Path::new(vec!["logs".to_string()]), &json(r#"{ "moo": { "cow": "moo" } }"#)).unwrap_err().error, "Expected string");
+    assert_eq!(logs.check(&Path::new(vec!["logs".to_string(), "moo".to_string(), "cow".to_string()]), &json(r#""moo""#)), Ok(()));
+
+    // No rules
+    assert_eq!(Schema::default().check(&user, &json("42")), Ok(()));
+
+    assert!(Schema::from_json(r#"{ "moo": { "type": "moo" } }"#).is_err());
+    assert!(Schema::from_json(r#"[]"#).is_err());
+}
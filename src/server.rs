@@ -6,35 +6,75 @@ use mioco;
 
 use app::{App, AppHandle};
 use client::Client;
+use http;
+use tls;
 
 pub struct Server {
     addr: SocketAddr,
-    app: AppHandle
+    app: AppHandle,
+    protocol: Protocol,
+    tls: Option<tls::Config>
+}
+
+/// What clients connecting speak.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Protocol {
+    Native,    // Commands a line each
+    WebSocket, // Commands a message each, see `websocket`
+    Http       // Reads and writes, see `http`
 }
 
 impl Server {
     pub fn new(app: &App, addr: SocketAddr) -> Server {
         Server {
             addr: addr,
-            app: app.handle()
+            app: app.handle(),
+            protocol: Protocol::Native,
+            tls: None
+        }
+    }
+
+    /// Server for clients connecting over WebSocket, dispatching their commands the same.
+    pub fn websocket(app: &App, addr: SocketAddr) -> Server {
+        Server {
+            protocol: Protocol::WebSocket,
+            ..Server::new(app, addr)
+        }
+    }
+
+    /// HTTP gateway, reading and writing without the native protocol.
+    pub fn http(app: &App, addr: SocketAddr) -> Server {
+        Server {
+            protocol: Protocol::Http,
+            ..Server::new(app, addr)
+        }
+    }
+
+    /// Clients connect over TLS with `tls`, see `tls`.
+    pub fn with_tls(self, tls: Option<tls::Config>) -> Server {
+        Server {
+            tls: tls,
+            ..self
         }
     }
 
     pub fn listen(&self) {
         let addr = self.addr.clone();
         let app = self.app.clone();
+        let protocol = self.protocol;
+        let tls = self.tls.clone();
 
         thread::spawn(move|| {
             mioco::start(move|| {
                 let listener = TcpListener::bind(&addr).unwrap();
 
-                accept_loop(app, listener);
+                accept_loop(app, listener, protocol, tls);
             }).unwrap();
         });
     }
 }
 
-fn accept_loop(app: AppHandle, listener: TcpListener) {
+fn accept_loop(app: AppHandle, listener: TcpListener, protocol: Protocol, tls: Option<tls::Config>) {
     loop {
         let stream = listener.accept();
 
@@ -42,7 +82,20 @@ fn accept_loop(app: AppHandle, listener: TcpListener) {
             Ok(stream) => {
                 // connection succeeded
                 println!("Connection from: {}", stream.peer_addr().unwrap());
-                Client::new(app.clone(), stream);
+
+                let stream = match tls::Stream::accept(stream, tls.as_ref()) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        println!("TLS error: {}", e);
+                        continue;
+                    }
+                };
+
+                match protocol {
+                    Protocol::Native => Client::new(app.clone(), stream),
+                    Protocol::WebSocket => Client::websocket(app.clone(), stream),
+                    Protocol::Http => http::serve(app.clone(), stream)
+                }
             },
             Err(e) => {
                 // connection failed
@@ -2,7 +2,9 @@ use std::io::prelude::*;
 use std::process;
 
 use app::{App, AppHandle};
+use intern;
 use path::Path;
+use zone::AccessStats;
 
 struct Shell<W> {
     app: AppHandle,
@@ -33,10 +35,26 @@ impl<W: Write> Shell<W> {
                     Some("active") => self.active(),
                     Some("cluster.sync") => self.sync(),
                     Some("cluster.sync_all") => self.sync_all(),
+                    Some("path.interned") => self.path_interned(),
+                    Some("store.compact") => self.store_compact(line.next().unwrap_or_default()),
+                    Some("store.compact_all") => self.store_compact_all(),
                     Some("store.dump") => self.store_dump(line.next().unwrap_or_default()),
+                    Some("store.export") => self.store_export(line.next().unwrap_or_default()),
+                    Some("store.gc") => self.store_gc(line.next().unwrap_or_default()),
+                    Some("store.stat") => self.store_stat(line.next().unwrap_or_default()),
+                    Some("store.stats") => self.store_stats(),
+                    Some("store.verify") => self.store_verify(line.next().unwrap_or_default()),
                     Some("stats") => self.stats(),
+                    Some("zone.du") => self.zone_du(line.next().unwrap_or_default()),
                     Some("zone.dump") => self.zone_dump(line.next().unwrap_or_default()),
+                    Some("zone.fold") => self.zone_fold(line.next().unwrap_or_default()),
+                    Some("zone.export") => self.zone_export(line.next().unwrap_or_default()),
+                    Some("zone.freeze") => self.zone_freeze(line.next().unwrap_or_default()),
+                    Some("zone.frozen") => self.zone_frozen(),
+                    Some("zone.import") => self.zone_import(line.next().unwrap_or_default()),
+                    Some("zone.stats") => self.zone_stats(line.next().unwrap_or_default()),
                     Some("zone.sync") => self.zone_sync(line.next().unwrap_or_default()),
+                    Some("zone.unfreeze") => self.zone_unfreeze(line.next().unwrap_or_default()),
                     Some("exit") | Some("quit") | Some("shutdown") => self.shutdown(),
                     Some("") => (),
                     _ => writeln!(self.writer, "Bad command").unwrap()
@@ -55,7 +73,7 @@ impl<W: Write> Shell<W> {
         writeln!(self.writer, "Active Zones:").unwrap();
 
         for z in active_zones {
-            let path = z.path().path.join(".");
+            let path = z.path().to_string();
             let size = z.size();
             let state = z.state();
 
@@ -68,7 +86,12 @@ impl<W: Write> Shell<W> {
     fn shutdown(&mut self) {
         writeln!(self.writer, "Shutting down...").unwrap();
 
-        // TODO: exit is not clean, destructors not called, files/sockets not flushed
+        // Writes queued in the Store are on disk before exiting
+        if let Err(err) = self.app.store.shutdown() {
+            writeln!(self.writer, "Error shutting down store: {}", err).unwrap();
+        }
+
+        // TODO: exit is not clean, destructors not called, sockets not flushed
         process::exit(0);
     }
 
@@ -78,6 +101,13 @@ impl<W: Write> Shell<W> {
         writeln!(self.writer, "{}", serde_json::to_string_pretty(&*self.app.stats).unwrap()).unwrap();
     }
 
+    fn path_interned(&mut self) {
+        let stats = intern::stats();
+
+        writeln!(self.writer, "{} keys interned, {} bytes, held {} times, saving {} bytes",
+                 stats.keys, stats.bytes, stats.refs, stats.saved).unwrap();
+    }
+
     fn sync(&mut self) {
         writeln!(self.writer, "Synchronizing local data with cluster...").unwrap();
         self.app.cluster.sync();
@@ -88,22 +118,148 @@ impl<W: Write> Shell<W> {
         self.app.cluster.sync_all();
     }
 
+    fn store_compact(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        writeln!(self.writer, "Compacting zone {:#?}...", &path).unwrap();
+
+        if let Err(err) = self.app.store.compact(&path) {
+            writeln!(self.writer, "Compaction failed: {}", err).unwrap();
+        }
+    }
+
+    fn store_compact_all(&mut self) {
+        writeln!(self.writer, "Compacting all zones...").unwrap();
+
+        if let Err(err) = self.app.store.compact_all() {
+            writeln!(self.writer, "Compaction failed: {}", err).unwrap();
+        }
+    }
+
     fn store_dump(&mut self, path: &str) {
-        let path = match path {
-            "" => Path::new(vec![]),
-            _ => Path::new(path.split('.').map(|s| s.into()).collect())
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
         };
 
         match self.app.store.load_data(path.clone()) {
-            None => writeln!(self.writer, "Could not load {:?}", path),
-            Some(data) => writeln!(self.writer, "Store data: {:?}", data)
+            Err(err) => writeln!(self.writer, "Could not load {:?}: {}", path, err),
+            Ok(None) => writeln!(self.writer, "Could not load {:?}", path),
+            Ok(Some(data)) => writeln!(self.writer, "Store data: {:?}", data)
         }.unwrap();
     }
 
+    fn store_export(&mut self, args: &str) {
+        use std::fs::File;
+        use std::io::BufWriter;
+
+        let mut args = args.splitn(2, ' ');
+
+        let filename = match args.next() {
+            None | Some("") => return writeln!(self.writer, "Usage: store.export <file> [path]").unwrap(),
+            Some(filename) => filename
+        };
+
+        let path: Path = match args.next().unwrap_or_default().parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        let file = match File::create(filename) {
+            Err(err) => return writeln!(self.writer, "Could not create {}: {}", filename, err).unwrap(),
+            Ok(file) => file
+        };
+
+        writeln!(self.writer, "Exporting zones under {:?} to {}...", path, filename).unwrap();
+
+        match self.app.store.export(&path, BufWriter::new(file)) {
+            Err(err) => writeln!(self.writer, "Export failed: {}", err),
+            Ok(exported) => writeln!(self.writer, "Exported {} zones", exported)
+        }.unwrap();
+    }
+
+    fn store_gc(&mut self, mode: &str) {
+        use store::gc;
+
+        let mode = match mode {
+            "" => gc::GcMode::default(),
+            _ => match mode.parse() {
+                Err(err) => return writeln!(self.writer, "{}", err).unwrap(),
+                Ok(mode) => mode
+            }
+        };
+
+        writeln!(self.writer, "Collecting orphaned zones ({:?})...", mode).unwrap();
+
+        match gc::collect(&self.app, mode) {
+            Err(err) => writeln!(self.writer, "GC failed: {}", err),
+            Ok(collected) => writeln!(self.writer, "Collected {} zones", collected)
+        }.unwrap();
+    }
+
+    fn store_stat(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        match self.app.store.stat(&path) {
+            Err(err) => writeln!(self.writer, "Could not stat {:?}: {}", path, err),
+            Ok(None) => writeln!(self.writer, "Nothing stored for {:?}", path),
+            Ok(Some(stat)) => writeln!(self.writer, "Stored: {} bytes, modified {:?}", stat.size, stat.modified)
+        }.unwrap();
+    }
+
+    fn store_stats(&mut self) {
+        use serde_json;
+
+        match self.app.store.stats() {
+            Err(err) => writeln!(self.writer, "Could not get stats: {}", err),
+            Ok(stats) => writeln!(self.writer, "{}", serde_json::to_string_pretty(&stats).unwrap())
+        }.unwrap();
+    }
+
+    fn store_verify(&mut self, path: &str) {
+        use store::verify;
+
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        writeln!(self.writer, "Verifying zones under {:?}...", path).unwrap();
+
+        match verify::verify(&self.app.store, &path, false) {
+            Err(err) => writeln!(self.writer, "Verification failed: {}", err),
+            Ok(report) => writeln!(self.writer, "{}", report)
+        }.unwrap();
+    }
+
+    /// Prints the estimated size of a zone and of its heaviest children, see `delegate::Sizes`.
+    fn zone_du(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        let (total, heaviest) = self.app.manager.load(&path).heaviest(20);
+
+        writeln!(self.writer, "{:>10} {:>8} key", "bytes", "nodes").unwrap();
+
+        for (key, size) in heaviest {
+            writeln!(self.writer, "{:>10} {:>8} {:?}", size.bytes, size.entries, key).unwrap();
+        }
+
+        writeln!(self.writer, "Total: {} bytes in {} nodes", total.bytes, total.entries).unwrap();
+    }
+
     fn zone_dump(&mut self, path: &str) {
-        let path = match path {
-            "" => Path::new(vec![]),
-            _ => Path::new(path.split('.').map(|s| s.into()).collect())
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
         };
 
         let zone = self.app.manager.load(&path);
@@ -113,10 +269,137 @@ impl<W: Write> Shell<W> {
         writeln!(self.writer, "Zone data: {:#?}", data).unwrap();
     }
 
+    fn zone_fold(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        if path.len() == 0 {
+            writeln!(self.writer, "The root zone has no parent to fold into").unwrap();
+            return;
+        }
+
+        writeln!(self.writer, "Folding zone {:#?} into its parent...", &path).unwrap();
+        self.app.manager.load(&path).fold();
+    }
+
+    /// Rejects writes to `path` and below, see `freeze`.
+    fn zone_freeze(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        if self.app.frozen.freeze(path.clone()) {
+            writeln!(self.writer, "Froze {:?}", &path).unwrap();
+        }
+        else {
+            writeln!(self.writer, "Already frozen: {:?}", &path).unwrap();
+        }
+    }
+
+    fn zone_frozen(&mut self) {
+        for path in self.app.frozen.patterns() {
+            writeln!(self.writer, "{:?}", path).unwrap();
+        }
+    }
+
+    fn zone_unfreeze(&mut self, path: &str) {
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        if self.app.frozen.unfreeze(&path) {
+            writeln!(self.writer, "Unfroze {:?}", &path).unwrap();
+        }
+        else {
+            writeln!(self.writer, "Not frozen: {:?}", &path).unwrap();
+        }
+    }
+
+    fn zone_export(&mut self, path: &str) {
+        use serde_json;
+
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        let json = self.app.manager.load(&path).export_json();
+
+        writeln!(self.writer, "{}", serde_json::to_string_pretty(&json).unwrap()).unwrap();
+    }
+
+    fn zone_import(&mut self, args: &str) {
+        use std::fs::File;
+        use serde_json;
+        use serde_json::Value;
+
+        let mut args = args.splitn(2, ' ');
+
+        let filename = match args.next() {
+            None | Some("") => return writeln!(self.writer, "Usage: zone.import <file> [path]").unwrap(),
+            Some(filename) => filename
+        };
+
+        let path: Path = match args.next().unwrap_or_default().parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
+        };
+
+        let json: Value = match File::open(filename).map_err(|err| err.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|err| err.to_string())) {
+            Err(err) => return writeln!(self.writer, "Could not read {}: {}", filename, err).unwrap(),
+            Ok(json) => json
+        };
+
+        writeln!(self.writer, "Importing {} into zone {:?}...", filename, path).unwrap();
+        self.app.manager.load(&path).import_json(json);
+    }
+
+    fn zone_stats(&mut self, count: &str) {
+        let count = match count {
+            "" => 20,
+            _ => match count.parse() {
+                Ok(count) => count,
+                Err(_) => {
+                    writeln!(self.writer, "Bad zone count: {}", count).unwrap();
+                    return;
+                }
+            }
+        };
+
+        let stats = self.app.manager.zone_stats();
+        let mut total = AccessStats::default();
+
+        writeln!(self.writer, "{:>8} {:>8} {:>8} {:>8} {:>9} {:>8} zone", "reads", "binds", "writes", "merges", "listeners", "bytes").unwrap();
+
+        let mut recovered = 0;
+
+        for (i, &(ref path, ref zone)) in stats.iter().enumerate() {
+            if i < count {
+                writeln!(self.writer, "{:>8} {:>8} {:>8} {:>8} {:>9} {:>8} {:?}{}",
+                         zone.reads, zone.binds, zone.writes, zone.merges, zone.listeners, zone.bytes, path.to_string(),
+                         if zone.recovered { " (recovered)" } else { "" }).unwrap();
+            }
+
+            if zone.recovered {
+                recovered += 1;
+            }
+
+            total.add(zone);
+        }
+
+        writeln!(self.writer, "{:>8} {:>8} {:>8} {:>8} {:>9} {:>8} total of {} active zones, {} recovered",
+                 total.reads, total.binds, total.writes, total.merges, total.listeners, total.bytes, stats.len(), recovered).unwrap();
+    }
+
     fn zone_sync(&mut self, path: &str) {
-        let path = match path {
-            "" => Path::new(vec![]),
-            _ => Path::new(path.split('.').map(|s| s.into()).collect())
+        let path: Path = match path.parse() {
+            Ok(path) => path,
+            Err(err) => return writeln!(self.writer, "{}", err).unwrap()
         };
 
         writeln!(self.writer, "Synchronizing zone {:#?}...", &path).unwrap();
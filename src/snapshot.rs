@@ -0,0 +1,117 @@
+//! Named snapshots of zone data, to read from later.
+//!
+//! A `snapshot` command with a name as its params keeps the data of the zones at its path as they
+//! are, under that name, in place of any snapshot of the same name; `{ "drop": name }` drops it.
+//! Reads with `{ "snapshot": name }` as params read from it, so several reads see the same data
+//! while writes go on, or data can be looked at as it was before something went wrong. Like reads,
+//! recursive wildcards reach the zones data is delegated to, each of which keeps its own snapshot;
+//! those are taken one zone after another, not at once.
+//!
+//! Node keys are shared between copies until one of them changes (see `node`), so a snapshot only
+//! costs memory for the parts of a zone written to since. Snapshots are kept in memory while the
+//! zone is loaded, and only on the node that got the command.
+
+use std::collections::VecDeque;
+
+use node::{DelegatedMatch, NodeTree, Update};
+use path::Path;
+
+/// Most snapshots kept in a zone, taking more drops the oldest
+pub const MAX_SNAPSHOTS: usize = 16;
+
+/// Snapshots of a zone's data, oldest first.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshots {
+    taken: VecDeque<(String, NodeTree)>
+}
+
+impl Snapshots {
+    pub fn is_empty(&self) -> bool {
+        self.taken.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.taken.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.taken.clear();
+    }
+
+    /// Keeps `tree` as it is now as snapshot `name`.
+    pub fn take(&mut self, name: &str, tree: &NodeTree) {
+        self.remove(name);
+
+        if self.taken.len() >= MAX_SNAPSHOTS {
+            self.taken.pop_front();
+        }
+
+        self.taken.push_back((name.to_string(), tree.clone()));
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.taken.iter().any(|&(ref taken, _)| taken == name)
+    }
+
+    /// Drops snapshot `name`. Returns false if there was none.
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.taken.iter().position(|&(ref taken, _)| taken == name) {
+            Some(i) => self.taken.remove(i).is_some(),
+            None => false
+        }
+    }
+
+    /// Reads `path` from snapshot `name`, as `NodeTree::read` does. Reads nothing if there is no
+    /// such snapshot.
+    pub fn read(&self, name: &str, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
+        match self.taken.iter().find(|&&(ref taken, _)| taken == name) {
+            Some(&(_, ref tree)) => tree.read(path),
+            None => (None, vec![])
+        }
+    }
+}
+
+#[test]
+fn test_take() {
+    use serde_json::{Map, Value};
+
+    use node::{Node, Vis};
+
+    let write = |tree: &mut NodeTree, value: u64, timestamp: u64| {
+        tree.merge(&mut Node::expand_from(&path![moo].path, value.into(), timestamp).noop_vis());
+    };
+
+    let mut tree = NodeTree { node: Node::expand(Value::Object(Map::new()), 1), vis: Vis::permanent() };
+    let mut snapshots = Snapshots::default();
+
+    write(&mut tree, 1, 1000);
+    snapshots.take("one", &tree);
+
+    let before = tree.clone();
+
+    write(&mut tree, 2, 2000);
+
+    // Later writes don't change it
+    assert_eq!(snapshots.read("one", &path![moo]).0, before.read(&path![moo]).0);
+    assert!(snapshots.read("one", &path![moo]).0 != tree.read(&path![moo]).0);
+    assert_eq!(snapshots.read("two", &path![moo]).0, None);
+
+    // Taking one of the same name replaces it
+    snapshots.take("one", &tree);
+    assert_eq!(snapshots.len(), 1);
+    assert_eq!(snapshots.read("one", &path![moo]).0, tree.read(&path![moo]).0);
+
+    for i in 0..MAX_SNAPSHOTS {
+        snapshots.take(&i.to_string(), &tree);
+    }
+
+    // The oldest is dropped
+    assert_eq!(snapshots.len(), MAX_SNAPSHOTS);
+    assert_eq!(snapshots.read("one", &path![moo]).0, None);
+
+    assert!(snapshots.remove("0"));
+    assert!(! snapshots.remove("0"));
+
+    snapshots.clear();
+    assert!(snapshots.is_empty());
+}
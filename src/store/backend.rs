@@ -0,0 +1,300 @@
+//! Pluggable persistence for zone data.
+//!
+//! A `StoreBackend` implements `StoreCall`s one method each, and `serve` runs it as a Store
+//! "process": it owns the backend, receives calls from `StoreHandle`s and dispatches them, loads
+//! ahead of writes (see `store::priority`). Write requests go through a `scheduler::Scheduler`, so
+//! backends only see the ones due for a flush.
+//!
+//! The built-in backends (`fs`, `memory`, `null`, `rocksdb`, `s3`, `sled`) are implementations like
+//! any other. Persistence not built in, e.g. a database qumulus doesn't know about, is plugged in
+//! with `store::spawn_custom`. `read_only::ReadOnly` and `tiered::Tiered` route calls to other
+//! Stores rather than persisting anything, and are not backends.
+//!
+//! Data passed to and from a backend is serialized `ZoneData` (see `store::migrate`); backends pick
+//! how they encode it (see `store::codec`). Replies are sent on the given `Reply`s (see
+//! `store::reply`), so backends are free to do the work on threads of their own. Dropping a reply
+//! fails the call.
+//!
+//! After `StoreCall::Shutdown`, `serve` refuses calls that would change stored data itself, so
+//! backends never see them.
+
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use super::{Config, StoreCall, StoreChannel, StoreError, ZoneStat, QUEUE_SIZE};
+use super::reply::{Callback, Reply};
+use super::cache::Cached;
+use super::migrate;
+use super::priority::CallQueue;
+use super::raw::RawZone;
+use super::scheduler::{FlushPolicy, Scheduler};
+use super::stats::StoreStats;
+use super::verify;
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+/// Persistence for zone data. See `StoreCall` for what each call means.
+pub trait StoreBackend: Send {
+    /// Logs a serialized diff, for backends with a WAL. Others rely on writes alone.
+    fn append(&mut self, _path: Path, _diff: Vec<u8>) {
+    }
+
+    /// Drops tombstones that can no longer affect merges from stored data, if the backend keeps
+    /// them around.
+    fn compact(&mut self, _path: Path) {
+    }
+
+    fn delete(&mut self, zone: ZoneHandle, path: Path);
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>);
+    /// Lists paths of stored zones under `prefix`, and `prefix` itself.
+    fn list(&mut self, prefix: Path, reply: Sender<Path>);
+    fn load(&mut self, zone: ZoneHandle, path: Path);
+    fn load_data(&mut self, path: Path, reply: Reply<Option<ZoneData>>);
+
+    /// Loads data for many zones at once, sorted by path. Backends that store zones near each
+    /// other in some other order read them in that order instead.
+    fn load_many(&mut self, mut zones: Vec<(ZoneHandle, Path)>) {
+        zones.sort_by(|a, b| a.1.cmp(&b.1));
+
+        for (zone, path) in zones {
+            self.load(zone, path);
+        }
+    }
+
+    /// Loads stored data for a zone path without deserializing it, replying with none if it could
+    /// not be read. Backends that only load `ZoneData` serialize it again.
+    fn load_raw(&mut self, path: Path, reply: Reply<Option<RawZone>>) {
+        let callback = Callback::new(move |data: Option<Option<ZoneData>>| {
+            let raw = data.and_then(|data| data)
+                .and_then(|data| migrate::serialize(&data).ok())
+                .map(RawZone::new);
+
+            reply.send(raw).is_ok(); // ignore if caller goes away
+        });
+
+        self.load_data(path, Reply::Callback(callback));
+    }
+
+    /// Moves stored data aside. Backends without anywhere to move it fail the call.
+    fn quarantine(&mut self, _path: Path, _reply: Reply<bool>) {
+    }
+
+    /// Same as `load`, for data changed outside of the Store. Backends that keep copies of data
+    /// read it again.
+    fn reload(&mut self, zone: ZoneHandle, path: Path) {
+        self.load(zone, path);
+    }
+
+    /// Tells a zone to write, once it has been held back long enough by the `Scheduler`.
+    /// Backends with limited write slots can hold it back longer.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        zone.save();
+    }
+
+    /// Restores stored data of one zone, or all of them, to what it was at a point in time.
+    /// Backends without history fail the call.
+    fn restore(&mut self, _path: Option<Path>, _timestamp: u64, _reply: Reply<usize>) {
+    }
+
+    /// Finishes writes handed to the backend so far and flushes them to disk, before the Store
+    /// stops taking writes. Backends that write on the Store thread and flush as they go have
+    /// nothing to do.
+    fn shutdown(&mut self) {
+    }
+
+    /// Looks up stored data without loading it. Backends that can't fail the call.
+    fn stat(&mut self, _path: Path, _reply: Reply<Option<ZoneStat>>) {
+    }
+
+    /// I/O metrics, with `pending_writes` left for `serve` to fill in.
+    fn stats(&mut self) -> StoreStats {
+        Default::default()
+    }
+
+    /// Checks that stored data for a zone path can be loaded. Backends that can tell why it can't
+    /// reply with that, rather than a failed load.
+    fn verify(&mut self, path: Path, reply: Reply<Result<(), StoreError>>) {
+        let loaded = path.clone();
+
+        let callback = Callback::new(move |data: Option<Option<ZoneData>>| {
+            let result = match data {
+                Some(Some(data)) => verify::check(&loaded, &data),
+                Some(None) => Err(StoreError::NotFound),
+                None => Err(StoreError::corrupt("Could not be loaded"))
+            };
+
+            reply.send(result).is_ok(); // ignore if caller goes away
+        });
+
+        self.load_data(path, Reply::Callback(callback));
+    }
+
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>);
+
+    /// Writes data for several zone paths all or nothing, even across a crash. Backends that can't
+    /// fail the call.
+    fn write_atomic(&mut self, _writes: Vec<(Path, Vec<u8>)>, _reply: Reply<bool>) {
+    }
+
+    fn write_batch(&mut self, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        for (zone, path, data) in writes {
+            self.write(zone, path, data);
+        }
+    }
+
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>);
+
+    /// Saves the diffs of a large zone. Backends without a delta log ask for a snapshot instead.
+    fn write_delta(&mut self, zone: ZoneHandle, _path: Path, _delta: Vec<u8>) {
+        zone.snapshot();
+    }
+
+    /// Whether writes waiting in the channel should be handed to `write_batch` together, rather
+    /// than one by one.
+    fn coalesces_writes(&self) -> bool {
+        false
+    }
+}
+
+/// Runs `backend` as a Store "process" in a thread of its own, serving calls on `channel`. Writes
+/// are held back by `config.flush`, and loads served from a cache of `config.cache`.
+pub fn spawn(backend: Box<StoreBackend>, channel: StoreChannel, config: &Config) {
+    let backend = Cached::wrap(backend, config.cache);
+    let flush = config.flush;
+
+    thread::spawn(move|| {
+        serve(backend, channel, flush);
+    });
+}
+
+/// Serves calls on `channel` with `backend`, by priority (see `store::priority`), holding back
+/// write requests according to `flush`.
+pub fn serve(mut backend: Box<StoreBackend>, channel: StoreChannel, flush: FlushPolicy) {
+    let mut scheduler = Scheduler::new(flush);
+    let mut queue = CallQueue::new();
+    let mut shut_down = false;
+
+    loop {
+        if queue.is_empty() {
+            queue.push(scheduler.recv(&channel.rx, |zone| backend.request_write(zone)));
+        }
+        else {
+            for zone in scheduler.take_due() {
+                backend.request_write(zone);
+            }
+        }
+
+        // Everything waiting, so it is served in order of priority. Calls past `QUEUE_SIZE` are
+        // left in the channel, where callers wait for room.
+        while queue.len() < QUEUE_SIZE {
+            match channel.rx.try_recv() {
+                Ok(call) => queue.push(call),
+                Err(_) => break
+            }
+        }
+
+        let mut call = queue.pop().unwrap();
+
+        if shut_down {
+            call = match refuse(call) {
+                None => continue,
+                Some(call) => call
+            };
+        }
+
+        match call {
+            StoreCall::Append(path, diff) => {
+                scheduler.appended(diff.len());
+                backend.append(path, diff)
+            },
+            StoreCall::Compact(path) => backend.compact(path),
+            StoreCall::Delete(zone, path) => backend.delete(zone, path),
+            StoreCall::DeleteData(path, reply) => backend.delete_data(path, reply),
+            StoreCall::List(prefix, reply) => backend.list(prefix, reply),
+            StoreCall::Load(zone, path) => backend.load(zone, path),
+            StoreCall::LoadData(path, reply) => backend.load_data(path, reply),
+            StoreCall::LoadMany(zones) => backend.load_many(zones),
+            StoreCall::LoadRaw(path, reply) => backend.load_raw(path, reply),
+            StoreCall::Quarantine(path, reply) => backend.quarantine(path, reply),
+            StoreCall::Reload(zone, path) => backend.reload(zone, path),
+            StoreCall::RequestWrite(zone, dirty) => scheduler.request_write(zone, dirty),
+            StoreCall::Restore(path, timestamp, reply) => backend.restore(path, timestamp, reply),
+            StoreCall::Shutdown(reply) => {
+                // Calls queued before are served by now, as it is queued behind them
+                backend.shutdown();
+                shut_down = true;
+                reply.send(()).is_ok(); // ignore if caller goes away
+            },
+            StoreCall::Stat(path, reply) => backend.stat(path, reply),
+            StoreCall::Stats(reply) => {
+                let mut stats = backend.stats();
+
+                stats.pending_writes = scheduler.pending();
+                reply.send(stats).is_ok(); // ignore if caller goes away
+            },
+            StoreCall::Verify(path, reply) => backend.verify(path, reply),
+            StoreCall::Write(zone, path, data) => {
+                if backend.coalesces_writes() {
+                    coalesce(&mut *backend, &mut queue, vec![(zone, path, data)]);
+                }
+                else {
+                    backend.write(zone, path, data);
+                }
+            },
+            StoreCall::WriteAtomic(writes, reply) => backend.write_atomic(writes, reply),
+            StoreCall::WriteBatch(writes) => {
+                if backend.coalesces_writes() {
+                    coalesce(&mut *backend, &mut queue, writes);
+                }
+                else {
+                    backend.write_batch(writes);
+                }
+            },
+            StoreCall::WriteData(path, data, reply) => backend.write_data(path, data, reply),
+            StoreCall::WriteDelta(zone, path, delta) => backend.write_delta(zone, path, delta)
+        }
+    }
+}
+
+/// Writes `writes` along with any writes queued up right behind them, in one batch.
+fn coalesce(backend: &mut StoreBackend, queue: &mut CallQueue, mut writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+    while let Some(call) = queue.pop_write() {
+        match call {
+            StoreCall::Write(zone, path, data) => writes.push((zone, path, data)),
+            StoreCall::WriteBatch(batch) => writes.extend(batch),
+            _ => unreachable!()
+        }
+    }
+
+    backend.write_batch(writes);
+}
+
+/// Fails `call` if it would change stored data, the way its caller is told about failed writes.
+/// Hands back calls that can still be served after shutdown.
+fn refuse(call: StoreCall) -> Option<StoreCall> {
+    match call {
+        StoreCall::Append(..) | StoreCall::Compact(..) | StoreCall::RequestWrite(..) => (), // zones stay dirty
+        StoreCall::Delete(zone, _) | StoreCall::Write(zone, _, _) | StoreCall::WriteDelta(zone, _, _) => {
+            zone.write_failed(StoreError::ShutDown);
+        },
+        StoreCall::WriteBatch(writes) => {
+            for (zone, _, _) in writes {
+                zone.write_failed(StoreError::ShutDown);
+            }
+        },
+        StoreCall::DeleteData(_, reply) | StoreCall::Quarantine(_, reply) | StoreCall::WriteAtomic(_, reply) |
+        StoreCall::WriteData(_, _, reply) => {
+            reply.send(false).is_ok(); // ignore if caller goes away
+        },
+        StoreCall::Restore(_, _, reply) => {
+            reply.send(0).is_ok(); // ignore if caller goes away
+        },
+        StoreCall::Shutdown(reply) => {
+            reply.send(()).is_ok(); // shut down already
+        },
+        call => return Some(call)
+    }
+
+    None
+}
+
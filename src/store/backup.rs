@@ -0,0 +1,174 @@
+//! Backups of stored zone data.
+//!
+//! `export` writes the zones a Store has under a path to a single archive, and `import` writes
+//! them back, into any backend. Archives are streamed one zone at a time both ways, and are:
+//!
+//! ```text
+//! "QBAK" <version: u8> <bincode serialized Record>...
+//! ```
+//!
+//! Each zone is a `Record::Zone` holding its serialized data (see `store::migrate`), checksummed
+//! but neither compressed nor encrypted, so archives can be imported with any `Codec`. Data in an
+//! older layout is upgraded on import. A `Record::End` ends the archive, so a truncated archive is
+//! told apart from a complete one.
+//!
+//! Exports go through the Store like any other load, so nodes can be backed up while running.
+//! Each zone is exported as it is stored at the time, logged changes included, but zones are not
+//! exported all at the same instant.
+
+use std::error::Error;
+use std::io::prelude::*;
+
+use bincode;
+
+use super::{StoreError, StoreHandle};
+use super::codec;
+use super::migrate;
+use super::raw::RawZone;
+use path::Path;
+
+const MAGIC: &'static [u8] = b"QBAK";
+
+/// Current version of the archive format.
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Record {
+    /// Checksummed, serialized `ZoneData` of the zone at `Path`
+    Zone(Path, Vec<u8>),
+
+    /// Number of zones in the archive
+    End(u64)
+}
+
+/// Writes all zones stored under `filter` (and `filter` itself) to `writer`. Returns the number
+/// of zones exported.
+pub fn export<W: Write>(store: &StoreHandle, filter: &Path, mut writer: W) -> Result<u64, StoreError> {
+    let mut paths = vec![];
+
+    try!(store.each_zone_under(filter, |path| paths.push(path)));
+
+    paths.sort();
+
+    try!(writer.write_all(MAGIC).and_then(|_| writer.write_all(&[VERSION])).map_err(StoreError::Io));
+
+    let mut exported = 0;
+
+    for path in paths {
+        let raw = match try!(store.load_raw(path.clone())) {
+            None => {
+                error!("Backup: {:?} went away while exporting", path);
+                return Err(StoreError::NotFound);
+            },
+            Some(raw) => raw
+        };
+
+        // Data with nothing to replay over it is exported as is, without deserializing it
+        let serialized = match raw {
+            RawZone { data: Some(data), ref deltas, ref entries } if deltas.is_empty() && entries.is_empty() => data,
+            raw => try!(migrate::serialize(&try!(raw.deserialize())))
+        };
+
+        let blob = codec::checksum(serialized);
+
+        try!(write_record(&mut writer, &Record::Zone(path, blob)));
+        exported += 1;
+    }
+
+    try!(write_record(&mut writer, &Record::End(exported)));
+    try!(writer.flush().map_err(StoreError::Io));
+
+    Ok(exported)
+}
+
+/// Writes all zones in an archive read from `reader` to `store`, replacing whatever it has stored
+/// for them. Returns the number of zones imported. Zones before a corrupt or truncated part of the
+/// archive are imported regardless.
+pub fn import<R: Read>(store: &StoreHandle, mut reader: R) -> Result<u64, StoreError> {
+    let mut header = vec![];
+
+    try!(reader.by_ref().take(MAGIC.len() as u64 + 1).read_to_end(&mut header).map_err(StoreError::Io));
+
+    if ! header.starts_with(MAGIC) {
+        return Err(StoreError::corrupt("Not a backup archive"));
+    }
+
+    if header[MAGIC.len()..] != [VERSION] {
+        return Err(StoreError::corrupt(format!("Unknown backup archive version: {:?}", &header[MAGIC.len()..])));
+    }
+
+    let mut imported = 0;
+
+    loop {
+        let record = try!(bincode::deserialize_from(&mut reader, bincode::Infinite)
+            .map_err(|err| StoreError::corrupt(format!("Bad backup record: {}", err.description()))));
+
+        match record {
+            Record::Zone(path, blob) => {
+                let data = try!(codec::verify(blob).and_then(migrate::deserialize).map_err(|err| err.for_path(&path)));
+
+                if ! try!(store.write_data(path.clone(), &data)) {
+                    return Err(StoreError::Backend(format!("Could not write {:?}", path)));
+                }
+
+                imported += 1;
+            },
+            Record::End(zones) if zones == imported => return Ok(imported),
+            Record::End(zones) => {
+                return Err(StoreError::corrupt(format!("Backup archive has {} zones, read {}", zones, imported)));
+            }
+        }
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &Record) -> Result<(), StoreError> {
+    bincode::serialize_into(writer, record, bincode::Infinite)
+        .map_err(|err| StoreError::SerializationFailed(err.to_string()))
+}
+
+#[test]
+fn test_export_import() {
+    use std::thread;
+
+    use app::App;
+    use super::{Config, StoreChannel};
+    use super::backend;
+    use super::memory::Memory;
+    use zone::ZoneData;
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+
+    let spawn = || {
+        let channel = StoreChannel::new();
+        let handle = channel.handle();
+        let store = Memory::new(app.handle(), &Config::default());
+
+        thread::spawn(move|| {
+            backend::serve(Box::new(store), channel, Default::default());
+        });
+
+        handle
+    };
+
+    let source = spawn();
+
+    for path in vec![path![moo], path![moo.cow], path![oink]] {
+        assert!(source.write_data(path.clone(), &ZoneData::new(path, Default::default())).unwrap());
+    }
+
+    let mut archive = vec![];
+
+    assert_eq!(export(&source, &path![moo], &mut archive).unwrap(), 2);
+
+    let target = spawn();
+
+    assert_eq!(import(&target, &archive[..]).unwrap(), 2);
+    assert_eq!(target.load_data(path![moo.cow]).unwrap(), Some(ZoneData::new(path![moo.cow], Default::default())));
+    assert_eq!(target.stat(&path![oink]).unwrap(), None);
+
+    // Truncated archives fail, after importing what they do have
+    let truncated = &archive[..archive.len() - 1];
+
+    assert!(import(&spawn(), truncated).is_err());
+    assert!(import(&spawn(), &b"moo"[..]).is_err());
+}
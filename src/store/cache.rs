@@ -0,0 +1,383 @@
+//! LRU cache of serialized zone data.
+//!
+//! Zones hibernated by the `EvictionManager` write their data on the way out, and are often loaded
+//! again shortly after. A `Cached` backend keeps the last serialized data written for each zone in
+//! a `Cache`, up to `CacheSize::bytes`, and serves `Load`, `LoadData` and `LoadRaw` from it without
+//! touching the backend. Zones least recently written or loaded are dropped first. Zones are sent
+//! their cached data still serialized, and deserialize it themselves (see `store::raw`), so the
+//! Store thread isn't busy deserializing when many zones come back at once.
+//!
+//! Cached data is what the zone last wrote, not what the backend stored, so anything else changing
+//! stored data drops it: logged diffs, deltas, deletes, quarantines, compactions, restores and
+//! `WriteData`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+
+use super::{StoreBackend, StoreError, ZoneStat};
+use super::migrate;
+use super::raw::RawZone;
+use super::reply::Reply;
+use super::stats::StoreStats;
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+/// Bytes of serialized zone data a Store keeps in memory.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheSize {
+    pub bytes: usize // 0 for no cache
+}
+
+/// Serialized zone data, by path.
+pub struct Cache {
+    capacity: usize,
+    used: usize,
+    blobs: HashMap<Path, (u64, Vec<u8>)>, // Last use and data
+    uses: BTreeMap<u64, Path>,            // Least recently used first
+    tick: u64
+}
+
+/// A backend with a `Cache` in front of it.
+pub struct Cached {
+    backend: Box<StoreBackend>,
+    cache: Cache,
+    hits: u64,
+    misses: u64
+}
+
+impl FromStr for CacheSize {
+    type Err = String;
+
+    /// Parses `none` or `<bytes>`.
+    fn from_str(s: &str) -> Result<CacheSize, String> {
+        if s == "none" {
+            return Ok(Default::default());
+        }
+
+        match s.parse() {
+            Ok(bytes) => Ok(CacheSize { bytes: bytes }),
+            Err(_) => Err(format!("Bad cache size: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for CacheSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bytes {
+            0 => write!(f, "none"),
+            bytes => write!(f, "{} bytes", bytes)
+        }
+    }
+}
+
+impl Cache {
+    pub fn new(size: CacheSize) -> Cache {
+        Cache {
+            capacity: size.bytes,
+            used: 0,
+            blobs: HashMap::new(),
+            uses: BTreeMap::new(),
+            tick: 0
+        }
+    }
+
+    /// Bytes of data cached.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Cached data for `path`, marking it as recently used.
+    pub fn get(&mut self, path: &Path) -> Option<&[u8]> {
+        self.tick += 1;
+
+        match self.blobs.get_mut(path) {
+            None => None,
+            Some(&mut (ref mut used, ref blob)) => {
+                let path = self.uses.remove(used).unwrap();
+
+                *used = self.tick;
+                self.uses.insert(self.tick, path);
+
+                Some(blob)
+            }
+        }
+    }
+
+    /// Caches data for `path`, dropping least recently used data to make room. Data larger than
+    /// the whole cache is not cached.
+    pub fn insert(&mut self, path: Path, blob: Vec<u8>) {
+        self.remove(&path);
+
+        if blob.len() > self.capacity {
+            return;
+        }
+
+        while self.used + blob.len() > self.capacity {
+            let oldest = *self.uses.keys().next().unwrap();
+            let path = self.uses.remove(&oldest).unwrap();
+
+            self.remove(&path);
+        }
+
+        self.tick += 1;
+        self.used += blob.len();
+        self.uses.insert(self.tick, path.clone());
+        self.blobs.insert(path, (self.tick, blob));
+    }
+
+    pub fn clear(&mut self) {
+        self.used = 0;
+        self.blobs.clear();
+        self.uses.clear();
+    }
+
+    pub fn remove(&mut self, path: &Path) {
+        if let Some((used, blob)) = self.blobs.remove(path) {
+            self.uses.remove(&used);
+            self.used -= blob.len();
+        }
+    }
+}
+
+impl Cached {
+    /// Puts `backend` behind a cache of `size`, or leaves it be without one.
+    pub fn wrap(backend: Box<StoreBackend>, size: CacheSize) -> Box<StoreBackend> {
+        if size.bytes == 0 {
+            return backend;
+        }
+
+        Box::new(Cached {
+            backend: backend,
+            cache: Cache::new(size),
+            hits: 0,
+            misses: 0
+        })
+    }
+
+    /// Deserializes cached data for `path`, if any. Data that fails to deserialize is dropped, to
+    /// be loaded from the backend instead.
+    fn read(&mut self, path: &Path) -> Option<ZoneData> {
+        let result = match self.cache.get(path) {
+            None => None,
+            Some(blob) => Some(migrate::deserialize(blob.to_vec()))
+        };
+
+        match result {
+            Some(Ok(data)) => {
+                self.hits += 1;
+                return Some(data);
+            },
+            Some(Err(err)) => {
+                error!("Error reading cached {:?}: {}", path, err);
+                self.cache.remove(path);
+            },
+            None => ()
+        }
+
+        self.misses += 1;
+        None
+    }
+
+    /// Cached data for `path`, if any, left serialized.
+    fn read_raw(&mut self, path: &Path) -> Option<RawZone> {
+        let raw = self.cache.get(path).map(|blob| RawZone::new(blob.to_vec()));
+
+        if raw.is_some() {
+            self.hits += 1;
+        }
+        else {
+            self.misses += 1;
+        }
+
+        raw
+    }
+}
+
+impl StoreBackend for Cached {
+    fn append(&mut self, path: Path, diff: Vec<u8>) {
+        self.cache.remove(&path);
+        self.backend.append(path, diff);
+    }
+
+    fn compact(&mut self, path: Path) {
+        self.cache.remove(&path);
+        self.backend.compact(path);
+    }
+
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        self.cache.remove(&path);
+        self.backend.delete(zone, path);
+    }
+
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        self.cache.remove(&path);
+        self.backend.delete_data(path, reply);
+    }
+
+    fn list(&mut self, prefix: Path, reply: Sender<Path>) {
+        self.backend.list(prefix, reply);
+    }
+
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        match self.read_raw(&path) {
+            Some(raw) => zone.loaded_raw(raw),
+            None => self.backend.load(zone, path)
+        }
+    }
+
+    fn load_data(&mut self, path: Path, reply: Reply<Option<ZoneData>>) {
+        match self.read(&path) {
+            Some(data) => {
+                reply.send(Some(data)).is_ok(); // ignore if caller goes away
+            },
+            None => self.backend.load_data(path, reply)
+        }
+    }
+
+    fn load_raw(&mut self, path: Path, reply: Reply<Option<RawZone>>) {
+        match self.read_raw(&path) {
+            Some(raw) => {
+                reply.send(Some(raw)).is_ok(); // ignore if caller goes away
+            },
+            None => self.backend.load_raw(path, reply)
+        }
+    }
+
+    fn load_many(&mut self, zones: Vec<(ZoneHandle, Path)>) {
+        let mut misses = vec![];
+
+        for (zone, path) in zones {
+            match self.read_raw(&path) {
+                Some(raw) => zone.loaded_raw(raw),
+                None => misses.push((zone, path))
+            }
+        }
+
+        if ! misses.is_empty() {
+            self.backend.load_many(misses);
+        }
+    }
+
+    fn quarantine(&mut self, path: Path, reply: Reply<bool>) {
+        self.cache.remove(&path);
+        self.backend.quarantine(path, reply);
+    }
+
+    fn reload(&mut self, zone: ZoneHandle, path: Path) {
+        self.cache.remove(&path);
+        self.backend.reload(zone, path);
+    }
+
+    fn request_write(&mut self, zone: ZoneHandle) {
+        self.backend.request_write(zone);
+    }
+
+    fn restore(&mut self, path: Option<Path>, timestamp: u64, reply: Reply<usize>) {
+        match path {
+            None => self.cache.clear(),
+            Some(ref path) => self.cache.remove(path)
+        }
+
+        self.backend.restore(path, timestamp, reply);
+    }
+
+    fn shutdown(&mut self) {
+        self.backend.shutdown();
+    }
+
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        self.backend.stat(path, reply);
+    }
+
+    /// Verifies what is stored, rather than what is cached.
+    fn verify(&mut self, path: Path, reply: Reply<Result<(), StoreError>>) {
+        self.backend.verify(path, reply);
+    }
+
+    fn stats(&mut self) -> StoreStats {
+        let mut stats = self.backend.stats();
+
+        stats.cache_hits = self.hits;
+        stats.cache_misses = self.misses;
+        stats.cache_bytes = self.cache.used();
+        stats
+    }
+
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        self.cache.insert(path.clone(), data.clone());
+        self.backend.write(zone, path, data);
+    }
+
+    fn write_batch(&mut self, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        for &(_, ref path, ref data) in &writes {
+            self.cache.insert(path.clone(), data.clone());
+        }
+
+        self.backend.write_batch(writes);
+    }
+
+    fn write_atomic(&mut self, writes: Vec<(Path, Vec<u8>)>, reply: Reply<bool>) {
+        for &(ref path, _) in &writes {
+            self.cache.remove(path);
+        }
+
+        self.backend.write_atomic(writes, reply);
+    }
+
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        self.cache.remove(&path);
+        self.backend.write_data(path, data, reply);
+    }
+
+    fn write_delta(&mut self, zone: ZoneHandle, path: Path, delta: Vec<u8>) {
+        self.cache.remove(&path);
+        self.backend.write_delta(zone, path, delta);
+    }
+
+    fn coalesces_writes(&self) -> bool {
+        self.backend.coalesces_writes()
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(CacheSize::default()));
+    assert_eq!("1048576".parse(), Ok(CacheSize { bytes: 1048576 }));
+
+    assert!("moo".parse::<CacheSize>().is_err());
+}
+
+#[test]
+fn test_lru() {
+    let mut cache = Cache::new(CacheSize { bytes: 10 });
+
+    cache.insert(path![moo], vec![0; 4]);
+    cache.insert(path![cow], vec![1; 4]);
+
+    // Using moo leaves cow the least recently used
+    assert_eq!(cache.get(&path![moo]), Some(&[0u8; 4][..]));
+
+    cache.insert(path![pig], vec![2; 4]);
+
+    assert_eq!(cache.get(&path![cow]), None);
+    assert_eq!(cache.get(&path![moo]), Some(&[0u8; 4][..]));
+    assert_eq!(cache.used(), 8);
+
+    // Replacing data frees up what it used
+    cache.insert(path![moo], vec![3; 2]);
+
+    assert_eq!(cache.used(), 6);
+
+    // Data larger than the cache is left out
+    cache.insert(path![cow], vec![4; 11]);
+
+    assert_eq!(cache.get(&path![cow]), None);
+    assert_eq!(cache.used(), 6);
+
+    cache.remove(&path![pig]);
+
+    assert_eq!(cache.get(&path![pig]), None);
+    assert_eq!(cache.used(), 2);
+}
@@ -0,0 +1,409 @@
+//! Encoding of persisted zone data.
+//!
+//! Backends store serialized `ZoneData` as blobs, encoded by a `Codec`: compressed, then optionally
+//! encrypted (see `store::encryption`), then checksummed. Compressed blobs start with a small
+//! header marking the codec:
+//!
+//! ```text
+//! "QMZ" <codec: u8> <compressed data>
+//! ```
+//!
+//! Every blob is wrapped with a CRC32 checksum, so truncated or bit-rotted data is reported as
+//! `StoreError::Corrupt` on load instead of deserializing to garbage:
+//!
+//! ```text
+//! "QMC" <crc32 of payload: u32 LE> <payload>
+//! ```
+//!
+//! Blobs written before these headers existed are plain bincode, and stay readable. They are
+//! rewritten with the configured codec on their next write. Plain bincode never starts with any of
+//! the magics, which would mean a `Path` with millions of segments.
+//!
+//! `lz4` and `zstd` need their cargo features enabled.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crc32fast;
+#[cfg(feature = "lz4")] use lz4;
+#[cfg(feature = "zstd")] use zstd;
+
+use super::StoreError;
+use super::encryption::{self, Keyring};
+
+const MAGIC: &'static [u8] = b"QMZ";
+const CHECKSUM_MAGIC: &'static [u8] = b"QMC";
+
+/// Length of the header added by `checksum`
+pub const CHECKSUM_HEADER_LEN: usize = 7;
+
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Encoding applied by a backend to the zone data it stores.
+#[derive(Clone, Debug, Default)]
+pub struct Codec {
+    pub compression: Compression,
+    pub keyring: Option<Arc<Keyring>>
+}
+
+/// Compression applied to zone data before it is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Lz4,
+    Zstd(i32) // compression level
+}
+
+impl Default for Compression {
+    fn default() -> Compression {
+        Compression::None
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    /// Parses `none`, `lz4`, `zstd` or `zstd:<level>`.
+    fn from_str(s: &str) -> Result<Compression, String> {
+        let mut parts = s.splitn(2, ':');
+
+        let compression = match (parts.next(), parts.next()) {
+            (Some("none"), None) => Compression::None,
+            (Some("lz4"), None) => Compression::Lz4,
+            (Some("zstd"), None) => Compression::Zstd(DEFAULT_ZSTD_LEVEL),
+            (Some("zstd"), Some(level)) => match level.parse() {
+                Ok(level) => Compression::Zstd(level),
+                Err(_) => return Err(format!("Bad zstd compression level: {}", level))
+            },
+            _ => return Err(format!("Unknown compression: {}", s))
+        };
+
+        if ! compression.is_available() {
+            return Err(format!("Compression {} not compiled in", compression));
+        }
+
+        Ok(compression)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd(level) => write!(f, "zstd:{}", level)
+        }
+    }
+}
+
+impl Compression {
+    /// Returns true if support for this compression is compiled in.
+    pub fn is_available(&self) -> bool {
+        match *self {
+            Compression::None => true,
+            Compression::Lz4 => cfg!(feature = "lz4"),
+            Compression::Zstd(_) => cfg!(feature = "zstd")
+        }
+    }
+}
+
+impl Codec {
+    /// Encodes serialized zone data for storage.
+    pub fn encode(&self, data: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        let compressed = try!(compress(data, self.compression));
+
+        let payload = match self.keyring {
+            None => compressed,
+            Some(ref keyring) => try!(keyring.encrypt(&compressed))
+        };
+
+        Ok(checksum(payload))
+    }
+
+    /// Decodes a stored blob back to serialized zone data, whichever codec and key it was written
+    /// with. Unencrypted blobs are read even with keys configured, so existing data is encrypted
+    /// as it is written again.
+    pub fn decode(&self, blob: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+        let blob = try!(verify(blob));
+
+        if ! encryption::is_encrypted(&blob) {
+            return decompress(blob);
+        }
+
+        match self.keyring {
+            None => Err(StoreError::Unsupported("Zone data is encrypted, but no keys are configured".into())),
+            Some(ref keyring) => decompress(try!(keyring.decrypt(&blob)))
+        }
+    }
+
+    /// Same as `decode`, borrowing the serialized zone data from `blob` if it is only checksummed.
+    pub fn decode_slice<'a>(&self, blob: &'a [u8]) -> Result<Cow<'a, [u8]>, StoreError> {
+        let payload = match try!(expected_checksum(blob)) {
+            None => blob,
+            Some(expected) => {
+                let payload = &blob[CHECKSUM_HEADER_LEN..];
+                let actual = crc32fast::hash(payload);
+
+                if actual != expected {
+                    return Err(checksum_mismatch(expected, actual));
+                }
+
+                payload
+            }
+        };
+
+        if is_plain(payload) {
+            Ok(Cow::Borrowed(payload))
+        }
+        else {
+            self.decode(payload.to_vec()).map(Cow::Owned)
+        }
+    }
+}
+
+/// Prepends a checksum header to `payload`.
+pub fn checksum(payload: Vec<u8>) -> Vec<u8> {
+    let crc = crc32fast::hash(&payload);
+    let mut blob = Vec::with_capacity(CHECKSUM_MAGIC.len() + 4 + payload.len());
+
+    blob.extend_from_slice(CHECKSUM_MAGIC);
+    blob.extend_from_slice(&[crc as u8, (crc >> 8) as u8, (crc >> 16) as u8, (crc >> 24) as u8]);
+    blob.extend_from_slice(&payload);
+
+    blob
+}
+
+/// Verifies and strips the checksum header added by `checksum`. Blobs without one are returned as
+/// is.
+pub fn verify(blob: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    let expected = match try!(expected_checksum(&blob)) {
+        None => return Ok(blob),
+        Some(expected) => expected
+    };
+
+    let actual = crc32fast::hash(&blob[CHECKSUM_HEADER_LEN..]);
+
+    if actual != expected {
+        return Err(checksum_mismatch(expected, actual));
+    }
+
+    Ok(blob[CHECKSUM_HEADER_LEN..].to_vec())
+}
+
+/// Checksum in the header added by `checksum` at the start of `blob`, None if there is none.
+pub fn expected_checksum(blob: &[u8]) -> Result<Option<u32>, StoreError> {
+    if ! blob.starts_with(CHECKSUM_MAGIC) {
+        return Ok(None);
+    }
+
+    if blob.len() < CHECKSUM_HEADER_LEN {
+        return Err(StoreError::corrupt("Truncated checksum header"));
+    }
+
+    let crc = &blob[CHECKSUM_MAGIC.len()..CHECKSUM_HEADER_LEN];
+
+    Ok(Some(crc[0] as u32 | (crc[1] as u32) << 8 | (crc[2] as u32) << 16 | (crc[3] as u32) << 24))
+}
+
+/// Error for a payload with checksum `actual` instead of `expected`.
+pub fn checksum_mismatch(expected: u32, actual: u32) -> StoreError {
+    StoreError::corrupt(format!("Checksum mismatch: expected {:08x}, got {:08x}", expected, actual))
+}
+
+/// Whether a checksummed payload is neither compressed nor encrypted, i.e. already serialized zone
+/// data. Needs at least the first 3 bytes of the payload.
+pub fn is_plain(payload: &[u8]) -> bool {
+    ! payload.starts_with(MAGIC) && ! encryption::is_encrypted(payload)
+}
+
+/// Compresses serialized zone data, adding a header unless `compression` is `None`.
+pub fn compress(data: Vec<u8>, compression: Compression) -> Result<Vec<u8>, StoreError> {
+    let (codec, compressed) = match compression {
+        Compression::None => return Ok(data),
+        Compression::Lz4 => (CODEC_LZ4, try!(compress_lz4(&data))),
+        Compression::Zstd(level) => (CODEC_ZSTD, try!(compress_zstd(&data, level)))
+    };
+
+    let mut blob = Vec::with_capacity(MAGIC.len() + 1 + compressed.len());
+
+    blob.extend_from_slice(MAGIC);
+    blob.push(codec);
+    blob.extend_from_slice(&compressed);
+
+    Ok(blob)
+}
+
+/// Decompresses the output of `compress`, whichever codec it was written with.
+pub fn decompress(blob: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    if ! blob.starts_with(MAGIC) {
+        return Ok(blob);
+    }
+
+    let body = &blob[MAGIC.len()..];
+
+    match body.split_first() {
+        Some((&CODEC_LZ4, compressed)) => decompress_lz4(compressed),
+        Some((&CODEC_ZSTD, compressed)) => decompress_zstd(compressed),
+        Some((codec, _)) => Err(StoreError::Unsupported(format!("Unknown codec {}", codec))),
+        None => Err(StoreError::corrupt("Truncated compression header"))
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn compress_lz4(data: &[u8]) -> Result<Vec<u8>, StoreError> {
+    lz4::block::compress(data, None, true).map_err(|err| StoreError::SerializationFailed(err.to_string()))
+}
+
+#[cfg(feature = "lz4")]
+fn decompress_lz4(compressed: &[u8]) -> Result<Vec<u8>, StoreError> {
+    lz4::block::decompress(compressed, None).map_err(|err| StoreError::corrupt(err.to_string()))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn compress_lz4(_: &[u8]) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("lz4 compression not compiled in".into()))
+}
+
+#[cfg(not(feature = "lz4"))]
+fn decompress_lz4(_: &[u8]) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("lz4 compression not compiled in".into()))
+}
+
+#[cfg(feature = "zstd")]
+fn compress_zstd(data: &[u8], level: i32) -> Result<Vec<u8>, StoreError> {
+    zstd::encode_all(data, level).map_err(|err| StoreError::SerializationFailed(err.to_string()))
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(compressed: &[u8]) -> Result<Vec<u8>, StoreError> {
+    zstd::decode_all(compressed).map_err(|err| StoreError::corrupt(err.to_string()))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_zstd(_: &[u8], _: i32) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("zstd compression not compiled in".into()))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_: &[u8]) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("zstd compression not compiled in".into()))
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(Compression::None));
+    assert!("zstd:x".parse::<Compression>().is_err());
+    assert!("moo".parse::<Compression>().is_err());
+
+    if cfg!(feature = "zstd") {
+        assert_eq!("zstd".parse(), Ok(Compression::Zstd(DEFAULT_ZSTD_LEVEL)));
+        assert_eq!("zstd:9".parse(), Ok(Compression::Zstd(9)));
+    }
+}
+
+#[test]
+fn test_encode_decode() {
+    let data = b"moo moo moo moo moo moo moo moo".to_vec();
+
+    // Uncompressed data is only checksummed
+    assert_eq!(Codec::default().encode(data.clone()).unwrap(), checksum(data.clone()));
+    assert_eq!(Codec::default().decode(data.clone()).unwrap(), data);
+
+    for &compression in &[Compression::Lz4, Compression::Zstd(DEFAULT_ZSTD_LEVEL)] {
+        if ! compression.is_available() {
+            assert!(compress(data.clone(), compression).is_err());
+            continue;
+        }
+
+        let blob = compress(data.clone(), compression).unwrap();
+
+        assert!(blob.starts_with(MAGIC));
+        assert_eq!(decompress(blob).unwrap(), data);
+    }
+
+    assert!(decompress(b"QMZ".to_vec()).is_err());
+    assert!(decompress(b"QMZ\xff".to_vec()).is_err());
+}
+
+#[test]
+fn test_encrypted() {
+    let keyring = Keyring::parse(&format!("1:{}", "01".repeat(32))).unwrap();
+    let data = b"moo moo moo moo moo moo moo moo".to_vec();
+
+    let plain = Codec::default();
+    let encrypted = Codec { compression: Compression::None, keyring: Some(Arc::new(keyring)) };
+
+    // Existing unencrypted data stays readable
+    assert_eq!(encrypted.decode(data.clone()).unwrap(), data);
+
+    if ! cfg!(feature = "encryption") {
+        assert!(encrypted.encode(data.clone()).is_err());
+        return;
+    }
+
+    let blob = encrypted.encode(data.clone()).unwrap();
+
+    assert_eq!(encrypted.decode(blob.clone()).unwrap(), data);
+    assert!(plain.decode(blob).is_err());
+}
+
+#[test]
+fn test_decode_slice() {
+    let data = b"moo moo moo moo moo moo moo moo".to_vec();
+    let blob = checksum(data.clone());
+
+    match Codec::default().decode_slice(&blob).unwrap() {
+        Cow::Borrowed(serialized) => assert_eq!(serialized, &data[..]),
+        Cow::Owned(_) => panic!("Expected borrowed data")
+    }
+
+    let mut flipped = blob.clone();
+    let last = flipped.len() - 1;
+
+    flipped[last] ^= 1;
+
+    assert!(Codec::default().decode_slice(&flipped).is_err());
+
+    if Compression::Zstd(DEFAULT_ZSTD_LEVEL).is_available() {
+        let codec = Codec { compression: Compression::Zstd(DEFAULT_ZSTD_LEVEL), keyring: None };
+        let blob = codec.encode(data.clone()).unwrap();
+
+        assert_eq!(&*codec.decode_slice(&blob).unwrap(), &data[..]);
+    }
+}
+
+#[test]
+fn test_checksum() {
+    let data = b"moo".to_vec();
+    let blob = checksum(data.clone());
+
+    assert_eq!(verify(blob.clone()).unwrap(), data);
+
+    // Blobs from before checksums pass through
+    assert_eq!(verify(data.clone()).unwrap(), data);
+
+    let mut flipped = blob.clone();
+    let last = flipped.len() - 1;
+
+    flipped[last] ^= 1;
+
+    match verify(flipped) {
+        Err(StoreError::Corrupt { .. }) => (),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+
+    match verify(blob[..blob.len() - 1].to_vec()) {
+        Err(StoreError::Corrupt { .. }) => (),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+
+    match verify(b"QMC".to_vec()) {
+        Err(StoreError::Corrupt { .. }) => (),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+}
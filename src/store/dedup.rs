@@ -0,0 +1,182 @@
+//! Zone files shared between zones with identical data.
+//!
+//! With `STORE_DEDUP`, the fs store keeps one copy of each distinct zone file in `_blobs` in its
+//! data directory, named after a hash of the encoded data and its length, and zone files are hard
+//! links to it. A zone written with the same data as another one links the stored blob instead of
+//! writing its own file. Blobs are compared byte for byte before they are shared, so colliding
+//! hashes only cost the space saved.
+//!
+//! The link count of a blob is its reference count: every zone file sharing it is a link, and zone
+//! files are only ever replaced by renaming, never changed in place. Blobs no zone file links to
+//! any more are removed on startup.
+//!
+//! Encrypted zone data is never identical (see `store::encryption`), and kept history relies on
+//! each zone file's modification time, so neither is deduplicated. The quota still counts every
+//! zone file in full.
+
+use std;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{DirBuilder, File};
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::io::prelude::*;
+
+/// Directory blobs are kept in, in each data directory.
+pub const DIR: &'static str = "_blobs";
+
+/// Blobs of the zone files in a set of data directories.
+#[derive(Debug, Default)]
+pub struct Dedup {
+    dirs: Vec<std::path::PathBuf> // Data directories, none if disabled
+}
+
+impl Dedup {
+    pub fn new(dirs: &[std::path::PathBuf]) -> Dedup {
+        Dedup { dirs: dirs.to_vec() }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        ! self.dirs.is_empty()
+    }
+
+    /// Links `tmp_path`, the temporary file for the zone file at `filepath`, to the stored blob of
+    /// `serialized`. Returns false if there is none, or it is not identical.
+    pub fn link(&self, filepath: &std::path::Path, serialized: &[u8], tmp_path: &std::path::Path) -> bool {
+        let blob = match self.blob_path(filepath, serialized) {
+            None => return false,
+            Some(blob) => blob
+        };
+
+        let mut stored = Vec::with_capacity(serialized.len());
+
+        match File::open(&blob).and_then(|mut file| file.read_to_end(&mut stored)) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return false,
+            Err(err) => {
+                error!("Error reading {}: {}", blob.display(), err);
+                return false;
+            },
+            Ok(_) if stored != serialized => {
+                warn!("Hash collision on {}, not sharing it", blob.display());
+                return false;
+            },
+            Ok(_) => ()
+        }
+
+        std::fs::remove_file(tmp_path).is_ok(); // left behind by an earlier write
+
+        match std::fs::hard_link(&blob, tmp_path) {
+            Err(err) => {
+                error!("Error linking {} to {}: {}", tmp_path.display(), blob.display(), err);
+                false
+            },
+            Ok(_) => true
+        }
+    }
+
+    /// Stores `tmp_path`, holding `serialized` for the zone file at `filepath`, as the blob of
+    /// `serialized` for later writes to share. Errors are logged, rather than failing the write.
+    pub fn share(&self, filepath: &std::path::Path, serialized: &[u8], tmp_path: &std::path::Path) {
+        let blob = match self.blob_path(filepath, serialized) {
+            None => return,
+            Some(blob) => blob
+        };
+
+        let result = blob.parent().map_or(Ok(()), |dir| DirBuilder::new().recursive(true).create(dir))
+            .and_then(|_| std::fs::hard_link(tmp_path, &blob));
+
+        match result {
+            // Stored by another write in the meantime, or a colliding hash
+            Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+            Err(err) => error!("Error sharing {}: {}", blob.display(), err),
+            Ok(_) => ()
+        }
+    }
+
+    /// Removes blobs no zone file links to any more. Returns the number removed.
+    pub fn sweep(&self) -> usize {
+        let mut removed = 0;
+
+        for dir in &self.dirs {
+            let entries = match std::fs::read_dir(dir.join(DIR)) {
+                Err(_) => continue,
+                Ok(entries) => entries
+            };
+
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                if entry.metadata().map(|meta| links(&meta) == 1).unwrap_or(false) {
+                    match std::fs::remove_file(entry.path()) {
+                        Err(err) => error!("Error removing {}: {}", entry.path().display(), err),
+                        Ok(_) => removed += 1
+                    }
+                }
+            }
+        }
+
+        removed
+    }
+
+    /// Blob of `serialized` in the data directory of the zone file at `filepath`.
+    fn blob_path(&self, filepath: &std::path::Path, serialized: &[u8]) -> Option<std::path::PathBuf> {
+        let mut hasher = DefaultHasher::new();
+
+        serialized.hash(&mut hasher);
+
+        let name = format!("{:016X}_{}", hasher.finish(), serialized.len());
+
+        self.dirs.iter().find(|dir| filepath.starts_with(dir)).map(|dir| dir.join(DIR).join(name))
+    }
+}
+
+/// Number of hard links to a file.
+#[cfg(unix)]
+fn links(meta: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    meta.nlink()
+}
+
+/// Link counts aren't available, so blobs are never known to be unused.
+#[cfg(not(unix))]
+fn links(_: &std::fs::Metadata) -> u64 {
+    std::u64::MAX
+}
+
+#[test]
+fn test_link_share_sweep() {
+    let dir = std::path::PathBuf::from("test_data/dedup");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let dedup = Dedup::new(&[dir.clone()]);
+    let (moo, cow) = (dir.join("moo"), dir.join("cow"));
+
+    // Nothing to share yet
+    assert!(! dedup.link(&moo, b"moo", &moo.with_extension("tmp")));
+
+    std::fs::write(moo.with_extension("tmp"), b"moo").unwrap();
+    dedup.share(&moo, b"moo", &moo.with_extension("tmp"));
+    std::fs::rename(moo.with_extension("tmp"), &moo).unwrap();
+
+    // Same data is linked, other data isn't
+    assert!(dedup.link(&cow, b"moo", &cow.with_extension("tmp")));
+    assert!(! dedup.link(&cow, b"oink", &dir.join("oink.tmp")));
+
+    std::fs::rename(cow.with_extension("tmp"), &cow).unwrap();
+
+    assert_eq!(std::fs::read(&cow).unwrap(), b"moo");
+    assert_eq!(dedup.sweep(), 0);
+
+    // Unused once both zone files are gone
+    std::fs::remove_file(&moo).unwrap();
+    assert_eq!(dedup.sweep(), 0);
+
+    std::fs::remove_file(&cow).unwrap();
+    assert_eq!(dedup.sweep(), 1);
+
+    // Disabled
+    assert!(! Dedup::default().link(&cow, b"moo", &cow.with_extension("tmp")));
+}
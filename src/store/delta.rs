@@ -0,0 +1,156 @@
+//! Per-zone delta logs, for zones too large to rewrite on every save.
+//!
+//! Instead of its full data, a large zone can save the parts of it changed since its last save
+//! (`StoreCall::WriteDelta`, see `region`). Backends supporting deltas append them to a log next to
+//! the zone's snapshot, and replay the log over the snapshot on load.
+//!
+//! Deltas only grow, so once they add up to a good part of the snapshot, the backend asks the
+//! zone for a full snapshot instead (`ZoneHandle::snapshot`). Writing a snapshot clears the log.
+//!
+//! The log is a sequence of bincode serialized byte strings, each an encoded (see `store::codec`)
+//! `Vec<NodeTree>`, serialized by `migrate::serialize_delta`.
+
+use std;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::io::prelude::*;
+
+use bincode;
+
+use super::migrate;
+use zone::ZoneData;
+
+/// Snapshot is requested once deltas add up to `1 / CONSOLIDATE_RATIO` of its size
+const CONSOLIDATE_RATIO: u64 = 4;
+
+/// Whether deltas of `deltas` bytes (including the one being saved) should be consolidated into
+/// a snapshot of `snapshot` bytes. Zones without a snapshot always need one.
+pub fn needs_snapshot(snapshot: u64, deltas: u64) -> bool {
+    snapshot == 0 || deltas * CONSOLIDATE_RATIO >= snapshot
+}
+
+/// Appends an encoded delta to the log at `filepath`, flushing it to disk if `sync` is set.
+pub fn append(filepath: &std::path::Path, delta: &[u8], sync: bool) -> io::Result<()> {
+    let limit = bincode::Infinite;
+    let serialized = try!(bincode::serialize(delta, limit)
+        .map_err(|err| io::Error::new(ErrorKind::Other, err)));
+
+    let mut file = try!(OpenOptions::new().create(true).append(true).open(filepath));
+
+    // Single write, so a crash can only tear the last delta
+    try!(file.write_all(&serialized));
+
+    if sync {
+        try!(file.sync_all());
+    }
+
+    Ok(())
+}
+
+/// Reads encoded deltas from the log at `filepath`, oldest first. A partially written delta at
+/// the end of the log is discarded.
+pub fn read(filepath: &std::path::Path) -> io::Result<Vec<Vec<u8>>> {
+    let file = match File::open(filepath) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+        Ok(file) => file
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut deltas = vec![];
+
+    loop {
+        match bincode::deserialize_from(&mut reader, bincode::Infinite) {
+            Ok(delta) => deltas.push(delta),
+            Err(err) => {
+                // End of log, or a torn delta
+                debug!("Delta replay stopped: {}", err);
+                break;
+            }
+        }
+    }
+
+    Ok(deltas)
+}
+
+/// Merges decoded deltas into zone data.
+pub fn replay(data: &mut ZoneData, deltas: Vec<Vec<u8>>) {
+    for delta in deltas {
+        match migrate::deserialize_delta(&delta) {
+            Err(err) => error!("Bad delta for {:?}: {}", data.path, err),
+            Ok(diffs) => {
+                for mut diff in diffs {
+                    data.tree.merge(&mut diff);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_needs_snapshot() {
+    assert!(needs_snapshot(0, 0));
+    assert!(needs_snapshot(0, 10));
+    assert!(needs_snapshot(1000, 250));
+    assert!(needs_snapshot(1000, 2000));
+
+    assert!(!needs_snapshot(1000, 10));
+    assert!(!needs_snapshot(1000, 249));
+}
+
+#[test]
+fn test_append_read() {
+    use std::fs::DirBuilder;
+
+    let dir = std::path::PathBuf::from("test_data/delta");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let file = dir.join("test_append_read.delta");
+
+    std::fs::remove_file(&file).ok();
+
+    assert!(read(&file).unwrap().is_empty());
+
+    append(&file, &[1, 2], true).unwrap();
+    append(&file, &[3], false).unwrap();
+
+    assert_eq!(read(&file).unwrap(), [vec![1, 2], vec![3]]);
+
+    // Torn delta at the end is dropped
+    OpenOptions::new().append(true).open(&file).unwrap().write_all(&[9, 0, 0]).unwrap();
+
+    assert_eq!(read(&file).unwrap(), [vec![1, 2], vec![3]]);
+}
+
+#[test]
+fn test_replay() {
+    use node::{Node, NodeTree, Vis};
+    use path::Path;
+    use serde_json::Value as JSON;
+
+    let cow = Node::expand_from(&["moo".to_string()], JSON::String(String::from("cow")), 1000);
+    let pig = Node::expand_from(&["moo".to_string()], JSON::String(String::from("pig")), 2000);
+    let serialized = migrate::serialize_delta(&[cow.clone().noop_vis(), pig.clone().noop_vis()]).unwrap();
+
+    let mut data = ZoneData::new(Path::empty(), NodeTree {
+        node: Default::default(),
+        vis: Vis::permanent()
+    });
+
+    replay(&mut data, vec![serialized]);
+
+    // Same as merging them in turn
+    let mut expected = NodeTree {
+        node: Default::default(),
+        vis: Vis::permanent()
+    };
+
+    expected.merge(&mut cow.noop_vis());
+    expected.merge(&mut pig.noop_vis());
+
+    assert_eq!(data.tree, expected);
+}
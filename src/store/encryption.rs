@@ -0,0 +1,244 @@
+//! Encryption at rest of stored zone data.
+//!
+//! Blobs are encrypted with ChaCha20-Poly1305 and tagged with the id of the key used:
+//!
+//! ```text
+//! "QME" <key id: u32 LE> <nonce: 12 bytes> <ciphertext>
+//! ```
+//!
+//! Keys are given at startup with `STORE_KEYS`, or one per line in the file named by
+//! `STORE_KEYS_FILE`, as `<id>:<hex encoded 32 byte key>`. The first key encrypts new data, the
+//! others only decrypt: to rotate keys, put a new key first and keep the old ones until all zones
+//! have been written again.
+//!
+//! Needs the `encryption` cargo feature.
+
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+
+#[cfg(feature = "encryption")]
+use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+
+use super::StoreError;
+
+pub const MAGIC: &'static [u8] = b"QME";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+/// Encryption keys, the first of which is current.
+pub struct Keyring {
+    keys: Vec<Key>
+}
+
+struct Key {
+    id: u32,
+    secret: Vec<u8>
+}
+
+impl Keyring {
+    /// Parses keys, separated by newlines or commas. Blank lines and `#` comments are ignored.
+    pub fn parse(s: &str) -> Result<Keyring, String> {
+        let mut keys: Vec<Key> = vec![];
+
+        for entry in s.split(|c| c == '\n' || c == ',') {
+            let entry = entry.trim();
+
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = entry.splitn(2, ':');
+
+            let id = match parts.next().unwrap().parse() {
+                Ok(id) => id,
+                Err(_) => return Err(format!("Bad key id: {}", entry))
+            };
+
+            let secret = match parts.next().and_then(from_hex) {
+                Some(ref secret) if secret.len() == KEY_LEN => secret.clone(),
+                _ => return Err(format!("Key {} is not {} hex encoded bytes", id, KEY_LEN))
+            };
+
+            if keys.iter().any(|key| key.id == id) {
+                return Err(format!("Duplicate key id: {}", id));
+            }
+
+            keys.push(Key { id: id, secret: secret });
+        }
+
+        if keys.is_empty() {
+            return Err("No keys given".into());
+        }
+
+        Ok(Keyring { keys: keys })
+    }
+
+    /// Reads keys from `STORE_KEYS` or `STORE_KEYS_FILE`, if set.
+    pub fn from_env() -> Result<Option<Keyring>, String> {
+        let keys = match (env::var("STORE_KEYS"), env::var("STORE_KEYS_FILE")) {
+            (Ok(_), Ok(_)) => return Err("Only one of STORE_KEYS and STORE_KEYS_FILE can be set".into()),
+            (Ok(keys), _) => keys,
+            (_, Ok(filename)) => {
+                let mut keys = String::new();
+
+                if let Err(err) = File::open(&filename).and_then(|mut file| file.read_to_string(&mut keys)) {
+                    return Err(format!("Could not read {}: {}", filename, err));
+                }
+
+                keys
+            },
+            _ => return Ok(None)
+        };
+
+        if ! cfg!(feature = "encryption") {
+            return Err("Encryption not compiled in".into());
+        }
+
+        Keyring::parse(&keys).map(Some)
+    }
+
+    /// Id of the key new data is encrypted with.
+    pub fn current_id(&self) -> u32 {
+        self.keys[0].id
+    }
+
+    /// Encrypts `plaintext` with the current key.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+        let key = &self.keys[0];
+        let sealed = try!(seal(&key.secret, plaintext));
+
+        let mut blob = Vec::with_capacity(MAGIC.len() + 4 + sealed.len());
+
+        blob.extend_from_slice(MAGIC);
+        blob.extend_from_slice(&[key.id as u8, (key.id >> 8) as u8, (key.id >> 16) as u8, (key.id >> 24) as u8]);
+        blob.extend_from_slice(&sealed);
+
+        Ok(blob)
+    }
+
+    /// Decrypts a blob returned by `encrypt`, with whichever key it was encrypted with.
+    pub fn decrypt(&self, blob: &[u8]) -> Result<Vec<u8>, StoreError> {
+        if blob.len() < MAGIC.len() + 4 + NONCE_LEN || ! blob.starts_with(MAGIC) {
+            return Err(StoreError::corrupt("Truncated encrypted data"));
+        }
+
+        let tag = &blob[MAGIC.len()..MAGIC.len() + 4];
+        let id = tag[0] as u32 | (tag[1] as u32) << 8 | (tag[2] as u32) << 16 | (tag[3] as u32) << 24;
+
+        match self.keys.iter().find(|key| key.id == id) {
+            None => Err(StoreError::Unsupported(format!("Unknown encryption key {}", id))),
+            Some(key) => open(&key.secret, &blob[MAGIC.len() + 4..])
+        }
+    }
+}
+
+impl fmt::Debug for Keyring {
+    /// Only shows key ids, never the keys themselves.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<u32> = self.keys.iter().map(|key| key.id).collect();
+
+        write!(f, "Keyring {{ ids: {:?} }}", ids)
+    }
+}
+
+/// Returns true if `blob` is encrypted.
+pub fn is_encrypted(blob: &[u8]) -> bool {
+    blob.starts_with(MAGIC)
+}
+
+/// Encrypts `plaintext`, returning the nonce followed by the ciphertext.
+#[cfg(feature = "encryption")]
+fn seal(secret: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let cipher = ChaCha20Poly1305::new_from_slice(secret).expect("Bad key length");
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+    match cipher.encrypt(&nonce, plaintext) {
+        Err(_) => Err(StoreError::SerializationFailed("Encryption failed".into())),
+        Ok(ciphertext) => {
+            let mut sealed = nonce.to_vec();
+
+            sealed.extend_from_slice(&ciphertext);
+
+            Ok(sealed)
+        }
+    }
+}
+
+/// Decrypts and authenticates the output of `seal`.
+#[cfg(feature = "encryption")]
+fn open(secret: &[u8], sealed: &[u8]) -> Result<Vec<u8>, StoreError> {
+    let cipher = ChaCha20Poly1305::new_from_slice(secret).expect("Bad key length");
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+
+    cipher.decrypt(Nonce::from_slice(nonce), ciphertext)
+        // Not `Corrupt`, which has zones start empty, as the key may just be wrong
+        .map_err(|_| StoreError::Unsupported("Decryption failed, wrong key or corrupt data".into()))
+}
+
+#[cfg(not(feature = "encryption"))]
+fn seal(_: &[u8], _: &[u8]) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("Encryption not compiled in".into()))
+}
+
+#[cfg(not(feature = "encryption"))]
+fn open(_: &[u8], _: &[u8]) -> Result<Vec<u8>, StoreError> {
+    Err(StoreError::Unsupported("Encryption not compiled in".into()))
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || ! s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+#[test]
+fn test_parse() {
+    let key = "00".repeat(KEY_LEN);
+    let keyring = Keyring::parse(&format!("# rotated 2017-09\n2:{}\n\n1:{}\n", key, key)).unwrap();
+
+    assert_eq!(keyring.current_id(), 2);
+    assert_eq!(format!("{:?}", keyring), "Keyring { ids: [2, 1] }");
+
+    assert_eq!(Keyring::parse(&format!("1:{},2:{}", key, key)).unwrap().current_id(), 1);
+
+    assert!(Keyring::parse("").is_err());
+    assert!(Keyring::parse("1:abcd").is_err());
+    assert!(Keyring::parse(&format!("moo:{}", key)).is_err());
+    assert!(Keyring::parse(&format!("1:{},1:{}", key, key)).is_err());
+}
+
+#[test]
+fn test_encrypt_decrypt() {
+    let old = Keyring::parse(&format!("1:{}", "01".repeat(KEY_LEN))).unwrap();
+    let new = Keyring::parse(&format!("2:{},1:{}", "02".repeat(KEY_LEN), "01".repeat(KEY_LEN))).unwrap();
+    let data = b"moo".to_vec();
+
+    if ! cfg!(feature = "encryption") {
+        assert!(old.encrypt(&data).is_err());
+        return;
+    }
+
+    let blob = old.encrypt(&data).unwrap();
+
+    assert!(is_encrypted(&blob));
+    assert_eq!(old.decrypt(&blob).unwrap(), data);
+
+    // Rotated keyring still reads data written with the old key, but not the other way around
+    assert_eq!(new.decrypt(&blob).unwrap(), data);
+    assert!(old.decrypt(&new.encrypt(&data).unwrap()).is_err());
+
+    // Tampered data fails authentication
+    let mut tampered = blob.clone();
+    let last = tampered.len() - 1;
+
+    tampered[last] ^= 1;
+
+    assert!(old.decrypt(&tampered).is_err());
+}
@@ -0,0 +1,76 @@
+//! Events from the Store, for components built on top of it.
+//!
+//! Replication, cache warming and the like need to know what the Store did, without being wired
+//! into every backend. `StoreHandle::subscribe` returns a channel receiving a `StoreEvent` for every
+//! zone loaded, written, deleted or failing to write, as the zone hears back from the Store, and for
+//! every compaction a backend finishes. Events go to every subscriber, in the order they happened
+//! for each zone. Direct data calls (`load_data`, `write_data` and friends) don't send any.
+//!
+//! Subscribers are dropped once their receiver is. Events queue up without limit for subscribers
+//! that don't keep up, so they should hand them off rather than do work inline.
+
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use path::Path;
+
+/// Something the Store did.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StoreEvent {
+    Compacted(Path),
+    Deleted(Path),
+    Loaded(Path),
+    WriteFailed(Path, String), // Why it failed
+    Written(Path)
+}
+
+/// Subscribers to a Store's events.
+#[derive(Default)]
+pub struct Events {
+    subscribers: Mutex<Vec<Sender<StoreEvent>>>
+}
+
+impl Events {
+    pub fn subscribe(&self) -> Receiver<StoreEvent> {
+        let (tx, rx) = channel();
+
+        self.subscribers.lock().unwrap().push(tx);
+
+        rx
+    }
+
+    /// Sends `event` to every subscriber, dropping those who went away.
+    pub fn emit(&self, event: StoreEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+
+        if subscribers.is_empty() {
+            return;
+        }
+
+        subscribers.retain(|tx| tx.send(event.clone()).is_ok());
+    }
+}
+
+#[test]
+fn test_events() {
+    let events = Events::default();
+
+    // Nobody listening
+    events.emit(StoreEvent::Loaded(path![moo]));
+
+    let first = events.subscribe();
+    let second = events.subscribe();
+
+    events.emit(StoreEvent::Written(path![moo]));
+
+    assert_eq!(first.try_recv(), Ok(StoreEvent::Written(path![moo])));
+    assert_eq!(second.try_recv(), Ok(StoreEvent::Written(path![moo])));
+    assert!(first.try_recv().is_err());
+
+    // Gone subscribers are dropped
+    drop(second);
+    events.emit(StoreEvent::Compacted(path![cow]));
+
+    assert_eq!(first.try_recv(), Ok(StoreEvent::Compacted(path![cow])));
+    assert_eq!(events.subscribers.lock().unwrap().len(), 1);
+}
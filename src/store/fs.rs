@@ -1,211 +1,421 @@
 //! A simple filesystem based zone store. For test use only.
+//!
+//! Data directories mirror the zone hierarchy: the zone at `moo.cow` keeps its data in
+//! `moo/cow/_zone`, next to the directories of its own children. Path components other than
+//! letters, digits, `-` and `#` are escaped (see `escape`), so a subtree of zones is browsed,
+//! listed and copied with plain file tools. Zone files in the flat layout of earlier versions are
+//! moved into place on startup.
+//!
+//! Zone files can be spread across several data directories with `STORE_FS_DIRS`, a comma
+//! separated list of directories to create a `data_<id>` directory in. A zone's data directory is
+//! picked by a hash of its directory, so zone files misplaced by a change in directories are moved
+//! on startup. The first directory holds the WAL.
+//!
+//! Diffs are logged to a write-ahead log (see `store::wal`) in the data directory and replayed on
+//! load, with each zone file write acting as a checkpoint. WAL entries are encoded (compressed and
+//! encrypted) the same way as zone files.
+//!
+//! `Durability` controls whether zone files are flushed to disk before zones are notified of the
+//! write, in the background, or not at all.
+//!
+//! Zone files keep tombstones of deleted data. `StoreCall::Compact` rewrites a zone file without
+//! tombstones that can no longer affect merges. Compaction excludes concurrent writes, so it never
+//! overwrites newer zone data.
+//!
+//! `StoreCall::Delete` removes a zone file, and checkpoints its WAL entries so they are not
+//! replayed into the zone again.
+//!
+//! Writes are refused once the data directory is over its `Quota` (see `store::quota`). A read-only
+//! store (see `store::read_only`) leaves the data directory exactly as it finds it.
+//!
+//! With `STORE_DEDUP`, zone files with identical data are hard links to one shared blob (see
+//! `store::dedup`).
+//!
+//! `StoreCall::WriteAtomic` commits by writing a manifest of the zone files it replaces, and writes
+//! interrupted after committing are finished on startup.
+//!
+//! `StoreCall::WriteDelta` appends to a `.delta` log next to the zone file (see `store::delta`),
+//! which is replayed on load and removed by the next full write or compaction.
+//!
+//! With the `mmap` feature, zone files are deserialized straight from a mapping of the file instead
+//! of being read into memory first, which saves a copy per zone when loading many of them.
+//!
+//! Zone files of at least `stream::STREAM_SIZE` are streamed to their zone in chunks, instead of
+//! being loaded in one go (see `store::stream`).
+//!
+//! With `STORE_HISTORY`, zone files are kept in a `_history` directory before they are replaced or
+//! removed (see `store::history`), and `StoreCall::Restore` brings back the ones current at a point
+//! in time. Restores exclude writes like compactions do.
+//!
+//! With the `watch` feature and `STORE_FS_WATCH` set, data directories are watched for zone files
+//! changed by other processes, e.g. restored from a backup by an operator, and their zones reload
+//! them (see `Zone::reload`). The store's own writes replace zone files by renaming a temporary
+//! file over them, and are told apart by the rename. This relies on inotify reporting both ends
+//! of a rename, so elsewhere zones may also reload after their own writes.
 
 use std;
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, HashSet, VecDeque};
 use std::collections::hash_map::DefaultHasher;
+use std::env;
 use std::error::Error;
 use std::fs::{DirBuilder, File};
 use std::hash::{Hash, Hasher};
-use std::io::ErrorKind;
+use std::io::{self, ErrorKind};
 use std::io::prelude::*;
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::mpsc::Sender;
 use std::thread;
+use std::time::{Duration, Instant};
 
-use bincode;
-use threadpool::ThreadPool;
+#[cfg(test)] use bincode;
+#[cfg(feature = "mmap")] use memmap::Mmap;
+#[cfg(feature = "watch")] use notify::{self, RawEvent, RecursiveMode, Watcher};
+#[cfg(feature = "watch")] use notify::op::Op;
 
 use super::*;
+use super::backend;
+use super::codec::Codec;
+use super::dedup::{self, Dedup};
+use super::events::StoreEvent;
+use super::delta;
+use super::history::{self, History, Retention};
+use super::migrate;
+use super::quota::Usage;
+use super::raw::RawZone;
+use super::retry::RetryPolicy;
+use super::throttle::Throttle;
+use super::stats::{Metrics, StoreStats};
+use super::stream::{self, Chunk};
+use super::verify;
+use super::wal::{self, Wal};
+use super::workers::Workers;
 use app::{App, AppHandle};
 use path::Path;
 use zone::{ZoneData, ZoneHandle};
 
 const NUM_THREADS: usize = 50;
 
+/// Zone files of at least this size are mapped rather than read, with the `mmap` feature
+#[cfg(feature = "mmap")] const MMAP_SIZE: u64 = 64 * 1024;
+#[cfg(not(feature = "mmap"))] const MMAP_SIZE: u64 = std::u64::MAX;
+
+/// Name of the zone file in each zone directory (see `zonedir`)
+const ZONE_FILE: &'static str = "_zone";
+
+/// Longest escaped path component (see `escape`)
+const MAX_DIR_NAME: usize = 200;
+
+/// Zone files written by an atomic write, once it is committed, in the first data directory
+const MANIFEST: &'static str = "atomic.manifest";
+
 pub struct FS {
     app: AppHandle,
 
-    dir: std::path::PathBuf,
-    rx: Receiver<StoreCall>,
+    dirs: Vec<std::path::PathBuf>, // Zone files are spread across these, WAL in the first one
+    codec: Codec,
+    durability: Durability,
+
+    // Loads and writes for each zone run in order
+    workers: Workers,
+
+    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>,
+
+    wal: Arc<Mutex<Wal>>,
+
+    // Held shared by writes, exclusively by compaction
+    compaction: Arc<RwLock<()>>,
 
-    read_pool: ThreadPool,
-    write_pool: ThreadPool,
+    // Files written but not yet flushed, with `Durability::Interval`
+    unsynced: Arc<Mutex<HashSet<std::path::PathBuf>>>,
 
-    write_queue: Arc<Mutex<VecDeque<ZoneHandle>>>
+    usage: Arc<Usage>,
+
+    // Failed writes, deletes and deltas are tried again
+    retry: RetryPolicy,
+    throttle: Arc<Throttle>,
+
+    // Zone files kept before they are replaced
+    history: Arc<History>,
+
+    // Zone files shared between zones with identical data
+    dedup: Arc<Dedup>,
+
+    metrics: Arc<Metrics>,
+
+    // Leave corrupt files in place
+    read_only: bool
 }
 
 impl FS {
     /// Start the Store "process".
-    pub fn spawn(app: &mut App) {
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
         // TODO: take serializer as parameter?
-        let dir = format!("data_{}", app.id);
-        let channel = app.channels.store.take().expect("Receiver already taken");
-        let store = FS::new(app.handle(), &dir, channel);
-
-        thread::spawn(move|| {
-            store.message_loop();
-        });
-    }
+        let dirs = FS::dirs(app);
+        let store = FS::sharded(app.handle(), &dirs, config);
 
-    pub fn new(app: AppHandle, dir: &str, channel: StoreChannel) -> FS {
-        let dir = std::path::PathBuf::from(dir);
-
-        if ! dir.is_dir() {
-            DirBuilder::new().recursive(true).create(&dir).unwrap();
+        #[cfg(feature = "watch")]
+        {
+            if env::var("STORE_FS_WATCH").is_ok() {
+                watch(app.handle(), store.dirs.clone(), store.codec.clone());
+            }
         }
 
-        FS {
-            app: app,
-            dir: dir,
-            rx: channel.rx,
-            read_pool: ThreadPool::new(NUM_THREADS),
-            write_pool: ThreadPool::new(NUM_THREADS),
-            write_queue: Arc::new(Mutex::new(VecDeque::new()))
-        }
+        backend::spawn(Box::new(store), channel, config);
     }
 
-    fn message_loop(self) {
-        loop {
-            let call = self.rx.recv().unwrap();
+    /// Data directories for `app`: `data_<id>` in each of the directories in `STORE_FS_DIRS`, or
+    /// in the working directory.
+    pub fn dirs(app: &App) -> Vec<String> {
+        let name = format!("data_{}", app.id);
 
-            match call {
-                StoreCall::List(reply) => self.list(reply),
-                StoreCall::Load(zone, path) => self.load(zone, path),
-                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
-                StoreCall::RequestWrite(zone) => self.request_write(zone),
-                StoreCall::Write(zone, path, data) => self.write(zone, path, data)
-            }
+        match env::var("STORE_FS_DIRS") {
+            Err(_) => vec![name],
+            Ok(roots) => roots.split(',')
+                .map(|root| std::path::Path::new(root.trim()).join(&name).to_string_lossy().into_owned())
+                .collect()
         }
     }
 
-    /// Lists all Zone Paths stored locally
-    pub fn list(&self, tx: Sender<Path>) {
-        let entries = match std::fs::read_dir(&self.dir) {
-            Err(err) => {
-                error!("Error listing directory.");
-                error!("  {:?}", err);
-                return;
-            },
-            Ok(entries) => entries
-        };
+    pub fn new(app: AppHandle, dir: &str, config: &Config) -> FS {
+        FS::sharded(app, &[dir.to_string()], config)
+    }
 
-        for entry in entries {
-            let entry = match entry {
-                Err(err) => {
-                    error!("Error reading entry.");
-                    error!("  {:?}", err);
-                    return;
-                },
-                Ok(entry) => entry
-            };
+    /// Same as `new`, spreading zone files across `dirs`.
+    pub fn sharded(app: AppHandle, dirs: &[String], config: &Config) -> FS {
+        assert!(! dirs.is_empty(), "No data directories");
 
+        let dirs: Vec<_> = dirs.iter().map(std::path::PathBuf::from).collect();
+        let dir = dirs[0].clone();
 
-            match blocking_read(&entry.path()) {
-                Err(err) => {
-                    error!("Error loading {:?}: {}", entry, err.description());
-                    error!("  {:?}", err);
-                },
-                Ok(node) => {
-                    tx.send(node.path).unwrap();
+        let read_only = config.read_only;
+
+        if ! read_only {
+            for dir in &dirs {
+                if ! dir.is_dir() {
+                    DirBuilder::new().recursive(true).create(dir).unwrap();
                 }
             }
         }
-    }
-
-    /// Loads data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn load(&self, zone: ZoneHandle, path: Path) {
-        let mut filepath = self.dir.clone();
-
-        self.app.stats.store.reads_pending.increment();
 
-        let stats = self.app.stats.clone();
+        let wal = if read_only {
+            if dir.join(MANIFEST).is_file() {
+                warn!("Interrupted atomic write in {}, not finished while read-only", dir.display());
+            }
 
-        self.read_pool.execute(move|| {
-            debug!("Loading: {:?}", path);
+            Wal::open_read_only(&dir.join("wal.log"))
+        }
+        else {
+            commit_manifest(&dir);
 
-            let filename = zonefilename(&path);
+            for dir in &dirs {
+                remove_tmp_files(dir);
+            }
 
-            filepath.push(filename);
+            relayout(&dirs, &config.codec);
+            rebalance(&dirs);
 
-            debug!("reading {}", filepath.display());
+            let removed = Dedup::new(&dirs).sweep();
 
-            match blocking_read(&*filepath) {
-                Err(err) => {
-                    error!("Error loading {:?} - {}: {}", path, filepath.display(), err.description());
-                    error!("{:?}", err);
-                    stats.store.reads_errors.increment();
-                    // TODO: set Zone to error state
-                    //zone.set_error(err);
-                },
-                Ok(node) => zone.loaded(node)
-            };
+            if removed > 0 {
+                info!("Removed {} unused blobs", removed);
+            }
+            Wal::open(&dir.join("wal.log"))
+        };
 
-            stats.store.reads_pending.decrement();
-            stats.store.reads.increment();
-        });
-    }
+        let wal = wal.expect("Could not open WAL");
+        let used = if read_only { 0 } else { dirs.iter().map(|dir| dir_size(dir)).sum() };
+        let usage = Usage::new(config.quota, used, wal.size());
 
-    /// Asynchronously load and send `ZoneData` for `Path` to channel.
-    pub fn load_data(&self, path: Path, tx: Sender<Option<ZoneData>>) {
-        let mut filepath = self.dir.clone();
+        if usage.is_low() {
+            warn!("Store is low on space: {} bytes used, quota {}", usage.used(), config.quota);
+        }
 
-        self.read_pool.execute(move|| {
-            debug!("Loading: {:?}", path);
+        let unsynced = Arc::new(Mutex::new(HashSet::new()));
 
-            let filename = zonefilename(&path);
+        if let Durability::Interval(ms) = config.durability {
+            let dirs = dirs.clone();
+            let unsynced = unsynced.clone();
 
-            filepath.push(filename);
+            thread::spawn(move|| {
+                flush_loop(&dirs, &unsynced, Duration::from_millis(ms));
+            });
+        }
 
-            debug!("reading {}", filepath.display());
+        let dedup = match (config.dedup, config.history) {
+            (false, _) => Dedup::default(),
+            (true, Retention::None) if config.codec.keyring.is_none() => Dedup::new(&dirs),
+            (true, _) => {
+                warn!("Not deduplicating zone data: not supported with encryption or history");
+                Dedup::default()
+            }
+        };
 
-            tx.send(blocking_read(&*filepath).ok()).is_ok(); // ignore if caller goes away
-        });
+        FS {
+            app: app,
+            dirs: dirs,
+            codec: config.codec.clone(),
+            durability: config.durability,
+            workers: Workers::new(config.workers.threads_or(NUM_THREADS)),
+            write_queue: Arc::new(Mutex::new(VecDeque::new())),
+            wal: Arc::new(Mutex::new(wal)),
+            compaction: Arc::new(RwLock::new(())),
+            unsynced: unsynced,
+            usage: Arc::new(usage),
+            retry: config.retry,
+            throttle: Arc::new(Throttle::new(config.throttle)),
+            history: Arc::new(History::new(config.history)),
+            dedup: Arc::new(dedup),
+            metrics: Default::default(),
+            read_only: read_only
+        }
     }
 
-    /// Request for notification to write data.
-    pub fn request_write(&self, zone: ZoneHandle) {
-        if self.write_pool.active_count() >= NUM_THREADS {
-            // No write slots available, save for later
-            self.write_queue.lock().unwrap().push_back(zone);
-        }
-        else {
-            zone.save();
-        }
+    /// Data directory holding the file for the zone at `path`.
+    fn shard_dir(&self, path: &Path) -> std::path::PathBuf {
+        self.dirs[shard(path, self.dirs.len())].clone()
     }
 
-    /// Write data for a `Zone` asynchronously, notifying its handle when done.
-    pub fn write(&self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
-        let path = path.clone();
-        let mut filepath = self.dir.clone();
+    /// Writes a batch of zones on worker thread `lane`. Each directory is synced and the WAL
+    /// checkpointed once for the whole batch.
+    fn write_lane(&self, lane: usize, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        let dirs = self.dirs.clone();
+        let count = writes.len();
 
         let pending = self.write_queue.clone();
 
-        self.app.stats.store.writes_pending.increment();
+        // This batch checkpoints all WAL entries logged so far
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
+
+        let compaction = self.compaction.clone();
+        let codec = self.codec.clone();
+        let durability = self.durability;
+        let unsynced = self.unsynced.clone();
+        let usage = self.usage.clone();
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let history = self.history.clone();
+        let dedup = self.dedup.clone();
+        let metrics = self.metrics.clone();
+
+        for _ in 0..count {
+            self.app.stats.store.writes_pending.increment();
+        }
 
         let stats = self.app.stats.clone();
 
-        self.write_pool.execute(move|| {
-            debug!("Writing: {:?}", path);
+        self.workers.execute(lane, move|| {
+            debug!("Writing batch of {} zones", count);
 
-            let filename = zonefilename(&path);
+            throttle.wait(count, writes.iter().map(|&(_, _, ref data)| data.len()).sum());
 
-            filepath.push(filename);
+            let started = Instant::now();
+            let sync = durability == Durability::Always;
+            let mut written = Vec::with_capacity(count);
+            let mut bytes = 0;
 
-            debug!("writing {}", filepath.display());
+            {
+                let _lock = compaction.read().unwrap();
+                let mut tmp_files = Vec::with_capacity(count);
 
-            match blocking_write(&*filepath, data) {
-                Err(err) => {
-                    error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
-                    error!("{:?}", err);
-                    stats.store.writes_errors.increment();
-                    // TODO set Zone to error state
-                    //zone.set_error(err);
-                },
-                Ok(_) => zone.saved()
-            };
+                for (zone, path, data) in writes {
+                    let filepath = dirs[shard(&path, dirs.len())].join(zonefilename(&path));
 
-            stats.store.writes_pending.decrement();
-            stats.store.writes.increment();
+                    let old = file_len(&filepath);
+
+                    let result = codec.encode(data).and_then(|blob| {
+                        let new = blob.len() as u64;
+
+                        try!(usage.check_write(old, new));
+
+                        retry.run(&format!("writing {:?}", path), || write_shared_tmp(&filepath, &blob, sync, &dedup))
+                            .map(|tmp_path| (tmp_path, new))
+                    });
+
+                    match result {
+                        Err(err) => {
+                            error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
+                            error!("{:?}", err);
+                            stats.store.writes_errors.increment();
+                            zone.write_failed(err);
+                        },
+                        Ok((tmp_path, new)) => tmp_files.push((zone, path, filepath, tmp_path, old, new))
+                    }
+                }
+
+                for (zone, path, filepath, tmp_path, old, new) in tmp_files {
+                    history.keep(&filepath);
+
+                    let result = retry.run(&format!("writing {:?}", path), || replace(&tmp_path, &filepath, false))
+                        .and_then(|_| {
+                            usage.resize(old, new);
+                            clear_deltas(&filepath, &usage)
+                        });
+
+                    match result {
+                        Err(err) => {
+                            error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
+                            stats.store.writes_errors.increment();
+                            zone.write_failed(err);
+                        },
+                        Ok(_) => {
+                            bytes += new;
+                            written.push((zone, path, filepath))
+                        }
+                    }
+                }
+
+                // One sync per directory covers all the renames
+                if sync && ! written.is_empty() {
+                    let synced: HashSet<_> = written.iter()
+                        .filter_map(|&(_, _, ref filepath)| filepath.parent().map(|dir| dir.to_path_buf()))
+                        .collect();
+
+                    let failed = synced.iter().any(|dir| match retry.run(&format!("flushing {}", dir.display()), || sync_dir(dir)) {
+                        Err(err) => {
+                            error!("Error flushing {}: {}", dir.display(), err.description());
+                            true
+                        },
+                        Ok(_) => false
+                    });
+
+                    if failed {
+                        for (zone, path, _) in written.drain(..) {
+                            stats.store.writes_errors.increment();
+                            zone.write_failed(StoreError::Io(io::Error::new(ErrorKind::Other, format!("Could not flush {:?}", path))));
+                        }
+                    }
+                }
+            }
+
+            // Flushed later by `flush_loop`
+            if let Durability::Interval(_) = durability {
+                let mut unsynced = unsynced.lock().unwrap();
+
+                for &(_, _, ref filepath) in &written {
+                    unsynced.insert(filepath.clone());
+                }
+            }
+
+            let paths: Vec<Path> = written.iter().map(|&(_, ref path, _)| path.clone()).collect();
+
+            if let Err(err) = wal.lock().unwrap().checkpoint_batch(&paths, seq) {
+                error!("Error checkpointing WAL for batch of {} zones: {}", paths.len(), err.description());
+            }
+
+            // One write as far as latency goes
+            if ! written.is_empty() {
+                metrics.written(bytes, started);
+            }
+
+            for (zone, _, _) in written {
+                zone.saved();
+            }
+
+            for _ in 0..count {
+                stats.store.writes_pending.decrement();
+                stats.store.writes.increment();
+            }
 
             let mut pending = pending.lock().unwrap();
 
@@ -215,185 +425,2372 @@ impl FS {
             }
         });
     }
-}
-
-fn blocking_read(filepath: &std::path::Path) -> Result<ZoneData, StoreError> {
-    debug!("blocking_read: {:?}", filepath);
 
-    let mut file = match File::open(filepath) {
-        Err(err) => {
-            match err.kind() {
-                ErrorKind::NotFound => {
-                    return Ok(Default::default())
+    fn list_dir(&self, dir: &std::path::Path, paths: &mut Vec<Path>) {
+        for filepath in zone_files(dir) {
+            match blocking_read(&filepath, &self.codec) {
+                Err(err) => {
+                    error!("Error loading {}: {}", filepath.display(), err.description());
+                    error!("  {:?}", err);
                 },
-                _ => {
-                    error!("  Error loading {}: {}", filepath.display(), err.description());
-                    error!("    {:?}", err);
+                Ok(node) => {
+                    paths.push(node.path);
                 }
             }
+        }
+    }
+}
 
-            error!("IO error: {}", err.description());
+impl StoreBackend for FS {
+    /// Logs a diff merged into a `Zone` to the WAL.
+    fn append(&mut self, path: Path, diff: Vec<u8>) {
+        let result = self.codec.encode(diff).and_then(|entry| {
+            let mut wal = self.wal.lock().unwrap();
 
-            return Err(StoreError::ReadError(Box::new(err)));
-        },
-        Ok(file) => file,
-    };
+            try!(self.usage.check_log(entry.len() as u64));
+            try!(wal.append(&path, entry).map_err(StoreError::Io));
 
-    let mut buffer = Vec::new();
+            self.usage.set_log(wal.size());
 
-    // read the whole file
-    if let Err(err) = file.read_to_end(&mut buffer) {
-        return Err(StoreError::ReadError(Box::new(err)));
-    }
+            Ok(())
+        });
 
-    match bincode::deserialize(&buffer) {
-        Err(err) => {
-            error!("err {}:", err.description());
-            Err(StoreError::ReadError(Box::new(err)))
-        },
-        Ok(data) => Ok(data)
+        if let Err(err) = result {
+            error!("Error appending to WAL for {:?}: {}", path, err.description());
+            error!("  {:?}", err);
+        }
     }
-}
-
-fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>) -> Result<(), StoreError> {
-    debug!("blocking_write: {:?}", filepath);
 
-    let tmp_path = filepath.with_extension("tmp");
-
-    let mut file = match File::create(&tmp_path) {
-        Err(err) => {
-            error!("  Error loading {}: {}", filepath.display(), err.description());
-            error!("    {:?}", err);
+    /// Rewrites the file for a `Zone` asynchronously, dropping tombstones that no longer affect
+    /// merges.
+    fn compact(&mut self, path: Path) {
+        let mut filepath = self.shard_dir(&path);
+        let compaction = self.compaction.clone();
+        let codec = self.codec.clone();
+        let usage = self.usage.clone();
+        let history = self.history.clone();
+        let dedup = self.dedup.clone();
+        let store = self.app.store.clone();
 
-            error!("IO error: {}", err.description());
+        self.workers.execute(self.workers.lane(&path), move|| {
+            filepath.push(zonefilename(&path));
 
-            return Err(StoreError::ReadError(Box::new(err)));
-        },
-        Ok(file) => file,
-    };
+            // Wait for in-flight writes, and hold off new ones
+            let _lock = compaction.write().unwrap();
 
-    if let Err(err) = file.write_all(&serialized) {
-        return Err(StoreError::WriteError(Box::new(err)));
+            match blocking_compact(&*filepath, &codec, &usage, &history, &dedup) {
+                Err(err) => {
+                    error!("Error compacting {:?} - {}: {}", path, filepath.display(), err.description());
+                    error!("{:?}", err);
+                },
+                Ok(_) => store.emit(StoreEvent::Compacted(path))
+            }
+        });
     }
 
-    if let Err(err) = std::fs::rename(&tmp_path, &filepath) {
-        return Err(StoreError::WriteError(Box::new(err)));
-    }
+    /// Deletes the file for a `Zone` asynchronously, notifying its handle when done.
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        let mut filepath = self.shard_dir(&path);
 
-    Ok(())
-}
+        // Diffs logged before the delete are gone with the file
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
 
-fn zonefilename(path: &Path) -> String {
-    let zonename = path.path.join(".");
-    let mut filename = String::from("r");
+        let compaction = self.compaction.clone();
+        let durability = self.durability;
+        let usage = self.usage.clone();
+        let retry = self.retry;
+        let history = self.history.clone();
 
-    if path.len() > 0 { 
-        filename.push_str(&zonename);
-    }
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
 
-    // Truncate and remove unsafe characters
-    filename.truncate(80);
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting: {:?}", path);
 
-    let mut filename: String = filename.chars().map(|x| match x { 
-        '#' | '0'...'9' | 'A'...'Z' | 'a'...'z' => x,
-        _  => '_'
-    }).collect();
+            filepath.push(zonefilename(&path));
 
-    filename.push_str("_");
+            let result = retry.run(&format!("deleting {:?}", path), || {
+                let _lock = compaction.read().unwrap();
 
-    // Add a unique hash
-    let mut hasher = DefaultHasher::new();
-    
-    zonename.hash(&mut hasher);
-    filename.push_str(&format!("{:X}", hasher.finish()));
+                history.keep(&filepath);
+                delete_zone_file(&*filepath, durability == Durability::Always, &usage)
+            });
 
-    filename
-}
+            match result {
+                Err(err) => {
+                    error!("Error deleting {:?} - {}: {}", path, filepath.display(), err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => {
+                    if let Err(err) = wal.lock().unwrap().checkpoint(&path, seq) {
+                        error!("Error checkpointing WAL for {:?}: {}", path, err.description());
+                    }
 
-#[test]
-fn test_read_write() {
-    let dir = std::path::PathBuf::from("test_data/read_write");
+                    zone.deleted()
+                }
+            };
 
-    if ! dir.is_dir() {
-        DirBuilder::new().recursive(true).create(&dir).unwrap();
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+        });
     }
 
-    let mut file = dir.clone();
+    /// Deletes the file for `path` and its WAL entries asynchronously, replying whether it
+    /// succeeded.
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
 
-    file.push("test_read_write");
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
 
-    std::fs::remove_file(&file).ok();
+        let compaction = self.compaction.clone();
+        let durability = self.durability;
+        let usage = self.usage.clone();
+        let history = self.history.clone();
 
-    let data = blocking_read(&file).unwrap();
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting data: {:?}", path);
 
-    assert_eq!(data, Default::default());
+            let result = {
+                let _lock = compaction.read().unwrap();
 
-    let limit = bincode::Infinite;
-    let serialized = bincode::serialize(&data, limit).unwrap();
+                history.keep(&filepath);
+                delete_zone_file(&*filepath, durability == Durability::Always, &usage)
+            };
 
-    blocking_write(&file, serialized).unwrap();
+            let deleted = match result {
+                Err(err) => {
+                    error!("Error deleting {:?} - {}: {}", path, filepath.display(), err.description());
+                    false
+                },
+                Ok(_) => {
+                    if let Err(err) = wal.lock().unwrap().checkpoint(&path, seq) {
+                        error!("Error checkpointing WAL for {:?}: {}", path, err.description());
+                    }
 
-    assert_eq!(blocking_read(&file).unwrap(), data);
+                    true
+                }
+            };
 
-    use node::{Node, NodeTree, Vis};
-    use serde_json::Value as JSON;
+            reply.send(deleted).is_ok(); // ignore if caller goes away
+        });
+    }
 
-    let expected = ZoneData::new(
-        Path::empty(),
-        NodeTree {
-            vis: Vis::update(1000),
-            node: Node::expand(JSON::String(String::from("moo")), 1000)
-        }
-    );
+    /// Lists all Zone Paths stored locally under `prefix`, in all data directories, in order (see
+    /// `path`). Only the directory of `prefix` is walked, in each of them.
+    fn list(&mut self, prefix: Path, tx: Sender<Path>) {
+        let mut paths = vec![];
 
-    let limit = bincode::Infinite;
-    let serialized = bincode::serialize(&expected, limit).unwrap();
+        for dir in &self.dirs {
+            let root = dir.join(zonedir(&prefix));
 
-    blocking_write(&file, serialized).unwrap();
+            if root.is_dir() {
+                self.list_dir(&root, &mut paths);
+            }
+        }
 
-    let verify = blocking_read(&file).unwrap();
+        paths.sort();
 
-    assert_eq!(verify, expected);
-}
+        for path in paths {
+            tx.send(path).unwrap();
+        }
+    }
 
-#[test]
-fn test_list() {
-    let dir = std::path::PathBuf::from("test_data/list");
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done. Corrupt data is
+    /// salvaged in part if it can be, unless it is streamed, and moved aside otherwise.
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        let mut filepath = self.shard_dir(&path);
+        let entries = self.wal.lock().unwrap().entries(&path);
+        let codec = self.codec.clone();
+        let read_only = self.read_only;
+        let metrics = self.metrics.clone();
 
-    if dir.exists() {
-        std::fs::remove_dir_all(dir).unwrap();
-    }
+        self.app.stats.store.reads_pending.increment();
 
-    let chan = StoreChannel::new();
+        let stats = self.app.stats.clone();
 
-    let app = App::new("127.0.0.1:42".parse().unwrap());
-    let store = FS::new(app.handle(), "127.0.0.1:42", chan);
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
 
-    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
-    let limit = bincode::Infinite;
+            let started = Instant::now();
 
-    for i in 0..3 {
-        let path = Path::new(vec![i.to_string()]);
-        let zone_data = ZoneData::new(path.clone(), Default::default());
+            let filename = zonefilename(&path);
 
-        let serialized = bincode::serialize(&zone_data, limit).unwrap();
+            filepath.push(filename);
 
-        store.write(noop_zone.clone(), path, serialized);
-    }
+            debug!("reading {}", filepath.display());
 
-    std::thread::sleep(std::time::Duration::from_millis(200));
+            // Large zones deserialize their data themselves, as it is read
+            let streamed = file_len(&filepath) >= stream::STREAM_SIZE;
 
-    let (tx, rx) = channel();
+            let result = if streamed {
+                blocking_stream(&filepath, &codec, entries, &zone)
+            }
+            else {
+                match blocking_load(&*filepath, &codec) {
+                    Ok(mut data) => {
+                        wal::replay(&mut data, decode_entries(&codec, entries));
+                        Ok(zone.loaded(data))
+                    },
+                    Err(err @ StoreError::Corrupt { .. }) => match blocking_recover(&*filepath, &codec, decode_entries(&codec, entries)) {
+                        Some(data) => {
+                            // The zone quarantines the file itself, before writing what was salvaged
+                            error!("Recovered part of {:?} - {}: {}", path, filepath.display(), err);
+                            stats.store.reads_corrupt.increment();
+                            Ok(zone.recovered(data))
+                        },
+                        None => Err(err)
+                    },
+                    Err(err) => Err(err)
+                }
+            };
 
-    store.list(tx);
+            match result {
+                Err(StoreError::Corrupt { reason, .. }) => {
+                    error!("Corrupt data for {:?} - {}: {}", path, filepath.display(), reason);
+                    stats.store.reads_corrupt.increment();
 
-    let mut paths: Vec<Path> = rx.iter().collect();
+                    if ! read_only {
+                        quarantine(&*filepath);
+                    }
 
-    paths.sort();
+                    if ! streamed {
+                        zone.corrupt();
+                    }
+                },
+                Err(err) => {
+                    error!("Error loading {:?} - {}: {}", path, filepath.display(), err.description());
+                    error!("{:?}", err);
+                    stats.store.reads_errors.increment();
+                    // TODO: set Zone to error state
+                    //zone.set_error(err);
+                },
+                Ok(_) => metrics.loaded(zone_file_len(&filepath), started)
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Loads data for many `Zone`s, data directory by data directory in path order, so zones stored
+    /// under the same directory are read one after the other.
+    fn load_many(&mut self, mut zones: Vec<(ZoneHandle, Path)>) {
+        let count = self.dirs.len();
+
+        zones.sort_by_key(|&(_, ref path)| (shard(path, count), zonefilename(path)));
+
+        for (zone, path) in zones {
+            self.load(zone, path);
+        }
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&mut self, path: Path, tx: Reply<Option<ZoneData>>) {
+        let mut filepath = self.shard_dir(&path);
+        let entries = self.wal.lock().unwrap().entries(&path);
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            let started = Instant::now();
+            let filename = zonefilename(&path);
+
+            filepath.push(filename);
+
+            debug!("reading {}", filepath.display());
+
+            let data = blocking_load(&*filepath, &codec).ok().map(|mut data| {
+                metrics.loaded(zone_file_len(&filepath), started);
+                wal::replay(&mut data, decode_entries(&codec, entries));
+                data
+            });
+
+            tx.send(data).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Asynchronously load and send data for `Path` to channel, still serialized.
+    fn load_raw(&mut self, path: Path, reply: Reply<Option<RawZone>>) {
+        let mut filepath = self.shard_dir(&path);
+        let entries = self.wal.lock().unwrap().entries(&path);
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading raw: {:?}", path);
+
+            let started = Instant::now();
+
+            filepath.push(zonefilename(&path));
+
+            let raw = blocking_load_raw(&*filepath, &codec).ok().map(|mut raw| {
+                metrics.loaded(zone_file_len(&filepath), started);
+                raw.entries = decode_entries(&codec, entries);
+                raw
+            });
+
+            reply.send(raw).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Moves the file for `path` aside asynchronously, replying whether it succeeded. Its WAL
+    /// entries are dropped, as the zone will not be loaded again.
+    fn quarantine(&mut self, path: Path, reply: Reply<bool>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
+
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
+
+        let compaction = self.compaction.clone();
+        let durability = self.durability;
+        let history = self.history.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Quarantining: {:?}", path);
+
+            let result = {
+                let _lock = compaction.read().unwrap();
+
+                history.keep(&filepath);
+                quarantine_zone_file(&filepath, durability == Durability::Always)
+            };
+
+            let moved = match result {
+                Err(err) => {
+                    error!("Error quarantining {:?} - {}: {}", path, filepath.display(), err.description());
+                    false
+                },
+                Ok(_) => {
+                    if let Err(err) = wal.lock().unwrap().checkpoint(&path, seq) {
+                        error!("Error checkpointing WAL for {:?}: {}", path, err.description());
+                    }
+
+                    true
+                }
+            };
+
+            reply.send(moved).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        if self.workers.active_count() >= self.workers.threads() {
+            // No write slots available, save for later
+            self.write_queue.lock().unwrap().push_back(zone);
+        }
+        else {
+            zone.save();
+        }
+    }
+
+    /// Restores the file for `path`, or all of them, to the version kept in history that was
+    /// current at `timestamp`, asynchronously, replying with the number of files changed. WAL
+    /// entries of restored zones are dropped, they were logged after the version was written.
+    fn restore(&mut self, path: Option<Path>, timestamp: u64, reply: Reply<usize>) {
+        let dirs = self.dirs.clone();
+        let filepath = path.as_ref().map(|path| self.shard_dir(path).join(zonefilename(path)));
+
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
+
+        let compaction = self.compaction.clone();
+        let durability = self.durability;
+        let usage = self.usage.clone();
+        let history = self.history.clone();
+
+        // Restoring them all takes the compaction lock, which holds up writes on other threads
+        let lane = self.workers.lane(path.as_ref().unwrap_or(&Path::default()));
+
+        self.workers.execute(lane, move|| {
+            debug!("Restoring {:?} to {}", path, timestamp);
+
+            // Every zone stored now, or at any point kept in history
+            let filepaths = match filepath {
+                Some(filepath) => vec![filepath],
+                None => {
+                    let mut filepaths: Vec<_> = dirs.iter().flat_map(|dir| {
+                        let mut filepaths = zone_files(dir);
+
+                        for dir in tree(dir) {
+                            filepaths.extend(history::zone_files(&dir));
+                        }
+
+                        filepaths
+                    }).collect();
+
+                    filepaths.sort();
+                    filepaths.dedup();
+                    filepaths
+                }
+            };
+
+            let mut restored = 0;
+
+            {
+                // Zones are restored all together, without writes in between
+                let _lock = compaction.write().unwrap();
+
+                for filepath in filepaths {
+                    match blocking_restore(&filepath, timestamp, durability == Durability::Always, &usage, &history) {
+                        Err(err) => error!("Error restoring {}: {}", filepath.display(), err.description()),
+                        Ok(true) => restored += 1,
+                        Ok(false) => ()
+                    }
+                }
+            }
+
+            let mut wal = wal.lock().unwrap();
+
+            let paths = match path {
+                Some(path) => vec![path],
+                None => wal.paths()
+            };
+
+            if let Err(err) = wal.checkpoint_batch(&paths, seq) {
+                error!("Error checkpointing WAL for {} restored zones: {}", paths.len(), err.description());
+            }
+
+            reply.send(restored).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Waits for loads and writes running on worker threads, then flushes zone files written with
+    /// `Durability::Interval` and the WAL without waiting for the next flush. With
+    /// `Durability::Never`, flushing is left to the OS as usual.
+    fn shutdown(&mut self) {
+        self.workers.drain();
+
+        if self.durability == Durability::Never {
+            return;
+        }
+
+        flush_unsynced(&self.dirs, &self.unsynced);
+
+        if let Err(err) = self.wal.lock().unwrap().sync() {
+            error!("Error flushing WAL: {}", err.description());
+        }
+    }
+
+    /// Looks up the file for `path` asynchronously, without reading it. WAL entries not yet
+    /// written to the file count towards its size.
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
+        let logged = self.wal.lock().unwrap().pending_bytes(&path);
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            reply.send(blocking_stat(&filepath, logged)).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// I/O metrics, including loads and writes waiting for a thread.
+    fn stats(&mut self) -> StoreStats {
+        let mut stats = self.metrics.snapshot();
+
+        stats.queue_depth = self.workers.queued_count() + self.write_queue.lock().unwrap().len();
+        stats
+    }
+
+    /// Reads the file for `path` and its deltas asynchronously, replying whether they pass their
+    /// checksums and deserialize.
+    fn verify(&mut self, path: Path, reply: Reply<Result<(), StoreError>>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
+        let codec = self.codec.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            let result = blocking_load(&filepath, &codec).and_then(|data| verify::check(&path, &data));
+
+            reply.send(result).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        let path = path.clone();
+        let mut filepath = self.shard_dir(&path);
+
+        let pending = self.write_queue.clone();
+
+        // This write checkpoints all WAL entries logged so far
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
+
+        let compaction = self.compaction.clone();
+        let codec = self.codec.clone();
+        let durability = self.durability;
+        let unsynced = self.unsynced.clone();
+        let usage = self.usage.clone();
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let history = self.history.clone();
+        let dedup = self.dedup.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing: {:?}", path);
+
+            throttle.wait(1, data.len());
+
+            let filename = zonefilename(&path);
+
+            filepath.push(filename);
+
+            debug!("writing {}", filepath.display());
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+
+                retry.run(&format!("writing {:?}", path), || {
+                    let _lock = compaction.read().unwrap();
+
+                    history.keep(&filepath);
+                    write_zone_file(&*filepath, blob.clone(), durability == Durability::Always, &usage, &dedup)
+                })
+            });
+
+            // Flushed later by `flush_loop`
+            if let (&Ok(_), Durability::Interval(_)) = (&result, durability) {
+                unsynced.lock().unwrap().insert(filepath.clone());
+            }
+
+            match result {
+                Err(err) => {
+                    error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => {
+                    metrics.written(bytes, started);
+
+                    if let Err(err) = wal.lock().unwrap().checkpoint(&path, seq) {
+                        error!("Error checkpointing WAL for {:?}: {}", path, err.description());
+                    }
+
+                    zone.saved()
+                }
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+
+            let mut pending = pending.lock().unwrap();
+
+            // "Wake" any zones waiting to write
+            if let Some(zone) = pending.pop_front() {
+                zone.save();
+            }
+        });
+    }
+
+    /// Writes data for several paths all or nothing, once writes already running on worker threads
+    /// are done, replying whether it succeeded. Like `write_data`, the WAL is not checkpointed.
+    ///
+    /// Every zone file is written to a temporary file first. The list of them, the manifest, is
+    /// then written to the first data directory, which commits the write: on startup, temporary
+    /// files listed in a manifest are renamed over their zone files rather than removed.
+    fn write_atomic(&mut self, writes: Vec<(Path, Vec<u8>)>, reply: Reply<bool>) {
+        self.workers.drain();
+
+        debug!("Writing {} zones atomically", writes.len());
+
+        let _lock = self.compaction.read().unwrap();
+        let started = Instant::now();
+        let sync = self.durability != Durability::Never;
+        let manifest = self.dirs[0].join(MANIFEST);
+        let mut tmp_files = Vec::with_capacity(writes.len());
+
+        let result = (|| -> Result<_, StoreError> {
+            let mut blobs = Vec::with_capacity(writes.len());
+            let (mut old, mut new) = (0, 0);
+
+            for (path, data) in writes {
+                let filepath = self.shard_dir(&path).join(zonefilename(&path));
+                let blob = try!(self.codec.encode(data));
+
+                old += file_len(&filepath);
+                new += blob.len() as u64;
+                blobs.push((filepath, blob));
+            }
+
+            try!(self.usage.check_write(old, new));
+
+            for &(ref filepath, ref blob) in &blobs {
+                tmp_files.push(try!(write_shared_tmp(filepath, blob, sync, &self.dedup)));
+            }
+
+            let listed: Vec<_> = blobs.iter().map(|&(ref filepath, _)| filepath.to_string_lossy().into_owned()).collect();
+
+            try!(blocking_write(&manifest, listed.join("\n").into_bytes(), sync));
+
+            Ok((blobs, old, new))
+        })();
+
+        let (blobs, old, new) = match result {
+            Err(err) => {
+                error!("Error writing zones atomically: {}", err.description());
+
+                for tmp_path in tmp_files {
+                    std::fs::remove_file(&tmp_path).is_ok(); // removed on startup otherwise
+                }
+
+                reply.send(false).is_ok(); // ignore if caller goes away
+                return;
+            },
+            Ok(written) => written
+        };
+
+        // Committed: from here on, a crash or failure is recovered from on startup
+        let mut failed = false;
+
+        for (&(ref filepath, _), tmp_path) in blobs.iter().zip(tmp_files) {
+            self.history.keep(filepath);
+
+            let result = replace(&tmp_path, filepath, false).and_then(|_| clear_deltas(filepath, &self.usage));
+
+            if let Err(err) = result {
+                error!("Error writing {}: {}", filepath.display(), err.description());
+                failed = true;
+            }
+        }
+
+        self.usage.resize(old, new);
+
+        let result = if failed {
+            Err(StoreError::Io(io::Error::new(ErrorKind::Other, "Zone files left to be replaced on startup")))
+        }
+        else {
+            sync_dirs(blobs.iter().filter_map(|&(ref filepath, _)| filepath.parent()), sync)
+                .and_then(|_| blocking_delete(&manifest, sync))
+        };
+
+        let written = match result {
+            Err(err) => {
+                error!("Error writing zones atomically: {}", err.description());
+                false
+            },
+            Ok(_) => {
+                self.metrics.written(new, started);
+                true
+            }
+        };
+
+        reply.send(written).is_ok(); // ignore if caller goes away
+    }
+
+    /// Writes data for `path` asynchronously, replying whether it succeeded. Unlike `write`, the WAL
+    /// is not checkpointed: the data may not include diffs logged so far.
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
+
+        let compaction = self.compaction.clone();
+        let codec = self.codec.clone();
+        let durability = self.durability;
+        let unsynced = self.unsynced.clone();
+        let usage = self.usage.clone();
+        let history = self.history.clone();
+        let dedup = self.dedup.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing data: {:?}", path);
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                let _lock = compaction.read().unwrap();
+
+                bytes = blob.len() as u64;
+                history.keep(&filepath);
+                write_zone_file(&*filepath, blob, durability == Durability::Always, &usage, &dedup)
+            });
+
+            let written = match result {
+                Err(err) => {
+                    error!("Error writing {:?} - {}: {}", path, filepath.display(), err.description());
+                    false
+                },
+                Ok(_) => {
+                    metrics.written(bytes, started);
+
+                    // Flushed later by `flush_loop`
+                    if let Durability::Interval(_) = durability {
+                        unsynced.lock().unwrap().insert(filepath.clone());
+                    }
+
+                    true
+                }
+            };
+
+            reply.send(written).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Appends a delta for a `Zone` to its delta log asynchronously, notifying its handle when done.
+    /// Once the log is large enough to be worth consolidating, the zone is asked for a snapshot
+    /// instead.
+    fn write_delta(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        let filepath = self.shard_dir(&path).join(zonefilename(&path));
+
+        let pending = self.write_queue.clone();
+
+        // This delta checkpoints all WAL entries logged so far
+        let wal = self.wal.clone();
+        let seq = wal.lock().unwrap().seq();
+
+        let compaction = self.compaction.clone();
+        let codec = self.codec.clone();
+        let durability = self.durability;
+        let unsynced = self.unsynced.clone();
+        let usage = self.usage.clone();
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing delta: {:?}", path);
+
+            throttle.wait(1, data.len());
+
+            let deltapath = deltapath(&filepath);
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+
+                // Deltas replay idempotently, so one appended twice does no harm
+                retry.run(&format!("writing delta {:?}", path), || {
+                    let _lock = compaction.read().unwrap();
+
+                    blocking_append_delta(&filepath, &deltapath, &blob, durability == Durability::Always, &usage)
+                })
+            });
+
+            // Flushed later by `flush_loop`
+            if let (&Ok(true), Durability::Interval(_)) = (&result, durability) {
+                unsynced.lock().unwrap().insert(deltapath.clone());
+            }
+
+            match result {
+                Err(err) => {
+                    error!("Error writing delta {:?} - {}: {}", path, deltapath.display(), err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(false) => zone.snapshot(),
+                Ok(true) => {
+                    metrics.written(bytes, started);
+
+                    if let Err(err) = wal.lock().unwrap().checkpoint(&path, seq) {
+                        error!("Error checkpointing WAL for {:?}: {}", path, err.description());
+                    }
+
+                    zone.saved()
+                }
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+
+            let mut pending = pending.lock().unwrap();
+
+            // "Wake" any zones waiting to write
+            if let Some(zone) = pending.pop_front() {
+                zone.save();
+            }
+        });
+    }
+
+    /// Writes data for a group of `Zone`s asynchronously, notifying each handle when done. Zones
+    /// are written by their own worker thread, one batch per thread.
+    fn write_batch(&mut self, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        let mut batches = BTreeMap::new();
+
+        for write in writes {
+            batches.entry(self.workers.lane(&write.1)).or_insert_with(Vec::new).push(write);
+        }
+
+        for (lane, writes) in batches {
+            self.write_lane(lane, writes);
+        }
+    }
+}
+
+fn blocking_read(filepath: &std::path::Path, codec: &Codec) -> Result<ZoneData, StoreError> {
+    debug!("blocking_read: {:?}", filepath);
+
+    let mut file = match File::open(filepath) {
+        Err(err) => {
+            match err.kind() {
+                ErrorKind::NotFound => {
+                    return Ok(Default::default())
+                },
+                _ => {
+                    error!("  Error loading {}: {}", filepath.display(), err.description());
+                    error!("    {:?}", err);
+                }
+            }
+
+            error!("IO error: {}", err.description());
+
+            return Err(StoreError::Io(err));
+        },
+        Ok(file) => file,
+    };
+
+    if file_len(filepath) >= MMAP_SIZE {
+        return blocking_map(&file, codec);
+    }
+
+    let mut buffer = Vec::new();
+
+    // read the whole file
+    if let Err(err) = file.read_to_end(&mut buffer) {
+        return Err(StoreError::Io(err));
+    }
+
+    migrate::deserialize(try!(codec.decode(buffer)))
+}
+
+/// Deserializes zone data straight from a mapping of `file`, without reading it into memory first.
+/// Zone files are only ever replaced by renaming, never truncated, so the mapping stays valid.
+#[cfg(feature = "mmap")]
+fn blocking_map(file: &File, codec: &Codec) -> Result<ZoneData, StoreError> {
+    let map = try!(unsafe { Mmap::map(file) }.map_err(StoreError::Io));
+    let serialized = try!(codec.decode_slice(&map));
+
+    migrate::deserialize_from(&*serialized)
+}
+
+#[cfg(not(feature = "mmap"))]
+fn blocking_map(_: &File, _: &Codec) -> Result<ZoneData, StoreError> {
+    Err(StoreError::Unsupported("mmap not compiled in".into()))
+}
+
+/// Reads zone data from `filepath`, with any deltas saved since replayed into it.
+fn blocking_load(filepath: &std::path::Path, codec: &Codec) -> Result<ZoneData, StoreError> {
+    let mut data = try!(blocking_read(filepath, codec));
+    let deltas = try!(delta::read(&deltapath(filepath)).map_err(StoreError::Io));
+
+    delta::replay(&mut data, decode_entries(codec, deltas));
+
+    Ok(data)
+}
+
+/// Reads and decodes zone data from `filepath`, along with any deltas saved since, without
+/// deserializing it.
+fn blocking_load_raw(filepath: &std::path::Path, codec: &Codec) -> Result<RawZone, StoreError> {
+    let mut buffer = Vec::new();
+
+    let data = match File::open(filepath).and_then(|mut file| file.read_to_end(&mut buffer)) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => None,
+        Err(err) => return Err(StoreError::Io(err)),
+        Ok(_) => Some(try!(codec.decode(buffer)))
+    };
+
+    let deltas = try!(delta::read(&deltapath(filepath)).map_err(StoreError::Io));
+
+    Ok(RawZone { data: data, deltas: decode_entries(codec, deltas), entries: vec![] })
+}
+
+/// Salvages what can be read of corrupt zone data in `filepath`, with the deltas saved since and
+/// WAL `entries` replayed over it (see `RawZone::recover`). Returns none if nothing could be.
+fn blocking_recover(filepath: &std::path::Path, codec: &Codec, entries: Vec<Vec<u8>>) -> Option<ZoneData> {
+    let mut raw = match blocking_load_raw(filepath, codec) {
+        Ok(raw) => raw,
+        Err(_) => return None
+    };
+
+    raw.entries = entries;
+    raw.recover().ok().map(|(data, _)| data)
+}
+
+/// Streams zone data from `filepath` to `zone`, followed by any deltas saved since and WAL
+/// `entries`. Errors are sent on to the zone too.
+fn blocking_stream(filepath: &std::path::Path, codec: &Codec, entries: Vec<Vec<u8>>, zone: &ZoneHandle) -> Result<(), StoreError> {
+    let (mut tx, reader) = stream::channel();
+
+    zone.loaded_stream(reader);
+
+    let result = File::open(filepath)
+        .map_err(StoreError::Io)
+        .and_then(|file| tx.send_blob(file, codec))
+        .and_then(|_| delta::read(&deltapath(filepath)).map_err(StoreError::Io));
+
+    match result {
+        Err(err) => {
+            tx.fail(&err);
+            Err(err)
+        },
+        Ok(deltas) => {
+            tx.send(Chunk::End(decode_entries(codec, deltas), decode_entries(codec, entries)));
+            Ok(())
+        }
+    }
+}
+
+/// Appends an encoded delta for the zone file at `filepath` to its delta log. Returns false without
+/// appending if the zone should write a snapshot instead.
+fn blocking_append_delta(filepath: &std::path::Path, deltapath: &std::path::Path, blob: &[u8], sync: bool, usage: &Usage) -> Result<bool, StoreError> {
+    let (snapshot, deltas) = (file_len(filepath), file_len(deltapath));
+
+    if delta::needs_snapshot(snapshot, deltas + blob.len() as u64) {
+        return Ok(false);
+    }
+
+    try!(usage.check_write(deltas, deltas + blob.len() as u64));
+    try!(delta::append(deltapath, blob, sync).map_err(StoreError::Io));
+
+    usage.resize(deltas, file_len(deltapath));
+
+    // A new log must be in the directory too
+    if sync && deltas == 0 {
+        if let Some(dir) = deltapath.parent() {
+            try!(sync_dir(dir));
+        }
+    }
+
+    Ok(true)
+}
+
+/// Removes the delta log for the zone file at `filepath`, once a full write has made it obsolete.
+/// Not flushed: replaying deltas already in the zone file is harmless.
+fn clear_deltas(filepath: &std::path::Path, usage: &Usage) -> Result<(), StoreError> {
+    let deltapath = deltapath(filepath);
+    let len = file_len(&deltapath);
+
+    try!(blocking_delete(&deltapath, false));
+
+    usage.resize(len, 0);
+
+    Ok(())
+}
+
+/// Same as `blocking_write` for the zone file at `filepath`, within the quota, sharing identical
+/// data through `dedup`. Deltas saved for the zone are obsolete once written.
+fn write_zone_file(filepath: &std::path::Path, serialized: Vec<u8>, sync: bool, usage: &Usage, dedup: &Dedup) -> Result<(), StoreError> {
+    let (old, new) = (file_len(filepath), serialized.len() as u64);
+
+    try!(usage.check_write(old, new));
+
+    let tmp_path = try!(write_shared_tmp(filepath, &serialized, sync, dedup));
+
+    try!(replace(&tmp_path, filepath, sync));
+
+    usage.resize(old, new);
+
+    clear_deltas(filepath, usage)
+}
+
+/// Same as `blocking_delete` for the zone file at `filepath`, along with its deltas.
+fn delete_zone_file(filepath: &std::path::Path, sync: bool, usage: &Usage) -> Result<(), StoreError> {
+    let len = file_len(filepath);
+
+    try!(clear_deltas(filepath, usage));
+    try!(blocking_delete(filepath, sync));
+
+    usage.resize(len, 0);
+
+    Ok(())
+}
+
+/// Restores the zone file at `filepath` to the version kept in `history` that was current at
+/// `timestamp`, or removes it if it was not written yet then. Deltas are dropped, they may have
+/// been saved after `timestamp`. Returns false if neither the zone file nor its deltas changed
+/// since.
+fn blocking_restore(filepath: &std::path::Path, timestamp: u64, sync: bool, usage: &Usage, history: &History) -> Result<bool, StoreError> {
+    if let Some(written) = history::modified(filepath) {
+        if written <= timestamp {
+            if history::modified(&deltapath(filepath)).map_or(true, |saved| saved <= timestamp) {
+                return Ok(false);
+            }
+
+            try!(clear_deltas(filepath, usage));
+
+            return Ok(true);
+        }
+    }
+
+    let version = history.version_at(filepath, timestamp);
+
+    if version.is_none() && ! filepath.exists() {
+        return Ok(false);
+    }
+
+    // What is replaced can be restored in turn
+    history.keep(filepath);
+
+    match version {
+        None => try!(delete_zone_file(filepath, sync, usage)),
+        Some(version) => {
+            let mut blob = vec![];
+
+            try!(File::open(&version)
+                .and_then(|mut file| file.read_to_end(&mut blob))
+                .map_err(StoreError::Io));
+
+            let (old, new) = (file_len(filepath), blob.len() as u64);
+
+            try!(blocking_write(filepath, blob, sync));
+
+            usage.resize(old, new);
+
+            try!(clear_deltas(filepath, usage));
+        }
+    }
+
+    Ok(true)
+}
+
+/// Moves the zone file at `filepath` and its delta log aside, with `.orphan` and `.orphan_delta`
+/// extensions. They are kept on disk, and count towards the quota, until removed by hand.
+fn quarantine_zone_file(filepath: &std::path::Path, sync: bool) -> Result<(), StoreError> {
+    let deltapath = deltapath(filepath);
+
+    if deltapath.exists() {
+        try!(replace(&deltapath, &filepath.with_extension("orphan_delta"), false));
+    }
+
+    replace(filepath, &filepath.with_extension("orphan"), sync)
+}
+
+/// Size and modification time of the zone file at `filepath` and its delta log, plus `logged`
+/// bytes in the WAL. None if there is neither a file nor anything logged.
+fn blocking_stat(filepath: &std::path::Path, logged: u64) -> Option<ZoneStat> {
+    let meta = match std::fs::metadata(filepath) {
+        Ok(meta) => meta,
+        Err(ref err) if err.kind() == ErrorKind::NotFound && logged > 0 => {
+            return Some(ZoneStat { size: logged, modified: None });
+        },
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return None,
+        Err(err) => {
+            error!("Error reading metadata - {}: {}", filepath.display(), err.description());
+            return None;
+        }
+    };
+
+    // Deltas are only ever appended after the zone file is written
+    let delta = std::fs::metadata(deltapath(filepath)).ok();
+    let modified = delta.as_ref().unwrap_or(&meta).modified().ok();
+
+    Some(ZoneStat {
+        size: meta.len() + delta.map_or(0, |delta| delta.len()) + logged,
+        modified: modified
+    })
+}
+
+/// Size of the file at `filepath`, 0 if there is none.
+fn file_len(filepath: &std::path::Path) -> u64 {
+    std::fs::metadata(filepath).map(|meta| meta.len()).unwrap_or(0)
+}
+
+/// Size of the zone file at `filepath` along with its delta log.
+fn zone_file_len(filepath: &std::path::Path) -> u64 {
+    file_len(filepath) + file_len(&deltapath(filepath))
+}
+
+/// Bytes used by files in the tree of data directory `dir`, other than the WAL and history.
+fn dir_size(dir: &std::path::Path) -> u64 {
+    tree(dir).iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+        .filter(|entry| entry.file_name() != "wal.log")
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|meta| meta.is_file())
+        .map(|meta| meta.len())
+        .sum()
+}
+
+/// Directories in the tree of data directory `dir`, `dir` first, parents before their children.
+/// History and shared blobs are left out.
+fn tree(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut i = 0;
+
+    while i < dirs.len() {
+        match std::fs::read_dir(&dirs[i]) {
+            Err(err) => error!("Error listing directory {}: {}", dirs[i].display(), err.description()),
+            Ok(entries) => {
+                let children: Vec<_> = entries.filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_type().map_or(false, |kind| kind.is_dir()))
+                    .filter(|entry| entry.file_name() != history::DIR && entry.file_name() != dedup::DIR)
+                    .map(|entry| entry.path())
+                    .collect();
+
+                dirs.extend(children);
+            }
+        }
+
+        i += 1;
+    }
+
+    dirs
+}
+
+/// Zone files in the tree of data directory `dir`.
+fn zone_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    tree(dir).into_iter().map(|dir| dir.join(ZONE_FILE)).filter(|filepath| filepath.is_file()).collect()
+}
+
+fn deltapath(filepath: &std::path::Path) -> std::path::PathBuf {
+    filepath.with_extension("delta")
+}
+
+/// Watches `dirs` for zone files changed by other processes, in a thread of its own, and tells
+/// their zones to reload them.
+#[cfg(feature = "watch")]
+fn watch(app: AppHandle, dirs: Vec<std::path::PathBuf>, codec: Codec) {
+    thread::spawn(move|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let mut watcher = match notify::raw_watcher(tx) {
+            Err(err) => return error!("Error watching data directories: {}", err),
+            Ok(watcher) => watcher
+        };
+
+        for dir in &dirs {
+            if let Err(err) = watcher.watch(dir, RecursiveMode::Recursive) {
+                error!("Error watching {}: {}", dir.display(), err);
+            }
+        }
+
+        let mut renamed = HashSet::new();
+
+        for event in rx.iter() {
+            let filepath = match changed_zone_file(event, &mut renamed) {
+                None => continue,
+                Some(filepath) => filepath
+            };
+
+            // Removed again, or the old name of a file renamed away
+            if ! filepath.is_file() {
+                continue;
+            }
+
+            match blocking_read(&filepath, &codec) {
+                Err(err) => error!("Error reading changed zone file {}: {}", filepath.display(), err.description()),
+                Ok(data) => match app.manager.find(&data.path) {
+                    None => debug!("Zone file of {:?} changed, not loaded", data.path),
+                    Some(zone) => {
+                        info!("Zone file of {:?} changed, reloading", data.path);
+                        zone.reload();
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Zone file changed by `event`, unless the store changed it itself. `renamed` keeps track of
+/// renames of temporary files, whose other end is the store replacing a zone file.
+#[cfg(feature = "watch")]
+fn changed_zone_file(event: RawEvent, renamed: &mut HashSet<u32>) -> Option<std::path::PathBuf> {
+    let (filepath, op) = match (event.path, event.op) {
+        (Some(filepath), Ok(op)) => (filepath, op),
+        _ => return None
+    };
+
+    if op.contains(Op::RENAME) {
+        if let Some(cookie) = event.cookie {
+            if filepath.extension().map_or(false, |extension| extension == "tmp") {
+                renamed.insert(cookie);
+                return None;
+            }
+
+            if renamed.remove(&cookie) {
+                return None;
+            }
+        }
+    }
+
+    // WAL, deltas, temporary and quarantined files, and history
+    if filepath.file_name().map_or(true, |name| name != ZONE_FILE) {
+        return None;
+    }
+
+    if op.contains(Op::CLOSE_WRITE) || op.contains(Op::RENAME) {
+        Some(filepath)
+    }
+    else {
+        None
+    }
+}
+
+/// Atomically replaces the file at `filepath`: after a crash, it holds either the old or the new
+/// data, never a mix of both. Unless `sync` is set, an OS crash may still lose the write.
+fn blocking_write(filepath: &std::path::Path, serialized: Vec<u8>, sync: bool) -> Result<(), StoreError> {
+    debug!("blocking_write: {:?}", filepath);
+
+    let tmp_path = try!(write_tmp(filepath, &serialized, sync));
+
+    replace(&tmp_path, filepath, sync)
+}
+
+/// Removes the file at `filepath`, if any, flushing the removal to disk if `sync` is set.
+fn blocking_delete(filepath: &std::path::Path, sync: bool) -> Result<(), StoreError> {
+    debug!("blocking_delete: {:?}", filepath);
+
+    if let Err(err) = std::fs::remove_file(filepath) {
+        if err.kind() != ErrorKind::NotFound {
+            return Err(StoreError::Io(err));
+        }
+    }
+
+    if sync {
+        if let Some(dir) = filepath.parent() {
+            try!(sync_dir(dir));
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes data for `filepath` to `<filepath>.tmp`, flushing it to disk if `sync` is set.
+fn write_tmp(filepath: &std::path::Path, serialized: &[u8], sync: bool) -> Result<std::path::PathBuf, StoreError> {
+    let tmp_path = filepath.with_extension("tmp");
+
+    if let Some(dir) = filepath.parent() {
+        try!(create_dirs(dir, sync));
+    }
+
+    let mut file = match File::create(&tmp_path) {
+        Err(err) => {
+            error!("  Error creating {}: {}", tmp_path.display(), err.description());
+            error!("    {:?}", err);
+
+            return Err(StoreError::Io(err));
+        },
+        Ok(file) => file,
+    };
+
+    if let Err(err) = file.write_all(serialized) {
+        return Err(StoreError::Io(err));
+    }
+
+    // Data must be on disk before the rename, or a crash could leave an empty file behind
+    if sync {
+        if let Err(err) = file.sync_all() {
+            return Err(StoreError::Io(err));
+        }
+    }
+
+    Ok(tmp_path)
+}
+
+/// Creates directory `dir` and any missing parents, flushing each new directory's entry to disk if
+/// `sync` is set.
+fn create_dirs(dir: &std::path::Path, sync: bool) -> Result<(), StoreError> {
+    if dir.as_os_str().is_empty() || dir.is_dir() {
+        return Ok(());
+    }
+
+    if let Some(parent) = dir.parent() {
+        try!(create_dirs(parent, sync));
+    }
+
+    match std::fs::create_dir(dir) {
+        Err(ref err) if err.kind() == ErrorKind::AlreadyExists => (),
+        Err(err) => return Err(StoreError::Io(err)),
+        Ok(_) => ()
+    }
+
+    match dir.parent() {
+        Some(parent) if sync => sync_dir(parent),
+        _ => Ok(())
+    }
+}
+
+/// Same as `write_tmp`, linking the stored blob of identical data instead of writing it again if
+/// `dedup` has one (see `store::dedup`).
+fn write_shared_tmp(filepath: &std::path::Path, serialized: &[u8], sync: bool, dedup: &Dedup) -> Result<std::path::PathBuf, StoreError> {
+    let tmp_path = filepath.with_extension("tmp");
+
+    if dedup.is_enabled() {
+        if let Some(dir) = filepath.parent() {
+            try!(create_dirs(dir, sync));
+        }
+
+        if dedup.link(filepath, serialized, &tmp_path) {
+            return Ok(tmp_path);
+        }
+    }
+
+    try!(write_tmp(filepath, serialized, sync));
+
+    dedup.share(filepath, serialized, &tmp_path);
+
+    Ok(tmp_path)
+}
+
+/// Renames a temporary file over `filepath`, flushing the rename itself if `sync` is set.
+fn replace(tmp_path: &std::path::Path, filepath: &std::path::Path, sync: bool) -> Result<(), StoreError> {
+    if let Err(err) = std::fs::rename(tmp_path, filepath) {
+        return Err(StoreError::Io(err));
+    }
+
+    if sync {
+        if let Some(dir) = filepath.parent() {
+            try!(sync_dir(dir));
+        }
+    }
+
+    Ok(())
+}
+
+/// Flushes directory entries (such as renames) in `dir` to disk.
+fn sync_dir(dir: &std::path::Path) -> Result<(), StoreError> {
+    let dir = if dir.as_os_str().is_empty() { std::path::Path::new(".") } else { dir };
+
+    File::open(dir).and_then(|dir| dir.sync_all()).map_err(StoreError::Io)
+}
+
+/// Flushes each of `dirs` to disk once, if `sync` is set.
+fn sync_dirs<'a, I>(dirs: I, sync: bool) -> Result<(), StoreError> where I: Iterator<Item=&'a std::path::Path> {
+    if ! sync {
+        return Ok(());
+    }
+
+    let dirs: HashSet<_> = dirs.collect();
+
+    for dir in dirs {
+        try!(sync_dir(dir));
+    }
+
+    Ok(())
+}
+
+/// Flushes written files every `interval`, for `Durability::Interval`.
+fn flush_loop(dirs: &[std::path::PathBuf], unsynced: &Mutex<HashSet<std::path::PathBuf>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        flush_unsynced(dirs, unsynced);
+    }
+}
+
+/// Flushes zone files written since the last flush, and the directories holding them, to disk.
+fn flush_unsynced(dirs: &[std::path::PathBuf], unsynced: &Mutex<HashSet<std::path::PathBuf>>) {
+    let files: Vec<_> = unsynced.lock().unwrap().drain().collect();
+
+    if files.is_empty() {
+        return;
+    }
+
+    debug!("Flushing {} zone files", files.len());
+
+    for file in files {
+        // Only fails if the file has been replaced since, which is flushed separately
+        if let Err(err) = File::open(&file).and_then(|file| file.sync_all()) {
+            debug!("Could not flush {}: {}", file.display(), err.description());
+        }
+    }
+
+    for dir in dirs {
+        if let Err(err) = sync_dir(dir) {
+            error!("Error flushing {}: {}", dir.display(), err.description());
+        }
+    }
+}
+
+/// Finishes an atomic write (see `FS::write_atomic`) interrupted after it was committed, by
+/// renaming the temporary files listed in the manifest in `dir` over their zone files. Their deltas
+/// are obsolete.
+fn commit_manifest(dir: &std::path::Path) {
+    let manifest = dir.join(MANIFEST);
+
+    let listed = match std::fs::read_to_string(&manifest) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) => {
+            error!("Error reading {}: {}", manifest.display(), err.description());
+            return;
+        },
+        Ok(listed) => listed
+    };
+
+    let mut dirs = HashSet::new();
+
+    for filepath in listed.lines().map(std::path::Path::new) {
+        let tmp_path = filepath.with_extension("tmp");
+
+        // Replaced already if missing
+        if tmp_path.is_file() {
+            info!("Finishing interrupted write {}", filepath.display());
+
+            if let Err(err) = replace(&tmp_path, filepath, false).and_then(|_| blocking_delete(&deltapath(filepath), false)) {
+                error!("Error replacing {}: {}", filepath.display(), err.description());
+                return;
+            }
+        }
+
+        if let Some(dir) = filepath.parent() {
+            dirs.insert(dir.to_path_buf());
+        }
+    }
+
+    if let Err(err) = sync_dirs(dirs.iter().map(|dir| dir.as_path()), true).and_then(|_| blocking_delete(&manifest, true)) {
+        error!("Error finishing interrupted write: {}", err.description());
+    }
+}
+
+/// Removes temporary files left behind by writes interrupted by a crash, in the tree of data
+/// directory `dir`.
+fn remove_tmp_files(dir: &std::path::Path) {
+    for dir in tree(dir) {
+        let entries = match std::fs::read_dir(&dir) {
+            Err(err) => {
+                error!("Error listing directory: {}", err.description());
+                continue;
+            },
+            Ok(entries) => entries
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+
+            if path.extension().map_or(false, |ext| ext == "tmp") {
+                info!("Removing interrupted write {}", path.display());
+
+                if let Err(err) = std::fs::remove_file(&path) {
+                    error!("Error removing {}: {}", path.display(), err.description());
+                }
+            }
+        }
+    }
+}
+
+/// Moves zone files (and their deltas) that are not in the directory their hash picks out of
+/// `dirs`, after data directories were added or removed.
+fn rebalance(dirs: &[std::path::PathBuf]) {
+    if dirs.len() < 2 {
+        return; // TODO: zone files left behind by dropping all but one directory
+    }
+
+    for (i, dir) in dirs.iter().enumerate() {
+        for zonedir in tree(dir) {
+            let relative = zonedir.strip_prefix(dir).unwrap_or(&zonedir).to_path_buf();
+            let target = (dirhash(&relative) % dirs.len() as u64) as usize;
+
+            if target == i {
+                continue;
+            }
+
+            let entries = match std::fs::read_dir(&zonedir) {
+                Err(err) => {
+                    error!("Error listing directory {}: {}", zonedir.display(), err.description());
+                    continue;
+                },
+                Ok(entries) => entries
+            };
+
+            // The zone file, its deltas and quarantined files, and the versions kept of it (see
+            // `store::history`), but not the directories of other zones
+            for entry in entries.filter_map(|entry| entry.ok()) {
+                let name = entry.file_name();
+                let is_dir = entry.file_type().map_or(false, |kind| kind.is_dir());
+
+                let owned = match name.to_str() {
+                    None => false,
+                    Some(name) if is_dir => name == history::DIR,
+                    Some(name) => name.starts_with(ZONE_FILE)
+                };
+
+                if owned {
+                    move_file(&entry.path(), &dirs[target].join(&relative).join(&name));
+                }
+            }
+        }
+    }
+}
+
+/// Moves zone files of the flat layout of earlier versions, named after the zone and a hash of its
+/// path, into zone directories (see `zonedir`) in `dirs`. Deltas and history move along.
+fn relayout(dirs: &[std::path::PathBuf], codec: &Codec) {
+    for dir in dirs {
+        let entries = match std::fs::read_dir(dir) {
+            Err(err) => {
+                error!("Error listing directory {}: {}", dir.display(), err.description());
+                continue;
+            },
+            Ok(entries) => entries
+        };
+
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let filepath = entry.path();
+
+            if filepath.extension().is_some() || entry.file_name() == ZONE_FILE || ! filepath.is_file() {
+                continue;
+            }
+
+            let path = match blocking_read(&filepath, codec) {
+                Err(err) => {
+                    error!("Error reading {} to move it: {}", filepath.display(), err.description());
+                    continue;
+                },
+                Ok(data) => data.path
+            };
+
+            let moved = dirs[shard(&path, dirs.len())].join(zonefilename(&path));
+
+            move_file(&filepath, &moved);
+
+            if deltapath(&filepath).is_file() {
+                move_file(&deltapath(&filepath), &deltapath(&moved));
+            }
+
+            let versions = dir.join("history").join(entry.file_name());
+
+            if versions.is_dir() {
+                move_file(&versions, &history::history_dir(&moved));
+            }
+        }
+
+        std::fs::remove_dir(dir.join("history")).is_ok(); // only once empty
+    }
+}
+
+/// Moves the file or directory at `from` to `to`, creating the directories it goes in.
+fn move_file(from: &std::path::Path, to: &std::path::Path) {
+    info!("Moving {} to {}", from.display(), to.display());
+
+    let result = match to.parent() {
+        None => Ok(()),
+        Some(dir) => create_dirs(dir, false)
+    };
+
+    // Renames fail across filesystems
+    let result = result.and_then(|_| std::fs::rename(from, to)
+        .or_else(|_| std::fs::copy(from, to).and_then(|_| std::fs::remove_file(from)))
+        .map_err(StoreError::Io));
+
+    if let Err(err) = result {
+        error!("Error moving {}: {}", from.display(), err.description());
+    }
+}
+
+fn blocking_compact(filepath: &std::path::Path, codec: &Codec, usage: &Usage, history: &History, dedup: &Dedup) -> Result<(), StoreError> {
+    debug!("blocking_compact: {:?}", filepath);
+
+    if ! filepath.is_file() {
+        return Ok(());
+    }
+
+    let has_deltas = deltapath(filepath).is_file();
+    let mut data = try!(blocking_load(filepath, codec));
+    let pruned = data.tree.prune();
+
+    if pruned == 0 && ! has_deltas {
+        return Ok(());
+    }
+
+    info!("Compacting {:?}: dropping {} tombstones", data.path, pruned);
+
+    let serialized = try!(migrate::serialize(&data));
+
+    history.keep(filepath);
+    write_zone_file(filepath, try!(codec.encode(serialized)), true, usage, dedup)
+}
+
+/// Upgrades all zone files in `dir` to the current `ZoneData` layout, offline, moving them out of
+/// the flat layout of earlier versions first. Returns the number of files rewritten.
+pub fn migrate_dir(dir: &str, codec: &Codec) -> Result<usize, StoreError> {
+    let dir = std::path::PathBuf::from(dir);
+
+    if ! dir.is_dir() {
+        return Err(StoreError::Io(io::Error::new(ErrorKind::NotFound, format!("No data directory {}", dir.display()))));
+    }
+
+    relayout(&[dir.clone()], codec);
+
+    let mut migrated = 0;
+
+    for filepath in zone_files(&dir) {
+        let mut buffer = Vec::new();
+
+        try!(File::open(&filepath)
+            .and_then(|mut file| file.read_to_end(&mut buffer))
+            .map_err(StoreError::Io));
+
+        let serialized = try!(codec.decode(buffer));
+
+        if try!(migrate::version(&serialized)) == migrate::VERSION {
+            continue;
+        }
+
+        info!("Migrating {}", filepath.display());
+
+        let upgraded = try!(migrate::upgrade(serialized));
+
+        try!(blocking_write(&filepath, try!(codec.encode(upgraded)), true));
+
+        migrated += 1;
+    }
+
+    Ok(migrated)
+}
+
+/// Moves a corrupt zone file out of the way, keeping it around for inspection.
+fn quarantine(filepath: &std::path::Path) {
+    let corrupt_path = filepath.with_extension("corrupt");
+
+    match std::fs::rename(filepath, &corrupt_path) {
+        Err(err) => error!("Could not move {} aside: {}", filepath.display(), err.description()),
+        Ok(_) => error!("Moved corrupt data to {}", corrupt_path.display())
+    }
+}
+
+/// Decodes WAL entries or deltas, which are encoded like zone data. Bad entries are logged and
+/// skipped.
+fn decode_entries(codec: &Codec, entries: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    entries.into_iter().filter_map(|entry| match codec.decode(entry) {
+        Err(err) => {
+            error!("Bad WAL entry or delta: {}", err.description());
+            None
+        },
+        Ok(entry) => Some(entry)
+    }).collect()
+}
+
+/// File of the zone at `path`, relative to its data directory: the `ZONE_FILE` in the directory
+/// of the zone (see `zonedir`).
+fn zonefilename(path: &Path) -> std::path::PathBuf {
+    zonedir(path).join(ZONE_FILE)
+}
+
+/// Directory of the zone at `path`, relative to its data directory: one level per component,
+/// nested in the directory of its parent. `path![]` is the data directory itself.
+fn zonedir(path: &Path) -> std::path::PathBuf {
+    path.path.iter().map(|component| escape(component)).collect()
+}
+
+/// Directory name for path component `component`. Letters, digits, `-` and `#` are kept, other
+/// bytes are escaped as `%XX`, so directories never clash with the store's own files, which start
+/// with `_` or have an extension. Names too long for most filesystems are cut short and end in `~`
+/// and a hash of the component.
+fn escape(component: &str) -> String {
+    let pieces: Vec<String> = component.bytes().map(|byte| match byte {
+        b'#' | b'-' | b'0'...b'9' | b'A'...b'Z' | b'a'...b'z' => (byte as char).to_string(),
+        _ => format!("%{:02X}", byte)
+    }).collect();
+
+    // Nothing else escapes to a lone `%`
+    if pieces.is_empty() {
+        return "%".to_string();
+    }
+
+    if pieces.iter().map(|piece| piece.len()).sum::<usize>() <= MAX_DIR_NAME {
+        return pieces.concat();
+    }
+
+    let mut name = String::new();
+
+    for piece in pieces {
+        if name.len() + piece.len() > MAX_DIR_NAME - 17 {
+            break;
+        }
+
+        name.push_str(&piece);
+    }
+
+    let mut hasher = DefaultHasher::new();
+
+    component.hash(&mut hasher);
+    name + &format!("~{:016X}", hasher.finish())
+}
+
+/// Hash of the zone at `path`, picking its data directory.
+fn zonehash(path: &Path) -> u64 {
+    dirhash(&zonedir(path))
+}
+
+/// Hash of the zone whose directory relative to its data directory is `dir` (see `zonedir`).
+fn dirhash(dir: &std::path::Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+
+    dir.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Index of the data directory out of `count` holding the file for the zone at `path`.
+fn shard(path: &Path, count: usize) -> usize {
+    (zonehash(path) % count as u64) as usize
+}
+
+#[test]
+fn test_read_write() {
+    let dir = std::path::PathBuf::from("test_data/read_write");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_read_write");
+
+    std::fs::remove_file(&file).ok();
+
+    let data = blocking_read(&file, &Codec::default()).unwrap();
+
+    assert_eq!(data, Default::default());
+
+    let limit = bincode::Infinite;
+    let serialized = bincode::serialize(&data, limit).unwrap();
+
+    blocking_write(&file, serialized, true).unwrap();
+
+    assert_eq!(blocking_read(&file, &Codec::default()).unwrap(), data);
+
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let expected = ZoneData::new(
+        Path::empty(),
+        NodeTree {
+            vis: Vis::update(1000),
+            node: Node::expand(JSON::String(String::from("moo")), 1000)
+        }
+    );
+
+    let serialized = migrate::serialize(&expected).unwrap();
+
+    blocking_write(&file, serialized, true).unwrap();
+
+    let verify = blocking_read(&file, &Codec::default()).unwrap();
+
+    assert_eq!(verify, expected);
+}
+
+#[test]
+fn test_list() {
+    let dir = std::path::PathBuf::from("test_data/list");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let mut store = FS::new(app.handle(), "127.0.0.1:42", &Config::default());
+
+    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
+    let limit = bincode::Infinite;
+
+    for path in vec![path![0], path![1], path![2], path![1.moo]] {
+        let zone_data = ZoneData::new(path.clone(), Default::default());
+
+        let serialized = bincode::serialize(&zone_data, limit).unwrap();
+
+        store.write(noop_zone.clone(), path, serialized);
+    }
+
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    let (tx, rx) = channel();
+
+    store.list(Path::empty(), tx);
+
+    let mut paths: Vec<Path> = rx.iter().collect();
+
+    paths.sort();
+
+    assert_eq!(paths, [path![0], path![1], path![1.moo], path![2]]);
+
+    // Only zones under the prefix
+    let (tx, rx) = channel();
+
+    store.list(path![1], tx);
+
+    let mut paths: Vec<Path> = rx.iter().collect();
+
+    paths.sort();
+
+    assert_eq!(paths, [path![1], path![1.moo]]);
+
+    let (tx, rx) = channel();
+
+    store.list(path![cow], tx);
+
+    assert_eq!(rx.iter().count(), 0);
+}
+
+#[test]
+fn test_compact() {
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let dir = std::path::PathBuf::from("test_data/compact");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_compact");
+
+    std::fs::remove_file(&file).ok();
+
+    // Nothing to compact
+    blocking_compact(&file, &Codec::default(), &Usage::new(Default::default(), 0, 0), &History::new(Default::default()), &Default::default()).unwrap();
+
+    assert!(! file.exists());
+
+    let mut tree = NodeTree {
+        vis: Vis::permanent(),
+        node: Node::expand_from(&["moo".to_string(), "cow".to_string()], JSON::String(String::from("moo")), 1000)
+    };
+
+    tree.merge(&mut Node::delete(2000).prepend_path(&["moo".to_string()]).noop_vis());
+
+    let data = ZoneData::new(Path::empty(), tree);
+
+    let serialized = migrate::serialize(&data).unwrap();
+
+    blocking_write(&file, serialized, true).unwrap();
+    blocking_compact(&file, &Codec::default(), &Usage::new(Default::default(), 0, 0), &History::new(Default::default()), &Default::default()).unwrap();
+
+    let mut expected = data.clone();
+
+    assert_eq!(expected.tree.prune(), 1);
+    assert_eq!(blocking_read(&file, &Codec::default()).unwrap(), expected);
+}
+
+#[test]
+fn test_corrupt() {
+    let dir = std::path::PathBuf::from("test_data/corrupt");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_corrupt");
+
+    let codec = Codec::default();
+    let limit = bincode::Infinite;
+    let serialized = bincode::serialize(&ZoneData::default(), limit).unwrap();
+    let mut blob = codec.encode(serialized).unwrap();
+
+    // Truncated
+    blob.pop();
+    blocking_write(&file, blob, true).unwrap();
+
+    match blocking_read(&file, &codec) {
+        Err(StoreError::Corrupt { .. }) => (),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+
+    quarantine(&file);
+
+    assert!(! file.exists());
+    assert!(file.with_extension("corrupt").exists());
+}
+
+#[test]
+fn test_interrupted_write() {
+    let dir = std::path::PathBuf::from("test_data/interrupted_write");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let mut file = dir.clone();
+
+    file.push("test_interrupted_write");
+
+    let codec = Codec::default();
+    let limit = bincode::Infinite;
+
+    let old = ZoneData::new(path![moo], Default::default());
+    let new = ZoneData::new(path![cow], Default::default());
+
+    let old_blob = codec.encode(bincode::serialize(&old, limit).unwrap()).unwrap();
+    let new_blob = codec.encode(bincode::serialize(&new, limit).unwrap()).unwrap();
+
+    blocking_write(&file, old_blob, true).unwrap();
+
+    // Killed half way through writing the temporary file
+    write_tmp(&file, &new_blob[..new_blob.len() / 2], true).unwrap();
+
+    assert_eq!(blocking_read(&file, &codec).unwrap(), old);
+
+    // Killed after writing the temporary file, before the rename
+    write_tmp(&file, &new_blob, true).unwrap();
+
+    assert_eq!(blocking_read(&file, &codec).unwrap(), old);
+
+    // Leftovers are cleaned up on restart
+    remove_tmp_files(&dir);
+
+    assert!(! file.with_extension("tmp").exists());
+    assert_eq!(blocking_read(&file, &codec).unwrap(), old);
+
+    blocking_write(&file, new_blob, true).unwrap();
+
+    assert_eq!(blocking_read(&file, &codec).unwrap(), new);
+}
+
+#[test]
+fn test_interrupted_atomic_write() {
+    let dir = std::path::PathBuf::from("test_data/interrupted_atomic_write");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let codec = Codec::default();
+    let limit = bincode::Infinite;
+
+    let paths = vec![path![moo], path![moo.cow]];
+    let files: Vec<_> = paths.iter().map(|path| dir.join(zonefilename(path))).collect();
+
+    let old = ZoneData::new(path![moo], Default::default());
+    let new = ZoneData::new(path![cow], Default::default());
+
+    let old_blob = codec.encode(bincode::serialize(&old, limit).unwrap()).unwrap();
+    let new_blob = codec.encode(bincode::serialize(&new, limit).unwrap()).unwrap();
+
+    for file in &files {
+        blocking_write(file, old_blob.clone(), true).unwrap();
+    }
+
+    blocking_write(&deltapath(&files[1]), vec![1], true).unwrap();
+
+    // Killed before committing: nothing is replaced
+    write_tmp(&files[0], &new_blob, true).unwrap();
+
+    commit_manifest(&dir);
+    remove_tmp_files(&dir);
+
+    assert!(! files[0].with_extension("tmp").exists());
+    assert_eq!(blocking_read(&files[0], &codec).unwrap(), old);
+
+    // Killed after committing, with one zone file replaced already: the other one is too
+    write_tmp(&files[1], &new_blob, true).unwrap();
+
+    let listed: Vec<_> = files.iter().map(|file| file.to_string_lossy().into_owned()).collect();
+
+    blocking_write(&dir.join(MANIFEST), listed.join("\n").into_bytes(), true).unwrap();
+    blocking_write(&files[0], new_blob, true).unwrap();
+
+    commit_manifest(&dir);
+    remove_tmp_files(&dir);
+
+    for file in &files {
+        assert_eq!(blocking_read(file, &codec).unwrap(), new);
+    }
+
+    assert!(! deltapath(&files[1]).exists());
+    assert!(! dir.join(MANIFEST).exists());
+}
+
+#[test]
+fn test_delete() {
+    let dir = std::path::PathBuf::from("test_data/delete");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_delete");
+
+    let codec = Codec::default();
+    let limit = bincode::Infinite;
+    let data = ZoneData::new(path![moo], Default::default());
+
+    blocking_write(&file, codec.encode(bincode::serialize(&data, limit).unwrap()).unwrap(), true).unwrap();
+    assert_eq!(blocking_read(&file, &codec).unwrap(), data);
+
+    blocking_delete(&file, true).unwrap();
+
+    assert!(! file.exists());
+    assert_eq!(blocking_read(&file, &codec).unwrap(), Default::default());
+
+    // Deleting a zone that was never written is fine
+    blocking_delete(&file, true).unwrap();
+}
+
+#[test]
+fn test_quarantine() {
+    let dir = std::path::PathBuf::from("test_data/quarantine");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let file = dir.join("test_quarantine");
+
+    std::fs::remove_file(file.with_extension("orphan")).ok();
+    std::fs::remove_file(file.with_extension("orphan_delta")).ok();
+
+    blocking_write(&file, vec![1, 2, 3], true).unwrap();
+    File::create(deltapath(&file)).unwrap().write_all(&[4]).unwrap();
+
+    quarantine_zone_file(&file, true).unwrap();
+
+    assert!(! file.exists());
+    assert!(! deltapath(&file).exists());
+    assert_eq!(file_len(&file.with_extension("orphan")), 3);
+    assert_eq!(file_len(&file.with_extension("orphan_delta")), 1);
+
+    // Nothing left to move
+    assert!(quarantine_zone_file(&file, true).is_err());
+}
+
+#[test]
+fn test_stat() {
+    let dir = std::path::PathBuf::from("test_data/stat");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let file = dir.join("test_stat");
+
+    std::fs::remove_file(&file).ok();
+    std::fs::remove_file(deltapath(&file)).ok();
+
+    assert_eq!(blocking_stat(&file, 0), None);
+    assert_eq!(blocking_stat(&file, 5), Some(ZoneStat { size: 5, modified: None }));
+
+    blocking_write(&file, vec![1, 2, 3], true).unwrap();
+
+    let stat = blocking_stat(&file, 0).unwrap();
+
+    assert_eq!(stat.size, 3);
+    assert!(stat.modified.is_some());
+
+    File::create(deltapath(&file)).unwrap().write_all(&[4]).unwrap();
+
+    assert_eq!(blocking_stat(&file, 2).unwrap().size, 6);
+
+    blocking_delete(&file, true).unwrap();
+    std::fs::remove_file(deltapath(&file)).unwrap();
+}
+
+#[test]
+fn test_migrate() {
+    let dir = std::path::PathBuf::from("test_data/migrate");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let data = ZoneData::new(path![moo], Default::default());
+    let file = dir.join(zonefilename(&data.path));
+
+    let codec = Codec::default();
+    let limit = bincode::Infinite;
+
+    // Written before versioning
+    blocking_write(&file, codec.encode(bincode::serialize(&data, limit).unwrap()).unwrap(), true).unwrap();
+
+    assert_eq!(migrate_dir(dir.to_str().unwrap(), &codec).unwrap(), 1);
+    assert_eq!(blocking_read(&file, &codec).unwrap(), data);
+
+    let mut buffer = Vec::new();
+
+    File::open(&file).unwrap().read_to_end(&mut buffer).unwrap();
+
+    assert_eq!(migrate::version(&codec.decode(buffer).unwrap()).unwrap(), migrate::VERSION);
+
+    // Nothing left to migrate
+    assert_eq!(migrate_dir(dir.to_str().unwrap(), &codec).unwrap(), 0);
+}
+
+#[test]
+fn test_delta() {
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let dir = std::path::PathBuf::from("test_data/delta_fs");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let file = dir.join("test_delta");
+
+    std::fs::remove_file(&file).ok();
+    std::fs::remove_file(&deltapath(&file)).ok();
+
+    let codec = Codec::default();
+    let usage = Usage::new(Default::default(), 0, 0);
+
+    let diff = Node::expand_from(&["moo".to_string()], JSON::String(String::from("cow")), 2000).noop_vis();
+    let blob = codec.encode(migrate::serialize_delta(&[diff.clone()]).unwrap()).unwrap();
+
+    // Nothing to append to yet
+    assert!(! blocking_append_delta(&file, &deltapath(&file), &blob, true, &usage).unwrap());
+
+    let data = ZoneData::new(Path::empty(), NodeTree {
+        vis: Vis::permanent(),
+        node: Node::expand_from(&["oink".to_string()], JSON::String("pig".repeat(1000)), 1000)
+    });
+
+    blocking_write(&file, codec.encode(migrate::serialize(&data).unwrap()).unwrap(), true).unwrap();
+
+    assert!(blocking_append_delta(&file, &deltapath(&file), &blob, true, &usage).unwrap());
+
+    let mut expected = data.clone();
+
+    expected.tree.merge(&mut diff.clone());
+
+    assert_eq!(blocking_load(&file, &codec).unwrap(), expected);
+
+    // A full write leaves nothing to replay
+    blocking_write(&file, codec.encode(migrate::serialize(&data).unwrap()).unwrap(), true).unwrap();
+    clear_deltas(&file, &usage).unwrap();
+
+    assert!(! deltapath(&file).exists());
+    assert_eq!(blocking_load(&file, &codec).unwrap(), data);
+}
+
+#[test]
+fn test_quota() {
+    let dir = std::path::PathBuf::from("test_data/quota");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let file = dir.join("test_quota");
+
+    std::fs::remove_file(&file).ok();
+
+    let usage = Usage::new("100:50".parse().unwrap(), 0, 0);
+
+    write_zone_file(&file, vec![0; 40], true, &usage, &Default::default()).unwrap();
+    assert_eq!(usage.used(), 40);
+
+    // Growing past the watermark is refused once past it
+    write_zone_file(&file, vec![0; 60], true, &usage, &Default::default()).unwrap();
+
+    match write_zone_file(&file, vec![0; 70], true, &usage, &Default::default()) {
+        Err(StoreError::QuotaExceeded) => (),
+        other => panic!("Expected quota exceeded, got {:?}", other)
+    }
+
+    assert_eq!(file_len(&file), 60);
+
+    // Shrinking and deleting free up space
+    write_zone_file(&file, vec![0; 10], true, &usage, &Default::default()).unwrap();
+    assert_eq!(usage.used(), 10);
+
+    delete_zone_file(&file, true, &usage).unwrap();
+    assert_eq!(usage.used(), 0);
+}
+
+#[test]
+fn test_layout() {
+    let component = |s: &str| Path::new(vec![s.to_string()]);
+
+    assert_eq!(zonefilename(&path![]), std::path::PathBuf::from("_zone"));
+    assert_eq!(zonefilename(&path![moo.cow]), std::path::PathBuf::from("moo/cow/_zone"));
+
+    // Nothing escapes to the store's own files, or outside the data directory
+    assert_eq!(escape("_zone"), "%5Fzone");
+    assert_eq!(escape("wal.log"), "wal%2Elog");
+    assert_eq!(escape(".."), "%2E%2E");
+    assert_eq!(escape("a/b"), "a%2Fb");
+    assert_eq!(escape(""), "%");
+    assert_eq!(escape("%"), "%25");
+    assert_eq!(escape("Moo-#1"), "Moo-#1");
+
+    // Long components are cut short, and told apart by their hash
+    let long = "moo".repeat(100);
+
+    assert!(escape(&long).len() <= MAX_DIR_NAME);
+    assert!(escape(&long).starts_with("moomoo"));
+    assert!(escape(&long) != escape(&(long.clone() + "x")));
+    assert!(escape(&"%".repeat(100)).len() <= MAX_DIR_NAME);
+
+    assert!(zonehash(&component("moo")) != zonehash(&component("moo.")));
+}
+
+#[test]
+fn test_relayout() {
+    let root = std::path::PathBuf::from("test_data/relayout");
+
+    if root.exists() {
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    let dirs: Vec<_> = (0..2).map(|i| root.join(i.to_string())).collect();
+
+    for dir in &dirs {
+        DirBuilder::new().recursive(true).create(dir).unwrap();
+    }
+
+    DirBuilder::new().create(dirs[1].join("history")).unwrap();
+    DirBuilder::new().create(dirs[1].join("history").join("rmoo_cow_1")).unwrap();
+
+    let codec = Codec::default();
+    let data = ZoneData::new(path![moo.cow], Default::default());
+    let blob = codec.encode(migrate::serialize(&data).unwrap()).unwrap();
+
+    // Flat zone file, with deltas and history
+    let flat = dirs[1].join("rmoo_cow_1");
+
+    File::create(&flat).unwrap().write_all(&blob).unwrap();
+    File::create(deltapath(&flat)).unwrap();
+    File::create(dirs[1].join("history").join("rmoo_cow_1").join("1")).unwrap();
+    File::create(dirs[0].join("wal.log")).unwrap();
+
+    relayout(&dirs, &codec);
+
+    let file = dirs[shard(&data.path, dirs.len())].join("moo").join("cow").join(ZONE_FILE);
+
+    assert_eq!(blocking_read(&file, &codec).unwrap(), data);
+    assert!(deltapath(&file).is_file());
+    assert!(history::history_dir(&file).join("1").is_file());
+    assert!(! flat.exists());
+    assert!(! dirs[1].join("history").exists());
+    assert!(dirs[0].join("wal.log").is_file());
+
+    assert_eq!(zone_files(&root), vec![file]);
+}
+
+#[test]
+fn test_rebalance() {
+    let root = std::path::PathBuf::from("test_data/rebalance");
+
+    if root.exists() {
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    let dirs: Vec<_> = (0..3).map(|i| root.join(i.to_string())).collect();
+
+    for dir in &dirs {
+        DirBuilder::new().recursive(true).create(dir).unwrap();
+    }
+
+    let mut paths: Vec<_> = (0..10).map(|i| Path::new(vec![i.to_string()])).collect();
+
+    paths.push(path![]);
+    paths.push(Path::new(vec!["0".to_string(), "moo".to_string()]));
+
+    // Written before there were more directories
+    for path in &paths {
+        let file = dirs[0].join(zonefilename(path));
+
+        create_dirs(file.parent().unwrap(), false).unwrap();
+        File::create(&file).unwrap();
+        File::create(deltapath(&file)).unwrap();
+    }
+
+    File::create(dirs[0].join("wal.log")).unwrap();
+
+    rebalance(&dirs);
+
+    // Moved along with their deltas, leaving the zones under them where they belong
+    for path in &paths {
+        let file = dirs[shard(path, dirs.len())].join(zonefilename(path));
+
+        assert!(file.is_file());
+        assert!(deltapath(&file).is_file());
+    }
+
+    assert!(dirs[0].join("wal.log").is_file());
+}
+
+#[test]
+fn test_restore() {
+    let dir = std::path::PathBuf::from("test_data/restore");
+
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    DirBuilder::new().recursive(true).create(&dir).unwrap();
+
+    let file = dir.join("test_restore");
+    let usage = Usage::new(Default::default(), 0, 0);
+    let history = History::new("versions:10".parse().unwrap());
+    let mut written = vec![];
+
+    for i in 1..4u8 {
+        history.keep(&file);
+        write_zone_file(&file, vec![i; i as usize], true, &usage, &Default::default()).unwrap();
+        written.push(history::modified(&file).unwrap());
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    File::create(deltapath(&file)).unwrap().write_all(&[4]).unwrap();
+    usage.resize(0, 1);
+
+    assert!(blocking_restore(&file, written[0], true, &usage, &history).unwrap());
+    assert_eq!(std::fs::read(&file).unwrap(), [1]);
+    assert!(! deltapath(&file).exists());
+    assert_eq!(usage.used(), 1);
+
+    // What was replaced by the restore can be restored in turn
+    assert!(blocking_restore(&file, written[2], true, &usage, &history).unwrap());
+    assert_eq!(std::fs::read(&file).unwrap(), [3, 3, 3]);
+
+    // Nothing to do if the zone did not change since
+    assert!(! blocking_restore(&file, history::modified(&file).unwrap(), true, &usage, &history).unwrap());
+
+    // Zone did not exist yet
+    assert!(blocking_restore(&file, written[0] - 1, true, &usage, &history).unwrap());
+    assert!(! file.exists());
+    assert_eq!(usage.used(), 0);
+
+    assert!(! blocking_restore(&file, written[0] - 1, true, &usage, &history).unwrap());
+}
+
+#[cfg(feature = "watch")]
+#[test]
+fn test_changed_zone_file() {
+    let event = |name: &str, op: Op, cookie: Option<u32>| {
+        RawEvent { path: Some(std::path::PathBuf::from(name)), op: Ok(op), cookie: cookie }
+    };
+
+    let mut renamed = HashSet::new();
+
+    // The store's own writes rename a temporary file over the zone file
+    assert_eq!(changed_zone_file(event("data/moo/_zone.tmp", Op::CLOSE_WRITE, None), &mut renamed), None);
+    assert_eq!(changed_zone_file(event("data/moo/_zone.tmp", Op::RENAME, Some(1)), &mut renamed), None);
+    assert_eq!(changed_zone_file(event("data/moo/_zone", Op::RENAME, Some(1)), &mut renamed), None);
+    assert!(renamed.is_empty());
+
+    // Files copied or moved in by others
+    let changed = Some(std::path::PathBuf::from("data/moo/_zone"));
+
+    assert_eq!(changed_zone_file(event("data/moo/_zone", Op::CLOSE_WRITE, None), &mut renamed), changed);
+    assert_eq!(changed_zone_file(event("data/moo/_zone", Op::RENAME, Some(2)), &mut renamed), changed);
 
-    assert_eq!(paths, [
-        Path::new(vec!["0".into()]),
-        Path::new(vec!["1".into()]),
-        Path::new(vec!["2".into()]),
-    ]);
+    // Other files, and other changes
+    assert_eq!(changed_zone_file(event("data/wal.log", Op::CLOSE_WRITE, None), &mut renamed), None);
+    assert_eq!(changed_zone_file(event("data/moo/_history/_zone/1", Op::CLOSE_WRITE, None), &mut renamed), None);
+    assert_eq!(changed_zone_file(event("data/moo/_zone", Op::CHMOD, None), &mut renamed), None);
 }
@@ -0,0 +1,251 @@
+//! Futures of Store calls, with the `async` feature.
+//!
+//! `StoreHandle` calls that return something block the calling thread until the Store replies.
+//! `AsyncStoreHandle` makes the same calls but returns a `StoreFuture` right away, which the
+//! backend completes with its reply (see `store::reply`). Tasks awaiting loads are woken when they
+//! are done, so servers on an executor don't need a thread per pending call.
+//!
+//! Calls that don't wait for a reply, such as `StoreHandle::load` and `StoreHandle::write`, don't
+//! block in the first place, and are made on the `StoreHandle` as usual.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::{StoreCall, StoreError, StoreHandle, ZoneStat};
+use super::migrate;
+use super::reply::Reply;
+use super::stats::StoreStats;
+use path::Path;
+use zone::ZoneData;
+
+/// Makes Store calls without blocking, returning futures of their results.
+#[derive(Clone)]
+pub struct AsyncStoreHandle {
+    store: StoreHandle
+}
+
+/// Result of a Store call, ready once the backend replies. Resolves like the matching
+/// `StoreHandle` call: calls the backend fails by dropping the reply resolve to their default
+/// result, or `StoreError::Disconnected` for `load_data`.
+pub struct StoreFuture<T> {
+    slot: Result<Arc<Mutex<Slot<T>>>, Option<StoreError>>, // Err if the call could not be made
+    fallback: Option<T>                                   // Result if the reply is dropped
+}
+
+/// Backend's end of a `StoreFuture`, sent in a `Reply::Future`.
+pub struct Completion<T> {
+    slot: Arc<Mutex<Slot<T>>>
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    closed: bool,         // Completion sent or dropped
+    waker: Option<Waker>  // Task waiting on the result
+}
+
+impl AsyncStoreHandle {
+    pub fn new(store: StoreHandle) -> AsyncStoreHandle {
+        AsyncStoreHandle { store: store }
+    }
+
+    /// The blocking handle this one makes calls through.
+    pub fn handle(&self) -> &StoreHandle {
+        &self.store
+    }
+
+    /// Same as `StoreHandle::delete_data`.
+    pub fn delete_data(&self, path: Path) -> StoreFuture<bool> {
+        self.call(Some(false), |reply| StoreCall::DeleteData(path, reply))
+    }
+
+    /// Same as `StoreHandle::load_data`.
+    pub fn load_data(&self, path: Path) -> StoreFuture<Option<ZoneData>> {
+        self.call(None, |reply| StoreCall::LoadData(path, reply))
+    }
+
+    /// Same as `StoreHandle::quarantine`.
+    pub fn quarantine(&self, path: Path) -> StoreFuture<bool> {
+        self.call(Some(false), |reply| StoreCall::Quarantine(path, reply))
+    }
+
+    /// Same as `StoreHandle::restore`, resolving to the number of zones whose stored data changed:
+    /// 1 or 0.
+    pub fn restore(&self, path: &Path, timestamp: u64) -> StoreFuture<usize> {
+        self.call(Some(0), |reply| StoreCall::Restore(Some(path.clone()), timestamp, reply))
+    }
+
+    /// Same as `StoreHandle::restore_all`.
+    pub fn restore_all(&self, timestamp: u64) -> StoreFuture<usize> {
+        self.call(Some(0), |reply| StoreCall::Restore(None, timestamp, reply))
+    }
+
+    /// Same as `StoreHandle::stat`.
+    pub fn stat(&self, path: &Path) -> StoreFuture<Option<ZoneStat>> {
+        self.call(Some(None), |reply| StoreCall::Stat(path.clone(), reply))
+    }
+
+    /// Same as `StoreHandle::stats`.
+    pub fn stats(&self) -> StoreFuture<StoreStats> {
+        self.call(Some(Default::default()), |reply| StoreCall::Stats(reply))
+    }
+
+    /// Same as `StoreHandle::write_data`.
+    pub fn write_data(&self, path: Path, data: &ZoneData) -> StoreFuture<bool> {
+        match migrate::serialize(data) {
+            Err(err) => StoreFuture::failed(err),
+            Ok(serialized) => self.call(Some(false), |reply| StoreCall::WriteData(path, serialized, reply))
+        }
+    }
+
+    /// Sends the call made by `f` with a reply completing the returned future, which resolves to
+    /// `fallback` if the reply is dropped.
+    fn call<T, F>(&self, fallback: Option<T>, f: F) -> StoreFuture<T> where F: FnOnce(Reply<T>) -> StoreCall {
+        let slot = Arc::new(Mutex::new(Slot { value: None, closed: false, waker: None }));
+        let completion = Completion { slot: slot.clone() };
+
+        match self.store.send(f(Reply::Future(completion))) {
+            Err(err) => StoreFuture::failed(err),
+            Ok(_) => StoreFuture { slot: Ok(slot), fallback: fallback }
+        }
+    }
+}
+
+impl From<StoreHandle> for AsyncStoreHandle {
+    fn from(store: StoreHandle) -> AsyncStoreHandle {
+        AsyncStoreHandle::new(store)
+    }
+}
+
+impl<T> StoreFuture<T> {
+    /// A future that fails right away with `err`.
+    fn failed(err: StoreError) -> StoreFuture<T> {
+        StoreFuture { slot: Err(Some(err)), fallback: None }
+    }
+}
+
+// Nothing is pinned: the result is moved out of the slot, never polled in place
+impl<T> Unpin for StoreFuture<T> {
+}
+
+impl<T> Future for StoreFuture<T> {
+    type Output = Result<T, StoreError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T, StoreError>> {
+        let this = self.get_mut();
+
+        let slot = match this.slot {
+            Err(ref mut err) => return Poll::Ready(Err(err.take().unwrap_or(StoreError::Disconnected))),
+            Ok(ref slot) => slot
+        };
+
+        let mut slot = slot.lock().unwrap();
+
+        if let Some(value) = slot.value.take() {
+            return Poll::Ready(Ok(value));
+        }
+
+        if slot.closed {
+            return Poll::Ready(this.fallback.take().ok_or(StoreError::Disconnected));
+        }
+
+        slot.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Completion<T> {
+    /// Resolves the future with `value`, waking its task. Hands `value` back if the future has
+    /// been dropped.
+    pub fn complete(self, value: T) -> Result<(), T> {
+        // The future holds the only other reference, until it is dropped
+        if Arc::strong_count(&self.slot) == 1 {
+            return Err(value);
+        }
+
+        self.slot.lock().unwrap().value = Some(value);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    /// Closes the slot, completed or not, so the future stops waiting.
+    fn drop(&mut self) {
+        let waker = match self.slot.lock() {
+            Err(_) => return,
+            Ok(mut slot) => {
+                slot.closed = true;
+                slot.waker.take()
+            }
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[test]
+fn test_async_handle() {
+    use std::sync::mpsc::{channel, Sender};
+    use std::task::Wake;
+    use std::thread;
+
+    use app::App;
+    use super::{Config, StoreChannel};
+    use super::backend;
+    use super::memory::Memory;
+
+    struct Unpark(Sender<()>);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.send(()).is_ok();
+        }
+    }
+
+    // Polls `future` to completion, sleeping until woken in between
+    fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+        let (tx, rx) = channel();
+        let waker = Waker::from(Arc::new(Unpark(tx)));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match Pin::new(&mut future).poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => rx.recv().unwrap()
+            }
+        }
+    }
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let channel = StoreChannel::new();
+    let store = AsyncStoreHandle::new(channel.handle());
+    let backend = Memory::new(app.handle(), &Config::default());
+
+    thread::spawn(move|| {
+        backend::serve(Box::new(backend), channel, Default::default());
+    });
+
+    let data = ZoneData::new(path![moo], Default::default());
+
+    // Futures are made before the backend gets to them, and resolve in any order
+    let written = store.write_data(path![moo], &data);
+    let missing = store.stat(&path![cow]);
+
+    assert_eq!(block_on(missing).unwrap(), None);
+    assert!(block_on(written).unwrap());
+    assert_eq!(block_on(store.load_data(path![moo])).unwrap(), Some(data));
+
+    // A dropped reply resolves like the blocking call
+    assert_eq!(block_on(store.quarantine(path![moo])).unwrap(), false);
+
+    // Calls to a Store that has gone away fail
+    let store = AsyncStoreHandle::new(StoreChannel::new().handle());
+
+    match block_on(store.load_data(path![moo])) {
+        Err(StoreError::Disconnected) => (),
+        result => panic!("Expected Disconnected, got {:?}", result)
+    }
+}
@@ -0,0 +1,238 @@
+//! Garbage collection of orphaned zone data.
+//!
+//! Stored data can outlive its zone: a node going down after a zone's data was delegated away but
+//! before the zone saved the delegation, a delete failing, or data copied in from another node.
+//! Such data is never loaded again, yet it is kept, listed and copied around forever.
+//!
+//! A GC pass starts at the root zone and follows delegations (see `Node::delegations`) through
+//! stored zone data to find the zones still in use. Any other stored zone is an orphan, and is
+//! either quarantined (`StoreCall::Quarantine`) or deleted. Active zones are never collected, and
+//! their data in memory is followed instead of what is stored, which may be behind.
+//!
+//! Passes run on demand (`store.gc` in the shell), and every so often with `STORE_GC`, within the
+//! maintenance windows of `STORE_MAINTENANCE` (see `store::maintenance`).
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use super::StoreError;
+use super::maintenance::MaintenancePolicy;
+use app::AppHandle;
+use node::NodeTree;
+use path::Path;
+
+/// What happens to orphaned zone data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GcMode {
+    Quarantine, // Moved aside, for backends that can
+    Delete
+}
+
+/// When GC passes run.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct GcPolicy {
+    pub interval: u64, // Seconds between passes, 0 for on demand only
+    pub mode: GcMode
+}
+
+impl Default for GcMode {
+    fn default() -> GcMode {
+        GcMode::Quarantine
+    }
+}
+
+impl FromStr for GcMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<GcMode, String> {
+        match s {
+            "quarantine" => Ok(GcMode::Quarantine),
+            "delete" => Ok(GcMode::Delete),
+            _ => Err(format!("Unknown GC mode: {}", s))
+        }
+    }
+}
+
+impl FromStr for GcPolicy {
+    type Err = String;
+
+    /// Parses `none`, `<seconds>` or `<seconds>:<mode>`. Orphans are quarantined by default.
+    fn from_str(s: &str) -> Result<GcPolicy, String> {
+        if s == "none" {
+            return Ok(Default::default());
+        }
+
+        let mut parts = s.splitn(2, ':');
+
+        let interval = match parts.next().unwrap().parse() {
+            Ok(0) | Err(_) => return Err(format!("Bad GC interval: {}", s)),
+            Ok(interval) => interval
+        };
+
+        let mode = match parts.next() {
+            None => Default::default(),
+            Some(mode) => try!(mode.parse())
+        };
+
+        Ok(GcPolicy { interval: interval, mode: mode })
+    }
+}
+
+impl fmt::Display for GcPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.interval {
+            0 => write!(f, "on demand"),
+            interval => write!(f, "every {}s, {:?}", interval, self.mode)
+        }
+    }
+}
+
+/// Runs GC passes every `policy.interval` seconds, if set, once `maintenance` allows them.
+pub fn spawn(app: &AppHandle, policy: GcPolicy, maintenance: MaintenancePolicy) {
+    if policy.interval == 0 {
+        return;
+    }
+
+    let app = app.clone();
+
+    thread::spawn(move|| {
+        loop {
+            thread::sleep(Duration::from_secs(policy.interval));
+
+            maintenance.wait(&app.store);
+
+            if let Err(err) = collect(&app, policy.mode) {
+                error!("GC failed: {}", err);
+            }
+        }
+    });
+}
+
+/// Runs a GC pass, returning the number of orphaned zones collected.
+pub fn collect(app: &AppHandle, mode: GcMode) -> Result<usize, StoreError> {
+    let orphans = try!(orphans(app));
+    let mut collected = 0;
+
+    for path in orphans {
+        let done = try!(match mode {
+            GcMode::Quarantine => app.store.quarantine(path.clone()),
+            GcMode::Delete => app.store.delete_data(path.clone())
+        });
+
+        if done {
+            info!("GC: {:?} orphaned zone {:?}", mode, path);
+            collected += 1;
+        }
+        else {
+            error!("GC: could not {:?} orphaned zone {:?}", mode, path);
+        }
+    }
+
+    Ok(collected)
+}
+
+/// Lists stored zones no longer reachable from the root zone. Fails if the Store does, rather than
+/// taking zones it could not load for orphans.
+pub fn orphans(app: &AppHandle) -> Result<Vec<Path>, StoreError> {
+    let mut stored = vec![];
+    let mut failed = None;
+
+    try!(app.store.each_zone(|path| stored.push(path)));
+
+    let orphans = find_orphans(stored, |path| {
+        if let Some(zone) = app.manager.find(path) {
+            if zone.state().is_ready() {
+                return Some(zone.dump());
+            }
+        }
+
+        if failed.is_some() {
+            return None;
+        }
+
+        match app.store.load_data(path.clone()) {
+            Err(err) => {
+                failed = Some(err);
+                None
+            },
+            Ok(data) => data.map(|data| data.tree)
+        }
+    });
+
+    if let Some(err) = failed {
+        return Err(err);
+    }
+
+    // Zones spawned since their parent was searched
+    Ok(orphans.into_iter().filter(|path| app.manager.find(path).is_none()).collect())
+}
+
+/// Walks delegations from the root zone, with `tree` giving the data of each zone found, and
+/// returns the `stored` zones not found.
+fn find_orphans<F>(stored: Vec<Path>, mut tree: F) -> Vec<Path> where F: FnMut(&Path) -> Option<NodeTree> {
+    let mut live = HashSet::new();
+    let mut pending = vec![Path::empty()];
+
+    live.insert(Path::empty());
+
+    while let Some(path) = pending.pop() {
+        let data = match tree(&path) {
+            None => continue,
+            Some(data) => data
+        };
+
+        for mut delegated in data.node.delegations() {
+            let mut zone = path.clone();
+
+            zone.append(&mut delegated);
+
+            if live.insert(zone.clone()) {
+                pending.push(zone);
+            }
+        }
+    }
+
+    stored.into_iter().filter(|path| ! live.contains(path)).collect()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(GcPolicy::default()));
+    assert_eq!("3600".parse(), Ok(GcPolicy { interval: 3600, mode: GcMode::Quarantine }));
+    assert_eq!("60:delete".parse(), Ok(GcPolicy { interval: 60, mode: GcMode::Delete }));
+
+    assert!("0".parse::<GcPolicy>().is_err());
+    assert!("moo".parse::<GcPolicy>().is_err());
+    assert!("60:moo".parse::<GcPolicy>().is_err());
+}
+
+#[test]
+fn test_find_orphans() {
+    use std::collections::HashMap;
+
+    use node::{Node, Vis};
+
+    fn tree(node: Node) -> NodeTree {
+        NodeTree { node: node, vis: Vis::permanent() }
+    }
+
+    // Root delegates moo, which delegates moo.cow. Nothing live delegates pig or its children
+    let mut zones = HashMap::new();
+
+    zones.insert(Path::empty(), tree(Node::delegate(1000).prepend_path(&["moo".to_string()])));
+    zones.insert(path![moo], tree(Node::delegate(1000).prepend_path(&["cow".to_string()])));
+    zones.insert(path![moo.cow], tree(Node::default()));
+    zones.insert(path![moo.cow.calf], tree(Node::default()));
+    zones.insert(path![pig], tree(Node::delegate(1000).prepend_path(&["piglet".to_string()])));
+    zones.insert(path![pig.piglet], tree(Node::default()));
+
+    let stored = zones.keys().cloned().collect();
+    let mut orphans = find_orphans(stored, |path| zones.get(path).cloned());
+
+    orphans.sort();
+
+    assert_eq!(orphans, [path![moo.cow.calf], path![pig], path![pig.piglet]]);
+}
@@ -0,0 +1,245 @@
+//! Retained history of zone files, for point-in-time recovery.
+//!
+//! Before the fs store replaces or removes a zone file, it keeps the file as a version of the zone
+//! in `_history/<zone file name>/<written>` in the same directory, named after the microsecond the
+//! file was written. Versions are hard links where possible, so keeping one costs no I/O: zone
+//! files are only ever replaced by renaming, never changed in place. The `Retention` decides how
+//! many versions are kept.
+//!
+//! Restoring a zone to a point in time brings back the version it had then, or removes it if it
+//! did not exist yet (see `fs::blocking_restore`). Versions are full writes only: deltas and WAL
+//! entries logged after the last full write before that time are dropped.
+//!
+//! History is not counted towards the quota.
+
+use std;
+use std::fmt;
+use std::fs::DirBuilder;
+use std::io::ErrorKind;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Directory versions are kept in, next to the zone files they are versions of.
+pub const DIR: &'static str = "_history";
+
+/// How much history is kept for each zone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retention {
+    None,
+    Versions(usize), // This many versions before the current one
+    Window(u64)      // Enough versions to restore to any point within this many seconds
+}
+
+/// Versions of zone files.
+pub struct History {
+    retention: Retention
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention::None
+    }
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    /// Parses `none`, `versions:<count>` or `window:<seconds>`.
+    fn from_str(s: &str) -> Result<Retention, String> {
+        let mut parts = s.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some("none"), None) => Ok(Retention::None),
+            (Some("versions"), Some(count)) => match count.parse() {
+                Ok(0) | Err(_) => Err(format!("Bad history version count: {}", count)),
+                Ok(count) => Ok(Retention::Versions(count))
+            },
+            (Some("window"), Some(secs)) => match secs.parse() {
+                Ok(0) | Err(_) => Err(format!("Bad history window: {}", secs)),
+                Ok(secs) => Ok(Retention::Window(secs))
+            },
+            _ => Err(format!("Unknown history retention: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Retention::None => write!(f, "none"),
+            Retention::Versions(count) => write!(f, "{} versions", count),
+            Retention::Window(secs) => write!(f, "{} seconds", secs)
+        }
+    }
+}
+
+impl History {
+    pub fn new(retention: Retention) -> History {
+        History { retention: retention }
+    }
+
+    /// Keeps the zone file at `filepath` as a version, if there is one, before it is replaced or
+    /// removed. Older versions past the retention are dropped. Errors are logged, rather than
+    /// failing the write.
+    pub fn keep(&self, filepath: &std::path::Path) {
+        if self.retention == Retention::None {
+            return;
+        }
+
+        let written = match std::fs::metadata(filepath) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => return,
+            Err(err) => {
+                error!("Error reading metadata - {}: {}", filepath.display(), err);
+                return;
+            },
+            Ok(meta) => meta.modified().map(micros).unwrap_or(0)
+        };
+
+        let dir = history_dir(filepath);
+        let version = dir.join(written.to_string());
+
+        let result = DirBuilder::new().recursive(true).create(&dir)
+            .and_then(|_| match std::fs::hard_link(filepath, &version) {
+                // Kept already, e.g. restored from this very version
+                Err(ref err) if err.kind() == ErrorKind::AlreadyExists => Ok(()),
+                Err(_) => std::fs::copy(filepath, &version).map(|_| ()),
+                Ok(_) => Ok(())
+            });
+
+        match result {
+            Err(err) => error!("Error keeping {} in history: {}", filepath.display(), err),
+            Ok(_) => self.prune(&dir)
+        }
+    }
+
+    /// Kept version of the zone file at `filepath` that was current at `timestamp`, in
+    /// milliseconds since the epoch. None if it was not written yet.
+    pub fn version_at(&self, filepath: &std::path::Path, timestamp: u64) -> Option<std::path::PathBuf> {
+        let dir = history_dir(filepath);
+
+        versions(&dir).into_iter()
+            .filter(|&written| written <= timestamp * 1000 + 999)
+            .last()
+            .map(|written| dir.join(written.to_string()))
+    }
+
+    /// Drops versions in `dir` past the retention.
+    fn prune(&self, dir: &std::path::Path) {
+        let versions = versions(dir);
+
+        let expired = match self.retention {
+            Retention::None => 0,
+            Retention::Versions(count) => versions.len().saturating_sub(count),
+            Retention::Window(secs) => {
+                let cutoff = micros(SystemTime::now()).saturating_sub(secs * 1000000);
+
+                // The version current at the cutoff is needed to restore to it
+                versions.iter().rposition(|&written| written <= cutoff).unwrap_or(0)
+            }
+        };
+
+        for written in &versions[..expired] {
+            let version = dir.join(written.to_string());
+
+            if let Err(err) = std::fs::remove_file(&version) {
+                error!("Error removing {}: {}", version.display(), err);
+            }
+        }
+    }
+}
+
+/// Paths of the zone files in `dir` with any history, whether or not they exist now.
+pub fn zone_files(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    match std::fs::read_dir(dir.join(DIR)) {
+        Err(_) => vec![],
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .map(|entry| dir.join(entry.file_name()))
+            .collect()
+    }
+}
+
+/// When the file at `filepath` was last written, in milliseconds since the epoch.
+pub fn modified(filepath: &std::path::Path) -> Option<u64> {
+    std::fs::metadata(filepath).and_then(|meta| meta.modified()).ok().map(|time| micros(time) / 1000)
+}
+
+/// Directory holding versions of the zone file at `filepath`.
+pub fn history_dir(filepath: &std::path::Path) -> std::path::PathBuf {
+    let dir = filepath.parent().unwrap_or(std::path::Path::new(""));
+
+    dir.join(DIR).join(filepath.file_name().unwrap_or_default())
+}
+
+/// Versions kept in `dir`, oldest first.
+fn versions(dir: &std::path::Path) -> Vec<u64> {
+    let mut versions: Vec<u64> = match std::fs::read_dir(dir) {
+        Err(_) => vec![],
+        Ok(entries) => entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().and_then(|name| name.parse().ok()))
+            .collect()
+    };
+
+    versions.sort();
+    versions
+}
+
+fn micros(time: SystemTime) -> u64 {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+    since.as_secs() * 1000000 + since.subsec_nanos() as u64 / 1000
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(Retention::None));
+    assert_eq!("versions:10".parse(), Ok(Retention::Versions(10)));
+    assert_eq!("window:86400".parse(), Ok(Retention::Window(86400)));
+
+    assert!("versions:0".parse::<Retention>().is_err());
+    assert!("window".parse::<Retention>().is_err());
+    assert!("moo".parse::<Retention>().is_err());
+}
+
+#[test]
+fn test_keep() {
+    use std::thread;
+    use std::time::Duration;
+
+    let dir = std::path::Path::new("test_data/history_keep");
+
+    std::fs::remove_dir_all(dir).ok();
+    DirBuilder::new().recursive(true).create(dir).unwrap();
+
+    let file = dir.join("rmoo_1");
+    let history = History::new(Retention::Versions(2));
+    let mut written = vec![];
+
+    for i in 0..3u8 {
+        // Replaced like zone files are, so kept versions stay as they were
+        std::fs::write(file.with_extension("tmp"), [i]).unwrap();
+        std::fs::rename(file.with_extension("tmp"), &file).unwrap();
+        written.push(modified(&file).unwrap());
+        history.keep(&file);
+
+        // Same file kept twice is one version
+        history.keep(&file);
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(versions(&history_dir(&file)).len(), 2);
+    assert_eq!(zone_files(dir), vec![file.clone()]);
+
+    // First version is gone, the others are found by when they were written
+    assert_eq!(history.version_at(&file, written[0]), None);
+    assert_eq!(std::fs::read(history.version_at(&file, written[1]).unwrap()).unwrap(), [1]);
+    assert_eq!(std::fs::read(history.version_at(&file, written[2] + 5).unwrap()).unwrap(), [2]);
+
+    // Nothing is kept without retention
+    let file = dir.join("rcow_2");
+
+    std::fs::write(&file, [0]).unwrap();
+    History::new(Retention::None).keep(&file);
+
+    assert!(History::new(Retention::None).version_at(&file, written[2]).is_none());
+}
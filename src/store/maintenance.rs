@@ -0,0 +1,193 @@
+//! Maintenance windows for heavy Store work.
+//!
+//! Periodic GC passes (see `store::gc`) and tier demotion (see `store::tiered`) read and write a lot
+//! of zone data, and compete with live traffic for I/O whenever they run. With `STORE_MAINTENANCE`,
+//! a pass only starts within the configured times of day, in UTC, and while the Store keeps up:
+//! fewer loads and writes queued than the configured depth. Otherwise it waits until both hold again.
+//! A pass that has started runs to the end.
+//!
+//! Compaction and verification only run when asked for in the shell, and start right away.
+
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::StoreHandle;
+
+/// How often waiting work checks again whether it may run.
+const CHECK_INTERVAL: u64 = 30;
+
+/// When heavy Store work may run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MaintenancePolicy {
+    pub windows: Vec<Window>, // Times of day, any time if none
+    pub max_queue: usize      // Queued Store work it runs below, 0 for no limit
+}
+
+/// Time of day, in minutes since midnight UTC, from `start` up to `end`. Windows ending before they
+/// start span midnight.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Window {
+    pub start: u32,
+    pub end: u32
+}
+
+impl MaintenancePolicy {
+    pub fn is_limited(&self) -> bool {
+        ! self.windows.is_empty() || self.max_queue > 0
+    }
+
+    /// Whether heavy work may run at `minute` of the day, with `queued` Store work waiting.
+    pub fn allows(&self, minute: u32, queued: usize) -> bool {
+        let in_window = self.windows.is_empty() || self.windows.iter().any(|window| window.contains(minute));
+
+        in_window && (self.max_queue == 0 || queued < self.max_queue)
+    }
+
+    /// Waits until heavy work may run on `store`. A Store that can't report its queue counts as
+    /// idle.
+    pub fn wait(&self, store: &StoreHandle) {
+        let mut waited = false;
+
+        loop {
+            let queued = store.stats().map(|stats| stats.queue_depth + stats.pending_writes).unwrap_or(0);
+
+            if self.allows(minute_now(), queued) {
+                break;
+            }
+
+            if ! waited {
+                info!("Waiting for maintenance window ({})", self);
+                waited = true;
+            }
+
+            thread::sleep(Duration::from_secs(CHECK_INTERVAL));
+        }
+    }
+}
+
+impl FromStr for MaintenancePolicy {
+    type Err = String;
+
+    /// Parses `always`, or a comma-separated list of `<HH:MM>-<HH:MM>` windows and an optional
+    /// `queue:<depth>`.
+    fn from_str(s: &str) -> Result<MaintenancePolicy, String> {
+        let mut policy = MaintenancePolicy::default();
+
+        if s == "always" {
+            return Ok(policy);
+        }
+
+        for part in s.split(',') {
+            if part.starts_with("queue:") {
+                policy.max_queue = match part["queue:".len()..].parse() {
+                    Ok(0) | Err(_) => return Err(format!("Bad maintenance queue depth: {}", part)),
+                    Ok(depth) => depth
+                };
+            }
+            else {
+                policy.windows.push(try!(part.parse()));
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for MaintenancePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if ! self.is_limited() {
+            return write!(f, "always");
+        }
+
+        let mut parts: Vec<String> = self.windows.iter().map(|window| window.to_string()).collect();
+
+        if self.max_queue > 0 {
+            parts.push(format!("under {} queued", self.max_queue));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+impl Window {
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        }
+        else {
+            self.start <= minute || minute < self.end
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    /// Parses `<HH:MM>-<HH:MM>`.
+    fn from_str(s: &str) -> Result<Window, String> {
+        let mut times = s.splitn(2, '-').map(parse_time);
+
+        match (times.next(), times.next()) {
+            (Some(Some(start)), Some(Some(end))) if start != end => Ok(Window { start: start, end: end }),
+            _ => Err(format!("Bad maintenance window: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02}:{:02}-{:02}:{:02}", self.start / 60, self.start % 60, self.end / 60, self.end % 60)
+    }
+}
+
+/// Parses `<HH:MM>` into minutes since midnight.
+fn parse_time(s: &str) -> Option<u32> {
+    let mut parts = s.splitn(2, ':').map(|part| part.parse::<u32>().ok());
+
+    match (parts.next(), parts.next()) {
+        (Some(Some(hours)), Some(Some(minutes))) if hours < 24 && minutes < 60 => Some(hours * 60 + minutes),
+        _ => None
+    }
+}
+
+/// Minutes since midnight UTC.
+fn minute_now() -> u32 {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+
+    ((secs % 86400) / 60) as u32
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("always".parse(), Ok(MaintenancePolicy::default()));
+    assert_eq!("02:00-05:30,queue:16".parse(), Ok(MaintenancePolicy {
+        windows: vec![Window { start: 120, end: 330 }],
+        max_queue: 16
+    }));
+    assert_eq!("queue:8".parse(), Ok(MaintenancePolicy { windows: vec![], max_queue: 8 }));
+
+    assert!("moo".parse::<MaintenancePolicy>().is_err());
+    assert!("02:00".parse::<MaintenancePolicy>().is_err());
+    assert!("02:00-24:00".parse::<MaintenancePolicy>().is_err());
+    assert!("02:00-02:00".parse::<MaintenancePolicy>().is_err());
+    assert!("queue:0".parse::<MaintenancePolicy>().is_err());
+}
+
+#[test]
+fn test_allows() {
+    let policy: MaintenancePolicy = "23:00-01:00,03:00-04:00,queue:10".parse().unwrap();
+
+    // Windows spanning midnight
+    assert!(policy.allows(23 * 60 + 30, 0));
+    assert!(policy.allows(30, 0));
+    assert!(! policy.allows(60, 0));
+    assert!(policy.allows(3 * 60, 0));
+    assert!(! policy.allows(12 * 60, 0));
+
+    // Busy Store
+    assert!(! policy.allows(30, 10));
+
+    assert!(MaintenancePolicy::default().allows(12 * 60, 1000));
+}
@@ -0,0 +1,222 @@
+//! An in-memory zone store. Serialized zone data is kept in a `HashMap` and lost on exit, for
+//! pure cache nodes and tests that shouldn't touch the filesystem.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::mpsc::Sender;
+#[cfg(test)] use std::thread;
+
+use super::*;
+use super::backend;
+use super::codec::Codec;
+#[cfg(test)] use super::migrate;
+use super::raw::RawZone;
+use super::verify;
+use app::{App, AppHandle};
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+pub struct Memory {
+    app: AppHandle,
+
+    codec: Codec,
+    zones: HashMap<Path, Vec<u8>>
+}
+
+impl Memory {
+    /// Start the Store "process".
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let store = Memory::new(app.handle(), config);
+
+        backend::spawn(Box::new(store), channel, config);
+    }
+
+    pub fn new(app: AppHandle, config: &Config) -> Memory {
+        Memory {
+            app: app,
+            codec: config.codec.clone(),
+            zones: HashMap::new()
+        }
+    }
+
+    fn read(&self, path: &Path) -> Result<ZoneData, StoreError> {
+        self.read_raw(path).and_then(RawZone::deserialize)
+    }
+
+    /// Decodes the data stored for `path`, leaving it serialized.
+    fn read_raw(&self, path: &Path) -> Result<RawZone, StoreError> {
+        match self.zones.get(path) {
+            None => Ok(Default::default()),
+            Some(blob) => Ok(RawZone::new(try!(self.codec.decode(blob.clone()))))
+        }
+    }
+}
+
+impl StoreBackend for Memory {
+    /// Lists all Zone Paths stored under `prefix`
+    fn list(&mut self, prefix: Path, tx: Sender<Path>) {
+        let prefix = Prefix(prefix);
+
+        for path in self.zones.keys().filter(|path| prefix.matches(path)) {
+            tx.send(path.clone()).unwrap();
+        }
+    }
+
+    /// Loads data for a `Zone`, notifying its handle when done. The `Zone` deserializes it.
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        match self.read_raw(&path) {
+            Err(StoreError::Corrupt { reason, .. }) => {
+                error!("Corrupt data for {:?}: {}", path, reason);
+                self.app.stats.store.reads_corrupt.increment();
+                zone.corrupt();
+            },
+            Err(err) => {
+                error!("Error loading {:?}: {}", path, err.description());
+                self.app.stats.store.reads_errors.increment();
+            },
+            Ok(raw) => zone.loaded_raw(raw)
+        }
+
+        self.app.stats.store.reads.increment();
+    }
+
+    /// Delete data for a `Zone`, notifying its handle when done.
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        self.zones.remove(&path);
+        zone.deleted();
+    }
+
+    /// Delete data for `path`, replying when done.
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        self.zones.remove(&path);
+        reply.send(true).is_ok(); // ignore if caller goes away
+    }
+
+    /// Load and send `ZoneData` for `Path` to channel.
+    fn load_data(&mut self, path: Path, tx: Reply<Option<ZoneData>>) {
+        tx.send(self.read(&path).ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Decode and send data for `Path` to channel, still serialized.
+    fn load_raw(&mut self, path: Path, reply: Reply<Option<RawZone>>) {
+        reply.send(self.read_raw(&path).ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Request for notification to write data. Writes never block, so always ready.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        zone.save();
+    }
+
+    /// Replies with the size of the data for `Path`. Modification times are not kept.
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        let stat = self.zones.get(&path).map(|blob| ZoneStat { size: blob.len() as u64, modified: None });
+
+        reply.send(stat).is_ok(); // ignore if caller goes away
+    }
+
+    /// Checks that the data for `Path` decodes and deserializes.
+    fn verify(&mut self, path: Path, reply: Reply<Result<(), StoreError>>) {
+        let result = self.read(&path).and_then(|data| verify::check(&path, &data));
+
+        reply.send(result).is_ok(); // ignore if caller goes away
+    }
+
+    /// Write data for a `Zone`, notifying its handle when done.
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        match self.codec.encode(data) {
+            Err(err) => {
+                error!("Error writing {:?}: {}", path, err.description());
+                self.app.stats.store.writes_errors.increment();
+                zone.write_failed(err);
+            },
+            Ok(blob) => {
+                self.zones.insert(path, blob);
+                zone.saved();
+            }
+        }
+
+        self.app.stats.store.writes.increment();
+    }
+
+    /// Write data for several paths, replying whether it succeeded. Nothing is written if any of
+    /// it can't be encoded.
+    fn write_atomic(&mut self, writes: Vec<(Path, Vec<u8>)>, reply: Reply<bool>) {
+        let mut blobs = Vec::with_capacity(writes.len());
+
+        for (path, data) in writes {
+            match self.codec.encode(data) {
+                Err(err) => {
+                    error!("Error writing {:?}: {}", path, err.description());
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                    return;
+                },
+                Ok(blob) => blobs.push((path, blob))
+            }
+        }
+
+        self.zones.extend(blobs);
+        reply.send(true).is_ok(); // ignore if caller goes away
+    }
+
+    /// Write data for `path`, replying whether it succeeded.
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        let written = match self.codec.encode(data) {
+            Err(err) => {
+                error!("Error writing {:?}: {}", path, err.description());
+                false
+            },
+            Ok(blob) => {
+                self.zones.insert(path, blob);
+                true
+            }
+        };
+
+        reply.send(written).is_ok(); // ignore if caller goes away
+    }
+}
+
+#[test]
+fn test_write_load() {
+    use std::sync::Arc;
+
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let chan = StoreChannel::new();
+    let handle = chan.handle();
+    let mut store = Memory::new(app.handle(), &Config::default());
+
+    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
+    let path = path![moo];
+
+    assert_eq!(store.read(&path).unwrap(), Default::default());
+
+    let expected = ZoneData::new(
+        path.clone(),
+        NodeTree {
+            vis: Vis::update(1000),
+            node: Node::expand(JSON::String(String::from("moo")), 1000)
+        }
+    );
+
+    let serialized = migrate::serialize(&expected).unwrap();
+
+    store.write(noop_zone, path.clone(), serialized);
+
+    assert_eq!(store.read(&path).unwrap(), expected);
+
+    thread::spawn(move|| {
+        backend::serve(Box::new(store), chan, Default::default());
+    });
+
+    assert_eq!(handle.load_raw(path.clone()).unwrap().unwrap().deserialize().unwrap(), expected);
+    assert_eq!(handle.load_data(path.clone()).unwrap(), Some(expected));
+
+    let mut paths = vec![];
+
+    handle.each_zone(|p| paths.push(p)).unwrap();
+
+    assert_eq!(paths, [path]);
+}
@@ -0,0 +1,519 @@
+//! Versioning of the persisted `ZoneData` layout.
+//!
+//! Serialized zone data is tagged with the version of its layout, before it is encoded by a
+//! `Codec`:
+//!
+//! ```text
+//! "QMV" <version: u32 LE> <bincode serialized ZoneData>
+//! ```
+//!
+//! Data written before the tag existed is version 0. On load, older versions are upgraded one
+//! version at a time by `MIGRATIONS` before being deserialized, and written back in the current
+//! layout on the next write. The fs store can also upgrade a data directory offline, see
+//! `fs::migrate_dir`.
+//!
+//! Diffs logged by the WAL and delta logs (see `store::wal`, `store::delta`) are tagged the same
+//! way. Those logged before version 3 are untagged, in the layout of version 2, and upgraded as
+//! they are replayed.
+//!
+//! Corrupt zone data in the current layout can be salvaged in part with `recover`, which keeps what
+//! still deserializes: nodes are read depth first until one can't be, and the rest is dropped.
+//!
+//! Changing the serde layout of `ZoneData` (or anything in it) means bumping `VERSION` and adding
+//! a migration from the previous layout.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{Cursor, Read};
+
+use bincode;
+
+use super::StoreError;
+use expiry::Expiry;
+use node::NodeTree;
+use path::Path;
+use value::Value;
+use zone::ZoneData;
+
+const MAGIC: &'static [u8] = b"QMV";
+const HEADER_LEN: usize = 7;
+
+/// Current version of the `ZoneData` layout.
+pub const VERSION: u32 = 3;
+
+/// Upgrades serialized data from version `i` to `i + 1`, without the tag.
+const MIGRATIONS: &'static [fn(Vec<u8>) -> Result<Vec<u8>, StoreError>] = &[
+    from_v0,
+    from_v1,
+    from_v2
+];
+
+/// Layouts of nodes before and after version 3, to convert data between them.
+#[derive(Deserialize, Serialize)]
+struct VisV2 {
+    updated: u64,
+    deleted: u64
+}
+
+#[derive(Deserialize, Serialize)]
+struct NodeV2 {
+    vis: VisV2,
+    value: Value,
+    keys: Option<BTreeMap<String, NodeV2>>,
+    delegated: u64
+}
+
+#[derive(Deserialize, Serialize)]
+struct NodeV3 {
+    vis: VisV2,
+    value: Value,
+    keys: Option<BTreeMap<String, NodeV3>>,
+    delegated: u64,
+    created: u64
+}
+
+#[derive(Deserialize, Serialize)]
+struct NodeTreeV2 {
+    node: NodeV2,
+    vis: VisV2
+}
+
+#[derive(Deserialize, Serialize)]
+struct NodeTreeV3 {
+    node: NodeV3,
+    vis: VisV2
+}
+
+#[derive(Deserialize, Serialize)]
+struct ZoneDataV2 {
+    path: Path,
+    tree: NodeTreeV2,
+    expiry: Expiry
+}
+
+#[derive(Deserialize, Serialize)]
+struct ZoneDataV3 {
+    path: Path,
+    tree: NodeTreeV3,
+    expiry: Expiry
+}
+
+impl From<NodeV2> for NodeV3 {
+    /// Nodes still visible count as created when their value was last written, as far back as
+    /// version 2 can tell.
+    fn from(node: NodeV2) -> NodeV3 {
+        let created = if node.vis.updated > node.vis.deleted { node.vis.updated } else { 0 };
+
+        NodeV3 {
+            vis: node.vis,
+            value: node.value,
+            keys: node.keys.map(|keys| keys.into_iter().map(|(k, child)| (k, NodeV3::from(child))).collect()),
+            delegated: node.delegated,
+            created: created
+        }
+    }
+}
+
+impl From<NodeTreeV2> for NodeTreeV3 {
+    fn from(tree: NodeTreeV2) -> NodeTreeV3 {
+        NodeTreeV3 { node: NodeV3::from(tree.node), vis: tree.vis }
+    }
+}
+
+/// Serializes zone data in the current layout, tagged with its version.
+pub fn serialize(data: &ZoneData) -> Result<Vec<u8>, StoreError> {
+    let serialized = try!(bincode::serialize(data, bincode::Infinite).map_err(failed));
+
+    Ok(tag(VERSION, serialized))
+}
+
+/// Serializes a diff for the WAL in the current layout, tagged with its version.
+pub fn serialize_diff(diff: &NodeTree) -> Result<Vec<u8>, StoreError> {
+    let serialized = try!(bincode::serialize(diff, bincode::Infinite).map_err(failed));
+
+    Ok(tag(VERSION, serialized))
+}
+
+/// Serializes the diffs of a delta in the current layout, tagged with its version.
+pub fn serialize_delta(diffs: &[NodeTree]) -> Result<Vec<u8>, StoreError> {
+    let serialized = try!(bincode::serialize(diffs, bincode::Infinite).map_err(failed));
+
+    Ok(tag(VERSION, serialized))
+}
+
+/// Deserializes a diff logged in the WAL, in the current layout or untagged from before version 3.
+pub fn deserialize_diff(buffer: &[u8]) -> Result<NodeTree, StoreError> {
+    if try!(diff_version(buffer)) == VERSION {
+        return bincode::deserialize(&buffer[HEADER_LEN..]).map_err(bad_diff);
+    }
+
+    let diff: NodeTreeV2 = try!(bincode::deserialize(buffer).map_err(bad_diff));
+    let upgraded = try!(bincode::serialize(&NodeTreeV3::from(diff), bincode::Infinite).map_err(failed));
+
+    bincode::deserialize(&upgraded).map_err(bad_diff)
+}
+
+/// Deserializes the diffs of a delta, in the current layout or untagged from before version 3.
+pub fn deserialize_delta(buffer: &[u8]) -> Result<Vec<NodeTree>, StoreError> {
+    if try!(diff_version(buffer)) == VERSION {
+        return bincode::deserialize(&buffer[HEADER_LEN..]).map_err(bad_diff);
+    }
+
+    let diffs: Vec<NodeTreeV2> = try!(bincode::deserialize(buffer).map_err(bad_diff));
+    let diffs: Vec<NodeTreeV3> = diffs.into_iter().map(NodeTreeV3::from).collect();
+    let upgraded = try!(bincode::serialize(&diffs, bincode::Infinite).map_err(failed));
+
+    bincode::deserialize(&upgraded).map_err(bad_diff)
+}
+
+/// Deserializes zone data of any known version.
+pub fn deserialize(buffer: Vec<u8>) -> Result<ZoneData, StoreError> {
+    let buffer = try!(upgrade(buffer));
+
+    bincode::deserialize(&buffer[HEADER_LEN..])
+        .map_err(|err| StoreError::corrupt(format!("Bad zone data: {}", err.description())))
+}
+
+/// Same as `deserialize`, reading serialized zone data from `reader` as it goes. Data in an older
+/// version is read in full first, to be upgraded.
+pub fn deserialize_from<R: Read>(mut reader: R) -> Result<ZoneData, StoreError> {
+    let mut buffer = Vec::with_capacity(HEADER_LEN);
+
+    try!(reader.by_ref().take(HEADER_LEN as u64).read_to_end(&mut buffer)
+        .map_err(StoreError::Io));
+
+    if buffer.len() == HEADER_LEN && version(&buffer).ok() == Some(VERSION) {
+        return bincode::deserialize_from(&mut reader, bincode::Infinite)
+            .map_err(|err| StoreError::corrupt(format!("Bad zone data: {}", err.description())));
+    }
+
+    try!(reader.read_to_end(&mut buffer).map_err(StoreError::Io));
+
+    deserialize(buffer)
+}
+
+/// Same as `deserialize`, salvaging what can be read of corrupt data in the current layout rather
+/// than failing, see `salvage`. Returns true along with the data if it had to.
+pub fn recover(buffer: Vec<u8>) -> Result<(ZoneData, bool), StoreError> {
+    if version(&buffer).ok() != Some(VERSION) {
+        return deserialize(buffer).map(|data| (data, false));
+    }
+
+    match bincode::deserialize(&buffer[HEADER_LEN..]) {
+        Ok(data) => Ok((data, false)),
+        Err(err) => match salvage(&buffer[HEADER_LEN..]) {
+            Some(data) => Ok((data, true)),
+            None => Err(StoreError::corrupt(format!("Bad zone data: {}", err.description())))
+        }
+    }
+}
+
+/// Version of serialized zone data.
+pub fn version(buffer: &[u8]) -> Result<u32, StoreError> {
+    if ! buffer.starts_with(MAGIC) {
+        return Ok(0);
+    }
+
+    if buffer.len() < HEADER_LEN {
+        return Err(StoreError::corrupt("Truncated version header"));
+    }
+
+    let v = &buffer[MAGIC.len()..HEADER_LEN];
+
+    Ok(v[0] as u32 | (v[1] as u32) << 8 | (v[2] as u32) << 16 | (v[3] as u32) << 24)
+}
+
+/// Upgrades serialized zone data to the current version.
+pub fn upgrade(buffer: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    let version = try!(version(&buffer));
+
+    if version == VERSION {
+        return Ok(buffer);
+    }
+
+    if version > VERSION {
+        return Err(StoreError::Unsupported(format!("Zone data version {} is newer than {}", version, VERSION)));
+    }
+
+    let mut buffer = if version == 0 { buffer } else { buffer[HEADER_LEN..].to_vec() };
+
+    for migration in &MIGRATIONS[version as usize..] {
+        buffer = try!(migration(buffer));
+    }
+
+    Ok(tag(VERSION, buffer))
+}
+
+/// Version of a logged diff, 0 for untagged ones. Diffs were only ever tagged with version 3 and up.
+fn diff_version(buffer: &[u8]) -> Result<u32, StoreError> {
+    match try!(version(buffer)) {
+        version if version > VERSION => Err(StoreError::Unsupported(format!("Diff version {} is newer than {}", version, VERSION))),
+        version if version != 0 && version < 3 => Err(StoreError::corrupt(format!("Unknown diff version {}", version))),
+        version => Ok(version)
+    }
+}
+
+/// Reads the next value from a `Cursor`, none if it can't be. Lengths read can't go past what is
+/// left, so bad ones fail rather than allocate.
+macro_rules! read {
+    ($reader:expr) => {{
+        let left = ($reader.get_ref().len() as u64).saturating_sub($reader.position());
+
+        bincode::deserialize_from($reader, bincode::Bounded(left)).ok()
+    }}
+}
+
+/// Reads what it can of zone data in the current layout, without the tag. Children are read in
+/// order, keeping those before and the readable part of the first that can't be; those after it
+/// can't be found and are lost, along with the TTLs. Returns none unless the path and the root's
+/// own value can be read.
+fn salvage(buffer: &[u8]) -> Option<ZoneData> {
+    let reader = &mut Cursor::new(buffer);
+
+    let path: Path = match read!(reader) {
+        Some(path) => path,
+        None => return None
+    };
+
+    let (node, complete) = match salvage_node(reader) {
+        Some(salvaged) => salvaged,
+        None => return None
+    };
+
+    let vis: Option<VisV2> = if complete { read!(reader) } else { None };
+    let expiry: Option<Expiry> = if vis.is_some() { read!(reader) } else { None };
+
+    let salvaged = ZoneDataV3 {
+        path: path,
+        tree: NodeTreeV3 { vis: vis.unwrap_or(VisV2 { updated: 0, deleted: 0 }), node: node },
+        expiry: expiry.unwrap_or_default()
+    };
+
+    bincode::serialize(&salvaged, bincode::Infinite).ok()
+        .and_then(|serialized| bincode::deserialize(&serialized).ok())
+}
+
+/// Reads a node and as many of its children as it can, and whether it read all of it.
+fn salvage_node(reader: &mut Cursor<&[u8]>) -> Option<(NodeV3, bool)> {
+    let vis: VisV2 = match read!(reader) {
+        Some(vis) => vis,
+        None => return None
+    };
+
+    let value: Value = match read!(reader) {
+        Some(value) => value,
+        None => return None
+    };
+
+    let mut node = NodeV3 { vis: vis, value: value, keys: None, delegated: 0, created: 0 };
+
+    let tag: Option<u8> = read!(reader);
+
+    let count: u64 = match tag {
+        Some(0) => 0,
+        Some(1) => match read!(reader) {
+            Some(count) => count,
+            None => return Some((node, false))
+        },
+        _ => return Some((node, false))
+    };
+
+    if count > 0 {
+        let mut keys = BTreeMap::new();
+        let mut complete = true;
+
+        for _ in 0..count {
+            let key: String = match read!(reader) {
+                Some(key) => key,
+                None => {
+                    complete = false;
+                    break;
+                }
+            };
+
+            match salvage_node(reader) {
+                Some((child, true)) => {
+                    keys.insert(key, child);
+                },
+                Some((child, false)) => {
+                    keys.insert(key, child);
+                    complete = false;
+                    break;
+                },
+                None => {
+                    complete = false;
+                    break;
+                }
+            }
+        }
+
+        node.keys = Some(keys);
+
+        if ! complete {
+            return Some((node, false));
+        }
+    }
+
+    let delegated: Option<u64> = read!(reader);
+    let created: Option<u64> = read!(reader);
+
+    match (delegated, created) {
+        (Some(delegated), Some(created)) => {
+            node.delegated = delegated;
+            node.created = created;
+            Some((node, true))
+        },
+        _ => Some((node, false))
+    }
+}
+
+fn bad_diff(err: bincode::Error) -> StoreError {
+    StoreError::corrupt(format!("Bad diff: {}", err.description()))
+}
+
+fn failed(err: bincode::Error) -> StoreError {
+    StoreError::SerializationFailed(err.to_string())
+}
+
+fn tag(version: u32, serialized: Vec<u8>) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(HEADER_LEN + serialized.len());
+
+    buffer.extend_from_slice(MAGIC);
+    buffer.extend_from_slice(&[version as u8, (version >> 8) as u8, (version >> 16) as u8, (version >> 24) as u8]);
+    buffer.extend_from_slice(&serialized);
+
+    buffer
+}
+
+/// Version 1 only adds the tag.
+fn from_v0(buffer: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    Ok(buffer)
+}
+
+/// Version 2 adds TTLs (`ZoneData::expiry`), after the rest of the data. Older zones have none.
+fn from_v1(mut buffer: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    let expiry = try!(bincode::serialize(&Expiry::default(), bincode::Infinite)
+        .map_err(|err| StoreError::SerializationFailed(err.to_string())));
+
+    buffer.extend_from_slice(&expiry);
+
+    Ok(buffer)
+}
+
+/// Version 3 adds when nodes were created, see `NodeV3::from`.
+fn from_v2(buffer: Vec<u8>) -> Result<Vec<u8>, StoreError> {
+    let data: ZoneDataV2 = try!(bincode::deserialize(&buffer)
+        .map_err(|err| StoreError::corrupt(format!("Bad version 2 zone data: {}", err.description()))));
+
+    let upgraded = ZoneDataV3 {
+        path: data.path,
+        tree: NodeTreeV3::from(data.tree),
+        expiry: data.expiry
+    };
+
+    bincode::serialize(&upgraded, bincode::Infinite).map_err(failed)
+}
+
+#[test]
+fn test_version() {
+    assert_eq!(MIGRATIONS.len(), VERSION as usize);
+
+    assert_eq!(version(b"moo").unwrap(), 0);
+    assert_eq!(version(&tag(VERSION, b"moo".to_vec())).unwrap(), VERSION);
+    assert_eq!(version(&tag(300, vec![])).unwrap(), 300);
+
+    assert!(version(b"QMV\x01").is_err());
+}
+
+#[cfg(test)]
+fn test_tree_v2() -> NodeTreeV2 {
+    NodeTreeV2 {
+        node: NodeV2 { vis: VisV2 { updated: 1000, deleted: 0 }, value: Value::Null, keys: None, delegated: 0 },
+        vis: VisV2 { updated: 1000, deleted: 0 }
+    }
+}
+
+#[test]
+fn test_upgrade() {
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let v1 = bincode::serialize(&(Path::empty(), test_tree_v2()), bincode::Infinite).unwrap();
+    let mut v2 = v1.clone();
+    v2.extend_from_slice(&bincode::serialize(&Expiry::default(), bincode::Infinite).unwrap());
+
+    let v3 = ZoneDataV3 {
+        path: Path::empty(),
+        tree: NodeTreeV3::from(test_tree_v2()),
+        expiry: Expiry::default()
+    };
+
+    let upgraded = tag(VERSION, bincode::serialize(&v3, bincode::Infinite).unwrap());
+
+    // Untagged data predates versioning
+    assert_eq!(upgrade(v1.clone()).unwrap(), upgraded);
+    assert_eq!(upgrade(tag(1, v1)).unwrap(), upgraded);
+    assert_eq!(upgrade(tag(2, v2)).unwrap(), upgraded);
+    assert_eq!(upgrade(tag(VERSION, b"moo".to_vec())).unwrap(), tag(VERSION, b"moo".to_vec()));
+
+    // Nodes count as created when last written
+    let expected = ZoneData::new(Path::empty(), NodeTree { node: Node::expand(JSON::Null, 1000), vis: Vis::update(1000) });
+
+    assert_eq!(deserialize(upgraded).unwrap(), expected);
+
+    // Data from the future can't be read
+    match upgrade(tag(VERSION + 1, b"moo".to_vec())) {
+        Err(StoreError::Unsupported(_)) => (),
+        other => panic!("Expected unsupported version, got {:?}", other)
+    }
+}
+
+#[test]
+fn test_diffs() {
+    use node::{Node, Vis};
+    use serde_json::Value as JSON;
+
+    let expected = NodeTree { node: Node::expand(JSON::Null, 1000), vis: Vis::update(1000) };
+
+    // Logged before diffs were tagged
+    assert_eq!(deserialize_diff(&bincode::serialize(&test_tree_v2(), bincode::Infinite).unwrap()).unwrap(), expected);
+    assert_eq!(deserialize_delta(&bincode::serialize(&vec![test_tree_v2()], bincode::Infinite).unwrap()).unwrap(), vec![expected.clone()]);
+
+    assert_eq!(deserialize_diff(&serialize_diff(&expected).unwrap()).unwrap(), expected);
+    assert_eq!(deserialize_delta(&serialize_delta(&[expected.clone()]).unwrap()).unwrap(), vec![expected]);
+
+    assert!(deserialize_diff(&tag(2, b"moo".to_vec())).is_err());
+    assert!(deserialize_diff(&tag(VERSION + 1, b"moo".to_vec())).is_err());
+}
+
+#[test]
+fn test_recover() {
+    use node::{Node, Vis};
+    use serde_json::{Map, Value as JSON};
+
+    let mut data = Map::new();
+
+    data.insert("cow".to_string(), JSON::from("m"));
+    data.insert("moo".to_string(), JSON::from("moo moo"));
+
+    let expected = ZoneData::new(path![moo], NodeTree { node: Node::expand(JSON::Object(data), 1000), vis: Vis::update(1000) });
+    let serialized = serialize(&expected).unwrap();
+
+    assert_eq!(recover(serialized.clone()).unwrap(), (expected.clone(), false));
+
+    // Only TTLs lost
+    assert_eq!(recover(serialized[..serialized.len() - 1].to_vec()).unwrap(), (expected.clone(), true));
+
+    // Children after the one cut short are lost
+    let key = b"\x03\0\0\0\0\0\0\0moo";
+    let at = (HEADER_LEN..serialized.len()).filter(|&i| serialized[i..].starts_with(key)).nth(1).unwrap();
+
+    let (recovered, salvaged) = recover(serialized[..at + key.len() + 4].to_vec()).unwrap();
+
+    assert!(salvaged);
+    assert_eq!(recovered.path, path![moo]);
+    assert_eq!(recovered.tree.node.len(), 1);
+
+    // Nothing to go on
+    assert!(recover(serialized[..HEADER_LEN + 4].to_vec()).is_err());
+}
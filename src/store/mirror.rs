@@ -0,0 +1,329 @@
+//! A mirrored zone store. Zone data is stored in a primary backend and copied to a secondary one,
+//! such as `store::s3`, so it survives losing the node without running a cluster.
+//!
+//! Loads and everything else are served by the primary alone. Each zone write, delete and direct
+//! data write also goes to the secondary, and the `Ack` decides when it counts as done: once the
+//! primary has it, with the secondary copy following in the background, or once both have it. With
+//! `Ack::Both` the secondary is written first, so a zone is never told it is saved while the
+//! secondary is left without its data.
+//!
+//! The secondary only ever gets complete zone data: diffs logged to the primary's WAL reach it with
+//! the next write, and zones always write all of their data rather than deltas.
+//!
+//! Configuration is read from the environment:
+//!
+//! * `STORE_MIRROR_PRIMARY` - primary backend, defaults to `fs`
+//! * `STORE_MIRROR_SECONDARY` - secondary backend (required), anything but `fs` and `tiered`,
+//!   which keep zones in the local data directory too
+//! * `STORE_MIRROR_ACK` - `primary` (default) or `both`
+
+use std::env;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use super::*;
+use super::reply::Callback;
+use super::spawn_backend;
+use super::stats::StoreStats;
+use app::App;
+use path::Path;
+use zone::ZoneHandle;
+
+/// Configuration for the mirrored Store.
+#[derive(Clone, Debug)]
+pub struct MirrorConfig {
+    pub primary: Backend,
+    pub secondary: Backend,
+    pub ack: Ack
+}
+
+/// When a mirrored write is done.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ack {
+    Primary, // Written to the primary, the secondary may still be behind
+    Both     // Written to both
+}
+
+pub struct Mirror {
+    rx: Receiver<StoreCall>,
+
+    primary: StoreHandle,
+    secondary: StoreHandle,
+
+    ack: Ack
+}
+
+impl MirrorConfig {
+    /// Reads configuration from `STORE_MIRROR_*` environment variables.
+    pub fn from_env() -> MirrorConfig {
+        let primary = env::var("STORE_MIRROR_PRIMARY").ok()
+            .map(|p| p.parse().unwrap_or_else(|err| panic!("STORE_MIRROR_PRIMARY: {}", err)))
+            .unwrap_or(Backend::FS);
+
+        let secondary = env::var("STORE_MIRROR_SECONDARY").expect("STORE_MIRROR_SECONDARY not set")
+            .parse().unwrap_or_else(|err| panic!("STORE_MIRROR_SECONDARY: {}", err));
+
+        if primary == Backend::Mirror {
+            panic!("STORE_MIRROR_PRIMARY: {:?} can't be the primary", primary);
+        }
+
+        if secondary == Backend::FS || secondary == Backend::Mirror || secondary == Backend::Tiered {
+            panic!("STORE_MIRROR_SECONDARY: {:?} can't be the secondary", secondary);
+        }
+
+        MirrorConfig {
+            primary: primary,
+            secondary: secondary,
+            ack: env::var("STORE_MIRROR_ACK").ok()
+                .map(|a| a.parse().unwrap_or_else(|err| panic!("STORE_MIRROR_ACK: {}", err)))
+                .unwrap_or(Ack::Primary)
+        }
+    }
+}
+
+impl FromStr for Ack {
+    type Err = String;
+
+    /// Parses `primary` or `both`.
+    fn from_str(s: &str) -> Result<Ack, String> {
+        match s {
+            "primary" => Ok(Ack::Primary),
+            "both" => Ok(Ack::Both),
+            _ => Err(format!("Unknown mirror ack: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for Ack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Ack::Primary => write!(f, "primary"),
+            Ack::Both => write!(f, "both")
+        }
+    }
+}
+
+impl Mirror {
+    /// Start the Store "process", along with the primary and secondary Stores it is made of.
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let mirror_config = MirrorConfig::from_env();
+
+        let primary = StoreChannel::new();
+        let secondary = StoreChannel::new();
+        let (primary_handle, secondary_handle) = (primary.handle(), secondary.handle());
+
+        // Zones are loaded from the primary, so that's where the cache does any good
+        let uncached = Config { cache: Default::default(), ..config.clone() };
+
+        spawn_backend(app, mirror_config.primary, primary, config);
+        spawn_backend(app, mirror_config.secondary, secondary, &uncached);
+
+        let store = Mirror::new(primary_handle, secondary_handle, channel, mirror_config.ack);
+
+        thread::spawn(move|| {
+            store.message_loop();
+        });
+    }
+
+    /// Creates a mirrored Store on top of running `primary` and `secondary` Stores.
+    pub fn new(primary: StoreHandle, secondary: StoreHandle, channel: StoreChannel, ack: Ack) -> Mirror {
+        Mirror {
+            rx: channel.rx,
+            primary: primary,
+            secondary: secondary,
+            ack: ack
+        }
+    }
+
+    fn message_loop(self) {
+        loop {
+            let call = self.rx.recv().unwrap();
+
+            match call {
+                StoreCall::Append(..) | StoreCall::List(..) | StoreCall::Load(..) | StoreCall::LoadData(..) |
+                StoreCall::LoadMany(..) | StoreCall::LoadRaw(..) | StoreCall::Quarantine(..) | StoreCall::Reload(..) |
+                StoreCall::RequestWrite(..) | StoreCall::Restore(..) | StoreCall::Stat(..) | StoreCall::Verify(..) => {
+                    self.primary.tx.send(call).unwrap()
+                },
+                StoreCall::Compact(path) => {
+                    self.secondary.tx.send(StoreCall::Compact(path.clone())).unwrap();
+                    self.primary.tx.send(StoreCall::Compact(path)).unwrap();
+                },
+                StoreCall::Delete(zone, path) => self.delete(zone, path),
+                StoreCall::DeleteData(path, reply) => {
+                    self.mirror(reply, move |reply| StoreCall::DeleteData(path.clone(), reply))
+                },
+                StoreCall::Shutdown(reply) => self.shutdown(reply),
+                StoreCall::Stats(reply) => self.stats(reply),
+                StoreCall::Write(zone, path, data) => self.write(zone, path, data),
+                StoreCall::WriteAtomic(writes, reply) => {
+                    self.mirror(reply, move |reply| StoreCall::WriteAtomic(writes.clone(), reply))
+                },
+                StoreCall::WriteBatch(writes) => for (zone, path, data) in writes {
+                    self.write(zone, path, data);
+                },
+                StoreCall::WriteData(path, data, reply) => {
+                    self.mirror(reply, move |reply| StoreCall::WriteData(path.clone(), data.clone(), reply))
+                },
+                // The secondary needs all of the zone's data
+                StoreCall::WriteDelta(zone, _, _) => zone.snapshot()
+            }
+        }
+    }
+
+    /// Deletes a zone from both Stores, notifying it once `ack` is satisfied.
+    fn delete(&self, zone: ZoneHandle, path: Path) {
+        let call = StoreCall::Delete(zone.clone(), path.clone());
+
+        self.mirror_zone(&zone, call, move |reply| StoreCall::DeleteData(path, reply));
+    }
+
+    /// Writes data for a `Zone` to both Stores, notifying it once `ack` is satisfied.
+    fn write(&self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        let call = StoreCall::Write(zone.clone(), path.clone(), data.clone());
+
+        self.mirror_zone(&zone, call, move |reply| StoreCall::WriteData(path, data, reply));
+    }
+
+    /// Makes `call` for `zone` on the primary Store, which notifies the zone, and the call made by
+    /// `secondary` on the secondary Store. With `Ack::Both`, `call` waits for the secondary to
+    /// succeed, and the zone fails to write if it doesn't.
+    fn mirror_zone<F>(&self, zone: &ZoneHandle, call: StoreCall, secondary: F) where F: FnOnce(Reply<bool>) -> StoreCall {
+        let path = zone.path();
+
+        let held = match self.ack {
+            Ack::Primary => {
+                self.primary.tx.send(call).unwrap();
+                None
+            },
+            Ack::Both => Some((call, zone.clone(), self.primary.clone()))
+        };
+
+        let callback = Callback::new(move |written: Option<bool>| {
+            let written = written.unwrap_or(false);
+
+            if ! written {
+                error!("Error mirroring {:?} to secondary store", path);
+            }
+
+            if let Some((call, zone, primary)) = held {
+                if written {
+                    primary.tx.send(call).is_ok(); // ignore if the Store goes away
+                }
+                else {
+                    zone.write_failed(StoreError::Backend(format!("Could not mirror {:?}", path)));
+                }
+            }
+        });
+
+        self.secondary.tx.send(secondary(Reply::Callback(callback))).unwrap();
+    }
+
+    /// Makes the call made by `make` on both Stores, replying whether it succeeded on the primary,
+    /// or on both with `Ack::Both`.
+    fn mirror<F>(&self, reply: Reply<bool>, make: F) where F: Fn(Reply<bool>) -> StoreCall {
+        let (primary_tx, primary_rx) = channel();
+        let (secondary_tx, secondary_rx) = channel();
+        let ack = self.ack;
+
+        self.primary.tx.send(make(primary_tx.into())).unwrap();
+        self.secondary.tx.send(make(secondary_tx.into())).unwrap();
+
+        thread::spawn(move|| {
+            let mut reply = Some(reply);
+            let primary = primary_rx.recv().unwrap_or(false);
+
+            if ack == Ack::Primary {
+                if let Some(reply) = reply.take() {
+                    reply.send(primary).is_ok(); // ignore if caller goes away
+                }
+            }
+
+            let secondary = secondary_rx.recv().unwrap_or(false);
+
+            if ! secondary {
+                error!("Error mirroring to secondary store");
+            }
+
+            if let Some(reply) = reply {
+                reply.send(primary && secondary).is_ok(); // ignore if caller goes away
+            }
+        });
+    }
+
+    /// Shuts down both Stores, replying once both are done.
+    fn shutdown(&self, reply: Reply<()>) {
+        let primary = self.primary.clone();
+        let secondary = self.secondary.clone();
+
+        thread::spawn(move|| {
+            for store in &[primary, secondary] {
+                if let Err(err) = store.shutdown() {
+                    error!("Error shutting down mirrored store: {}", err);
+                }
+            }
+
+            reply.send(()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Replies with I/O metrics of both Stores added up.
+    fn stats(&self, reply: Reply<StoreStats>) {
+        let mut stats = self.primary.stats().unwrap_or_default();
+
+        stats.merge(&self.secondary.stats().unwrap_or_default());
+
+        reply.send(stats).is_ok(); // ignore if caller goes away
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("primary".parse(), Ok(Ack::Primary));
+    assert_eq!("both".parse(), Ok(Ack::Both));
+
+    assert!("moo".parse::<Ack>().is_err());
+}
+
+#[test]
+fn test_mirror() {
+    use super::backend;
+    use super::memory::Memory;
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+
+    let spawn = || {
+        let channel = StoreChannel::new();
+        let handle = channel.handle();
+        let store = Memory::new(app.handle(), &Config::default());
+
+        thread::spawn(move|| {
+            backend::serve(Box::new(store), channel, Default::default());
+        });
+
+        handle
+    };
+
+    let (primary, secondary) = (spawn(), spawn());
+    let channel = StoreChannel::new();
+    let store = channel.handle();
+    let mirror = Mirror::new(primary.clone(), secondary.clone(), channel, Ack::Both);
+
+    thread::spawn(move|| {
+        mirror.message_loop();
+    });
+
+    let data = ZoneData::new(path![moo], Default::default());
+
+    // Written to both, loaded from the primary
+    assert!(store.write_data(path![moo], &data).unwrap());
+    assert_eq!(secondary.load_data(path![moo]).unwrap(), Some(data.clone()));
+    assert_eq!(store.load_data(path![moo]).unwrap(), Some(data));
+
+    // Deleted from both
+    assert!(store.delete_data(path![moo]).unwrap());
+    assert_eq!(secondary.stat(&path![moo]).unwrap(), None);
+    assert_eq!(primary.stat(&path![moo]).unwrap(), None);
+}
@@ -4,107 +4,735 @@
 //!
 //! Zones can load data or request to save data. When requesting to save data, `Store` will notify
 //! the Zone when it is not busy, at which point the Zone can send its latest copy of its data.
+//! Write requests can also be held back for a while by a `scheduler::Scheduler`, so frequently
+//! updated zones are not rewritten for every change.
+//!
+//! Zones splitting off children write their own and their children's data with
+//! `StoreCall::WriteAtomic`, so a crash mid-split doesn't lose what was moved. Backends that can't
+//! write atomically fail the call, and zones fall back to logging the split like any other change.
+//!
+//! Large zones can send just their changes since the last save with `StoreCall::WriteDelta`.
+//! Backends that keep deltas (see `store::delta`) ask the zone for a full snapshot every so often
+//! to consolidate them, and backends that don't always do.
+//!
+//! Stores queue up to `QUEUE_SIZE` calls. Past that, write requests fail with `StoreError::Busy`
+//! and zones ask again later, while other calls wait for room.
+//!
+//! `StoreHandle::shutdown` stops a Store taking writes, which it returns once every write queued
+//! before is on disk.
+//!
+//! Writes that fail with a transient error are retried with backoff (see `store::retry`), and the
+//! zone is told if they still fail, so it stays dirty.
+//!
+//! Backends also keep I/O metrics (see `store::stats`), retrieved with `StoreHandle::stats`.
+//!
+//! Recently written zone data can be kept in memory (see `store::cache`), so zones loaded again
+//! soon after they hibernate don't hit the backend.
+//!
+//! Backends do their I/O on worker threads that keep calls for each zone in order (see
+//! `store::workers`).
+//!
+//! Backends implement `StoreBackend` (see `store::backend`), so persistence not built in can be
+//! plugged in with `spawn_custom`.
+//!
+//! `StoreHandle` calls fail with `StoreError::Disconnected` once the Store "process" has gone
+//! away, rather than panicking in the caller.
+//!
+//! `StoreHandle::load_data_async` and `StoreHandle::load_data_then` load data without blocking
+//! either, sending it on a channel or to a callback. With the `async` feature,
+//! `future::AsyncStoreHandle` makes all of the calls that wait for a reply without blocking,
+//! returning futures instead.
 
+pub mod backend;
+pub mod backup;
+pub mod cache;
+pub mod codec;
+pub mod dedup;
+pub mod delta;
+pub mod encryption;
+pub mod events;
 pub mod fs;
+#[cfg(feature = "async")] pub mod future;
+pub mod gc;
+pub mod history;
+pub mod maintenance;
+pub mod memory;
+pub mod migrate;
+pub mod mirror;
 pub mod null;
+pub mod priority;
+pub mod quota;
+pub mod raw;
+pub mod read_only;
+pub mod reply;
+pub mod retry;
+#[cfg(feature = "rocksdb")] pub mod rocksdb;
+#[cfg(feature = "s3")] pub mod s3;
+pub mod scheduler;
+#[cfg(feature = "sled")] pub mod sled;
+pub mod stats;
+pub mod stream;
+pub mod throttle;
+pub mod tiered;
+pub mod verify;
+pub mod wal;
+pub mod workers;
 
+use std::env;
 use std::error::Error;
 use std::fmt;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError};
+use std::time::SystemTime;
 
+#[cfg(any(feature = "rocksdb", feature = "s3", feature = "sled"))]
 use bincode;
 
+use app::App;
+use self::cache::CacheSize;
+use self::codec::Codec;
+use self::encryption::Keyring;
+use self::events::{Events, StoreEvent};
+use self::gc::GcPolicy;
+use self::history::Retention;
+use self::maintenance::MaintenancePolicy;
+use self::quota::Quota;
+use self::raw::RawZone;
+use self::reply::{Callback, Reply};
+use self::retry::RetryPolicy;
+use self::scheduler::{Dirty, FlushPolicy};
+use self::stats::StoreStats;
+use self::throttle::ThrottlePolicy;
+use self::workers::PoolSize;
+use node::NodeTree;
 use path::Path;
 use zone::{ZoneData, ZoneHandle};
 
+pub use self::backend::StoreBackend;
+
+/// Store configuration, read from the environment at startup.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub backend: Backend,
+    pub cache: CacheSize,
+    pub codec: Codec,
+    pub dedup: bool,
+    pub durability: Durability,
+    pub flush: FlushPolicy,
+    pub gc: GcPolicy,
+    pub history: Retention,
+    pub maintenance: MaintenancePolicy,
+    pub quota: Quota,
+    pub read_only: bool,
+    pub retry: RetryPolicy,
+    pub throttle: ThrottlePolicy,
+    pub workers: PoolSize
+}
+
+/// Available Store backends. `RocksDB`, `S3` and `Sled` need their cargo feature enabled.
+/// `Tiered` keeps recently used zones in `FS`, and the rest in another backend. `Mirror` copies zone
+/// writes to a second backend.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    FS,
+    Memory,
+    Mirror,
+    RocksDB,
+    S3,
+    Sled,
+    Tiered
+}
+
+/// When backends flush written zone data to disk. Without a flush, writes survive the process
+/// crashing but not the OS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Durability {
+    Always,        // Flush before notifying a zone its data is saved
+    Interval(u64), // Flush in the background, at most this many milliseconds after writes
+    Never          // Leave it to the OS
+}
+
+/// Calls a Store queues up before callers have to wait, in its channel and again while they are
+/// served by priority (see `store::priority`).
+pub const QUEUE_SIZE: usize = 10000;
+
 /// A handle to the Store process. This is the shareable public interface.
 #[derive(Clone)]
 pub struct StoreHandle {
-    tx: Sender<StoreCall>
+    tx: SyncSender<StoreCall>,
+    events: Arc<Events>
 }
 
 /// Channel (both ends) to talk to Store, `rx` needed to spawn Store.
 pub struct StoreChannel {
     rx: Receiver<StoreCall>,
-    tx: Sender<StoreCall>
+    tx: SyncSender<StoreCall>,
+    events: Arc<Events>
 }
 
 /// Used for dispatching calls via message passing.
 pub enum StoreCall {
-    List(Sender<Path>),
+    Append(Path, Vec<u8>),
+    Compact(Path),
+    Delete(ZoneHandle, Path),
+    DeleteData(Path, Reply<bool>),
+    List(Path, Sender<Path>),
     Load(ZoneHandle, Path),
-    LoadData(Path, Sender<Option<ZoneData>>),
-    RequestWrite(ZoneHandle),
-    Write(ZoneHandle, Path, Vec<u8>)
+    LoadData(Path, Reply<Option<ZoneData>>),
+    LoadMany(Vec<(ZoneHandle, Path)>),
+    LoadRaw(Path, Reply<Option<RawZone>>),
+    Quarantine(Path, Reply<bool>),
+    Reload(ZoneHandle, Path),
+    RequestWrite(ZoneHandle, Dirty),
+    Restore(Option<Path>, u64, Reply<usize>),
+    Shutdown(Reply<()>),
+    Stat(Path, Reply<Option<ZoneStat>>),
+    Stats(Reply<StoreStats>),
+    Verify(Path, Reply<Result<(), StoreError>>),
+    Write(ZoneHandle, Path, Vec<u8>),
+    WriteAtomic(Vec<(Path, Vec<u8>)>, Reply<bool>),
+    WriteBatch(Vec<(ZoneHandle, Path, Vec<u8>)>),
+    WriteData(Path, Vec<u8>, Reply<bool>),
+    WriteDelta(ZoneHandle, Path, Vec<u8>)
+}
+
+/// What is stored for a zone, found without loading it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoneStat {
+    pub size: u64,                   // Bytes stored, as encoded by the backend
+    pub modified: Option<SystemTime> // Last written, if the backend keeps track
 }
 
-/// Storage error that includes generic Error-implementing errors
+/// Storage error, by kind, so callers can tell what went wrong without parsing descriptions
 #[derive(Debug)]
 pub enum StoreError {
-    ReadError(Box<Error>),
-    OtherError(Box<Error>),
-    WriteError(Box<Error>)
+    Backend(String),              // Backend library or service failed, such as RocksDB or S3
+    Busy,                         // Store queue is full, try again later
+    Corrupt {                     // Stored data failed its checksum or could not be deserialized
+        path: Option<Path>,       // Zone it was stored for, if known where it was found
+        reason: String
+    },
+    Disconnected,                 // Store "process" has gone away
+    Io(io::Error),                // Reading or writing files failed
+    NotFound,                     // Nothing is stored for the zone
+    QuotaExceeded,                // Write refused, stored data would exceed the quota (see `store::quota`)
+    ReadOnly,                     // Write or delete refused, the Store is read-only (see `store::read_only`)
+    SerializationFailed(String),  // Data could not be serialized, compressed or encrypted to be stored
+    ShutDown,                     // Write or delete refused, the Store has been shut down
+    Unsupported(String)           // Needs a feature this build or configuration doesn't have
+}
+
+impl Config {
+    /// Reads configuration from the environment. `STORE` selects the backend, `STORE_CACHE` how
+    /// much recently written zone data it keeps in memory, `STORE_COMPRESSION` the compression it
+    /// applies to zone data, `STORE_KEYS` / `STORE_KEYS_FILE` the keys it
+    /// encrypts zone data with, `STORE_DEDUP` whether zones with identical data share it,
+    /// `STORE_DURABILITY` when it flushes writes to disk, `STORE_FLUSH`
+    /// how often dirty zones are written, `STORE_GC` how often orphaned zone data is collected,
+    /// `STORE_HISTORY` how many old versions of zone data are kept, `STORE_MAINTENANCE` when
+    /// heavy background work may run,
+    /// `STORE_QUOTA` how much it may store, `STORE_READ_ONLY` whether it may change stored data
+    /// at all, `STORE_RETRY` how often it retries failed writes, `STORE_THROTTLE` how fast zones
+    /// may write, and `STORE_WORKERS` how many threads it does I/O on.
+    pub fn from_env() -> Config {
+        let keyring = Keyring::from_env().unwrap_or_else(|err| panic!("{}", err));
+
+        Config {
+            backend: parse_env("STORE"),
+            cache: parse_env("STORE_CACHE"),
+            codec: Codec {
+                compression: parse_env("STORE_COMPRESSION"),
+                keyring: keyring.map(Arc::new)
+            },
+            dedup: parse_flag("STORE_DEDUP"),
+            durability: parse_env("STORE_DURABILITY"),
+            flush: parse_env("STORE_FLUSH"),
+            gc: parse_env("STORE_GC"),
+            history: parse_env("STORE_HISTORY"),
+            maintenance: parse_env("STORE_MAINTENANCE"),
+            quota: parse_env("STORE_QUOTA"),
+            read_only: parse_flag("STORE_READ_ONLY"),
+            retry: parse_env("STORE_RETRY"),
+            throttle: parse_env("STORE_THROTTLE"),
+            workers: parse_env("STORE_WORKERS")
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Backend {
+        Backend::FS
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "fs" => Ok(Backend::FS),
+            "memory" => Ok(Backend::Memory),
+            "mirror" => Ok(Backend::Mirror),
+            "rocksdb" => Ok(Backend::RocksDB),
+            "s3" => Ok(Backend::S3),
+            "sled" => Ok(Backend::Sled),
+            "tiered" => Ok(Backend::Tiered),
+            _ => Err(format!("Unknown store backend: {}", s))
+        }
+    }
+}
+
+impl Default for Durability {
+    fn default() -> Durability {
+        Durability::Always
+    }
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    /// Parses `always`, `never` or `interval:<ms>`.
+    fn from_str(s: &str) -> Result<Durability, String> {
+        let mut parts = s.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some("always"), None) => Ok(Durability::Always),
+            (Some("never"), None) => Ok(Durability::Never),
+            (Some("interval"), Some(ms)) => match ms.parse() {
+                Ok(0) | Err(_) => Err(format!("Bad durability interval: {}", ms)),
+                Ok(ms) => Ok(Durability::Interval(ms))
+            },
+            _ => Err(format!("Unknown durability: {}", s))
+        }
+    }
+}
+
+/// Parses an optional environment variable, panicking with a useful message if it's bad.
+fn parse_env<T>(name: &str) -> T where T: Default + FromStr<Err = String> {
+    match env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|err| panic!("{}: {}", name, err)),
+        Err(_) => Default::default()
+    }
+}
+
+/// Parses an optional boolean environment variable, set with `1` or `true`.
+fn parse_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => match value.as_str() {
+            "1" | "true" => true,
+            "0" | "false" | "" => false,
+            _ => panic!("{}: Expected true or false, got {}", name, value)
+        },
+        Err(_) => false
+    }
+}
+
+/// Start the Store "process" for the configured backend, behind a `read_only::ReadOnly` Store if
+/// configured.
+pub fn spawn(app: &mut App, config: &Config) {
+    spawn_with(app, config, |app, channel| spawn_backend(app, config.backend, channel, config));
+}
+
+/// Same as `spawn`, with a backend not built in instead of `config.backend`. The rest of `config`
+/// still applies: writes are held back by `config.flush`, loads are cached by `config.cache`, and
+/// the backend is read-only with `config.read_only`.
+pub fn spawn_custom(app: &mut App, backend: Box<StoreBackend>, config: &Config) {
+    spawn_with(app, config, move |_, channel| backend::spawn(backend, channel, config));
+}
+
+fn spawn_with<F>(app: &mut App, config: &Config, spawn: F) where F: FnOnce(&App, StoreChannel) {
+    let channel = app.channels.store.take().expect("Receiver already taken");
+
+    if config.read_only {
+        let inner = StoreChannel::new();
+        let handle = inner.handle();
+
+        spawn(app, inner);
+        read_only::ReadOnly::spawn(app, channel, handle);
+    }
+    else {
+        spawn(app, channel);
+    }
+}
+
+/// Start a Store "process" for `backend`, serving calls on `channel`.
+fn spawn_backend(app: &App, backend: Backend, channel: StoreChannel, config: &Config) {
+    match backend {
+        Backend::FS => fs::FS::spawn(app, channel, config),
+        Backend::Memory => memory::Memory::spawn(app, channel, config),
+        Backend::Mirror => mirror::Mirror::spawn(app, channel, config),
+        #[cfg(feature = "rocksdb")]
+        Backend::RocksDB => rocksdb::RocksDB::spawn(app, channel, config),
+        #[cfg(feature = "s3")]
+        Backend::S3 => s3::S3::spawn(app, channel, config),
+        #[cfg(feature = "sled")]
+        Backend::Sled => sled::Sled::spawn(app, channel, config),
+        Backend::Tiered => tiered::Tiered::spawn(app, channel, config),
+        #[allow(unreachable_patterns)]
+        backend => panic!("Store backend {:?} not compiled in", backend)
+    }
+}
+
+/// Upgrades stored data to the current `ZoneData` layout while the Store is not running. Returns
+/// the number of zones upgraded. Other backends, and the cold tier of `Tiered`, upgrade zones as
+/// they are loaded and written.
+pub fn migrate(app: &App, config: &Config) -> Result<usize, StoreError> {
+    if config.read_only {
+        return Err(StoreError::ReadOnly);
+    }
+
+    match config.backend {
+        Backend::FS | Backend::Tiered => {
+            let mut migrated = 0;
+
+            for dir in fs::FS::dirs(app) {
+                migrated += try!(fs::migrate_dir(&dir, &config.codec));
+            }
+
+            Ok(migrated)
+        },
+        backend => Err(StoreError::Unsupported(format!("Offline migration not supported by {:?}", backend)))
+    }
 }
 
 impl StoreChannel {
+    /// Channel queueing up to `QUEUE_SIZE` calls. Calls past that wait for room, and write
+    /// requests fail with `StoreError::Busy`.
     pub fn new() -> StoreChannel {
-        let (tx, rx) = channel();
+        let (tx, rx) = sync_channel(QUEUE_SIZE);
 
-        StoreChannel { rx: rx, tx: tx }
+        StoreChannel { rx: rx, tx: tx, events: Default::default() }
     }
 
     pub fn handle(&self) -> StoreHandle {
-        StoreHandle { tx: self.tx.clone() }
+        StoreHandle { tx: self.tx.clone(), events: self.events.clone() }
     }
 }
 
 impl StoreHandle {
+    /// Logs a diff merged into a zone, so it can be recovered if the node goes down before the
+    /// zone is written.
+    pub fn append(&self, path: &Path, diff: &NodeTree) -> Result<(), StoreError> {
+        self.send(StoreCall::Append(path.clone(), try!(migrate::serialize_diff(diff))))
+    }
+
+    /// Rewrites stored data for a zone, dropping tombstones that no longer affect merges.
+    pub fn compact(&self, path: &Path) -> Result<(), StoreError> {
+        self.send(StoreCall::Compact(path.clone()))
+    }
+
+    /// Compacts all zones stored locally.
+    pub fn compact_all(&self) -> Result<(), StoreError> {
+        let mut result = Ok(());
+
+        try!(self.each_zone(|path| {
+            if result.is_ok() {
+                result = self.compact(&path);
+            }
+        }));
+
+        result
+    }
+
+    /// Deletes stored data for a zone and notifies zone directly via its handle.
+    pub fn delete(&self, zone: &ZoneHandle, path: &Path) -> Result<(), StoreError> {
+        self.send(StoreCall::Delete(zone.clone(), path.clone()))
+    }
+
+    /// Deletes stored data for a zone path without involving its `Zone`. Returns true if deleted.
+    pub fn delete_data(&self, path: Path) -> Result<bool, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::DeleteData(path, tx.into())));
+
+        Ok(rx.recv().unwrap_or(false))
+    }
+
+    /// Sends `event` to subscribers (see `store::events`).
+    pub fn emit(&self, event: StoreEvent) {
+        self.events.emit(event);
+    }
+
+    /// Writes all zones stored under `path_filter` to `writer` as a backup archive (see
+    /// `store::backup`). Returns the number of zones exported.
+    pub fn export<W: Write>(&self, path_filter: &Path, writer: W) -> Result<u64, StoreError> {
+        backup::export(self, path_filter, writer)
+    }
+
+    /// Writes all zones in a backup archive read from `reader` to the Store. Returns the number of
+    /// zones imported.
+    pub fn import<R: Read>(&self, reader: R) -> Result<u64, StoreError> {
+        backup::import(self, reader)
+    }
+
     /// Gets a list of Zone Paths stored locally
-    pub fn each_zone<F>(&self, mut f: F) where F: FnMut(Path) {
+    pub fn each_zone<F>(&self, f: F) -> Result<(), StoreError> where F: FnMut(Path) {
+        self.each_zone_under(&Path::empty(), f)
+    }
+
+    /// Gets a list of Zone Paths stored locally under `prefix`, and `prefix` itself. Backends skip
+    /// other zones where they can, rather than listing every zone.
+    pub fn each_zone_under<F>(&self, prefix: &Path, mut f: F) -> Result<(), StoreError> where F: FnMut(Path) {
         let (tx, rx) = channel();
 
-        self.tx.send(StoreCall::List(tx)).unwrap();
+        try!(self.send(StoreCall::List(prefix.clone(), tx)));
 
         for p in rx.iter() {
             f(p)
         }
+
+        Ok(())
     }
 
     /// Reads data for a given zone path and sends data back directly to the `Zone` asynchronously.
-    pub fn load(&self, zone: &ZoneHandle, path: &Path) {
-        self.tx.send(StoreCall::Load(zone.clone(), path.clone())).unwrap();
+    pub fn load(&self, zone: &ZoneHandle, path: &Path) -> Result<(), StoreError> {
+        self.send(StoreCall::Load(zone.clone(), path.clone()))
+    }
+
+    /// Same as `load`, reading stored data again even if a copy is cached, for zones whose data
+    /// was changed outside of the Store.
+    pub fn reload(&self, zone: &ZoneHandle, path: &Path) -> Result<(), StoreError> {
+        self.send(StoreCall::Reload(zone.clone(), path.clone()))
+    }
+
+    /// Same as `load` for many zones in one call, which the backend can read in whatever order reads
+    /// fastest, e.g. grouped by directory, rather than one by one.
+    pub fn load_many(&self, zones: Vec<(ZoneHandle, Path)>) -> Result<(), StoreError> {
+        self.send(StoreCall::LoadMany(zones))
+    }
+
+    /// Reads data for a given zone path and returns it. Returns None if it could not be read.
+    pub fn load_data(&self, path: Path) -> Result<Option<ZoneData>, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::LoadData(path, tx.into())));
+
+        rx.recv().map_err(|_| StoreError::Disconnected)
+    }
+
+    /// Same as `load_data`, sending the data on `tx` instead of waiting for it. `tx` is dropped
+    /// without a send if the Store fails the call.
+    pub fn load_data_async(&self, path: Path, tx: Sender<Option<ZoneData>>) -> Result<(), StoreError> {
+        self.send(StoreCall::LoadData(path, tx.into()))
+    }
+
+    /// Same as `load_data`, calling `f` with the result instead of waiting for it. `f` is called
+    /// once, on a Store thread, so it should hand the data off rather than do much with it. It is
+    /// called with the error too if the call can't be made.
+    pub fn load_data_then<F>(&self, path: Path, f: F) -> Result<(), StoreError> where F: FnOnce(Result<Option<ZoneData>, StoreError>) + Send + 'static {
+        let callback = Callback::new(move|data: Option<Option<ZoneData>>| f(data.ok_or(StoreError::Disconnected)));
+
+        self.send(StoreCall::LoadData(path, Reply::Callback(callback)))
+    }
+
+    /// Same as `load_data`, leaving the data serialized for the caller to deserialize on its own
+    /// thread (see `store::raw`).
+    pub fn load_raw(&self, path: Path) -> Result<Option<RawZone>, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::LoadRaw(path, tx.into())));
+
+        rx.recv().map_err(|_| StoreError::Disconnected)
+    }
+
+    /// Moves stored data for a zone path aside, where it is no longer listed or loaded, without
+    /// involving its `Zone`. Returns true if moved, false if the backend can't.
+    pub fn quarantine(&self, path: Path) -> Result<bool, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Quarantine(path, tx.into())));
+
+        Ok(rx.recv().unwrap_or(false))
+    }
+
+    /// Restores stored data for a zone path to what it was at `timestamp`, in milliseconds since
+    /// the epoch, from retained history (see `store::history`). Returns true if stored data
+    /// changed. Zones must not be loaded while they are restored, or they write their data over
+    /// it.
+    pub fn restore(&self, path: &Path, timestamp: u64) -> Result<bool, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Restore(Some(path.clone()), timestamp, tx.into())));
+
+        Ok(rx.recv().unwrap_or(0) > 0)
     }
 
-    /// Reads data for a given zone path and returns it.
-    pub fn load_data(&self, path: Path) -> Option<ZoneData> {
+    /// Same as `restore` for every zone stored now or at `timestamp`. Returns the number of zones
+    /// whose stored data changed.
+    pub fn restore_all(&self, timestamp: u64) -> Result<usize, StoreError> {
         let (tx, rx) = channel();
 
-        self.tx.send(StoreCall::LoadData(path, tx)).unwrap();
+        try!(self.send(StoreCall::Restore(None, timestamp, tx.into())));
 
-        rx.recv().unwrap()
+        Ok(rx.recv().unwrap_or(0))
     }
 
-    /// Ask for non-busy write notification.
-    pub fn request_write(&self, zone: &ZoneHandle) {
-        self.tx.send(StoreCall::RequestWrite(zone.clone())).unwrap();
+    /// Ask for non-busy write notification, for a zone with `dirty` changes not yet written (see
+    /// `scheduler`). Fails with `StoreError::Busy` rather than waiting if the Store is backed up,
+    /// so the zone can stay dirty and ask again later.
+    pub fn request_write(&self, zone: &ZoneHandle, dirty: Dirty) -> Result<(), StoreError> {
+        match self.tx.try_send(StoreCall::RequestWrite(zone.clone(), dirty)) {
+            Err(TrySendError::Full(_)) => Err(StoreError::Busy),
+            Err(TrySendError::Disconnected(_)) => Err(StoreError::Disconnected),
+            Ok(_) => Ok(())
+        }
+    }
+
+    /// Stops the Store taking writes, once the writes queued before are done and flushed to disk.
+    /// Returns when they are, so the node can exit without losing them. Writes and deletes made
+    /// after fail with `StoreError::ShutDown`, leaving their zones dirty, while loads are still
+    /// served. Write requests still held back by the `scheduler::Scheduler` are dropped too, and
+    /// their zones recovered from the WAL on restart, for backends with one.
+    pub fn shutdown(&self) -> Result<(), StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Shutdown(tx.into())));
+
+        rx.recv().map_err(|_| StoreError::Disconnected)
+    }
+
+    /// Finds out whether anything is stored for a zone path, and how much, without reading and
+    /// deserializing it. Returns None if nothing is.
+    pub fn stat(&self, path: &Path) -> Result<Option<ZoneStat>, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Stat(path.clone(), tx.into())));
+
+        Ok(rx.recv().unwrap_or(None))
+    }
+
+    /// Gets I/O metrics from the Store.
+    pub fn stats(&self) -> Result<StoreStats, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Stats(tx.into())));
+
+        Ok(rx.recv().unwrap_or_default())
+    }
+
+    /// Receives events from the Store, from now on (see `store::events`).
+    pub fn subscribe(&self) -> Receiver<StoreEvent> {
+        self.events.subscribe()
+    }
+
+    /// Checks that stored data for a zone path passes its checksum and deserializes, without
+    /// involving its `Zone` (see `store::verify`). Fails with the reason if it doesn't.
+    pub fn verify(&self, path: Path) -> Result<(), StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::Verify(path.clone(), tx.into())));
+
+        rx.recv().unwrap_or(Err(StoreError::Backend("Verification failed".into()))).map_err(|err| err.for_path(&path))
     }
 
     /// Saves data for a zone and notifies zone directly via its handle.
-    pub fn write(&self, zone: &ZoneHandle, path: &Path, data: &ZoneData) {
+    pub fn write(&self, zone: &ZoneHandle, path: &Path, data: &ZoneData) -> Result<(), StoreError> {
         // Optimization: seralize to send over channel instead of cloning ZoneData
-        let limit = bincode::Infinite;
-        let serialized = bincode::serialize(&data, limit).unwrap();
+        let serialized = try!(migrate::serialize(data));
+
+        self.send(StoreCall::Write(zone.clone(), path.clone(), serialized))
+    }
+
+    /// Saves data for several zone paths all or nothing, without involving their `Zone`s: after a
+    /// crash, either all of it is stored or none of it. Returns true if written, false if it
+    /// could not be, or the backend can't write atomically.
+    pub fn write_atomic(&self, zones: &[(Path, &ZoneData)]) -> Result<bool, StoreError> {
+        let mut writes = Vec::with_capacity(zones.len());
+
+        for &(ref path, data) in zones {
+            writes.push((path.clone(), try!(migrate::serialize(data))));
+        }
+
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::WriteAtomic(writes, tx.into())));
+
+        Ok(rx.recv().unwrap_or(false))
+    }
+
+    /// Saves data for a group of zones in one go, notifying each zone directly via its handle.
+    pub fn write_batch(&self, zones: &[(ZoneHandle, Path, &ZoneData)]) -> Result<(), StoreError> {
+        let mut writes = Vec::with_capacity(zones.len());
+
+        for &(ref zone, ref path, data) in zones {
+            writes.push((zone.clone(), path.clone(), try!(migrate::serialize(data))));
+        }
+
+        self.send(StoreCall::WriteBatch(writes))
+    }
+
+    /// Saves data for a zone path without involving its `Zone`. Returns true if written.
+    pub fn write_data(&self, path: Path, data: &ZoneData) -> Result<bool, StoreError> {
+        let (tx, rx) = channel();
+
+        try!(self.send(StoreCall::WriteData(path, try!(migrate::serialize(data)), tx.into())));
 
-        self.tx.send(StoreCall::Write(zone.clone(), path.clone(), serialized)).unwrap();
+        Ok(rx.recv().unwrap_or(false))
+    }
+
+    /// Saves diffs merged into a zone since its last save, instead of all its data. Notifies zone
+    /// directly via its handle, either that the diffs are saved or that the store wants a full
+    /// snapshot instead.
+    pub fn write_delta(&self, zone: &ZoneHandle, path: &Path, diffs: &[NodeTree]) -> Result<(), StoreError> {
+        self.send(StoreCall::WriteDelta(zone.clone(), path.clone(), try!(migrate::serialize_delta(diffs))))
+    }
+
+    /// Sends `call` to the Store, waiting for room in its queue. Fails if it has gone away.
+    fn send(&self, call: StoreCall) -> Result<(), StoreError> {
+        self.tx.send(call).map_err(|_| StoreError::Disconnected)
     }
 
     /// Creates a noop StoreHandle for testing
     #[cfg(test)]
     pub fn test_handle() -> StoreHandle {
-        use std::sync::mpsc::channel;
-
         StoreHandle {
-            tx: channel().0
+            tx: sync_channel(QUEUE_SIZE).0,
+            events: Default::default()
+        }
+    }
+}
+
+/// Encodes a zone `Path` as a key for key-value backends. Reversible, so zones can be listed from
+/// their keys alone.
+#[cfg(any(feature = "rocksdb", feature = "s3", feature = "sled"))]
+fn zonekey(path: &Path) -> Vec<u8> {
+    let limit = bincode::Infinite;
+
+    bincode::serialize(path, limit).unwrap()
+}
+
+impl StoreError {
+    /// Corrupt data, for a zone path if known.
+    pub fn corrupt<S: Into<String>>(reason: S) -> StoreError {
+        StoreError::Corrupt { path: None, reason: reason.into() }
+    }
+
+    /// Ties corrupt data to the zone `path` it was found for, if not already.
+    pub fn for_path(self, path: &Path) -> StoreError {
+        match self {
+            StoreError::Corrupt { path: None, reason } => StoreError::Corrupt { path: Some(path.clone()), reason: reason },
+            err => err
+        }
+    }
+
+    /// Whether the same call could succeed if tried again. Corrupt or missing data, data that
+    /// can't be stored, a dead Store, a full quota, a missing feature and a read-only or shut down
+    /// store don't go away on their own.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            StoreError::Backend(_) |
+            StoreError::Busy |
+            StoreError::Io(_) => true,
+            StoreError::Corrupt { .. } |
+            StoreError::Disconnected |
+            StoreError::NotFound |
+            StoreError::QuotaExceeded |
+            StoreError::ReadOnly |
+            StoreError::SerializationFailed(_) |
+            StoreError::ShutDown |
+            StoreError::Unsupported(_) => false
         }
     }
 }
@@ -112,9 +740,18 @@ impl StoreHandle {
 impl fmt::Display for StoreError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            StoreError::ReadError(ref err) => write!(f, "Read error: {}", err.description()),
-            StoreError::OtherError(ref err) => write!(f, "Other error: {}", err.description()),
-            StoreError::WriteError(ref err) => write!(f, "Write error: {}", err.description())
+            StoreError::Backend(ref reason) => write!(f, "Backend error: {}", reason),
+            StoreError::Busy => write!(f, "Store busy"),
+            StoreError::Corrupt { path: Some(ref path), ref reason } => write!(f, "Corrupt data for {:?}: {}", path, reason),
+            StoreError::Corrupt { path: None, ref reason } => write!(f, "Corrupt data: {}", reason),
+            StoreError::Disconnected => write!(f, "Store disconnected"),
+            StoreError::Io(ref err) => write!(f, "IO error: {}", err),
+            StoreError::NotFound => write!(f, "Not found"),
+            StoreError::QuotaExceeded => write!(f, "Quota exceeded"),
+            StoreError::ReadOnly => write!(f, "Read-only"),
+            StoreError::SerializationFailed(ref reason) => write!(f, "Serialization failed: {}", reason),
+            StoreError::ShutDown => write!(f, "Store shut down"),
+            StoreError::Unsupported(ref reason) => write!(f, "Unsupported: {}", reason)
         }
     }
 }
@@ -122,17 +759,137 @@ impl fmt::Display for StoreError {
 impl Error for StoreError {
     fn description(&self) -> &str {
         match *self {
-            StoreError::ReadError(ref err) => err.description(),
-            StoreError::OtherError(ref err) => err.description(),
-            StoreError::WriteError(ref err) => err.description()
+            StoreError::Backend(ref reason) => reason,
+            StoreError::Busy => "Store is busy, try again later",
+            StoreError::Corrupt { ref reason, .. } => reason,
+            StoreError::Disconnected => "Store has gone away",
+            StoreError::Io(ref err) => err.description(),
+            StoreError::NotFound => "No data stored",
+            StoreError::QuotaExceeded => "Store quota exceeded",
+            StoreError::ReadOnly => "Store is read-only",
+            StoreError::SerializationFailed(ref reason) => reason,
+            StoreError::ShutDown => "Store has been shut down",
+            StoreError::Unsupported(ref reason) => reason
         }
     }
 
     fn cause(&self) -> Option<&Error> {
         match *self {
-            StoreError::ReadError(ref err) => Some(&**err),
-            StoreError::OtherError(ref err) => Some(&**err),
-            StoreError::WriteError(ref err) => Some(&**err)
+            StoreError::Io(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+#[test]
+fn test_parse_durability() {
+    assert_eq!("always".parse(), Ok(Durability::Always));
+    assert_eq!("never".parse(), Ok(Durability::Never));
+    assert_eq!("interval:250".parse(), Ok(Durability::Interval(250)));
+
+    assert!("interval".parse::<Durability>().is_err());
+    assert!("interval:0".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+}
+
+#[test]
+fn test_disconnected() {
+    let handle = StoreChannel::new().handle();
+
+    assert!(match handle.stats() { Err(StoreError::Disconnected) => true, _ => false });
+    assert!(match handle.delete_data(path![moo]) { Err(StoreError::Disconnected) => true, _ => false });
+}
+
+#[test]
+fn test_busy() {
+    let channel = StoreChannel::new();
+    let handle = channel.handle();
+    let zone = ZoneHandle::test_handle(Arc::new(path![moo]));
+
+    for _ in 0..QUEUE_SIZE {
+        handle.request_write(&zone, Dirty::new()).unwrap();
+    }
+
+    // Write requests are shed once the queue is full, rather than waiting
+    assert!(match handle.request_write(&zone, Dirty::new()) { Err(StoreError::Busy) => true, _ => false });
+
+    channel.rx.recv().unwrap();
+
+    assert!(handle.request_write(&zone, Dirty::new()).is_ok());
+}
+
+#[test]
+fn test_error_kinds() {
+    let err = StoreError::corrupt("Checksum mismatch").for_path(&path![moo]);
+
+    // The first path found sticks
+    assert!(match err.for_path(&path![cow]) { StoreError::Corrupt { path: Some(ref path), .. } => *path == path![moo], _ => false });
+    assert!(match StoreError::NotFound.for_path(&path![moo]) { StoreError::NotFound => true, _ => false });
+
+    assert!(StoreError::Busy.is_transient());
+    assert!(StoreError::Io(io::Error::new(io::ErrorKind::Other, "moo")).is_transient());
+    assert!(! StoreError::corrupt("moo").is_transient());
+    assert!(! StoreError::SerializationFailed("moo".into()).is_transient());
+}
+
+#[test]
+fn test_load_data_async() {
+    let store = StoreChannel::new();
+    let handle = store.handle();
+    let (tx, rx) = channel();
+    let (done_tx, done_rx) = channel();
+    let done = done_tx.clone();
+
+    handle.load_data_async(path![moo], tx).unwrap();
+    handle.load_data_then(path![cow], move|result| done.send(result).unwrap()).unwrap();
+
+    // Callers get the result whenever the Store gets to the call, or fail if it drops the reply
+    for _ in 0..2 {
+        match store.rx.recv().unwrap() {
+            StoreCall::LoadData(path, reply) => if path == path![moo] {
+                reply.send(Some(ZoneData::new(path, Default::default()))).is_ok();
+            },
+            _ => panic!("Expected LoadData")
         }
     }
+
+    assert_eq!(rx.recv().unwrap(), Some(ZoneData::new(path![moo], Default::default())));
+    assert!(match done_rx.recv().unwrap() { Err(StoreError::Disconnected) => true, _ => false });
+
+    // Callbacks are called even when the Store has gone away
+    drop(store);
+
+    assert!(handle.load_data_then(path![moo], move|result| done_tx.send(result).unwrap()).is_err());
+    assert!(done_rx.recv().unwrap().is_err());
+}
+
+#[test]
+fn test_shutdown() {
+    use std::thread;
+
+    use self::memory::Memory;
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let store = StoreChannel::new();
+    let handle = store.handle();
+    let memory = Memory::new(app.handle(), &Config::default());
+
+    thread::spawn(move|| {
+        backend::serve(Box::new(memory), store, Default::default());
+    });
+
+    let data = ZoneData::new(path![moo], Default::default());
+
+    assert!(handle.write_data(path![moo], &data).unwrap());
+
+    handle.shutdown().unwrap();
+
+    // Writes and deletes are refused from then on, while reads are still served
+    assert!(! handle.write_data(path![cow], &data).unwrap());
+    assert!(! handle.delete_data(path![moo]).unwrap());
+    assert!(handle.stat(&path![moo]).unwrap().is_some());
+    assert_eq!(handle.stat(&path![cow]).unwrap(), None);
+
+    // Shutting down again is harmless
+    handle.shutdown().unwrap();
 }
@@ -1,77 +1,77 @@
 //! A null store that loads emppty data and ignores writes. For test use only
 
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use std::sync::mpsc::Sender;
 
 use super::*;
+use super::backend;
 use path::Path;
 use zone::ZoneHandle;
 
-pub struct Null {
-    rx: Receiver<StoreCall>,
-    tx: Sender<StoreCall>,
-}
+pub struct Null;
 
 impl Null {
     /// Start the Store "process".
     pub fn spawn() -> StoreHandle {
-        let store = Null::new();
-        let handle = store.handle();
+        let channel = StoreChannel::new();
+        let handle = channel.handle();
 
-        thread::spawn(move|| {
-            store.message_loop();
-        });
+        backend::spawn(Box::new(Null), channel, &Default::default());
 
         handle
     }
+}
 
-    pub fn new() -> Null {
-        let (tx, rx) = channel();
-
-        Null { tx: tx, rx: rx }
-    }
-
-    /// Return a handle to Store "process".
-    fn handle(&self) -> StoreHandle {
-        StoreHandle { tx: self.tx.clone() }
+impl StoreBackend for Null {
+    /// Deletes stored data for a `Zone`, notifying its handle when done. Nothing to delete.
+    fn delete(&mut self, zone: ZoneHandle, _: Path) {
+        zone.deleted();
     }
 
-    fn message_loop(self) {
-        loop {
-            let call = self.rx.recv().unwrap();
-
-            match call {
-                StoreCall::List(reply) => self.list(reply),
-                StoreCall::Load(zone, path) => self.load(zone, &path),
-                StoreCall::LoadData(path, tx) => self.load_data(path, tx),
-                StoreCall::RequestWrite(zone) => self.request_write(zone),
-                StoreCall::Write(zone, path, data) => self.write(zone, &path, &data)
-            }
-        }
+    /// Deletes stored data for a `Path`. Nothing to delete.
+    fn delete_data(&mut self, _: Path, reply: Reply<bool>) {
+        reply.send(true).is_ok(); // ignore if caller goes away
     }
 
     /// Lists all Zone Paths stored locally
-    pub fn list(&self, _: Sender<Path>) {
+    fn list(&mut self, _: Path, _: Sender<Path>) {
     }
 
     /// Loads data for a `Zone` asynchronously, notifying its handle when done. Will always load an
     /// empty data set.
-    pub fn load(&self, zone: ZoneHandle, _: &Path) {
+    fn load(&mut self, zone: ZoneHandle, _: Path) {
         zone.loaded(Default::default());
     }
 
     /// Asynchronously load and send `ZoneData` for `Path` to channel.
-    pub fn load_data(&self, _path: Path, tx: Sender<Option<ZoneData>>) {
+    fn load_data(&mut self, _path: Path, tx: Reply<Option<ZoneData>>) {
         tx.send(Some(Default::default())).is_ok(); // ignore if caller goes away
     }
 
+    /// Moves stored data for a `Path` aside. Nothing to move.
+    fn quarantine(&mut self, _: Path, reply: Reply<bool>) {
+        reply.send(true).is_ok(); // ignore if caller goes away
+    }
+
     /// Request for notification to write data. Never gonna happen.
-    pub fn request_write(&self, _: ZoneHandle) {
+    fn request_write(&mut self, _: ZoneHandle) {
+    }
+
+    /// Replies with what is stored for a `Path`. Nothing ever is.
+    fn stat(&mut self, _: Path, reply: Reply<Option<ZoneStat>>) {
+        reply.send(None).is_ok(); // ignore if caller goes away
     }
 
     /// Write data for a `Zone` asynchronously, notifying its handle when done.
     /// Not happening either.
-    pub fn write(&self, _: ZoneHandle, _: &Path, _: &Vec<u8>) {
+    fn write(&mut self, _: ZoneHandle, _: Path, _: Vec<u8>) {
     }
 
+    /// Write data for a `Path`. Ignored.
+    fn write_data(&mut self, _: Path, _: Vec<u8>, reply: Reply<bool>) {
+        reply.send(true).is_ok(); // ignore if caller goes away
+    }
+
+    /// Write a delta for a `Zone` asynchronously, notifying its handle when done. Same as `write`.
+    fn write_delta(&mut self, _: ZoneHandle, _: Path, _: Vec<u8>) {
+    }
 }
@@ -0,0 +1,244 @@
+//! Priorities of Store calls.
+//!
+//! Under heavy persistence, a Store's channel fills up with writes, and a zone loaded for a client
+//! request would wait for all of them. `serve` takes every call waiting in the channel into a
+//! `CallQueue` instead, and serves loads and stats first (`Priority::Interactive`), then write
+//! requests (`Priority::Notify`), then the writes, deletes and the rest, in the order they came
+//! (`Priority::Bulk`).
+//!
+//! A call never jumps ahead of bulk calls queued for the same zone, so a zone that hibernates and is
+//! loaded again still loads what it last wrote. Calls for every zone at once, such as a shutdown,
+//! hold up everything queued after them.
+//!
+//! Backends may still run work for a zone on a worker thread behind other work (see
+//! `store::workers`).
+
+use std::collections::{HashMap, VecDeque};
+
+use super::StoreCall;
+use path::Path;
+
+/// How soon a call is served, highest last.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Bulk,
+    Notify,
+    Interactive
+}
+
+/// Calls waiting to be served, by priority.
+pub struct CallQueue {
+    queues: [VecDeque<StoreCall>; 3], // By priority, lowest first
+    pending: HashMap<Path, usize>,    // Zones with bulk calls queued, and how many
+    barriers: usize                   // Bulk calls queued for every zone
+}
+
+/// Zones a bulk call affects.
+enum Affects<'a> {
+    Zones(Vec<&'a Path>),
+    All
+}
+
+impl Priority {
+    /// Priority of `call`, before it is ordered against queued calls.
+    pub fn of(call: &StoreCall) -> Priority {
+        match *call {
+            StoreCall::Load(..) | StoreCall::LoadData(..) | StoreCall::LoadMany(..) | StoreCall::LoadRaw(..) |
+            StoreCall::Reload(..) | StoreCall::Stat(..) | StoreCall::Stats(..) => Priority::Interactive,
+            StoreCall::RequestWrite(..) => Priority::Notify,
+            _ => Priority::Bulk
+        }
+    }
+}
+
+impl CallQueue {
+    pub fn new() -> CallQueue {
+        CallQueue {
+            queues: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            pending: HashMap::new(),
+            barriers: 0
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len()).sum()
+    }
+
+    /// Queues `call` behind calls of its priority, and behind bulk calls for its zone.
+    pub fn push(&mut self, call: StoreCall) {
+        let priority = match Priority::of(&call) {
+            Priority::Interactive if self.is_held(&call) => Priority::Bulk,
+            priority => priority
+        };
+
+        if priority == Priority::Bulk {
+            match affects(&call) {
+                Affects::All => self.barriers += 1,
+                Affects::Zones(paths) => for path in paths {
+                    *self.pending.entry(path.clone()).or_insert(0) += 1;
+                }
+            }
+        }
+
+        self.queues[priority as usize].push_back(call);
+    }
+
+    /// Takes the next call to serve.
+    pub fn pop(&mut self) -> Option<StoreCall> {
+        if let Some(call) = self.queues[Priority::Interactive as usize].pop_front() {
+            return Some(call);
+        }
+
+        if let Some(call) = self.queues[Priority::Notify as usize].pop_front() {
+            return Some(call);
+        }
+
+        let call = match self.queues[Priority::Bulk as usize].pop_front() {
+            None => return None,
+            Some(call) => call
+        };
+
+        self.served(&call);
+        Some(call)
+    }
+
+    /// Takes the next bulk call if it is a write, to be coalesced with the one being served.
+    pub fn pop_write(&mut self) -> Option<StoreCall> {
+        match self.queues[Priority::Bulk as usize].front() {
+            Some(&StoreCall::Write(..)) | Some(&StoreCall::WriteBatch(..)) => (),
+            _ => return None
+        }
+
+        let call = self.queues[Priority::Bulk as usize].pop_front().unwrap();
+
+        self.served(&call);
+        Some(call)
+    }
+
+    /// Whether `call` has to wait for bulk calls queued before it.
+    fn is_held(&self, call: &StoreCall) -> bool {
+        if self.barriers > 0 {
+            return true;
+        }
+
+        match *call {
+            StoreCall::Load(_, ref path) | StoreCall::LoadData(ref path, _) | StoreCall::LoadRaw(ref path, _) |
+            StoreCall::Reload(_, ref path) | StoreCall::Stat(ref path, _) => self.pending.contains_key(path),
+            StoreCall::LoadMany(ref zones) => zones.iter().any(|&(_, ref path)| self.pending.contains_key(path)),
+            _ => false
+        }
+    }
+
+    /// Forgets a bulk call taken off the queue.
+    fn served(&mut self, call: &StoreCall) {
+        match affects(call) {
+            Affects::All => self.barriers -= 1,
+            Affects::Zones(paths) => for path in paths {
+                let done = match self.pending.get_mut(path) {
+                    None => false,
+                    Some(count) => {
+                        *count -= 1;
+                        *count == 0
+                    }
+                };
+
+                if done {
+                    self.pending.remove(path);
+                }
+            }
+        }
+    }
+}
+
+fn affects(call: &StoreCall) -> Affects {
+    match *call {
+        StoreCall::Append(ref path, _) | StoreCall::Compact(ref path) | StoreCall::Delete(_, ref path) |
+        StoreCall::DeleteData(ref path, _) | StoreCall::Load(_, ref path) | StoreCall::LoadData(ref path, _) |
+        StoreCall::LoadRaw(ref path, _) | StoreCall::Quarantine(ref path, _) | StoreCall::Reload(_, ref path) | StoreCall::Restore(Some(ref path), _, _) |
+        StoreCall::Stat(ref path, _) | StoreCall::Verify(ref path, _) | StoreCall::Write(_, ref path, _) |
+        StoreCall::WriteData(ref path, _, _) | StoreCall::WriteDelta(_, ref path, _) => Affects::Zones(vec![path]),
+        StoreCall::LoadMany(ref zones) => Affects::Zones(zones.iter().map(|&(_, ref path)| path).collect()),
+        StoreCall::WriteAtomic(ref writes, _) => Affects::Zones(writes.iter().map(|&(ref path, _)| path).collect()),
+        StoreCall::WriteBatch(ref writes) => Affects::Zones(writes.iter().map(|&(_, ref path, _)| path).collect()),
+        StoreCall::RequestWrite(..) | StoreCall::Stats(_) => Affects::Zones(vec![]),
+        StoreCall::List(..) | StoreCall::Restore(None, _, _) | StoreCall::Shutdown(_) => Affects::All
+    }
+}
+
+#[test]
+fn test_priority() {
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+
+    use super::scheduler::Dirty;
+    use zone::ZoneHandle;
+
+    let zone = ZoneHandle::test_handle(Arc::new(path![moo]));
+    let mut queue = CallQueue::new();
+
+    // Writes for other zones wait behind loads and write requests
+    queue.push(StoreCall::Write(zone.clone(), path![cow], vec![]));
+    queue.push(StoreCall::RequestWrite(zone.clone(), Dirty::new()));
+    queue.push(StoreCall::LoadData(path![pig], channel().0.into()));
+
+    // A write for the same zone doesn't
+    queue.push(StoreCall::Write(zone.clone(), path![moo], vec![]));
+    queue.push(StoreCall::Load(zone.clone(), path![moo]));
+
+    assert_eq!(queue.len(), 5);
+
+    let order: Vec<_> = (0..5).map(|_| match queue.pop() {
+        Some(StoreCall::Write(_, path, _)) => format!("write {:?}", path.path),
+        Some(StoreCall::RequestWrite(..)) => "request".to_string(),
+        Some(StoreCall::LoadData(path, _)) | Some(StoreCall::Load(_, path)) => format!("load {:?}", path.path),
+        _ => panic!("Unexpected call")
+    }).collect();
+
+    assert_eq!(order, vec!["load [\"pig\"]", "request", "write [\"cow\"]", "write [\"moo\"]", "load [\"moo\"]"]);
+    assert!(queue.is_empty());
+
+    // Once the write is served, loads for its zone go first again
+    queue.push(StoreCall::Write(zone.clone(), path![moo], vec![]));
+    queue.push(StoreCall::Write(zone.clone(), path![cow], vec![]));
+
+    assert!(queue.pop_write().is_some());
+
+    queue.push(StoreCall::Load(zone.clone(), path![moo]));
+
+    match queue.pop() {
+        Some(StoreCall::Load(..)) => (),
+        _ => panic!("Expected load first")
+    }
+
+    // Listing holds up everything queued after it
+    queue.push(StoreCall::List(Path::empty(), channel().0));
+    queue.push(StoreCall::Load(zone.clone(), path![pig]));
+
+    assert!(queue.pop_write().is_some());
+
+    match queue.pop() {
+        Some(StoreCall::List(..)) => (),
+        _ => panic!("Expected list first")
+    }
+
+    assert!(queue.pop().is_some());
+
+    // A batch of loads waits for bulk calls queued for any of its zones
+    queue.push(StoreCall::Write(zone.clone(), path![moo], vec![]));
+    queue.push(StoreCall::LoadMany(vec![(zone.clone(), path![pig]), (zone.clone(), path![moo])]));
+    queue.push(StoreCall::LoadData(path![cow], channel().0.into()));
+
+    let order: Vec<_> = (0..3).map(|_| match queue.pop() {
+        Some(StoreCall::Write(..)) => "write",
+        Some(StoreCall::LoadMany(..)) => "load many",
+        Some(StoreCall::LoadData(..)) => "load",
+        _ => panic!("Unexpected call")
+    }).collect();
+
+    assert_eq!(order, vec!["load", "write", "load many"]);
+    assert!(queue.is_empty());
+}
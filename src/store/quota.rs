@@ -0,0 +1,184 @@
+//! Disk quota for stored zone data.
+//!
+//! A `Usage` tracks the bytes a backend has stored, against a `Quota`. Zone writes that would take
+//! usage past the quota's limit fail with `StoreError::QuotaExceeded` instead of filling up the
+//! disk. Once usage passes the low watermark, the store is low on space: zone writes that grow
+//! stored data fail too, leaving what is left below the limit to the WAL and to writes, deletes
+//! and compactions that free up space. The store leaves low-space mode on its own once usage drops
+//! below the watermark again.
+//!
+//! Usage is checked before each write and updated after it, so concurrent writes can overshoot
+//! the watermark slightly, but not the limit by more than a few zones.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use super::StoreError;
+
+/// Limit on stored bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quota {
+    pub limit: u64,    // Bytes that may be stored, 0 for no limit
+    pub watermark: u64 // Bytes past which zone data may not grow
+}
+
+/// Bytes stored by a backend.
+pub struct Usage {
+    quota: Quota,
+    used: Mutex<Used>
+}
+
+struct Used {
+    data: u64, // Zone data
+    log: u64   // WAL
+}
+
+impl Quota {
+    /// False for no limit.
+    pub fn is_limited(&self) -> bool {
+        self.limit > 0
+    }
+}
+
+impl FromStr for Quota {
+    type Err = String;
+
+    /// Parses `none`, `<bytes>` or `<bytes>:<watermark bytes>`. The watermark defaults to 90% of
+    /// the limit.
+    fn from_str(s: &str) -> Result<Quota, String> {
+        if s == "none" {
+            return Ok(Default::default());
+        }
+
+        let mut parts = s.splitn(2, ':');
+
+        let limit: u64 = match parts.next().unwrap().parse() {
+            Ok(0) | Err(_) => return Err(format!("Bad quota limit: {}", s)),
+            Ok(limit) => limit
+        };
+
+        let watermark = match parts.next().map(|bytes| bytes.parse()) {
+            None => limit / 10 * 9,
+            Some(Ok(bytes)) if bytes <= limit => bytes,
+            Some(_) => return Err(format!("Bad quota watermark: {}", s))
+        };
+
+        Ok(Quota { limit: limit, watermark: watermark })
+    }
+}
+
+impl fmt::Display for Quota {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            0 => write!(f, "none"),
+            limit => write!(f, "{} bytes, low on space past {}", limit, self.watermark)
+        }
+    }
+}
+
+impl Usage {
+    pub fn new(quota: Quota, data: u64, log: u64) -> Usage {
+        Usage {
+            quota: quota,
+            used: Mutex::new(Used { data: data, log: log })
+        }
+    }
+
+    /// Total bytes stored.
+    pub fn used(&self) -> u64 {
+        let used = self.used.lock().unwrap();
+
+        used.data + used.log
+    }
+
+    /// True once usage has passed the low watermark.
+    pub fn is_low(&self) -> bool {
+        self.quota.is_limited() && self.used() > self.quota.watermark
+    }
+
+    /// Checks that zone data of `old` bytes can be replaced with `new` bytes. Shrinking is always
+    /// allowed, growing only until low on space.
+    pub fn check_write(&self, old: u64, new: u64) -> Result<(), StoreError> {
+        if new <= old || ! self.quota.is_limited() {
+            return Ok(());
+        }
+
+        let used = self.used();
+
+        if used > self.quota.watermark || used + new - old > self.quota.limit {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `bytes` can be appended to the WAL. Only fails past the limit.
+    pub fn check_log(&self, bytes: u64) -> Result<(), StoreError> {
+        if self.quota.is_limited() && self.used() + bytes > self.quota.limit {
+            return Err(StoreError::QuotaExceeded);
+        }
+
+        Ok(())
+    }
+
+    /// Records zone data of `old` bytes replaced with `new` bytes.
+    pub fn resize(&self, old: u64, new: u64) {
+        let mut used = self.used.lock().unwrap();
+
+        used.data = (used.data + new).saturating_sub(old);
+    }
+
+    /// Records the current size of the WAL.
+    pub fn set_log(&self, bytes: u64) {
+        self.used.lock().unwrap().log = bytes;
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(Quota::default()));
+    assert_eq!("1000".parse(), Ok(Quota { limit: 1000, watermark: 900 }));
+    assert_eq!("1000:500".parse(), Ok(Quota { limit: 1000, watermark: 500 }));
+
+    assert!("0".parse::<Quota>().is_err());
+    assert!("moo".parse::<Quota>().is_err());
+    assert!("1000:2000".parse::<Quota>().is_err());
+    assert!("1000:moo".parse::<Quota>().is_err());
+}
+
+#[test]
+fn test_usage() {
+    let usage = Usage::new("1000:500".parse().unwrap(), 300, 100);
+
+    assert_eq!(usage.used(), 400);
+    assert!(! usage.is_low());
+
+    // Growing within the watermark, or past it the first time
+    assert!(usage.check_write(0, 100).is_ok());
+    assert!(usage.check_write(100, 700).is_ok());
+
+    // Not past the limit
+    assert!(usage.check_write(0, 700).is_err());
+
+    usage.resize(100, 300);
+    assert_eq!(usage.used(), 600);
+    assert!(usage.is_low());
+
+    // Low on space, only shrinking and logging allowed
+    assert!(usage.check_write(0, 1).is_err());
+    assert!(usage.check_write(300, 200).is_ok());
+    assert!(usage.check_log(400).is_ok());
+    assert!(usage.check_log(401).is_err());
+
+    usage.resize(300, 0);
+    usage.set_log(0);
+    assert_eq!(usage.used(), 200);
+    assert!(! usage.is_low());
+
+    // Unlimited
+    let usage = Usage::new(Default::default(), 300, 100);
+
+    assert!(usage.check_write(0, 1 << 40).is_ok());
+    assert!(usage.check_log(1 << 40).is_ok());
+}
@@ -0,0 +1,75 @@
+//! Loads of zone data left serialized.
+//!
+//! `LoadData` replies with `ZoneData` deserialized by the Store, on the Store thread or one of its
+//! workers. During mass loads that is where the CPU goes, while the threads waiting for the data
+//! sit idle. `LoadRaw` replies with a `RawZone` instead: zone data read, checksummed and decoded,
+//! but still serialized, along with the deltas and WAL entries to replay over it. Whoever asked
+//! deserializes it on their own thread, with `RawZone::deserialize`.
+//!
+//! Zones served from the cache get their data the same way (`ZoneHandle::loaded_raw`), and
+//! deserialize it in their own thread.
+
+use super::StoreError;
+use super::delta;
+use super::migrate;
+use super::wal;
+use zone::ZoneData;
+
+/// Stored data for a zone, decoded but not deserialized.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawZone {
+    pub data: Option<Vec<u8>>, // Serialized zone data, none if nothing is stored
+    pub deltas: Vec<Vec<u8>>,  // Decoded deltas to replay over it, oldest first
+    pub entries: Vec<Vec<u8>>  // Decoded WAL entries to replay after the deltas
+}
+
+impl RawZone {
+    /// Serialized zone data, with nothing to replay over it.
+    pub fn new(data: Vec<u8>) -> RawZone {
+        RawZone { data: Some(data), ..Default::default() }
+    }
+
+    /// Deserializes the zone data, replaying deltas and WAL entries over it. Zones with nothing
+    /// stored start out empty.
+    pub fn deserialize(self) -> Result<ZoneData, StoreError> {
+        let mut data = match self.data {
+            None => Default::default(),
+            Some(data) => try!(migrate::deserialize(data))
+        };
+
+        delta::replay(&mut data, self.deltas);
+        wal::replay(&mut data, self.entries);
+
+        Ok(data)
+    }
+
+    /// Same as `deserialize`, salvaging what can be read of corrupt zone data rather than failing
+    /// (see `migrate::recover`). Returns true along with the data if it had to.
+    pub fn recover(self) -> Result<(ZoneData, bool), StoreError> {
+        let (mut data, recovered) = match self.data {
+            None => (Default::default(), false),
+            Some(data) => try!(migrate::recover(data))
+        };
+
+        delta::replay(&mut data, self.deltas);
+        wal::replay(&mut data, self.entries);
+
+        Ok((data, recovered))
+    }
+}
+
+#[test]
+fn test_deserialize() {
+    use path::Path;
+
+    let data = ZoneData::new(path![moo], Default::default());
+    let raw = RawZone::new(migrate::serialize(&data).unwrap());
+
+    assert_eq!(raw.deserialize().unwrap(), data);
+    assert_eq!(RawZone::default().deserialize().unwrap(), Default::default());
+
+    match RawZone::new(b"moo".to_vec()).deserialize() {
+        Err(StoreError::Corrupt { .. }) => (),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+}
@@ -0,0 +1,123 @@
+//! Read-only Store mode, for restoring from backups and for standby replicas that must never
+//! change their data.
+//!
+//! A `ReadOnly` Store sits in front of the configured backend. Loads and listings go through,
+//! while every call that would change stored data is refused with `StoreError::ReadOnly`. Write
+//! requests are dropped, so dirty zones stay in memory rather than being written or hibernated.
+//!
+//! Backends also skip their own housekeeping when read-only: the fs store leaves interrupted
+//! writes and corrupt files in place, and does not rewrite its WAL on startup.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+use super::*;
+use app::{App, Stats};
+use path::Path;
+
+pub struct ReadOnly {
+    rx: Receiver<StoreCall>,
+    inner: StoreHandle,
+    stats: Arc<Stats>
+}
+
+impl ReadOnly {
+    /// Start the Store "process" in front of a running `inner` Store.
+    pub fn spawn(app: &App, channel: StoreChannel, inner: StoreHandle) {
+        let store = ReadOnly::new(app.stats(), channel, inner);
+
+        thread::spawn(move|| {
+            store.message_loop();
+        });
+    }
+
+    pub fn new(stats: Arc<Stats>, channel: StoreChannel, inner: StoreHandle) -> ReadOnly {
+        ReadOnly {
+            rx: channel.rx,
+            inner: inner,
+            stats: stats
+        }
+    }
+
+    fn message_loop(self) {
+        loop {
+            let call = self.rx.recv().unwrap();
+
+            match call {
+                StoreCall::Append(..) => (), // nothing to recover, zones are never written
+                StoreCall::Compact(path) => self.reject("compaction", &path),
+                StoreCall::Delete(_, path) => self.reject("delete", &path),
+                StoreCall::DeleteData(path, reply) => {
+                    self.reject("delete", &path);
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                },
+                StoreCall::List(..) | StoreCall::Load(..) | StoreCall::LoadData(..) | StoreCall::LoadMany(..) |
+                StoreCall::LoadRaw(..) | StoreCall::Reload(..) | StoreCall::Shutdown(..) | StoreCall::Stat(..) |
+                StoreCall::Stats(..) | StoreCall::Verify(..) => self.inner.tx.send(call).unwrap(),
+                StoreCall::Quarantine(path, reply) => {
+                    self.reject("quarantine", &path);
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                },
+                StoreCall::RequestWrite(zone, _) => debug!("Not writing {:?}, read-only", zone.path()),
+                StoreCall::Restore(path, _, reply) => {
+                    self.reject("restore", &path.unwrap_or_default());
+                    reply.send(0).is_ok(); // ignore if caller goes away
+                },
+                StoreCall::Write(_, path, _) | StoreCall::WriteDelta(_, path, _) => self.reject("write", &path),
+                StoreCall::WriteAtomic(writes, reply) => {
+                    for (path, _) in writes {
+                        self.reject("write", &path);
+                    }
+
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                },
+                StoreCall::WriteBatch(writes) => {
+                    for (_, path, _) in writes {
+                        self.reject("write", &path);
+                    }
+                },
+                StoreCall::WriteData(path, _, reply) => {
+                    self.reject("write", &path);
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                }
+            }
+        }
+    }
+
+    fn reject(&self, op: &str, path: &Path) {
+        let err = StoreError::ReadOnly;
+
+        error!("Refused {} of {:?}: {}", op, path, err.description());
+        self.stats.store.writes_errors.increment();
+    }
+}
+
+#[test]
+fn test_read_only() {
+    use std::sync::mpsc::channel;
+
+    let outer = StoreChannel::new();
+    let inner = StoreChannel::new();
+    let handle = outer.handle();
+
+    let store = ReadOnly::new(Default::default(), outer, inner.handle());
+
+    thread::spawn(move|| {
+        store.message_loop();
+    });
+
+    assert!(! handle.delete_data(path![moo]).unwrap());
+    assert!(! handle.write_data(path![moo], &Default::default()).unwrap());
+
+    // Only loads get through
+    let (tx, _rx) = channel();
+
+    handle.tx.send(StoreCall::LoadData(path![moo], tx.into())).unwrap();
+
+    match inner.rx.recv().unwrap() {
+        StoreCall::LoadData(path, _) => assert_eq!(path, path![moo]),
+        _ => panic!("Expected a load")
+    }
+}
@@ -0,0 +1,67 @@
+//! Replies to Store calls.
+//!
+//! Calls that return something carry a `Reply` for the backend to send it on, once. `StoreHandle`
+//! replies on a channel and blocks on it, unless the caller passes its own channel or a
+//! `Callback` to get the result later. With the `async` feature, a reply can complete a
+//! `store::future::StoreFuture` instead, which wakes its task rather than a blocked thread.
+
+use std::sync::mpsc::Sender;
+
+#[cfg(feature = "async")]
+use super::future::Completion;
+
+/// Where the result of a call goes.
+pub enum Reply<T> {
+    Channel(Sender<T>),
+    Callback(Callback<T>),
+    #[cfg(feature = "async")]
+    Future(Completion<T>)
+}
+
+/// Function called with the result of a call, on whichever thread the backend sends it from, or
+/// with `None` if the reply is dropped.
+pub struct Callback<T> {
+    f: Option<Box<FnOnce(Option<T>) + Send>>
+}
+
+impl<T> Reply<T> {
+    /// Sends the result to the caller. Hands it back if the caller has gone away.
+    pub fn send(self, value: T) -> Result<(), T> {
+        match self {
+            Reply::Channel(tx) => tx.send(value).map_err(|err| err.0),
+            Reply::Callback(callback) => {
+                callback.call(value);
+                Ok(())
+            },
+            #[cfg(feature = "async")]
+            Reply::Future(completion) => completion.complete(value)
+        }
+    }
+}
+
+impl<T> From<Sender<T>> for Reply<T> {
+    fn from(tx: Sender<T>) -> Reply<T> {
+        Reply::Channel(tx)
+    }
+}
+
+impl<T> Callback<T> {
+    pub fn new<F>(f: F) -> Callback<T> where F: FnOnce(Option<T>) + Send + 'static {
+        Callback { f: Some(Box::new(f)) }
+    }
+
+    fn call(mut self, value: T) {
+        if let Some(f) = self.f.take() {
+            f(Some(value));
+        }
+    }
+}
+
+impl<T> Drop for Callback<T> {
+    /// Calls the function with `None` if the result was never sent, so callers aren't left waiting.
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            f(None);
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Retries of failed zone writes.
+//!
+//! Backends retry writes, deletes and deltas of a zone that fail with a transient error (see
+//! `StoreError::is_transient`), waiting `RetryPolicy::backoff` milliseconds before the first retry
+//! and twice as long before each one after that, up to `max_backoff`. Once a write has failed
+//! `attempts` times, or with an error retrying won't fix, the zone is told with
+//! `ZoneHandle::write_failed`. It stays dirty, and asks to write again.
+//!
+//! Retries wait on the thread doing the write, a pool thread for most backends and the Store
+//! thread itself for `sled`, so other writes queue up behind them while the store is failing.
+
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use super::StoreError;
+
+/// How often failed writes are tried again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: u32,   // Tries in all, 1 for no retries
+    pub backoff: u64,    // Milliseconds before the first retry
+    pub max_backoff: u64 // Milliseconds between retries, at most
+}
+
+impl RetryPolicy {
+    /// Runs `op` until it succeeds, fails with an error that is not transient, or has been tried
+    /// `attempts` times. Returns the last result.
+    pub fn run<T, F>(&self, what: &str, mut op: F) -> Result<T, StoreError> where F: FnMut() -> Result<T, StoreError> {
+        let mut attempt = 1;
+
+        loop {
+            match op() {
+                Err(ref err) if err.is_transient() && attempt < self.attempts => {
+                    let delay = self.delay(attempt);
+
+                    warn!("Error {}, retrying in {}ms: {}", what, delay, err);
+                    thread::sleep(Duration::from_millis(delay));
+                },
+                result => return result
+            }
+
+            attempt += 1;
+        }
+    }
+
+    /// Milliseconds to wait after failed `attempt`, counting from 1.
+    pub fn delay(&self, attempt: u32) -> u64 {
+        let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::max_value());
+
+        cmp::min(self.backoff.saturating_mul(factor), self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 3,
+            backoff: 100,
+            max_backoff: 10000
+        }
+    }
+}
+
+impl FromStr for RetryPolicy {
+    type Err = String;
+
+    /// Parses `none`, `<attempts>`, `<attempts>:<backoff ms>` or
+    /// `<attempts>:<backoff ms>:<max backoff ms>`.
+    fn from_str(s: &str) -> Result<RetryPolicy, String> {
+        if s == "none" {
+            return Ok(RetryPolicy { attempts: 1, ..Default::default() });
+        }
+
+        let mut policy = RetryPolicy::default();
+        let mut parts = s.splitn(3, ':');
+
+        policy.attempts = match parts.next().unwrap().parse() {
+            Ok(0) | Err(_) => return Err(format!("Bad retry attempts: {}", s)),
+            Ok(attempts) => attempts
+        };
+
+        if let Some(backoff) = parts.next() {
+            policy.backoff = match backoff.parse() {
+                Ok(backoff) => backoff,
+                Err(_) => return Err(format!("Bad retry backoff: {}", s))
+            };
+
+            policy.max_backoff = cmp::max(policy.max_backoff, policy.backoff);
+        }
+
+        if let Some(max_backoff) = parts.next() {
+            policy.max_backoff = match max_backoff.parse() {
+                Ok(max_backoff) if max_backoff >= policy.backoff => max_backoff,
+                _ => return Err(format!("Bad retry max backoff: {}", s))
+            };
+        }
+
+        Ok(policy)
+    }
+}
+
+impl fmt::Display for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.attempts {
+            1 => write!(f, "none"),
+            attempts => write!(f, "{} attempts, {}ms to {}ms apart", attempts, self.backoff, self.max_backoff)
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse::<RetryPolicy>().unwrap().attempts, 1);
+    assert_eq!("5".parse(), Ok(RetryPolicy { attempts: 5, ..Default::default() }));
+    assert_eq!("5:50".parse(), Ok(RetryPolicy { attempts: 5, backoff: 50, max_backoff: 10000 }));
+    assert_eq!("5:50:400".parse(), Ok(RetryPolicy { attempts: 5, backoff: 50, max_backoff: 400 }));
+
+    assert!("0".parse::<RetryPolicy>().is_err());
+    assert!("5:50:10".parse::<RetryPolicy>().is_err());
+    assert!("moo".parse::<RetryPolicy>().is_err());
+}
+
+#[test]
+fn test_run() {
+    let policy = RetryPolicy { attempts: 4, backoff: 1, max_backoff: 2 };
+
+    assert_eq!((1..6).map(|attempt| policy.delay(attempt)).collect::<Vec<_>>(), vec![1, 2, 2, 2, 2]);
+
+    // Transient errors are retried until attempts run out
+    let mut tries = 0;
+    let result: Result<(), _> = policy.run("testing", || {
+        tries += 1;
+        Err(StoreError::Backend("moo".into()))
+    });
+
+    assert!(result.is_err());
+    assert_eq!(tries, 4);
+
+    let mut tries = 0;
+    let result = policy.run("testing", || {
+        tries += 1;
+
+        match tries {
+            1 => Err(StoreError::Backend("moo".into())),
+            _ => Ok(tries)
+        }
+    });
+
+    assert_eq!(result.unwrap(), 2);
+
+    // Others fail right away
+    let mut tries = 0;
+    let result: Result<(), _> = policy.run("testing", || {
+        tries += 1;
+        Err(StoreError::QuotaExceeded)
+    });
+
+    assert!(result.is_err());
+    assert_eq!(tries, 1);
+}
@@ -0,0 +1,613 @@
+//! A RocksDB based zone store.
+//!
+//! All zones are kept in a single `zones` column family, keyed by their serialized `Path`. RocksDB
+//! takes care of compaction, write batching and crash consistency.
+//!
+//! With `Durability::Always` each write syncs the RocksDB WAL. With `Durability::Interval` the WAL
+//! is synced in the background instead.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bincode;
+use rocksdb::{DB, IteratorMode, Options, WriteBatch, WriteOptions};
+
+use super::*;
+use super::backend;
+use super::codec::Codec;
+#[cfg(test)] use super::migrate;
+use super::raw::RawZone;
+use super::retry::RetryPolicy;
+use super::throttle::Throttle;
+use super::stats::{Metrics, StoreStats};
+use super::workers::Workers;
+use app::{App, AppHandle};
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+const NUM_THREADS: usize = 8;
+const ZONES_CF: &'static str = "zones";
+
+pub struct RocksDB {
+    app: AppHandle,
+
+    db: Arc<DB>,
+    codec: Codec,
+    durability: Durability,
+    retry: RetryPolicy,
+    throttle: Arc<Throttle>,
+
+    // Loads and writes for each zone run in order
+    workers: Workers,
+
+    metrics: Arc<Metrics>
+}
+
+impl RocksDB {
+    /// Start the Store "process".
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let dir = format!("rocksdb_{}", app.id);
+        let store = RocksDB::new(app.handle(), &dir, config);
+
+        backend::spawn(Box::new(store), channel, config);
+    }
+
+    pub fn new(app: AppHandle, dir: &str, config: &Config) -> RocksDB {
+        let mut opts = Options::default();
+
+        opts.create_if_missing(true);
+        opts.create_missing_column_families(true);
+
+        let db = Arc::new(DB::open_cf(&opts, dir, &[ZONES_CF]).expect("Could not open RocksDB"));
+
+        if let Durability::Interval(ms) = config.durability {
+            let db = db.clone();
+
+            thread::spawn(move|| {
+                loop {
+                    thread::sleep(Duration::from_millis(ms));
+
+                    if let Err(err) = db.flush_wal(true) {
+                        error!("Error syncing RocksDB WAL: {}", err.description());
+                    }
+                }
+            });
+        }
+
+        RocksDB {
+            app: app,
+            db: db,
+            codec: config.codec.clone(),
+            durability: config.durability,
+            retry: config.retry,
+            throttle: Arc::new(Throttle::new(config.throttle)),
+            workers: Workers::new(config.workers.threads_or(NUM_THREADS)),
+            metrics: Default::default()
+        }
+    }
+
+    /// Writes a batch of zones as one RocksDB batch on worker thread `lane`.
+    fn write_lane(&self, lane: usize, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let sync = self.durability == Durability::Always;
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let count = writes.len();
+        let metrics = self.metrics.clone();
+
+        for _ in 0..count {
+            self.app.stats.store.writes_pending.increment();
+        }
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(lane, move|| {
+            debug!("Writing batch of {} zones", count);
+
+            throttle.wait(count, writes.iter().map(|&(_, _, ref data)| data.len()).sum());
+
+            let started = Instant::now();
+            let cf = db.cf_handle(ZONES_CF).expect("Missing zones column family");
+            let mut blobs = Vec::with_capacity(count);
+            let mut zones = Vec::with_capacity(count);
+            let mut bytes = 0;
+
+            for (zone, path, data) in writes {
+                match codec.encode(data) {
+                    Err(err) => {
+                        error!("Error encoding {:?}: {}", path, err.description());
+                        stats.store.writes_errors.increment();
+                        zone.write_failed(err);
+                    },
+                    Ok(blob) => {
+                        bytes += blob.len() as u64;
+                        blobs.push((zonekey(&path), blob));
+                        zones.push(zone);
+                    }
+                }
+            }
+
+            let mut opts = WriteOptions::default();
+
+            opts.set_sync(sync);
+
+            // A batch is used up by writing it, so each attempt builds its own
+            let result = retry.run(&format!("writing batch of {} zones", zones.len()), || {
+                let mut batch = WriteBatch::default();
+
+                for &(ref key, ref blob) in &blobs {
+                    batch.put_cf(cf, key, blob);
+                }
+
+                db.write_opt(batch, &opts).map_err(|err| StoreError::Backend(err.to_string()))
+            });
+
+            match result {
+                Err(err) => {
+                    error!("Error writing batch of {} zones: {}", zones.len(), err.description());
+                    error!("{:?}", err);
+
+                    for zone in zones {
+                        stats.store.writes_errors.increment();
+                        zone.write_failed(StoreError::Backend(format!("Batch write failed: {}", err)));
+                    }
+                },
+                Ok(_) => {
+                    metrics.written(bytes, started);
+
+                    for zone in zones {
+                        zone.saved();
+                    }
+                }
+            }
+
+            for _ in 0..count {
+                stats.store.writes_pending.decrement();
+                stats.store.writes.increment();
+            }
+        });
+    }
+}
+
+impl StoreBackend for RocksDB {
+    /// Deletes data for a `Zone` asynchronously, notifying its handle when done.
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        let db = self.db.clone();
+        let sync = self.durability == Durability::Always;
+        let retry = self.retry;
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting: {:?}", path);
+
+            match retry.run(&format!("deleting {:?}", path), || blocking_delete(&db, &path, sync)) {
+                Err(err) => {
+                    error!("Error deleting {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => zone.deleted()
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+        });
+    }
+
+    /// Deletes data for `path` asynchronously, replying whether it succeeded.
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        let db = self.db.clone();
+        let sync = self.durability == Durability::Always;
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting data: {:?}", path);
+
+            let result = blocking_delete(&db, &path, sync);
+
+            if let Err(ref err) = result {
+                error!("Error deleting {:?}: {}", path, err.description());
+            }
+
+            reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Lists all Zone Paths stored locally under `prefix`. Keys don't sort parent zones before
+    /// their children, so every key is read.
+    fn list(&mut self, prefix: Path, tx: Sender<Path>) {
+        let prefix = Prefix(prefix);
+        let cf = self.db.cf_handle(ZONES_CF).expect("Missing zones column family");
+
+        for entry in self.db.iterator_cf(cf, IteratorMode::Start) {
+            let key = match entry {
+                Err(err) => {
+                    error!("Error iterating zones: {}", err.description());
+                    error!("  {:?}", err);
+                    return;
+                },
+                Ok((key, _)) => key
+            };
+
+            match bincode::deserialize::<Path>(&key) {
+                Err(err) => {
+                    error!("Bad zone key {:?}: {}", key, err.description());
+                },
+                Ok(path) => if prefix.matches(&path) {
+                    tx.send(path).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done, with what could be
+    /// salvaged if it is corrupt.
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.reads_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            match blocking_read_raw(&db, &path, &codec, &metrics).and_then(RawZone::recover) {
+                Err(StoreError::Corrupt { reason, .. }) => {
+                    error!("Corrupt data for {:?}: {}", path, reason);
+                    stats.store.reads_corrupt.increment();
+                    zone.corrupt();
+                },
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.reads_errors.increment();
+                    // TODO: set Zone to error state
+                },
+                Ok((data, false)) => zone.loaded(data),
+                Ok((data, true)) => {
+                    error!("Corrupt data for {:?}, recovered in part", path);
+                    stats.store.reads_corrupt.increment();
+                    zone.recovered(data);
+                }
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&mut self, path: Path, tx: Reply<Option<ZoneData>>) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read(&db, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Asynchronously load and send data for `Path` to channel, still serialized.
+    fn load_raw(&mut self, path: Path, tx: Reply<Option<RawZone>>) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read_raw(&db, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data. RocksDB buffers writes itself, so always ready.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        zone.save();
+    }
+
+    /// Waits for loads and writes running on worker threads, then syncs the RocksDB WAL unless
+    /// flushing is left to the OS.
+    fn shutdown(&mut self) {
+        self.workers.drain();
+
+        if self.durability != Durability::Never {
+            if let Err(err) = self.db.flush_wal(true) {
+                error!("Error syncing RocksDB WAL: {}", err.description());
+            }
+        }
+    }
+
+    /// Looks up the size of the value for `Path` asynchronously, without decoding it.
+    /// Modification times are not kept.
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        let db = self.db.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            reply.send(blocking_stat(&db, &path)).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// I/O metrics.
+    fn stats(&mut self) -> StoreStats {
+        let mut stats = self.metrics.snapshot();
+
+        stats.queue_depth = self.workers.queued_count();
+        stats
+    }
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let sync = self.durability == Durability::Always;
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing: {:?}", path);
+
+            throttle.wait(1, data.len());
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+                retry.run(&format!("writing {:?}", path), || blocking_write(&db, &path, blob.clone(), sync))
+            });
+
+            match result {
+                Err(err) => {
+                    error!("Error writing {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => {
+                    metrics.written(bytes, started);
+                    zone.saved()
+                }
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+        });
+    }
+
+    /// Write data for `path` asynchronously, replying whether it succeeded.
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        let db = self.db.clone();
+        let codec = self.codec.clone();
+        let sync = self.durability == Durability::Always;
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing data: {:?}", path);
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+                blocking_write(&db, &path, blob, sync)
+            });
+
+            match result {
+                Err(ref err) => error!("Error writing {:?}: {}", path, err.description()),
+                Ok(_) => metrics.written(bytes, started)
+            }
+
+            reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Write data for several paths as one RocksDB batch, once writes already running on worker
+    /// threads are done, replying whether it succeeded.
+    fn write_atomic(&mut self, writes: Vec<(Path, Vec<u8>)>, reply: Reply<bool>) {
+        self.workers.drain();
+
+        debug!("Writing {} zones atomically", writes.len());
+
+        let cf = self.db.cf_handle(ZONES_CF).expect("Missing zones column family");
+        let started = Instant::now();
+        let mut blobs = Vec::with_capacity(writes.len());
+        let mut bytes = 0;
+
+        for (path, data) in writes {
+            match self.codec.encode(data) {
+                Err(err) => {
+                    error!("Error encoding {:?}: {}", path, err.description());
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                    return;
+                },
+                Ok(blob) => {
+                    bytes += blob.len() as u64;
+                    blobs.push((zonekey(&path), blob));
+                }
+            }
+        }
+
+        let mut opts = WriteOptions::default();
+
+        opts.set_sync(self.durability == Durability::Always);
+
+        let db = &self.db;
+
+        let result = self.retry.run(&format!("writing {} zones atomically", blobs.len()), || {
+            let mut batch = WriteBatch::default();
+
+            for &(ref key, ref blob) in &blobs {
+                batch.put_cf(cf, key, blob);
+            }
+
+            db.write_opt(batch, &opts).map_err(|err| StoreError::Backend(err.to_string()))
+        });
+
+        match result {
+            Err(ref err) => error!("Error writing {} zones atomically: {}", blobs.len(), err.description()),
+            Ok(_) => self.metrics.written(bytes, started)
+        }
+
+        reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Write data for a group of `Zone`s asynchronously, notifying each handle when done. Zones
+    /// are written by their own worker thread, one RocksDB batch per thread.
+    fn write_batch(&mut self, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        let mut batches = BTreeMap::new();
+
+        for write in writes {
+            batches.entry(self.workers.lane(&write.1)).or_insert_with(Vec::new).push(write);
+        }
+
+        for (lane, writes) in batches {
+            self.write_lane(lane, writes);
+        }
+    }
+}
+
+fn blocking_stat(db: &DB, path: &Path) -> Option<ZoneStat> {
+    let cf = db.cf_handle(ZONES_CF).expect("Missing zones column family");
+
+    match db.get_cf(cf, &zonekey(path)) {
+        Err(err) => {
+            error!("Error reading {:?}: {}", path, err.description());
+            None
+        },
+        Ok(buffer) => buffer.map(|buffer| ZoneStat { size: buffer.len() as u64, modified: None })
+    }
+}
+
+fn blocking_read(db: &DB, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<ZoneData, StoreError> {
+    blocking_read_raw(db, path, codec, metrics).and_then(RawZone::deserialize)
+}
+
+fn blocking_read_raw(db: &DB, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<RawZone, StoreError> {
+    let cf = db.cf_handle(ZONES_CF).expect("Missing zones column family");
+    let started = Instant::now();
+
+    let buffer = match db.get_cf(cf, &zonekey(path)) {
+        Err(err) => return Err(StoreError::Backend(err.to_string())),
+        Ok(None) => return Ok(RawZone::default()),
+        Ok(Some(buffer)) => buffer
+    };
+
+    metrics.loaded(buffer.len() as u64, started);
+
+    Ok(RawZone::new(try!(codec.decode(buffer.to_vec()))))
+}
+
+fn blocking_write(db: &DB, path: &Path, serialized: Vec<u8>, sync: bool) -> Result<(), StoreError> {
+    let cf = db.cf_handle(ZONES_CF).expect("Missing zones column family");
+    let mut opts = WriteOptions::default();
+
+    opts.set_sync(sync);
+
+    if let Err(err) = db.put_cf_opt(cf, &zonekey(path), &serialized, &opts) {
+        return Err(StoreError::Backend(err.to_string()));
+    }
+
+    Ok(())
+}
+
+fn blocking_delete(db: &DB, path: &Path, sync: bool) -> Result<(), StoreError> {
+    let cf = db.cf_handle(ZONES_CF).expect("Missing zones column family");
+    let mut opts = WriteOptions::default();
+
+    opts.set_sync(sync);
+
+    if let Err(err) = db.delete_cf_opt(cf, &zonekey(path), &opts) {
+        return Err(StoreError::Backend(err.to_string()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_read_write() {
+    let dir = "test_data/rocksdb_read_write";
+
+    std::fs::remove_dir_all(dir).ok();
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let store = RocksDB::new(app.handle(), dir, &Config::default());
+
+    let path = path![moo];
+
+    assert_eq!(blocking_read(&store.db, &path, &store.codec, &store.metrics).unwrap(), Default::default());
+
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let expected = ZoneData::new(
+        path.clone(),
+        NodeTree {
+            vis: Vis::update(1000),
+            node: Node::expand(JSON::String(String::from("moo")), 1000)
+        }
+    );
+
+    let serialized = migrate::serialize(&expected).unwrap();
+
+    blocking_write(&store.db, &path, serialized, true).unwrap();
+
+    assert_eq!(blocking_read(&store.db, &path, &store.codec, &store.metrics).unwrap(), expected);
+
+    blocking_delete(&store.db, &path, true).unwrap();
+
+    assert_eq!(blocking_read(&store.db, &path, &store.codec, &store.metrics).unwrap(), Default::default());
+}
+
+#[test]
+fn test_list() {
+    let dir = "test_data/rocksdb_list";
+
+    std::fs::remove_dir_all(dir).ok();
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let mut store = RocksDB::new(app.handle(), dir, &Config::default());
+
+    let limit = bincode::Infinite;
+
+    for i in 0..3 {
+        let path = Path::new(vec![i.to_string()]);
+        let zone_data = ZoneData::new(path.clone(), Default::default());
+
+        let serialized = bincode::serialize(&zone_data, limit).unwrap();
+
+        blocking_write(&store.db, &path, serialized, true).unwrap();
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    store.list(Path::empty(), tx);
+
+    let mut paths: Vec<Path> = rx.iter().collect();
+
+    paths.sort();
+
+    assert_eq!(paths, [
+        Path::new(vec!["0".into()]),
+        Path::new(vec!["1".into()]),
+        Path::new(vec!["2".into()]),
+    ]);
+}
@@ -0,0 +1,471 @@
+//! An S3 (or S3-compatible object storage) based zone store, for nodes running on ephemeral
+//! containers.
+//!
+//! Each zone is stored as one object under a configurable prefix. Object names are the hex encoded
+//! serialized `Path`, so zones can be listed without fetching any objects.
+//!
+//! Configuration is read from the environment:
+//!
+//! * `STORE_S3_BUCKET` - bucket name (required)
+//! * `STORE_S3_PREFIX` - object name prefix, defaults to the replica ID
+//! * `STORE_S3_REGION` - region, defaults to `us-east-1`
+//! * `STORE_S3_ENDPOINT` - endpoint of an S3-compatible service, defaults to AWS
+//! * `STORE_S3_THREADS` - number of concurrent requests, defaults to 16
+//!
+//! Credentials are picked up from the usual AWS environment variables / profile.
+
+use std::env;
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bincode;
+use s3::bucket::Bucket;
+use s3::credentials::Credentials;
+use s3::region::Region;
+
+use super::*;
+use super::backend;
+use super::codec::Codec;
+use super::raw::RawZone;
+use super::retry::RetryPolicy;
+use super::throttle::Throttle;
+use super::stats::{Metrics, StoreStats};
+use super::workers::Workers;
+use app::{App, AppHandle};
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+const DEFAULT_THREADS: usize = 16;
+
+/// Configuration for the S3 Store.
+#[derive(Clone, Debug)]
+pub struct S3Config {
+    pub bucket: String,
+    pub prefix: String,
+    pub region: String,
+    pub endpoint: Option<String>,
+    pub threads: usize
+}
+
+pub struct S3 {
+    app: AppHandle,
+
+    bucket: Arc<Bucket>,
+    prefix: Arc<String>,
+    codec: Codec,
+    retry: RetryPolicy,
+    throttle: Arc<Throttle>,
+
+    // Loads and writes for each zone run in order
+    workers: Workers,
+
+    metrics: Arc<Metrics>
+}
+
+impl S3Config {
+    /// Reads configuration from `STORE_S3_*` environment variables.
+    pub fn from_env(app: &App) -> S3Config {
+        S3Config {
+            bucket: env::var("STORE_S3_BUCKET").expect("STORE_S3_BUCKET not set"),
+            prefix: env::var("STORE_S3_PREFIX").unwrap_or_else(|_| app.id.to_string()),
+            region: env::var("STORE_S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            endpoint: env::var("STORE_S3_ENDPOINT").ok(),
+            threads: env::var("STORE_S3_THREADS").ok()
+                .map(|t| t.parse().expect("Bad STORE_S3_THREADS"))
+                .unwrap_or(DEFAULT_THREADS)
+        }
+    }
+}
+
+impl S3 {
+    /// Start the Store "process".
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let s3_config = S3Config::from_env(app);
+        let store = S3::new(app.handle(), &s3_config, config);
+
+        backend::spawn(Box::new(store), channel, config);
+    }
+
+    pub fn new(app: AppHandle, s3_config: &S3Config, config: &Config) -> S3 {
+        let region = match s3_config.endpoint {
+            Some(ref endpoint) => Region::Custom {
+                region: s3_config.region.clone(),
+                endpoint: endpoint.clone()
+            },
+            None => s3_config.region.parse().expect("Bad STORE_S3_REGION")
+        };
+
+        let bucket = Bucket::new(&s3_config.bucket, region, Credentials::default());
+
+        S3 {
+            app: app,
+            bucket: Arc::new(bucket),
+            prefix: Arc::new(s3_config.prefix.clone()),
+            codec: config.codec.clone(),
+            retry: config.retry,
+            throttle: Arc::new(Throttle::new(config.throttle)),
+            workers: Workers::new(config.workers.threads_or(s3_config.threads)),
+            metrics: Default::default()
+        }
+    }
+}
+
+impl StoreBackend for S3 {
+    /// Lists all Zone Paths stored in the bucket under our prefix, and under `path_prefix`. Object
+    /// names don't keep parent zones together, so the whole bucket prefix is listed.
+    fn list(&mut self, path_prefix: Path, tx: Sender<Path>) {
+        let path_prefix = Prefix(path_prefix);
+        let prefix = format!("{}/", self.prefix);
+
+        let results = match self.bucket.list(&prefix, None) {
+            Err(err) => {
+                error!("Error listing bucket: {}", err.description());
+                error!("  {:?}", err);
+                return;
+            },
+            Ok(results) => results
+        };
+
+        for (result, _) in results {
+            for object in result.contents {
+                match path_from_objectname(&object.key[prefix.len()..]) {
+                    None => error!("Bad zone object name: {}", object.key),
+                    Some(path) => if path_prefix.matches(&path) {
+                        tx.send(path).unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done, with what could be
+    /// salvaged if it is corrupt.
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.reads_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            match blocking_read_raw(&bucket, &prefix, &path, &codec, &metrics).and_then(RawZone::recover) {
+                Err(StoreError::Corrupt { reason, .. }) => {
+                    error!("Corrupt data for {:?}: {}", path, reason);
+                    stats.store.reads_corrupt.increment();
+                    zone.corrupt();
+                },
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.reads_errors.increment();
+                    // TODO: set Zone to error state
+                },
+                Ok((data, false)) => zone.loaded(data),
+                Ok((data, true)) => {
+                    error!("Corrupt data for {:?}, recovered in part", path);
+                    stats.store.reads_corrupt.increment();
+                    zone.recovered(data);
+                }
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&mut self, path: Path, tx: Reply<Option<ZoneData>>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read(&bucket, &prefix, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Asynchronously load and send data for `Path` to channel, still serialized.
+    fn load_raw(&mut self, path: Path, tx: Reply<Option<RawZone>>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read_raw(&bucket, &prefix, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Waits for uploads and deletes running on worker threads to finish.
+    fn shutdown(&mut self) {
+        self.workers.drain();
+    }
+
+    /// Looks up the object for `Path` asynchronously from a bucket listing, without fetching it.
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            reply.send(blocking_stat(&bucket, &prefix, &path)).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Deletes the object for a `Zone` asynchronously, notifying its handle when done.
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let retry = self.retry;
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting: {:?}", path);
+
+            match retry.run(&format!("deleting {:?}", path), || blocking_delete(&bucket, &prefix, &path)) {
+                Err(err) => {
+                    error!("Error deleting {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => zone.deleted()
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+        });
+    }
+
+    /// Deletes the object for `path` asynchronously, replying whether it succeeded.
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Deleting data: {:?}", path);
+
+            let result = blocking_delete(&bucket, &prefix, &path);
+
+            if let Err(ref err) = result {
+                error!("Error deleting {:?}: {}", path, err.description());
+            }
+
+            reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Request for notification to write data. Writes queue up behind the worker threads.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        zone.save();
+    }
+
+    /// I/O metrics.
+    fn stats(&mut self) -> StoreStats {
+        let mut stats = self.metrics.snapshot();
+
+        stats.queue_depth = self.workers.queued_count();
+        stats
+    }
+
+    /// Write data for a `Zone` asynchronously, notifying its handle when done.
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let codec = self.codec.clone();
+        let retry = self.retry;
+        let throttle = self.throttle.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.writes_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing: {:?}", path);
+
+            throttle.wait(1, data.len());
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+                retry.run(&format!("writing {:?}", path), || blocking_write(&bucket, &prefix, &path, blob.clone()))
+            });
+
+            match result {
+                Err(err) => {
+                    error!("Error writing {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(_) => {
+                    metrics.written(bytes, started);
+                    zone.saved()
+                }
+            };
+
+            stats.store.writes_pending.decrement();
+            stats.store.writes.increment();
+        });
+    }
+
+    /// Write data for `path` asynchronously, replying whether it succeeded.
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        let bucket = self.bucket.clone();
+        let prefix = self.prefix.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Writing data: {:?}", path);
+
+            let started = Instant::now();
+            let mut bytes = 0;
+
+            let result = codec.encode(data).and_then(|blob| {
+                bytes = blob.len() as u64;
+                blocking_write(&bucket, &prefix, &path, blob)
+            });
+
+            match result {
+                Err(ref err) => error!("Error writing {:?}: {}", path, err.description()),
+                Ok(_) => metrics.written(bytes, started)
+            }
+
+            reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+}
+
+fn blocking_read(bucket: &Bucket, prefix: &str, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<ZoneData, StoreError> {
+    blocking_read_raw(bucket, prefix, path, codec, metrics).and_then(RawZone::deserialize)
+}
+
+fn blocking_read_raw(bucket: &Bucket, prefix: &str, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<RawZone, StoreError> {
+    let started = Instant::now();
+
+    let buffer = match bucket.get(&objectname(prefix, path)) {
+        Err(err) => return Err(StoreError::Backend(err.to_string())),
+        Ok((_, 404)) => return Ok(RawZone::default()),
+        Ok((buffer, 200)) => buffer,
+        Ok((_, code)) => return Err(StoreError::Backend(format!("S3 GET returned {}", code)))
+    };
+
+    metrics.loaded(buffer.len() as u64, started);
+
+    Ok(RawZone::new(try!(codec.decode(buffer))))
+}
+
+fn blocking_stat(bucket: &Bucket, prefix: &str, path: &Path) -> Option<ZoneStat> {
+    let name = objectname(prefix, path);
+
+    // Listing by the object name also finds objects whose names start with it
+    let results = match bucket.list(&name, None) {
+        Err(err) => {
+            error!("Error listing bucket for {:?}: {}", path, err.description());
+            return None;
+        },
+        Ok(results) => results
+    };
+
+    results.into_iter()
+        .flat_map(|(result, _)| result.contents)
+        .find(|object| object.key == name)
+        .map(|object| ZoneStat { size: object.size, modified: parse_timestamp(&object.last_modified) })
+}
+
+/// Parses an S3 timestamp, e.g. `2009-10-12T17:50:30.000Z`, ignoring fractions of a second.
+fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let field = |start: usize, end: usize| s.get(start..end).and_then(|field| field.parse::<u64>().ok());
+
+    let (year, month, day) = match (field(0, 4), field(5, 7), field(8, 10)) {
+        (Some(year), Some(month), Some(day)) if year >= 1970 && month >= 1 && month <= 12 => (year, month, day),
+        _ => return None
+    };
+
+    let seconds = match (field(11, 13), field(14, 16), field(17, 19)) {
+        (Some(hours), Some(minutes), Some(seconds)) => hours * 3600 + minutes * 60 + seconds,
+        _ => return None
+    };
+
+    // Days since the epoch, counting years from March so leap days come last
+    let year = if month <= 2 { year - 1 } else { year };
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year - 719468;
+
+    Some(UNIX_EPOCH + Duration::from_secs(days * 86400 + seconds))
+}
+
+fn blocking_write(bucket: &Bucket, prefix: &str, path: &Path, serialized: Vec<u8>) -> Result<(), StoreError> {
+    match bucket.put(&objectname(prefix, path), &serialized, "application/octet-stream") {
+        Err(err) => Err(StoreError::Backend(err.to_string())),
+        Ok((_, 200)) => Ok(()),
+        Ok((_, code)) => Err(StoreError::Backend(format!("S3 PUT returned {}", code)))
+    }
+}
+
+fn blocking_delete(bucket: &Bucket, prefix: &str, path: &Path) -> Result<(), StoreError> {
+    match bucket.delete(&objectname(prefix, path)) {
+        Err(err) => Err(StoreError::Backend(err.to_string())),
+        Ok((_, 204)) | Ok((_, 404)) => Ok(()),
+        Ok((_, code)) => Err(StoreError::Backend(format!("S3 DELETE returned {}", code)))
+    }
+}
+
+fn objectname(prefix: &str, path: &Path) -> String {
+    let hex: String = zonekey(path).iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}/{}", prefix, hex)
+}
+
+fn path_from_objectname(name: &str) -> Option<Path> {
+    if name.len() % 2 != 0 || ! name.is_ascii() {
+        return None;
+    }
+
+    let key: Result<Vec<u8>, _> = (0..name.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16))
+        .collect();
+
+    key.ok().and_then(|key| bincode::deserialize(&key).ok())
+}
+
+#[test]
+fn test_objectname() {
+    let path = path![moo.cow];
+    let name = objectname("prefix", &path);
+
+    assert!(name.starts_with("prefix/"));
+    assert_eq!(path_from_objectname(&name["prefix/".len()..]), Some(path));
+
+    assert_eq!(path_from_objectname("abc"), None);
+    assert_eq!(path_from_objectname("zz"), None);
+}
+
+#[test]
+fn test_parse_timestamp() {
+    assert_eq!(parse_timestamp("1970-01-01T00:00:00.000Z"), Some(UNIX_EPOCH));
+    assert_eq!(parse_timestamp("2009-10-12T17:50:30.000Z"), Some(UNIX_EPOCH + Duration::from_secs(1255369830)));
+    assert_eq!(parse_timestamp("2000-02-29T00:00:00Z"), Some(UNIX_EPOCH + Duration::from_secs(951782400)));
+
+    assert_eq!(parse_timestamp("moo"), None);
+    assert_eq!(parse_timestamp("2009-13-12T17:50:30.000Z"), None);
+}
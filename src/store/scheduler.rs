@@ -0,0 +1,256 @@
+//! Flush scheduling for dirty zones.
+//!
+//! Zones ask to write with `StoreCall::RequestWrite` as soon as they are dirty, and write their
+//! full data when told to `save`. For zones updated continuously, that means rewriting the whole
+//! zone for every few changes. A `Scheduler` holds on to write requests instead, and lets them
+//! through every `FlushPolicy::interval`, or earlier once enough diffs have been logged. A zone
+//! stays dirty while it waits, so any number of changes are coalesced into its next write.
+//!
+//! Requests carry how long their zone has been `Dirty` and how many changes it has yet to write.
+//! Zones due together are flushed the longest dirty first, then those with the most changes, so
+//! when backends hold writes back (see `StoreBackend::request_write`), the changes that would be
+//! lost first are written first.
+//!
+//! Diffs logged with `StoreCall::Append` keep updates safe in the meantime, for backends with a
+//! WAL.
+
+use std::collections::HashMap;
+use std::cmp;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use super::StoreCall;
+use zone::ZoneHandle;
+
+/// When dirty zones are flushed.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlushPolicy {
+    pub interval: u64,     // Milliseconds between flushes, 0 to flush as soon as requested
+    pub dirty_bytes: usize // Flush early once diffs of this size are logged, 0 for no limit
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    /// Parses `immediate`, `<ms>` or `<ms>:<dirty bytes>`.
+    fn from_str(s: &str) -> Result<FlushPolicy, String> {
+        if s == "immediate" {
+            return Ok(Default::default());
+        }
+
+        let mut parts = s.splitn(2, ':');
+
+        let interval = match parts.next().unwrap().parse() {
+            Ok(interval) => interval,
+            Err(_) => return Err(format!("Bad flush interval: {}", s))
+        };
+
+        let dirty_bytes = match parts.next().map(|bytes| bytes.parse()) {
+            None => 0,
+            Some(Ok(bytes)) => bytes,
+            Some(Err(_)) => return Err(format!("Bad flush dirty bytes: {}", s))
+        };
+
+        Ok(FlushPolicy { interval: interval, dirty_bytes: dirty_bytes })
+    }
+}
+
+impl fmt::Display for FlushPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.interval, self.dirty_bytes) {
+            (0, _) => write!(f, "immediate"),
+            (interval, 0) => write!(f, "every {}ms", interval),
+            (interval, bytes) => write!(f, "every {}ms or {} dirty bytes", interval, bytes)
+        }
+    }
+}
+
+/// Changes in a zone not yet written, sent along with its write requests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dirty {
+    pub since: Instant, // First change not yet written
+    pub changes: u64    // Changes not yet written
+}
+
+/// Tracks zones waiting to write, and decides when they can.
+pub struct Scheduler {
+    policy: FlushPolicy,
+    dirty: HashMap<ZoneHandle, Dirty>,
+    dirty_bytes: usize,
+    deadline: Option<Instant> // Next flush, set once a zone is waiting
+}
+
+impl Dirty {
+    /// Dirty as of now, with nothing changed yet.
+    pub fn new() -> Dirty {
+        Dirty { since: Instant::now(), changes: 0 }
+    }
+
+    /// Changes not yet written here and in `other`, dirty since the earlier of the two.
+    pub fn join(self, other: Dirty) -> Dirty {
+        Dirty { since: cmp::min(self.since, other.since), changes: self.changes + other.changes }
+    }
+}
+
+impl Scheduler {
+    pub fn new(policy: FlushPolicy) -> Scheduler {
+        Scheduler {
+            policy: policy,
+            dirty: HashMap::new(),
+            dirty_bytes: 0,
+            deadline: None
+        }
+    }
+
+    /// Queues a write request. Repeated requests from a waiting zone are coalesced, keeping what
+    /// it last reported as `dirty`.
+    pub fn request_write(&mut self, zone: ZoneHandle, dirty: Dirty) {
+        if self.deadline.is_none() {
+            self.deadline = Some(Instant::now() + Duration::from_millis(self.policy.interval));
+        }
+
+        self.dirty.insert(zone, dirty);
+    }
+
+    /// Counts a logged diff towards the dirty byte threshold.
+    pub fn appended(&mut self, bytes: usize) {
+        self.dirty_bytes += bytes;
+    }
+
+    /// Number of zones waiting to write.
+    pub fn pending(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Returns true if waiting zones should be flushed now.
+    pub fn is_due(&self) -> bool {
+        match self.deadline {
+            None => false,
+            Some(deadline) => {
+                Instant::now() >= deadline ||
+                    (self.policy.dirty_bytes > 0 && self.dirty_bytes >= self.policy.dirty_bytes)
+            }
+        }
+    }
+
+    /// Takes all waiting zones if they are due for a flush, longest dirty first, then those with
+    /// most changes.
+    pub fn take_due(&mut self) -> Vec<ZoneHandle> {
+        if ! self.is_due() {
+            return vec![];
+        }
+
+        self.deadline = None;
+        self.dirty_bytes = 0;
+
+        let mut due: Vec<(ZoneHandle, Dirty)> = self.dirty.drain().collect();
+
+        due.sort_by(|a, b| a.1.since.cmp(&b.1.since).then(b.1.changes.cmp(&a.1.changes)));
+        due.into_iter().map(|(zone, _)| zone).collect()
+    }
+
+    /// Waits for the next call, handing zones due for a flush to `flush` in the meantime.
+    pub fn recv<F>(&mut self, rx: &Receiver<StoreCall>, mut flush: F) -> StoreCall where F: FnMut(ZoneHandle) {
+        loop {
+            for zone in self.take_due() {
+                flush(zone);
+            }
+
+            let timeout = match self.deadline {
+                None => return rx.recv().unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+
+                    if deadline > now { deadline - now } else { Duration::from_millis(0) }
+                }
+            };
+
+            match rx.recv_timeout(timeout) {
+                Ok(call) => return call,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => panic!("Store channel disconnected")
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("immediate".parse(), Ok(FlushPolicy::default()));
+    assert_eq!("5000".parse(), Ok(FlushPolicy { interval: 5000, dirty_bytes: 0 }));
+    assert_eq!("5000:1048576".parse(), Ok(FlushPolicy { interval: 5000, dirty_bytes: 1048576 }));
+
+    assert!("moo".parse::<FlushPolicy>().is_err());
+    assert!("5000:moo".parse::<FlushPolicy>().is_err());
+}
+
+#[test]
+fn test_coalesce() {
+    use std::sync::Arc;
+
+    use path::Path;
+
+    let moo = ZoneHandle::test_handle(Arc::new(path![moo]));
+    let cow = ZoneHandle::test_handle(Arc::new(path![cow]));
+
+    // Immediate flushes are due right away
+    let mut scheduler = Scheduler::new(Default::default());
+
+    assert!(scheduler.take_due().is_empty());
+
+    scheduler.request_write(moo.clone(), Dirty::new());
+    scheduler.request_write(moo.clone(), Dirty::new());
+
+    assert_eq!(scheduler.take_due().len(), 1);
+    assert!(scheduler.take_due().is_empty());
+
+    // Interval flushes wait, unless enough diffs are logged
+    let mut scheduler = Scheduler::new(FlushPolicy { interval: 60000, dirty_bytes: 100 });
+
+    scheduler.request_write(moo.clone(), Dirty::new());
+    scheduler.request_write(cow.clone(), Dirty::new());
+    scheduler.request_write(moo.clone(), Dirty::new());
+    scheduler.appended(99);
+
+    assert!(scheduler.take_due().is_empty());
+    assert_eq!(scheduler.pending(), 2);
+
+    scheduler.appended(1);
+
+    assert_eq!(scheduler.take_due().len(), 2);
+    assert!(! scheduler.is_due());
+    assert_eq!(scheduler.pending(), 0);
+}
+
+#[test]
+fn test_dirty_order() {
+    use std::sync::Arc;
+
+    use path::Path;
+
+    let moo = ZoneHandle::test_handle(Arc::new(path![moo]));
+    let cow = ZoneHandle::test_handle(Arc::new(path![cow]));
+    let pig = ZoneHandle::test_handle(Arc::new(path![pig]));
+
+    let now = Instant::now();
+    let earlier = Dirty { since: now - Duration::from_millis(1000), changes: 1 };
+
+    // Longest dirty first, then most changes, whatever order they asked in
+    let mut scheduler = Scheduler::new(FlushPolicy { interval: 60000, dirty_bytes: 1 });
+
+    scheduler.request_write(moo.clone(), Dirty { since: now, changes: 2 });
+    scheduler.request_write(cow.clone(), Dirty { since: now, changes: 5 });
+    scheduler.request_write(pig.clone(), Dirty { since: now, changes: 1 });
+    scheduler.request_write(pig.clone(), earlier);
+    scheduler.appended(1);
+
+    let order: Vec<_> = scheduler.take_due().iter().map(|zone| zone.path().path).collect();
+
+    assert_eq!(order, vec![vec!["pig".to_string()], vec!["cow".to_string()], vec!["moo".to_string()]]);
+
+    let joined = Dirty { since: now, changes: 2 }.join(earlier);
+
+    assert_eq!(joined, Dirty { since: earlier.since, changes: 3 });
+}
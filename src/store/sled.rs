@@ -0,0 +1,471 @@
+//! A sled based zone store. Pure Rust alternative to `store::rocksdb`.
+//!
+//! Zones are kept in a `zones` tree keyed by their serialized `Path`. Writes are applied by the
+//! Store thread itself: any writes queued up while a batch is being flushed are coalesced into the
+//! next batch, which is applied atomically. With `Durability::Always` each batch is flushed to disk
+//! before zones are notified, with `Durability::Interval` sled flushes in the background.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+use bincode;
+use sled::{Batch, Tree};
+
+use super::*;
+use super::backend;
+use super::codec::Codec;
+use super::raw::RawZone;
+use super::retry::RetryPolicy;
+use super::stats::{Metrics, StoreStats};
+use super::workers::Workers;
+use app::{App, AppHandle};
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use zone::{ZoneData, ZoneHandle};
+
+const NUM_THREADS: usize = 8;
+const ZONES_TREE: &'static str = "zones";
+
+pub struct Sled {
+    app: AppHandle,
+
+    tree: Tree,
+    codec: Codec,
+    durability: Durability,
+    retry: RetryPolicy,
+
+    // Loads for each zone run in order, writes on the Store thread
+    workers: Workers,
+
+    metrics: Arc<Metrics>
+}
+
+impl Sled {
+    /// Start the Store "process".
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let dir = format!("sled_{}", app.id);
+        let store = Sled::new(app.handle(), &dir, config);
+
+        backend::spawn(Box::new(store), channel, config);
+    }
+
+    pub fn new(app: AppHandle, dir: &str, config: &Config) -> Sled {
+        // sled flushes in the background every `flush_every_ms`
+        let flush_every_ms = match config.durability {
+            Durability::Interval(ms) => Some(ms),
+            Durability::Always | Durability::Never => None
+        };
+
+        let db = ::sled::Config::new()
+            .path(dir)
+            .flush_every_ms(flush_every_ms)
+            .open()
+            .expect("Could not open sled database");
+        let tree = db.open_tree(ZONES_TREE).expect("Could not open zones tree");
+
+        Sled {
+            app: app,
+            tree: tree,
+            codec: config.codec.clone(),
+            durability: config.durability,
+            retry: config.retry,
+            workers: Workers::new(config.workers.threads_or(NUM_THREADS)),
+            metrics: Default::default()
+        }
+    }
+}
+
+impl StoreBackend for Sled {
+    /// Lists all Zone Paths stored locally under `prefix`. Keys don't sort parent zones before
+    /// their children, so every key is read.
+    fn list(&mut self, prefix: Path, tx: Sender<Path>) {
+        let prefix = Prefix(prefix);
+        for entry in self.tree.iter() {
+            let key = match entry {
+                Err(err) => {
+                    error!("Error iterating zones: {}", err.description());
+                    error!("  {:?}", err);
+                    return;
+                },
+                Ok((key, _)) => key
+            };
+
+            match bincode::deserialize::<Path>(&key) {
+                Err(err) => {
+                    error!("Bad zone key {:?}: {}", key, err.description());
+                },
+                Ok(path) => if prefix.matches(&path) {
+                    tx.send(path).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Loads data for a `Zone` asynchronously, notifying its handle when done, with what could be
+    /// salvaged if it is corrupt.
+    fn load(&mut self, zone: ZoneHandle, path: Path) {
+        let tree = self.tree.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.app.stats.store.reads_pending.increment();
+
+        let stats = self.app.stats.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            match blocking_read_raw(&tree, &path, &codec, &metrics).and_then(RawZone::recover) {
+                Err(StoreError::Corrupt { reason, .. }) => {
+                    error!("Corrupt data for {:?}: {}", path, reason);
+                    stats.store.reads_corrupt.increment();
+                    zone.corrupt();
+                },
+                Err(err) => {
+                    error!("Error loading {:?}: {}", path, err.description());
+                    error!("{:?}", err);
+                    stats.store.reads_errors.increment();
+                    // TODO: set Zone to error state
+                },
+                Ok((data, false)) => zone.loaded(data),
+                Ok((data, true)) => {
+                    error!("Corrupt data for {:?}, recovered in part", path);
+                    stats.store.reads_corrupt.increment();
+                    zone.recovered(data);
+                }
+            };
+
+            stats.store.reads_pending.decrement();
+            stats.store.reads.increment();
+        });
+    }
+
+    /// Asynchronously load and send `ZoneData` for `Path` to channel.
+    fn load_data(&mut self, path: Path, tx: Reply<Option<ZoneData>>) {
+        let tree = self.tree.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read(&tree, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Asynchronously load and send data for `Path` to channel, still serialized.
+    fn load_raw(&mut self, path: Path, tx: Reply<Option<RawZone>>) {
+        let tree = self.tree.clone();
+        let codec = self.codec.clone();
+        let metrics = self.metrics.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            debug!("Loading: {:?}", path);
+
+            tx.send(blocking_read_raw(&tree, &path, &codec, &metrics).ok()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Delete data for a `Zone`, notifying its handle when done.
+    fn delete(&mut self, zone: ZoneHandle, path: Path) {
+        debug!("Deleting: {:?}", path);
+
+        let sync = self.durability == Durability::Always;
+        let tree = &self.tree;
+
+        match self.retry.run(&format!("deleting {:?}", path), || blocking_delete(tree, &path, sync)) {
+            Err(err) => {
+                error!("Error deleting {:?}: {}", path, err.description());
+                error!("{:?}", err);
+                self.app.stats.store.writes_errors.increment();
+                zone.write_failed(err);
+            },
+            Ok(_) => zone.deleted()
+        }
+
+        self.app.stats.store.writes.increment();
+    }
+
+    /// Delete data for `path`, replying whether it succeeded.
+    fn delete_data(&mut self, path: Path, reply: Reply<bool>) {
+        let result = blocking_delete(&self.tree, &path, self.durability == Durability::Always);
+
+        if let Err(ref err) = result {
+            error!("Error deleting {:?}: {}", path, err.description());
+        }
+
+        reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Waits for loads running on worker threads, then flushes writes sled would otherwise flush
+    /// in the background, unless flushing is left to the OS.
+    fn shutdown(&mut self) {
+        self.workers.drain();
+
+        if self.durability != Durability::Never {
+            if let Err(err) = self.tree.flush() {
+                error!("Error flushing sled: {}", err.description());
+            }
+        }
+    }
+
+    /// Looks up the size of the value for `Path` asynchronously, without decoding it.
+    /// Modification times are not kept.
+    fn stat(&mut self, path: Path, reply: Reply<Option<ZoneStat>>) {
+        let tree = self.tree.clone();
+
+        self.workers.execute(self.workers.lane(&path), move|| {
+            reply.send(blocking_stat(&tree, &path)).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// I/O metrics. Writes are never queued, they are applied as they come in.
+    fn stats(&mut self) -> StoreStats {
+        let mut stats = self.metrics.snapshot();
+
+        stats.queue_depth = self.workers.queued_count();
+        stats
+    }
+
+    /// Write data for `path`, replying whether it succeeded.
+    fn write_data(&mut self, path: Path, data: Vec<u8>, reply: Reply<bool>) {
+        let sync = self.durability == Durability::Always;
+        let started = Instant::now();
+        let mut bytes = 0;
+
+        let result = self.codec.encode(data).and_then(|blob| {
+            let mut batch = Batch::default();
+
+            bytes = blob.len() as u64;
+            batch.insert(zonekey(&path), blob);
+
+            blocking_write(&self.tree, batch, sync)
+        });
+
+        match result {
+            Err(ref err) => error!("Error writing {:?}: {}", path, err.description()),
+            Ok(_) => self.metrics.written(bytes, started)
+        }
+
+        reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Write data for several paths as one batch, applied atomically, replying whether it
+    /// succeeded.
+    fn write_atomic(&mut self, writes: Vec<(Path, Vec<u8>)>, reply: Reply<bool>) {
+        let sync = self.durability == Durability::Always;
+        let started = Instant::now();
+        let count = writes.len();
+        let mut batch = Batch::default();
+        let mut bytes = 0;
+
+        for (path, data) in writes {
+            match self.codec.encode(data) {
+                Err(err) => {
+                    error!("Error encoding {:?}: {}", path, err.description());
+                    reply.send(false).is_ok(); // ignore if caller goes away
+                    return;
+                },
+                Ok(blob) => {
+                    bytes += blob.len() as u64;
+                    batch.insert(zonekey(&path), blob);
+                }
+            }
+        }
+
+        let result = blocking_write(&self.tree, batch, sync);
+
+        match result {
+            Err(ref err) => error!("Error writing {} zones atomically: {}", count, err.description()),
+            Ok(_) => self.metrics.written(bytes, started)
+        }
+
+        reply.send(result.is_ok()).is_ok(); // ignore if caller goes away
+    }
+
+    /// Request for notification to write data. Writes are batched, so always ready.
+    fn request_write(&mut self, zone: ZoneHandle) {
+        zone.save();
+    }
+
+    fn write(&mut self, zone: ZoneHandle, path: Path, data: Vec<u8>) {
+        self.write_batch(vec![(zone, path, data)]);
+    }
+
+    /// Atomically write and flush a batch of `Zone` data, notifying zones when done.
+    fn write_batch(&mut self, writes: Vec<(ZoneHandle, Path, Vec<u8>)>) {
+        let count = writes.len();
+
+        debug!("Writing batch of {} zones", count);
+
+        for _ in 0..count {
+            self.app.stats.store.writes_pending.increment();
+        }
+
+        let started = Instant::now();
+        let mut blobs = Vec::with_capacity(count);
+        let mut zones = Vec::with_capacity(count);
+        let mut bytes = 0;
+
+        for (zone, path, data) in writes {
+            match self.codec.encode(data) {
+                Err(err) => {
+                    error!("Error encoding {:?}: {}", path, err.description());
+                    self.app.stats.store.writes_errors.increment();
+                    zone.write_failed(err);
+                },
+                Ok(blob) => {
+                    bytes += blob.len() as u64;
+                    blobs.push((zonekey(&path), blob));
+                    zones.push(zone);
+                }
+            }
+        }
+
+        let sync = self.durability == Durability::Always;
+        let tree = &self.tree;
+
+        // A batch is used up by applying it, so each attempt builds its own
+        let result = self.retry.run(&format!("writing batch of {} zones", zones.len()), || {
+            let mut batch = Batch::default();
+
+            for &(ref key, ref blob) in &blobs {
+                batch.insert(key.clone(), blob.clone());
+            }
+
+            blocking_write(tree, batch, sync)
+        });
+
+        match result {
+            Err(err) => {
+                error!("Error writing batch of {} zones: {}", zones.len(), err.description());
+                error!("{:?}", err);
+
+                for zone in zones {
+                    self.app.stats.store.writes_errors.increment();
+                    zone.write_failed(StoreError::Backend(format!("Batch write failed: {}", err)));
+                }
+            },
+            Ok(_) => {
+                self.metrics.written(bytes, started);
+
+                for zone in zones {
+                    zone.saved();
+                }
+            }
+        }
+
+        for _ in 0..count {
+            self.app.stats.store.writes_pending.decrement();
+            self.app.stats.store.writes.increment();
+        }
+    }
+
+    /// Writes queued up together are applied in a single batch.
+    fn coalesces_writes(&self) -> bool {
+        true
+    }
+}
+
+fn blocking_stat(tree: &Tree, path: &Path) -> Option<ZoneStat> {
+    match tree.get(zonekey(path)) {
+        Err(err) => {
+            error!("Error reading {:?}: {}", path, err.description());
+            None
+        },
+        Ok(buffer) => buffer.map(|buffer| ZoneStat { size: buffer.len() as u64, modified: None })
+    }
+}
+
+fn blocking_read(tree: &Tree, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<ZoneData, StoreError> {
+    blocking_read_raw(tree, path, codec, metrics).and_then(RawZone::deserialize)
+}
+
+fn blocking_read_raw(tree: &Tree, path: &Path, codec: &Codec, metrics: &Metrics) -> Result<RawZone, StoreError> {
+    let started = Instant::now();
+
+    let buffer = match tree.get(zonekey(path)) {
+        Err(err) => return Err(StoreError::Backend(err.to_string())),
+        Ok(None) => return Ok(RawZone::default()),
+        Ok(Some(buffer)) => buffer
+    };
+
+    metrics.loaded(buffer.len() as u64, started);
+
+    Ok(RawZone::new(try!(codec.decode(buffer.to_vec()))))
+}
+
+fn blocking_delete(tree: &Tree, path: &Path, sync: bool) -> Result<(), StoreError> {
+    if let Err(err) = tree.remove(zonekey(path)) {
+        return Err(StoreError::Backend(err.to_string()));
+    }
+
+    if sync {
+        if let Err(err) = tree.flush() {
+            return Err(StoreError::Backend(err.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn blocking_write(tree: &Tree, batch: Batch, sync: bool) -> Result<(), StoreError> {
+    if let Err(err) = tree.apply_batch(batch) {
+        return Err(StoreError::Backend(err.to_string()));
+    }
+
+    if sync {
+        if let Err(err) = tree.flush() {
+            return Err(StoreError::Backend(err.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_read_write_list() {
+    use std::sync::Arc;
+
+    let dir = "test_data/sled_read_write_list";
+
+    std::fs::remove_dir_all(dir).ok();
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let mut store = Sled::new(app.handle(), dir, &Config::default());
+
+    assert_eq!(blocking_read(&store.tree, &path![moo], &store.codec, &store.metrics).unwrap(), Default::default());
+
+    let noop_zone = ZoneHandle::test_handle(Arc::new(path![]));
+    let limit = bincode::Infinite;
+
+    let writes = (0..3).map(|i| {
+        let path = Path::new(vec![i.to_string()]);
+        let zone_data = ZoneData::new(path.clone(), Default::default());
+
+        (noop_zone.clone(), path, bincode::serialize(&zone_data, limit).unwrap())
+    }).collect();
+
+    store.write_batch(writes);
+
+    let path = Path::new(vec!["1".into()]);
+    let expected = ZoneData::new(path.clone(), Default::default());
+
+    assert_eq!(blocking_read(&store.tree, &path, &store.codec, &store.metrics).unwrap(), expected);
+
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    store.list(Path::empty(), tx);
+
+    let mut paths: Vec<Path> = rx.iter().collect();
+
+    paths.sort();
+
+    assert_eq!(paths, [
+        Path::new(vec!["0".into()]),
+        Path::new(vec!["1".into()]),
+        Path::new(vec!["2".into()]),
+    ]);
+}
@@ -0,0 +1,132 @@
+//! Store I/O metrics, for operators.
+//!
+//! Backends record bytes read and written, and how long loads and writes take, in a shared
+//! `Metrics`. `StoreCall::Stats` replies with a `StoreStats` snapshot of them, along with how many
+//! loads and writes are waiting and how well the cache does. Unlike `app::StoreStats`, these are only gathered on request.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Upper bounds of latency histogram buckets, in microseconds. Slower operations are counted in
+/// one more bucket.
+pub const BUCKETS: [u64; 6] = [100, 1000, 10000, 100000, 1000000, 10000000];
+
+/// Operation latencies.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Histogram {
+    pub buckets: [u64; 7], // Operations per bucket in `BUCKETS`
+    pub count: u64,
+    pub total_us: u64
+}
+
+/// Snapshot of a Store's metrics.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StoreStats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub load_latency: Histogram,
+    pub write_latency: Histogram,
+    pub queue_depth: usize,    // Loads and writes waiting for a worker thread
+    pub pending_writes: usize, // Write requests held back by the scheduler
+    pub cache_hits: u64,       // Loads served from the cache (see `store::cache`)
+    pub cache_misses: u64,
+    pub cache_bytes: usize
+}
+
+/// Metrics shared by a Store and its worker threads.
+#[derive(Default)]
+pub struct Metrics {
+    stats: Mutex<StoreStats>
+}
+
+impl Histogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let us = elapsed.as_secs() * 1000000 + elapsed.subsec_nanos() as u64 / 1000;
+        let bucket = BUCKETS.iter().position(|&bound| us <= bound).unwrap_or(BUCKETS.len());
+
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_us += us;
+    }
+
+    /// Mean latency in microseconds, 0 if nothing was recorded.
+    pub fn mean_us(&self) -> u64 {
+        if self.count == 0 { 0 } else { self.total_us / self.count }
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += *count;
+        }
+
+        self.count += other.count;
+        self.total_us += other.total_us;
+    }
+}
+
+impl StoreStats {
+    /// Adds up metrics of another Store, e.g. for both tiers of `store::tiered`.
+    pub fn merge(&mut self, other: &StoreStats) {
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.load_latency.merge(&other.load_latency);
+        self.write_latency.merge(&other.write_latency);
+        self.queue_depth += other.queue_depth;
+        self.pending_writes += other.pending_writes;
+        self.cache_hits += other.cache_hits;
+        self.cache_misses += other.cache_misses;
+        self.cache_bytes += other.cache_bytes;
+    }
+}
+
+impl Metrics {
+    /// Records a load of `bytes` started at `started`.
+    pub fn loaded(&self, bytes: u64, started: Instant) {
+        let mut stats = self.stats.lock().unwrap();
+
+        stats.bytes_read += bytes;
+        stats.load_latency.record(started.elapsed());
+    }
+
+    /// Records a write of `bytes` started at `started`.
+    pub fn written(&self, bytes: u64, started: Instant) {
+        let mut stats = self.stats.lock().unwrap();
+
+        stats.bytes_written += bytes;
+        stats.write_latency.record(started.elapsed());
+    }
+
+    /// Current metrics, with `queue_depth` and `pending_writes` left for the Store to fill in.
+    pub fn snapshot(&self) -> StoreStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+#[test]
+fn test_histogram() {
+    let mut histogram = Histogram::default();
+
+    histogram.record(Duration::from_millis(0));
+    histogram.record(Duration::new(0, 500000));
+    histogram.record(Duration::from_millis(50));
+    histogram.record(Duration::from_secs(60));
+
+    assert_eq!(histogram.buckets, [1, 1, 0, 1, 0, 0, 1]);
+    assert_eq!(histogram.count, 4);
+    assert_eq!(histogram.mean_us(), (500 + 50000 + 60000000) / 4);
+
+    let mut total = StoreStats::default();
+    let mut stats = StoreStats::default();
+
+    stats.bytes_read = 10;
+    stats.load_latency = histogram.clone();
+    stats.queue_depth = 2;
+
+    total.merge(&stats);
+    total.merge(&stats);
+
+    assert_eq!(total.bytes_read, 20);
+    assert_eq!(total.load_latency.count, 8);
+    assert_eq!(total.load_latency.buckets[6], 2);
+    assert_eq!(total.queue_depth, 4);
+}
@@ -0,0 +1,250 @@
+//! Streaming loads of large zones.
+//!
+//! `ZoneHandle::loaded` hands a zone all of its data in one message, so the Store holds the whole
+//! stored blob and its deserialized `ZoneData` at once. For multi-hundred-MB zones, that is a big
+//! spike on top of the zone itself. Zone files of at least `STREAM_SIZE` are streamed instead: the
+//! fs store sends serialized zone data in chunks of `CHUNK_SIZE` (see `ChunkSender`), and the zone
+//! deserializes it as chunks come in (see `ChunkReader::receive`).
+//!
+//! At most `DEPTH` chunks are in flight, the Store waits for the zone to catch up. Blobs that are
+//! only checksummed are read from disk chunk by chunk, and their checksum verified once all of it
+//! has been sent, failing the load after the fact if it does not match. Compressed or encrypted
+//! blobs have to be decoded in one go before they are sent.
+//!
+//! Deltas and WAL entries for the zone follow the data, and are replayed by the zone.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc;
+
+use crc32fast;
+use mioco;
+use mioco::sync::mpsc::{Receiver, Sender};
+
+use super::StoreError;
+use super::codec::{self, Codec};
+use super::delta;
+use super::migrate;
+use super::wal;
+use zone::ZoneData;
+
+/// Zone files of at least this many bytes are streamed to their zone
+pub const STREAM_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Bytes of serialized zone data per chunk
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Chunks sent but not yet read by the zone, at most
+pub const DEPTH: usize = 4;
+
+/// Streamed by a Store, in order: data, then one of the others.
+pub enum Chunk {
+    Data(Vec<u8>),                   // Next piece of serialized zone data
+    End(Vec<Vec<u8>>, Vec<Vec<u8>>), // Decoded deltas and WAL entries to replay over the data
+    Corrupt(String),                 // Data sent failed its checksum
+    Failed(String)                   // Store could not read on
+}
+
+/// Store side of a stream.
+pub struct ChunkSender {
+    tx: Sender<Chunk>,
+    acks: mpsc::Receiver<()>, // One per chunk read
+    in_flight: usize
+}
+
+/// Zone side of a stream, reading serialized zone data as it comes in.
+pub struct ChunkReader {
+    rx: Receiver<Chunk>,
+    acks: mpsc::Sender<()>,
+    chunk: Vec<u8>,
+    pos: usize,
+    end: Option<Chunk> // Set once all data is read
+}
+
+/// Creates a stream. Zones receive on a mioco channel, so they never block a mioco thread.
+pub fn channel() -> (ChunkSender, ChunkReader) {
+    let (tx, rx) = mioco::sync::mpsc::channel();
+    let (acks_tx, acks_rx) = mpsc::channel();
+
+    let sender = ChunkSender { tx: tx, acks: acks_rx, in_flight: 0 };
+    let reader = ChunkReader { rx: rx, acks: acks_tx, chunk: vec![], pos: 0, end: None };
+
+    (sender, reader)
+}
+
+impl ChunkSender {
+    /// Sends a chunk, once fewer than `DEPTH` are in flight. Returns false if the zone went away.
+    pub fn send(&mut self, chunk: Chunk) -> bool {
+        if self.in_flight == DEPTH {
+            if self.acks.recv().is_err() {
+                return false;
+            }
+
+            self.in_flight -= 1;
+        }
+
+        self.in_flight += 1;
+        self.tx.send(chunk).is_ok()
+    }
+
+    /// Sends the serialized zone data in a stored `blob`, read from `reader`, without the deltas
+    /// and WAL entries that end the stream. Errors are left for the caller to send on.
+    pub fn send_blob<R: Read>(&mut self, mut reader: R, codec: &Codec) -> Result<(), StoreError> {
+        // Enough to tell how the payload is encoded
+        let mut head = vec![];
+
+        try!(reader.by_ref().take(codec::CHECKSUM_HEADER_LEN as u64 + 3).read_to_end(&mut head)
+            .map_err(StoreError::Io));
+
+        let expected = try!(codec::expected_checksum(&head));
+        let start = if expected.is_some() { codec::CHECKSUM_HEADER_LEN } else { 0 };
+
+        if ! codec::is_plain(&head[start..]) {
+            try!(reader.read_to_end(&mut head).map_err(StoreError::Io));
+
+            let serialized = try!(codec.decode(head));
+
+            return self.send_all(serialized.chunks(CHUNK_SIZE).map(|chunk| chunk.to_vec()));
+        }
+
+        let payload = head.split_off(start);
+        let mut hasher = crc32fast::Hasher::new();
+
+        hasher.update(&payload);
+        try!(self.send_all(Some(payload)));
+
+        loop {
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+
+            try!(reader.by_ref().take(CHUNK_SIZE as u64).read_to_end(&mut chunk)
+                .map_err(StoreError::Io));
+
+            if chunk.is_empty() {
+                break;
+            }
+
+            hasher.update(&chunk);
+            try!(self.send_all(Some(chunk)));
+        }
+
+        match (expected, hasher.finalize()) {
+            (Some(expected), actual) if actual != expected => Err(codec::checksum_mismatch(expected, actual)),
+            _ => Ok(())
+        }
+    }
+
+    /// Ends the stream after a failed load.
+    pub fn fail(&mut self, err: &StoreError) {
+        let chunk = match *err {
+            StoreError::Corrupt { ref reason, .. } => Chunk::Corrupt(reason.clone()),
+            ref err => Chunk::Failed(err.to_string())
+        };
+
+        self.send(chunk); // nothing more to tell if the zone went away
+    }
+
+    fn send_all<I>(&mut self, chunks: I) -> Result<(), StoreError> where I: IntoIterator<Item=Vec<u8>> {
+        for chunk in chunks {
+            if ! chunk.is_empty() && ! self.send(Chunk::Data(chunk)) {
+                return Err(StoreError::Disconnected);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl ChunkReader {
+    /// Deserializes the streamed zone data, and replays deltas and WAL entries sent after it.
+    pub fn receive(mut self) -> Result<ZoneData, StoreError> {
+        let result = migrate::deserialize_from(&mut self);
+
+        // A checksum mismatch is only found after the last chunk
+        io::copy(&mut self, &mut io::sink()).ok();
+
+        match self.end.take() {
+            Some(Chunk::End(deltas, entries)) => {
+                let mut data = try!(result);
+
+                delta::replay(&mut data, deltas);
+                wal::replay(&mut data, entries);
+
+                Ok(data)
+            },
+            Some(Chunk::Corrupt(reason)) => Err(StoreError::corrupt(reason)),
+            Some(Chunk::Failed(reason)) => Err(StoreError::Backend(reason)),
+            _ => Err(StoreError::Disconnected)
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            if self.end.is_some() {
+                return Ok(0);
+            }
+
+            match self.rx.recv() {
+                Ok(Chunk::Data(chunk)) => {
+                    self.acks.send(()).ok(); // ignore if the Store is done sending
+
+                    self.chunk = chunk;
+                    self.pos = 0;
+                },
+                Ok(end) => self.end = Some(end),
+                Err(_) => self.end = Some(Chunk::Failed("Store went away while streaming".into()))
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_stream() {
+    use std::thread;
+
+    let blob: Vec<u8> = (0..CHUNK_SIZE * DEPTH * 2 + 10).map(|i| i as u8).collect();
+    let (mut sender, mut reader) = channel();
+
+    // Sender blocks until chunks are read
+    let sent = blob.clone();
+    let sending = thread::spawn(move|| {
+        sender.send_blob(&sent[..], &Codec::default()).unwrap();
+        sender.send(Chunk::End(vec![], vec![]))
+    });
+
+    let mut received = vec![];
+
+    reader.read_to_end(&mut received).unwrap();
+
+    assert!(sending.join().unwrap());
+    assert_eq!(received, blob);
+    assert!(match reader.end { Some(Chunk::End(..)) => true, _ => false });
+
+    // Checksummed data is verified once sent
+    let mut blob = codec::checksum(b"moo moo moo".to_vec());
+    let last = blob.len() - 1;
+
+    blob[last] ^= 1;
+
+    let (mut sender, mut reader) = channel();
+
+    match sender.send_blob(&blob[..], &Codec::default()) {
+        Err(err @ StoreError::Corrupt { .. }) => sender.fail(&err),
+        other => panic!("Expected corrupt data, got {:?}", other)
+    }
+
+    let mut received = vec![];
+
+    reader.read_to_end(&mut received).unwrap();
+
+    assert_eq!(received, b"moo moo mon");
+    assert!(match reader.end { Some(Chunk::Corrupt(_)) => true, _ => false });
+}
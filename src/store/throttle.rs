@@ -0,0 +1,183 @@
+//! Rate limiting of background zone writes.
+//!
+//! Dirty zones all flushing at once can saturate the disk, and loads for client requests wait
+//! behind them. With `STORE_THROTTLE`, zone writes take their bytes and one op per zone from a
+//! `Throttle` shared by all of a backend's worker threads before doing any I/O, and wait when it
+//! runs dry. It refills at the configured rates and holds up to a second's worth, so short bursts
+//! still go through at full speed.
+//!
+//! Only zone writes (`Write`, `WriteBatch` and `WriteDelta`) are throttled, as zones stay dirty
+//! while they wait. Loads, deletes and writes callers wait on go through right away. Bytes are
+//! counted as serialized, before compression. The `fs`, `rocksdb` and `s3` stores are throttled;
+//! `sled` writes on the Store thread, where waiting would hold up loads too.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rates zone writes are limited to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ThrottlePolicy {
+    pub bytes: u64, // Bytes per second, 0 for no limit
+    pub ops: u64    // Zones per second, 0 for no limit
+}
+
+/// Allowance for zone writes, shared by worker threads.
+pub struct Throttle {
+    policy: ThrottlePolicy,
+    state: Mutex<State>
+}
+
+/// Bytes and ops left to take, negative when taken ahead of time.
+struct State {
+    bytes: f64,
+    ops: f64,
+    updated: Instant
+}
+
+impl ThrottlePolicy {
+    pub fn is_limited(&self) -> bool {
+        self.bytes > 0 || self.ops > 0
+    }
+}
+
+impl FromStr for ThrottlePolicy {
+    type Err = String;
+
+    /// Parses `none`, `<bytes/s>` or `<bytes/s>:<zones/s>`. A rate of 0 leaves that one unlimited.
+    fn from_str(s: &str) -> Result<ThrottlePolicy, String> {
+        if s == "none" {
+            return Ok(Default::default());
+        }
+
+        let mut parts = s.splitn(2, ':');
+
+        let bytes = match parts.next().unwrap().parse() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(format!("Bad throttle bytes: {}", s))
+        };
+
+        let ops = match parts.next().map(|ops| ops.parse()) {
+            None => 0,
+            Some(Ok(ops)) => ops,
+            Some(Err(_)) => return Err(format!("Bad throttle zones: {}", s))
+        };
+
+        match (ThrottlePolicy { bytes: bytes, ops: ops }) {
+            policy if policy.is_limited() => Ok(policy),
+            _ => Err(format!("Bad throttle, no limit: {}", s))
+        }
+    }
+}
+
+impl fmt::Display for ThrottlePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.bytes, self.ops) {
+            (0, 0) => write!(f, "none"),
+            (bytes, 0) => write!(f, "{} bytes/s", bytes),
+            (0, ops) => write!(f, "{} zones/s", ops),
+            (bytes, ops) => write!(f, "{} bytes/s, {} zones/s", bytes, ops)
+        }
+    }
+}
+
+impl Throttle {
+    pub fn new(policy: ThrottlePolicy) -> Throttle {
+        Throttle {
+            policy: policy,
+            state: Mutex::new(State {
+                bytes: policy.bytes as f64,
+                ops: policy.ops as f64,
+                updated: Instant::now()
+            })
+        }
+    }
+
+    /// Waits until writing `zones` zones of `bytes` in all is allowed.
+    pub fn wait(&self, zones: usize, bytes: usize) {
+        let delay = self.take(zones, bytes, Instant::now());
+
+        if delay > Duration::from_millis(0) {
+            debug!("Throttling write of {} zones, {} bytes for {:?}", zones, bytes, delay);
+            thread::sleep(delay);
+        }
+    }
+
+    /// Takes the allowance for writing `zones` zones of `bytes` at `now`, returning how long to
+    /// wait for it. Later writes wait for any taken ahead of time too.
+    fn take(&self, zones: usize, bytes: usize, now: Instant) -> Duration {
+        if ! self.policy.is_limited() {
+            return Duration::from_millis(0);
+        }
+
+        let mut state = self.state.lock().unwrap();
+
+        // Threads racing for the lock may come in with `now` slightly out of order
+        let elapsed = if now > state.updated { now.duration_since(state.updated) } else { Duration::from_millis(0) };
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+
+        state.updated = state.updated.max(now);
+
+        let bytes_wait = refill(&mut state.bytes, self.policy.bytes, elapsed, bytes);
+        let ops_wait = refill(&mut state.ops, self.policy.ops, elapsed, zones);
+        let wait = bytes_wait.max(ops_wait);
+
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+}
+
+/// Refills `left` at `rate` per second for `elapsed` seconds, up to a second's worth, and takes
+/// `amount` from it. Returns the seconds until it is back to 0, for a limited `rate`.
+fn refill(left: &mut f64, rate: u64, elapsed: f64, amount: usize) -> f64 {
+    if rate == 0 {
+        return 0.0;
+    }
+
+    let rate = rate as f64;
+
+    *left = (*left + elapsed * rate).min(rate) - amount as f64;
+
+    if *left < 0.0 { -*left / rate } else { 0.0 }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("none".parse(), Ok(ThrottlePolicy::default()));
+    assert_eq!("1000000".parse(), Ok(ThrottlePolicy { bytes: 1000000, ops: 0 }));
+    assert_eq!("1000000:50".parse(), Ok(ThrottlePolicy { bytes: 1000000, ops: 50 }));
+    assert_eq!("0:50".parse(), Ok(ThrottlePolicy { bytes: 0, ops: 50 }));
+
+    assert!("0".parse::<ThrottlePolicy>().is_err());
+    assert!("moo".parse::<ThrottlePolicy>().is_err());
+    assert!("1000:moo".parse::<ThrottlePolicy>().is_err());
+}
+
+#[test]
+fn test_take() {
+    let throttle = Throttle::new(ThrottlePolicy { bytes: 1000, ops: 10 });
+    let start = throttle.state.lock().unwrap().updated;
+    let zero = Duration::from_millis(0);
+
+    // A second's worth goes through right away
+    assert_eq!(throttle.take(1, 1000, start), zero);
+
+    // Then writes wait for bytes, and for those taken before them
+    assert_eq!(throttle.take(1, 500, start), Duration::from_millis(500));
+    assert_eq!(throttle.take(1, 500, start), Duration::from_millis(1000));
+
+    // Or for ops
+    let throttle = Throttle::new(ThrottlePolicy { bytes: 0, ops: 10 });
+    let start = throttle.state.lock().unwrap().updated;
+
+    assert_eq!(throttle.take(15, 1000000, start), Duration::from_millis(500));
+
+    // Refilled over time, but no more than a second's worth
+    assert_eq!(throttle.take(5, 0, start + Duration::from_secs(1)), zero);
+    assert_eq!(throttle.take(5, 0, start + Duration::from_secs(10)), zero);
+    assert_eq!(throttle.take(10, 0, start + Duration::from_secs(10)), Duration::from_millis(500));
+
+    // Unlimited
+    assert_eq!(Throttle::new(Default::default()).take(1000, 1000000, start), zero);
+}
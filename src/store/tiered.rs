@@ -0,0 +1,565 @@
+//! A tiered zone store. Recently used zones are kept on local disk by `store::fs`, the rest are
+//! demoted to a secondary "cold" backend such as `store::s3`.
+//!
+//! A demotion pass runs periodically, within the maintenance windows of `STORE_MAINTENANCE` (see
+//! `store::maintenance`), copying the zones picked by the `TierPolicy` to the cold store and then
+//! removing them from local disk. Loading a cold zone pulls it back to local disk
+//! first, so apart from latency zones can't tell the difference.
+//!
+//! Configuration is read from the environment:
+//!
+//! * `STORE_TIER_COLD` - cold backend (required), anything but `fs` and `tiered`
+//! * `STORE_TIER_POLICY` - `age:<seconds>` demotes zones unused for that long (default
+//!   `age:86400`), `lru:<zones>` keeps that many most recently used zones on local disk
+//! * `STORE_TIER_INTERVAL` - seconds between demotion passes, defaults to 60
+//!
+//! A read-only tiered Store neither demotes zones nor pulls them back, cold zones are loaded
+//! straight from the cold store.
+
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::*;
+use super::spawn_backend;
+use super::stats::StoreStats;
+use app::App;
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use zone::ZoneHandle;
+
+const DEFAULT_AGE: u64 = 86400;
+const DEFAULT_INTERVAL: u64 = 60;
+
+/// Configuration for the tiered Store.
+#[derive(Clone, Debug)]
+pub struct TierConfig {
+    pub cold: Backend,
+    pub policy: TierPolicy,
+    pub interval: u64 // Seconds between demotion passes
+}
+
+/// Which zones are demoted to the cold store.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TierPolicy {
+    Age(u64), // Zones unused for this many seconds
+    Lru(usize) // All but this many most recently used zones
+}
+
+/// Where data for a zone is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Tier {
+    Hot(Instant), // Local disk, last used at
+    Demoting,     // Local disk, being copied to the cold store
+    Cold
+}
+
+type Tiers = Arc<Mutex<HashMap<Path, Tier>>>;
+
+pub struct Tiered {
+    rx: Receiver<StoreCall>,
+
+    hot: StoreHandle,
+    cold: StoreHandle,
+
+    tiers: Tiers,
+
+    // Leave zones where they are, loading cold ones straight from the cold store
+    read_only: bool
+}
+
+impl TierConfig {
+    /// Reads configuration from `STORE_TIER_*` environment variables.
+    pub fn from_env() -> TierConfig {
+        let cold = env::var("STORE_TIER_COLD").expect("STORE_TIER_COLD not set")
+            .parse().unwrap_or_else(|err| panic!("STORE_TIER_COLD: {}", err));
+
+        if cold == Backend::FS || cold == Backend::Mirror || cold == Backend::Tiered {
+            panic!("STORE_TIER_COLD: {:?} can't be the cold tier", cold);
+        }
+
+        TierConfig {
+            cold: cold,
+            policy: env::var("STORE_TIER_POLICY").ok()
+                .map(|p| p.parse().unwrap_or_else(|err| panic!("STORE_TIER_POLICY: {}", err)))
+                .unwrap_or(TierPolicy::Age(DEFAULT_AGE)),
+            interval: env::var("STORE_TIER_INTERVAL").ok()
+                .map(|i| i.parse().expect("Bad STORE_TIER_INTERVAL"))
+                .unwrap_or(DEFAULT_INTERVAL)
+        }
+    }
+}
+
+impl FromStr for TierPolicy {
+    type Err = String;
+
+    /// Parses `age:<seconds>` or `lru:<zones>`.
+    fn from_str(s: &str) -> Result<TierPolicy, String> {
+        let mut parts = s.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some("age"), Some(secs)) => secs.parse().map(TierPolicy::Age)
+                .map_err(|_| format!("Bad tier age: {}", secs)),
+            (Some("lru"), Some(zones)) => zones.parse().map(TierPolicy::Lru)
+                .map_err(|_| format!("Bad tier zone count: {}", zones)),
+            _ => Err(format!("Unknown tier policy: {}", s))
+        }
+    }
+}
+
+impl Tiered {
+    /// Start the Store "process", along with the fs and cold Stores it is made of.
+    pub fn spawn(app: &App, channel: StoreChannel, config: &Config) {
+        let tier_config = TierConfig::from_env();
+
+        let hot = StoreChannel::new();
+        let cold = StoreChannel::new();
+        let (hot_handle, cold_handle) = (hot.handle(), cold.handle());
+
+        // Zones are written to the fs Store, so that's where the cache does any good
+        let uncached = Config { cache: Default::default(), ..config.clone() };
+
+        spawn_backend(app, Backend::FS, hot, config);
+        spawn_backend(app, tier_config.cold, cold, &uncached);
+
+        let store = Tiered::new(hot_handle, cold_handle, channel, config.read_only);
+
+        if ! config.read_only {
+            let hot = store.hot.clone();
+            let cold = store.cold.clone();
+            let tiers = store.tiers.clone();
+            let maintenance = config.maintenance.clone();
+
+            thread::spawn(move|| {
+                demote_loop(&hot, &cold, &tiers, tier_config.policy, tier_config.interval, &maintenance);
+            });
+        }
+
+        thread::spawn(move|| {
+            store.message_loop();
+        });
+    }
+
+    /// Creates a tiered Store on top of running `hot` and `cold` Stores, finding out which zones
+    /// are where.
+    pub fn new(hot: StoreHandle, cold: StoreHandle, channel: StoreChannel, read_only: bool) -> Tiered {
+        let mut tiers = HashMap::new();
+        let now = Instant::now();
+
+        if let Err(err) = hot.each_zone(|path| { tiers.insert(path, Tier::Hot(now)); }) {
+            error!("Error listing hot zones: {}", err);
+        }
+
+        // Zones pulled back may leave a stale copy behind, local data wins
+        if let Err(err) = cold.each_zone(|path| { tiers.entry(path).or_insert(Tier::Cold); }) {
+            error!("Error listing cold zones: {}", err);
+        }
+
+        Tiered {
+            rx: channel.rx,
+            hot: hot,
+            cold: cold,
+            tiers: Arc::new(Mutex::new(tiers)),
+            read_only: read_only
+        }
+    }
+
+    fn message_loop(self) {
+        loop {
+            let call = self.rx.recv().unwrap();
+
+            match call {
+                StoreCall::Append(..) => self.append(call),
+                StoreCall::Compact(..) => self.compact(call),
+                StoreCall::Delete(..) | StoreCall::DeleteData(..) => self.delete(call),
+                StoreCall::List(prefix, reply) => self.list(prefix, reply),
+                StoreCall::Load(zone, path) => self.load(zone, path),
+                StoreCall::LoadData(..) => self.load_data(call),
+                StoreCall::LoadMany(zones) => self.load_many(zones),
+                StoreCall::LoadRaw(..) => self.load_data(call),
+                StoreCall::Quarantine(path, reply) => self.quarantine(path, reply),
+                StoreCall::Reload(zone, path) => self.reload(zone, path),
+                StoreCall::RequestWrite(..) | StoreCall::Restore(..) => self.hot.tx.send(call).unwrap(),
+                StoreCall::Shutdown(reply) => self.shutdown(reply),
+                StoreCall::Stat(..) => self.load_data(call),
+                StoreCall::Stats(reply) => self.stats(reply),
+                StoreCall::Verify(..) => self.load_data(call),
+                StoreCall::Write(..) | StoreCall::WriteAtomic(..) | StoreCall::WriteBatch(..) |
+                StoreCall::WriteData(..) | StoreCall::WriteDelta(..) => self.write(call)
+            }
+        }
+    }
+
+    /// Logs a diff to the fs Store. Zones demoted while still in memory stay cold: the diff is
+    /// replayed when they are pulled back.
+    fn append(&self, call: StoreCall) {
+        if let StoreCall::Append(ref path, _) = call {
+            let mut tiers = self.tiers.lock().unwrap();
+
+            match tiers.get(path).cloned() {
+                Some(Tier::Cold) => (),
+                _ => { tiers.insert(path.clone(), Tier::Hot(Instant::now())); }
+            }
+        }
+
+        self.hot.tx.send(call).unwrap();
+    }
+
+    /// Compacts a zone wherever it is stored.
+    fn compact(&self, call: StoreCall) {
+        let cold = match call {
+            StoreCall::Compact(ref path) => self.tiers.lock().unwrap().get(path) == Some(&Tier::Cold),
+            _ => false
+        };
+
+        if cold {
+            self.cold.tx.send(call).unwrap();
+        }
+        else {
+            self.hot.tx.send(call).unwrap();
+        }
+    }
+
+    /// Deletes a zone from both tiers, notifying the caller once the local copy is gone.
+    fn delete(&self, call: StoreCall) {
+        let path = match call {
+            StoreCall::Delete(_, ref path) | StoreCall::DeleteData(ref path, _) => path.clone(),
+            _ => return
+        };
+
+        let mut tiers = self.tiers.lock().unwrap();
+
+        tiers.remove(&path);
+
+        // Any cold copy goes too, even a stale one
+        let (tx, _) = channel();
+
+        self.cold.tx.send(StoreCall::DeleteData(path, tx.into())).unwrap();
+        self.hot.tx.send(call).unwrap();
+    }
+
+    /// Lists all Zone Paths under `prefix` stored in either tier.
+    pub fn list(&self, prefix: Path, tx: Sender<Path>) {
+        let prefix = Prefix(prefix);
+        let paths: Vec<Path> = self.tiers.lock().unwrap().keys()
+            .filter(|path| prefix.matches(path))
+            .cloned().collect();
+
+        for path in paths {
+            tx.send(path).unwrap();
+        }
+    }
+
+    /// Loads data for a `Zone`, pulling it back from the cold store first if needed.
+    pub fn load(&self, zone: ZoneHandle, path: Path) {
+        let mut tiers = self.tiers.lock().unwrap();
+
+        if tiers.get(&path) != Some(&Tier::Cold) {
+            tiers.insert(path.clone(), Tier::Hot(Instant::now()));
+
+            if let Err(err) = self.hot.load(&zone, &path) {
+                error!("Error loading {:?}: {}", path, err);
+            }
+
+            return;
+        }
+
+        if self.read_only {
+            if let Err(err) = self.cold.load(&zone, &path) {
+                error!("Error loading {:?} from cold store: {}", path, err);
+            }
+
+            return;
+        }
+
+        let hot = self.hot.clone();
+        let cold = self.cold.clone();
+        let tiers = self.tiers.clone();
+
+        thread::spawn(move|| {
+            promote(&hot, &cold, &tiers, zone, path);
+        });
+    }
+
+    /// Loads data for many `Zone`s, those in the hot tier in one call to the fs Store.
+    fn load_many(&self, zones: Vec<(ZoneHandle, Path)>) {
+        let mut hot = vec![];
+        let mut cold = vec![];
+
+        {
+            let mut tiers = self.tiers.lock().unwrap();
+            let now = Instant::now();
+
+            for (zone, path) in zones {
+                if tiers.get(&path) == Some(&Tier::Cold) {
+                    cold.push((zone, path));
+                }
+                else {
+                    tiers.insert(path.clone(), Tier::Hot(now));
+                    hot.push((zone, path));
+                }
+            }
+        }
+
+        if let Err(err) = self.hot.load_many(hot) {
+            error!("Error loading zones: {}", err);
+        }
+
+        for (zone, path) in cold {
+            self.load(zone, path);
+        }
+    }
+
+    /// Loads data for a `Zone` again, from whichever tier holds it.
+    fn reload(&self, zone: ZoneHandle, path: Path) {
+        let cold = self.tiers.lock().unwrap().get(&path) == Some(&Tier::Cold);
+
+        if cold {
+            self.load(zone, path);
+        }
+        else if let Err(err) = self.hot.reload(&zone, &path) {
+            error!("Error reloading {:?}: {}", path, err);
+        }
+    }
+
+    /// Sends `ZoneData` (serialized or not, or its `ZoneStat`, or whether it verifies) for `Path`
+    /// to channel from wherever it is stored.
+    fn load_data(&self, call: StoreCall) {
+        let cold = match call {
+            StoreCall::LoadData(ref path, _) |
+            StoreCall::LoadRaw(ref path, _) |
+            StoreCall::Stat(ref path, _) |
+            StoreCall::Verify(ref path, _) => self.tiers.lock().unwrap().get(path) == Some(&Tier::Cold),
+            _ => false
+        };
+
+        if cold {
+            self.cold.tx.send(call).unwrap();
+        }
+        else {
+            self.hot.tx.send(call).unwrap();
+        }
+    }
+
+    /// Moves a zone aside in the tier holding it, replying whether it succeeded.
+    fn quarantine(&self, path: Path, reply: Reply<bool>) {
+        let tiers = self.tiers.clone();
+
+        let store = match tiers.lock().unwrap().get(&path) {
+            Some(&Tier::Cold) => self.cold.clone(),
+            _ => self.hot.clone()
+        };
+
+        thread::spawn(move|| {
+            let moved = store.quarantine(path.clone()).unwrap_or(false);
+
+            if moved {
+                tiers.lock().unwrap().remove(&path);
+            }
+
+            reply.send(moved).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Shuts down both tiers, replying once both are done. Zones being moved between them fail to
+    /// write.
+    fn shutdown(&self, reply: Reply<()>) {
+        let hot = self.hot.clone();
+        let cold = self.cold.clone();
+
+        thread::spawn(move|| {
+            for store in &[hot, cold] {
+                if let Err(err) = store.shutdown() {
+                    error!("Error shutting down tier: {}", err);
+                }
+            }
+
+            reply.send(()).is_ok(); // ignore if caller goes away
+        });
+    }
+
+    /// Replies with I/O metrics of both tiers added up.
+    fn stats(&self, reply: Reply<StoreStats>) {
+        let mut stats = self.hot.stats().unwrap_or_default();
+
+        stats.merge(&self.cold.stats().unwrap_or_default());
+
+        reply.send(stats).is_ok(); // ignore if caller goes away
+    }
+
+    /// Writes zone data (or a delta) to the fs Store, where it is up to date from then on.
+    fn write(&self, call: StoreCall) {
+        {
+            let mut tiers = self.tiers.lock().unwrap();
+            let now = Instant::now();
+
+            match call {
+                StoreCall::Write(_, ref path, _) | StoreCall::WriteData(ref path, _, _) |
+                StoreCall::WriteDelta(_, ref path, _) => {
+                    tiers.insert(path.clone(), Tier::Hot(now));
+                },
+                StoreCall::WriteAtomic(ref writes, _) => {
+                    for &(ref path, _) in writes {
+                        tiers.insert(path.clone(), Tier::Hot(now));
+                    }
+                },
+                StoreCall::WriteBatch(ref writes) => {
+                    for &(_, ref path, _) in writes {
+                        tiers.insert(path.clone(), Tier::Hot(now));
+                    }
+                },
+                _ => ()
+            }
+        }
+
+        self.hot.tx.send(call).unwrap();
+    }
+}
+
+/// Copies a cold zone back to local disk, then loads it from there with any diffs logged since it
+/// was demoted.
+fn promote(hot: &StoreHandle, cold: &StoreHandle, tiers: &Tiers, zone: ZoneHandle, path: Path) {
+    debug!("Pulling back {:?}", path);
+
+    let data = match cold.load_data(path.clone()) {
+        Ok(Some(data)) => data,
+        _ => {
+            error!("Error loading {:?} from cold store", path);
+            return; // TODO: set Zone to error state
+        }
+    };
+
+    if ! hot.write_data(path.clone(), &data).unwrap_or(false) {
+        error!("Error pulling back {:?} from cold store", path);
+        return; // TODO: set Zone to error state
+    }
+
+    tiers.lock().unwrap().insert(path.clone(), Tier::Hot(Instant::now()));
+
+    if let Err(err) = hot.load(&zone, &path) {
+        error!("Error loading {:?}: {}", path, err);
+    }
+
+    if ! cold.delete_data(path.clone()).unwrap_or(false) {
+        info!("Could not delete cold copy of {:?}, left behind", path);
+    }
+}
+
+/// Periodically demotes zones picked by `policy`, once `maintenance` allows it.
+fn demote_loop(hot: &StoreHandle, cold: &StoreHandle, tiers: &Tiers, policy: TierPolicy, interval: u64, maintenance: &MaintenancePolicy) {
+    loop {
+        thread::sleep(Duration::from_secs(interval));
+
+        maintenance.wait(hot);
+
+        let paths = {
+            let tiers = tiers.lock().unwrap();
+
+            select(&tiers, policy, Instant::now())
+        };
+
+        if ! paths.is_empty() {
+            info!("Demoting {} zones to cold store", paths.len());
+        }
+
+        for path in paths {
+            demote(hot, cold, tiers, path);
+        }
+    }
+}
+
+/// Copies a zone to the cold store, then removes it from local disk unless it was used meanwhile.
+fn demote(hot: &StoreHandle, cold: &StoreHandle, tiers: &Tiers, path: Path) {
+    {
+        let mut tiers = tiers.lock().unwrap();
+
+        match tiers.get(&path) {
+            Some(&Tier::Hot(_)) => (),
+            _ => return
+        }
+
+        tiers.insert(path.clone(), Tier::Demoting);
+    }
+
+    debug!("Demoting {:?}", path);
+
+    let copied = match hot.load_data(path.clone()) {
+        Err(_) | Ok(None) => false,
+        Ok(Some(ref data)) if data.is_empty() => true, // nothing worth keeping
+        Ok(Some(ref data)) => cold.write_data(path.clone(), data).unwrap_or(false)
+    };
+
+    // Hold the lock until the local copy is gone, so no call for the zone reaches the fs Store
+    // in between
+    let mut tiers = tiers.lock().unwrap();
+
+    match tiers.get(&path).cloned() {
+        Some(Tier::Demoting) => (),
+        Some(_) => return, // used meanwhile, stays hot
+        None => {
+            // deleted meanwhile
+            cold.delete_data(path).ok();
+            return;
+        }
+    }
+
+    if copied && hot.delete_data(path.clone()).unwrap_or(false) {
+        tiers.insert(path, Tier::Cold);
+    }
+    else {
+        error!("Error demoting {:?}, keeping it on local disk", path);
+        tiers.insert(path, Tier::Hot(Instant::now()));
+    }
+}
+
+/// Picks zones to demote under `policy`, least recently used first.
+fn select(tiers: &HashMap<Path, Tier>, policy: TierPolicy, now: Instant) -> Vec<Path> {
+    let mut hot: Vec<(Instant, &Path)> = tiers.iter().filter_map(|(path, tier)| match *tier {
+        Tier::Hot(used) => Some((used, path)),
+        _ => None
+    }).collect();
+
+    hot.sort();
+
+    let count = match policy {
+        TierPolicy::Age(secs) => {
+            hot.iter().take_while(|&&(used, _)| now.duration_since(used) >= Duration::from_secs(secs)).count()
+        },
+        TierPolicy::Lru(zones) => hot.len().saturating_sub(zones)
+    };
+
+    hot.into_iter().take(count).map(|(_, path)| path.clone()).collect()
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("age:3600".parse(), Ok(TierPolicy::Age(3600)));
+    assert_eq!("lru:1000".parse(), Ok(TierPolicy::Lru(1000)));
+
+    assert!("age".parse::<TierPolicy>().is_err());
+    assert!("lru:moo".parse::<TierPolicy>().is_err());
+    assert!("moo:1".parse::<TierPolicy>().is_err());
+}
+
+#[test]
+fn test_select() {
+    let now = Instant::now();
+    let mut tiers = HashMap::new();
+
+    tiers.insert(path![moo], Tier::Hot(now - Duration::from_secs(300)));
+    tiers.insert(path![cow], Tier::Hot(now - Duration::from_secs(200)));
+    tiers.insert(path![pig], Tier::Hot(now));
+    tiers.insert(path![cold], Tier::Cold);
+    tiers.insert(path![busy], Tier::Demoting);
+
+    assert_eq!(select(&tiers, TierPolicy::Age(250), now), [path![moo]]);
+    assert_eq!(select(&tiers, TierPolicy::Age(100), now), [path![moo], path![cow]]);
+    assert!(select(&tiers, TierPolicy::Age(1000), now).is_empty());
+
+    assert_eq!(select(&tiers, TierPolicy::Lru(1), now), [path![moo], path![cow]]);
+    assert!(select(&tiers, TierPolicy::Lru(3), now).is_empty());
+}
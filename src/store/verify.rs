@@ -0,0 +1,124 @@
+//! Verification of stored zone data, like fsck.
+//!
+//! A pass lists the stored zones and has the backend check each one (`StoreCall::Verify`): its data
+//! must pass its checksum, decode and deserialize into `ZoneData` for its own path. Zones that
+//! don't are reported, and with `quarantine` also moved aside (`StoreCall::Quarantine`), so they
+//! start empty when next loaded instead of failing on load. Backends that can't quarantine keep
+//! them.
+//!
+//! Passes run on demand: `store.verify` in the shell, or `verify` on the command line before the
+//! node starts.
+
+use std::fmt;
+
+use super::{StoreError, StoreHandle};
+use path::Path;
+use zone::ZoneData;
+
+/// Outcome of a verification pass.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub verified: usize,              // Zones checked
+    pub bad: Vec<(Path, StoreError)>, // Zones that failed, and why
+    pub quarantined: usize            // Bad zones moved aside
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.bad.is_empty()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(ref path, ref err) in &self.bad {
+            try!(writeln!(f, "Bad zone {:?}: {}", path, err));
+        }
+
+        write!(f, "Verified {} zones, {} bad, {} quarantined", self.verified, self.bad.len(), self.quarantined)
+    }
+}
+
+/// Verifies the zones stored under `prefix`, and `prefix` itself, quarantining bad ones if
+/// `quarantine` is set. Fails if the Store goes away, rather than reporting every zone as bad.
+pub fn verify(store: &StoreHandle, prefix: &Path, quarantine: bool) -> Result<Report, StoreError> {
+    let mut paths = vec![];
+    let mut report = Report::default();
+
+    try!(store.each_zone_under(prefix, |path| paths.push(path)));
+
+    paths.sort();
+
+    for path in paths {
+        report.verified += 1;
+
+        let err = match store.verify(path.clone()) {
+            Err(StoreError::Disconnected) => return Err(StoreError::Disconnected),
+            Err(err) => err,
+            Ok(()) => continue
+        };
+
+        error!("Verify: bad zone {:?}: {}", path, err);
+
+        if quarantine {
+            if try!(store.quarantine(path.clone())) {
+                info!("Verify: quarantined {:?}", path);
+                report.quarantined += 1;
+            }
+            else {
+                error!("Verify: could not quarantine {:?}", path);
+            }
+        }
+
+        report.bad.push((path, err));
+    }
+
+    Ok(report)
+}
+
+/// Checks that `data`, loaded for `path`, is for that zone.
+pub fn check(path: &Path, data: &ZoneData) -> Result<(), StoreError> {
+    if data.path != *path {
+        return Err(StoreError::corrupt(format!("Data stored is for {:?}", data.path)));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_verify() {
+    use std::thread;
+
+    use app::App;
+    use super::{backend, Config, StoreCall, StoreChannel};
+    use super::memory::Memory;
+
+    let app = App::new("127.0.0.1:42".parse().unwrap());
+    let channel = StoreChannel::new();
+    let store = channel.handle();
+    let memory = Memory::new(app.handle(), &Config::default());
+
+    thread::spawn(move|| {
+        backend::serve(Box::new(memory), channel, Default::default());
+    });
+
+    store.write_data(path![moo], &ZoneData::new(path![moo], Default::default())).unwrap();
+    store.write_data(path![moo.cow], &ZoneData::new(path![pig], Default::default())).unwrap();
+
+    let (tx, rx) = ::std::sync::mpsc::channel();
+
+    store.tx.send(StoreCall::WriteData(path![moo.calf], b"moo".to_vec(), tx.into())).unwrap();
+    assert!(rx.recv().unwrap());
+
+    // Garbage and data for another zone are bad, the memory store can't quarantine them
+    let report = verify(&store, &path![moo], true).unwrap();
+
+    assert_eq!(report.verified, 3);
+    assert_eq!(report.bad.iter().map(|&(ref path, _)| path.clone()).collect::<Vec<_>>(), [path![moo.calf], path![moo.cow]]);
+    assert_eq!(report.quarantined, 0);
+
+    let report = verify(&store, &path![pig], false).unwrap();
+
+    assert!(report.is_ok());
+    assert_eq!(report.verified, 0);
+}
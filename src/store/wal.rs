@@ -0,0 +1,373 @@
+//! Write-ahead log for zone persistence.
+//!
+//! Zones append every effective change (a `NodeTree` diff, serialized by `migrate::serialize_diff`)
+//! to the log as soon as it is
+//! merged, instead of waiting for their turn to write. Full zone writes (and deltas, see
+//! `store::delta`) act as checkpoints: once a zone's data is written, its entries up to that point
+//! are obsolete. Entries logged after the last checkpoint are replayed into zone data on load, so
+//! updates survive a crash between `request_write` and the eventual `Write`.
+//!
+//! The log is a sequence of bincode serialized `Entry` records. It is rewritten without obsolete
+//! entries on open, and whenever obsolete entries make up most of the file.
+
+use std;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::io::prelude::*;
+
+use bincode;
+
+use super::migrate;
+use path::Path;
+use zone::ZoneData;
+
+/// Minimum number of obsolete entries before the log is rewritten
+const REWRITE_THRESHOLD: usize = 1000;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum Entry {
+    /// Serialized `NodeTree` diff merged into the zone at `Path`
+    Append(u64, Path, Vec<u8>),
+
+    /// Zone at `Path` has been written, including all entries up to the sequence number
+    Checkpoint(u64, Path)
+}
+
+pub struct Wal {
+    filepath: std::path::PathBuf,
+    file: Option<File>,                            // None when read-only
+
+    seq: u64,                                      // Sequence number of last entry
+    pending: HashMap<Path, Vec<(u64, Vec<u8>)>>, // Entries not yet checkpointed, per zone
+    live: usize,                                   // Number of pending entries
+    obsolete: usize                                // Number of checkpointed entries in file
+}
+
+impl Wal {
+    /// Opens the log at `filepath`, replaying any existing entries. A partially written entry at
+    /// the end of the log (from a crash mid-append) is discarded.
+    pub fn open(filepath: &std::path::Path) -> io::Result<Wal> {
+        Wal::open_with(filepath, false)
+    }
+
+    /// Same as `open`, but leaves the log untouched. Appends and checkpoints fail.
+    pub fn open_read_only(filepath: &std::path::Path) -> io::Result<Wal> {
+        Wal::open_with(filepath, true)
+    }
+
+    fn open_with(filepath: &std::path::Path, read_only: bool) -> io::Result<Wal> {
+        let mut seq = 0;
+        let mut pending = HashMap::new();
+
+        match File::open(filepath) {
+            Err(ref err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => return Err(err),
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+
+                loop {
+                    match bincode::deserialize_from(&mut reader, bincode::Infinite) {
+                        Ok(entry) => apply(&mut pending, &mut seq, entry),
+                        Err(err) => {
+                            // End of log, or a torn entry
+                            debug!("WAL replay stopped: {}", err);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let file = if read_only { None } else { Some(try!(write_log(filepath, &pending))) };
+        let live = pending.values().map(|entries| entries.len()).sum();
+
+        Ok(Wal {
+            filepath: filepath.to_path_buf(),
+            file: file,
+            seq: seq,
+            pending: pending,
+            live: live,
+            obsolete: 0
+        })
+    }
+
+    /// Sequence number of the last appended entry. A zone write started now covers all entries up
+    /// to this number.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Appends a serialized diff merged into the zone at `path`.
+    pub fn append(&mut self, path: &Path, diff: Vec<u8>) -> io::Result<()> {
+        let entry = Entry::Append(self.seq + 1, path.clone(), diff);
+
+        try!(self.write(&entry));
+
+        apply(&mut self.pending, &mut self.seq, entry);
+        self.live += 1;
+
+        Ok(())
+    }
+
+    /// Flushes appended entries to disk. Nothing to flush for a read-only log.
+    pub fn sync(&self) -> io::Result<()> {
+        match self.file {
+            None => Ok(()),
+            Some(ref file) => file.sync_all()
+        }
+    }
+
+    /// Marks entries for `path` up to `seq` as persisted by a full zone write.
+    pub fn checkpoint(&mut self, path: &Path, seq: u64) -> io::Result<()> {
+        self.checkpoint_batch(&[path.clone()], seq)
+    }
+
+    /// Same as `checkpoint` for several zones written together, with a single write to the log.
+    pub fn checkpoint_batch(&mut self, paths: &[Path], seq: u64) -> io::Result<()> {
+        let limit = bincode::Infinite;
+        let mut serialized = vec![];
+        let mut removed = 0;
+
+        for path in paths {
+            let count = remove_upto(&mut self.pending, path, seq);
+
+            if count > 0 {
+                try!(bincode::serialize_into(&mut serialized, &Entry::Checkpoint(seq, path.clone()), limit)
+                    .map_err(|err| io::Error::new(ErrorKind::Other, err)));
+
+                removed += count;
+            }
+        }
+
+        if removed == 0 {
+            return Ok(());
+        }
+
+        try!(try!(self.file()).write_all(&serialized));
+
+        self.live -= removed;
+        self.obsolete += removed;
+
+        if self.obsolete > REWRITE_THRESHOLD && self.obsolete > self.live {
+            self.file = Some(try!(write_log(&self.filepath, &self.pending)));
+            self.obsolete = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Size of the log file in bytes.
+    pub fn size(&self) -> u64 {
+        self.file.as_ref().and_then(|file| file.metadata().ok()).map_or(0, |meta| meta.len())
+    }
+
+    /// Returns serialized diffs for `path` that have not been checkpointed, oldest first.
+    pub fn entries(&self, path: &Path) -> Vec<Vec<u8>> {
+        match self.pending.get(path) {
+            None => vec![],
+            Some(entries) => entries.iter().map(|&(_, ref diff)| diff.clone()).collect()
+        }
+    }
+
+    /// Zones with entries that have not been checkpointed.
+    pub fn paths(&self) -> Vec<Path> {
+        self.pending.keys().cloned().collect()
+    }
+
+    /// Size in bytes of the diffs for `path` that have not been checkpointed.
+    pub fn pending_bytes(&self, path: &Path) -> u64 {
+        self.pending.get(path).map_or(0, |entries| entries.iter().map(|&(_, ref diff)| diff.len() as u64).sum())
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        let limit = bincode::Infinite;
+        let serialized = try!(bincode::serialize(entry, limit)
+            .map_err(|err| io::Error::new(ErrorKind::Other, err)));
+
+        // Single write, so a crash can only tear the last entry
+        try!(self.file()).write_all(&serialized)
+    }
+
+    fn file(&mut self) -> io::Result<&mut File> {
+        self.file.as_mut().ok_or_else(|| io::Error::new(ErrorKind::PermissionDenied, "WAL is read-only"))
+    }
+}
+
+/// Replays serialized diffs returned by `Wal::entries` into loaded zone data.
+pub fn replay(data: &mut ZoneData, entries: Vec<Vec<u8>>) {
+    for entry in entries {
+        match migrate::deserialize_diff(&entry) {
+            Err(err) => error!("Bad WAL entry for {:?}: {}", data.path, err),
+            Ok(mut diff) => {
+                // Delegated data is replayed by the delegated zone itself
+                data.tree.merge(&mut diff);
+            }
+        }
+    }
+}
+
+fn apply(pending: &mut HashMap<Path, Vec<(u64, Vec<u8>)>>, seq: &mut u64, entry: Entry) {
+    match entry {
+        Entry::Append(s, path, diff) => {
+            *seq = s;
+            pending.entry(path).or_insert_with(Vec::new).push((s, diff));
+        },
+        Entry::Checkpoint(s, path) => {
+            remove_upto(pending, &path, s);
+        }
+    }
+}
+
+fn remove_upto(pending: &mut HashMap<Path, Vec<(u64, Vec<u8>)>>, path: &Path, seq: u64) -> usize {
+    let (removed, empty) = match pending.get_mut(path) {
+        None => return 0,
+        Some(entries) => {
+            let len = entries.len();
+
+            entries.retain(|&(s, _)| s > seq);
+
+            (len - entries.len(), entries.is_empty())
+        }
+    };
+
+    if empty {
+        pending.remove(path);
+    }
+
+    removed
+}
+
+/// Atomically replaces the log at `filepath` with `pending` entries, and reopens it for appending.
+fn write_log(filepath: &std::path::Path, pending: &HashMap<Path, Vec<(u64, Vec<u8>)>>) -> io::Result<File> {
+    let tmp_path = filepath.with_extension("tmp");
+
+    {
+        let mut file = try!(File::create(&tmp_path));
+        let mut entries: Vec<_> = pending.iter()
+            .flat_map(|(path, entries)| entries.iter().map(move |&(s, ref diff)| (s, path, diff)))
+            .collect();
+
+        entries.sort_by_key(|&(s, _, _)| s);
+
+        for (s, path, diff) in entries {
+            let entry = Entry::Append(s, path.clone(), diff.clone());
+
+            try!(bincode::serialize_into(&mut file, &entry, bincode::Infinite)
+                .map_err(|err| io::Error::new(ErrorKind::Other, err)));
+        }
+
+        try!(file.sync_all());
+    }
+
+    try!(std::fs::rename(&tmp_path, filepath));
+
+    OpenOptions::new().append(true).open(filepath)
+}
+
+#[test]
+fn test_append_checkpoint() {
+    use std::fs::DirBuilder;
+
+    let dir = std::path::PathBuf::from("test_data/wal");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_append_checkpoint.log");
+
+    std::fs::remove_file(&file).ok();
+
+    let moo = path![moo];
+    let cow = path![cow];
+
+    {
+        let mut wal = Wal::open(&file).unwrap();
+
+        wal.append(&moo, vec![1]).unwrap();
+
+        let seq = wal.seq();
+
+        wal.append(&cow, vec![2]).unwrap();
+        wal.append(&moo, vec![3]).unwrap();
+        wal.checkpoint(&moo, seq).unwrap();
+
+        assert_eq!(wal.entries(&moo), [vec![3]]);
+        assert_eq!(wal.entries(&cow), [vec![2]]);
+    }
+
+    // Entries survive reopening
+    let mut wal = Wal::open(&file).unwrap();
+
+    assert_eq!(wal.seq(), 3);
+    assert_eq!(wal.entries(&moo), [vec![3]]);
+    assert_eq!(wal.entries(&cow), [vec![2]]);
+
+    wal.checkpoint(&cow, 3).unwrap();
+
+    assert!(wal.entries(&cow).is_empty());
+}
+
+#[test]
+fn test_checkpoint_batch() {
+    use std::fs::DirBuilder;
+
+    let dir = std::path::PathBuf::from("test_data/wal");
+
+    if ! dir.is_dir() {
+        DirBuilder::new().recursive(true).create(&dir).unwrap();
+    }
+
+    let mut file = dir.clone();
+
+    file.push("test_checkpoint_batch.log");
+
+    std::fs::remove_file(&file).ok();
+
+    let moo = path![moo];
+    let cow = path![cow];
+
+    {
+        let mut wal = Wal::open(&file).unwrap();
+
+        wal.append(&moo, vec![1]).unwrap();
+        wal.append(&cow, vec![2]).unwrap();
+
+        let seq = wal.seq();
+
+        wal.append(&moo, vec![3]).unwrap();
+        wal.checkpoint_batch(&[moo.clone(), cow.clone()], seq).unwrap();
+
+        assert_eq!(wal.entries(&moo), [vec![3]]);
+        assert!(wal.entries(&cow).is_empty());
+    }
+
+    let wal = Wal::open(&file).unwrap();
+
+    assert_eq!(wal.entries(&moo), [vec![3]]);
+    assert!(wal.entries(&cow).is_empty());
+}
+
+#[test]
+fn test_replay() {
+    use node::{Node, NodeTree, Vis};
+    use serde_json::Value as JSON;
+
+    let diff = Node::expand_from(&["moo".to_string()], JSON::String(String::from("cow")), 1000);
+    let serialized = migrate::serialize_diff(&diff.noop_vis()).unwrap();
+
+    let mut data = ZoneData::new(Path::empty(), NodeTree {
+        node: Default::default(),
+        vis: Vis::permanent()
+    });
+
+    replay(&mut data, vec![serialized]);
+
+    let expected = Node::expand_from(&["moo".to_string()], JSON::String(String::from("cow")), 1000);
+
+    assert_eq!(data.tree.node, expected);
+}
@@ -0,0 +1,219 @@
+//! Worker threads that keep calls for each zone in order.
+//!
+//! Backends do their I/O on worker threads, so the Store thread is free to take the next call. A
+//! plain thread pool runs calls for the same zone concurrently, in whatever order threads pick
+//! them up: a delete could overtake the write before it, or a load a write it was queued after.
+//! `Workers` runs every job for a zone on the same thread, its lane, picked by hashing its path. Jobs
+//! for one zone run one at a time in the order they were queued, while jobs for different zones run
+//! concurrently on up to `threads` threads.
+//!
+//! A slow job holds up the other zones hashed to its thread too. `STORE_WORKERS` sets the number of
+//! threads, which backends otherwise pick themselves.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+
+use path::Path;
+
+/// Number of worker threads a backend runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolSize {
+    pub threads: usize // 0 for the backend's default
+}
+
+/// Worker threads, each running the jobs of the zones hashed to it in order.
+pub struct Workers {
+    lanes: Vec<Sender<Job>>,
+    queued: Arc<AtomicUsize>,
+    active: Arc<AtomicUsize>
+}
+
+type Job = Box<FnOnce() + Send>;
+
+impl PoolSize {
+    /// Threads to run, `default` unless configured.
+    pub fn threads_or(&self, default: usize) -> usize {
+        match self.threads {
+            0 => default,
+            threads => threads
+        }
+    }
+}
+
+impl FromStr for PoolSize {
+    type Err = String;
+
+    /// Parses `<threads>`.
+    fn from_str(s: &str) -> Result<PoolSize, String> {
+        match s.parse() {
+            Ok(0) | Err(_) => Err(format!("Bad worker thread count: {}", s)),
+            Ok(threads) => Ok(PoolSize { threads: threads })
+        }
+    }
+}
+
+impl fmt::Display for PoolSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.threads {
+            0 => write!(f, "backend default"),
+            threads => write!(f, "{} threads", threads)
+        }
+    }
+}
+
+impl Workers {
+    pub fn new(threads: usize) -> Workers {
+        assert!(threads > 0, "No worker threads");
+
+        let queued = Arc::new(AtomicUsize::new(0));
+        let active = Arc::new(AtomicUsize::new(0));
+
+        let lanes = (0..threads).map(|_| {
+            let (tx, rx) = channel::<Job>();
+            let queued = queued.clone();
+            let active = active.clone();
+
+            thread::spawn(move|| {
+                for job in rx.iter() {
+                    queued.fetch_sub(1, Ordering::SeqCst);
+                    active.fetch_add(1, Ordering::SeqCst);
+
+                    // Keep the thread, and the zones hashed to it, going after a panic
+                    if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                        error!("Store worker job panicked");
+                    }
+
+                    active.fetch_sub(1, Ordering::SeqCst);
+                }
+            });
+
+            tx
+        }).collect();
+
+        Workers {
+            lanes: lanes,
+            queued: queued,
+            active: active
+        }
+    }
+
+    /// Thread running the jobs for `path`.
+    pub fn lane(&self, path: &Path) -> usize {
+        let mut hasher = DefaultHasher::new();
+
+        path.hash(&mut hasher);
+
+        (hasher.finish() % self.lanes.len() as u64) as usize
+    }
+
+    /// Runs `job` on thread `lane`, once the jobs queued there before it are done.
+    pub fn execute<F>(&self, lane: usize, job: F) where F: FnOnce() + Send + 'static {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.lanes[lane].send(Box::new(job)).expect("Store worker thread gone");
+    }
+
+    /// Waits for every job queued so far to finish.
+    pub fn drain(&self) {
+        let (tx, rx) = channel();
+
+        for lane in 0..self.lanes.len() {
+            let tx = tx.clone();
+
+            self.execute(lane, move|| {
+                tx.send(()).is_ok();
+            });
+        }
+
+        drop(tx);
+
+        for _ in rx.iter() {
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.lanes.len()
+    }
+
+    /// Jobs running now.
+    pub fn active_count(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Jobs waiting for their thread.
+    pub fn queued_count(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn test_parse() {
+    assert_eq!("4".parse(), Ok(PoolSize { threads: 4 }));
+    assert_eq!(PoolSize::default().threads_or(8), 8);
+    assert_eq!(PoolSize { threads: 4 }.threads_or(8), 4);
+
+    assert!("0".parse::<PoolSize>().is_err());
+    assert!("moo".parse::<PoolSize>().is_err());
+}
+
+#[test]
+fn test_ordering() {
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    let workers = Workers::new(4);
+    let done = Arc::new(Mutex::new(vec![]));
+    let (tx, rx) = channel();
+    let lane = workers.lane(&path![moo]);
+
+    // Jobs for moo run in order, even when the first one is slow
+    for i in 0..3 {
+        let done = done.clone();
+        let tx = tx.clone();
+
+        workers.execute(lane, move|| {
+            if i == 0 {
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            done.lock().unwrap().push(i);
+            tx.send(()).unwrap();
+        });
+    }
+
+    // A panic doesn't hold up jobs queued after it
+    workers.execute(lane, || panic!("moo"));
+
+    let done_moo = done.clone();
+
+    workers.execute(lane, move|| {
+        done_moo.lock().unwrap().push(3);
+        tx.send(()).unwrap();
+    });
+
+    for _ in 0..4 {
+        rx.recv().unwrap();
+    }
+
+    assert_eq!(*done.lock().unwrap(), vec![0, 1, 2, 3]);
+    assert_eq!(workers.queued_count(), 0);
+
+    // Draining waits for slow jobs on any thread
+    let done_slow = done.clone();
+
+    workers.execute(workers.lane(&path![cow]), move|| {
+        thread::sleep(Duration::from_millis(50));
+        done_slow.lock().unwrap().push(4);
+    });
+
+    workers.drain();
+
+    assert_eq!(done.lock().unwrap().len(), 5);
+    assert_eq!(workers.threads(), 4);
+}
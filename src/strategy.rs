@@ -0,0 +1,226 @@
+//! Resolution of values written to the same path from different sources.
+//!
+//! By default the later write wins, by timestamp (see `clock`), wherever it came from: clients,
+//! replicas or imports. A `MergeStrategy` can pick the other value instead, for the paths it is set
+//! for. With `ZONE_MERGE`, strategies are set for patterns of dotted paths, where `*` matches any
+//! key, and cover everything below them, the longest matching pattern winning:
+//!
+//! ```text
+//! ZONE_MERGE=counters.*:max,config:lww
+//! ```
+//!
+//! `lww` is last-writer-wins and `max` keeps the greatest value (see `MaxValue`). Other strategies,
+//! such as closures, are set on `App::strategies` before the app starts.
+//!
+//! Strategies only choose between two values neither of which was killed. When one picks the value
+//! that lost by timestamp, the value is merged as a write just after both, so stores replaying
+//! logs and replicas with other strategies resolve it the same way by timestamp alone. Strategies
+//! should pick the same value whichever of the two arrived first.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use matcher::{PathMatcher, Prefix};
+use path::Path;
+use value::Value;
+
+/// Chooses between two values written to a path.
+pub trait MergeStrategy: Send + Sync {
+    /// Whether `incoming` should replace `current`, at `path`, each with its version.
+    fn prefers_incoming(&self, path: &Path, current: (&Value, u64), incoming: (&Value, u64)) -> bool;
+}
+
+/// The later write wins.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LastWriterWins;
+
+/// The greatest value wins, the later write for equal ones. Numbers are less than strings, which
+/// are compared by bytes, and `null` and booleans less than numbers. Lists are greater than any of
+/// them, and CRDT values join instead (see `crdt`).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MaxValue;
+
+/// Strategies by path pattern.
+#[derive(Clone, Default)]
+pub struct Strategies {
+    rules: Vec<(Prefix, Arc<MergeStrategy>)>
+}
+
+/// Strategies for the data of a zone, at `prefix`.
+#[derive(Clone, Copy)]
+pub struct Resolver<'a> {
+    strategies: &'a Strategies,
+    prefix: &'a Path
+}
+
+impl MergeStrategy for LastWriterWins {
+    fn prefers_incoming(&self, _: &Path, current: (&Value, u64), incoming: (&Value, u64)) -> bool {
+        incoming.1 > current.1
+    }
+}
+
+impl MergeStrategy for MaxValue {
+    fn prefers_incoming(&self, _: &Path, current: (&Value, u64), incoming: (&Value, u64)) -> bool {
+        match compare(incoming.0, current.0) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => incoming.1 > current.1
+        }
+    }
+}
+
+impl<F> MergeStrategy for F where F: Fn(&Path, (&Value, u64), (&Value, u64)) -> bool + Send + Sync {
+    fn prefers_incoming(&self, path: &Path, current: (&Value, u64), incoming: (&Value, u64)) -> bool {
+        self(path, current, incoming)
+    }
+}
+
+impl Strategies {
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Sets `strategy` for the paths matching `pattern` and below, in place of any set for it.
+    pub fn set(&mut self, pattern: Path, strategy: Arc<MergeStrategy>) {
+        self.rules.retain(|&(ref set, _)| set.0 != pattern);
+        self.rules.push((Prefix(pattern), strategy));
+    }
+
+    /// Strategies for the data of the zone at `prefix`.
+    pub fn at<'a>(&'a self, prefix: &'a Path) -> Resolver<'a> {
+        Resolver { strategies: self, prefix: prefix }
+    }
+
+    /// Strategy for `path`, from the longest pattern matching it, if any.
+    fn find(&self, path: &Path) -> Option<&Arc<MergeStrategy>> {
+        self.rules.iter()
+            .filter(|&&(ref pattern, _)| pattern.matches(path))
+            .max_by_key(|&&(ref pattern, _)| pattern.0.len())
+            .map(|&(_, ref strategy)| strategy)
+    }
+}
+
+impl FromStr for Strategies {
+    type Err = String;
+
+    /// Parses comma separated `<path>:<strategy>` pairs, where strategies are `lww` or `max`.
+    fn from_str(s: &str) -> Result<Strategies, String> {
+        let mut strategies = Strategies::default();
+
+        for rule in s.split(',').filter(|rule| ! rule.is_empty()) {
+            let mut parts = rule.rsplitn(2, ':');
+
+            let strategy: Arc<MergeStrategy> = match parts.next() {
+                Some("lww") => Arc::new(LastWriterWins),
+                Some("max") => Arc::new(MaxValue),
+                _ => return Err(format!("Bad merge strategy: {}", rule))
+            };
+
+            let pattern = match parts.next() {
+                Some(pattern) => Path::new(pattern.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect()),
+                None => return Err(format!("Bad merge strategy, no path: {}", rule))
+            };
+
+            strategies.set(pattern, strategy);
+        }
+
+        Ok(strategies)
+    }
+}
+
+impl fmt::Debug for Strategies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let patterns: Vec<&Path> = self.rules.iter().map(|&(ref pattern, _)| &pattern.0).collect();
+
+        write!(f, "Strategies {:?}", patterns)
+    }
+}
+
+impl<'a> Resolver<'a> {
+    /// Whether `incoming` should replace `current` at `path`, relative to the zone, by the
+    /// strategy set for it. `None` if there is none, for last-writer-wins.
+    pub fn prefers_incoming(&self, path: &Path, current: (&Value, u64), incoming: (&Value, u64)) -> Option<bool> {
+        if self.strategies.is_empty() {
+            return None;
+        }
+
+        let mut absolute = self.prefix.clone();
+
+        absolute.append(&mut path.clone());
+
+        self.strategies.find(&absolute).map(|strategy| strategy.prefers_incoming(&absolute, current, incoming))
+    }
+}
+
+/// Orders values for `MaxValue`.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match *value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::I64(_) | Value::U64(_) | Value::F64(_) => 2,
+            Value::String(_) => 3,
+            Value::Crdt(_) => 4,
+            Value::List(_) => 5
+        }
+    }
+
+    fn number(value: &Value) -> f64 {
+        match *value {
+            Value::I64(v) => v as f64,
+            Value::U64(v) => v as f64,
+            Value::F64(v) => v,
+            _ => 0.0
+        }
+    }
+
+    match (a, b) {
+        (&Value::Bool(a), &Value::Bool(b)) => a.cmp(&b),
+        (&Value::String(ref a), &Value::String(ref b)) => a.cmp(b),
+        _ if rank(a) == 2 && rank(b) == 2 => number(a).partial_cmp(&number(b)).unwrap_or(Ordering::Equal),
+        _ => rank(a).cmp(&rank(b))
+    }
+}
+
+#[test]
+fn test_strategies() {
+    let strategies: Strategies = "counters.*:max,counters.hits.total:lww".parse().unwrap();
+    let prefix = path![counters];
+    let resolver = strategies.at(&prefix);
+
+    let one = Value::F64(1.0);
+    let two = Value::F64(2.0);
+
+    // Greatest value, even if written earlier
+    assert_eq!(resolver.prefers_incoming(&path![hits], (&one, 2000), (&two, 1000)), Some(true));
+    assert_eq!(resolver.prefers_incoming(&path![hits.today], (&two, 1000), (&one, 2000)), Some(false));
+    assert_eq!(resolver.prefers_incoming(&path![hits], (&one, 1000), (&one, 2000)), Some(true));
+
+    // Longest pattern wins
+    assert_eq!(resolver.prefers_incoming(&path![hits.total], (&two, 1000), (&one, 2000)), Some(true));
+
+    // None set
+    assert_eq!(resolver.prefers_incoming(&Path::empty(), (&one, 1000), (&two, 2000)), None);
+    assert_eq!(Strategies::default().at(&prefix).prefers_incoming(&path![hits], (&one, 1000), (&two, 2000)), None);
+
+    // Callbacks
+    let mut strategies = Strategies::default();
+
+    strategies.set(path![moo], Arc::new(|_: &Path, _: (&Value, u64), incoming: (&Value, u64)| incoming.0 == &Value::from("cow".to_string())));
+
+    let root = Path::empty();
+    let cow = Value::from("cow".to_string());
+
+    assert_eq!(strategies.at(&root).prefers_incoming(&path![moo], (&one, 2000), (&cow, 1000)), Some(true));
+    assert_eq!(strategies.at(&root).prefers_incoming(&path![moo], (&cow, 1000), (&one, 2000)), Some(false));
+
+    assert!("moo:moo".parse::<Strategies>().is_err());
+    assert!("max".parse::<Strategies>().is_err());
+    assert_eq!(compare(&Value::String("a".to_string().into_boxed_str()), &Value::F64(9.0)), Ordering::Greater);
+}
@@ -0,0 +1,209 @@
+//! Listeners of a zone, by path.
+//!
+//! A `Zone` keeps its listeners in a trie keyed by the keys of their paths, so an update is only
+//! matched against the listeners along the paths it changed, following the keys of the update
+//! down the trie, however many other listeners the zone has. Listeners at `*` keys are on a branch
+//! of their own, followed for every key of the update, those at regular expression keys (see
+//! `matcher`) on one for each, followed for the keys it matches, and listeners with recursive
+//! wildcards (`**` and `*#`) stop there, matching whatever changed below. Listeners found this way are
+//! notified as before (see `Listener::update`), so only those whose path matches hear of it. Keys
+//! are matched here rather than through a `PathMatcher`, see `matcher`.
+
+use std::collections::HashMap;
+use std::mem;
+
+use listener::Listener;
+use matcher::{self, RegexKey};
+use node::Update;
+
+/// Listeners by path.
+#[derive(Default)]
+pub struct Subscriptions {
+    root: Trie
+}
+
+#[derive(Default)]
+struct Trie {
+    here: Vec<Listener>,          // Paths ending here, or with a recursive wildcard here
+    keys: HashMap<String, Trie>,
+    any: Option<Box<Trie>>,       // `*`
+    regexes: Vec<(RegexKey, Trie)>, // Regular expression keys, compiled
+    len: usize                    // Listeners here and below
+}
+
+impl Subscriptions {
+    pub fn is_empty(&self) -> bool {
+        self.root.len == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.root.len
+    }
+
+    pub fn insert(&mut self, listener: Listener) {
+        let mut trie = &mut self.root;
+
+        trie.len += 1;
+
+        for key in listener.path.iter() {
+            trie = match key.as_str() {
+                "**" | "*#" => break,
+                "*" => &mut **trie.any.get_or_insert_with(Default::default),
+                key if matcher::is_regex_key(key) => {
+                    let regex = match listener.regexes.get(key) {
+                        Some(regex) => regex,
+                        None => break // Matching nothing, see `Listener::update`
+                    };
+
+                    let regexes = &mut trie.regexes;
+                    let pos = match regexes.iter().position(|&(ref r, _)| r.key() == key) {
+                        Some(pos) => pos,
+                        None => {
+                            regexes.push((regex.clone(), Default::default()));
+                            regexes.len() - 1
+                        }
+                    };
+
+                    &mut regexes[pos].1
+                },
+                key => trie.keys.entry(key.to_string()).or_insert_with(Default::default)
+            };
+
+            trie.len += 1;
+        }
+
+        trie.here.push(listener);
+    }
+
+    /// Notifies the listeners `update` could match, dropping those gone.
+    pub fn notify(&mut self, update: &Update) {
+        self.root.notify(update, update);
+    }
+
+    /// Keeps the listeners `f` returns true for, all of them visited.
+    pub fn retain<F>(&mut self, mut f: F) where F: FnMut(&Listener) -> bool {
+        for listener in self.drain() {
+            if f(&listener) {
+                self.insert(listener);
+            }
+        }
+    }
+
+    /// Takes all listeners out.
+    pub fn drain(&mut self) -> Vec<Listener> {
+        let mut listeners = Vec::with_capacity(self.root.len);
+
+        mem::replace(&mut self.root, Default::default()).drain_into(&mut listeners);
+        listeners
+    }
+}
+
+impl Trie {
+    /// Notifies the listeners here of `root`, the whole update, and those below of it too where
+    /// `update`, the part of it here, reaches. Returns how many were dropped, and drops branches
+    /// left without listeners.
+    fn notify(&mut self, root: &Update, update: &Update) -> usize {
+        let len = self.here.len();
+
+        self.here.retain(|listener| listener.update(root).is_ok());
+
+        let mut dropped = len - self.here.len();
+        let (keys, any, regexes) = (&mut self.keys, &mut self.any, &mut self.regexes);
+
+        update.each_child(|k, child| {
+            if let Some(trie) = keys.get_mut(k) {
+                dropped += trie.notify(root, child);
+            }
+
+            if let Some(ref mut trie) = *any {
+                dropped += trie.notify(root, child);
+            }
+
+            for &mut (ref regex, ref mut trie) in regexes.iter_mut() {
+                if regex.matches(k) {
+                    dropped += trie.notify(root, child);
+                }
+            }
+        });
+
+        if dropped > 0 {
+            self.keys.retain(|_, trie| trie.len > 0);
+            self.regexes.retain(|&(_, ref trie)| trie.len > 0);
+
+            if self.any.as_ref().map_or(false, |any| any.len == 0) {
+                self.any = None;
+            }
+        }
+
+        self.len -= dropped;
+        dropped
+    }
+
+    fn drain_into(self, listeners: &mut Vec<Listener>) {
+        listeners.extend(self.here);
+
+        for (_, trie) in self.keys {
+            trie.drain_into(listeners);
+        }
+
+        if let Some(any) = self.any {
+            any.drain_into(listeners);
+        }
+
+        for (_, trie) in self.regexes {
+            trie.drain_into(listeners);
+        }
+    }
+}
+
+#[test]
+fn test_subscriptions() {
+    use std::sync::Arc;
+
+    use mioco::sync::mpsc::channel;
+    use serde_json::Value as JSON;
+
+    use node::{Node, NodeTree, Vis};
+    use path::Path;
+
+    let path = |path: &str| Path::new(path.split('.').filter(|key| ! key.is_empty()).map(|key| key.to_string()).collect());
+
+    let mut subs = Subscriptions::default();
+    let mut rxs = vec![];
+
+    for pattern in &["users.moo.status", "users.*.status", "users.**", "posts.*", "users.cow"] {
+        let (tx, rx) = channel();
+
+        subs.insert(Listener::new(Arc::new(Path::empty()), Arc::new(path(pattern)), Default::default(), None, tx));
+        rxs.push(rx);
+    }
+
+    let (tx, _) = channel();
+
+    subs.insert(Listener::new(Arc::new(Path::empty()), Arc::new(path("users.moo.status")), Default::default(), None, tx));
+    assert_eq!(subs.len(), 6);
+
+    let object = |key: &str, value: JSON| JSON::Object(vec![(key.to_string(), value)].into_iter().collect());
+
+    let mut tree = NodeTree {
+        node: Node::expand(object("users", object("moo", object("status", JSON::from(1)))), 1000),
+        vis: Vis::permanent()
+    };
+
+    let status = path("users.moo.status");
+    let (update, _) = tree.merge(&mut Node::expand_from(&status.path, JSON::from(2), 2000).noop_vis());
+
+    subs.notify(&update.unwrap());
+
+    let notified: Vec<bool> = rxs.iter().map(|rx| rx.try_recv().is_ok()).collect();
+
+    assert_eq!(notified, vec![true, true, true, false, false]);
+
+    // The listener gone is dropped
+    assert_eq!(subs.len(), 5);
+
+    subs.retain(|listener| listener.path.len() > 2);
+    assert_eq!(subs.len(), 2);
+    assert_eq!(subs.drain().len(), 2);
+    assert!(subs.is_empty());
+}
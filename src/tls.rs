@@ -0,0 +1,398 @@
+//! TLS on the listeners of clients.
+//!
+//! With `API_TLS_CERT` and `API_TLS_KEY` naming PEM files of a certificate chain and its private
+//! key, clients connect over TLS to the API, WebSocket (`wss://`) and HTTP (`https://`) ports, and
+//! with `API_TLS_CLIENT_CA` naming a PEM file of certificate authorities too, only clients with a
+//! certificate signed by one of them can. Peers, the monitor and gRPC are not covered.
+//!
+//! A `Stream` is cloned to read and write it apart, like a `TcpStream`: reads wait on the socket
+//! without holding the TLS session, so replies can be written while clients are read.
+//!
+//! Needs the `tls` cargo feature.
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+#[cfg(feature = "tls")]
+use std::fs::File;
+#[cfg(feature = "tls")]
+use std::io::BufReader;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+#[cfg(feature = "tls")]
+use mioco::sync::Mutex;
+use mioco::tcp::TcpStream;
+#[cfg(feature = "tls")]
+use rustls::{Certificate, PrivateKey, RootCertStore};
+#[cfg(feature = "tls")]
+use rustls_pemfile;
+
+/// Bytes read off the socket at a time
+#[cfg(feature = "tls")]
+const READ_BYTES: usize = 16 << 10;
+
+/// Certificate and key served, and the authorities client certificates are checked against.
+#[derive(Clone)]
+pub struct Config {
+    cert: String,
+    client_ca: Option<String>,
+    #[cfg(feature = "tls")]
+    server: Arc<rustls::ServerConfig>
+}
+
+/// A client connection, over TLS or not.
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TlsStream)
+}
+
+/// A TLS connection, its socket and the session shared by its clones.
+#[cfg(feature = "tls")]
+pub struct TlsStream {
+    tcp: TcpStream,
+    session: Arc<Mutex<Session>>
+}
+
+#[cfg(feature = "tls")]
+struct Session {
+    conn: rustls::ServerConnection,
+    incoming: Vec<u8> // Read off the socket, not taken by `conn` yet
+}
+
+impl Config {
+    /// Loads the PEM files named.
+    #[cfg(feature = "tls")]
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Config, String> {
+        Ok(Config {
+            cert: cert.to_string(),
+            client_ca: client_ca.map(|ca| ca.to_string()),
+            server: Arc::new(try!(server_config(cert, key, client_ca)))
+        })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    pub fn new(_: &str, _: &str, _: Option<&str>) -> Result<Config, String> {
+        Err("TLS not compiled in".into())
+    }
+
+    /// Reads files named by `API_TLS_CERT`, `API_TLS_KEY` and `API_TLS_CLIENT_CA`, if set.
+    pub fn from_env() -> Result<Option<Config>, String> {
+        let client_ca = env::var("API_TLS_CLIENT_CA").ok();
+
+        match (env::var("API_TLS_CERT"), env::var("API_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => Config::new(&cert, &key, client_ca.as_ref().map(|ca| ca.as_str())).map(Some),
+            (Err(_), Err(_)) if client_ca.is_none() => Ok(None),
+            _ => Err("API_TLS_CERT and API_TLS_KEY are both needed for TLS".into())
+        }
+    }
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.cert));
+
+        match self.client_ca {
+            Some(ref ca) => write!(f, ", clients verified by {}", ca),
+            None => Ok(())
+        }
+    }
+}
+
+impl Stream {
+    /// A client connecting on `tcp`, over TLS if configured. The TLS handshake is made as the
+    /// stream is first read.
+    pub fn accept(tcp: TcpStream, tls: Option<&Config>) -> io::Result<Stream> {
+        match tls {
+            None => Ok(Stream::Plain(tcp)),
+            Some(config) => accept_tls(tcp, config)
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match *self {
+            Stream::Plain(ref tcp) => tcp.try_clone().map(Stream::Plain),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref tls) => Ok(Stream::Tls(TlsStream {
+                tcp: try!(tls.tcp.try_clone()),
+                session: tls.session.clone()
+            }))
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut tcp) => tcp.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut tls) => tls.read(buf)
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Plain(ref mut tcp) => tcp.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut tls) => tls.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Plain(ref mut tcp) => tcp.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut tls) => tls.flush()
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let mut session = self.session.lock().unwrap();
+                let Session { ref mut conn, ref mut incoming } = *session;
+
+                match conn.reader().read(buf) {
+                    Ok(n) => return Ok(n), // 0 once the client closed
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(e) => return Err(e)
+                }
+
+                // Taken only once what was decrypted has been read, so it doesn't pile up
+                if ! incoming.is_empty() {
+                    let n = try!(conn.read_tls(&mut &incoming[..]));
+
+                    incoming.drain(..n);
+
+                    if let Err(err) = conn.process_new_packets() {
+                        // Alerting the client why
+                        let _ = write_tls(conn, &mut self.tcp);
+
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                    }
+
+                    // Handshake messages, and replies held back until the handshake was made
+                    try!(write_tls(conn, &mut self.tcp));
+                    continue;
+                }
+            }
+
+            let mut raw = [0; READ_BYTES];
+            let n = try!(self.tcp.read(&mut raw));
+
+            if n == 0 {
+                return Ok(0);
+            }
+
+            self.session.lock().unwrap().incoming.extend_from_slice(&raw[..n]);
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = try!(session.conn.writer().write(buf));
+
+        try!(write_tls(&mut session.conn, &mut self.tcp));
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session.lock().unwrap();
+
+        try!(session.conn.writer().flush());
+        try!(write_tls(&mut session.conn, &mut self.tcp));
+        self.tcp.flush()
+    }
+}
+
+/// Writes what `conn` has to send.
+#[cfg(feature = "tls")]
+fn write_tls(conn: &mut rustls::ServerConnection, tcp: &mut TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        try!(conn.write_tls(tcp));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn accept_tls(tcp: TcpStream, config: &Config) -> io::Result<Stream> {
+    let conn = try!(rustls::ServerConnection::new(config.server.clone()).map_err(|err| io::Error::new(io::ErrorKind::Other, err)));
+
+    Ok(Stream::Tls(TlsStream {
+        tcp: tcp,
+        session: Arc::new(Mutex::new(Session { conn: conn, incoming: vec![] }))
+    }))
+}
+
+#[cfg(not(feature = "tls"))]
+fn accept_tls(_: TcpStream, _: &Config) -> io::Result<Stream> {
+    Err(io::Error::new(io::ErrorKind::Other, "TLS not compiled in"))
+}
+
+#[cfg(feature = "tls")]
+fn server_config(cert: &str, key: &str, client_ca: Option<&str>) -> Result<rustls::ServerConfig, String> {
+    let certs = try!(read_certs(cert));
+    let key = try!(read_key(key));
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+
+            for ca in try!(read_certs(client_ca)) {
+                try!(roots.add(&ca).map_err(|err| format!("Bad certificate in {}: {}", client_ca, err)));
+            }
+
+            builder.with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots).boxed())
+        },
+        None => builder.with_no_client_auth()
+    };
+
+    builder.with_single_cert(certs, key).map_err(|err| format!("Bad certificate or key: {}", err))
+}
+
+#[cfg(feature = "tls")]
+fn read_certs(filename: &str) -> Result<Vec<Certificate>, String> {
+    let file = try!(File::open(filename).map_err(|err| format!("Could not read {}: {}", filename, err)));
+    let certs = try!(rustls_pemfile::certs(&mut BufReader::new(file)).map_err(|err| format!("Bad PEM in {}: {}", filename, err)));
+
+    if certs.is_empty() {
+        return Err(format!("No certificates in {}", filename));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+#[cfg(feature = "tls")]
+fn read_key(filename: &str) -> Result<PrivateKey, String> {
+    use rustls_pemfile::Item;
+
+    let file = try!(File::open(filename).map_err(|err| format!("Could not read {}: {}", filename, err)));
+    let items = try!(rustls_pemfile::read_all(&mut BufReader::new(file)).map_err(|err| format!("Bad PEM in {}: {}", filename, err)));
+
+    items.into_iter().filter_map(|item| match item {
+        Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+        _ => None
+    }).next().ok_or(format!("No private key in {}", filename))
+}
+
+#[test]
+fn test_config() {
+    assert!(Config::new("/nonexistent/cert.pem", "/nonexistent/key.pem", None).is_err());
+    assert!(Config::new("/nonexistent/cert.pem", "/nonexistent/key.pem", Some("/nonexistent/ca.pem")).is_err());
+}
+
+#[cfg(feature = "tls")]
+#[test]
+fn test_loopback() {
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::net;
+    use std::process;
+    use std::thread;
+
+    use mioco;
+    use mioco::tcp::TcpListener;
+    use rcgen;
+
+    // A CA, and a server and a client certificate it signed
+    let dir = env::temp_dir().join(format!("qumulus-tls-{}", process::id()));
+
+    fs::create_dir_all(&dir).unwrap();
+
+    let file = |name: &str, pem: String| {
+        let file = dir.join(name);
+
+        fs::write(&file, pem).unwrap();
+        file.to_str().unwrap().to_string()
+    };
+
+    let cert = |name: &str, ca: bool| {
+        let mut params = rcgen::CertificateParams::new(vec![name.to_string()]);
+
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+
+        if ca {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+
+        rcgen::Certificate::from_params(params).unwrap()
+    };
+
+    let ca = cert("ca", true);
+    let server = cert("localhost", false);
+    let client = cert("client", false);
+
+    let ca_file = file("ca.pem", ca.serialize_pem().unwrap());
+    let cert_file = file("cert.pem", server.serialize_pem_with_signer(&ca).unwrap());
+    let key_file = file("key.pem", server.serialize_private_key_pem());
+
+    let mut roots = RootCertStore::empty();
+
+    roots.add(&Certificate(ca.serialize_der().unwrap())).unwrap();
+
+    let without_cert = || rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone()).with_no_client_auth();
+    let with_cert = || rustls::ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone())
+        .with_client_auth_cert(vec![Certificate(client.serialize_der_with_signer(&ca).unwrap())], PrivateKey(client.serialize_private_key_der()))
+        .unwrap();
+
+    // What the server and the client each read of the other, the server replying on a clone
+    let talk = |config: Config, client: rustls::ClientConfig| -> (io::Result<Vec<u8>>, io::Result<Vec<u8>>) {
+        mioco::start(move || {
+            let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+            let addr = listener.local_addr().unwrap();
+
+            let client = thread::spawn(move || -> io::Result<Vec<u8>> {
+                let conn = rustls::ClientConnection::new(Arc::new(client), rustls::ServerName::try_from("localhost").unwrap()).unwrap();
+                let mut tls = rustls::StreamOwned::new(conn, try!(net::TcpStream::connect(addr)));
+                let mut read = vec![0; 3];
+
+                try!(tls.write_all(b"moo"));
+                try!(tls.read_exact(&mut read));
+                Ok(read)
+            });
+
+            let mut reader = Stream::accept(listener.accept().unwrap(), Some(&config)).unwrap();
+            let mut writer = reader.try_clone().unwrap();
+            let mut read = vec![0; 3];
+
+            let server = reader.read_exact(&mut read).and_then(|_| writer.write_all(b"cow")).map(|_| read);
+
+            drop((reader, writer));
+            (server, client.join().unwrap())
+        }).unwrap()
+    };
+
+    let config = Config::new(&cert_file, &key_file, None).unwrap();
+    let (server, client) = talk(config, without_cert());
+
+    assert_eq!(server.unwrap(), b"moo");
+    assert_eq!(client.unwrap(), b"cow");
+
+    // Verifying clients
+    let config = Config::new(&cert_file, &key_file, Some(&ca_file)).unwrap();
+    let (server, client) = talk(config.clone(), with_cert());
+
+    assert_eq!(format!("{}", config), format!("{}, clients verified by {}", cert_file, ca_file));
+    assert_eq!(server.unwrap(), b"moo");
+    assert_eq!(client.unwrap(), b"cow");
+
+    let (server, client) = talk(config, without_cert());
+
+    assert!(server.is_err());
+    assert!(client.is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
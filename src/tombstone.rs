@@ -0,0 +1,72 @@
+//! Garbage collection of tombstones.
+//!
+//! Killed data leaves tombstones: the visibility of the nodes deleted, so merges of writes older
+//! than the kill, from replicas or logs replayed late, stay hidden. Tombstones hidden by a deleted
+//! ancestor carry no information and are always dropped by `Node::prune`, but the topmost ones
+//! otherwise stay forever. With `ZONE_TOMBSTONES=<seconds>`, zones drop those older than that too,
+//! along with what they hide, before writing all of their data. Merges of writes older than a
+//! dropped tombstone bring the data back, so the retention should be longer than replicas and
+//! stores can lag behind.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// How long tombstones are kept.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retention {
+    Forever,
+    Secs(u64)
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention::Forever
+    }
+}
+
+impl FromStr for Retention {
+    type Err = String;
+
+    /// Parses `forever` or `<seconds>`.
+    fn from_str(s: &str) -> Result<Retention, String> {
+        match s {
+            "forever" => Ok(Retention::Forever),
+            secs => match secs.parse() {
+                Ok(secs) => Ok(Retention::Secs(secs)),
+                Err(_) => Err(format!("Bad tombstone retention: {}", s))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Retention::Forever => write!(f, "forever"),
+            Retention::Secs(secs) => write!(f, "{} seconds", secs)
+        }
+    }
+}
+
+impl Retention {
+    /// Timestamp tombstones older than are dropped at `now` (see `clock`), none if kept forever.
+    pub fn cutoff(&self, now: u64) -> Option<u64> {
+        match *self {
+            Retention::Forever => None,
+            Retention::Secs(secs) => Some(now.saturating_sub(secs * 1000000000))
+        }
+    }
+}
+
+#[test]
+fn test_parse_retention() {
+    assert_eq!("forever".parse(), Ok(Retention::Forever));
+    assert_eq!("3600".parse(), Ok(Retention::Secs(3600)));
+    assert!("moo".parse::<Retention>().is_err());
+    assert!("-1".parse::<Retention>().is_err());
+
+    assert_eq!(Retention::Forever.cutoff(5000000000), None);
+    assert_eq!(Retention::Secs(2).cutoff(5000000000), Some(3000000000));
+    assert_eq!(Retention::Secs(10).cutoff(5000000000), Some(0));
+    assert_eq!(Retention::Secs(3600).to_string(), "3600 seconds");
+}
@@ -1,3 +1,8 @@
+use serde_json::Value as JSON;
+
+use crdt::Crdt;
+use list::List;
+
 /// Leaf value storable in Node
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
@@ -18,7 +23,13 @@ pub enum Value {
     F64(f64),
 
     /// Represents a JSON string
-    String(Box<str>)
+    String(Box<str>),
+
+    /// Represents a value merged by joining, see `crdt`
+    Crdt(Box<Crdt>),
+
+    /// Represents an ordered list, see `list`
+    List(Box<List>)
 }
 
 impl Default for Value {
@@ -32,3 +43,55 @@ impl From<String> for Value {
         Value::String(s.into_boxed_str())
     }
 }
+
+impl Value {
+    pub fn to_json(&self) -> JSON {
+        match *self {
+            Value::Null => JSON::Null,
+            Value::Bool(v) => JSON::Bool(v),
+            Value::I64(v) => v.into(),
+            Value::U64(v) => v.into(),
+            Value::F64(v) => v.into(),
+            Value::String(ref s) => JSON::String(String::from(&**s)),
+            Value::Crdt(ref crdt) => crdt.to_json(),
+            Value::List(ref list) => list.to_json()
+        }
+    }
+
+    /// Adds `delta` to this value, for `incr`, `null` counting as 0. Returns `None` for values that
+    /// aren't numbers. Integers stay integers, unless the sum doesn't fit in one.
+    pub fn add(&self, delta: &JSON) -> Option<JSON> {
+        let int = match *self {
+            Value::Null => Some(0),
+            Value::I64(v) => Some(v),
+            Value::U64(v) if v <= i64::max_value() as u64 => Some(v as i64),
+            _ => None
+        };
+
+        if let (Some(v), Some(n)) = (int, delta.as_i64()) {
+            if let Some(sum) = v.checked_add(n) {
+                return Some(sum.into());
+            }
+        }
+
+        let float = match *self {
+            Value::Null => 0.0,
+            Value::I64(v) => v as f64,
+            Value::U64(v) => v as f64,
+            Value::F64(v) => v,
+            _ => return None
+        };
+
+        delta.as_f64().map(|n| (float + n).into())
+    }
+}
+
+#[test]
+fn test_add() {
+    assert_eq!(Value::Null.add(&JSON::from(2)), Some(JSON::from(2)));
+    assert_eq!(Value::I64(-3).add(&JSON::from(2)), Some(JSON::from(-1)));
+    assert_eq!(Value::U64(3).add(&JSON::from(0.5)), Some(JSON::from(3.5)));
+    assert_eq!(Value::I64(i64::max_value()).add(&JSON::from(1)), Some(JSON::from(i64::max_value() as f64 + 1.0)));
+    assert_eq!(Value::from("moo".to_string()).add(&JSON::from(1)), None);
+    assert_eq!(Value::Bool(true).add(&JSON::from(1)), None);
+}
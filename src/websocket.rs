@@ -0,0 +1,430 @@
+//! WebSocket framing (RFC 6455) for API clients, for browsers to connect directly.
+//!
+//! The WebSocket server listens next to the TCP one (see `Replica::websocket_addr`) and speaks the
+//! same protocol, one command or reply per text message instead of per line, so clients connecting
+//! either way share everything past reading and writing messages (see `Client`), binds included.
+//! Once a client upgraded with `handshake`, `Messages` reads the messages it sends, answering pings
+//! and closes, and `write_text` writes a message. Binary messages are read as text, and messages
+//! bigger than `MAX_MESSAGE_BYTES` close the connection.
+
+use std::io;
+use std::io::prelude::*;
+
+/// Longest handshake request read, in bytes
+pub const MAX_HANDSHAKE_BYTES: usize = 8 << 10;
+
+/// Biggest message read, in bytes, of all its frames
+pub const MAX_MESSAGE_BYTES: usize = 64 << 20;
+
+/// Appended to the key of a handshake for the key accepting it, see `accept_key`
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Messages read from a client, each the payload of its frames, till it closes the connection.
+pub struct Messages<R: Read, W: Write> {
+    reader: R,
+    writer: W, // For pongs and closes
+    closed: bool
+}
+
+impl<R: Read, W: Write> Messages<R, W> {
+    pub fn new(reader: R, writer: W) -> Messages<R, W> {
+        Messages {
+            reader: reader,
+            writer: writer,
+            closed: false
+        }
+    }
+
+    /// The next message, `None` once closed.
+    fn read_message(&mut self) -> io::Result<Option<String>> {
+        let mut message = vec![];
+        let mut started = false; // Reading the frames of a fragmented message
+
+        loop {
+            let (fin, opcode, payload) = try!(read_frame(&mut self.reader, MAX_MESSAGE_BYTES - message.len()));
+
+            match opcode {
+                TEXT | BINARY if ! started => message = payload,
+                CONTINUATION if started => message.extend(payload),
+                PING => {
+                    try!(write_frame(&mut self.writer, PONG, &payload));
+                    continue;
+                },
+                PONG => continue,
+                CLOSE if payload.len() == 1 => return Err(invalid("Close frame without a whole status code")),
+                CLOSE => {
+                    try!(write_frame(&mut self.writer, CLOSE, &payload[..payload.len().min(2)]));
+                    return Ok(None);
+                },
+                _ => return Err(invalid("Unexpected frame"))
+            }
+
+            if ! fin {
+                started = true;
+                continue;
+            }
+
+            return String::from_utf8(message).map(Some).map_err(|_| invalid("Message not UTF-8"));
+        }
+    }
+}
+
+impl<R: Read, W: Write> Iterator for Messages<R, W> {
+    type Item = io::Result<String>;
+
+    /// Like `BufRead::lines`, so clients read either the same way.
+    fn next(&mut self) -> Option<io::Result<String>> {
+        if self.closed {
+            return None;
+        }
+
+        match self.read_message() {
+            Ok(Some(message)) => Some(Ok(message)),
+            Ok(None) => {
+                self.closed = true;
+                None
+            },
+            Err(err) => {
+                self.closed = true;
+
+                // Not a stream to go on reading once out of step
+                if err.kind() != io::ErrorKind::UnexpectedEof {
+                    write_frame(&mut self.writer, CLOSE, &[0x03, 0xea]).unwrap_or_default(); // 1002, protocol error
+                }
+
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+/// Reads the upgrade request of a client connecting, up to the blank line ending it, and accepts
+/// it, or replies `400 Bad Request` if it isn't one and `426 Upgrade Required` if it is for another
+/// version than 13.
+pub fn handshake<S: Read + Write>(stream: &mut S) -> io::Result<()> {
+    let mut request = vec![];
+
+    // Byte by byte, so nothing after the request is read with it
+    while ! request.ends_with(b"\r\n\r\n") {
+        if request.len() >= MAX_HANDSHAKE_BYTES {
+            return Err(invalid("Handshake too long"));
+        }
+
+        let mut byte = [0];
+
+        try!(stream.read_exact(&mut byte));
+        request.push(byte[0]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let header = |name: &str| request.lines().skip(1).filter_map(|line| {
+        let mut header = line.splitn(2, ':');
+
+        match (header.next(), header.next()) {
+            (Some(header), Some(value)) if header.trim().eq_ignore_ascii_case(name) => Some(value.trim()),
+            _ => None
+        }
+    }).next();
+
+    // Either header can list other tokens too, e.g. `Connection: keep-alive, Upgrade`
+    let has_token = |name: &str, token: &str| header(name).map_or(false, |value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)));
+
+    let key = match header("sec-websocket-key") {
+        Some(key) if request.starts_with("GET ") && has_token("upgrade", "websocket") && has_token("connection", "upgrade") => key,
+        _ => {
+            try!(stream.write_all(b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n"));
+            return Err(invalid("Not a WebSocket upgrade"));
+        }
+    };
+
+    if header("sec-websocket-version") != Some("13") {
+        try!(stream.write_all(b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nConnection: close\r\n\r\n"));
+        return Err(invalid("Unsupported WebSocket version"));
+    }
+
+    let response = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(key));
+
+    stream.write_all(response.as_bytes())
+}
+
+/// Writes `message` as one text frame.
+pub fn write_text<W: Write>(writer: &mut W, message: &str) -> io::Result<()> {
+    write_frame(writer, TEXT, message.as_bytes())
+}
+
+/// Key accepting the upgrade with `key`: base64 of the SHA-1 of the key and `GUID`.
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Reads a frame masked as clients' are, with at most `max` bytes of payload. Returns whether it
+/// ends its message, its opcode and its payload, unmasked.
+fn read_frame<R: Read>(reader: &mut R, max: usize) -> io::Result<(bool, u8, Vec<u8>)> {
+    let mut head = [0; 2];
+
+    try!(reader.read_exact(&mut head));
+
+    let (fin, opcode, masked) = (head[0] & 0x80 != 0, head[0] & 0x0f, head[1] & 0x80 != 0);
+
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+
+            try!(reader.read_exact(&mut len));
+            (len[0] as u64) << 8 | len[1] as u64
+        },
+        127 => {
+            let mut len = [0; 8];
+
+            try!(reader.read_exact(&mut len));
+            len.iter().fold(0, |n, &b| n << 8 | b as u64)
+        },
+        len => len as u64
+    };
+
+    if ! masked {
+        return Err(invalid("Frame not masked"));
+    }
+
+    // Pings, pongs and closes come whole, between the frames of other messages
+    if opcode & 0x8 != 0 && (len > 125 || ! fin) {
+        return Err(invalid("Bad control frame"));
+    }
+
+    if len > max as u64 {
+        return Err(invalid("Message too big"));
+    }
+
+    let mut mask = [0; 4];
+    let mut payload = vec![0; len as usize];
+
+    try!(reader.read_exact(&mut mask));
+    try!(reader.read_exact(&mut payload));
+
+    for (i, b) in payload.iter_mut().enumerate() {
+        *b ^= mask[i % 4];
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+/// Writes one frame ending its message, unmasked as servers' are.
+fn write_frame<W: Write>(writer: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+
+    frame.push(0x80 | opcode);
+
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= 0xffff => {
+            frame.push(126);
+            frame.extend(&[(len >> 8) as u8, len as u8]);
+        },
+        len => {
+            frame.push(127);
+            frame.extend((0..8).rev().map(|i| (len as u64 >> (i * 8)) as u8));
+        }
+    }
+
+    frame.extend(payload);
+    writer.write_all(&frame)
+}
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// SHA-1 of `data`, only used for handshakes.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut message = data.to_vec();
+
+    message.push(0x80);
+
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+
+    message.extend((0..8).rev().map(|i| ((data.len() as u64 * 8) >> (i * 8)) as u8));
+
+    for block in message.chunks(64) {
+        let mut w = [0u32; 80];
+
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 | (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+
+        for i in 0..80 {
+            let (f, k) = match i {
+                0...19 => ((b & c) | (! b & d), 0x5a827999),
+                20...39 => (b ^ c ^ d, 0x6ed9eba1),
+                40...59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6)
+            };
+
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut digest = [0; 20];
+
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            digest[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+
+    digest
+}
+
+/// Standard base64, padded.
+fn base64(data: &[u8]) -> String {
+    const CHARS: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - i * 8));
+
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(CHARS[(n >> (18 - i * 6)) as usize & 0x3f] as char),
+                false => encoded.push('=')
+            }
+        }
+    }
+
+    encoded
+}
+
+#[test]
+fn test_handshake() {
+    use std::io::Cursor;
+
+    // From RFC 6455
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert_eq!(base64(b"moo"), "bW9v");
+    assert_eq!(base64(b"mooo"), "bW9vbw==");
+
+    struct Stream(Cursor<Vec<u8>>, Vec<u8>);
+
+    impl Read for Stream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> { self.0.read(buf) }
+    }
+
+    impl Write for Stream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.1.write(buf) }
+        fn flush(&mut self) -> io::Result<()> { Ok(()) }
+    }
+
+    let request = |headers: &str| format!("GET / HTTP/1.1\r\nHost: moo\r\n{}\r\n[ 1, ", headers).into_bytes();
+    let upgrade = "Upgrade: websocket\r\nConnection: keep-alive, Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n";
+    let mut stream = Stream(Cursor::new(request(&format!("{}Sec-WebSocket-Version: 13\r\n", upgrade))), vec![]);
+
+    handshake(&mut stream).unwrap();
+    assert!(String::from_utf8_lossy(&stream.1).contains("101 Switching Protocols\r\n"));
+    assert!(String::from_utf8_lossy(&stream.1).contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    assert_eq!(stream.0.position() as usize, stream.0.get_ref().len() - 5); // Up to the end of the request
+
+    let replied = |headers: &str| {
+        let mut stream = Stream(Cursor::new(request(headers)), vec![]);
+
+        assert!(handshake(&mut stream).is_err());
+        String::from_utf8_lossy(&stream.1).lines().next().unwrap().to_string()
+    };
+
+    assert_eq!(replied(""), "HTTP/1.1 400 Bad Request");
+    assert_eq!(replied("Connection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n"), "HTTP/1.1 400 Bad Request");
+    assert_eq!(replied("Upgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n"), "HTTP/1.1 400 Bad Request");
+    assert_eq!(replied(&format!("{}Sec-WebSocket-Version: 8\r\n", upgrade)), "HTTP/1.1 426 Upgrade Required");
+    assert_eq!(replied(upgrade), "HTTP/1.1 426 Upgrade Required");
+}
+
+#[test]
+fn test_messages() {
+    use std::io::Cursor;
+
+    // Masked as clients' are
+    let frame = |fin: bool, opcode: u8, payload: &[u8]| {
+        let mask = [1, 2, 3, 4];
+        let mut frame = vec![if fin { 0x80 | opcode } else { opcode }, 0x80 | payload.len() as u8];
+
+        frame.extend(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    };
+
+    let mut input = frame(true, TEXT, b"[ 1, \"read\", [], null ]");
+
+    input.extend(frame(false, TEXT, b"[ 2, \"read\", "));
+    input.extend(frame(true, PING, b"moo"));
+    input.extend(frame(true, CONTINUATION, b"[], null ]"));
+    input.extend(frame(true, CLOSE, b"\x03\xe8bye"));
+    input.extend(frame(true, TEXT, b"after close"));
+
+    let mut output = vec![];
+    let messages: Vec<String> = Messages::new(Cursor::new(input), &mut output).map(|message| message.unwrap()).collect();
+
+    assert_eq!(messages, vec!["[ 1, \"read\", [], null ]", "[ 2, \"read\", [], null ]"]);
+
+    // A pong and a close written back, unmasked, with the status code but not the reason
+    let mut expected = vec![0x80 | PONG, 3];
+
+    expected.extend(b"moo");
+    expected.extend(&[0x80 | CLOSE, 2, 0x03, 0xe8]);
+    assert_eq!(output, expected);
+
+    // Longer messages
+    let mut long = vec![];
+
+    write_text(&mut long, &"m".repeat(300)).unwrap();
+    assert_eq!(&long[..4], &[0x80 | TEXT, 126, 1, 44]);
+    assert_eq!(long.len(), 304);
+
+    // Unmasked frames are rejected
+    let mut output = vec![];
+    let mut messages = Messages::new(Cursor::new(long), &mut output);
+
+    assert!(messages.next().unwrap().is_err());
+    assert!(messages.next().is_none());
+
+    // Control frames too long or fragmented, and closes with half a status code, closed as a
+    // protocol error
+    let mut long_ping = vec![0x80 | PING, 0x80 | 126, 0, 126, 0, 0, 0, 0];
+
+    long_ping.extend(&[0; 126][..]);
+
+    for input in vec![long_ping, frame(false, PING, b"moo"), frame(false, CLOSE, &[0x03, 0xe8]), frame(true, CLOSE, &[0x03])] {
+        let mut output = vec![];
+        let mut messages = Messages::new(Cursor::new(input), &mut output);
+
+        assert!(messages.next().unwrap().is_err());
+        assert!(messages.next().is_none());
+        assert_eq!(output, vec![0x80 | CLOSE, 2, 0x03, 0xea]);
+    }
+}
@@ -6,46 +6,113 @@
 
 use std::collections::VecDeque;
 use std::hash::{Hash, Hasher};
+use std::mem;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use mioco;
 use mioco::sync::mpsc::{channel, Receiver, Sender};
 use serde_json::Value;
+use time;
 
 use app::AppHandle;
-use command::{Call, Command};
-use delegate::delegate;
-use listener::{Listener, RListener};
-use node::{DelegatedMatch, Node, Update, Vis, NodeTree};
+use clock;
+use cold::Cold;
+use command::{self, Call, Command};
+use crdt::{self, Crdt};
+use delegate::{delegate, Size, Sizes};
+use expiry::{self, Expiry, Ttl};
+use lease::{Leases, Request, Waiter};
+use list::{self, List};
+use listener::{self, Listener, RListener};
+use matcher::Regexes;
+use node::{DelegatedMatch, Difference, External, Node, Update, Vis, NodeTree};
 use path::Path;
+use prefetch::Prefetch;
+use region::Regions;
+use schema::Invalid;
+use snapshot::Snapshots;
+use store::StoreError;
+use store::events::StoreEvent;
+use store::raw::RawZone;
+use store::scheduler::Dirty;
+use store::stream::ChunkReader;
+use subs::Subscriptions;
+use value;
+
+/// Zones at least this big (see `Zone::size`) save the regions changed (see `region`) instead of
+/// all their data
+const DELTA_SIZE: usize = 1024 * 1024;
+
+/// Milliseconds before asking a busy Store to write again
+const WRITE_RETRY_MS: u64 = 100;
 
 /// Persistent Zone data
 #[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct ZoneData {
-    pub path: Path,    // TODO: repeated data needed for persistence
-    pub tree: NodeTree // Mergeable data for this Zone
+    pub path: Path,     // TODO: repeated data needed for persistence
+    pub tree: NodeTree, // Mergeable data for this Zone
+    pub expiry: Expiry  // TTLs set in this Zone
 }
 
 /// Public shareable handle to a `Zone`
 #[derive(Clone)]
 pub struct ZoneHandle {
     path: Arc<Path>,
-    tx: Sender<ZoneCall>
+    tx: Sender<ZoneCall>,
+    usage: Arc<Usage>
+}
+
+/// What a `Zone` takes up, shared with its handles for eviction without calling it
+#[derive(Default)]
+struct Usage {
+    accessed: AtomicUsize, // Milliseconds (see `now_ms`) of the last command or merge
+    bytes: AtomicUsize     // Estimated size of data, see `Zone::size`
 }
 
 /// Zones communicate via message passing. This enum is a list of valid calls.
 enum ZoneCall {
     UserCommand(UserCommand),
     Dump(Sender<NodeTree>),
+    Heaviest(usize, Sender<(Size, Vec<(String, Size)>)>),
     Hibernate,
     Load,
+    Loading,
     Loaded(ZoneData),
+    LoadedStream(ChunkReader),
+    LoadedRaw(RawZone),
+    Corrupt,
+    Recovered(ZoneData),
+    Fold,
+    Folded(FoldedZone),
+    FoldDone,
+    Hold(Vec<Path>, Sender<Option<Vec<Read>>>, Receiver<NodeTree>, Sender<()>),
     Merge(NodeTree, bool),
     MergeWithListeners(NodeTree, Vec<RListener>),
+    Reload,
     Save,
     Saved,
+    WriteFailed(StoreError),
+    RetryWrite,
+    Sweep,
+    Pack,
+    Prefetch,
+    Deleted,
+    Snapshot,
     Size(Sender<usize>),
-    State(Sender<ZoneState>)
+    State(Sender<ZoneState>),
+    Stats(Sender<AccessStats>)
+}
+
+/// Data read at a path, and what of it is delegated to other zones.
+pub type Read = (Option<Update>, Vec<DelegatedMatch>);
+
+/// A `Zone` held for a change spanning zones, serving nothing else until the change is committed or
+/// the hold dropped. See `ZoneHandle::hold`.
+pub struct Hold {
+    commit: Sender<NodeTree>,
+    applied: Receiver<()>
 }
 
 struct UserCommand {
@@ -54,10 +121,37 @@ struct UserCommand {
     listener: Sender<String>
 }
 
+/// Data of a child `Zone` folding back into its parent, see `Zone::fold`
+struct FoldedZone {
+    relative: Path,           // Path to the child from its parent
+    node: Node,               // Its data, no longer delegated
+    expiry: Expiry,           // Its TTLs
+    listeners: Vec<RListener>,
+    child: ZoneHandle
+}
+
 #[derive(Default)]
 pub struct ZoneResult {
     pub update: Option<Update>,
-    pub delegated: Vec<DelegatedMatch>
+    pub delegated: Vec<DelegatedMatch>,
+    pub conflict: bool,           // A `cas` that didn't match, with the current value in `update`
+    pub invalid: Option<Invalid>, // A write the schema rejected
+    pub data: Option<Value>,      // Replied with in place of the update: a `lock`'s lease, or the
+                                  // other owner's on a conflict, or the changes a `diff` found
+    pub queued: bool,             // A `lock` waiting for its lease, replied to once it gets it
+    pub frozen: bool              // A write to a frozen path, see `freeze`
+}
+
+/// Access and mutation counts of a `Zone` since it was spawned, to find hot spots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct AccessStats {
+    pub reads: u64,       // Read, diff and snapshot commands
+    pub binds: u64,       // Bind commands
+    pub writes: u64,      // Write, cas, txn, crdt, incr, list, lock, copy, move, kill and expire commands
+    pub merges: u64,      // Diffs merged from replicas and other zones
+    pub listeners: usize, // Current binds
+    pub bytes: usize,     // Estimated size of data, see `Zone::size`
+    pub recovered: bool   // Loaded with what could be salvaged of corrupt stored data
 }
 
 /// Tracks current state of a Zone
@@ -76,9 +170,24 @@ pub struct Zone {
     handle: ZoneHandle,         // Handle to zone
     rx: Receiver<ZoneCall>,     // Zone message inbox
     queued: VecDeque<ZoneCall>, // When Zone data is not active, queue up all commands
-    listeners: Vec<Listener>,   // List of binds
-    writes: u64                 // Number of writes since last fragment check
-    // TODO: size: u64,
+    listeners: Subscriptions,   // Binds, by path
+    writes: u64,                // Number of writes since last fragment check
+    sizes: Sizes,               // Estimated size of data, kept up to date as it changes
+    regions: Regions,           // Changed since last save
+    snapshots: Snapshots,       // Taken by `snapshot` commands, see `snapshot`
+    leases: Leases,             // Held for `lock` commands, see `lease`
+    write_retry: bool,          // Waiting to ask a busy Store to write again
+    unsaved: Option<Dirty>,     // Changes not yet written, sent with write requests
+    saving: Option<Dirty>,      // Changes being written, unsaved again if the write fails
+    expiry_changed: bool,       // TTLs changed since last save, which diffs don't carry
+    sweeping: bool,             // Waiting to sweep expired TTLs
+    cold: Cold,                 // Children packed to save memory, see `cold`
+    packing: bool,              // Waiting to pack cold children
+    prefetch: Prefetch,         // Recent reads of child zones, see `prefetch`
+    fold_requested: bool,       // Folding once changes not yet written are
+    folded_into: Option<(Path, ZoneHandle)>, // Parent this Zone folded into, and the path from it
+    recovered: bool,            // Loaded from salvaged data, see `recovered`
+    access: AccessStats         // Counts of calls made to this Zone
     // TODO: prefixes: Option<BTreeMap<String, Node>>
 }
 
@@ -97,11 +206,61 @@ impl ZoneHandle {
         self.tx.send(ZoneCall::Load).unwrap();
     }
 
+    /// Signal `Zone` that its data has been requested from the `Store` on its behalf, so it waits
+    /// for it instead of loading it itself. Usually called by `Manager` before
+    /// `StoreHandle::load_many`.
+    pub fn loading(&self) {
+        self.tx.send(ZoneCall::Loading).unwrap();
+    }
+
     /// Signal `Zone` with loaded data. Usually called by `Store` with loaded data.
     pub fn loaded(&self, data: ZoneData) {
         self.tx.send(ZoneCall::Loaded(data)).unwrap();
     }
 
+    /// Signal `Zone` that its stored data changed outside of the `Store`, e.g. restored from a
+    /// backup, so it loads it again. Usually called by the `fs` Store watching its data directory.
+    pub fn reload(&self) {
+        self.tx.send(ZoneCall::Reload).unwrap();
+    }
+
+    /// Signal `Zone` with data being loaded, to read as it is streamed. Usually called by `Store`
+    /// instead of `loaded` for large zones (see `store::stream`).
+    pub fn loaded_stream(&self, reader: ChunkReader) {
+        self.tx.send(ZoneCall::LoadedStream(reader)).unwrap();
+    }
+
+    /// Signal `Zone` with loaded data still serialized, to deserialize in its own thread rather
+    /// than the `Store`'s. Usually called by `Store` instead of `loaded` (see `store::raw`).
+    pub fn loaded_raw(&self, raw: RawZone) {
+        self.tx.send(ZoneCall::LoadedRaw(raw)).unwrap();
+    }
+
+    /// Signal `Zone` that its stored data is corrupt and could not be loaded. Usually called by
+    /// `Store` instead of `loaded`.
+    pub fn corrupt(&self) {
+        self.tx.send(ZoneCall::Corrupt).unwrap();
+    }
+
+    /// Signal `Zone` with what could be salvaged of its corrupt stored data. Usually called by
+    /// `Store` instead of `loaded` (see `store::migrate::recover`).
+    pub fn recovered(&self, data: ZoneData) {
+        self.tx.send(ZoneCall::Recovered(data)).unwrap();
+    }
+
+    /// Holds this `Zone` for a change spanning zones, reading `paths` once nothing else will change
+    /// them until the change is committed. `None` if it folded into its parent. Hold zones in path
+    /// order, so changes holding some of the same zones take turns rather than deadlock.
+    pub fn hold(&self, paths: Vec<Path>) -> Option<(Hold, Vec<Read>)> {
+        let (tx, rx) = channel();
+        let (commit, committed) = channel();
+        let (applied, done) = channel();
+
+        self.tx.send(ZoneCall::Hold(paths, tx, committed, applied)).unwrap();
+
+        rx.recv().unwrap().map(|reads| (Hold { commit: commit, applied: done }, reads))
+    }
+
     /// Merge data into this `Zone`. The effective parent visibility (through all ancestors) must
     /// be provided.
     pub fn merge(&self, diff: NodeTree, replicate: bool) {
@@ -133,6 +292,58 @@ impl ZoneHandle {
         self.tx.send(ZoneCall::Saved).unwrap();
     }
 
+    /// Signal `Zone` that save has failed, after any retries. Usually called by `Store` instead of
+    /// `saved`.
+    pub fn write_failed(&self, err: StoreError) {
+        self.tx.send(ZoneCall::WriteFailed(err)).unwrap();
+    }
+
+    /// Signal `Zone` to ask to write again, after the `Store` was busy.
+    fn retry_write(&self) {
+        self.tx.send(ZoneCall::RetryWrite).is_ok(); // ignore if zone goes away
+    }
+
+    fn sweep(&self) {
+        self.tx.send(ZoneCall::Sweep).is_ok(); // ignore if zone goes away
+    }
+
+    fn pack(&self) {
+        self.tx.send(ZoneCall::Pack).is_ok(); // ignore if zone goes away
+    }
+
+    /// Signal `Zone` to load, if it isn't, ahead of reads likely to reach it. Usually called by
+    /// `Manager`, see `prefetch`.
+    pub fn prefetch(&self) {
+        self.tx.send(ZoneCall::Prefetch).is_ok(); // ignore if zone goes away
+    }
+
+    /// Signal `Zone` to fold its data back into its parent zone. Usually called by `Manager` once
+    /// the parent took over the data, or from the shell.
+    pub fn fold(&self) {
+        self.tx.send(ZoneCall::Fold).is_ok(); // ignore if zone goes away
+    }
+
+    /// Hands the data of a child `Zone` folding back into this one.
+    fn folded(&self, folded: FoldedZone) {
+        self.tx.send(ZoneCall::Folded(folded)).unwrap();
+    }
+
+    /// Signal `Zone` that its parent took its data, which can be deleted.
+    fn fold_done(&self) {
+        self.tx.send(ZoneCall::FoldDone).is_ok(); // ignore if zone goes away
+    }
+
+    /// Signal `Zone` that its stored data was deleted. Usually called by `Store` instead of `saved`.
+    pub fn deleted(&self) {
+        self.tx.send(ZoneCall::Deleted).unwrap();
+    }
+
+    /// Signal `Zone` to save all of its data instead of a delta. Usually called by `Store` instead
+    /// of `saved`.
+    pub fn snapshot(&self) {
+        self.tx.send(ZoneCall::Snapshot).unwrap();
+    }
+
     /// Get raw data of this `Zone`.
     pub fn dump(&self) -> NodeTree {
         let (tx, rx) = channel();
@@ -141,6 +352,26 @@ impl ZoneHandle {
         rx.recv().unwrap()
     }
 
+    /// Gets the estimated size of this `Zone`'s data, and that of its `count` heaviest children.
+    /// See `delegate::Sizes`.
+    pub fn heaviest(&self, count: usize) -> (Size, Vec<(String, Size)>) {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::Heaviest(count, tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    /// Get data of this `Zone` as plain JSON, without versions or data delegated to other zones.
+    pub fn export_json(&self) -> Value {
+        self.dump().to_plain_json()
+    }
+
+    /// Write `json`, as exported by `export_json`, over the data of this `Zone`. Merges like a
+    /// write at the zone's path: values are versioned now, and data not in `json` is kept.
+    pub fn import_json(&self, json: Value) {
+        self.merge(Node::expand(json, clock::now()).noop_vis(), true);
+    }
+
     /// Get approximate storage size of this `Zone`.
     pub fn size(&self) -> usize {
         let (tx, rx) = channel();
@@ -157,6 +388,26 @@ impl ZoneHandle {
         rx.recv().unwrap()
     }
 
+    /// Gets access and mutation counts of this `Zone`.
+    pub fn stats(&self) -> AccessStats {
+        let (tx, rx) = channel();
+
+        self.tx.send(ZoneCall::Stats(tx)).unwrap();
+        rx.recv().unwrap()
+    }
+
+    /// Time since the last command or merge reached this `Zone`.
+    pub fn idle(&self) -> Duration {
+        let accessed = self.usage.accessed.load(Ordering::Relaxed);
+
+        Duration::from_millis(now_ms().saturating_sub(accessed) as u64)
+    }
+
+    /// Estimated size of data loaded in this `Zone`, 0 when not loaded.
+    pub fn bytes(&self) -> usize {
+        self.usage.bytes.load(Ordering::Relaxed)
+    }
+
     /// Creates a noop ZoneHandle for testing
     #[cfg(test)]
     pub fn test_handle(path: Arc<Path>) -> ZoneHandle {
@@ -168,7 +419,24 @@ impl ZoneHandle {
 
         ZoneHandle {
             path: path,
-            tx: tx
+            tx: tx,
+            usage: Default::default()
+        }
+    }
+
+    /// Makes a test handle's `Zone` look last used `idle_ms` ago, with `bytes` of data loaded
+    #[cfg(test)]
+    pub fn set_usage(&self, idle_ms: usize, bytes: usize) {
+        self.usage.accessed.store(now_ms().saturating_sub(idle_ms), Ordering::Relaxed);
+        self.usage.bytes.store(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Hold {
+    /// Merges `diff` into the zone held, as a user command would, and releases it once merged.
+    pub fn commit(self, diff: NodeTree) {
+        if self.commit.send(diff).is_ok() {
+            self.applied.recv().unwrap_or_default(); // released anyway if the zone goes away
         }
     }
 }
@@ -252,15 +520,39 @@ impl Zone {
                         0 => Vis::permanent(),
                         _ => Default::default()
                     }
-                }
+                },
+                expiry: Default::default()
             },
             state: Default::default(),
             app: app,
-            handle: ZoneHandle { path: arc_path, tx: tx },
+            handle: ZoneHandle {
+                path: arc_path,
+                tx: tx,
+                usage: Arc::new(Usage {
+                    accessed: AtomicUsize::new(now_ms()),
+                    bytes: AtomicUsize::new(0)
+                })
+            },
             rx: rx,
             queued: VecDeque::new(),
-            listeners: vec![],
-            writes: 0
+            listeners: Default::default(),
+            writes: 0,
+            sizes: Default::default(),
+            regions: Default::default(),
+            snapshots: Default::default(),
+            leases: Default::default(),
+            write_retry: false,
+            unsaved: None,
+            saving: None,
+            expiry_changed: false,
+            sweeping: false,
+            cold: Default::default(),
+            packing: false,
+            prefetch: Default::default(),
+            fold_requested: false,
+            folded_into: None,
+            recovered: false,
+            access: Default::default()
         }
     }
 
@@ -279,10 +571,29 @@ impl Zone {
 
                 match call {
                     ZoneCall::Load |
+                    ZoneCall::Loading |
                     ZoneCall::Loaded(_) |
+                    ZoneCall::LoadedStream(_) |
+                    ZoneCall::LoadedRaw(_) |
+                    ZoneCall::Corrupt |
+                    ZoneCall::Recovered(_) |
+                    ZoneCall::FoldDone |
                     ZoneCall::Hibernate |
+                    ZoneCall::Reload |
+                    ZoneCall::RetryWrite |
+                    ZoneCall::Sweep |
+                    ZoneCall::Pack |
                     ZoneCall::Size(_) |
-                    ZoneCall::State(_) => {
+                    ZoneCall::State(_) |
+                    ZoneCall::Stats(_) => {
+                        self.handle_call(call);
+                    },
+                    ZoneCall::UserCommand(_) |
+                    ZoneCall::Fold |
+                    ZoneCall::Hold(..) |
+                    ZoneCall::Merge(..) |
+                    ZoneCall::MergeWithListeners(..) if self.folded_into.is_some() => {
+                        // Forwarded or already folded, nothing to load
                         self.handle_call(call);
                     },
                     _ => {
@@ -295,26 +606,75 @@ impl Zone {
                     }
                 }
             }
+
+            self.handle.usage.bytes.store(self.sizes.total().bytes.saturating_sub(self.cold.saved()), Ordering::Relaxed);
         }
     }
 
     fn handle_call(&mut self, call: ZoneCall) {
         match call {
             ZoneCall::UserCommand(cmd) => {
-                let result = self.dispatch(cmd.command, cmd.listener);
+                let result = match self.folded_into {
+                    Some(_) => self.forward(cmd.command, &cmd.listener),
+                    None => self.dispatch(cmd.command, cmd.listener)
+                };
 
                 cmd.reply.send(result).unwrap(); // TODO: don't crash the Zone!
             },
             ZoneCall::Dump(reply) => {
+                self.thaw_all();
                 reply.send(self.dump()).unwrap();
             },
+            ZoneCall::Heaviest(count, reply) => {
+                reply.send((self.sizes.total(), self.sizes.heaviest(count))).unwrap();
+            },
             ZoneCall::Load => {
                 self.load();
             },
+            ZoneCall::Loading => {
+                self.loading();
+            },
             ZoneCall::Loaded(data) => {
                 self.loaded(data);
             },
+            ZoneCall::LoadedStream(reader) => {
+                self.loaded_stream(reader);
+            },
+            ZoneCall::LoadedRaw(raw) => {
+                self.loaded_raw(raw);
+            },
+            ZoneCall::Corrupt => {
+                self.corrupt();
+            },
+            ZoneCall::Recovered(data) => {
+                self.recovered(data);
+            },
+            ZoneCall::Fold => {
+                self.fold();
+            },
+            ZoneCall::Folded(folded) => {
+                self.folded(folded);
+            },
+            ZoneCall::FoldDone => {
+                self.fold_done();
+            },
+            ZoneCall::Hold(paths, reply, commit, applied) => {
+                self.hold(paths, reply, commit, applied);
+            },
+            ZoneCall::Merge(diff, replicate) if self.folded_into.is_some() => {
+                let (relative, parent) = self.folded_into.clone().unwrap();
+
+                parent.merge(diff.node.prepend_path(&relative.path).noop_vis(), replicate);
+            },
+            ZoneCall::MergeWithListeners(diff, listeners) if self.folded_into.is_some() => {
+                let (relative, parent) = self.folded_into.clone().unwrap();
+                let listeners = listeners.into_iter().map(|l| l.prefixed(&relative)).collect();
+
+                parent.merge_with_listeners(diff.node.prepend_path(&relative.path).noop_vis(), listeners);
+            },
             ZoneCall::Merge(diff, replicate) => {
+                self.access.merges += 1;
+                self.touch();
                 self.merge(diff, replicate);
 
                 if replicate {
@@ -322,9 +682,14 @@ impl Zone {
                 }
             },
             ZoneCall::MergeWithListeners(diff, listeners) => {
+                self.access.merges += 1;
+                self.touch();
                 self.merge_with_listeners(diff, listeners);
                 self.split_check();
             },
+            ZoneCall::Reload => {
+                self.reload();
+            },
             ZoneCall::Hibernate => {
                 self.hibernate();
             },
@@ -334,33 +699,132 @@ impl Zone {
             ZoneCall::Saved => {
                 self.saved();
             },
+            ZoneCall::WriteFailed(err) => {
+                self.write_failed(err);
+            },
+            ZoneCall::RetryWrite => {
+                self.retry_write();
+            },
+            ZoneCall::Sweep => {
+                self.sweep();
+            },
+            ZoneCall::Pack => {
+                self.pack();
+            },
+            ZoneCall::Prefetch => (), // loaded to get here
+            ZoneCall::Deleted => {
+                self.deleted();
+            },
+            ZoneCall::Snapshot => {
+                self.snapshot();
+            },
             ZoneCall::Size(reply) => {
                 reply.send(self.size()).unwrap();
             },
             ZoneCall::State(reply) => {
                 reply.send(self.state()).unwrap();
+            },
+            ZoneCall::Stats(reply) => {
+                reply.send(self.stats()).unwrap();
             }
         }
     }
 
     pub fn dispatch(&mut self, command: Command, tx: Sender<String>) -> ZoneResult {
+        self.access.count(&command.call);
+        self.touch();
+
+        // Packed data the command reaches, see `cold`
+        match command.call {
+            Call::Txn => {
+                for op in command.ops().unwrap_or_default() {
+                    self.thaw(&op.path);
+                }
+            },
+            _ => self.thaw(&command.path)
+        }
+
+        if command.writes() && self.frozen(&command) {
+            return ZoneResult { frozen: true, ..Default::default() };
+        }
+
         match command.call {
             Call::Bind => {
-                let (update, delegated) = self.bind(&command.path, tx);
+                let (update, delegated) = self.bind(&command.path, &command.params, tx);
+
+                self.prefetch(&command.path, &delegated);
+
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
+            },
+            Call::Cas => {
+                let result = self.cas(&command.path, command.timestamp, command.params);
+                self.split_check();
 
-                ZoneResult { update: update, delegated: delegated }
+                result
+            },
+            Call::Crdt => {
+                let result = self.crdt(&command.path, command.timestamp, &command.params);
+                self.split_check();
+
+                result
+            },
+            Call::Diff => self.diff(&command.path, &command.params),
+            Call::Expire => {
+                self.expire(&command.path, command.timestamp, command.params);
+
+                ZoneResult { ..Default::default() }
+            }
+            Call::Incr => {
+                let result = self.incr(&command.path, command.timestamp, &command.params);
+                self.split_check();
+
+                result
             },
             Call::Kill => {
                 self.kill(&command.path, command.timestamp);
 
                 ZoneResult { ..Default::default() }
             }
+            Call::List => {
+                let result = self.list(&command.path, command.timestamp, &command.params);
+                self.split_check();
+
+                result
+            },
+            Call::Lock => self.lock(&command.path, command.id, &command.params, tx),
+            // Within this zone, otherwise see `client::copy_path`
+            Call::Copy | Call::Move => {
+                let result = self.copy(&command);
+                self.split_check();
+
+                result
+            },
             Call::Read => {
-                let (update, delegated) = self.read(&command.path);
+                let (update, delegated) = match command.params.get("snapshot").and_then(|name| name.as_str()) {
+                    Some(name) => self.snapshots.read(name, &command.path),
+                    None => self.read(&command.path)
+                };
+
+                self.prefetch(&command.path, &delegated);
 
-                ZoneResult { update: update, delegated: delegated }
+                ZoneResult { update: update, delegated: delegated, ..Default::default() }
+            },
+            Call::Snapshot => {
+                let delegated = self.take_snapshot(&command.path, &command.params);
+
+                ZoneResult { delegated: delegated, ..Default::default() }
+            },
+            Call::Txn => {
+                let result = self.txn(&command);
+                self.split_check();
+
+                result
             },
             Call::Write => {
+                if let Err(invalid) = self.validate(&command.path, &command.params) {
+                    return ZoneResult { invalid: Some(invalid), ..Default::default() };
+                }
+
                 self.write(&command.path, command.timestamp, command.params);
                 self.split_check();
 
@@ -369,150 +833,602 @@ impl Zone {
         }
     }
 
-    /// Bind value(s)
-    pub fn bind(&mut self, path: &Path, tx: Sender<String>) -> (Option<Update>, Vec<DelegatedMatch>) {
+    /// Changes to the data at `path` since snapshot `params`, a name (see `snapshot`), as
+    /// `[ "added" or "changed" or "removed", path ]` for each path, absolute, whose value or
+    /// version differs. Replied to with `"invalid"` if there is no such snapshot.
+    pub fn diff(&self, path: &Path, params: &Value) -> ZoneResult {
+        let name = params.as_str().unwrap_or_default();
+
+        if ! self.snapshots.contains(name) {
+            let mut absolute = self.path();
+
+            absolute.append(&mut path.clone());
+
+            return ZoneResult { invalid: Some(Invalid { path: absolute, error: format!("No snapshot {}", name) }), ..Default::default() };
+        }
+
+        let (current, delegated) = self.read(path);
+        let (snapshot, _) = self.snapshots.read(name, path);
+
+        let changes = Update::diff(snapshot.as_ref(), current.as_ref()).into_iter().map(|(mut changed, difference)| {
+            let mut absolute = self.path();
+
+            absolute.append(&mut changed);
+            Value::Array(vec![Value::from(difference.as_str()), absolute.to_json()])
+        }).collect();
+
+        ZoneResult { data: Some(Value::Array(changes)), delegated: delegated, ..Default::default() }
+    }
+
+    /// Bind value(s). `params` can narrow down the changes notified: `filter` is a path below
+    /// `path` they have to match, or `regex` one with `/` separated regular expressions for keys,
+    /// and `depth` how many levels recursive wildcards match, e.g.
+    /// `{ "filter": ["*", "status"], "depth": 1 }` or `{ "regex": "[0-9a-f]{8}/status" }`. The
+    /// reply reads all of `path` either way.
+    pub fn bind(&mut self, path: &Path, params: &Value, tx: Sender<String>) -> (Option<Update>, Vec<DelegatedMatch>) {
         // TODO verify path
         // TODO don't sub if path has been delegated completely
 
-        self.sub(path, tx);
-        self.read(path)
-    }
+        let mut listening = path.clone();
 
-    /// Kill value(s)
-    pub fn kill(&mut self, path: &Path, ts: u64) {
-        let node = Node::delete(ts);
+        // Refused by `Command::check_params` if bad
+        let (mut filter, regexes) = listener::bind_filter(params).unwrap_or_else(|_| (Path::empty(), Regexes::default()));
 
-        let diff = node.prepend_path(&path.path);
+        listening.append(&mut filter);
 
-        self.merge(diff.noop_vis(), true);
-        // TODO: externals goes to external nodes
+        self.sub(&listening, Arc::new(regexes), listener::bind_depth(params), tx);
+        self.read(path)
     }
 
-    /// Merge value(s). Merge is generic and most operations are defined as a merge. Set
-    /// `replicate` flag if merge was due to a user command.
-    pub fn merge(&mut self, mut diff: NodeTree, replicate: bool) {
-        let (update, externals) = self.data.tree.merge(&mut diff);
+    /// Writes a value to the node at `path` at time `ts`, if the version of its value is still the
+    /// one expected, or it has none if `null` is. `params` is `[ expected, value ]`. Returns the
+    /// value written, or a conflict with the current value if it was changed in the meantime.
+    pub fn cas(&mut self, path: &Path, ts: u64, params: Value) -> ZoneResult {
+        let (expected, value) = command::cas_params(params);
 
-        // Only notify if there are changes
-        if let Some(update) = update {
-            self.notify(&update);
+        if let Err(invalid) = self.validate(path, &value) {
+            return ZoneResult { invalid: Some(invalid), ..Default::default() };
         }
 
-        if ! diff.node.is_noop() {
-            self.writes += 1;
-            self.dirty();
+        if let Some(conflict) = self.conflict(path, Some(expected)) {
+            return conflict;
         }
 
-        if externals.len() > 0 {
-            for external in externals {
-                // Check if Node is in a transition to being delegated.
-                // Existing listeners are either:
-                //   - unaffected by delegation
-                //   - delegated and no longer in scope for this Zone
-                //   - delegated but still in scope
-                let mut x_listeners = vec![];
+        self.write(path, ts, value);
 
-                if external.initial {
-                    self.listeners.retain(|l| {
-                        let (retain, x_listener) = l.delegate(&external.path);
+        ZoneResult { update: self.read(path).0, ..Default::default() }
+    }
 
-                        if let Some(x_listener) = x_listener {
-                            x_listeners.push(x_listener);
-                        }
+    /// Applies the op of a `crdt` command to the CRDT value at `path`, or to a new one if there is
+    /// none (see `crdt`). Replies with the value, or as invalid if it's another type of CRDT.
+    pub fn crdt(&mut self, path: &Path, ts: u64, params: &Value) -> ZoneResult {
+        let mut absolute = self.path();
 
-                        retain
-                    });
-                }
+        absolute.append(&mut path.clone());
 
-                // Data meant for delegated node
-                if x_listeners.is_empty() {
-                    self.app.manager.send_external(&self.path, external, replicate);
-                }
-                else {
-                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
-                }
-            }
-        }
+        let invalid = |error: String| ZoneResult { invalid: Some(Invalid { path: absolute, error: error }), ..Default::default() };
 
-        if replicate && ! diff.node.is_noop() {
-            self.app.cluster.replicate(&self.path, diff);
+        let (kind, op) = match crdt::Op::from_params(params) {
+            Ok(parsed) => parsed,
+            Err(err) => return invalid(err)
+        };
+
+        if let Some(conflict) = self.conflict(path, None) {
+            return conflict;
         }
-    }
 
-    /// Same as Merge except a list of listeners is provided, which expects
-    /// updates that would bring those listeners up to date.
-    ///
-    /// TODO: This might be better implemented as a merge_bind operation (where
-    /// bind takes in a "cached values" parameter), which will solve the
-    /// recursive delegation problem.
-    pub fn merge_with_listeners(&mut self, diff: NodeTree, listeners: Vec<RListener>) {
-        // First, bring listeners up to date
-        let (update, externals) = {
-            // TODO: workaround merge mutating receiver and argument
-            let mut tree_clone = self.data.tree.clone();
-            let mut diff_clone = diff.clone();
+        let current = self.read(path).0;
 
-            // merge "the other way" to get the reverse updates
-            diff_clone.merge(&mut tree_clone)
+        let mut value = match current.as_ref().and_then(|update| update.crdt_at(path)) {
+            Some(crdt) if crdt.kind() == kind => crdt.clone(),
+            Some(crdt) => return invalid(format!("Not {}: {}", kind, crdt.kind())),
+            None => Crdt::new(kind).unwrap()
         };
 
-        // Convert all RListeners to Listeners
-        let mut listeners: Vec<_> = listeners
-            .into_iter()
-            .map(|l| l.to_absolute(self.path.clone()))
-            .collect();
+        if let Err(err) = value.apply(&op, &self.app.id.to_string(), ts) {
+            return invalid(err);
+        }
 
-        if externals.len() > 0 {
-            // Zone already has delegations so listeners need to
-            // be propagated to those external Zones as well.
-            for external in externals {
-                let mut x_listeners = vec![];
+        self.merge(Node::crdt(value, ts).prepend_path(&path.path).noop_vis(), true);
 
-                if external.initial {
-                    listeners.retain(|l| {
-                        let (retain, x_listener) = l.delegate(&external.path);
+        ZoneResult { update: self.read(path).0, ..Default::default() }
+    }
 
-                        if let Some(x_listener) = x_listener {
-                            x_listeners.push(x_listener);
-                        }
+    /// Adds `delta`, a number, to the number at `path` at time `ts`, no value counting as 0, so
+    /// counters don't need a read and a `cas` from the client. Atomic on this node, like `cas`;
+    /// increments on replicas at the same time are resolved by version, so counters written to on
+    /// several nodes are better off as a `crdt`. Replies with the sum written, or as invalid if the
+    /// value isn't a number.
+    pub fn incr(&mut self, path: &Path, ts: u64, delta: &Value) -> ZoneResult {
+        if let Some(conflict) = self.conflict(path, None) {
+            return conflict;
+        }
 
-                        retain
-                    });
-                }
+        let current = self.read(path).0;
+        let current = current.as_ref().and_then(|update| update.value_at(path)).cloned().unwrap_or_default();
 
-                // Recursively propagate listeners-with-cached-data
-                if ! x_listeners.is_empty() {
-                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
-                }
+        let sum = match current.add(delta) {
+            Some(sum) => sum,
+            None => {
+                let mut absolute = self.path();
+
+                absolute.append(&mut path.clone());
+
+                let error = "Not a number".to_string();
+
+                return ZoneResult { invalid: Some(Invalid { path: absolute, error: error }), ..Default::default() };
             }
-        }
+        };
 
-        // Only notify if there are backports
-        if let Some(update) = update {
-            listeners.retain(|listener| {
-                listener.update(&update).is_ok()
-            });
+        if let Err(invalid) = self.validate(path, &sum) {
+            return ZoneResult { invalid: Some(invalid), ..Default::default() };
         }
 
-        // Merge data delegated from parent. Each parent replica should have
-        // independently delegated, so don't replicate
-        self.merge(diff, false);
+        self.write(path, ts, sum);
 
-        // Add delegated listeners to `Zone`
-        self.listeners.append(&mut listeners);
+        ZoneResult { update: self.read(path).0, ..Default::default() }
     }
 
-    /// Read value(s)
-    pub fn read(&self, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
-        // TODO verify path
+    /// Leases `path` to the owner of a `lock` command, renews their lease, or releases it, by
+    /// `params` (see `lease`). A lock that waits for another owner's lease is replied to over `tx`
+    /// once it gets it, as command `id`.
+    pub fn lock(&mut self, path: &Path, id: u64, params: &Value, tx: Sender<String>) -> ZoneResult {
+        let request = match Request::from_params(params) {
+            Ok(request) => request,
+            Err(error) => {
+                let mut absolute = self.path();
 
-        self.data.tree.read(path)
-    }
+                absolute.append(&mut path.clone());
 
-    /// Load data if not already loaded. Usually called by `Manager` when sufficient memory is available.
-    pub fn load(&mut self) {
+                return ZoneResult { invalid: Some(Invalid { path: absolute, error: error }), ..Default::default() };
+            }
+        };
+
+        let now = expiry::now();
+
+        if request.release {
+            return match self.leases.release(path, &request.owner, now) {
+                Ok(()) => ZoneResult { ..Default::default() },
+                Err(lease) => ZoneResult { conflict: true, data: lease.map(|lease| lease.to_json()), ..Default::default() }
+            };
+        }
+
+        let result = match self.leases.acquire(path, &request.owner, request.ttl, now) {
+            Ok(lease) => ZoneResult { data: Some(lease.to_json()), ..Default::default() },
+            Err(lease) => match request.wait {
+                Some(wait) => {
+                    self.leases.wait(path, Waiter {
+                        owner: request.owner,
+                        ttl: request.ttl,
+                        until: now + (wait * 1000.0) as u64,
+                        id: id,
+                        prefix: self.path(),
+                        tx: tx
+                    });
+
+                    ZoneResult { queued: true, ..Default::default() }
+                },
+                None => ZoneResult { conflict: true, data: Some(lease.to_json()), ..Default::default() }
+            }
+        };
+
+        self.sweep_later();
+
+        result
+    }
+
+    /// Applies the op of a `list` command to the list at `path`, or to a new one if there is no
+    /// value (see `list`). Replies with the list, or as invalid if the value isn't one or the op's
+    /// index is out of range.
+    pub fn list(&mut self, path: &Path, ts: u64, params: &Value) -> ZoneResult {
+        let mut absolute = self.path();
+
+        absolute.append(&mut path.clone());
+
+        let invalid = |error: String| ZoneResult { invalid: Some(Invalid { path: absolute, error: error }), ..Default::default() };
+
+        let op = match list::Op::from_params(params) {
+            Ok(op) => op,
+            Err(err) => return invalid(err)
+        };
+
+        if let Some(conflict) = self.conflict(path, None) {
+            return conflict;
+        }
+
+        let current = self.read(path).0;
+
+        let mut list = match current.as_ref().and_then(|update| update.value_at(path)) {
+            Some(&value::Value::List(ref list)) => (**list).clone(),
+            Some(&value::Value::Null) | None => List::default(),
+            Some(_) => return invalid("Not a list".to_string())
+        };
+
+        if let Err(err) = list.apply(&op) {
+            return invalid(err);
+        }
+
+        self.merge(Node::list(list, ts).prepend_path(&path.path).noop_vis(), true);
+
+        ZoneResult { update: self.read(path).0, ..Default::default() }
+    }
+
+    /// Applies the writes, kills and cas of a `txn` command (see `Command::ops`) in one merge, so
+    /// readers and listeners see either all of them or none, and they are logged and replicated
+    /// as one. Each op is timestamped later than the one before it, so later ops win. A cas whose
+    /// version doesn't match the data from before the transaction, or an op on data delegated to
+    /// another zone, fails it with a conflict and the current value at that op's path. A value the
+    /// schema rejects fails it too.
+    pub fn txn(&mut self, command: &Command) -> ZoneResult {
+        let ops = match command.ops() {
+            Ok(ops) => ops,
+            Err(_) => return ZoneResult { conflict: true, ..Default::default() }
+        };
+
+        let mut diff = NodeTree { node: Default::default(), vis: Vis::permanent() };
+
+        for (i, op) in ops.into_iter().enumerate() {
+            let ts = if i == 0 { op.timestamp } else { clock::now() };
+
+            let node = match op.call {
+                Call::Cas => {
+                    let (expected, value) = command::cas_params(op.params);
+
+                    if let Err(invalid) = self.validate(&op.path, &value) {
+                        return ZoneResult { invalid: Some(invalid), ..Default::default() };
+                    }
+
+                    if let Some(conflict) = self.conflict(&op.path, Some(expected)) {
+                        return conflict;
+                    }
+
+                    Node::expand_from(&op.path.path[..], value, ts)
+                },
+                Call::Kill => {
+                    if let Some(conflict) = self.conflict(&op.path, None) {
+                        return conflict;
+                    }
+
+                    Node::delete(ts).prepend_path(&op.path.path)
+                },
+                _ => {
+                    if let Err(invalid) = self.validate(&op.path, &op.params) {
+                        return ZoneResult { invalid: Some(invalid), ..Default::default() };
+                    }
+
+                    if let Some(conflict) = self.conflict(&op.path, None) {
+                        return conflict;
+                    }
+
+                    Node::expand_from(&op.path.path[..], op.params, ts)
+                }
+            };
+
+            diff.merge(&mut node.noop_vis());
+        }
+
+        self.merge(diff.node.noop_vis(), true);
+
+        ZoneResult { ..Default::default() }
+    }
+
+    /// Copies the data at the path of a `copy` or `move` command to its destination, both in this
+    /// zone, in one merge: what was at the destination, if anything, is killed as of the command,
+    /// and the data written there with the same values and, for moves, killed where it was, both at
+    /// one later time. Readers and listeners see the data where it was or where it went, never in
+    /// neither place, or for moves in both. Conflicts if there is no data, or either path has some
+    /// delegated to another zone, and is invalid if the schema rejects the data at its destination.
+    pub fn copy(&mut self, command: &Command) -> ZoneResult {
+        let to = match command.destination() {
+            Ok(to) => to,
+            Err(_) => return ZoneResult { conflict: true, ..Default::default() }
+        };
+
+        let all = |path: &Path| {
+            let mut all = path.clone();
+
+            all.push(&"*#".to_string());
+            self.read(&all)
+        };
+
+        let (update, replaced) = match (all(&command.path), all(&to)) {
+            ((Some(update), ref delegated), (replaced, ref to_delegated)) if delegated.is_empty() && to_delegated.is_empty() => {
+                (update.at(&command.path), replaced.and_then(|replaced| replaced.at(&to)).is_some())
+            },
+            _ => (None, false)
+        };
+
+        let update = match update {
+            Some(update) => update,
+            None => return ZoneResult { conflict: true, ..Default::default() }
+        };
+
+        let ts = clock::now();
+        let node = update.to_node(ts);
+
+        if let Err(invalid) = self.validate(&to, &NodeTree { node: node.clone(), vis: Vis::permanent() }.to_plain_json()) {
+            return ZoneResult { invalid: Some(invalid), ..Default::default() };
+        }
+
+        let mut diff = NodeTree { node: Default::default(), vis: Vis::permanent() };
+
+        if replaced {
+            diff.merge(&mut Node::delete(command.timestamp).prepend_path(&to.path).noop_vis());
+        }
+
+        diff.merge(&mut node.prepend_path(&to.path).noop_vis());
+
+        if command.call == Call::Move {
+            diff.merge(&mut Node::delete(ts).prepend_path(&command.path.path).noop_vis());
+        }
+
+        self.merge(diff.node.noop_vis(), true);
+
+        ZoneResult { ..Default::default() }
+    }
+
+    /// Reads `paths` for a change spanning zones and replies with what was read, then serves nothing
+    /// else until the change is committed, or released if the `Hold` is dropped. Forwarded zones
+    /// reply `None`, to be held through their parent instead.
+    fn hold(&mut self, paths: Vec<Path>, reply: Sender<Option<Vec<Read>>>, commit: Receiver<NodeTree>, applied: Sender<()>) {
+        if self.folded_into.is_some() {
+            reply.send(None).unwrap_or_default(); // ignore if the change was given up
+            return;
+        }
+
+        self.touch();
+
+        for path in &paths {
+            self.thaw(path);
+        }
+
+        if reply.send(Some(paths.iter().map(|path| self.read(path)).collect())).is_err() {
+            return;
+        }
+
+        if let Ok(diff) = commit.recv() {
+            self.access.merges += 1;
+            self.merge(diff, true);
+
+            // Before splitting, which may wait on zones still held
+            applied.send(()).unwrap_or_default();
+            self.split_check();
+        }
+    }
+
+    /// Sets when value(s) expire, `value` seconds from time `ts`, or keeps them after all if it is
+    /// not a number. See `expiry`.
+    pub fn expire(&mut self, path: &Path, ts: u64, value: Value) {
+        match value.as_f64() {
+            Some(secs) => self.data.expiry.set(path.clone(), Ttl::new(ts, secs)),
+            None => if ! self.data.expiry.clear(path) { return }
+        }
+
+        self.expiry_changed = true;
+        self.dirty();
+        self.sweep_later();
+    }
+
+    /// Kill value(s)
+    pub fn kill(&mut self, path: &Path, ts: u64) {
+        let node = Node::delete(ts);
+
+        let diff = node.prepend_path(&path.path);
+
+        self.merge(diff.noop_vis(), true);
+        // TODO: externals goes to external nodes
+    }
+
+    /// Merge value(s). Merge is generic and most operations are defined as a merge. Set
+    /// `replicate` flag if merge was due to a user command.
+    pub fn merge(&mut self, mut diff: NodeTree, replicate: bool) {
+        self.thaw_changes(&diff.node);
+
+        let (update, externals) = self.data.tree.merge_sized(&mut diff, self.app.strategies.at(&self.path), &mut self.sizes);
+
+        // Only notify if there are changes
+        if let Some(update) = update {
+            self.notify(&update);
+        }
+
+        if ! diff.node.is_noop() {
+            // Child zones folded back in, see `fold`
+            for mut child in diff.node.undelegations() {
+                let mut path = self.path();
+
+                path.append(&mut child);
+                self.app.manager.zone_folded(&path);
+            }
+
+            if let Err(err) = self.app.store.append(&self.path, &diff) {
+                println!("Error logging diff for {:?}: {}", &self.path, err);
+            }
+
+            self.regions.mark_changes(&diff.node);
+            self.writes += 1;
+            self.dirty();
+        }
+
+        if externals.len() > 0 {
+            // Data moved to new zones is saved along with what's left here before they get it, so
+            // a crash mid-split doesn't lose it
+            if externals.iter().any(|external| external.initial) {
+                self.write_split(&externals);
+            }
+
+            for external in externals {
+                // Check if Node is in a transition to being delegated.
+                // Existing listeners are either:
+                //   - unaffected by delegation
+                //   - delegated and no longer in scope for this Zone
+                //   - delegated but still in scope
+                let mut x_listeners = vec![];
+
+                if external.initial {
+                    self.listeners.retain(|l| {
+                        let (retain, x_listener) = l.delegate(&external.path);
+
+                        if let Some(x_listener) = x_listener {
+                            x_listeners.push(x_listener);
+                        }
+
+                        retain
+                    });
+                }
+
+                // Data meant for delegated node
+                if x_listeners.is_empty() {
+                    self.app.manager.send_external(&self.path, external, replicate);
+                }
+                else {
+                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
+                }
+            }
+        }
+
+        if replicate && ! diff.node.is_noop() {
+            self.app.cluster.replicate(&self.path, diff);
+        }
+    }
+
+    /// Saves this zone's data along with the data of the zones it just delegated to, all or
+    /// nothing. If the Store can't, the split is only as safe as the diff logged for it.
+    fn write_split(&self, externals: &[External]) {
+        let children: Vec<_> = externals.iter().filter(|external| external.initial).map(|external| {
+            let mut path = self.path();
+            let mut relative = external.path.clone();
+
+            path.append(&mut relative);
+
+            ZoneData::new(path, external.tree.clone())
+        }).collect();
+
+        let mut zones = vec![(self.path(), &self.data)];
+
+        zones.extend(children.iter().map(|child| (child.path.clone(), child)));
+
+        match self.app.store.write_atomic(&zones) {
+            Err(err) => println!("Error writing split of {:?}: {}", &self.path, err),
+            Ok(false) => println!("Could not write split of {:?} atomically", &self.path),
+            Ok(true) => ()
+        }
+
+        let paths: Vec<Path> = children.iter().map(|child| child.path.clone()).collect();
+
+        if ! paths.is_empty() {
+            self.app.hooks.split(&self.path(), &paths);
+        }
+    }
+
+    /// Same as Merge except a list of listeners is provided, which expects
+    /// updates that would bring those listeners up to date.
+    ///
+    /// TODO: This might be better implemented as a merge_bind operation (where
+    /// bind takes in a "cached values" parameter), which will solve the
+    /// recursive delegation problem.
+    pub fn merge_with_listeners(&mut self, diff: NodeTree, listeners: Vec<RListener>) {
+        self.thaw_changes(&diff.node);
+
+        // First, bring listeners up to date
+        let (update, externals) = {
+            // TODO: workaround merge mutating receiver and argument
+            let mut tree_clone = self.data.tree.clone();
+            let mut diff_clone = diff.clone();
+
+            // merge "the other way" to get the reverse updates
+            diff_clone.merge(&mut tree_clone)
+        };
+
+        // Convert all RListeners to Listeners
+        let mut listeners: Vec<_> = listeners
+            .into_iter()
+            .map(|l| l.to_absolute(self.path.clone()))
+            .collect();
+
+        if externals.len() > 0 {
+            // Zone already has delegations so listeners need to
+            // be propagated to those external Zones as well.
+            for external in externals {
+                let mut x_listeners = vec![];
+
+                if external.initial {
+                    listeners.retain(|l| {
+                        let (retain, x_listener) = l.delegate(&external.path);
+
+                        if let Some(x_listener) = x_listener {
+                            x_listeners.push(x_listener);
+                        }
+
+                        retain
+                    });
+                }
+
+                // Recursively propagate listeners-with-cached-data
+                if ! x_listeners.is_empty() {
+                    self.app.manager.send_external_with_listeners(&self.path, external, x_listeners);
+                }
+            }
+        }
+
+        // Only notify if there are backports
+        if let Some(update) = update {
+            listeners.retain(|listener| {
+                listener.update(&update).is_ok()
+            });
+        }
+
+        // Merge data delegated from parent. Each parent replica should have
+        // independently delegated, so don't replicate
+        self.merge(diff, false);
+
+        // Add delegated listeners to `Zone`
+        for listener in listeners {
+            self.listeners.insert(listener);
+        }
+    }
+
+    /// Read value(s)
+    pub fn read(&self, path: &Path) -> (Option<Update>, Vec<DelegatedMatch>) {
+        // TODO verify path
+
+        self.data.tree.read(path)
+    }
+
+    /// Takes snapshot `params` of all data, or drops one for `{ "drop": name }`. Returns the data
+    /// delegated at `path`, to take or drop it in those zones too.
+    pub fn take_snapshot(&mut self, path: &Path, params: &Value) -> Vec<DelegatedMatch> {
+        if params.is_string() {
+            self.thaw_all();
+        }
+
+        match params.as_str() {
+            Some(name) => self.snapshots.take(name, &self.data.tree),
+            None => {
+                if let Some(name) = params.get("drop").and_then(|name| name.as_str()) {
+                    self.snapshots.remove(name);
+                }
+            }
+        }
+
+        self.read(path).1
+    }
+
+    /// Load data if not already loaded. Usually called by `Manager` when sufficient memory is available.
+    pub fn load(&mut self) {
         if self.state.is_init() {
-            self.app.store.load(&self.handle, &self.path);
+            match self.app.store.load(&self.handle, &self.path) {
+                Err(err) => println!("Error loading {:?}: {}", &self.path, err),
+                Ok(_) => self.state.set(ZoneState::LOADING)
+            }
+        }
+        else {
+            panic!("Zone is in the wrong state to load data: {:?}", self.path())
+        }
+    }
+
+    /// Wait for data requested on our behalf. Usually called by `Manager` loading many zones at once.
+    pub fn loading(&mut self) {
+        if self.state.is_idle() || self.state.is_init() {
             self.state.set(ZoneState::LOADING);
         }
         else {
@@ -528,18 +1444,114 @@ impl Zone {
             }
 
             self.data.tree = data.tree;
+            self.data.expiry = data.expiry;
+            self.cold.clear();
+            self.sizes = self.data.sizes();
             self.state.set(ZoneState::ACTIVE);
+            self.app.store.emit(StoreEvent::Loaded(self.path()));
+            self.app.hooks.loaded(&self.path(), &self.data);
+
+            // Expired while hibernating
+            self.expire_due();
+            self.sweep_later();
+            self.pack_later();
         }
         else {
             unimplemented!()
         }
     }
 
-    /// Callback to notify Zone to hibernate.
-    pub fn hibernate(&mut self) {
+    /// Callback for stores streaming loaded data to `Zone`, which is deserialized as it comes in.
+    /// Like loads failing in the `Store`, a stream failing for other reasons than corrupt data
+    /// leaves the `Zone` loading.
+    pub fn loaded_stream(&mut self, reader: ChunkReader) {
+        match reader.receive() {
+            Ok(data) => self.loaded(data),
+            Err(StoreError::Corrupt { .. }) => self.corrupt(),
+            Err(err) => println!("Error streaming data for {:?}: {}", &self.path, err)
+        }
+    }
+
+    /// Callback for stores loading data left serialized, which is deserialized here, salvaging
+    /// what it can if it is corrupt. Like `loaded_stream`, data failing for other reasons than
+    /// being corrupt leaves the `Zone` loading.
+    pub fn loaded_raw(&mut self, raw: RawZone) {
+        match raw.recover() {
+            Ok((data, false)) => self.loaded(data),
+            Ok((data, true)) => self.recovered(data),
+            Err(StoreError::Corrupt { .. }) => self.corrupt(),
+            Err(err) => println!("Error deserializing data for {:?}: {}", &self.path, err)
+        }
+    }
+
+    /// Callback for stores when data for this `Zone` is corrupt. Rather than refusing service, the
+    /// `Zone` starts out empty. Replicas fill it up again as they replicate changes, and the
+    /// corrupt data is replaced on the next write. A `Zone` already loaded keeps what it has.
+    // TODO: request a full copy of the Zone from replicas
+    pub fn corrupt(&mut self) {
+        if self.state.is_loading() {
+            println!("Corrupt data in {:?}, starting empty", &self.path);
+            self.state.set(ZoneState::ACTIVE);
+        }
+        else {
+            println!("Corrupt data for {:?} once loaded, ignoring it", &self.path);
+        }
+    }
+
+    /// Callback for stores with what could be salvaged of corrupt data for this `Zone`. It loads
+    /// what there is, and is flagged as recovered in its stats. The corrupt data is quarantined
+    /// by backends that can, for inspection, and replaced with what was salvaged right away. A
+    /// `Zone` already loaded keeps what it has.
+    pub fn recovered(&mut self, data: ZoneData) {
+        if self.state.is_loading() {
+            println!("Corrupt data in {:?}, recovered what could be read", &self.path);
+
+            match self.app.store.quarantine(self.path()) {
+                Ok(true) => (),
+                Ok(false) => println!("Could not quarantine corrupt data in {:?}, replacing it", &self.path),
+                Err(err) => println!("Error quarantining corrupt data in {:?}: {}", &self.path, err)
+            }
+
+            self.loaded(data);
+            self.recovered = true;
+            self.dirty();
+        }
+        else {
+            println!("Recovered data for {:?} once loaded, ignoring it", &self.path);
+        }
+    }
+
+    /// Callback to notify Zone its stored data changed, to load it again in place of its data. A
+    /// `Zone` with changes not yet written keeps them, and writes them over the stored data. Zones
+    /// not loaded have nothing to reload.
+    pub fn reload(&mut self) {
         if self.state.is_active() {
+            match self.app.store.reload(&self.handle, &self.path) {
+                Err(err) => println!("Error reloading {:?}: {}", &self.path, err),
+                Ok(_) => self.state.set(ZoneState::LOADING)
+            }
+        }
+        else if self.state.is_ready() {
+            println!("Not reloading {:?}, it has changes not yet written", &self.path);
+        }
+    }
+
+    /// Callback to notify Zone to hibernate. Zones left near-empty, by deletes or expiry, fold back
+    /// into their parent first rather than linger. Zones holding leases hibernate once they don't.
+    pub fn hibernate(&mut self) {
+        if self.state.is_active() && self.leases.is_empty() {
+            if self.sizes.total().is_small() {
+                self.fold();
+            }
+
             self.state.set(ZoneState::IDLE);
             self.data.tree = Default::default();
+            self.data.expiry = Default::default();
+            self.snapshots.clear();
+            self.cold.clear();
+            self.prefetch.clear();
+            self.sizes = Default::default();
+            self.app.hooks.evicted(&self.path());
             self.app.manager.zone_hibernated(self.handle.clone());
         }
         else {
@@ -547,31 +1559,279 @@ impl Zone {
         }
     }
 
-    /// Callback to notify Zone of available resources to persist dirty data.
+    /// Callback to notify Zone of available resources to persist dirty data. A `Zone` left with
+    /// no data deletes its stored data instead, and a large `Zone` only saves its diffs since the
+    /// last save.
+    // TODO: zones holding nothing but tombstones still take up disk
     pub fn save(&mut self) {
         if self.state.is_dirty() {
-            self.app.store.write(&self.handle, &self.path, &self.data);
-            self.state.set(ZoneState::WRITING);
+            let result = if self.data.is_empty() && self.cold.is_empty() {
+                self.app.store.delete(&self.handle, &self.path)
+            }
+            else if ! self.regions.is_empty() && ! self.expiry_changed && self.size() >= DELTA_SIZE {
+                self.app.hooks.before_persist(&self.path(), &self.data);
+                self.app.store.write_delta(&self.handle, &self.path, &[self.regions.extract(&self.data.tree)])
+            }
+            else {
+                self.thaw_all();
+                self.collect_tombstones();
+                self.app.hooks.before_persist(&self.path(), &self.data);
+                self.app.store.write(&self.handle, &self.path, &self.data)
+            };
+
+            match result {
+                Err(err) => println!("Error saving {:?}, staying dirty: {}", &self.path, err),
+                Ok(_) => {
+                    self.regions.clear();
+                    self.expiry_changed = false;
+                    self.saving = self.unsaved.take();
+                    self.state.set(ZoneState::WRITING);
+                }
+            }
         }
         else {
             println!("Spurious save callback in {:?}", &self.path);
         }
     }
 
+    /// Drops tombstones past the retention before all data is written, see `tombstone`.
+    fn collect_tombstones(&mut self) {
+        if let Some(cutoff) = self.app.tombstones.cutoff(clock::now()) {
+            let dropped = self.data.tree.collect_tombstones(cutoff);
+
+            if dropped > 0 {
+                debug!("Dropped {} tombstones in {:?}", dropped, &self.path);
+                self.sizes = self.data.sizes();
+            }
+        }
+    }
+
     /// Callback to notify Zone that data was persisted.
     pub fn saved(&mut self) {
+        self.app.store.emit(StoreEvent::Written(self.path()));
+        self.stored();
+    }
+
+    /// Moves on once stored data is up to date, after a write or delete.
+    fn stored(&mut self) {
+        self.saving = None;
+
         if self.state.is_writing() {
             self.state.set(ZoneState::ACTIVE);
+
+            if mem::replace(&mut self.fold_requested, false) {
+                self.fold();
+            }
         }
         else if self.state.is_dirty() {
             // Zone dirtied itself during a write
-            self.app.store.request_write(&self.handle);
+            self.request_write();
         }
         else {
             unimplemented!();
         }
     }
 
+    /// Callback to notify Zone that data could not be persisted. The `Zone` stays dirty, and asks
+    /// to write again: right away after a transient error, otherwise in `WRITE_RETRY_MS` rather
+    /// than failing over and over.
+    pub fn write_failed(&mut self, err: StoreError) {
+        if self.state.is_writing() || self.state.is_dirty() {
+            println!("Error saving {:?}, will try again: {}", &self.path, err);
+
+            self.app.store.emit(StoreEvent::WriteFailed(self.path(), err.to_string()));
+
+            if let Some(saving) = self.saving.take() {
+                self.unsaved = Some(self.unsaved.map_or(saving, |unsaved| unsaved.join(saving)));
+            }
+
+            self.state.set(ZoneState::DIRTY);
+
+            if err.is_transient() {
+                self.request_write();
+            }
+            else {
+                self.retry_write_later();
+            }
+        }
+        else {
+            println!("Spurious write failure callback in {:?}", &self.path);
+        }
+    }
+
+    /// Callback to ask the Store to write again, after it was busy. Zones no longer dirty have
+    /// nothing to write.
+    pub fn retry_write(&mut self) {
+        self.write_retry = false;
+
+        if self.state.is_dirty() {
+            self.request_write();
+        }
+    }
+
+    /// Callback to sweep expired TTLs, kept up while the `Zone` has any. Zones not loaded sweep
+    /// once they load.
+    pub fn sweep(&mut self) {
+        self.sweeping = false;
+
+        if self.state.is_ready() {
+            self.expire_due();
+            self.leases.sweep(expiry::now());
+            self.sweep_later();
+        }
+    }
+
+    /// Callback to pack the cold children of this `Zone`'s root, see `cold`, kept up while it is
+    /// loaded. Zones with changes not yet written pack on the next one.
+    pub fn pack(&mut self) {
+        self.packing = false;
+
+        if let Some(policy) = self.app.cold {
+            if self.state.is_active() {
+                let packed = self.cold.pack(&mut self.data.tree.node, &policy, now_ms());
+
+                if packed > 0 {
+                    debug!("Packed {} children of {:?}", packed, &self.path);
+                }
+            }
+
+            if self.state.is_ready() {
+                self.pack_later();
+            }
+        }
+    }
+
+    /// Folds the data of this `Zone` back into its parent zone, which takes over its TTLs and
+    /// listeners, and routing its paths once it merged the data. The stored data of this `Zone` is
+    /// deleted after that, and calls still reaching it are forwarded to the parent. Zones with
+    /// changes not yet written fold once they are; the root zone, zones with delegations of their
+    /// own and zones holding leases don't fold.
+    // TODO: listeners of the parent that also matched in this Zone are notified twice
+    pub fn fold(&mut self) {
+        if self.folded_into.is_some() || self.path.len() == 0 {
+            return;
+        }
+
+        if ! self.state.is_active() {
+            self.fold_requested = true;
+            return;
+        }
+
+        if ! self.data.tree.node.delegations().is_empty() {
+            println!("Not folding {:?}, it has delegated zones", &self.path);
+            return;
+        }
+
+        if ! self.leases.is_empty() {
+            return;
+        }
+
+        self.thaw_all();
+
+        let parent_path = self.path.parent().unwrap(); // not the root, see above
+
+        let (prefix, parent) = self.app.manager.find_nearest(&parent_path);
+        let relative = self.path.relative_to(&prefix).unwrap();
+
+        let tree = mem::replace(&mut self.data.tree, Default::default());
+        let expiry = mem::replace(&mut self.data.expiry, Default::default());
+        let listeners = self.listeners.drain().into_iter()
+            .map(|l| RListener::new((*l.path).clone(), l.regexes.clone(), l.depth, &l.tx).prefixed(&relative))
+            .collect();
+
+        parent.folded(FoldedZone {
+            relative: relative.clone(),
+            node: tree.node.undelegated(clock::now()),
+            expiry: expiry,
+            listeners: listeners,
+            child: self.handle.clone()
+        });
+
+        self.regions.clear();
+        self.snapshots.clear();
+        self.sizes = Default::default();
+        self.expiry_changed = false;
+        self.folded_into = Some((relative, parent));
+    }
+
+    /// Callback to take the data of a child `Zone` folding back into this one. See `fold`.
+    fn folded(&mut self, folded: FoldedZone) {
+        let FoldedZone { relative, node, expiry, listeners, child } = folded;
+
+        self.merge(node.prepend_path(&relative.path).noop_vis(), true);
+
+        if ! expiry.is_empty() {
+            self.data.expiry.adopt(&relative, expiry);
+            self.expiry_changed = true;
+            self.sweep_later();
+        }
+
+        for listener in listeners {
+            self.listeners.insert(listener.to_absolute(self.path.clone()));
+        }
+
+        // Also done as the undelegation merges, unless the child's path wasn't delegated after all
+        let mut path = self.path();
+
+        path.append(&mut relative.clone());
+        self.app.manager.zone_folded(&path);
+
+        child.fold_done();
+    }
+
+    /// Callback once the parent took the data of this `Zone`, to delete its stored data. With
+    /// nothing left, a write deletes it.
+    pub fn fold_done(&mut self) {
+        if self.state.is_ready() {
+            self.dirty();
+        }
+        else if let Err(err) = self.app.store.delete_data(self.path()) {
+            println!("Error deleting folded {:?}: {}", &self.path, err);
+        }
+    }
+
+    /// Dispatches a command reaching this `Zone` after it folded to its parent, with paths in the
+    /// result relative to this `Zone` as the caller expects.
+    fn forward(&self, mut command: Command, listener: &Sender<String>) -> ZoneResult {
+        let &(ref relative, ref parent) = self.folded_into.as_ref().unwrap();
+        let mut path = relative.clone();
+
+        path.append(&mut command.path);
+        command.path = path;
+
+        let mut result = parent.dispatch(command, listener);
+
+        result.update = result.update.and_then(|update| update.at(relative));
+
+        for delegated in &mut result.delegated {
+            delegated.path = delegated.path.slice(relative.len());
+        }
+
+        result
+    }
+
+    /// Callback to notify Zone that its stored data was deleted. Same as a completed write.
+    pub fn deleted(&mut self) {
+        self.app.store.emit(StoreEvent::Deleted(self.path()));
+        self.stored();
+    }
+
+    /// Callback to notify Zone that the store wants all of its data rather than a delta, which
+    /// was not saved.
+    pub fn snapshot(&mut self) {
+        if self.state.is_writing() || self.state.is_dirty() {
+            self.thaw_all();
+            self.collect_tombstones();
+
+            if let Err(err) = self.app.store.write(&self.handle, &self.path, &self.data) {
+                self.write_failed(err);
+            }
+        }
+        else {
+            println!("Spurious snapshot callback in {:?}", &self.path);
+        }
+    }
+
     /// Get zone path.
     pub fn path(&self) -> Path {
         (*self.path).clone()
@@ -585,7 +1845,7 @@ impl Zone {
     /// Get estimated size.
     pub fn size(&self) -> usize {
         // TODO: size does not handle cloaked data properly
-        self.data.tree.node.total_byte_size()
+        self.sizes.total().bytes
     }
 
     /// Get zone state.
@@ -593,6 +1853,21 @@ impl Zone {
         self.state
     }
 
+    /// Marks the `Zone` as just used, see `ZoneHandle::idle`.
+    fn touch(&self) {
+        self.handle.usage.accessed.store(now_ms(), Ordering::Relaxed);
+    }
+
+    /// Get access and mutation counts.
+    pub fn stats(&self) -> AccessStats {
+        AccessStats {
+            listeners: self.listeners.len(),
+            bytes: self.size(),
+            recovered: self.recovered,
+            ..self.access
+        }
+    }
+
     /// Writes value(s) to the node at `path` at time `ts`
     pub fn write(&mut self, path: &Path, ts: u64, value: Value) {
         // TODO verify path
@@ -601,13 +1876,45 @@ impl Zone {
         self.merge(diff.noop_vis(), true);
     }
 
+    /// Returns a conflict, with the current value at `path`, if its data was delegated to another
+    /// zone since the client looked up the zone, or if `version` is given and isn't the version of
+    /// its value (`None` for no value).
+    fn conflict(&self, path: &Path, version: Option<Option<u64>>) -> Option<ZoneResult> {
+        let (update, delegated) = self.read(path);
+
+        if ! delegated.is_empty() {
+            return Some(ZoneResult { conflict: true, ..Default::default() });
+        }
+
+        match version {
+            Some(expected) if update.as_ref().and_then(|update| update.version_at(path)) != expected => {
+                Some(ZoneResult { update: update, conflict: true, ..Default::default() })
+            },
+            _ => None
+        }
+    }
+
+    /// Checks a value to be written at `path` against the schema, see `schema`.
+    fn validate(&self, path: &Path, value: &Value) -> Result<(), Invalid> {
+        let mut absolute = self.path();
+
+        absolute.append(&mut path.clone());
+        self.app.schema.check(&absolute, value)
+    }
+
+    /// Counts a change not yet written, and asks to write it unless the `Zone` has already.
     fn dirty(&mut self) {
+        let mut unsaved = self.unsaved.unwrap_or_else(Dirty::new);
+
+        unsaved.changes += 1;
+        self.unsaved = Some(unsaved);
+
         if self.state.is_dirty() {
             return; // already dirty
         }
 
         if self.state.is_active() {
-            self.app.store.request_write(&self.handle);
+            self.request_write();
             self.state.set(ZoneState::DIRTY);
 
             return;
@@ -622,22 +1929,165 @@ impl Zone {
         unimplemented!();
     }
 
+    /// Asks the Store for a write notification, with what it has left to write so it is flushed
+    /// in turn (see `store::scheduler`). A `Zone` whose Store is busy asks again a little later,
+    /// and one whose Store has gone away stays dirty.
+    fn request_write(&mut self) {
+        let dirty = self.unsaved.unwrap_or_else(Dirty::new);
+
+        match self.app.store.request_write(&self.handle, dirty) {
+            Err(StoreError::Busy) => self.retry_write_later(),
+            Err(err) => println!("Error requesting write for {:?}: {}", &self.path, err),
+            Ok(_) => ()
+        }
+    }
+
+    /// Has the `Zone` ask to write again in `WRITE_RETRY_MS`, unless it is waiting to already.
+    fn retry_write_later(&mut self) {
+        if self.write_retry {
+            return;
+        }
+
+        self.write_retry = true;
+
+        let handle = self.handle.clone();
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_millis(WRITE_RETRY_MS));
+            handle.retry_write();
+        });
+    }
+
+    /// Kills values whose TTLs are due, as of the `expire` command that set them.
+    fn expire_due(&mut self) {
+        let due = self.data.expiry.due(expiry::now());
+
+        if due.is_empty() {
+            return;
+        }
+
+        for (path, ttl) in due {
+            self.kill(&path, ttl.set + 1);
+        }
+
+        self.expiry_changed = true;
+        self.dirty();
+    }
+
+    /// Has the `Zone` sweep expired TTLs and leases in `expiry::SWEEP_MS`, if it has any and is not
+    /// waiting to already.
+    fn sweep_later(&mut self) {
+        if self.sweeping || (self.data.expiry.is_empty() && self.leases.is_empty()) {
+            return;
+        }
+
+        self.sweeping = true;
+
+        let handle = self.handle.clone();
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_millis(expiry::SWEEP_MS));
+            handle.sweep();
+        });
+    }
+
+    /// Has the `Zone` pack cold children once they could have been unused for long enough, if
+    /// the app packs them and it is not waiting to already.
+    fn pack_later(&mut self) {
+        let policy = match self.app.cold {
+            Some(policy) if ! self.packing => policy,
+            _ => return
+        };
+
+        self.packing = true;
+
+        let handle = self.handle.clone();
+
+        mioco::spawn(move|| {
+            mioco::sleep(Duration::from_secs(policy.idle.max(1)));
+            handle.pack();
+        });
+    }
+
+    /// Whether `command` writes to a frozen path, any of its ops for a `txn`, see `freeze`.
+    fn frozen(&self, command: &Command) -> bool {
+        if self.app.frozen.is_empty() {
+            return false;
+        }
+
+        let paths = match command.call {
+            Call::Txn => command.ops().unwrap_or_default().into_iter().map(|op| op.path).collect(),
+            _ => vec![command.path.clone()]
+        };
+
+        paths.into_iter().any(|mut path| {
+            let mut absolute = self.path();
+
+            absolute.append(&mut path);
+            self.app.frozen.is_frozen(&absolute)
+        })
+    }
+
+    /// Counts reads of child zones at `delegated`, reached by a read of `path`, and has the
+    /// `Manager` load those likely to be read next, see `prefetch`.
+    fn prefetch(&mut self, path: &Path, delegated: &[DelegatedMatch]) {
+        let now = now_ms();
+
+        self.prefetch.record(delegated.iter().map(|delegated| &delegated.path), now);
+
+        let likely = self.prefetch.likely(path, now);
+
+        if ! likely.is_empty() {
+            let paths = likely.into_iter().map(|mut child| {
+                let mut path = self.path();
+
+                path.append(&mut child);
+                path
+            }).collect();
+
+            self.app.manager.prefetch(paths);
+        }
+    }
+
+    /// Inflates the packed children `path` reaches, and marks them used, see `cold`.
+    fn thaw(&mut self, path: &Path) {
+        if self.app.cold.is_some() {
+            self.cold.thaw(&mut self.data.tree.node, path, now_ms());
+        }
+    }
+
+    /// Inflates the packed children `diff` changes, before it is merged.
+    fn thaw_changes(&mut self, diff: &Node) {
+        if self.app.cold.is_some() {
+            for path in diff.changes() {
+                self.thaw(&path);
+            }
+        }
+    }
+
+    /// Inflates all packed children, before all data is used.
+    fn thaw_all(&mut self) {
+        self.cold.thaw_all(&mut self.data.tree.node);
+    }
+
     /// Notifies listeners
     fn notify(&mut self, update: &Update) {
-        self.listeners.retain(|listener| {
-            listener.update(update).is_ok()
-        });
+        self.listeners.notify(update);
     }
 
-    fn sub(&mut self, path: &Path, tx: Sender<String>) {
-        let listener = Listener::new(self.path.clone(), Arc::new(path.clone()), tx);
+    fn sub(&mut self, path: &Path, regexes: Arc<Regexes>, depth: Option<usize>, tx: Sender<String>) {
+        let listener = Listener::new(self.path.clone(), Arc::new(path.clone()), regexes, depth, tx);
 
-        self.listeners.push(listener);
+        self.listeners.insert(listener);
     }
 
+    /// Splits off children of a `Zone` grown too big (see `delegate`) into zones of their own,
+    /// which `merge` writes along with what's left here (see `write_split`). Zones holding leases
+    /// split once they don't, so leases stay with the zone owning their paths.
     fn split_check(&mut self) {
-        if self.writes >= 10 {
+        if self.writes >= 10 && self.sizes.total().exceeds() && self.leases.is_empty() {
             self.writes = 0;
+            self.thaw_all();
 
             if let Some(delegate_node) = delegate(&self.data.tree.node) {
                 self.merge(delegate_node.noop_vis(), true);
@@ -646,13 +2096,61 @@ impl Zone {
     }
 }
 
+/// Milliseconds on a monotonic clock, for `Usage`.
+fn now_ms() -> usize {
+    (time::precise_time_ns() / 1000000) as usize
+}
+
+impl AccessStats {
+    pub fn count(&mut self, call: &Call) {
+        match call {
+            &Call::Bind => self.binds += 1,
+            &Call::Diff | &Call::Read | &Call::Snapshot => self.reads += 1,
+            &Call::Cas | &Call::Copy | &Call::Crdt | &Call::Expire | &Call::Incr | &Call::Kill | &Call::List | &Call::Lock | &Call::Move | &Call::Txn | &Call::Write => self.writes += 1
+        }
+    }
+
+    /// Commands and merges in all.
+    pub fn ops(&self) -> u64 {
+        self.reads + self.binds + self.writes + self.merges
+    }
+
+    pub fn add(&mut self, other: &AccessStats) {
+        self.reads += other.reads;
+        self.binds += other.binds;
+        self.writes += other.writes;
+        self.merges += other.merges;
+        self.listeners += other.listeners;
+        self.bytes += other.bytes;
+    }
+}
+
 impl ZoneData {
     pub fn new(path: Path, tree: NodeTree) -> ZoneData {
         ZoneData {
             path: path,
-            tree: tree
+            tree: tree,
+            expiry: Default::default()
         }
     }
+
+    /// Measures the data, see `delegate::Sizes`.
+    pub fn sizes(&self) -> Sizes {
+        Sizes::of(&self.tree.node)
+    }
+
+    /// Returns true if there is nothing worth storing: loading no data gives the same result.
+    pub fn is_empty(&self) -> bool {
+        self.tree.node.is_noop() && self.tree.vis.is_noop()
+    }
+
+    /// Paths, relative to the zone, whose values differ in `other`, e.g. the same zone later or on
+    /// another replica. See `Update::diff`.
+    pub fn diff(&self, other: &ZoneData) -> Vec<(Path, Difference)> {
+        let all = Path::new(vec!["*#".to_string()]);
+
+        Update::diff(self.tree.read(&all).0.as_ref(), other.tree.read(&all).0.as_ref())
+    }
 }
 
 #[test]
@@ -678,3 +2176,45 @@ fn test_zone_state() {
     assert!(state.is_writing());
     assert!(state.is_ready());
 }
+
+#[test]
+fn test_zone_data_is_empty() {
+    use serde_json::Value as JSON;
+
+    assert!(ZoneData::new(path![moo], Default::default()).is_empty());
+
+    let written = NodeTree { vis: Default::default(), node: Node::expand(JSON::String("moo".into()), 1000) };
+    let killed = NodeTree { vis: Default::default(), node: Node::delete(1000) };
+
+    assert!(! ZoneData::new(path![moo], written).is_empty());
+
+    // Tombstones still affect merges
+    assert!(! ZoneData::new(path![moo], killed).is_empty());
+}
+
+#[test]
+fn test_zone_data_diff() {
+    use serde_json::Map;
+
+    let mut cow = Map::new();
+    let mut data = Map::new();
+
+    cow.insert("pig".to_string(), Value::from(2));
+    data.insert("moo".to_string(), Value::from(1));
+    data.insert("cow".to_string(), Value::Object(cow));
+
+    let before = ZoneData::new(path![], NodeTree { node: Node::expand(Value::Object(data), 1000), vis: Vis::permanent() });
+    let mut after = ZoneData::new(path![], before.tree.clone());
+
+    after.tree.merge(&mut Node::expand_from(&path![moo].path, Value::from(3), 2000).noop_vis());
+    after.tree.merge(&mut Node::expand_from(&path![hen].path, Value::from(4), 2000).noop_vis());
+    after.tree.merge(&mut Node::delete(2000).prepend_path(&path![cow].path).noop_vis());
+
+    assert!(before.diff(&before).is_empty());
+    assert_eq!(before.diff(&after), vec![
+        (path![cow.pig], Difference::Removed),
+        (path![hen], Difference::Added),
+        (path![moo], Difference::Changed)
+    ]);
+    assert_eq!(after.diff(&before)[1], (path![hen], Difference::Removed));
+}